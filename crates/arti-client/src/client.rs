@@ -904,12 +904,15 @@ impl<R: Runtime> TorClient<R> {
             let pt_state_dir = state_dir.as_path().join("pt_state");
             config.storage.permissions().make_directory(&pt_state_dir)?;
 
-            let mgr = Arc::new(tor_ptmgr::PtMgr::new(
-                config.bridges.transports.clone(),
-                pt_state_dir,
-                Arc::clone(&path_resolver),
-                runtime.clone(),
-            )?);
+            let mgr = Arc::new(
+                tor_ptmgr::PtMgr::new(
+                    config.bridges.transports.clone(),
+                    pt_state_dir,
+                    Arc::clone(&path_resolver),
+                    runtime.clone(),
+                )?
+                .with_stage_timeouts(chanmgr.stage_timeouts()),
+            );
 
             chanmgr.set_pt_mgr(mgr.clone());
 
@@ -1774,6 +1777,26 @@ impl<R: Runtime> TorClient<R> {
         Ok((service, stream))
     }
 
+    /// Return a handle to the state of this client, for storing a `T`
+    /// on behalf of the onion service `nickname`.
+    ///
+    /// Different values of `key` refer to different stored values.
+    /// This is meant for things that run alongside an onion service,
+    /// such as the `tor-hsrproxy` reverse proxy.
+    #[cfg(feature = "onion-service-service")]
+    pub fn onion_service_storage_handle<T>(
+        &self,
+        nickname: &tor_hsservice::HsNickname,
+        key: &str,
+    ) -> tor_persist::DynStorageHandle<T>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + 'static,
+    {
+        self.statemgr
+            .clone()
+            .create_handle(format!("hs_{nickname}_{key}"))
+    }
+
     /// Try to launch an onion service with a given configuration and provided
     /// [`HsIdKeypair`]. If an onion service with the given nickname already has an
     /// associated `HsIdKeypair`  in this `TorClient`'s `KeyMgr`, then this operation
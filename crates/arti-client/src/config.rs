@@ -13,7 +13,7 @@ use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::time::Duration;
 
-pub use tor_chanmgr::{ChannelConfig, ChannelConfigBuilder};
+pub use tor_chanmgr::{AddressFamilyPreference, ChannelConfig, ChannelConfigBuilder};
 pub use tor_config::convert_helper_via_multi_line_list_builder;
 pub use tor_config::impl_standard_builder;
 pub use tor_config::list_builder::{MultilineListBuilder, MultilineListBuilderError};
@@ -64,7 +64,10 @@ pub mod onion_service {
 
 /// Types for configuring vanguards.
 pub mod vanguards {
-    pub use tor_guardmgr::{VanguardConfig, VanguardConfigBuilder};
+    pub use tor_guardmgr::{
+        VanguardConfig, VanguardConfigBuilder, VanguardLayerRequirements,
+        VanguardLayerRequirementsBuilder,
+    };
 }
 
 /// Configuration for client behavior relating to addresses.
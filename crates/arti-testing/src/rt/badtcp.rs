@@ -229,6 +229,13 @@ impl<S: StreamOps> StreamOps for BreakableTcpStream<S> {
         }
     }
 
+    fn set_ip_tos(&self, tos: u8) -> IoResult<()> {
+        match self {
+            BreakableTcpStream::Broken => Ok(()),
+            BreakableTcpStream::Present(s) => s.set_ip_tos(tos),
+        }
+    }
+
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
         match self {
             BreakableTcpStream::Broken => Box::new(NoOpStreamOpsHandle::default()),
@@ -142,6 +142,10 @@ impl<S: StreamOps> StreamOps for Counting<S> {
         self.inner.set_tcp_notsent_lowat(notsent_lowat)
     }
 
+    fn set_ip_tos(&self, tos: u8) -> IoResult<()> {
+        self.inner.set_ip_tos(tos)
+    }
+
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
         self.inner.new_handle()
     }
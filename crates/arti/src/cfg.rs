@@ -578,6 +578,8 @@ mod test {
                 // Examples exist but are not auto-testable
                 "tor_network.authorities",
                 "tor_network.fallback_caches",
+                // Unset by default, so the example can't be the default value
+                "channel.max_open_channels",
             ],
         );
 
@@ -697,6 +699,12 @@ mod test {
                 // Vanguards-specific settings
                 "vanguards",
                 "vanguards.mode",
+                "vanguards.l2_requirements",
+                "vanguards.l2_requirements.require_guard_flag",
+                "vanguards.l2_requirements.min_consensus_weight",
+                "vanguards.l3_requirements",
+                "vanguards.l3_requirements.require_guard_flag",
+                "vanguards.l3_requirements.min_consensus_weight",
             ],
         );
 
@@ -156,7 +156,7 @@ impl From<OnionServiceProxyConfigMapBuilder> for ProxyBuilderMap {
     }
 }
 
-/// A running onion service and an associated reverse proxy.
+/// A running onion service, whose connections are handled by a reverse proxy.
 ///
 /// This is what a user configures when they add an onion service to their
 /// configuration.
@@ -168,21 +168,34 @@ struct Proxy {
     svc: Arc<RunningOnionService>,
     /// The reverse proxy that accepts connections from the onion service.
     ///
-    /// This is also launched and running.
+    /// This is shared by all of our onion services,
+    /// and holds the configuration for each of them.
     proxy: Arc<OnionServiceReverseProxy>,
 }
 
 impl Proxy {
-    /// Create and launch a new onion service proxy, using a given `client`,
-    /// to handle connections according to `config`.
+    /// Create and launch a new onion service, using a given `client`,
+    /// according to `svc_cfg`, and use `proxy` to handle its connections.
+    ///
+    /// `proxy` must already have the configuration for this onion service.
     pub(crate) fn launch_new<R: Runtime>(
         client: &arti_client::TorClient<R>,
-        config: OnionServiceProxyConfig,
+        proxy: &Arc<OnionServiceReverseProxy>,
+        svc_cfg: OnionServiceConfig,
     ) -> anyhow::Result<Self> {
-        let OnionServiceProxyConfig { svc_cfg, proxy_cfg } = config;
         let nickname = svc_cfg.nickname().clone();
         let (svc, request_stream) = client.launch_onion_service(svc_cfg)?;
-        let proxy = OnionServiceReverseProxy::new(proxy_cfg);
+        let proxy = proxy.clone();
+        if let Err(e) = proxy.set_service_usage_storage(
+            &nickname,
+            client.onion_service_storage_handle(&nickname, "proxy_usage"),
+        ) {
+            warn_report!(
+                e,
+                "Unable to load the saved bandwidth usage of onion service {}",
+                nickname
+            );
+        }
 
         {
             let proxy = proxy.clone();
@@ -240,8 +253,9 @@ impl Proxy {
     ) -> Result<(), ReconfigureError> {
         let OnionServiceProxyConfig { svc_cfg, proxy_cfg } = config;
 
+        let nickname = svc_cfg.nickname().clone();
         self.svc.reconfigure(svc_cfg, how)?;
-        self.proxy.reconfigure(proxy_cfg, how)?;
+        self.proxy.reconfigure_service(nickname, proxy_cfg, how)?;
 
         Ok(())
     }
@@ -252,6 +266,8 @@ impl Proxy {
 pub(crate) struct ProxySet<R: Runtime> {
     /// The arti_client that we use to launch proxies.
     client: arti_client::TorClient<R>,
+    /// The reverse proxy that handles the connections for all of our onion services.
+    proxy: Arc<OnionServiceReverseProxy>,
     /// The proxies themselves, indexed by nickname.
     proxies: Mutex<BTreeMap<HsNickname, Proxy>>,
 }
@@ -262,13 +278,22 @@ impl<R: Runtime> ProxySet<R> {
         client: &arti_client::TorClient<R>,
         config_list: OnionServiceProxyConfigMap,
     ) -> anyhow::Result<Self> {
+        let proxy = OnionServiceReverseProxy::for_services(
+            config_list
+                .iter()
+                .map(|(nickname, cfg)| (nickname.clone(), cfg.proxy_cfg.clone())),
+        );
         let proxies: BTreeMap<_, _> = config_list
             .into_iter()
-            .map(|(nickname, cfg)| Ok((nickname, Proxy::launch_new(client, cfg)?)))
+            .map(|(nickname, cfg)| {
+                let svc = Proxy::launch_new(client, &proxy, cfg.svc_cfg)?;
+                Ok((nickname, svc))
+            })
             .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
 
         Ok(Self {
             client: client.clone(),
+            proxy,
             proxies: Mutex::new(proxies),
         })
     }
@@ -306,12 +331,19 @@ impl<R: Runtime> ProxySet<R> {
                 Entry::Vacant(ent) => {
                     // We do not have a proxy by this name, so we try to launch
                     // one.
-                    match Proxy::launch_new(&self.client, cfg) {
+                    let OnionServiceProxyConfig { svc_cfg, proxy_cfg } = cfg;
+                    self.proxy.reconfigure_service(
+                        ent.key().clone(),
+                        proxy_cfg,
+                        Reconfigure::WarnOnFailures,
+                    )?;
+                    match Proxy::launch_new(&self.client, &self.proxy, svc_cfg) {
                         Ok(new_proxy) => {
                             ent.insert(new_proxy);
                         }
                         Err(err) => {
                             warn_report!(err, "Unable to launch onion service {}", ent.key());
+                            let _: Option<ProxyConfig> = self.proxy.remove_service(ent.key());
                         }
                     }
                 }
@@ -324,8 +356,10 @@ impl<R: Runtime> ProxySet<R> {
             let defunct_proxy = proxy_map
                 .remove(&nickname)
                 .expect("Somehow a proxy disappeared from the map");
-            // This "drop" should shut down the proxy.
+            // This "drop" should shut down the onion service,
+            // and with it, the proxy's handling of its requests.
             drop(defunct_proxy);
+            let _: Option<ProxyConfig> = self.proxy.remove_service(&nickname);
         }
 
         Ok(())
@@ -1,21 +1,25 @@
 //! Implement a concrete type to build channels over a transport.
 
+use std::future::Future;
 use std::io;
 use std::sync::{Arc, Mutex};
 
 use crate::factory::{BootstrapReporter, ChannelFactory, IncomingChannelFactory};
 use crate::transport::TransportImplHelper;
-use crate::{Error, event::ChanMgrEventSender};
+use crate::{ChanCloseReason, ChannelConfig, Error, event::ChanBuildProgress};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tor_error::internal;
-use tor_linkspec::{BridgeAddr, HasChanMethod, IntoOwnedChanTarget, OwnedChanTarget};
+use tor_linkspec::{
+    BridgeAddr, ChannelMethod, HasChanMethod, IntoOwnedChanTarget, OwnedChanTarget,
+};
 use tor_proto::channel::kist::KistParams;
 use tor_proto::channel::params::ChannelPaddingInstructionsUpdates;
 use tor_proto::memquota::ChannelAccount;
-use tor_rtcompat::{Runtime, TlsProvider, tls::TlsConnector};
+use tor_rtcompat::{Runtime, SleepProvider as _, TlsProvider, tls::TlsConnector};
 
 use async_trait::async_trait;
+use futures::FutureExt as _;
 use futures::task::SpawnExt;
 
 /// TLS-based channel builder.
@@ -29,7 +33,7 @@ use futures::task::SpawnExt;
 /// TLS session.
 ///
 /// This channel builder does not retry on failure, but it _does_ implement a
-/// time-out.
+/// time-out for each stage of building a channel.
 pub struct ChanBuilder<R: Runtime, H: TransportImplHelper>
 where
     R: tor_rtcompat::TlsProvider<H::Stream>,
@@ -40,6 +44,10 @@ where
     transport: H,
     /// Object to build TLS connections.
     tls_connector: <R as TlsProvider<H::Stream>>::Connector,
+    /// The longest we allow each stage of building a channel to take.
+    ///
+    /// This is shared with the [`ChanMgr`](crate::ChanMgr), which updates it on reconfiguration.
+    timeouts: Arc<Mutex<StageTimeouts>>,
 }
 
 impl<R: Runtime, H: TransportImplHelper> ChanBuilder<R, H>
@@ -47,15 +55,89 @@ where
     R: TlsProvider<H::Stream>,
 {
     /// Construct a new ChanBuilder.
+    ///
+    /// It uses the default [`ChannelConfig`] timeouts for each stage of building a channel.
     pub fn new(runtime: R, transport: H) -> Self {
+        let timeouts = Arc::new(Mutex::new(StageTimeouts::default()));
+        Self::new_with_timeouts(runtime, transport, timeouts)
+    }
+
+    /// Construct a new ChanBuilder that takes its stage timeouts from `timeouts`.
+    ///
+    /// Use [`ChanMgr::stage_timeouts`](crate::ChanMgr::stage_timeouts)
+    /// to get the timeouts that a `ChanMgr` uses,
+    /// so that this builder follows the `ChanMgr`'s configuration.
+    pub fn new_with_timeouts(
+        runtime: R,
+        transport: H,
+        timeouts: Arc<Mutex<StageTimeouts>>,
+    ) -> Self {
         let tls_connector = <R as TlsProvider<H::Stream>>::tls_connector(&runtime);
         ChanBuilder {
             runtime,
             transport,
             tls_connector,
+            timeouts,
+        }
+    }
+}
+
+/// The longest we allow building a channel to take, across all of its stages.
+///
+/// (Channels through a pluggable transport or proxy get twice as long.)
+pub(crate) const OVERALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest we allow building a channel, and each stage of building it, to take.
+///
+/// Each stage may have its own timeout, so that we can notice quickly if a relay
+/// stops responding partway through a handshake.
+/// Whatever the stage timeouts, the whole build must finish within an overall limit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StageTimeouts {
+    /// For building the whole channel.
+    overall: Duration,
+    /// For connecting to the relay (or to the pluggable transport or proxy).
+    connect: Duration,
+    /// For the TLS handshake.
+    tls_handshake: Duration,
+    /// For the Tor handshake, including authenticating the relay.
+    tor_handshake: Duration,
+}
+
+impl StageTimeouts {
+    /// Return the stage timeouts configured in `config`.
+    pub(crate) fn from_config(config: &ChannelConfig) -> Self {
+        StageTimeouts {
+            overall: OVERALL_TIMEOUT,
+            connect: config.connect_timeout,
+            tls_handshake: config.tls_handshake_timeout,
+            tor_handshake: config.tor_handshake_timeout,
+        }
+    }
+
+    /// Return the timeouts to use when connecting with `method`.
+    ///
+    /// Connections that aren't direct go through a pluggable transport or a proxy,
+    /// so we give them twice as long.
+    fn for_method(&self, method: &ChannelMethod) -> Self {
+        if method.is_direct() {
+            *self
+        } else {
+            StageTimeouts {
+                overall: self.overall.saturating_mul(2),
+                connect: self.connect.saturating_mul(2),
+                tls_handshake: self.tls_handshake.saturating_mul(2),
+                tor_handshake: self.tor_handshake.saturating_mul(2),
+            }
         }
     }
 }
+
+impl Default for StageTimeouts {
+    fn default() -> Self {
+        Self::from_config(&ChannelConfig::default())
+    }
+}
 #[async_trait]
 impl<R: Runtime, H: TransportImplHelper> ChannelFactory for ChanBuilder<R, H>
 where
@@ -68,22 +150,14 @@ where
         reporter: BootstrapReporter,
         memquota: ChannelAccount,
     ) -> crate::Result<Arc<tor_proto::channel::Channel>> {
-        use tor_rtcompat::SleepProviderExt;
+        let timeouts = self
+            .timeouts
+            .lock()
+            .expect("Lock poisoned")
+            .for_method(&target.chan_method());
 
-        // TODO: make this an option.  And make a better value.
-        let delay = if target.chan_method().is_direct() {
-            std::time::Duration::new(5, 0)
-        } else {
-            std::time::Duration::new(10, 0)
-        };
-
-        let connect_future = self.connect_no_timeout(target, reporter.0, memquota);
-        self.runtime
-            .timeout(delay, connect_future)
+        self.connect_in_stages(target, &reporter, memquota, timeouts)
             .await
-            .map_err(|_| Error::ChanTimeout {
-                peer: target.to_logged(),
-            })?
     }
 }
 
@@ -124,23 +198,60 @@ where
     R: tor_rtcompat::TlsProvider<H::Stream> + Send + Sync,
     H: Send + Sync,
 {
-    /// Perform the work of `connect_via_transport`, but without enforcing a timeout.
-    async fn connect_no_timeout(
+    /// Run `fut`, the stage of building a channel to `target` that follows `reached`.
+    ///
+    /// Fail with [`Error::ChanTimeout`] if it takes longer than `timeout`,
+    /// or if it is still running at `deadline`.
+    async fn run_stage<T>(
+        &self,
+        target: &OwnedChanTarget,
+        reached: ChanBuildProgress,
+        timeout: Duration,
+        deadline: Instant,
+        fut: impl Future<Output = crate::Result<T>>,
+    ) -> crate::Result<T> {
+        use tor_rtcompat::SleepProviderExt;
+
+        let timeout = timeout.min(deadline.saturating_duration_since(self.runtime.now()));
+        self.runtime
+            .timeout(timeout, fut)
+            .await
+            .map_err(|_| Error::ChanTimeout {
+                peer: target.to_logged(),
+                reached,
+            })?
+    }
+
+    /// Perform the work of `connect_via_transport`,
+    /// giving up if the whole build, or any stage of it, takes longer than `timeouts` allows.
+    async fn connect_in_stages(
         &self,
         target: &OwnedChanTarget,
-        event_sender: Arc<Mutex<ChanMgrEventSender>>,
+        reporter: &BootstrapReporter,
         memquota: ChannelAccount,
+        timeouts: StageTimeouts,
     ) -> crate::Result<Arc<tor_proto::channel::Channel>> {
         use tor_proto::channel::ChannelBuilder;
         use tor_rtcompat::tls::CertifiedConn;
 
+        let event_sender = &reporter.events;
+        let deadline = self.runtime.now() + timeouts.overall;
+
         {
             event_sender.lock().expect("Lock poisoned").record_attempt();
         }
 
         // 1a. Negotiate the TCP connection or other stream.
 
-        let (using_target, stream) = self.transport.connect(target).await?;
+        let (using_target, stream) = self
+            .run_stage(
+                target,
+                ChanBuildProgress::Launched,
+                timeouts.connect,
+                deadline,
+                self.transport.connect(target),
+            )
+            .await?;
         let using_method = using_target.chan_method();
         let peer = using_target.chan_method().target_addr();
         let peer_ref = &peer;
@@ -166,19 +277,35 @@ where
                 .expect("Lock poisoned")
                 .record_tcp_success();
         }
+        reporter.record_progress(ChanBuildProgress::TcpConnected);
 
         // 1b. Negotiate TLS.
 
         // TODO: add a random hostname here if it will be used for SNI?
         let tls = self
-            .tls_connector
-            .negotiate_unvalidated(stream, "ignored")
-            .await
-            .map_err(map_ioe("TLS negotiation"))?;
+            .run_stage(
+                target,
+                ChanBuildProgress::TcpConnected,
+                timeouts.tls_handshake,
+                deadline,
+                async {
+                    self.tls_connector
+                        .negotiate_unvalidated(stream, "ignored")
+                        .await
+                        .map_err(map_ioe("TLS negotiation"))
+                },
+            )
+            .await?;
 
         let peer_cert = tls
             .peer_certificate()
-            .map_err(map_ioe("TLS certs"))?
+            .map_err(|ioe| Error::PeerCertificate {
+                peer: peer_ref
+                    .as_ref()
+                    .and_then(|peer| Option::<BridgeAddr>::from(peer.clone()))
+                    .map(Into::into),
+                source: ioe.into(),
+            })?
             .ok_or_else(|| Error::Internal(internal!("TLS connection with no peer certificate")))?;
 
         {
@@ -187,42 +314,54 @@ where
                 .expect("Lock poisoned")
                 .record_tls_finished();
         }
+        reporter.record_progress(ChanBuildProgress::TlsFinished);
 
         // 2. Set up the channel.
-        let mut builder = ChannelBuilder::new();
-        builder.set_declared_method(using_method);
-        let chan = builder
-            .launch(
-                tls,
-                self.runtime.clone(), /* TODO provide ZST SleepProvider instead */
-                memquota,
+        let handshake = async {
+            let mut builder = ChannelBuilder::new();
+            builder.set_declared_method(using_method);
+            let chan = builder
+                .launch(
+                    tls,
+                    self.runtime.clone(), /* TODO provide ZST SleepProvider instead */
+                    memquota,
+                )
+                .connect(|| self.runtime.wallclock())
+                .await
+                .map_err(|e| Error::from_proto_no_skew(e, &using_target))?;
+            let clock_skew = Some(chan.clock_skew()); // Not yet authenticated; can't use it till `check` is done.
+            let now = self.runtime.wallclock();
+            let chan =
+                chan.check(target, &peer_cert, Some(now))
+                    .map_err(|source| match &source {
+                        tor_proto::Error::HandshakeCertsExpired { .. } => {
+                            event_sender
+                                .lock()
+                                .expect("Lock poisoned")
+                                .record_handshake_done_with_skewed_clock();
+                            Error::Proto {
+                                source,
+                                peer: using_target.to_logged(),
+                                clock_skew,
+                            }
+                        }
+                        _ => Error::from_proto_no_skew(source, &using_target),
+                    })?;
+            chan.finish().await.map_err(|source| Error::Proto {
+                source,
+                peer: target.to_logged(),
+                clock_skew,
+            })
+        };
+        let (chan, reactor) = self
+            .run_stage(
+                target,
+                ChanBuildProgress::TlsFinished,
+                timeouts.tor_handshake,
+                deadline,
+                handshake,
             )
-            .connect(|| self.runtime.wallclock())
-            .await
-            .map_err(|e| Error::from_proto_no_skew(e, &using_target))?;
-        let clock_skew = Some(chan.clock_skew()); // Not yet authenticated; can't use it till `check` is done.
-        let now = self.runtime.wallclock();
-        let chan = chan
-            .check(target, &peer_cert, Some(now))
-            .map_err(|source| match &source {
-                tor_proto::Error::HandshakeCertsExpired { .. } => {
-                    event_sender
-                        .lock()
-                        .expect("Lock poisoned")
-                        .record_handshake_done_with_skewed_clock();
-                    Error::Proto {
-                        source,
-                        peer: using_target.to_logged(),
-                        clock_skew,
-                    }
-                }
-                _ => Error::from_proto_no_skew(source, &using_target),
-            })?;
-        let (chan, reactor) = chan.finish().await.map_err(|source| Error::Proto {
-            source,
-            peer: target.to_logged(),
-            clock_skew,
-        })?;
+            .await?;
 
         {
             event_sender
@@ -230,6 +369,7 @@ where
                 .expect("Lock poisoned")
                 .record_handshake_done();
         }
+        reporter.record_progress(ChanBuildProgress::Authenticated);
 
         // 3. Launch a task to run the channel reactor.
         self.runtime
@@ -245,6 +385,16 @@ impl crate::mgr::AbstractChannel for tor_proto::channel::Channel {
     fn is_usable(&self) -> bool {
         !self.is_closing()
     }
+    fn close_reason(&self) -> Option<ChanCloseReason> {
+        use tor_proto::channel::ClosedUnexpectedly;
+        let outcome = self.wait_for_close().now_or_never()?;
+        Some(match outcome {
+            Ok(info) if info.closed_by_peer() => ChanCloseReason::PeerClosed,
+            Ok(_) => ChanCloseReason::LocalShutdown,
+            Err(ClosedUnexpectedly::ReactorError(_)) => ChanCloseReason::ProtocolError,
+            Err(_) => ChanCloseReason::LocalShutdown,
+        })
+    }
     fn duration_unused(&self) -> Option<Duration> {
         self.duration_unused()
     }
@@ -260,6 +410,22 @@ impl crate::mgr::AbstractChannel for tor_proto::channel::Channel {
     fn engage_padding_activities(&self) {
         tor_proto::channel::Channel::engage_padding_activities(self);
     }
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.target()
+            .chan_method()
+            .socket_addrs()
+            .and_then(|addrs| addrs.first().copied())
+    }
+    fn set_traffic_class(&self, class: crate::TrafficClass) {
+        // If this fails, the channel is closed, so its class doesn't matter.
+        let _ = tor_proto::channel::Channel::set_traffic_class(self, class);
+    }
+    fn padding_counts(&self) -> tor_proto::channel::padding::PaddingCounts {
+        tor_proto::channel::Channel::padding_counts(self)
+    }
+    fn terminate(&self) {
+        tor_proto::channel::Channel::terminate(self);
+    }
 }
 
 #[cfg(test)]
@@ -279,18 +445,19 @@ mod test {
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
     use crate::{
-        Result,
+        ChanFailureClass, Result,
         mgr::{AbstractChannel, AbstractChannelFactory},
     };
     use futures::StreamExt as _;
     use std::net::SocketAddr;
+    use std::task::Poll;
     use std::time::{Duration, SystemTime};
     use tor_linkspec::{ChannelMethod, HasRelayIds, RelayIdType};
     use tor_llcrypto::pk::ed25519::Ed25519Identity;
     use tor_llcrypto::pk::rsa::RsaIdentity;
     use tor_proto::channel::Channel;
     use tor_proto::memquota::{ChannelAccount, SpecificAccount as _};
-    use tor_rtcompat::{NetStreamListener, test_with_one_runtime};
+    use tor_rtcompat::{NetStreamListener, SleepProvider as _, test_with_one_runtime};
     use tor_rtmock::{io::LocalStream, net::MockNetwork};
 
     #[allow(deprecated)] // TODO #1885
@@ -343,7 +510,10 @@ mod test {
             client_rt.jump_to(now);
 
             // Create the channel builder that we want to test.
-            let transport = crate::transport::DefaultTransport::new(client_rt.clone());
+            let transport = crate::transport::DefaultTransport::new(
+                client_rt.clone(),
+                crate::AddressFamilyPreference::default(),
+            );
             let builder = ChanBuilder::new(client_rt, transport);
 
             let (r1, r2): (Result<Arc<Channel>>, Result<LocalStream>) = futures::join!(
@@ -390,5 +560,98 @@ mod test {
         })
     }
 
-    // TODO: Write tests for timeout logic, once there is smarter logic.
+    #[test]
+    fn stage_timeouts() {
+        let mut config = crate::ChannelConfigBuilder::default();
+        config
+            .connect_timeout(Duration::from_secs(3))
+            .tls_handshake_timeout(Duration::from_secs(4))
+            .tor_handshake_timeout(Duration::from_secs(7));
+        let timeouts = StageTimeouts::from_config(&config.build().unwrap());
+        assert_eq!(timeouts.connect, Duration::from_secs(3));
+        assert_eq!(timeouts.tls_handshake, Duration::from_secs(4));
+        assert_eq!(timeouts.tor_handshake, Duration::from_secs(7));
+        assert_eq!(timeouts.overall, OVERALL_TIMEOUT);
+
+        let orport: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+        let direct = timeouts.for_method(&ChannelMethod::Direct(vec![orport]));
+        assert_eq!(direct, timeouts);
+    }
+
+    #[test]
+    fn stage_timeout_fires() {
+        let target = OwnedChanTarget::builder()
+            .addrs(vec![])
+            .method(ChannelMethod::Direct(vec![]))
+            .build()
+            .unwrap();
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let network = MockNetwork::new();
+            let client_rt = network.builder().runtime(rt.clone());
+            let transport = crate::transport::DefaultTransport::new(
+                client_rt.clone(),
+                crate::AddressFamilyPreference::default(),
+            );
+            let builder = ChanBuilder::new(client_rt, transport);
+            let far_future = rt.now() + Duration::from_secs(3600);
+
+            // A stage that never finishes times out, recording how far we got.
+            let stage = builder.run_stage(
+                &target,
+                ChanBuildProgress::TlsFinished,
+                Duration::from_secs(5),
+                far_future,
+                futures::future::pending::<Result<()>>(),
+            );
+            let (result, _) = futures::join!(stage, rt.advance_by(Duration::from_secs(6)));
+            let err = result.unwrap_err();
+            assert!(matches!(
+                err,
+                Error::ChanTimeout {
+                    reached: ChanBuildProgress::TlsFinished,
+                    ..
+                }
+            ));
+            assert_eq!(err.failure_class(), ChanFailureClass::HandshakeTimeout);
+
+            // If we never connected, the relay was unreachable.
+            let stage = builder.run_stage(
+                &target,
+                ChanBuildProgress::Launched,
+                Duration::from_secs(5),
+                far_future,
+                futures::future::pending::<Result<()>>(),
+            );
+            let (result, _) = futures::join!(stage, rt.advance_by(Duration::from_secs(6)));
+            assert_eq!(
+                result.unwrap_err().failure_class(),
+                ChanFailureClass::TcpUnreachable
+            );
+
+            // Whatever its own limit, a stage can't run past the overall deadline.
+            let deadline = rt.now() + Duration::from_secs(2);
+            let mut stage = Box::pin(builder.run_stage(
+                &target,
+                ChanBuildProgress::TcpConnected,
+                Duration::from_secs(5),
+                deadline,
+                futures::future::pending::<Result<()>>(),
+            ));
+            assert!(futures::poll!(&mut stage).is_pending());
+            rt.advance_by(Duration::from_secs(1)).await;
+            assert!(futures::poll!(&mut stage).is_pending());
+            rt.advance_by(Duration::from_secs(1)).await;
+            let Poll::Ready(Err(err)) = futures::poll!(&mut stage) else {
+                panic!("stage didn't time out at the deadline");
+            };
+            assert!(matches!(
+                err,
+                Error::ChanTimeout {
+                    reached: ChanBuildProgress::TcpConnected,
+                    ..
+                }
+            ));
+        });
+    }
 }
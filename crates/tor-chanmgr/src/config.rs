@@ -9,6 +9,8 @@ use tor_config::{ConfigBuildError, PaddingLevel};
 
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::time::Duration;
 
 /// Channel configuration
 ///
@@ -21,9 +23,84 @@ pub struct ChannelConfig {
     /// Control of channel padding
     #[builder(default)]
     pub(crate) padding: PaddingLevel,
+
+    /// Which address family to try first, when connecting to a relay
+    #[builder(default)]
+    pub(crate) preferred_address_family: AddressFamilyPreference,
+
+    /// The largest number of channels that we will have open (or opening) at once
+    ///
+    /// When we need a new channel and we are at this limit,
+    /// we close the channel that has been idle for longest.
+    /// If none of our channels are idle, we don't open the new channel.
+    ///
+    /// `None` means that there is no limit.
+    #[builder(default)]
+    pub(crate) max_open_channels: Option<NonZeroUsize>,
+
+    /// How long to wait for a TCP connection to a relay
+    /// (or for a connection through a pluggable transport or proxy)
+    ///
+    /// Whatever the limits on each stage, building a channel may take
+    /// no more than 5 seconds altogether;
+    /// so by default, only that overall limit applies.
+    /// Setting a stage's limit lower lets us give up sooner on a relay
+    /// that stops responding partway through.
+    ///
+    /// When we are connecting through a pluggable transport or proxy,
+    /// we allow twice as long for this stage, for each of the following ones,
+    /// and altogether.
+    #[builder(default = "default_connect_timeout()")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) connect_timeout: Duration,
+
+    /// How long to wait for the TLS handshake with a relay, once we are connected to it
+    #[builder(default = "default_tls_handshake_timeout()")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) tls_handshake_timeout: Duration,
+
+    /// How long to wait for the Tor handshake with a relay,
+    /// including authenticating it, once the TLS handshake is done
+    #[builder(default = "default_tor_handshake_timeout()")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    pub(crate) tor_handshake_timeout: Duration,
 }
 impl_standard_builder! { ChannelConfig }
 
+/// Return the default value for `connect_timeout`.
+fn default_connect_timeout() -> Duration {
+    crate::builder::OVERALL_TIMEOUT
+}
+
+/// Return the default value for `tls_handshake_timeout`.
+fn default_tls_handshake_timeout() -> Duration {
+    crate::builder::OVERALL_TIMEOUT
+}
+
+/// Return the default value for `tor_handshake_timeout`.
+fn default_tor_handshake_timeout() -> Duration {
+    crate::builder::OVERALL_TIMEOUT
+}
+
+/// Which address family to try first, when connecting to a relay that has several addresses
+///
+/// Whatever our preference, we race connections to all of a relay's addresses,
+/// in the style of RFC 8305 "happy eyeballs":
+/// this only controls which addresses get a head start.
+/// (An address that has worked for a relay before gets a head start regardless.)
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum AddressFamilyPreference {
+    /// Try the relay's addresses in the order it lists them
+    #[default]
+    Any,
+    /// Try IPv4 addresses first
+    Ipv4,
+    /// Try IPv6 addresses first
+    Ipv6,
+}
+
 #[cfg(feature = "testing")]
 impl ChannelConfig {
     /// The padding level (accessor for testing)
@@ -54,5 +131,14 @@ mod test {
         let config = ChannelConfig::default();
 
         assert_eq!(PaddingLevel::Normal, config.padding);
+        assert_eq!(
+            AddressFamilyPreference::Any,
+            config.preferred_address_family
+        );
+        assert_eq!(None, config.max_open_channels);
+        // By default, each stage may take as long as the whole build.
+        assert_eq!(Duration::from_secs(5), config.connect_timeout);
+        assert_eq!(Duration::from_secs(5), config.tls_handshake_timeout);
+        assert_eq!(Duration::from_secs(5), config.tor_handshake_timeout);
     }
 }
@@ -6,7 +6,9 @@ use std::sync::Arc;
 use futures::task::SpawnError;
 use thiserror::Error;
 
+use crate::event::ChanBuildProgress;
 use crate::factory::AbstractPtError;
+use crate::mgr::UniqPendingChanId;
 use tor_error::{ErrorKind, internal};
 use tor_linkspec::{BridgeAddr, ChanTarget, IntoOwnedChanTarget, LoggedChanTarget};
 use tor_proto::ClockSkew;
@@ -35,10 +37,15 @@ pub enum Error {
     },
 
     /// It took too long for us to establish this connection.
-    #[error("Channel for {peer} timed out")]
+    #[error("Channel for {peer} timed out after reaching stage {reached:?}")]
+    #[non_exhaustive]
     ChanTimeout {
         /// Who we were trying to talk to
         peer: LoggedChanTarget,
+        /// The last stage of building the channel that we completed
+        ///
+        /// The stage after this one is the one that took too long.
+        reached: ChanBuildProgress,
     },
 
     /// A protocol error while making a channel
@@ -68,6 +75,17 @@ pub enum Error {
         source: Arc<std::io::Error>,
     },
 
+    /// We negotiated TLS with a relay, but couldn't get the certificate it presented.
+    #[error("Unable to get the TLS certificate of {peer:?}")]
+    PeerCertificate {
+        /// Who we were talking to
+        peer: Option<BoxChanSensitive<BridgeAddr>>,
+
+        /// What happened.  Probably some TLS library error wrapped up in io::Error
+        #[source]
+        source: Arc<std::io::Error>,
+    },
+
     /// Failed to build a channel, after trying multiple addresses.
     #[error("Channel build failed: [(address, error)] = {addresses:?}")]
     ChannelBuild {
@@ -101,6 +119,14 @@ pub enum Error {
     #[error("Relay identity keys were only a partial match for what we wanted.")]
     IdentityConflict,
 
+    /// We needed a new channel, but we already had as many channels as we are configured
+    /// to allow, and none of them was idle.
+    #[error("Reached our limit of {limit} open channels, and none of them are idle")]
+    TooManyChannels {
+        /// The configured limit.
+        limit: usize,
+    },
+
     /// Tried to connect via a transport that we don't support.
     #[error("No plugin available for the transport {0}")]
     NoSuchTransport(tor_linkspec::TransportId),
@@ -123,6 +149,20 @@ pub enum Error {
     #[error("Pluggable transport error: {0}")]
     Pt(#[source] Arc<dyn AbstractPtError>),
 
+    /// An attempt to build a channel failed.
+    ///
+    /// Every error from building a channel is wrapped in one of these,
+    /// so that it can be matched up with the log messages from the same attempt.
+    /// Use [`Error::without_build_attempt`] to find the underlying error.
+    #[error("{attempt} failed")]
+    BuildAttempt {
+        /// The ID of the failed attempt.
+        attempt: UniqPendingChanId,
+        /// What went wrong.
+        #[source]
+        cause: Box<Error>,
+    },
+
     /// Memory quota error
     #[error("memory quota error")]
     Memquota(#[from] tor_memquota::Error),
@@ -132,6 +172,48 @@ pub enum Error {
     Internal(#[from] tor_error::Bug),
 }
 
+/// A coarse classification of why we failed to build a channel.
+///
+/// This is meant for code (such as a circuit manager) that needs to react differently
+/// to different kinds of failure: for example, by penalizing a guard that presented
+/// the wrong identity, but not one that merely timed out.
+///
+/// See [`Error::failure_class`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, derive_more::Display)]
+pub enum ChanFailureClass {
+    /// We couldn't connect to the relay, or the connection broke while we were using it.
+    ///
+    /// This includes failures to connect via a proxy,
+    /// timeouts before the connection was made,
+    /// and I/O errors during the TLS negotiation.
+    #[display("relay unreachable")]
+    TcpUnreachable,
+    /// The relay's certificates were invalid, expired,
+    /// or didn't certify the key of its TLS connection.
+    ///
+    /// If the certificates were expired, this might be due to our clock being wrong:
+    /// see [`Error::clock_skew`].
+    #[display("TLS certificate mismatch")]
+    TlsCertMismatch,
+    /// We connected to the relay, but didn't manage to establish the channel in time.
+    #[display("handshake timed out")]
+    HandshakeTimeout,
+    /// The relay completed the handshake,
+    /// but didn't have the identity we wanted.
+    #[display("authentication failure")]
+    AuthenticationFailure,
+    /// The relay violated the channel protocol.
+    #[display("protocol violation")]
+    ProtocolViolation,
+    /// The failure was on our side, and says nothing about the relay.
+    ///
+    /// This includes configuration errors, resource exhaustion,
+    /// cancelled requests, and internal errors.
+    #[display("local failure")]
+    Local,
+}
+
 impl<T> From<std::sync::PoisonError<T>> for Error {
     fn from(_: std::sync::PoisonError<T>) -> Error {
         Error::Internal(internal!("Thread failed while holding lock"))
@@ -158,6 +240,7 @@ impl tor_error::HasKind for Error {
         match self {
             E::ChanTimeout { .. }
             | E::Io { .. }
+            | E::PeerCertificate { .. }
             | E::Proto {
                 source: ProtoErr::ChanIoErr(_),
                 ..
@@ -171,9 +254,11 @@ impl tor_error::HasKind for Error {
             E::IdentityConflict => EK::TorAccessFailed,
             E::ChannelBuild { .. } => EK::TorAccessFailed,
             E::RequestCancelled => EK::TransientFailure,
+            E::TooManyChannels { .. } => EK::LocalResourceExhausted,
             E::Proxy(e) => e.kind(),
             E::Memquota(e) => e.kind(),
             E::Pt(e) => e.kind(),
+            E::BuildAttempt { cause, .. } => cause.kind(),
         }
     }
 }
@@ -190,9 +275,13 @@ impl tor_error::HasRetryTime for Error {
             //
             // TODO: Someday we might want to distinguish among different kinds of IO
             // errors.
-            E::PendingFailed { .. } | E::Proto { .. } | E::Io { .. } => RT::AfterWaiting,
+            E::PendingFailed { .. }
+            | E::Proto { .. }
+            | E::Io { .. }
+            | E::PeerCertificate { .. } => RT::AfterWaiting,
 
             // Delegate.
+            E::BuildAttempt { cause, .. } => cause.retry_time(),
             E::Proxy(e) => e.retry_time(),
             E::Pt(e) => e.retry_time(),
 
@@ -216,6 +305,9 @@ impl tor_error::HasRetryTime for Error {
 
             E::RequestCancelled => RT::Immediate,
 
+            // Wait for one of our channels to become idle.
+            E::TooManyChannels { .. } => RT::AfterWaiting,
+
             // Hopefully the problem will pass!
             E::Memquota { .. } => RT::AfterWaiting,
 
@@ -255,9 +347,82 @@ impl Error {
     ///
     /// Only returns the clock skew information if it is authenticated.
     pub fn clock_skew(&self) -> Option<ClockSkew> {
-        match self {
+        match self.without_build_attempt() {
             Error::Proto { clock_skew, .. } => *clock_skew,
             _ => None,
         }
     }
+
+    /// Return a coarse classification of why we failed to build a channel.
+    ///
+    /// Every request that was waiting for the same pending channel
+    /// gets a copy of the same error, and therefore the same classification.
+    pub fn failure_class(&self) -> ChanFailureClass {
+        use ChanFailureClass as FC;
+        use Error as E;
+        use tor_proto::Error as ProtoErr;
+        match self {
+            E::BuildAttempt { cause, .. } => cause.failure_class(),
+            E::ChanTimeout { reached, .. } => match reached {
+                // We never managed to connect.
+                ChanBuildProgress::Launched => FC::TcpUnreachable,
+                ChanBuildProgress::TcpConnected
+                | ChanBuildProgress::TlsFinished
+                | ChanBuildProgress::Authenticated => FC::HandshakeTimeout,
+            },
+            // The relay finished the TLS handshake, but with a certificate we couldn't read.
+            E::PeerCertificate { .. } => FC::ProtocolViolation,
+            E::Io { .. } | E::ChannelBuild { .. } | E::Proxy(_) | E::PendingFailed { .. } => {
+                FC::TcpUnreachable
+            }
+            E::Proto { source, .. } => match source {
+                ProtoErr::ChanIoErr(_) | ProtoErr::HandshakeIoErr(_) => FC::TcpUnreachable,
+                ProtoErr::HandshakeCertErr(_) | ProtoErr::HandshakeCertsExpired { .. } => {
+                    FC::TlsCertMismatch
+                }
+                ProtoErr::ChanMismatch(_) => FC::AuthenticationFailure,
+                ProtoErr::Bug(_) | ProtoErr::Memquota(_) => FC::Local,
+                _ => FC::ProtocolViolation,
+            },
+            E::IdentityConflict => FC::AuthenticationFailure,
+            E::UnusableTarget(_)
+            | E::Spawn { .. }
+            | E::MissingId
+            | E::TooManyChannels { .. }
+            | E::NoSuchTransport(_)
+            | E::RequestCancelled
+            | E::Pt(_)
+            | E::Memquota(_)
+            | E::Internal(_) => FC::Local,
+        }
+    }
+
+    /// Wrap `cause`, an error from the channel build attempt `attempt`.
+    pub(crate) fn in_build_attempt(attempt: UniqPendingChanId, cause: Error) -> Self {
+        match cause {
+            // Don't wrap an error twice.
+            e @ Error::BuildAttempt { .. } => e,
+            cause => Error::BuildAttempt {
+                attempt,
+                cause: Box::new(cause),
+            },
+        }
+    }
+
+    /// Return the ID of the channel build attempt that caused this error, if there was one.
+    pub fn build_attempt(&self) -> Option<UniqPendingChanId> {
+        match self {
+            Error::BuildAttempt { attempt, .. } => Some(*attempt),
+            _ => None,
+        }
+    }
+
+    /// Return the underlying error,
+    /// without the information about which channel build attempt it came from.
+    pub fn without_build_attempt(&self) -> &Error {
+        match self {
+            Error::BuildAttempt { cause, .. } => cause,
+            e => e,
+        }
+    }
 }
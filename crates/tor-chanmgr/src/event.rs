@@ -1,7 +1,9 @@
 //! Code for exporting events from the channel manager.
 #![allow(dead_code, unreachable_pub)]
 
+use crate::{ChanCloseReason, ChanFailureClass};
 use educe::Educe;
+use futures::channel::mpsc;
 use futures::{Stream, StreamExt};
 use postage::watch;
 use std::{
@@ -9,6 +11,10 @@ use std::{
     time::{Duration, Instant},
 };
 use tor_basic_utils::skip_fmt;
+use tor_linkspec::{HasRelayIds, RelayIdRef, RelayIdType, RelayIds};
+
+/// How many [`ChanMgrEvent`]s we queue for each subscriber before we start dropping them.
+const EVENT_QUEUE_LEN: usize = 128;
 
 /// The status of our connection to the internet.
 #[derive(Default, Debug, Clone)]
@@ -174,6 +180,162 @@ impl Stream for ConnStatusEvents {
     }
 }
 
+/// How far an attempt to build a single channel has progressed.
+///
+/// The stages are listed in the order in which they are reached.
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[non_exhaustive]
+pub enum ChanBuildProgress {
+    /// We have launched the attempt, but have not yet made a connection.
+    #[default]
+    Launched,
+    /// We have made a TCP connection (or other stream) to the alleged relay.
+    TcpConnected,
+    /// We have finished a TLS handshake with the alleged relay.
+    ///
+    /// (Its identity won't be verified till the next step.)
+    TlsFinished,
+    /// We have finished the Tor handshake, and authenticated the relay.
+    ///
+    /// The channel will be ready for use momentarily.
+    Authenticated,
+}
+
+/// A stream of [`ChanBuildProgress`] updates for a single pending channel.
+///
+/// Like [`ConnStatusEvents`], this stream is lossy: the reader will always see
+/// the most recent stage, but may miss some of the intermediate ones.
+///
+/// The stream ends once the attempt to build the channel has either succeeded or failed.
+#[derive(Clone, Educe)]
+#[educe(Debug)]
+pub struct ChanBuildProgressEvents {
+    /// The receiver that implements this stream.
+    #[educe(Debug(method = "skip_fmt"))]
+    inner: watch::Receiver<ChanBuildProgress>,
+}
+
+impl ChanBuildProgressEvents {
+    /// Wrap `inner` in a new `ChanBuildProgressEvents`.
+    pub(crate) fn new(inner: watch::Receiver<ChanBuildProgress>) -> Self {
+        Self { inner }
+    }
+
+    /// Return the most recent stage reached by this channel build attempt.
+    pub fn current(&self) -> ChanBuildProgress {
+        *self.inner.borrow()
+    }
+}
+
+impl Stream for ChanBuildProgressEvents {
+    type Item = ChanBuildProgress;
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Something that happened to one of the channels of a `ChanMgr`.
+///
+/// See [`ChanMgr::subscribe_events`](crate::ChanMgr::subscribe_events).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ChanMgrEvent {
+    /// The identities of the relay at the other end of the channel.
+    ///
+    /// For a channel that we are still building,
+    /// these are the identities that we asked for.
+    pub ids: RelayIds,
+    /// What happened.
+    pub kind: ChanMgrEventKind,
+    /// When it happened (or, for a closed channel, when we noticed).
+    pub at: Instant,
+}
+
+impl HasRelayIds for ChanMgrEvent {
+    fn identity(&self, key_type: RelayIdType) -> Option<RelayIdRef<'_>> {
+        self.ids.identity(key_type)
+    }
+}
+
+/// The kind of a [`ChanMgrEvent`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ChanMgrEventKind {
+    /// We have started trying to build a channel.
+    PendingStarted,
+    /// A channel that we were building is now open.
+    ChannelOpened,
+    /// We failed to build a channel.
+    ChannelFailed(ChanFailureClass),
+    /// An open channel has closed.
+    ///
+    /// We only notice that a channel has closed when we next look at it,
+    /// which may be some time after the channel actually closed.
+    ChannelClosed(ChanCloseReason),
+}
+
+/// A stream of [`ChanMgrEvent`]s, returned by
+/// [`ChanMgr::subscribe_events`](crate::ChanMgr::subscribe_events).
+///
+/// This stream is lossy: if the reader falls too far behind,
+/// it will miss some events.
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct ChanMgrEvents {
+    /// The receiver that implements this stream.
+    #[educe(Debug(method = "skip_fmt"))]
+    inner: mpsc::Receiver<ChanMgrEvent>,
+}
+
+impl Stream for ChanMgrEvents {
+    type Item = ChanMgrEvent;
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// The senders for every subscriber to a `ChanMgr`'s [`ChanMgrEvent`]s.
+#[derive(Default)]
+pub(crate) struct ChanMgrEventPublisher {
+    /// One sender for each [`ChanMgrEvents`] that we have handed out.
+    subscribers: Vec<mpsc::Sender<ChanMgrEvent>>,
+}
+
+impl ChanMgrEventPublisher {
+    /// Return a stream of every event published from now on.
+    pub(crate) fn subscribe(&mut self) -> ChanMgrEvents {
+        let (tx, rx) = mpsc::channel(EVENT_QUEUE_LEN);
+        self.subscribers.push(tx);
+        ChanMgrEvents { inner: rx }
+    }
+
+    /// Tell every subscriber that `kind` happened at `at` to the channel with `ids`.
+    ///
+    /// Subscribers that have gone away are forgotten;
+    /// subscribers whose queue is full miss this event.
+    pub(crate) fn publish(&mut self, ids: &RelayIds, kind: ChanMgrEventKind, at: Instant) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let event = ChanMgrEvent {
+            ids: ids.clone(),
+            kind,
+            at,
+        };
+        self.subscribers
+            .retain_mut(|tx| match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(e) => !e.is_disconnected(),
+            });
+    }
+}
+
 /// Crate-internal view of "how connected are we to the internet?"
 ///
 /// This is a more complex and costly structure than ConnStatus, so we track
@@ -3,8 +3,9 @@
 
 use std::sync::{Arc, Mutex};
 
-use crate::event::ChanMgrEventSender;
+use crate::event::{ChanBuildProgress, ChanMgrEventSender};
 use async_trait::async_trait;
+use postage::watch;
 use tor_error::{HasKind, HasRetryTime, internal};
 use tor_linkspec::{HasChanMethod, OwnedChanTarget, PtTransportName};
 use tor_proto::channel::Channel;
@@ -16,14 +17,46 @@ use tracing::debug;
 /// A future release of this crate might make this type less opaque.
 // FIXME(eta): Do that.
 #[derive(Clone)]
-pub struct BootstrapReporter(pub(crate) Arc<Mutex<ChanMgrEventSender>>);
+pub struct BootstrapReporter {
+    /// The sender we use to report our overall ability to connect to the network.
+    pub(crate) events: Arc<Mutex<ChanMgrEventSender>>,
+    /// The sender we use to report the progress of the single channel being built, if any.
+    ///
+    /// This is only set when the channel is being built on behalf of a pending
+    /// entry in the `ChanMgr`'s channel map.
+    pub(crate) progress: Option<Arc<Mutex<watch::Sender<ChanBuildProgress>>>>,
+}
 
 impl BootstrapReporter {
+    /// Create a new `BootstrapReporter` that reports to `events`.
+    pub(crate) fn new(events: Arc<Mutex<ChanMgrEventSender>>) -> Self {
+        Self {
+            events,
+            progress: None,
+        }
+    }
+
     #[cfg(test)]
     /// Create a useless version of this type to satisfy some test.
     pub(crate) fn fake() -> Self {
         let (snd, _rcv) = crate::event::channel();
-        Self(Arc::new(Mutex::new(snd)))
+        Self::new(Arc::new(Mutex::new(snd)))
+    }
+
+    /// Return a copy of this reporter that also reports the progress of a single
+    /// channel build attempt to `progress`.
+    pub(crate) fn with_progress(&self, progress: watch::Sender<ChanBuildProgress>) -> Self {
+        Self {
+            events: Arc::clone(&self.events),
+            progress: Some(Arc::new(Mutex::new(progress))),
+        }
+    }
+
+    /// Note that the channel we are building has reached the stage `progress`.
+    pub(crate) fn record_progress(&self, progress: ChanBuildProgress) {
+        if let Some(sender) = &self.progress {
+            *sender.lock().expect("Lock poisoned").borrow_mut() = progress;
+        }
     }
 }
 
@@ -237,4 +270,19 @@ impl<CF: ChannelFactory + 'static> CompoundFactory<CF> {
     pub(crate) fn replace_ptmgr(&mut self, ptmgr: Arc<dyn AbstractPtMgr + 'static>) {
         self.ptmgr = Some(ptmgr);
     }
+
+    #[cfg(feature = "pt-client")]
+    /// Return a copy of this object that uses `ptmgr` (if any) for pluggable transports.
+    pub(crate) fn with_ptmgr(&self, ptmgr: Option<Arc<dyn AbstractPtMgr + 'static>>) -> Self {
+        Self {
+            ptmgr,
+            default_factory: Arc::clone(&self.default_factory),
+        }
+    }
+
+    #[cfg(feature = "pt-client")]
+    /// Return the PtMgr in this object, if any.
+    pub(crate) fn ptmgr(&self) -> Option<&Arc<dyn AbstractPtMgr + 'static>> {
+        self.ptmgr.as_ref()
+    }
 }
@@ -58,12 +58,16 @@ pub(crate) mod util;
 use futures::StreamExt;
 use futures::select_biased;
 use futures::task::SpawnExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::result::Result as StdResult;
 use std::sync::{Arc, Weak};
-use std::time::Duration;
-use tor_config::ReconfigureError;
+use std::time::{Duration, Instant};
+use tor_config::{PaddingLevel, ReconfigureError};
 use tor_error::error_report;
-use tor_linkspec::{ChanTarget, OwnedChanTarget};
+use tor_linkspec::{
+    ChanTarget, HasAddrs as _, HasRelayIds, OwnedChanTarget, RelayIdRef, RelayIdType, RelayIds,
+};
 use tor_netdir::{NetDirProvider, params::NetParameters};
 use tor_proto::channel::Channel;
 #[cfg(feature = "experimental-api")]
@@ -72,18 +76,32 @@ use tor_proto::memquota::ToplevelAccount;
 use tracing::debug;
 use void::{ResultVoidErrExt, Void};
 
-pub use err::Error;
+pub use err::{ChanFailureClass, Error};
+pub use mgr::{FactoryGeneration, PaddingStats, SuspectChannel, UniqPendingChanId};
 
-pub use config::{ChannelConfig, ChannelConfigBuilder};
+pub use config::{AddressFamilyPreference, ChannelConfig, ChannelConfigBuilder};
 
-use tor_rtcompat::Runtime;
+/// The kind of traffic that a channel is expected to carry.
+///
+/// A `TrafficClass` is given along with each request for a channel.
+/// The channel manager remembers the most latency-sensitive class
+/// that each channel has been requested for,
+/// and tells the channel about it,
+/// so that the channel can mark its packets with a suitable DSCP value.
+pub use tor_proto::channel::traffic_class::TrafficClass;
+
+use tor_rtcompat::{DynTimeProvider, Runtime};
 
 /// A Result as returned by this crate.
 pub type Result<T> = std::result::Result<T, Error>;
 
 use crate::factory::BootstrapReporter;
-pub use event::{ConnBlockage, ConnStatus, ConnStatusEvents};
+pub use event::{
+    ChanBuildProgress, ChanBuildProgressEvents, ChanMgrEvent, ChanMgrEventKind, ChanMgrEvents,
+    ConnBlockage, ConnStatus, ConnStatusEvents,
+};
 use tor_rtcompat::scheduler::{TaskHandle, TaskSchedule};
+use tor_rtcompat::task::registry::{RegisteredTask, TaskRegistry};
 
 /// An object that remembers a set of live channels, and launches new ones on
 /// request.
@@ -129,11 +147,51 @@ pub struct ChanMgr<R: Runtime> {
     /// Stream of [`ConnStatus`] events.
     bootstrap_status: event::ConnStatusEvents,
 
-    /// This currently isn't actually used, but we're keeping a PhantomData here
-    /// since probably we'll want it again, sooner or later.
-    runtime: std::marker::PhantomData<fn(R) -> R>,
+    /// The transport used by the default factory in `mgr`.
+    ///
+    /// We keep a handle to it here so that we can reconfigure it.
+    default_transport: transport::DefaultTransport<R>,
+
+    /// The stage timeouts used by the default factory in `mgr`.
+    ///
+    /// We keep a handle to them here so that we can reconfigure them.
+    stage_timeouts: Arc<std::sync::Mutex<builder::StageTimeouts>>,
+
+    /// The runtime, used for spawning background tasks.
+    runtime: R,
+
+    /// The relays to which our maintenance task keeps channels open.
+    ///
+    /// See [`ChanMgr::set_prewarm_targets`].
+    prewarm_targets: std::sync::Mutex<Vec<OwnedChanTarget>>,
 }
 
+/// The largest number of channels that [`ChanMgr::prewarm`] builds at once.
+const MAX_CONCURRENT_PREWARM: usize = 4;
+
+/// How often our maintenance task makes sure that we have a channel
+/// to each of our [prewarm targets](ChanMgr::set_prewarm_targets).
+const PREWARM_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How soon our maintenance task tries again to update the parameters of channels
+/// that did not accept them.
+///
+/// (Otherwise, we only try again when we next expire our channels,
+/// which can be several minutes later.)
+const STALE_PARAMS_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often our maintenance task looks for channels that may have leaked.
+///
+/// See [`ChanMgr::suspect_leaked_channels`].
+const LEAK_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How long a channel must have had no circuits
+/// before our maintenance task reports it as a possible leak.
+///
+/// We expire idle channels after at most a few minutes,
+/// so a channel that is still in our map after this long has somehow escaped expiry.
+const LEAK_SUSPECT_UNUSED: Duration = Duration::from_secs(15 * 60);
+
 /// Description of how we got a channel.
 #[non_exhaustive]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -145,6 +203,53 @@ pub enum ChanProvenance {
     Preexisting,
 }
 
+/// Why a channel stopped being usable.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, derive_more::Display)]
+pub enum ChanCloseReason {
+    /// The relay closed the connection.
+    #[display("closed by peer")]
+    PeerClosed,
+    /// The channel failed because of a protocol violation or a network error.
+    #[display("protocol or network error")]
+    ProtocolError,
+    /// We closed the channel because it had been unused for too long.
+    #[display("expired while idle")]
+    ExpiredIdle,
+    /// We closed the channel while it was idle,
+    /// to make room for a new one within our limit on open channels.
+    #[display("evicted to make room for another channel")]
+    Evicted,
+    /// The channel was shut down on our side, for some other reason.
+    #[display("shut down locally")]
+    LocalShutdown,
+    /// We closed the channel because it looked as if it had leaked.
+    ///
+    /// See [`ChanMgr::close_suspect_leaked_channels`].
+    #[display("closed as a suspected leak")]
+    SuspectedLeak,
+}
+
+/// Information about a channel that closed recently.
+///
+/// Returned by [`ChanMgr::recently_closed`].
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct ClosedChanInfo {
+    /// The identities of the relay at the other end of the channel.
+    pub ids: RelayIds,
+    /// Why the channel closed.
+    pub reason: ChanCloseReason,
+    /// When we noticed that the channel had closed.
+    pub noticed_at: Instant,
+}
+
+impl HasRelayIds for ClosedChanInfo {
+    fn identity(&self, key_type: RelayIdType) -> Option<RelayIdRef<'_>> {
+        self.ids.identity(key_type)
+    }
+}
+
 /// Dormancy state, as far as the channel manager is concerned
 ///
 /// This is usually derived in higher layers from `arti_client::DormantMode`.
@@ -192,6 +297,16 @@ pub enum ChannelUsage {
     UselessCircuit,
 }
 
+impl From<ChannelUsage> for TrafficClass {
+    fn from(usage: ChannelUsage) -> Self {
+        match usage {
+            ChannelUsage::Dir => TrafficClass::Directory,
+            ChannelUsage::UserTraffic => TrafficClass::Interactive,
+            ChannelUsage::UselessCircuit => TrafficClass::Bulk,
+        }
+    }
+}
+
 impl<R: Runtime> ChanMgr<R> {
     /// Construct a new channel manager.
     ///
@@ -224,25 +339,46 @@ impl<R: Runtime> ChanMgr<R> {
     {
         let (sender, receiver) = event::channel();
         let sender = Arc::new(std::sync::Mutex::new(sender));
-        let reporter = BootstrapReporter(sender);
-        let transport = transport::DefaultTransport::new(runtime.clone());
-        let builder = builder::ChanBuilder::new(runtime, transport);
+        let reporter = BootstrapReporter::new(sender);
+        let transport =
+            transport::DefaultTransport::new(runtime.clone(), config.preferred_address_family);
+        let stage_timeouts = Arc::new(std::sync::Mutex::new(builder::StageTimeouts::from_config(
+            config,
+        )));
+        let builder = builder::ChanBuilder::new_with_timeouts(
+            runtime.clone(),
+            transport.clone(),
+            Arc::clone(&stage_timeouts),
+        );
         let factory = factory::CompoundFactory::new(
             Arc::new(builder),
             #[cfg(feature = "pt-client")]
             None,
         );
-        let mgr =
-            mgr::AbstractChanMgr::new(factory, config, dormancy, netparams, reporter, memquota);
+        let mgr = mgr::AbstractChanMgr::new(
+            factory,
+            config,
+            dormancy,
+            netparams,
+            reporter,
+            memquota,
+            DynTimeProvider::new(runtime.clone()),
+        );
         ChanMgr {
             mgr,
             bootstrap_status: receiver,
-            runtime: std::marker::PhantomData,
+            default_transport: transport,
+            stage_timeouts,
+            runtime,
+            prewarm_targets: Default::default(),
         }
     }
 
     /// Launch the periodic daemon tasks required by the manager to function properly.
     ///
+    /// This launches the [maintenance task](ChanMgr::launch_maintenance_task),
+    /// and a task that updates our channels' parameters whenever `netdir` has a new consensus.
+    ///
     /// Returns a [`TaskHandle`] that can be used to manage
     /// those daemon tasks that poll periodically.
     pub fn launch_background_tasks(
@@ -257,14 +393,39 @@ impl<R: Runtime> ChanMgr<R> {
             ))
             .map_err(|e| Error::from_spawn("channels config task", e))?;
 
-        let (sched, handle) = TaskSchedule::new(runtime.clone());
-        runtime
-            .spawn(Self::continually_expire_channels(
+        let handle = self.launch_maintenance_task()?;
+        Ok(vec![handle])
+    }
+
+    /// Launch a background task that keeps this manager's channels in order,
+    /// using the runtime that this manager was created with.
+    ///
+    /// The task:
+    ///   * expires channels that have been unused for too long
+    ///     (as [`expire_channels`](ChanMgr::expire_channels) does),
+    ///   * tries again to update the parameters of any channels that didn't accept them
+    ///     (see [`n_channels_with_stale_params`](ChanMgr::n_channels_with_stale_params)), and
+    ///   * periodically makes sure that we have a channel to each of our
+    ///     [prewarm targets](ChanMgr::set_prewarm_targets).
+    ///
+    /// [`launch_background_tasks`](ChanMgr::launch_background_tasks) already launches this task;
+    /// use this method instead if you have no [`NetDirProvider`],
+    /// and are updating our network parameters yourself.
+    ///
+    /// The task stops running when the returned [`TaskHandle`] is
+    /// [cancelled](TaskHandle::cancel), until it is [fired](TaskHandle::fire) again;
+    /// it exits when this `ChanMgr` or the `TaskHandle` is dropped.
+    pub fn launch_maintenance_task(self: &Arc<Self>) -> Result<TaskHandle> {
+        let (sched, handle) = TaskSchedule::new(self.runtime.clone());
+        let task = TaskRegistry::global().register("channel maintenance", self.runtime.clone());
+        self.runtime
+            .spawn(Self::continually_maintain_channels(
                 sched,
                 Arc::downgrade(self),
+                task,
             ))
-            .map_err(|e| Error::from_spawn("channel expiration task", e))?;
-        Ok(vec![handle])
+            .map_err(|e| Error::from_spawn("channel maintenance task", e))?;
+        Ok(handle)
     }
 
     /// Build a channel for an incoming stream.
@@ -289,14 +450,34 @@ impl<R: Runtime> ChanMgr<R> {
     /// If there is already a channel launch attempt in progress, this
     /// function will wait until that launch is complete, and succeed
     /// or fail depending on its outcome.
+    ///
+    /// The channel is marked with the [`TrafficClass`] that corresponds to `usage`.
     pub async fn get_or_launch<T: ChanTarget + ?Sized>(
         &self,
         target: &T,
         usage: ChannelUsage,
+    ) -> Result<(Arc<Channel>, ChanProvenance)> {
+        self.get_or_launch_with_class(target, usage, usage.into())
+            .await
+    }
+
+    /// As [`ChanMgr::get_or_launch`], but mark the channel with a given `class`
+    /// of traffic.
+    ///
+    /// If the channel was already marked with a less latency-sensitive class,
+    /// it is marked with `class` instead.
+    pub async fn get_or_launch_with_class<T: ChanTarget + ?Sized>(
+        &self,
+        target: &T,
+        usage: ChannelUsage,
+        class: TrafficClass,
     ) -> Result<(Arc<Channel>, ChanProvenance)> {
         let targetinfo = OwnedChanTarget::from_chan_target(target);
 
-        let (chan, provenance) = self.mgr.get_or_launch(targetinfo, usage).await?;
+        let (chan, provenance) = self
+            .mgr
+            .get_or_launch_with_class(targetinfo, usage, class)
+            .await?;
         // Double-check the match to make sure that the RSA identity is
         // what we wanted too.
         chan.check_match(target)
@@ -304,6 +485,72 @@ impl<R: Runtime> ChanMgr<R> {
         Ok((chan, provenance))
     }
 
+    /// As [`ChanMgr::get_or_launch`], but only return a channel that is canonical for `target`:
+    /// that is, a channel connected to one of `target`'s addresses.
+    ///
+    /// Ordinarily, we reuse any open channel to a relay with the right identities,
+    /// wherever we connected to it.
+    /// Some callers (for example, relays handling EXTEND2 requests,
+    /// or code talking to a bridge at a particular address)
+    /// need a channel to one of the addresses they asked for.
+    /// If we have no such channel, this launches a new one.
+    pub async fn get_or_launch_canonical<T: ChanTarget + ?Sized>(
+        &self,
+        target: &T,
+        usage: ChannelUsage,
+    ) -> Result<(Arc<Channel>, ChanProvenance)> {
+        let targetinfo = OwnedChanTarget::from_chan_target(target);
+        let canonical_addrs = targetinfo.addrs().to_vec();
+
+        let (chan, provenance) = self
+            .mgr
+            .get_or_launch_restricted(
+                targetinfo,
+                usage,
+                usage.into(),
+                mgr::ChannelRestriction::CanonicalOnly(&canonical_addrs),
+            )
+            .await?;
+        chan.check_match(target)
+            .map_err(|e| Error::from_proto_no_skew(e, target))?;
+        Ok((chan, provenance))
+    }
+
+    /// Build channels to each of `targets` in the background,
+    /// so that they are ready by the time we need them.
+    ///
+    /// This is meant for relays that we expect to use often
+    /// (such as our guards, our directory caches,
+    /// or the introduction points of an onion service),
+    /// so that building circuits through them doesn't have to wait
+    /// for a TLS handshake.
+    ///
+    /// Targets to which we already have a channel are skipped,
+    /// and at most a few channels are built at a time.
+    /// Failures are logged, but not otherwise reported.
+    pub fn prewarm(self: &Arc<Self>, targets: Vec<OwnedChanTarget>) -> Result<()> {
+        let mgr = Arc::downgrade(self);
+        self.runtime
+            .spawn(async move {
+                if let Some(mgr) = mgr.upgrade() {
+                    mgr.mgr.prewarm(targets, MAX_CONCURRENT_PREWARM).await;
+                }
+            })
+            .map_err(|e| Error::from_spawn("channel prewarming task", e))
+    }
+
+    /// Replace the list of relays to which the maintenance task keeps channels open.
+    ///
+    /// Every few minutes, the task launched by
+    /// [`launch_maintenance_task`](ChanMgr::launch_maintenance_task)
+    /// [prewarms](ChanMgr::prewarm) a channel to each of `targets`,
+    /// so that a channel that has closed or expired is soon replaced.
+    ///
+    /// Passing an empty list turns this off.
+    pub fn set_prewarm_targets(&self, targets: Vec<OwnedChanTarget>) {
+        *self.prewarm_targets.lock().expect("Lock poisoned") = targets;
+    }
+
     /// Return a stream of [`ConnStatus`] events to tell us about changes
     /// in our ability to connect to the internet.
     ///
@@ -313,6 +560,62 @@ impl<R: Runtime> ChanMgr<R> {
         self.bootstrap_status.clone()
     }
 
+    /// Return a stream of [`ChanMgrEvent`]s, telling us whenever we start building a channel,
+    /// and whenever one of our channels opens, fails, or closes.
+    ///
+    /// This lets callers (such as bootstrap reporting, or a status display)
+    /// follow what is happening to our channels without polling.
+    ///
+    /// Note that this stream is lossy: a caller that falls too far behind will miss some events.
+    /// Also, we only notice that a channel has closed when we next look at our channels,
+    /// which we do regularly in our [maintenance task](ChanMgr::launch_maintenance_task).
+    pub fn subscribe_events(&self) -> ChanMgrEvents {
+        self.mgr.subscribe_events()
+    }
+
+    /// Return the identities of every channel that we are currently trying to build,
+    /// along with a stream of [`ChanBuildProgress`] updates for each attempt.
+    ///
+    /// This can be used to find out where channel establishment is getting stuck:
+    /// unlike [`get_or_launch`](ChanMgr::get_or_launch), which only resolves
+    /// once a channel is ready or has failed, the returned streams report every
+    /// intermediate stage (TCP connection, TLS handshake, and authentication).
+    pub fn pending_channel_progress(&self) -> Vec<(RelayIds, ChanBuildProgressEvents)> {
+        self.mgr.pending_channel_progress()
+    }
+
+    /// Return the identities of every channel that we currently have open,
+    /// along with the address we connected to, if we know it.
+    ///
+    /// (We won't know the address if, for example,
+    /// the channel was built over a pluggable transport.)
+    pub fn open_channel_addrs(&self) -> Vec<(RelayIds, Option<SocketAddr>)> {
+        self.mgr.open_channel_addrs()
+    }
+
+    /// Return the number of open channels that have not accepted
+    /// our latest channel padding or KIST parameters.
+    ///
+    /// These parameters usually change when we get a new consensus, or a new configuration.
+    /// A channel that fails to accept them keeps running with its old parameters;
+    /// we try to update it again whenever we next reconfigure or expire our channels.
+    pub fn n_channels_with_stale_params(&self) -> usize {
+        self.mgr.n_channels_with_stale_params()
+    }
+
+    /// Return statistics about the channel padding on our channels,
+    /// for each padding level that has been configured.
+    ///
+    /// Each channel is counted under the [`PaddingLevel`]
+    /// that was configured when it was opened.
+    /// Channels that we have closed are still counted,
+    /// so these statistics cover the whole lifetime of this `ChanMgr`.
+    ///
+    /// This is useful for finding out how much overhead channel padding adds.
+    pub fn padding_stats(&self) -> HashMap<PaddingLevel, PaddingStats> {
+        self.mgr.padding_stats()
+    }
+
     /// Expire all channels that have been unused for too long.
     ///
     /// Return the duration from now until next channel expires.
@@ -320,6 +623,45 @@ impl<R: Runtime> ChanMgr<R> {
         self.mgr.expire_channels()
     }
 
+    /// Return every open channel that looks as if it may have leaked, or got stuck.
+    ///
+    /// These are the channels that are still usable,
+    /// and that have had no circuits for at least `min_unused`,
+    /// but that nobody except this `ChanMgr` refers to.
+    /// Since we close channels that have been idle for more than a few minutes,
+    /// a channel that stays in this state for much longer than that
+    /// suggests a bug, either in our expiry logic or in the channel's accounting of its circuits.
+    ///
+    /// Our [maintenance task](ChanMgr::launch_maintenance_task) regularly logs a warning
+    /// about every such channel that has been idle for a long time.
+    /// This is mainly useful for long-running relays.
+    pub fn suspect_leaked_channels(&self, min_unused: Duration) -> Vec<SuspectChannel> {
+        self.mgr.suspect_leaked_channels(min_unused)
+    }
+
+    /// Shut down every channel that [`suspect_leaked_channels`](ChanMgr::suspect_leaked_channels)
+    /// would return for `min_unused`, and stop managing it.
+    ///
+    /// Return the channels that we closed.
+    /// They are reported as closed with [`ChanCloseReason::SuspectedLeak`].
+    pub fn close_suspect_leaked_channels(&self, min_unused: Duration) -> Vec<SuspectChannel> {
+        self.mgr.close_suspect_leaked_channels(min_unused)
+    }
+
+    /// If we recently had a channel to the relay with the identity `ident`
+    /// and that channel has closed, return why it closed.
+    ///
+    /// We remember closed channels for a few minutes after we notice that they closed.
+    /// This lets callers distinguish a relay that has dropped our connection
+    /// (or that we could talk to, but which violated the protocol)
+    /// from, say, a channel that we closed ourselves because it was idle.
+    pub fn recently_closed<'a, T>(&self, ident: T) -> Option<ClosedChanInfo>
+    where
+        T: Into<RelayIdRef<'a>>,
+    {
+        self.mgr.recently_closed(ident)
+    }
+
     /// Notifies the chanmgr to be dormant like dormancy
     pub fn set_dormancy(
         &self,
@@ -344,6 +686,11 @@ impl<R: Runtime> ChanMgr<R> {
             return Ok(());
         }
 
+        self.default_transport
+            .set_preferred_address_family(config.preferred_address_family);
+        *self.stage_timeouts.lock().expect("Lock poisoned") =
+            builder::StageTimeouts::from_config(config);
+
         let r = self.mgr.reconfigure(config, netparams);
 
         // Check that `self.mgr.reconfigure` returns an error type of `Bug` (see comment above).
@@ -352,6 +699,16 @@ impl<R: Runtime> ChanMgr<R> {
         Ok(r?)
     }
 
+    /// Return a handle to the timeouts we use for each stage of building a channel.
+    ///
+    /// We update these timeouts whenever we are reconfigured.
+    /// Give this handle to any other [`ChanBuilder`](builder::ChanBuilder)
+    /// (such as those used for pluggable transports)
+    /// so that it uses the same timeouts.
+    pub fn stage_timeouts(&self) -> Arc<std::sync::Mutex<builder::StageTimeouts>> {
+        Arc::clone(&self.stage_timeouts)
+    }
+
     /// Replace the transport registry with one that may know about
     /// more transports.
     ///
@@ -359,11 +716,53 @@ impl<R: Runtime> ChanMgr<R> {
     /// required to time-out channels that take too long to build.  You'll get
     /// this behavior by default if the factories implement [`ChannelFactory`](factory::ChannelFactory) using
     /// [`transport::proxied::ExternalProxyPlugin`], which `tor-ptmgr` does.
+    ///
+    /// Channels built after this call are tagged with a new [`FactoryGeneration`].
+    /// Channels that are already being built finish using the old transports.
     #[cfg(feature = "pt-client")]
     pub fn set_pt_mgr(&self, ptmgr: Arc<dyn factory::AbstractPtMgr + 'static>) {
         self.mgr.with_mut_builder(|f| f.replace_ptmgr(ptmgr));
     }
 
+    /// Switch to using `ptmgr` for pluggable transports, and return the
+    /// transport registry we were using before.
+    ///
+    /// If `ptmgr` is `None`, we stop supporting pluggable transports,
+    /// and only build direct channels.
+    ///
+    /// Unlike [`set_pt_mgr`](ChanMgr::set_pt_mgr), this is meant for switching
+    /// between ways of connecting while we are running
+    /// (for example, in response to a change in connectivity).
+    /// Channels built after this call are tagged with a new [`FactoryGeneration`].
+    /// Channel builds that are already in progress finish using the old transports;
+    /// if they fail, the requests that launched them try again with the new ones,
+    /// without counting the failure against their retry limit.
+    #[cfg(feature = "pt-client")]
+    pub fn replace_pt_mgr(
+        &self,
+        ptmgr: Option<Arc<dyn factory::AbstractPtMgr + 'static>>,
+    ) -> Option<Arc<dyn factory::AbstractPtMgr + 'static>> {
+        self.mgr
+            .replace_builder(|f| f.with_ptmgr(ptmgr))
+            .ptmgr()
+            .cloned()
+    }
+
+    /// Return the generation of the channel factory that we are currently using.
+    ///
+    /// This changes whenever our factory changes:
+    /// for example, when we are given a new pluggable transport registry.
+    pub fn factory_generation(&self) -> FactoryGeneration {
+        self.mgr.factory_generation()
+    }
+
+    /// Return the generation of the channel factory that built `chan`.
+    ///
+    /// Returns `None` if `chan` is not managed by this `ChanMgr`.
+    pub fn factory_generation_of(&self, chan: &Arc<Channel>) -> Option<FactoryGeneration> {
+        self.mgr.factory_generation_of(chan)
+    }
+
     /// Try to create a new, unmanaged channel to `target`.
     ///
     /// Unlike [`get_or_launch`](ChanMgr::get_or_launch), this function always
@@ -433,20 +832,168 @@ impl<R: Runtime> ChanMgr<R> {
     }
 
     /// Periodically expire any channels that have been unused beyond
-    /// the maximum duration allowed.
+    /// the maximum duration allowed, retry updating the parameters of stale channels,
+    /// prewarm channels to our prewarm targets,
+    /// and warn about channels that may have leaked.
     ///
-    /// Exist when we find that `chanmgr` is dropped
+    /// Exits when we find that `chanmgr` is dropped,
+    /// or when `sched` is cancelled.
     ///
-    /// This is a daemon task that runs indefinitely in the background
-    async fn continually_expire_channels(mut sched: TaskSchedule<R>, chanmgr: Weak<Self>) {
+    /// This is a daemon task that runs indefinitely in the background.
+    /// `task` is its entry in the [`TaskRegistry`].
+    async fn continually_maintain_channels(
+        mut sched: TaskSchedule<R>,
+        chanmgr: Weak<Self>,
+        task: RegisteredTask,
+    ) {
+        let mut next_prewarm: Option<Instant> = None;
+        let mut next_leak_check: Option<Instant> = None;
         while sched.next().await.is_some() {
             let Some(cm) = Weak::upgrade(&chanmgr) else {
                 // channel manager is closed.
                 return;
             };
-            let delay = cm.expire_channels();
+            // This also retries updating the parameters of any stale channels.
+            let mut delay = cm.expire_channels();
+            if cm.n_channels_with_stale_params() > 0 {
+                delay = delay.min(STALE_PARAMS_RETRY_INTERVAL);
+            }
+
+            let now = cm.runtime.now();
+            let prewarm_at = match next_prewarm {
+                Some(at) if at > now => at,
+                _ => {
+                    let targets = cm.prewarm_targets.lock().expect("Lock poisoned").clone();
+                    if !targets.is_empty() {
+                        if let Err(e) = cm.prewarm(targets) {
+                            error_report!(e, "Unable to prewarm channels");
+                        }
+                    }
+                    now + PREWARM_INTERVAL
+                }
+            };
+            next_prewarm = Some(prewarm_at);
+            delay = delay.min(prewarm_at.saturating_duration_since(now));
+
+            cm.mgr.warn_about_suspect_leaks(
+                &mut next_leak_check,
+                LEAK_SUSPECT_UNUSED,
+                LEAK_CHECK_INTERVAL,
+            );
+            if let Some(leak_check_at) = next_leak_check {
+                delay = delay.min(leak_check_at.saturating_duration_since(now));
+            }
+
+            task.note_activity();
             // This will sometimes be an underestimate, but it's no big deal; we just sleep some more.
             sched.fire_in(delay);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::testing::msgs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tor_linkspec::ChannelMethod;
+    use tor_rtcompat::NetStreamListener as _;
+    use tor_rtmock::{MockRuntime, net::MockNetwork};
+
+    #[test]
+    fn maintenance_prewarms() {
+        MockRuntime::test_with_various(|rt| async move {
+            let orport: SocketAddr = msgs::ADDR.parse().unwrap();
+            let network = MockNetwork::new();
+            let client_rt = network
+                .builder()
+                .add_address("192.0.2.17".parse().unwrap())
+                .runtime(rt.clone());
+            let relay_rt = network
+                .builder()
+                .add_address(orport.ip())
+                .runtime(rt.clone());
+            let lis = relay_rt
+                .mock_net()
+                .listen_tls(&orport, msgs::X509_CERT.into())
+                .unwrap();
+
+            // Count the connections to the relay, and close them all at once,
+            // so that every channel we try to build fails.
+            let n_connections = Arc::new(AtomicUsize::new(0));
+            rt.spawn({
+                let n_connections = n_connections.clone();
+                async move {
+                    let mut incoming = lis.incoming();
+                    while let Some(Ok((conn, _addr))) = incoming.next().await {
+                        n_connections.fetch_add(1, Ordering::SeqCst);
+                        drop(conn);
+                    }
+                }
+            })
+            .unwrap();
+            let connections = || n_connections.load(Ordering::SeqCst);
+
+            let target = OwnedChanTarget::builder()
+                .addrs(vec![orport])
+                .method(ChannelMethod::Direct(vec![orport]))
+                .ed_identity(msgs::ED_ID.into())
+                .rsa_identity(msgs::RSA_ID.into())
+                .build()
+                .unwrap();
+            let cm = Arc::new(ChanMgr::new(
+                client_rt,
+                &ChannelConfig::default(),
+                Dormancy::Active,
+                &NetParameters::default(),
+                ToplevelAccount::new_noop(),
+            ));
+            cm.set_prewarm_targets(vec![target.clone()]);
+            let handle = cm.launch_maintenance_task().unwrap();
+
+            // We try to build a channel to our target at once,
+            // and then every PREWARM_INTERVAL while we don't have one.
+            // (Each try may make more than one connection attempt.)
+            rt.progress_until_stalled().await;
+            let per_try = connections();
+            assert!(per_try > 0);
+            rt.advance_by(PREWARM_INTERVAL - Duration::from_secs(1))
+                .await;
+            assert_eq!(connections(), per_try);
+            rt.advance_by(Duration::from_secs(1)).await;
+            assert_eq!(connections(), per_try * 2);
+
+            // Without any targets, we don't.
+            cm.set_prewarm_targets(vec![]);
+            rt.advance_by(PREWARM_INTERVAL * 2).await;
+            assert_eq!(connections(), per_try * 2);
+
+            // Nor do we while the task is cancelled.
+            cm.set_prewarm_targets(vec![target]);
+            assert!(handle.cancel());
+            rt.advance_by(PREWARM_INTERVAL * 2).await;
+            assert_eq!(connections(), per_try * 2);
+            assert!(handle.fire());
+            rt.progress_until_stalled().await;
+            assert_eq!(connections(), per_try * 3);
+
+            // Once the manager is dropped, the task exits.
+            drop(cm);
+            rt.advance_by(PREWARM_INTERVAL).await;
+            assert!(!handle.fire());
+        });
+    }
+}
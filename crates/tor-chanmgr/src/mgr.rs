@@ -2,25 +2,40 @@
 
 use crate::mgr::state::{ChannelForTarget, PendingChannelHandle};
 use crate::util::defer::Defer;
-use crate::{ChanProvenance, ChannelConfig, ChannelUsage, Dormancy, Error, Result};
+use crate::{
+    ChanCloseReason, ChanProvenance, ChannelConfig, ChannelUsage, ClosedChanInfo, Dormancy, Error,
+    Result, TrafficClass,
+};
 
+use crate::event::{ChanBuildProgress, ChanBuildProgressEvents, ChanMgrEvents};
 use crate::factory::BootstrapReporter;
 use async_trait::async_trait;
 use futures::future::Shared;
 use oneshot_fused_workaround as oneshot;
+use postage::watch;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::result::Result as StdResult;
 use std::sync::Arc;
-use std::time::Duration;
-use tor_error::{error_report, internal};
-use tor_linkspec::HasRelayIds;
+use std::time::{Duration, Instant};
+use tor_config::PaddingLevel;
+use tor_error::{debug_report, error_report, internal};
+use tor_linkspec::{HasRelayIds, RelayIds};
 use tor_netdir::params::NetParameters;
 use tor_proto::channel::kist::KistParams;
+use tor_proto::channel::padding::PaddingCounts;
 use tor_proto::channel::params::ChannelPaddingInstructionsUpdates;
 use tor_proto::memquota::{ChannelAccount, SpecificAccount as _, ToplevelAccount};
+use tor_rtcompat::DynTimeProvider;
+use tracing::{Instrument as _, debug, warn};
 
 mod select;
 mod state;
 
+pub(crate) use select::ChannelRestriction;
+
+pub use state::{FactoryGeneration, PaddingStats, SuspectChannel, UniqPendingChanId};
+
 /// Trait to describe as much of a
 /// [`Channel`](tor_proto::channel::Channel) as `AbstractChanMgr`
 /// needs to use.
@@ -31,6 +46,11 @@ pub(crate) trait AbstractChannel: HasRelayIds {
     /// hit a bug, or for some other reason.  We don't return unusable
     /// channels back to the user.
     fn is_usable(&self) -> bool;
+    /// If this channel has closed, return why.
+    ///
+    /// Return None if the channel is still open,
+    /// or if we can't tell why it closed.
+    fn close_reason(&self) -> Option<ChanCloseReason>;
     /// Return the amount of time a channel has not been in use.
     /// Return None if the channel is currently in use.
     fn duration_unused(&self) -> Option<Duration>;
@@ -56,6 +76,26 @@ pub(crate) trait AbstractChannel: HasRelayIds {
     ///
     /// [`Channel::engage_padding_activities`]: tor_proto::channel::Channel::engage_padding_activities
     fn engage_padding_activities(&self);
+
+    /// Return the address to which this channel is connected,
+    /// if it is connected to a known `SocketAddr`.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// Tell this channel what class of traffic it is expected to carry.
+    ///
+    /// The channel may use this to set socket options (such as DSCP marking),
+    /// or as a scheduling hint.
+    /// This is called when the channel is opened,
+    /// and again whenever its class becomes more latency-sensitive.
+    fn set_traffic_class(&self, class: TrafficClass);
+
+    /// Return the number of padding cells that this channel has sent and received so far.
+    fn padding_counts(&self) -> PaddingCounts;
+
+    /// Shut this channel down, even if something may still be using it.
+    ///
+    /// See [`Channel::terminate`](tor_proto::channel::Channel::terminate).
+    fn terminate(&self);
 }
 
 /// Trait to describe how channels-like objects are created.
@@ -119,12 +159,19 @@ pub(crate) struct AbstractChanMgr<CF: AbstractChannelFactory> {
 
 /// Type alias for a future that we wait on to see when a pending
 /// channel is done or failed.
+///
+/// If the channel failed, every waiter gets a copy of the same error,
+/// so that they can all classify the failure with [`Error::failure_class`].
 type Pending = Shared<oneshot::Receiver<Result<()>>>;
 
 /// Type alias for the sender we notify when we complete a channel (or fail to
 /// complete it).
 type Sending = oneshot::Sender<Result<()>>;
 
+/// Type alias for the sender we use to report how far a pending channel
+/// has progressed.
+type ProgressSending = watch::Sender<ChanBuildProgress>;
+
 impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
     /// Make a new empty channel manager.
     pub(crate) fn new(
@@ -134,15 +181,24 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
         netparams: &NetParameters,
         reporter: BootstrapReporter,
         memquota: ToplevelAccount,
+        time_provider: DynTimeProvider,
     ) -> Self {
         AbstractChanMgr {
-            channels: state::MgrState::new(connector, config.clone(), dormancy, netparams),
+            channels: state::MgrState::new(
+                connector,
+                config.clone(),
+                dormancy,
+                netparams,
+                time_provider,
+            ),
             reporter,
             memquota,
         }
     }
 
     /// Run a function to modify the channel builder in this object.
+    ///
+    /// The modified builder gets a new [`FactoryGeneration`].
     #[allow(dead_code)]
     pub(crate) fn with_mut_builder<F>(&self, func: F)
     where
@@ -151,6 +207,35 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
         self.channels.with_mut_builder(func);
     }
 
+    /// Replace the channel builder in this object with the one returned by `func`,
+    /// and return the old one.
+    ///
+    /// `func` is given the old builder.
+    /// The new builder gets a new [`FactoryGeneration`].
+    /// Channel builds that are already in progress finish using the old builder;
+    /// if they fail, the requests that launched them try again with the new one.
+    pub(crate) fn replace_builder<F>(&self, func: F) -> CF
+    where
+        F: FnOnce(&CF) -> CF,
+    {
+        self.channels.replace_builder(func)
+    }
+
+    /// Return the generation of the channel builder in this object.
+    pub(crate) fn factory_generation(&self) -> FactoryGeneration {
+        self.channels.builder_generation()
+    }
+
+    /// Return the generation of the channel builder that built `chan`.
+    ///
+    /// Returns `None` if `chan` is not managed by this object.
+    pub(crate) fn factory_generation_of(
+        &self,
+        chan: &Arc<CF::Channel>,
+    ) -> Option<FactoryGeneration> {
+        self.channels.factory_generation_of(chan)
+    }
+
     /// Remove every unusable entry from this channel manager.
     #[cfg(test)]
     pub(crate) fn remove_unusable_entries(&self) -> Result<()> {
@@ -188,10 +273,40 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
         &self,
         target: CF::BuildSpec,
         usage: ChannelUsage,
+    ) -> Result<(Arc<CF::Channel>, ChanProvenance)> {
+        self.get_or_launch_with_class(target, usage, usage.into())
+            .await
+    }
+
+    /// As [`AbstractChanMgr::get_or_launch`], but mark the channel with `class`.
+    pub(crate) async fn get_or_launch_with_class(
+        &self,
+        target: CF::BuildSpec,
+        usage: ChannelUsage,
+        class: TrafficClass,
+    ) -> Result<(Arc<CF::Channel>, ChanProvenance)> {
+        self.get_or_launch_restricted(target, usage, class, ChannelRestriction::None)
+            .await
+    }
+
+    /// As [`AbstractChanMgr::get_or_launch_with_class`],
+    /// but only return an existing channel if `restriction` permits it.
+    ///
+    /// If it doesn't, launch a new channel instead.
+    pub(crate) async fn get_or_launch_restricted(
+        &self,
+        target: CF::BuildSpec,
+        usage: ChannelUsage,
+        class: TrafficClass,
+        restriction: ChannelRestriction<'_>,
     ) -> Result<(Arc<CF::Channel>, ChanProvenance)> {
         use ChannelUsage as CU;
 
-        let chan = self.get_or_launch_internal(target).await?;
+        let chan = self
+            .get_or_launch_internal(target, class, restriction)
+            .await?;
+        // The channel might have been built (or requested) for a different class.
+        self.channels.note_traffic_class(&chan.0, class)?;
 
         match usage {
             CU::Dir | CU::UselessCircuit => {}
@@ -205,6 +320,8 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
     async fn get_or_launch_internal(
         &self,
         target: CF::BuildSpec,
+        class: TrafficClass,
+        restriction: ChannelRestriction<'_>,
     ) -> Result<(Arc<CF::Channel>, ChanProvenance)> {
         /// How many times do we try?
         const N_ATTEMPTS: usize = 2;
@@ -222,7 +339,7 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
             // to decide on an `Action`, and _then_ we execute that action.
 
             // First, see what state we're in, and what we should do about it.
-            let action = self.choose_action(&target, final_attempt)?;
+            let action = self.choose_action(&target, final_attempt, restriction)?;
 
             // We are done deciding on our Action! It's time act based on the
             // Action that we chose.
@@ -264,7 +381,7 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
                     }
                 }
                 // We need to launch a channel.
-                Some(Action::Launch((handle, send))) => {
+                Some(Action::Launch((handle, send, progress))) => {
                     // If the remainder of this code returns early or is cancelled, we still want to
                     // clean up our pending entry in the channel map. The following closure will be
                     // run when dropped to ensure that it's cleaned up properly.
@@ -274,6 +391,7 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
                     // methods of `MgrState`. When this `Defer` is being dropped, no other
                     // `MgrState` methods will be running on this thread, so the lock will not have
                     // already been acquired.
+                    let unique_id = handle.unique_id();
                     let defer_remove_pending = Defer::new(handle, |handle| {
                         if let Err(e) = self.channels.remove_pending_channel(handle) {
                             // Just log an error if we're unable to remove it, since there's
@@ -286,23 +404,41 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
                         }
                     });
 
-                    let connector = self.channels.builder();
+                    let (connector, factory_generation) = self.channels.builder_with_generation();
                     let memquota = ChannelAccount::new(&self.memquota)?;
+                    let reporter = self.reporter.with_progress(progress);
+
+                    // Everything logged while building this channel
+                    // is tagged with the ID of this attempt.
+                    let span = tracing::debug_span!(
+                        "chan_build",
+                        pending_chan = %unique_id,
+                        factory = %factory_generation,
+                    );
 
                     let outcome = connector
-                        .build_channel(&target, self.reporter.clone(), memquota)
-                        .await;
+                        .build_channel(&target, reporter, memquota)
+                        .instrument(span.clone())
+                        .await
+                        .map_err(|e| Error::in_build_attempt(unique_id, e));
 
                     match outcome {
                         Ok(ref chan) => {
+                            span.in_scope(|| debug!("Channel build succeeded"));
                             // Replace the pending channel with the newly built channel.
                             let handle = defer_remove_pending.cancel();
-                            self.channels
-                                .upgrade_pending_channel_to_open(handle, Arc::clone(chan))?;
+                            self.channels.upgrade_pending_channel_to_open(
+                                handle,
+                                Arc::clone(chan),
+                                class,
+                                factory_generation,
+                            )?;
                         }
-                        Err(_) => {
+                        Err(ref e) => {
+                            span.in_scope(|| debug_report!(e, "Channel build failed"));
                             // Remove the pending channel.
                             drop(defer_remove_pending);
+                            self.channels.note_build_failed(&target, e.failure_class());
                         }
                     }
 
@@ -314,7 +450,20 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
                         Ok(chan) => {
                             return Ok((chan, ChanProvenance::NewlyCreated));
                         }
-                        Err(e) => last_err = Some(e),
+                        Err(e) => {
+                            if self.channels.builder_generation() != factory_generation {
+                                // Our factory was replaced while we were using it,
+                                // so this failure says little about the new one:
+                                // don't count this attempt, and try again with the new factory.
+                                span.in_scope(|| {
+                                    debug!(
+                                        "Channel factory replaced during failed build; retrying"
+                                    );
+                                });
+                                attempts_so_far -= 1;
+                            }
+                            last_err = Some(e);
+                        }
                     }
                 }
             }
@@ -337,11 +486,13 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
         &self,
         target: &CF::BuildSpec,
         final_attempt: bool,
+        restriction: ChannelRestriction<'_>,
     ) -> Result<Option<Action<CF::Channel>>> {
         // don't create new channels on the final attempt
         let response = self.channels.request_channel(
             target,
             /* add_new_entry_if_not_found= */ !final_attempt,
+            restriction,
         );
 
         match response {
@@ -354,9 +505,9 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
                     Ok(None)
                 }
             }
-            Ok(Some(ChannelForTarget::NewEntry((handle, send)))) => {
+            Ok(Some(ChannelForTarget::NewEntry((handle, send, progress)))) => {
                 // do not drop the handle if refactoring; see `PendingChannelHandle` for details
-                Ok(Some(Action::Launch((handle, send))))
+                Ok(Some(Action::Launch((handle, send, progress))))
             }
             Ok(None) => Ok(None),
             Err(e @ Error::IdentityConflict) => Ok(Some(Action::Return(Err(e)))),
@@ -392,6 +543,58 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
             .reconfigure_general(Some(config), None, netparams)
     }
 
+    /// Return the identities of every channel we are currently trying to build,
+    /// along with a stream of updates about how far each attempt has progressed.
+    pub(crate) fn pending_channel_progress(&self) -> Vec<(RelayIds, ChanBuildProgressEvents)> {
+        self.channels.pending_channel_progress()
+    }
+
+    /// Return a stream of events about our channels.
+    ///
+    /// See [`ChanMgr::subscribe_events`](crate::ChanMgr::subscribe_events).
+    pub(crate) fn subscribe_events(&self) -> ChanMgrEvents {
+        self.channels.subscribe_events()
+    }
+
+    /// Return the identities and peer address of every usable open channel.
+    pub(crate) fn open_channel_addrs(&self) -> Vec<(RelayIds, Option<SocketAddr>)> {
+        self.channels.open_channel_addrs()
+    }
+
+    /// Return the number of usable open channels that are running with stale parameters.
+    pub(crate) fn n_channels_with_stale_params(&self) -> usize {
+        self.channels.n_channels_with_stale_params()
+    }
+
+    /// Return padding statistics for our channels, by padding level.
+    pub(crate) fn padding_stats(&self) -> HashMap<PaddingLevel, PaddingStats> {
+        self.channels.padding_stats()
+    }
+
+    /// Make sure that we have a channel to each of `targets`,
+    /// building at most `max_concurrent` channels at a time.
+    ///
+    /// Failures are logged, and otherwise ignored.
+    pub(crate) async fn prewarm(&self, targets: Vec<CF::BuildSpec>, max_concurrent: usize) {
+        use futures::StreamExt as _;
+
+        futures::stream::iter(targets)
+            .for_each_concurrent(max_concurrent, |target| async move {
+                let ids = RelayIds::from_relay_ids(&target);
+                if let Err(e) = self
+                    .get_or_launch(target, ChannelUsage::UselessCircuit)
+                    .await
+                {
+                    debug_report!(
+                        e,
+                        "Unable to prewarm channel to {}",
+                        ids.display_relay_ids()
+                    );
+                }
+            })
+            .await;
+    }
+
     /// Expire any channels that have been unused longer than
     /// their maximum unused duration assigned during creation.
     ///
@@ -404,6 +607,57 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
         self.channels.expire_channels()
     }
 
+    /// Return every open channel that may have leaked:
+    /// see [`ChanMgr::suspect_leaked_channels`](crate::ChanMgr::suspect_leaked_channels).
+    pub(crate) fn suspect_leaked_channels(&self, min_unused: Duration) -> Vec<SuspectChannel> {
+        self.channels.suspect_leaked_channels(min_unused)
+    }
+
+    /// Warn about every open channel that may have leaked, if it is time to look for them.
+    ///
+    /// We look if `next_check` is `None` or has passed, and then set it to `interval` from now.
+    ///
+    /// Return the channels that we warned about.
+    pub(crate) fn warn_about_suspect_leaks(
+        &self,
+        next_check: &mut Option<Instant>,
+        min_unused: Duration,
+        interval: Duration,
+    ) -> Vec<SuspectChannel> {
+        let now = self.channels.now();
+        if next_check.is_some_and(|at| at > now) {
+            return vec![];
+        }
+        *next_check = Some(now + interval);
+
+        let suspects = self.suspect_leaked_channels(min_unused);
+        for suspect in &suspects {
+            warn!(
+                "Channel to {} has had no circuits for {} seconds, but has not expired: it may have leaked",
+                suspect.ids.display_relay_ids(),
+                suspect.unused_for.as_secs(),
+            );
+        }
+        suspects
+    }
+
+    /// Close every open channel that may have leaked, and return the channels we closed.
+    pub(crate) fn close_suspect_leaked_channels(
+        &self,
+        min_unused: Duration,
+    ) -> Vec<SuspectChannel> {
+        self.channels.close_suspect_leaked_channels(min_unused)
+    }
+
+    /// Return information about the most recent channel with the identity `ident`,
+    /// if it closed recently.
+    pub(crate) fn recently_closed<'a, T>(&self, ident: T) -> Option<ClosedChanInfo>
+    where
+        T: Into<tor_linkspec::RelayIdRef<'a>>,
+    {
+        self.channels.recently_closed(ident)
+    }
+
     /// Test only: return the open usable channels with a given `ident`.
     #[cfg(test)]
     pub(crate) fn get_nowait<'a, T>(&self, ident: T) -> Vec<Arc<CF::Channel>>
@@ -430,7 +684,7 @@ impl<CF: AbstractChannelFactory + Clone> AbstractChanMgr<CF> {
 enum Action<C: AbstractChannel> {
     /// We found no channel.  We're going to launch a new one,
     /// then tell everybody about it.
-    Launch((PendingChannelHandle, Sending)),
+    Launch((PendingChannelHandle, Sending, ProgressSending)),
     /// We found an in-progress attempt at making a channel.
     /// We're going to wait for it to finish.
     Wait(Pending),
@@ -457,19 +711,21 @@ mod test {
     use crate::Error;
 
     use futures::join;
-    use std::sync::Arc;
-    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
     use tor_error::bad_api_usage;
     use tor_llcrypto::pk::ed25519::Ed25519Identity;
     use tor_memquota::ArcMemoryQuotaTrackerExt as _;
 
     use crate::ChannelUsage as CU;
-    use tor_rtcompat::{Runtime, task::yield_now, test_with_one_runtime};
+    use tor_rtcompat::{Runtime, SleepProvider as _, task::yield_now, test_with_one_runtime};
 
     #[derive(Clone)]
     struct FakeChannelFactory<RT> {
         runtime: RT,
+        /// The number of channels this factory has tried to build.
+        n_builds: Arc<AtomicUsize>,
     }
 
     #[derive(Clone, Debug)]
@@ -478,6 +734,9 @@ mod test {
         mood: char,
         closing: Arc<AtomicBool>,
         detect_reuse: Arc<char>,
+        traffic_class: Arc<Mutex<Option<TrafficClass>>>,
+        /// If present, the clock to use, and the time since which this channel has been unused.
+        unused_since: Option<(DynTimeProvider, Instant)>,
         // last_params: Option<ChannelPaddingInstructionsUpdates>,
     }
 
@@ -491,8 +750,13 @@ mod test {
         fn is_usable(&self) -> bool {
             !self.closing.load(Ordering::SeqCst)
         }
+        fn close_reason(&self) -> Option<ChanCloseReason> {
+            (!self.is_usable()).then_some(ChanCloseReason::PeerClosed)
+        }
         fn duration_unused(&self) -> Option<Duration> {
-            None
+            self.unused_since
+                .as_ref()
+                .map(|(clock, since)| clock.now().saturating_duration_since(*since))
         }
         fn reparameterize(
             &self,
@@ -505,6 +769,18 @@ mod test {
             Ok(())
         }
         fn engage_padding_activities(&self) {}
+        fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+            None
+        }
+        fn set_traffic_class(&self, class: TrafficClass) {
+            *self.traffic_class.lock().unwrap() = Some(class);
+        }
+        fn padding_counts(&self) -> PaddingCounts {
+            PaddingCounts::default()
+        }
+        fn terminate(&self) {
+            self.closing.store(true, Ordering::SeqCst);
+        }
     }
 
     impl HasRelayIds for FakeChannel {
@@ -527,11 +803,15 @@ mod test {
 
     impl<RT: Runtime> FakeChannelFactory<RT> {
         fn new(runtime: RT) -> Self {
-            FakeChannelFactory { runtime }
+            FakeChannelFactory {
+                runtime,
+                n_builds: Default::default(),
+            }
         }
     }
 
     fn new_test_abstract_chanmgr<R: Runtime>(runtime: R) -> AbstractChanMgr<FakeChannelFactory<R>> {
+        let time_provider = DynTimeProvider::new(runtime.clone());
         let cf = FakeChannelFactory::new(runtime);
         AbstractChanMgr::new(
             cf,
@@ -540,6 +820,7 @@ mod test {
             &Default::default(),
             BootstrapReporter::fake(),
             ToplevelAccount::new_noop(),
+            time_provider,
         )
     }
 
@@ -574,9 +855,10 @@ mod test {
         async fn build_channel(
             &self,
             target: &Self::BuildSpec,
-            _reporter: BootstrapReporter,
+            reporter: BootstrapReporter,
             _memquota: ChannelAccount,
         ) -> Result<Arc<FakeChannel>> {
+            self.n_builds.fetch_add(1, Ordering::SeqCst);
             yield_now().await;
             let FakeBuildSpec(ident, mood, id) = *target;
             let ed_ident = u32_to_ed(ident);
@@ -584,17 +866,36 @@ mod test {
             match mood {
                 // "X" means never connect.
                 '❌' | '🔥' => return Err(Error::UnusableTarget(bad_api_usage!("emoji"))),
+                // "Plug" means the relay is unreachable, after a short while.
+                '🔌' => {
+                    yield_now().await;
+                    return Err(Error::ChannelBuild { addresses: vec![] });
+                }
+                // "Hourglass" means wait for 15 seconds then fail.
+                '⌛' => {
+                    self.runtime.sleep(Duration::new(15, 0)).await;
+                    return Err(Error::ChannelBuild { addresses: vec![] });
+                }
                 // "zzz" means wait for 15 seconds then succeed.
                 '💤' => {
+                    reporter.record_progress(ChanBuildProgress::TcpConnected);
                     self.runtime.sleep(Duration::new(15, 0)).await;
                 }
                 _ => {}
             }
+            // "Yawn" means the channel never carries any circuits.
+            let unused_since = (mood == '🥱').then(|| {
+                let clock = DynTimeProvider::new(self.runtime.clone());
+                let now = clock.now();
+                (clock, now)
+            });
             Ok(Arc::new(FakeChannel {
                 ed_ident,
                 mood,
                 closing: Arc::new(AtomicBool::new(false)),
                 detect_reuse: Default::default(),
+                traffic_class: Default::default(),
+                unused_since,
                 // last_params: None,
             }))
         }
@@ -610,6 +911,35 @@ mod test {
         }
     }
 
+    #[test]
+    fn traffic_class() {
+        test_with_one_runtime!(|runtime| async {
+            let mgr = new_test_abstract_chanmgr(runtime);
+            let target = FakeBuildSpec(413, '!', u32_to_ed(413));
+            let class = |chan: &FakeChannel| *chan.traffic_class.lock().unwrap();
+
+            let chan = mgr
+                .get_or_launch(target.clone(), CU::UselessCircuit)
+                .await
+                .unwrap()
+                .0;
+            assert_eq!(class(&chan), Some(TrafficClass::Bulk));
+
+            // A more latency-sensitive request upgrades the channel...
+            let chan2 = mgr
+                .get_or_launch_with_class(target.clone(), CU::Dir, TrafficClass::Interactive)
+                .await
+                .unwrap()
+                .0;
+            assert_eq!(chan, chan2);
+            assert_eq!(class(&chan), Some(TrafficClass::Interactive));
+
+            // ...but a less latency-sensitive one doesn't downgrade it.
+            let _ = mgr.get_or_launch(target, CU::Dir).await.unwrap();
+            assert_eq!(class(&chan), Some(TrafficClass::Interactive));
+        });
+    }
+
     #[test]
     fn connect_one_ok() {
         test_with_one_runtime!(|runtime| async {
@@ -624,6 +954,73 @@ mod test {
 
             assert_eq!(chan1, chan2);
             assert_eq!(mgr.get_nowait(&u32_to_ed(413)), vec![chan1]);
+
+            let addrs = mgr.open_channel_addrs();
+            assert_eq!(addrs.len(), 1);
+            assert_eq!(addrs[0].0.ed_identity(), Some(&u32_to_ed(413)));
+            assert_eq!(addrs[0].1, None);
+        });
+    }
+
+    #[test]
+    fn prewarm() {
+        test_with_one_runtime!(|runtime| async {
+            let mgr = new_test_abstract_chanmgr(runtime);
+
+            let targets = vec![
+                FakeBuildSpec(3, 'a', u32_to_ed(3)),
+                FakeBuildSpec(4, 'a', u32_to_ed(4)),
+                FakeBuildSpec(86, '❌', u32_to_ed(86)),
+                FakeBuildSpec(5, 'a', u32_to_ed(5)),
+            ];
+            mgr.prewarm(targets, 2).await;
+
+            // We built every channel that we could, and kept going after the failure.
+            for id in [3, 4, 5] {
+                assert_eq!(mgr.get_nowait(&u32_to_ed(id)).len(), 1);
+            }
+            assert!(mgr.get_nowait(&u32_to_ed(86)).is_empty());
+
+            // Prewarmed channels are reused.
+            let chan3 = mgr.get_nowait(&u32_to_ed(3)).remove(0);
+            let (chan, provenance) = mgr
+                .get_or_launch(FakeBuildSpec(3, 'b', u32_to_ed(3)), CU::UserTraffic)
+                .await
+                .unwrap();
+            assert_eq!(chan, chan3);
+            assert_eq!(provenance, ChanProvenance::Preexisting);
+        });
+    }
+
+    #[test]
+    fn replace_builder() {
+        test_with_one_runtime!(|runtime| async {
+            let mgr = new_test_abstract_chanmgr(runtime.clone());
+            let gen0 = mgr.factory_generation();
+
+            let chan1 = mgr
+                .get_or_launch(FakeBuildSpec(1, 'a', u32_to_ed(1)), CU::UserTraffic)
+                .await
+                .unwrap()
+                .0;
+            assert_eq!(mgr.factory_generation_of(&chan1), Some(gen0));
+
+            let _old = mgr.replace_builder(|_| FakeChannelFactory::new(runtime));
+            let gen1 = mgr.factory_generation();
+            assert!(gen1 > gen0);
+
+            // New channels are tagged with the new factory; old ones keep their tag.
+            let chan2 = mgr
+                .get_or_launch(FakeBuildSpec(2, 'a', u32_to_ed(2)), CU::UserTraffic)
+                .await
+                .unwrap()
+                .0;
+            assert_eq!(mgr.factory_generation_of(&chan2), Some(gen1));
+            assert_eq!(mgr.factory_generation_of(&chan1), Some(gen0));
+
+            // Modifying the factory counts as replacing it.
+            mgr.with_mut_builder(|_| {});
+            assert!(mgr.factory_generation() > gen1);
         });
     }
 
@@ -635,12 +1032,97 @@ mod test {
             // This is set up to always fail.
             let target = FakeBuildSpec(999, '❌', u32_to_ed(999));
             let res1 = mgr.get_or_launch(target, CU::UserTraffic).await;
-            assert!(matches!(res1, Err(Error::UnusableTarget(_))));
+            let err = res1.unwrap_err();
+            assert!(matches!(
+                err.without_build_attempt(),
+                Error::UnusableTarget(_)
+            ));
+            // The error tells us which attempt failed.
+            assert!(err.build_attempt().is_some());
 
             assert!(mgr.get_nowait(&u32_to_ed(999)).is_empty());
         });
     }
 
+    #[test]
+    fn events() {
+        use crate::event::ChanMgrEventKind as K;
+        use futures::{FutureExt as _, StreamExt as _};
+
+        test_with_one_runtime!(|runtime| async {
+            let mgr = new_test_abstract_chanmgr(runtime);
+            let mut events = mgr.subscribe_events();
+            let mut drain = || {
+                std::iter::from_fn(|| events.next().now_or_never().flatten())
+                    .map(|ev| (ev.ids.ed_identity().copied(), ev.kind))
+                    .collect::<Vec<_>>()
+            };
+
+            let chan = mgr
+                .get_or_launch(FakeBuildSpec(413, '!', u32_to_ed(413)), CU::UserTraffic)
+                .await
+                .unwrap()
+                .0;
+            let ed = Some(u32_to_ed(413));
+            assert_eq!(
+                drain(),
+                vec![(ed, K::PendingStarted), (ed, K::ChannelOpened)]
+            );
+
+            // We try twice before giving up.
+            let _ = mgr
+                .get_or_launch(FakeBuildSpec(999, '❌', u32_to_ed(999)), CU::UserTraffic)
+                .await
+                .unwrap_err();
+            let ed = Some(u32_to_ed(999));
+            let failed = K::ChannelFailed(crate::ChanFailureClass::Local);
+            assert_eq!(
+                drain(),
+                vec![
+                    (ed, K::PendingStarted),
+                    (ed, failed),
+                    (ed, K::PendingStarted),
+                    (ed, failed),
+                ]
+            );
+
+            // We tell our subscribers about a closed channel once we notice it.
+            chan.start_closing();
+            assert!(drain().is_empty());
+            let _ = mgr.recently_closed(&u32_to_ed(413));
+            let ed = Some(u32_to_ed(413));
+            assert_eq!(
+                drain(),
+                vec![(ed, K::ChannelClosed(ChanCloseReason::PeerClosed))]
+            );
+        });
+    }
+
+    #[test]
+    fn pending_failure_class() {
+        test_with_one_runtime!(|runtime| async {
+            let mgr = new_test_abstract_chanmgr(runtime);
+
+            // The second request waits for the channel launched by the first one,
+            // and learns why it failed.
+            let (res_a, res_b) = join!(
+                mgr.get_or_launch(FakeBuildSpec(7, '🔌', u32_to_ed(7)), CU::UserTraffic),
+                mgr.get_or_launch(FakeBuildSpec(7, '🔌', u32_to_ed(7)), CU::UserTraffic),
+            );
+            for err in [res_a.unwrap_err(), res_b.unwrap_err()] {
+                assert_eq!(err.failure_class(), crate::ChanFailureClass::TcpUnreachable);
+            }
+
+            let res = mgr
+                .get_or_launch(FakeBuildSpec(8, '❌', u32_to_ed(8)), CU::UserTraffic)
+                .await;
+            assert_eq!(
+                res.unwrap_err().failure_class(),
+                crate::ChanFailureClass::Local
+            );
+        });
+    }
+
     #[test]
     fn test_concurrent() {
         test_with_one_runtime!(|runtime| async {
@@ -668,8 +1150,101 @@ mod test {
             assert_eq!(ch44a, ch44b);
             assert_ne!(ch44a, ch3a);
 
-            assert!(matches!(err_a, Error::UnusableTarget(_)));
-            assert!(matches!(err_b, Error::UnusableTarget(_)));
+            assert!(matches!(
+                err_a.without_build_attempt(),
+                Error::UnusableTarget(_)
+            ));
+            assert!(matches!(
+                err_b.without_build_attempt(),
+                Error::UnusableTarget(_)
+            ));
+        });
+    }
+
+    #[test]
+    fn pending_progress() {
+        tor_rtmock::MockRuntime::test_with_various(|runtime| async move {
+            let mgr = new_test_abstract_chanmgr(runtime.clone());
+            let target = FakeBuildSpec(7, '💤', u32_to_ed(7));
+
+            let (chan, ()) = join!(mgr.get_or_launch(target, CU::UserTraffic), async {
+                runtime.progress_until_stalled().await;
+
+                let pending = mgr.pending_channel_progress();
+                assert_eq!(pending.len(), 1);
+                let (ids, progress) = &pending[0];
+                assert_eq!(ids.ed_identity(), Some(&u32_to_ed(7)));
+                assert_eq!(progress.current(), ChanBuildProgress::TcpConnected);
+
+                runtime.advance_by(Duration::from_secs(15)).await;
+            });
+
+            assert_eq!(chan.unwrap().0.mood, '💤');
+            assert!(mgr.pending_channel_progress().is_empty());
+        });
+    }
+
+    #[test]
+    fn retry_with_replaced_builder() {
+        tor_rtmock::MockRuntime::test_with_various(|runtime| async move {
+            let mgr = new_test_abstract_chanmgr(runtime.clone());
+            let target = FakeBuildSpec(8, '⌛', u32_to_ed(8));
+            let old_builds = Arc::clone(&mgr.channels.builder().n_builds);
+            let new_builder = FakeChannelFactory::new(runtime.clone());
+            let new_builds = Arc::clone(&new_builder.n_builds);
+
+            let (chan, ()) = join!(mgr.get_or_launch(target, CU::UserTraffic), async {
+                runtime.progress_until_stalled().await;
+                // Replace the builder while the first attempt is in progress.
+                let _old = mgr.replace_builder(|_| new_builder);
+                runtime.advance_by(Duration::from_secs(60)).await;
+            });
+            assert!(chan.is_err());
+
+            // The attempt that failed with the old builder didn't count:
+            // we still made every attempt we could with the new one.
+            assert_eq!(old_builds.load(Ordering::SeqCst), 1);
+            assert_eq!(new_builds.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn warn_about_suspect_leaks() {
+        tor_rtmock::MockRuntime::test_with_various(|runtime| async move {
+            let mgr = new_test_abstract_chanmgr(runtime.clone());
+            let min_unused = Duration::from_secs(15 * 60);
+            let interval = Duration::from_secs(10 * 60);
+            let mut next_check = None;
+
+            let target = FakeBuildSpec(9, '🥱', u32_to_ed(9));
+            let (chan, _) = mgr.get_or_launch(target, CU::UserTraffic).await.unwrap();
+            drop(chan);
+
+            // We look straight away, but the channel hasn't been unused for long enough.
+            let check = |next_check: &mut Option<Instant>| {
+                mgr.warn_about_suspect_leaks(next_check, min_unused, interval)
+            };
+            assert!(check(&mut next_check).is_empty());
+            assert_eq!(next_check, Some(runtime.now() + interval));
+
+            runtime.advance_by(interval).await;
+            assert!(check(&mut next_check).is_empty());
+            assert_eq!(next_check, Some(runtime.now() + interval));
+
+            // The channel is a suspect now, but it isn't time to look again yet.
+            runtime.advance_by(Duration::from_secs(5 * 60)).await;
+            assert!(check(&mut next_check).is_empty());
+
+            runtime.advance_by(Duration::from_secs(5 * 60)).await;
+            let suspects = check(&mut next_check);
+            assert_eq!(suspects.len(), 1);
+            assert_eq!(suspects[0].ids.ed_identity(), Some(&u32_to_ed(9)));
+            assert_eq!(suspects[0].unused_for, Duration::from_secs(20 * 60));
+
+            // We don't warn about it again until the interval has passed.
+            assert!(check(&mut next_check).is_empty());
+            runtime.advance_by(interval).await;
+            assert_eq!(check(&mut next_check).len(), 1);
         });
     }
 
@@ -690,6 +1265,9 @@ mod test {
 
             ch3.start_closing();
             ch5.start_closing();
+            let closed = mgr.recently_closed(&u32_to_ed(5)).unwrap();
+            assert_eq!(closed.reason, ChanCloseReason::PeerClosed);
+            assert!(mgr.recently_closed(&u32_to_ed(4)).is_none());
 
             let ch3_new = mgr
                 .get_or_launch(FakeBuildSpec(3, 'b', u32_to_ed(3)), CU::UserTraffic)
@@ -2,8 +2,58 @@
 
 use crate::mgr::AbstractChannel;
 use crate::mgr::state::{ChannelState, OpenEntry, PendingEntry};
+use std::net::SocketAddr;
 use tor_linkspec::{HasRelayIds, RelayIds};
 
+/// A restriction, beyond the target's relay ids, on which channels a request may use.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) enum ChannelRestriction<'a> {
+    /// Any channel that is allowed for the target.
+    #[default]
+    None,
+    /// Only channels that are canonical for the target,
+    /// meaning that they are connected to one of these addresses.
+    ///
+    /// Requests with this restriction only wait for pending channels
+    /// that can only end up connected to one of these addresses
+    /// (that is, pending channels launched by other requests with this restriction).
+    CanonicalOnly(&'a [SocketAddr]),
+}
+
+impl ChannelRestriction<'_> {
+    /// Returns `true` if this restriction permits using the open channel `chan`.
+    pub(crate) fn permits_open<C: AbstractChannel>(&self, chan: &OpenEntry<C>) -> bool {
+        match self {
+            ChannelRestriction::None => true,
+            ChannelRestriction::CanonicalOnly(addrs) => chan
+                .channel
+                .peer_addr()
+                .is_some_and(|addr| addrs.contains(&addr)),
+        }
+    }
+
+    /// Returns `true` if this restriction permits waiting for the pending channel `chan`.
+    pub(crate) fn permits_pending(&self, chan: &PendingEntry) -> bool {
+        match self {
+            ChannelRestriction::None => true,
+            ChannelRestriction::CanonicalOnly(addrs) => {
+                chan.addrs.as_ref().is_some_and(|chan_addrs| {
+                    !chan_addrs.is_empty() && chan_addrs.iter().all(|addr| addrs.contains(addr))
+                })
+            }
+        }
+    }
+
+    /// Return the addresses to which a channel launched for a request with this restriction
+    /// may connect, if we know them.
+    pub(crate) fn launch_addrs(&self) -> Option<Vec<SocketAddr>> {
+        match self {
+            ChannelRestriction::None => None,
+            ChannelRestriction::CanonicalOnly(addrs) => Some(addrs.to_vec()),
+        }
+    }
+}
+
 /// Returns `true` if the open channel is allowed to be used for a new channel request to the
 /// target.
 pub(crate) fn open_channel_is_allowed<C: AbstractChannel>(
@@ -47,6 +97,9 @@ pub(crate) fn pending_channel_maybe_allowed(
     //
     // NOTE (opara): It has been decided that C-tor's approach would be better. See the thread at:
     // https://gitlab.torproject.org/tpo/core/arti/-/merge_requests/2544#note_3094696
+    //
+    // We now follow C-tor as far as relay ids are concerned: we also wait for a pending channel
+    // whose relay ids are a superset of `target`s (see below).
 
     // We want to avoid returning pending channels that were initially created from malicious
     // channel requests (for example from malicious relay-extend requests) that build channels which
@@ -74,7 +127,20 @@ pub(crate) fn pending_channel_maybe_allowed(
         //   all of the relay ids. So this channel request was doomed from the start.
         // - If the built channel gains additional ids that `target` doesn't have, that's fine and
         //   we can still use the channel for `target`.
-        .filter(|entry| target.has_all_relay_ids_from(&entry.ids))
+        //
+        // Or, channels where `entry`s relay ids are a superset of `target`s relay ids.
+        // - If the channel is built successfully, it will have all of the ids that `target`
+        //   requires, so building another channel for `target` would be wasteful. This is
+        //   common when several requests for the same relay race each other, and only some of
+        //   them know all of the relay's ids.
+        // - This does not fully address 1. above: the pending channel might have been requested
+        //   with an additional incorrect relay id. But if it fails, the waiting requester will
+        //   notice, and will then try to build a channel of its own, so a malicious request can
+        //   only delay a legitimate one by one channel build attempt.
+        .filter(|entry| {
+            target.has_all_relay_ids_from(&entry.ids)
+                || (target.has_any_identity() && entry.ids.has_all_relay_ids_from(target))
+        })
         // TODO: Only channels which have the exact same address list as `target` (the two sets of
         // addresses must match exactly).
         // - While an EXTEND2 message usually only contains one IPv4 and IPv6 address, `target`
@@ -217,6 +283,7 @@ mod test {
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
 
     use super::*;
+    use crate::ChanCloseReason;
 
     use std::sync::Arc;
     use std::time::Duration;
@@ -237,6 +304,9 @@ mod test {
         fn is_usable(&self) -> bool {
             self.usable
         }
+        fn close_reason(&self) -> Option<ChanCloseReason> {
+            (!self.usable).then_some(ChanCloseReason::ProtocolError)
+        }
         fn duration_unused(&self) -> Option<Duration> {
             None
         }
@@ -250,6 +320,14 @@ mod test {
             Ok(())
         }
         fn engage_padding_activities(&self) {}
+        fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+            None
+        }
+        fn set_traffic_class(&self, _class: crate::TrafficClass) {}
+        fn padding_counts(&self) -> tor_proto::channel::padding::PaddingCounts {
+            Default::default()
+        }
+        fn terminate(&self) {}
     }
 
     impl HasRelayIds for FakeChannel {
@@ -333,6 +411,10 @@ mod test {
         OpenEntry {
             channel: Arc::new(chan),
             max_unused_duration: Duration::from_secs(0),
+            close_reason: Default::default(),
+            traffic_class: crate::TrafficClass::Interactive,
+            factory_generation: Default::default(),
+            padding_level: Default::default(),
         }
     }
 
@@ -346,6 +428,8 @@ mod test {
             ids,
             pending: oneshot::channel().1.shared(),
             unique_id: UniqPendingChanId::new(),
+            progress: postage::watch::channel().1,
+            addrs: None,
         }
     }
 
@@ -572,12 +656,18 @@ mod test {
             &target,
         ));
 
-        // not allowed: channel with additional relay id
-        assert!(!pending_channel_maybe_allowed(
+        // allowed: channel with additional relay id
+        assert!(pending_channel_maybe_allowed(
             &pending_channel(ids(rsa(b"X"), ed(b"A"))),
             &target,
         ));
 
+        // not allowed: channel with a different relay id
+        assert!(!pending_channel_maybe_allowed(
+            &pending_channel(ids(rsa(b"X"), ed(b"B"))),
+            &target,
+        ));
+
         // target with multiple relay ids
         let target = FakeBuildSpec::new(ids(rsa(b"X"), ed(b"A")));
 
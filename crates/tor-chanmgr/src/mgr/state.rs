@@ -1,34 +1,51 @@
 //! Simple implementation for the internal map state of a ChanMgr.
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::AbstractChannelFactory;
-use super::{AbstractChannel, Pending, Sending, select};
-use crate::{ChannelConfig, Dormancy, Error, Result};
+use super::{AbstractChannel, Pending, ProgressSending, Sending, select};
+use crate::event::{
+    ChanBuildProgress, ChanBuildProgressEvents, ChanMgrEventKind, ChanMgrEventPublisher,
+    ChanMgrEvents,
+};
+use crate::{
+    ChanCloseReason, ChanFailureClass, ChannelConfig, ClosedChanInfo, Dormancy, Error, Result,
+    TrafficClass,
+};
 
 use futures::FutureExt;
+use postage::watch;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::result::Result as StdResult;
-use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
 use tor_async_utils::oneshot;
 use tor_basic_utils::RngExt as _;
 use tor_cell::chancell::msg::PaddingNegotiate;
 use tor_config::PaddingLevel;
-use tor_error::{error_report, internal, into_internal};
+use tor_error::{debug_report, error_report, internal, into_internal};
 use tor_linkspec::{HasRelayIds, ListByRelayIds, RelayIds};
 use tor_netdir::{params::CHANNEL_PADDING_TIMEOUT_UPPER_BOUND, params::NetParameters};
 use tor_proto::ChannelPaddingInstructions;
 use tor_proto::channel::ChannelPaddingInstructionsUpdates;
 use tor_proto::channel::kist::{KistMode, KistParams};
+use tor_proto::channel::padding::PaddingCounts;
 use tor_proto::channel::padding::Parameters as PaddingParameters;
 use tor_proto::channel::padding::ParametersBuilder as PaddingParametersBuilder;
+use tor_rtcompat::{DynTimeProvider, SleepProvider as _};
 use tor_units::{BoundedInt32, IntegerMilliseconds};
-use tracing::info;
+use tracing::{debug, info};
 use void::{ResultVoidExt as _, Void};
 
 #[cfg(test)]
 mod padding_test;
 
+/// How long we remember a channel after noticing that it has closed.
+///
+/// See [`MgrState::recently_closed`].
+const RECENTLY_CLOSED_RETENTION: Duration = Duration::from_secs(5 * 60);
+
 /// All mutable state held by an `AbstractChannelMgr`.
 ///
 /// One reason that this is an isolated type is that we want to
@@ -41,6 +58,9 @@ pub(crate) struct MgrState<C: AbstractChannelFactory> {
     /// (Danger: this uses a blocking mutex close to async code.  This mutex
     /// must never be held while an await is happening.)
     inner: std::sync::Mutex<Inner<C>>,
+
+    /// The clock we use to timestamp events and closed channels.
+    time_provider: DynTimeProvider,
 }
 
 /// Parameters for channels that we create, and that all existing channels are using
@@ -60,9 +80,26 @@ struct Inner<C: AbstractChannelFactory> {
     /// hand out clones of it when asked.
     builder: C,
 
+    /// The generation of `builder`.
+    ///
+    /// Incremented every time `builder` is replaced or modified.
+    builder_generation: FactoryGeneration,
+
     /// A map from identity to channels, or to pending channel statuses.
     channels: ListByRelayIds<ChannelState<C::Channel>>,
 
+    /// Channels that we have removed from `channels` because they closed,
+    /// and why.
+    ///
+    /// Entries are discarded after [`RECENTLY_CLOSED_RETENTION`].
+    recently_closed: ListByRelayIds<ClosedChanInfo>,
+
+    /// Padding statistics for the channels that we have removed from `channels`,
+    /// by the padding level that was configured when each of them was opened.
+    ///
+    /// See [`MgrState::padding_stats`].
+    removed_padding: HashMap<PaddingLevel, PaddingStats>,
+
     /// Parameters for channels that we create, and that all existing channels are using
     ///
     /// Will be updated by a background task, which also notifies all existing
@@ -81,6 +118,28 @@ struct Inner<C: AbstractChannelFactory> {
     /// Updated via `MgrState::set_dormancy` and hence `MgrState::reconfigure_general`,
     /// which then uses it to calculate how to reconfigure the channels.
     dormancy: Dormancy,
+
+    /// Open channels that failed to accept some of the parameters in `channels_params`.
+    ///
+    /// We try again to send them their missing parameters
+    /// whenever we reconfigure our channels, and whenever we expire channels.
+    stale_params: Vec<StaleParams<C::Channel>>,
+
+    /// The subscribers to our [`ChanMgrEvent`](crate::ChanMgrEvent)s.
+    events: ChanMgrEventPublisher,
+}
+
+/// The parameters that an open channel has failed to accept.
+struct StaleParams<C> {
+    /// The channel.
+    ///
+    /// This is a weak reference so that we don't keep the channel alive
+    /// after it has been removed from our map.
+    channel: Weak<C>,
+    /// All the padding updates that the channel hasn't accepted, combined.
+    padding: Option<ChannelPaddingInstructionsUpdates>,
+    /// Whether the channel hasn't accepted our latest KIST parameters.
+    kist: bool,
 }
 
 /// The state of a channel (or channel build attempt) within a map.
@@ -112,11 +171,87 @@ pub(crate) struct OpenEntry<C> {
     pub(crate) channel: Arc<C>,
     /// The maximum unused duration allowed for this channel.
     pub(crate) max_unused_duration: Duration,
+    /// Why this channel closed, once we have noticed that it has.
+    ///
+    /// We keep closed channels in the map until they expire,
+    /// but we only want to record each closure once.
+    pub(crate) close_reason: OnceLock<ChanCloseReason>,
+    /// The most latency-sensitive class of traffic
+    /// that this channel has been requested for.
+    pub(crate) traffic_class: TrafficClass,
+    /// The generation of the factory that built this channel.
+    pub(crate) factory_generation: FactoryGeneration,
+    /// The padding level that was configured when this channel was opened.
+    pub(crate) padding_level: PaddingLevel,
 }
 
-/// A unique ID for a pending ([`PendingEntry`]) channel.
+/// Statistics about channel padding,
+/// for the channels that were opened while one padding level was configured.
+///
+/// Returned by [`ChanMgr::padding_stats`](crate::ChanMgr::padding_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PaddingStats {
+    /// The number of channels, open or closed, that these statistics cover.
+    pub n_channels: usize,
+    /// The number of padding cells that those channels have sent and received.
+    pub counts: PaddingCounts,
+}
+
+impl PaddingStats {
+    /// Add the padding cells that `channel` has sent and received to these statistics.
+    fn add_channel<C: AbstractChannel>(&mut self, channel: &C) {
+        self.n_channels += 1;
+        self.counts += channel.padding_counts();
+    }
+}
+
+/// An open channel that looks as if it may have leaked, or got stuck.
+///
+/// Returned by [`ChanMgr::suspect_leaked_channels`](crate::ChanMgr::suspect_leaked_channels).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SuspectChannel {
+    /// The identities of the relay at the other end of the channel.
+    pub ids: RelayIds,
+    /// The address to which the channel is connected, if we know it.
+    pub peer_addr: Option<SocketAddr>,
+    /// How long the channel has had no circuits.
+    pub unused_for: Duration,
+}
+
+/// Identifies which of a channel manager's factories built a channel.
+///
+/// A channel manager's factory can be replaced, or reconfigured, while it is running
+/// (for example, to start using pluggable transports).
+/// Each time this happens, the factory gets a new generation, greater than all earlier ones;
+/// every channel that the channel manager builds
+/// is tagged with the generation of the factory that built it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FactoryGeneration(u64);
+
+impl FactoryGeneration {
+    /// Return the generation that comes after this one.
+    fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl std::fmt::Display for FactoryGeneration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Factory generation {}", self.0)
+    }
+}
+
+/// A unique ID for an attempt to build a channel.
+///
+/// These IDs appear in the tracing spans for channel builds,
+/// and in the errors from failed builds,
+/// so that log messages and errors from the same attempt can be matched up.
+///
+/// They are only unique within a single process.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct UniqPendingChanId(u64);
+pub struct UniqPendingChanId(u64);
 
 impl UniqPendingChanId {
     /// Construct a new `UniqPendingChanId`.
@@ -152,6 +287,15 @@ pub(crate) struct PendingEntry {
 
     /// A unique ID that allows us to find this exact pending entry later.
     pub(crate) unique_id: UniqPendingChanId,
+
+    /// A receiver we can clone to learn how far this channel attempt has progressed.
+    pub(crate) progress: watch::Receiver<ChanBuildProgress>,
+
+    /// The addresses to which this channel may end up connected, if we know them.
+    ///
+    /// We only know them for channels launched by requests
+    /// that were restricted to canonical channels.
+    pub(crate) addrs: Option<Vec<SocketAddr>>,
 }
 
 impl<C> HasRelayIds for ChannelState<C>
@@ -298,6 +442,30 @@ impl<C: AbstractChannel> ChannelState<C> {
         *expire_after = std::cmp::min(*expire_after, remaining);
         false
     }
+
+    /// If this is an open channel that may have leaked, return a description of it.
+    ///
+    /// That is the case if the channel is still usable,
+    /// but has had no circuits for at least `min_unused`,
+    /// and nothing but our map refers to it.
+    /// We would usually have expired such a channel long ago.
+    fn as_suspected_leak(&self, min_unused: Duration) -> Option<SuspectChannel> {
+        let ChannelState::Open(ent) = self else {
+            return None;
+        };
+        if Arc::strong_count(&ent.channel) != 1 || !ent.channel.is_usable() {
+            return None;
+        }
+        let unused_for = ent
+            .channel
+            .duration_unused()
+            .filter(|unused| *unused >= min_unused)?;
+        Some(SuspectChannel {
+            ids: RelayIds::from_relay_ids(&*ent.channel),
+            peer_addr: ent.channel.peer_addr(),
+            unused_for,
+        })
+    }
 }
 
 impl<C: AbstractChannelFactory> MgrState<C> {
@@ -307,6 +475,7 @@ impl<C: AbstractChannelFactory> MgrState<C> {
         config: ChannelConfig,
         dormancy: Dormancy,
         netparams: &NetParameters,
+        time_provider: DynTimeProvider,
     ) -> Self {
         let mut padding_params = ChannelPaddingInstructions::default();
         let netparams = NetParamsExtract::from(netparams);
@@ -323,14 +492,25 @@ impl<C: AbstractChannelFactory> MgrState<C> {
         MgrState {
             inner: std::sync::Mutex::new(Inner {
                 builder,
+                builder_generation: FactoryGeneration::default(),
                 channels: ListByRelayIds::new(),
+                recently_closed: ListByRelayIds::new(),
+                removed_padding: HashMap::new(),
                 config,
                 channels_params,
                 dormancy,
+                stale_params: Vec::new(),
+                events: ChanMgrEventPublisher::default(),
             }),
+            time_provider,
         }
     }
 
+    /// Return the current time, according to the clock we were given.
+    pub(crate) fn now(&self) -> Instant {
+        self.time_provider.now()
+    }
+
     /// Run a function on the [`ListByRelayIds`] that implements the map in this `MgrState`.
     ///
     /// This function grabs a mutex: do not provide a slow function.
@@ -361,8 +541,24 @@ impl<C: AbstractChannelFactory> MgrState<C> {
         inner.builder.clone()
     }
 
+    /// Return a copy of the builder stored in this state, along with its generation.
+    pub(crate) fn builder_with_generation(&self) -> (C, FactoryGeneration)
+    where
+        C: Clone,
+    {
+        let inner = self.inner.lock().expect("lock poisoned");
+        (inner.builder.clone(), inner.builder_generation)
+    }
+
+    /// Return the generation of the builder stored in this state.
+    pub(crate) fn builder_generation(&self) -> FactoryGeneration {
+        self.inner.lock().expect("lock poisoned").builder_generation
+    }
+
     /// Run a function to modify the builder stored in this state.
     ///
+    /// The modified builder gets a new [`FactoryGeneration`].
+    ///
     /// # Deadlock
     ///
     /// Calling a method on [`MgrState`] from within `func` may cause a deadlock.
@@ -373,15 +569,61 @@ impl<C: AbstractChannelFactory> MgrState<C> {
     {
         let mut inner = self.inner.lock().expect("lock poisoned");
         func(&mut inner.builder);
+        inner.builder_generation = inner.builder_generation.next();
+    }
+
+    /// Replace the builder stored in this state with the one returned by `func`,
+    /// and return the old one.
+    ///
+    /// `func` is given the old builder.
+    /// The new builder gets a new [`FactoryGeneration`].
+    ///
+    /// Channels that are already being built keep using the old builder;
+    /// once they are open, they stay tagged with its generation.
+    pub(crate) fn replace_builder<F>(&self, func: F) -> C
+    where
+        F: FnOnce(&C) -> C,
+    {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        let builder = func(&inner.builder);
+        inner.builder_generation = inner.builder_generation.next();
+        debug!(
+            "Replaced channel factory; now using {}",
+            inner.builder_generation
+        );
+        std::mem::replace(&mut inner.builder, builder)
+    }
+
+    /// Return the generation of the factory that built the open `channel`.
+    ///
+    /// Returns `None` if `channel` is not in our map.
+    pub(crate) fn factory_generation_of(
+        &self,
+        channel: &Arc<C::Channel>,
+    ) -> Option<FactoryGeneration> {
+        let inner = self.inner.lock().expect("lock poisoned");
+        // We need only one relay id to locate the channel.
+        let id = channel.identities().next()?;
+        inner.channels.by_id(id).find_map(|c| match c {
+            ChannelState::Open(ent) if Arc::ptr_eq(&ent.channel, channel) => {
+                Some(ent.factory_generation)
+            }
+            _ => None,
+        })
     }
 
     /// Remove every unusable state from the map in this state.
     #[cfg(test)]
     pub(crate) fn remove_unusable(&self) -> Result<()> {
         let mut inner = self.inner.lock()?;
+        let inner = &mut *inner;
+        let removed_padding = &mut inner.removed_padding;
         inner.channels.retain(|state| match state {
-            ChannelState::Open(ent) => ent.channel.is_usable(),
-            ChannelState::Building(_) => true,
+            ChannelState::Open(ent) if !ent.channel.is_usable() => {
+                note_removed_padding(removed_padding, ent);
+                false
+            }
+            _ => true,
         });
         Ok(())
     }
@@ -390,10 +632,13 @@ impl<C: AbstractChannelFactory> MgrState<C> {
     /// an open or pending channel isn't found, a new pending entry will be added and
     /// [`ChannelForTarget::NewEntry`] will be returned. This is all done as part of the same method
     /// so that all operations are performed under the same lock acquisition.
+    ///
+    /// Only channels permitted by `restriction` are returned.
     pub(crate) fn request_channel(
         &self,
         target: &C::BuildSpec,
         add_new_entry_if_not_found: bool,
+        restriction: select::ChannelRestriction<'_>,
     ) -> Result<Option<ChannelForTarget<C>>> {
         use ChannelState::*;
 
@@ -417,7 +662,9 @@ impl<C: AbstractChannelFactory> MgrState<C> {
             // channels with all target relay identifiers
             .by_all_ids(target)
             .filter(|entry| match entry {
-                Open(x) => select::open_channel_is_allowed(x, target),
+                Open(x) => {
+                    select::open_channel_is_allowed(x, target) && restriction.permits_open(x)
+                }
                 Building(_) => false,
             });
 
@@ -428,9 +675,19 @@ impl<C: AbstractChannelFactory> MgrState<C> {
             // channels that have a subset of the relay ids of `target`
             .all_subset(target)
             .into_iter()
+            // channels that have a strict superset of the relay ids of `target`
+            .chain(
+                inner
+                    .channels
+                    .by_all_ids(target)
+                    .filter(|entry| !target.has_all_relay_ids_from(*entry)),
+            )
             .filter(|entry| match entry {
                 Open(_) => false,
-                Building(x) => select::pending_channel_maybe_allowed(x, target),
+                Building(x) => {
+                    restriction.permits_pending(x)
+                        && select::pending_channel_maybe_allowed(x, target)
+                }
             });
 
         match select::choose_best_channel(open_channels.chain(pending_channels), target) {
@@ -473,18 +730,26 @@ impl<C: AbstractChannelFactory> MgrState<C> {
             return Ok(None);
         }
 
+        // Stay within our limit on open channels, if we have one.
+        inner.make_room_for_new_channel(self.now())?;
+
         // Great, nothing interfered at all.
         let any_relay_id = target
             .identities()
             .next()
             .ok_or(internal!("relay target had no id"))?
             .to_owned();
-        let (new_state, send, unique_id) = setup_launch(RelayIds::from_relay_ids(target));
+        let ids = RelayIds::from_relay_ids(target);
+        let (new_state, send, progress, unique_id) =
+            setup_launch(ids.clone(), restriction.launch_addrs());
         inner
             .channels
             .try_insert(ChannelState::Building(new_state))?;
+        inner
+            .events
+            .publish(&ids, ChanMgrEventKind::PendingStarted, self.now());
         let handle = PendingChannelHandle::new(any_relay_id, unique_id);
-        Ok(Some(ChannelForTarget::NewEntry((handle, send))))
+        Ok(Some(ChannelForTarget::NewEntry((handle, send, progress))))
     }
 
     /// Remove the pending channel identified by its `handle`.
@@ -496,10 +761,14 @@ impl<C: AbstractChannelFactory> MgrState<C> {
 
     /// Upgrade the pending channel identified by its `handle` by replacing it with a new open
     /// `channel`.
+    ///
+    /// The channel is marked with the traffic `class` it was requested for.
     pub(crate) fn upgrade_pending_channel_to_open(
         &self,
         handle: PendingChannelHandle,
         channel: Arc<C::Channel>,
+        class: TrafficClass,
+        factory_generation: FactoryGeneration,
     ) -> Result<()> {
         // Do all operations under the same lock acquisition.
         let mut inner = self.inner.lock()?;
@@ -520,6 +789,12 @@ impl<C: AbstractChannelFactory> MgrState<C> {
                 .reparameterize(update.into())
                 .map_err(|_| internal!("failure on new channel"))?;
         }
+        channel.set_traffic_class(class);
+        inner.events.publish(
+            &RelayIds::from_relay_ids(&*channel),
+            ChanMgrEventKind::ChannelOpened,
+            self.now(),
+        );
         let new_entry = ChannelState::Open(OpenEntry {
             channel,
             max_unused_duration: Duration::from_secs(
@@ -527,12 +802,67 @@ impl<C: AbstractChannelFactory> MgrState<C> {
                     .gen_range_checked(180..270)
                     .expect("not 180 < 270 !"),
             ),
+            close_reason: OnceLock::new(),
+            traffic_class: class,
+            factory_generation,
+            padding_level: inner.config.padding,
         });
         inner.channels.insert(new_entry);
 
         Ok(())
     }
 
+    /// Tell our subscribers that we failed to build a channel to `target`,
+    /// with a failure of `class`.
+    pub(crate) fn note_build_failed(&self, target: &C::BuildSpec, class: ChanFailureClass) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        inner.events.publish(
+            &RelayIds::from_relay_ids(target),
+            ChanMgrEventKind::ChannelFailed(class),
+            self.now(),
+        );
+    }
+
+    /// Return a stream of every [`ChanMgrEvent`](crate::ChanMgrEvent) from now on.
+    pub(crate) fn subscribe_events(&self) -> ChanMgrEvents {
+        self.inner.lock().expect("Poisoned lock").events.subscribe()
+    }
+
+    /// Note that the open `channel` has been requested for traffic of `class`.
+    ///
+    /// If the channel was marked with a less latency-sensitive class,
+    /// mark it with `class` instead, and tell the channel.
+    ///
+    /// Does nothing if `channel` is not in our map.
+    pub(crate) fn note_traffic_class(
+        &self,
+        channel: &Arc<C::Channel>,
+        class: TrafficClass,
+    ) -> Result<()> {
+        let mut inner = self.inner.lock()?;
+
+        // We need only one relay id to locate the channel.
+        let Some(id) = channel.identities().next() else {
+            return Ok(());
+        };
+        let removed = inner.channels.remove_by_id(id, |c| match c {
+            ChannelState::Open(ent) => {
+                Arc::ptr_eq(&ent.channel, channel) && ent.traffic_class < class
+            }
+            ChannelState::Building(_) => false,
+        });
+
+        for mut entry in removed {
+            if let ChannelState::Open(ent) = &mut entry {
+                ent.traffic_class = class;
+                ent.channel.set_traffic_class(class);
+            }
+            inner.channels.insert(entry);
+        }
+
+        Ok(())
+    }
+
     /// Reconfigure all channels as necessary
     ///
     /// (By reparameterizing channels as needed)
@@ -594,26 +924,94 @@ impl<C: AbstractChannelFactory> MgrState<C> {
 
         if update.is_none() && kist_params.is_none() {
             // Return early, nothing to reconfigure
+            inner.retry_stale_params();
             return Ok(());
         }
 
-        for channel in inner.channels.values() {
-            let channel = match channel {
-                CS::Open(OpenEntry { channel, .. }) => channel,
-                CS::Building(_) => continue,
-            };
+        let channels: Vec<_> = inner
+            .channels
+            .values()
+            .filter_map(|channel| match channel {
+                CS::Open(OpenEntry { channel, .. }) => Some(Arc::clone(channel)),
+                CS::Building(_) => None,
+            })
+            .collect();
+        for channel in channels {
+            inner.reparameterize_channel(&channel, update.as_deref(), kist_params.is_some());
+        }
+        inner.retry_stale_params();
 
-            if let Some(ref update) = update {
-                // Ignore error (which simply means the channel is closed or gone)
-                let _ = channel.reparameterize(Arc::clone(update));
-            }
+        Ok(())
+    }
 
-            if let Some(kist) = kist_params {
-                // Ignore error (which simply means the channel is closed or gone)
-                let _ = channel.reparameterize_kist(kist);
+    /// Return the number of usable open channels that have failed to accept
+    /// our latest channel parameters, and are therefore running with stale ones.
+    pub(crate) fn n_channels_with_stale_params(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("Poisoned lock")
+            .stale_params
+            .iter()
+            .filter(|stale| {
+                stale
+                    .channel
+                    .upgrade()
+                    .is_some_and(|channel| channel.is_usable())
+            })
+            .count()
+    }
+
+    /// Return padding statistics for all of our channels, by padding level.
+    ///
+    /// Each channel is counted under the padding level that was configured
+    /// when it was opened.
+    /// Channels that we have removed from our map are still counted.
+    pub(crate) fn padding_stats(&self) -> HashMap<PaddingLevel, PaddingStats> {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        let mut stats = inner.removed_padding.clone();
+        for chan in inner.channels.values() {
+            if let ChannelState::Open(ent) = chan {
+                stats
+                    .entry(ent.padding_level)
+                    .or_default()
+                    .add_channel(&*ent.channel);
             }
         }
-        Ok(())
+        stats
+    }
+
+    /// Return the identities and build progress of every pending channel.
+    pub(crate) fn pending_channel_progress(&self) -> Vec<(RelayIds, ChanBuildProgressEvents)> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock")
+            .channels
+            .values()
+            .filter_map(|chan| match chan {
+                ChannelState::Open(_) => None,
+                ChannelState::Building(ent) => Some((
+                    ent.ids.clone(),
+                    ChanBuildProgressEvents::new(ent.progress.clone()),
+                )),
+            })
+            .collect()
+    }
+
+    /// Return the identities and peer address of every usable open channel.
+    pub(crate) fn open_channel_addrs(&self) -> Vec<(RelayIds, Option<SocketAddr>)> {
+        self.inner
+            .lock()
+            .expect("Poisoned lock")
+            .channels
+            .values()
+            .filter_map(|chan| match chan {
+                ChannelState::Open(ent) if ent.channel.is_usable() => Some((
+                    RelayIds::from_relay_ids(&*ent.channel),
+                    ent.channel.peer_addr(),
+                )),
+                _ => None,
+            })
+            .collect()
     }
 
     /// Expire all channels that have been unused for too long.
@@ -621,16 +1019,303 @@ impl<C: AbstractChannelFactory> MgrState<C> {
     /// Return a Duration until the next time at which
     /// a channel _could_ expire.
     pub(crate) fn expire_channels(&self) -> Duration {
+        let now = self.now();
         let mut ret = Duration::from_secs(180);
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let inner = &mut *inner;
+        inner.note_closed_channels(now);
+        inner.retry_stale_params();
+
+        let mut expired = vec![];
+        let removed_padding = &mut inner.removed_padding;
+        inner.channels.retain(|chan| {
+            if !chan.ready_to_expire(&mut ret) {
+                return true;
+            }
+            if let ChannelState::Open(ent) = chan {
+                note_removed_padding(removed_padding, ent);
+            }
+            match chan {
+                // Closed channels have already been recorded.
+                ChannelState::Open(ent) if ent.channel.is_usable() => {
+                    expired.push(ClosedChanInfo {
+                        ids: RelayIds::from_relay_ids(&*ent.channel),
+                        reason: ChanCloseReason::ExpiredIdle,
+                        noticed_at: now,
+                    })
+                }
+                _ => {}
+            }
+            false
+        });
+        for info in expired {
+            inner.remember_closed(info);
+        }
+        ret
+    }
+
+    /// Return every open channel that may have leaked.
+    ///
+    /// See [`ChannelState::as_suspected_leak`] for what we look for.
+    pub(crate) fn suspect_leaked_channels(&self, min_unused: Duration) -> Vec<SuspectChannel> {
         self.inner
             .lock()
             .expect("Poisoned lock")
             .channels
-            .retain(|chan| !chan.ready_to_expire(&mut ret));
-        ret
+            .values()
+            .filter_map(|chan| chan.as_suspected_leak(min_unused))
+            .collect()
+    }
+
+    /// Shut down, and remove from our map, every open channel that may have leaked.
+    ///
+    /// Return the channels that we closed.
+    pub(crate) fn close_suspect_leaked_channels(
+        &self,
+        min_unused: Duration,
+    ) -> Vec<SuspectChannel> {
+        let now = self.now();
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let inner = &mut *inner;
+
+        let mut closed = vec![];
+        let removed_padding = &mut inner.removed_padding;
+        inner.channels.retain(|chan| {
+            let Some(suspect) = chan.as_suspected_leak(min_unused) else {
+                return true;
+            };
+            if let ChannelState::Open(ent) = chan {
+                note_removed_padding(removed_padding, ent);
+                // Dropping our reference would usually be enough to close the channel,
+                // but a channel that has got stuck might not notice.
+                ent.channel.terminate();
+            }
+            closed.push(suspect);
+            false
+        });
+        for suspect in &closed {
+            inner.remember_closed(ClosedChanInfo {
+                ids: suspect.ids.clone(),
+                reason: ChanCloseReason::SuspectedLeak,
+                noticed_at: now,
+            });
+        }
+        closed
+    }
+
+    /// Return information about the most recent channel with the identity `ident`,
+    /// if it closed within the last [`RECENTLY_CLOSED_RETENTION`].
+    pub(crate) fn recently_closed<'a, T>(&self, ident: T) -> Option<ClosedChanInfo>
+    where
+        T: Into<tor_linkspec::RelayIdRef<'a>>,
+    {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        // Make sure we have noticed every channel that has closed by now.
+        inner.note_closed_channels(self.now());
+        inner
+            .recently_closed
+            .by_id(ident)
+            .max_by_key(|info| info.noticed_at)
+            .cloned()
     }
 }
 
+impl<C: AbstractChannelFactory> Inner<C> {
+    /// Send the padding `update` to the open `channel`,
+    /// and our current KIST parameters too if `kist` is true,
+    /// along with any parameters that the channel failed to accept earlier.
+    ///
+    /// If the channel doesn't accept them, remember them in `stale_params`,
+    /// so that we can try again later.
+    fn reparameterize_channel(
+        &mut self,
+        channel: &Arc<C::Channel>,
+        update: Option<&ChannelPaddingInstructionsUpdates>,
+        kist: bool,
+    ) {
+        let mut stale = match self
+            .stale_params
+            .iter()
+            .position(|stale| Weak::as_ptr(&stale.channel) == Arc::as_ptr(channel))
+        {
+            Some(idx) => self.stale_params.swap_remove(idx),
+            None => StaleParams {
+                channel: Arc::downgrade(channel),
+                padding: None,
+                kist: false,
+            },
+        };
+
+        // Combine the new update with any that the channel hasn't accepted yet,
+        // so that we send the channel all of its missing changes, in order.
+        if let Some(update) = update {
+            stale
+                .padding
+                .get_or_insert_with(Default::default)
+                .combine(update);
+        }
+        stale.kist |= kist;
+
+        if let Some(padding) = stale.padding.take() {
+            let padding = Arc::new(padding);
+            if let Err(e) = channel.reparameterize(Arc::clone(&padding)) {
+                debug_report!(e, "Failed to update channel padding parameters");
+                stale.padding = Some(Arc::unwrap_or_clone(padding));
+            }
+        }
+        if stale.kist {
+            if let Err(e) = channel.reparameterize_kist(self.channels_params.kist) {
+                debug_report!(e, "Failed to update channel KIST parameters");
+            } else {
+                stale.kist = false;
+            }
+        }
+
+        if stale.padding.is_some() || stale.kist {
+            self.stale_params.push(stale);
+        }
+    }
+
+    /// Try again to send their missing parameters to the channels in `stale_params`.
+    ///
+    /// Channels that have closed, or that we no longer have, are forgotten.
+    fn retry_stale_params(&mut self) {
+        for stale in std::mem::take(&mut self.stale_params) {
+            let Some(channel) = stale.channel.upgrade() else {
+                continue;
+            };
+            if !channel.is_usable() {
+                continue;
+            }
+            self.stale_params.push(stale);
+            self.reparameterize_channel(&channel, None, false);
+        }
+    }
+
+    /// Record every open channel that has closed since we last looked,
+    /// and forget about channels that closed too long before `now`.
+    fn note_closed_channels(&mut self, now: Instant) {
+        self.recently_closed.retain(|info| {
+            now.saturating_duration_since(info.noticed_at) < RECENTLY_CLOSED_RETENTION
+        });
+
+        let closed: Vec<_> = self
+            .channels
+            .values()
+            .filter_map(|chan| match chan {
+                ChannelState::Open(ent) if !ent.channel.is_usable() => {
+                    // If we can't tell why it closed yet, we'll find out next time.
+                    let reason = ent.channel.close_reason()?;
+                    ent.close_reason.set(reason).ok()?;
+                    Some(ClosedChanInfo {
+                        ids: RelayIds::from_relay_ids(&*ent.channel),
+                        reason,
+                        noticed_at: now,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        for info in closed {
+            self.remember_closed(info);
+        }
+    }
+
+    /// If we are at our configured limit on open (or opening) channels,
+    /// close idle channels, least recently used first, until there is room for one more.
+    ///
+    /// Channels that are in use are never closed.
+    /// If we can't make room without closing one of them,
+    /// return [`Error::TooManyChannels`].
+    fn make_room_for_new_channel(&mut self, now: Instant) -> Result<()> {
+        let Some(limit) = self.config.max_open_channels else {
+            return Ok(());
+        };
+        let limit = limit.get();
+
+        let n_channels = self
+            .channels
+            .values()
+            .filter(|chan| match chan {
+                // Closed channels are only here so that we can notice why they closed.
+                ChannelState::Open(ent) => ent.channel.is_usable(),
+                ChannelState::Building(_) => true,
+            })
+            .count();
+
+        // We need to close enough channels that we can open one more.
+        let Some(n_to_close) = (n_channels + 1).checked_sub(limit).filter(|n| *n > 0) else {
+            return Ok(());
+        };
+
+        // Idle channels, least recently used first.
+        let mut idle = self
+            .channels
+            .values()
+            .filter_map(|chan| match chan {
+                ChannelState::Open(ent) if ent.channel.is_usable() => {
+                    Some((ent.channel.duration_unused()?, Arc::clone(&ent.channel)))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if idle.len() < n_to_close {
+            return Err(Error::TooManyChannels { limit });
+        }
+        idle.sort_by_key(|(unused, _)| std::cmp::Reverse(*unused));
+        idle.truncate(n_to_close);
+
+        let removed_padding = &mut self.removed_padding;
+        self.channels.retain(|chan| match chan {
+            ChannelState::Open(ent) if idle.iter().any(|(_, c)| Arc::ptr_eq(&ent.channel, c)) => {
+                note_removed_padding(removed_padding, ent);
+                false
+            }
+            _ => true,
+        });
+        for (_, channel) in idle {
+            let ids = RelayIds::from_relay_ids(&*channel);
+            debug!(
+                "Closed idle channel to {} to stay within our limit of {} channels",
+                ids.display_relay_ids(),
+                limit,
+            );
+            self.remember_closed(ClosedChanInfo {
+                ids,
+                reason: ChanCloseReason::Evicted,
+                noticed_at: now,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add `info` to our list of recently closed channels.
+    ///
+    /// Also tell our subscribers that the channel has closed.
+    fn remember_closed(&mut self, info: ClosedChanInfo) {
+        self.events.publish(
+            &info.ids,
+            ChanMgrEventKind::ChannelClosed(info.reason),
+            info.noticed_at,
+        );
+        // This can only fail if the channel had no identities.
+        let _ = self.recently_closed.try_insert(info);
+    }
+}
+
+/// Record the padding statistics of `ent`, which we are removing from our map,
+/// in `removed_padding`.
+fn note_removed_padding<C: AbstractChannel>(
+    removed_padding: &mut HashMap<PaddingLevel, PaddingStats>,
+    ent: &OpenEntry<C>,
+) {
+    removed_padding
+        .entry(ent.padding_level)
+        .or_default()
+        .add_channel(&*ent.channel);
+}
+
 /// A channel for a given target relay.
 pub(crate) enum ChannelForTarget<CF: AbstractChannelFactory> {
     /// A channel that is open.
@@ -638,7 +1323,7 @@ pub(crate) enum ChannelForTarget<CF: AbstractChannelFactory> {
     /// A channel that is building.
     Pending(Pending),
     /// Information about a new pending channel entry.
-    NewEntry((PendingChannelHandle, Sending)),
+    NewEntry((PendingChannelHandle, Sending, ProgressSending)),
 }
 
 /// A handle for a pending channel.
@@ -667,6 +1352,11 @@ impl PendingChannelHandle {
         }
     }
 
+    /// Return the unique ID of the pending channel.
+    pub(crate) fn unique_id(&self) -> UniqPendingChanId {
+        self.unique_id
+    }
+
     /// This should be called when the pending channel has been removed from the pending channel
     /// map. Not calling this will result in an error log message (and panic in debug builds) when
     /// this handle is dropped.
@@ -688,18 +1378,25 @@ impl std::ops::Drop for PendingChannelHandle {
     }
 }
 
-/// Helper: return the objects used to inform pending tasks about a newly open or failed channel.
-fn setup_launch(ids: RelayIds) -> (PendingEntry, Sending, UniqPendingChanId) {
+/// Helper: return the objects used to inform pending tasks about a newly open or failed channel,
+/// and about the progress of the attempt to build it.
+fn setup_launch(
+    ids: RelayIds,
+    addrs: Option<Vec<SocketAddr>>,
+) -> (PendingEntry, Sending, ProgressSending, UniqPendingChanId) {
     let (snd, rcv) = oneshot::channel();
     let pending = rcv.shared();
+    let (progress_snd, progress) = watch::channel();
     let unique_id = UniqPendingChanId::new();
     let entry = PendingEntry {
         ids,
         pending,
         unique_id,
+        progress,
+        addrs,
     };
 
-    (entry, snd, unique_id)
+    (entry, snd, progress_snd, unique_id)
 }
 
 /// Helper: remove the pending channel identified by `handle` from `channel_map`.
@@ -869,11 +1566,15 @@ mod test {
 
     use super::*;
     use crate::factory::BootstrapReporter;
+    use crate::mgr::select::ChannelRestriction as CR;
     use async_trait::async_trait;
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::AtomicBool;
     use std::sync::{Arc, Mutex};
     use tor_llcrypto::pk::ed25519::Ed25519Identity;
     use tor_proto::channel::params::ChannelPaddingInstructionsUpdates;
     use tor_proto::memquota::ChannelAccount;
+    use tor_rtmock::simple_time::SimpleMockTimeProvider;
 
     fn new_test_state() -> MgrState<FakeChannelFactory> {
         MgrState::new(
@@ -881,6 +1582,7 @@ mod test {
             ChannelConfig::default(),
             Default::default(),
             &Default::default(),
+            DynTimeProvider::new(SimpleMockTimeProvider::from_real()),
         )
     }
 
@@ -922,11 +1624,20 @@ mod test {
         usable: bool,
         unused_duration: Option<u64>,
         params_update: Arc<Mutex<Option<Arc<ChannelPaddingInstructionsUpdates>>>>,
+        /// If true, fail to accept any new parameters.
+        reject_params: Arc<AtomicBool>,
+        padding: PaddingCounts,
+        peer_addr: Option<SocketAddr>,
+        /// Set once we have been told to shut down.
+        terminated: Arc<AtomicBool>,
     }
     impl AbstractChannel for FakeChannel {
         fn is_usable(&self) -> bool {
             self.usable
         }
+        fn close_reason(&self) -> Option<ChanCloseReason> {
+            (!self.usable).then_some(ChanCloseReason::ProtocolError)
+        }
         fn duration_unused(&self) -> Option<Duration> {
             self.unused_duration.map(Duration::from_secs)
         }
@@ -934,13 +1645,29 @@ mod test {
             &self,
             update: Arc<ChannelPaddingInstructionsUpdates>,
         ) -> tor_proto::Result<()> {
+            if self.reject_params.load(Ordering::SeqCst) {
+                return Err(tor_proto::Error::CircuitClosed);
+            }
             *self.params_update.lock().unwrap() = Some(update);
             Ok(())
         }
         fn reparameterize_kist(&self, _kist_params: KistParams) -> tor_proto::Result<()> {
+            if self.reject_params.load(Ordering::SeqCst) {
+                return Err(tor_proto::Error::CircuitClosed);
+            }
             Ok(())
         }
         fn engage_padding_activities(&self) {}
+        fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+            self.peer_addr
+        }
+        fn set_traffic_class(&self, _class: TrafficClass) {}
+        fn padding_counts(&self) -> PaddingCounts {
+            self.padding
+        }
+        fn terminate(&self) {
+            self.terminated.store(true, Ordering::SeqCst);
+        }
     }
     impl tor_linkspec::HasRelayIds for FakeChannel {
         fn identity(
@@ -964,10 +1691,18 @@ mod test {
             usable: true,
             unused_duration: None,
             params_update: Arc::new(Mutex::new(None)),
+            reject_params: Default::default(),
+            padding: Default::default(),
+            peer_addr: None,
+            terminated: Default::default(),
         };
         ChannelState::Open(OpenEntry {
             channel: Arc::new(channel),
             max_unused_duration: Duration::from_secs(180),
+            close_reason: Default::default(),
+            traffic_class: TrafficClass::Interactive,
+            factory_generation: Default::default(),
+            padding_level: Default::default(),
         })
     }
     fn ch_with_details(
@@ -980,10 +1715,18 @@ mod test {
             usable: true,
             unused_duration,
             params_update: Arc::new(Mutex::new(None)),
+            reject_params: Default::default(),
+            padding: Default::default(),
+            peer_addr: None,
+            terminated: Default::default(),
         };
         ChannelState::Open(OpenEntry {
             channel: Arc::new(channel),
             max_unused_duration,
+            close_reason: Default::default(),
+            traffic_class: TrafficClass::Interactive,
+            factory_generation: Default::default(),
+            padding_level: Default::default(),
         })
     }
     fn closed(ident: &'static str) -> ChannelState<FakeChannel> {
@@ -992,13 +1735,69 @@ mod test {
             usable: false,
             unused_duration: None,
             params_update: Arc::new(Mutex::new(None)),
+            reject_params: Default::default(),
+            padding: Default::default(),
+            peer_addr: None,
+            terminated: Default::default(),
         };
         ChannelState::Open(OpenEntry {
             channel: Arc::new(channel),
             max_unused_duration: Duration::from_secs(180),
+            close_reason: Default::default(),
+            traffic_class: TrafficClass::Interactive,
+            factory_generation: Default::default(),
+            padding_level: Default::default(),
         })
     }
 
+    #[test]
+    fn padding_stats() -> Result<()> {
+        let map = new_test_state();
+
+        /// Return an open channel to `ident`, at padding level `level`,
+        /// that has sent and received `n` padding cells.
+        fn padded(
+            ident: &'static str,
+            level: PaddingLevel,
+            usable: bool,
+            n: u64,
+        ) -> ChannelState<FakeChannel> {
+            let state = if usable { ch(ident) } else { closed(ident) };
+            let ChannelState::Open(mut ent) = state else {
+                unreachable!()
+            };
+            let mut channel = (*ent.channel).clone();
+            channel.padding.sent = n;
+            channel.padding.received = n * 2;
+            ent.channel = Arc::new(channel);
+            ent.padding_level = level;
+            ChannelState::Open(ent)
+        }
+
+        map.with_channels(|map| {
+            map.insert(padded("Ambrose", PaddingLevel::Normal, true, 10));
+            map.insert(padded("Bierce", PaddingLevel::Normal, false, 5));
+            map.insert(padded("Chesterton", PaddingLevel::Reduced, true, 1));
+        })?;
+
+        let stats = map.padding_stats();
+        assert_eq!(stats.len(), 2);
+        let normal = stats[&PaddingLevel::Normal];
+        assert_eq!(normal.n_channels, 2);
+        assert_eq!(normal.counts.sent, 15);
+        assert_eq!(normal.counts.received, 30);
+        let reduced = stats[&PaddingLevel::Reduced];
+        assert_eq!(reduced.n_channels, 1);
+        assert_eq!(reduced.counts.sent, 1);
+
+        // Channels that we remove from the map are still counted.
+        map.remove_unusable()?;
+        assert_eq!(map.with_channels(|map| map.values().count())?, 2);
+        assert_eq!(map.padding_stats(), stats);
+
+        Ok(())
+    }
+
     #[test]
     fn rmv_unusable() -> Result<()> {
         let map = new_test_state();
@@ -1082,6 +1881,59 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn retry_stale_params() -> Result<()> {
+        let map = new_test_state();
+
+        // Set some non-default parameters, so that the netdir causes an update.
+        let _ = map
+            .inner
+            .lock()
+            .unwrap()
+            .channels_params
+            .padding
+            .start_update()
+            .padding_parameters(
+                PaddingParametersBuilder::default()
+                    .low(1234.into())
+                    .build()
+                    .unwrap(),
+            )
+            .finish();
+
+        let ch = ch("track");
+        let ChannelState::Open(ent) = &ch else {
+            panic!("not open");
+        };
+        let channel = Arc::clone(&ent.channel);
+        map.with_channels(|map| {
+            map.insert(ch);
+        })?;
+
+        let netdir = tor_netdir::testnet::construct_netdir()
+            .unwrap_if_sufficient()
+            .unwrap();
+        let netdir = Arc::new(netdir);
+
+        // The channel doesn't accept the update, so we remember it as stale.
+        channel.reject_params.store(true, Ordering::SeqCst);
+        map.reconfigure_general(None, None, netdir).unwrap();
+        assert!(channel.params_update.lock().unwrap().is_none());
+        assert_eq!(map.n_channels_with_stale_params(), 1);
+
+        // We keep trying...
+        let _ = map.expire_channels();
+        assert_eq!(map.n_channels_with_stale_params(), 1);
+
+        // ...until it accepts the update.
+        channel.reject_params.store(false, Ordering::SeqCst);
+        let _ = map.expire_channels();
+        assert!(channel.params_update.lock().unwrap().is_some());
+        assert_eq!(map.n_channels_with_stale_params(), 0);
+
+        Ok(())
+    }
+
     #[test]
     fn expire_channels() -> Result<()> {
         let map = new_test_state();
@@ -1138,4 +1990,314 @@ mod test {
         })?;
         Ok(())
     }
+
+    #[test]
+    fn recently_closed() -> Result<()> {
+        let map = new_test_state();
+        map.with_channels(|map| {
+            map.insert(closed("hello"));
+            map.insert(ch_with_details(
+                "gello",
+                Duration::from_secs(180),
+                Some(181),
+            ));
+            map.insert(ch("wello"));
+        })?;
+
+        let info = map.recently_closed(&str_to_ed("h")).unwrap();
+        assert_eq!(info.reason, ChanCloseReason::ProtocolError);
+        assert_eq!(info.ids.ed_identity(), Some(&str_to_ed("h")));
+
+        // This one is only closed once it expires.
+        assert!(map.recently_closed(&str_to_ed("g")).is_none());
+        map.expire_channels();
+        let info = map.recently_closed(&str_to_ed("g")).unwrap();
+        assert_eq!(info.reason, ChanCloseReason::ExpiredIdle);
+
+        // This one is still open.
+        assert!(map.recently_closed(&str_to_ed("w")).is_none());
+
+        // We only record each closure once.
+        let n_closed = map.inner.lock().unwrap().recently_closed.values().count();
+        assert_eq!(n_closed, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn recently_closed_expires() -> Result<()> {
+        let time = SimpleMockTimeProvider::from_real();
+        let map = MgrState::new(
+            FakeChannelFactory::default(),
+            ChannelConfig::default(),
+            Default::default(),
+            &Default::default(),
+            DynTimeProvider::new(time.clone()),
+        );
+        map.with_channels(|map| {
+            map.insert(closed("hello"));
+        })?;
+        assert!(map.recently_closed(&str_to_ed("h")).is_some());
+
+        // We forget the closure once it is old enough by the runtime's clock.
+        time.advance(RECENTLY_CLOSED_RETENTION - Duration::from_secs(1));
+        assert!(map.recently_closed(&str_to_ed("h")).is_some());
+        time.advance(Duration::from_secs(1));
+        assert!(map.recently_closed(&str_to_ed("h")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn suspect_leaked_channels() -> Result<()> {
+        let min_unused = Duration::from_secs(3600);
+        let map = new_test_state();
+        // A channel that somebody else still refers to.
+        let held = ch_with_details("held", Duration::from_secs(180), Some(7200));
+        let ChannelState::Open(held_ent) = &held else {
+            panic!("not open");
+        };
+        let held_chan = Arc::clone(&held_ent.channel);
+        map.with_channels(|map| {
+            // Idle for long enough, but not referred to from anywhere else.
+            map.insert(ch_with_details(
+                "leak",
+                Duration::from_secs(180),
+                Some(7200),
+            ));
+            // Not idle for long enough.
+            map.insert(ch_with_details("idle", Duration::from_secs(180), Some(200)));
+            // In use.
+            map.insert(ch("used"));
+            map.insert(closed("closed"));
+            map.insert(held);
+        })?;
+
+        let suspects = map.suspect_leaked_channels(min_unused);
+        assert_eq!(suspects.len(), 1);
+        assert_eq!(suspects[0].ids.ed_identity(), Some(&str_to_ed("l")));
+        assert_eq!(suspects[0].unused_for, Duration::from_secs(7200));
+
+        let leaked = map.with_channels(|map| match map.by_ed25519(&str_to_ed("l")).next() {
+            Some(ChannelState::Open(ent)) => Arc::clone(&ent.channel),
+            _ => panic!("no channel"),
+        })?;
+        // Now we hold a reference too, so it isn't a suspect any more.
+        assert!(map.suspect_leaked_channels(min_unused).is_empty());
+        let leaked_terminated = Arc::clone(&leaked.terminated);
+        drop(leaked);
+
+        let closed = map.close_suspect_leaked_channels(min_unused);
+        assert_eq!(closed.len(), 1);
+        assert!(leaked_terminated.load(Ordering::SeqCst));
+        assert!(!held_chan.terminated.load(Ordering::SeqCst));
+        map.with_channels(|map| {
+            assert_eq!(map.by_ed25519(&str_to_ed("l")).count(), 0);
+            assert_eq!(map.by_ed25519(&str_to_ed("h")).count(), 1);
+        })?;
+        let info = map.recently_closed(&str_to_ed("l")).unwrap();
+        assert_eq!(info.reason, ChanCloseReason::SuspectedLeak);
+        assert!(map.suspect_leaked_channels(min_unused).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn channel_limit() -> Result<()> {
+        let target = |ed: &str| {
+            tor_linkspec::OwnedChanTarget::builder()
+                .ed_identity(str_to_ed(ed))
+                .build()
+                .unwrap()
+        };
+        let is_new = |r: &Option<ChannelForTarget<FakeChannelFactory>>| {
+            matches!(r, Some(ChannelForTarget::NewEntry(_)))
+        };
+        let mut config = ChannelConfig::builder();
+        config.max_open_channels(NonZeroUsize::new(3));
+        let map = MgrState::new(
+            FakeChannelFactory::default(),
+            config.build().unwrap(),
+            Default::default(),
+            &Default::default(),
+            DynTimeProvider::new(SimpleMockTimeProvider::from_real()),
+        );
+
+        map.with_channels(|map| {
+            map.insert(ch_with_details("aaa", Duration::from_secs(180), Some(10)));
+            map.insert(ch_with_details("bbb", Duration::from_secs(180), Some(20)));
+            map.insert(ch("ccc"));
+            // Closed channels don't count towards the limit.
+            map.insert(closed("ddd"));
+        })?;
+
+        // We're at the limit, so the least recently used idle channel goes.
+        assert!(is_new(&map.request_channel(
+            &target("e"),
+            true,
+            CR::None
+        )?));
+        map.with_channels(|map| {
+            assert_eq!(map.by_ed25519(&str_to_ed("a")).len(), 1);
+            assert_eq!(map.by_ed25519(&str_to_ed("b")).len(), 0);
+        })?;
+        let info = map.recently_closed(&str_to_ed("b")).unwrap();
+        assert_eq!(info.reason, ChanCloseReason::Evicted);
+
+        // Asking for a channel we already have doesn't close anything.
+        assert!(matches!(
+            map.request_channel(&target("a"), true, CR::None)?,
+            Some(ChannelForTarget::Open(_))
+        ));
+
+        // Pending channels count towards the limit too.
+        assert!(is_new(&map.request_channel(
+            &target("f"),
+            true,
+            CR::None
+        )?));
+        map.with_channels(|map| {
+            assert_eq!(map.by_ed25519(&str_to_ed("a")).len(), 0);
+        })?;
+
+        // Channels that are in use are never closed.
+        assert!(matches!(
+            map.request_channel(&target("g"), true, CR::None),
+            Err(Error::TooManyChannels { limit: 3 })
+        ));
+        map.with_channels(|map| {
+            assert_eq!(map.by_ed25519(&str_to_ed("c")).len(), 1);
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn coalesce_pending_requests() {
+        let target = |ed: Option<&str>, rsa: Option<u8>| {
+            let mut builder = tor_linkspec::OwnedChanTarget::builder();
+            if let Some(ed) = ed {
+                builder.ed_identity(str_to_ed(ed));
+            }
+            if let Some(rsa) = rsa {
+                builder.rsa_identity([rsa; 20].into());
+            }
+            builder.build().unwrap()
+        };
+        let is_new = |r: &Option<ChannelForTarget<FakeChannelFactory>>| {
+            matches!(r, Some(ChannelForTarget::NewEntry(_)))
+        };
+        let is_pending = |r: &Option<ChannelForTarget<FakeChannelFactory>>| {
+            matches!(r, Some(ChannelForTarget::Pending(_)))
+        };
+
+        // A pending channel with more ids than the request is shared.
+        let state = new_test_state();
+        let full = state
+            .request_channel(&target(Some("A"), Some(1)), true, CR::None)
+            .unwrap();
+        assert!(is_new(&full));
+        let ed_only = state
+            .request_channel(&target(Some("A"), None), true, CR::None)
+            .unwrap();
+        assert!(is_pending(&ed_only));
+        let rsa_only = state
+            .request_channel(&target(None, Some(1)), true, CR::None)
+            .unwrap();
+        assert!(is_pending(&rsa_only));
+
+        // ... but not with requests that disagree about one of its ids.
+        let other_rsa = state
+            .request_channel(&target(Some("A"), Some(2)), true, CR::None)
+            .unwrap();
+        assert!(is_new(&other_rsa));
+
+        // A pending channel with fewer ids than the request is shared, as before.
+        let state = new_test_state();
+        let ed_only = state
+            .request_channel(&target(Some("A"), None), true, CR::None)
+            .unwrap();
+        assert!(is_new(&ed_only));
+        let full = state
+            .request_channel(&target(Some("A"), Some(1)), true, CR::None)
+            .unwrap();
+        assert!(is_pending(&full));
+    }
+
+    #[test]
+    fn canonical_only() -> Result<()> {
+        let canonical: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+        let other: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let target = || {
+            tor_linkspec::OwnedChanTarget::builder()
+                .ed_identity(str_to_ed("a"))
+                .addrs(vec![canonical])
+                .build()
+                .unwrap()
+        };
+        let with_addr = |addr| {
+            let ChannelState::Open(mut ent) = ch("aaa") else {
+                panic!("not open");
+            };
+            let mut channel = (*ent.channel).clone();
+            channel.peer_addr = addr;
+            ent.channel = Arc::new(channel);
+            ChannelState::Open(ent)
+        };
+
+        // A channel to some other address is fine, unless we insist on a canonical one.
+        let map = new_test_state();
+        map.with_channels(|map| map.insert(with_addr(Some(other))))?;
+        assert!(matches!(
+            map.request_channel(&target(), false, CR::None)?,
+            Some(ChannelForTarget::Open(_))
+        ));
+        assert!(matches!(
+            map.request_channel(&target(), true, CR::CanonicalOnly(&[canonical]))?,
+            Some(ChannelForTarget::NewEntry(_))
+        ));
+        // We wait for a pending channel launched by another canonical request,
+        // since it can only connect to one of the same addresses...
+        assert!(matches!(
+            map.request_channel(&target(), true, CR::CanonicalOnly(&[canonical]))?,
+            Some(ChannelForTarget::Pending(_))
+        ));
+        // ...but not if it might connect to an address we didn't ask for.
+        assert!(matches!(
+            map.request_channel(&target(), true, CR::CanonicalOnly(&[other]))?,
+            Some(ChannelForTarget::NewEntry(_))
+        ));
+
+        // We don't wait for a pending channel launched by an unrestricted request,
+        // since we can't tell where it will connect to.
+        let map = new_test_state();
+        assert!(matches!(
+            map.request_channel(&target(), true, CR::None)?,
+            Some(ChannelForTarget::NewEntry(_))
+        ));
+        assert!(matches!(
+            map.request_channel(&target(), true, CR::CanonicalOnly(&[canonical]))?,
+            Some(ChannelForTarget::NewEntry(_))
+        ));
+        // Unrestricted requests can wait for either.
+        assert!(matches!(
+            map.request_channel(&target(), true, CR::None)?,
+            Some(ChannelForTarget::Pending(_))
+        ));
+
+        // A channel to one of the target's addresses is canonical.
+        let map = new_test_state();
+        map.with_channels(|map| map.insert(with_addr(Some(canonical))))?;
+        assert!(matches!(
+            map.request_channel(&target(), false, CR::CanonicalOnly(&[canonical]))?,
+            Some(ChannelForTarget::Open(_))
+        ));
+
+        // A channel whose address we don't know isn't.
+        let map = new_test_state();
+        map.with_channels(|map| map.insert(with_addr(None)))?;
+        assert!(
+            map.request_channel(&target(), false, CR::CanonicalOnly(&[canonical]))?
+                .is_none()
+        );
+
+        Ok(())
+    }
 }
@@ -30,6 +30,7 @@ use tor_memquota::ArcMemoryQuotaTrackerExt as _;
 use tor_netdir::NetDir;
 use tor_proto::channel::{Channel, CtrlMsg};
 use tor_proto::memquota::{ChannelAccount, ToplevelAccount};
+use tor_rtmock::simple_time::SimpleMockTimeProvider;
 
 use crate::ChannelUsage;
 use crate::mgr::{AbstractChanMgr, AbstractChannelFactory};
@@ -194,6 +195,7 @@ async fn case(level: PaddingLevel, dormancy: Dormancy, usage: ChannelUsage) -> C
         &netparams,
         BootstrapReporter::fake(),
         ToplevelAccount::new_noop(),
+        DynTimeProvider::new(SimpleMockTimeProvider::from_real()),
     );
 
     let (channel, _prov) = chanmgr.get_or_launch(relay_ids, usage).await.unwrap();
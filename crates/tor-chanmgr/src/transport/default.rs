@@ -1,33 +1,124 @@
 //! Implement the default transport, which opens TCP connections using a
 //! happy-eyeballs style parallel algorithm.
 
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use futures::{FutureExt, StreamExt, TryFutureExt, stream::FuturesUnordered};
 use safelog::sensitive as sv;
 use tor_error::bad_api_usage;
-use tor_linkspec::{ChannelMethod, HasChanMethod, OwnedChanTarget};
+use tor_linkspec::{ChannelMethod, HasChanMethod, HasRelayIds as _, OwnedChanTarget, RelayIds};
 use tor_rtcompat::{NetStreamProvider, Runtime};
 use tracing::trace;
 
-use crate::Error;
+use crate::{AddressFamilyPreference, Error};
 
 /// A default transport object that opens TCP connections for a
 /// `ChannelMethod::Direct`.
 ///
 /// It opens almost-simultaneous parallel TCP connections to each address, and
 /// chooses the first one to succeed.
+/// The order in which it starts those connections is decided by an [`AddrPolicy`],
+/// which is shared between all the clones of this object.
 #[derive(Clone, Debug)]
 pub(crate) struct DefaultTransport<R: Runtime> {
     /// The runtime that we use for connecting.
     runtime: R,
+    /// How we decide which addresses to try first.
+    policy: Arc<Mutex<AddrPolicy>>,
 }
 
 impl<R: Runtime> DefaultTransport<R> {
     /// Construct a new DefaultTransport
-    pub(crate) fn new(runtime: R) -> Self {
-        Self { runtime }
+    pub(crate) fn new(runtime: R, preference: AddressFamilyPreference) -> Self {
+        let policy = AddrPolicy {
+            preference,
+            last_success: HashMap::new(),
+        };
+        Self {
+            runtime,
+            policy: Arc::new(Mutex::new(policy)),
+        }
+    }
+
+    /// Change which address family we try first.
+    pub(crate) fn set_preferred_address_family(&self, preference: AddressFamilyPreference) {
+        self.policy.lock().expect("Lock poisoned").preference = preference;
+    }
+}
+
+/// The maximum number of relays for which an [`AddrPolicy`] remembers a working address.
+///
+/// This is comfortably more than the number of relays in the network.
+const MAX_REMEMBERED_ADDRS: usize = 16384;
+
+/// The policy that decides in which order we try to connect to a relay's addresses.
+#[derive(Debug)]
+struct AddrPolicy {
+    /// Which address family to try first.
+    preference: AddressFamilyPreference,
+    /// For each relay, the address at which we most recently reached it.
+    last_success: HashMap<RelayIds, SocketAddr>,
+}
+
+impl AddrPolicy {
+    /// Return `addrs`, in the order in which we should try to connect to them,
+    /// for the relay with identities `ids`.
+    ///
+    /// The address at which we last reached the relay (if any) comes first.
+    /// The rest follow in order, if we have no preference;
+    /// otherwise, we alternate between the two families,
+    /// starting with the preferred one, as recommended by RFC 8305.
+    fn order_addrs(&self, ids: &RelayIds, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let last_success = self
+            .last_success
+            .get(ids)
+            .filter(|addr| addrs.contains(addr))
+            .copied();
+        let rest = addrs
+            .iter()
+            .copied()
+            .filter(|addr| Some(*addr) != last_success);
+
+        let prefer_ipv6 = match self.preference {
+            AddressFamilyPreference::Any => return last_success.into_iter().chain(rest).collect(),
+            AddressFamilyPreference::Ipv4 => false,
+            AddressFamilyPreference::Ipv6 => true,
+        };
+
+        let (preferred, other): (Vec<_>, Vec<_>) =
+            rest.partition(|addr| addr.is_ipv6() == prefer_ipv6);
+        let mut preferred = preferred.into_iter();
+        let mut other = other.into_iter();
+
+        let mut ordered: Vec<_> = last_success.into_iter().collect();
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => break,
+                (a, b) => ordered.extend(a.into_iter().chain(b)),
+            }
+        }
+        ordered
+    }
+
+    /// Note that we reached the relay with identities `ids` at `addr`.
+    fn note_success(&mut self, ids: RelayIds, addr: SocketAddr) {
+        if !self.last_success.contains_key(&ids) && self.last_success.len() >= MAX_REMEMBERED_ADDRS
+        {
+            // Forget about some relay; it doesn't much matter which.
+            let victim = self.last_success.keys().next().cloned();
+            if let Some(victim) = victim {
+                self.last_success.remove(&victim);
+            }
+        }
+        self.last_success.insert(ids, addr);
+    }
+
+    /// Note that we failed to reach the relay with identities `ids` at any of its addresses.
+    fn note_failure(&mut self, ids: &RelayIds) {
+        self.last_success.remove(ids);
     }
 }
 
@@ -53,7 +144,23 @@ impl<R: Runtime> crate::transport::TransportImplHelper for DefaultTransport<R> {
 
         trace!("Launching direct connection for {}", target);
 
-        let (stream, addr) = connect_to_one(&self.runtime, &direct_addrs).await?;
+        let ids = RelayIds::from_relay_ids(target);
+        let remember = ids.has_any_identity();
+        let ordered_addrs = self
+            .policy
+            .lock()
+            .expect("Lock poisoned")
+            .order_addrs(&ids, &direct_addrs);
+
+        let outcome = connect_to_one(&self.runtime, &ordered_addrs).await;
+        if remember {
+            let mut policy = self.policy.lock().expect("Lock poisoned");
+            match &outcome {
+                Ok((_stream, addr)) => policy.note_success(ids, *addr),
+                Err(_) => policy.note_failure(&ids),
+            }
+        }
+        let (stream, addr) = outcome?;
         let mut using_target = target.clone();
         let _ignore = using_target.chan_method_mut().retain_addrs(|a| a == &addr);
 
@@ -84,7 +191,7 @@ async fn connect_to_one<R: Runtime>(
     // simultaneously and returning the results in completion order.
     //
     // This is basically the concurrent-connection stuff from RFC 8305, ish.
-    // TODO(eta): sort the addresses first?
+    // (The caller is responsible for sorting the addresses.)
     let mut connections = addrs
         .iter()
         .enumerate()
@@ -233,4 +340,44 @@ mod test {
             assert_eq!(addr, addr4);
         });
     }
+
+    #[test]
+    fn order_addrs() {
+        let a4 = SocketAddr::from_str("192.0.2.17:443").unwrap();
+        let b4 = SocketAddr::from_str("192.0.2.18:443").unwrap();
+        let a6 = SocketAddr::from_str("[2001:db8::17]:443").unwrap();
+        let b6 = SocketAddr::from_str("[2001:db8::18]:443").unwrap();
+        let addrs = [a4, b4, a6, b6];
+
+        let ids = RelayIds::builder()
+            .ed_identity([7; 32].into())
+            .build()
+            .unwrap();
+        let mut policy = AddrPolicy {
+            preference: AddressFamilyPreference::Any,
+            last_success: HashMap::new(),
+        };
+
+        assert_eq!(policy.order_addrs(&ids, &addrs), addrs);
+        policy.preference = AddressFamilyPreference::Ipv6;
+        assert_eq!(policy.order_addrs(&ids, &addrs), [a6, a4, b6, b4]);
+        policy.preference = AddressFamilyPreference::Ipv4;
+        assert_eq!(policy.order_addrs(&ids, &addrs), [a4, a6, b4, b6]);
+        assert_eq!(policy.order_addrs(&ids, &[a6, b6]), [a6, b6]);
+
+        // An address that worked before goes first, whatever our preference.
+        policy.note_success(ids.clone(), b6);
+        assert_eq!(policy.order_addrs(&ids, &addrs), [b6, a4, a6, b4]);
+        // ... but only for that relay, and only if it's still listed.
+        let other_ids = RelayIds::builder()
+            .ed_identity([8; 32].into())
+            .build()
+            .unwrap();
+        assert_eq!(policy.order_addrs(&other_ids, &addrs), [a4, a6, b4, b6]);
+        assert_eq!(policy.order_addrs(&ids, &[a4, a6]), [a4, a6]);
+
+        // Once it stops working, we forget it.
+        policy.note_failure(&ids);
+        assert_eq!(policy.order_addrs(&ids, &addrs), [a4, a6, b4, b6]);
+    }
 }
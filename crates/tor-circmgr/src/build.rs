@@ -12,7 +12,7 @@ use std::sync::{
     atomic::{AtomicU32, Ordering},
 };
 use std::time::{Duration, Instant};
-use tor_chanmgr::{ChanMgr, ChanProvenance, ChannelUsage};
+use tor_chanmgr::{ChanMgr, ChanProvenance, ChannelUsage, TrafficClass};
 use tor_error::into_internal;
 use tor_guardmgr::GuardStatus;
 use tor_linkspec::{ChanTarget, IntoOwnedChanTarget, OwnedChanTarget, OwnedCircTarget};
@@ -86,8 +86,11 @@ async fn create_common<RT: Runtime, CT: ChanTarget>(
     guard_status: &GuardStatusHandle,
     usage: ChannelUsage,
 ) -> Result<PendingClientTunnel> {
-    // Get or construct the channel.
-    let result = chanmgr.get_or_launch(target, usage).await;
+    // Get or construct the channel, and mark it with the class of traffic
+    // that this circuit will carry.
+    let result = chanmgr
+        .get_or_launch_with_class(target, usage, TrafficClass::from(usage))
+        .await;
 
     // Report the clock skew if appropriate, and exit if there has been an error.
     let chan = match result {
@@ -574,10 +577,13 @@ fn circparameters_from_netparameters(
         .map_err(into_internal!(
             "Unable to build CongestionControl params from NetParams"
         ))?;
-    Ok(CircParameters::new(
-        inp.extend_by_ed25519_id.into(),
-        ccontrol,
-    ))
+    let stream_window = |window| {
+        u16::try_from(u32::from(window)).map_err(into_internal!("Stream window out of range"))
+    };
+    let mut params = CircParameters::new(inp.extend_by_ed25519_id.into(), ccontrol);
+    params.stream_send_window_init = stream_window(inp.stream_send_window_init)?;
+    params.stream_recv_window_init = stream_window(inp.stream_recv_window_init)?;
+    Ok(params)
 }
 
 /// Extract a [`CircParameters`] from the [`NetParameters`] from a consensus for an exit circuit or
@@ -1179,4 +1185,32 @@ mod test {
             //assert_eq!(timeouts[1].2, Duration::from_millis(3300));
         });
     }
+
+    #[test]
+    fn stream_windows_from_netparams() {
+        // Without consensus parameters, we use the protocol defaults.
+        let params = exit_circparams_from_netparams(&NetParameters::default()).unwrap();
+        let defaults = CircParameters::new(true, params.ccontrol.clone());
+        assert_eq!(
+            params.stream_send_window_init,
+            defaults.stream_send_window_init
+        );
+        assert_eq!(
+            params.stream_recv_window_init,
+            defaults.stream_recv_window_init
+        );
+
+        let netparams = NetParameters::from_map(
+            &"stream_sendwindow_init=100 stream_recvwindow_init=200"
+                .parse()
+                .unwrap(),
+        );
+        for params in [
+            exit_circparams_from_netparams(&netparams).unwrap(),
+            onion_circparams_from_netparams(&netparams).unwrap(),
+        ] {
+            assert_eq!(params.stream_send_window_init, 100);
+            assert_eq!(params.stream_recv_window_init, 200);
+        }
+    }
 }
@@ -237,10 +237,14 @@ impl HasRetryTime for Error {
             // that we picked a guard or fallback we couldn't use.  A channel to
             // _that_ target will never succeed, but circuit operations using it
             // will do fine.)
-            E::Channel {
-                cause: tor_chanmgr::Error::UnusableTarget(_),
-                ..
-            } => RT::AfterWaiting,
+            E::Channel { cause, .. }
+                if matches!(
+                    cause.without_build_attempt(),
+                    tor_chanmgr::Error::UnusableTarget(_)
+                ) =>
+            {
+                RT::AfterWaiting
+            }
             E::Channel { cause, .. } => cause.retry_time(),
 
             // These errors are safe to delegate.
@@ -842,7 +842,10 @@ mod test {
                 assert!(
                     matches!(
                         err,
-                        Error::VanguardMgrInit(VanguardMgrError::NoSuitableRelay(Layer::Layer3)),
+                        Error::VanguardMgrInit(VanguardMgrError::NoSuitableRelay {
+                            layer: Layer::Layer3,
+                            ..
+                        }),
                     ),
                     "{err:?}"
                 );
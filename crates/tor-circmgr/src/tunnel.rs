@@ -479,6 +479,31 @@ impl ServiceOnionServiceDataTunnel {
                 unique_id: Some(self.tunnel.unique_id()),
             })
     }
+
+    /// As [`allow_stream_requests`](Self::allow_stream_requests),
+    /// but bound the queue of requests that the application has not yet taken
+    /// as `queue` says.
+    #[cfg(feature = "hs-service")]
+    pub async fn allow_stream_requests_with_queue<'a, FILT>(
+        &self,
+        allow_commands: &'a [tor_cell::relaycell::RelayCmd],
+        hop: TargetHop,
+        filter: FILT,
+        queue: &tor_proto::stream::IncomingStreamQueueParams,
+    ) -> Result<impl futures::Stream<Item = tor_proto::stream::IncomingStream> + use<'a, FILT>>
+    where
+        FILT: tor_proto::stream::IncomingStreamRequestFilter,
+    {
+        self.tunnel_ref()
+            .allow_stream_requests_with_queue(allow_commands, hop, filter, queue)
+            .await
+            .map_err(|error| Error::Protocol {
+                action: "allow stream requests",
+                peer: None,
+                error,
+                unique_id: Some(self.tunnel.unique_id()),
+            })
+    }
 }
 
 #[cfg(feature = "hs-service")]
@@ -113,7 +113,7 @@ pub use skew::SkewEstimate;
 
 #[cfg(feature = "vanguards")]
 #[cfg_attr(docsrs, doc(cfg(feature = "vanguards")))]
-pub use vanguards::VanguardMgrError;
+pub use vanguards::{VanguardMgrError, VanguardRejections};
 
 use pending::{PendingRequest, RequestId};
 use sample::{GuardSet, Universe, UniverseRef};
@@ -1910,6 +1910,14 @@ pub struct VanguardConfig {
     #[builder_field_attr(serde(default))]
     #[builder(default)]
     mode: ExplicitOrAuto<VanguardMode>,
+    /// Extra requirements for the relays we select as layer 2 vanguards.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    l2_requirements: VanguardLayerRequirements,
+    /// Extra requirements for the relays we select as layer 3 vanguards.
+    #[builder(sub_builder)]
+    #[builder_field_attr(serde(default))]
+    l3_requirements: VanguardLayerRequirements,
 }
 
 impl VanguardConfig {
@@ -1923,6 +1931,68 @@ impl VanguardConfig {
             ExplicitOrAuto::Explicit(mode) => mode,
         }
     }
+
+    /// Return the extra requirements for layer 2 vanguards.
+    pub fn l2_requirements(&self) -> &VanguardLayerRequirements {
+        &self.l2_requirements
+    }
+
+    /// Return the extra requirements for layer 3 vanguards.
+    pub fn l3_requirements(&self) -> &VanguardLayerRequirements {
+        &self.l3_requirements
+    }
+}
+
+/// Requirements that a relay must meet to be selected as a vanguard of some layer,
+/// in addition to those of the vanguards specification.
+///
+/// The specification requires every vanguard to have the `Fast` and `Stable` flags,
+/// and by default, that is all we require.
+/// Operators who want a stricter choice of (longer-lived) layer 2 vanguards
+/// can set tighter requirements for that layer than for layer 3.
+///
+/// We check these requirements when we select new vanguards,
+/// and whenever we get a new consensus, so that any vanguard
+/// (including one loaded from the vanguard state file)
+/// that stops meeting them is replaced.
+#[derive(Debug, Default, Clone, Eq, PartialEq, derive_builder::Builder)]
+#[builder(build_fn(error = "ConfigBuildError"))]
+#[builder(derive(Debug, Serialize, Deserialize))]
+pub struct VanguardLayerRequirements {
+    /// Whether the relay must have the `Guard` flag.
+    ///
+    /// The directory authorities only give this flag to relays that have been
+    /// reliably available for a long time, with enough bandwidth.
+    /// (Clients can't see how long a relay has been up,
+    /// so this is the closest thing we have to a minimum uptime.)
+    #[builder(default)]
+    require_guard_flag: bool,
+    /// The smallest consensus weight that the relay may have.
+    ///
+    /// This is the relay's bandwidth as measured by the bandwidth authorities,
+    /// in the (roughly kilobytes per second) units of the consensus.
+    #[builder(default)]
+    min_consensus_weight: u32,
+}
+
+impl_standard_builder! { VanguardLayerRequirements }
+
+impl VanguardLayerRequirements {
+    /// Return true if these requirements don't exclude any relay.
+    #[cfg(feature = "vanguards")]
+    pub(crate) fn is_unrestricted(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Return true if `relay` meets these requirements, according to `netdir`.
+    #[cfg(feature = "vanguards")]
+    pub(crate) fn permits(&self, netdir: &NetDir, relay: &Relay<'_>) -> bool {
+        if self.require_guard_flag && !relay.low_level_details().is_suitable_as_guard() {
+            return false;
+        }
+        let min_weight = tor_netdir::RelayWeight::from(u64::from(self.min_consensus_weight));
+        netdir.relay_weight(relay, tor_netdir::WeightRole::Unweighted) >= min_weight
+    }
 }
 
 /// The kind of vanguards to use.
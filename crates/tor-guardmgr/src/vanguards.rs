@@ -4,13 +4,16 @@
 //!
 //! [vanguards spec]: https://spec.torproject.org/vanguards-spec/index.html.
 
+pub mod audit;
 pub mod config;
 mod err;
+mod schedule;
 mod set;
 
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::time::{Duration, SystemTime};
 
+use futures::channel::mpsc;
 use futures::stream::BoxStream;
 use futures::task::SpawnExt as _;
 use futures::{FutureExt as _, future};
@@ -18,28 +21,41 @@ use futures::{StreamExt as _, select_biased};
 use postage::stream::Stream as _;
 use postage::watch;
 use rand::RngCore;
+use rand::rngs::StdRng;
 
 use tor_async_utils::PostageWatchSenderExt as _;
+use tor_basic_utils::retry::RetryDelay;
 use tor_config::ReconfigureError;
-use tor_error::{error_report, internal, into_internal};
+use tor_error::{error_report, into_internal, warn_report};
+use tor_linkspec::RelayIds;
 use tor_netdir::{DirEvent, NetDir, NetDirProvider, Timeliness};
 use tor_persist::{DynStorageHandle, StateMgr};
 use tor_relay_selection::RelaySelector;
-use tor_rtcompat::Runtime;
-use tracing::{debug, info};
+use tor_rtcompat::task::registry::{RegisteredTask, TaskRegistry};
+use tor_rtcompat::{Runtime, SleepProviderExt as _};
+use tracing::{debug, info, warn};
 
 use crate::{RetireCircuits, VanguardMode};
 
-use set::VanguardSets;
+use audit::{VanguardAuditEvent, VanguardAuditRecord, VanguardAuditSink};
+use schedule::RotationSubscriber;
+use set::{VanguardSet, VanguardSets};
 
-use crate::VanguardConfig;
+use crate::{VanguardConfig, VanguardLayerRequirements};
 pub use config::VanguardParams;
-pub use err::VanguardMgrError;
+pub use err::{VanguardMgrError, VanguardRejections};
+pub use schedule::{LayerRotationSchedule, UpcomingRotation, VanguardLifetime};
 pub use set::Vanguard;
 
 /// The key used for storing the vanguard sets to persistent storage using `StateMgr`.
 const STORAGE_KEY: &str = "vanguards";
 
+/// The lowest delay before we retry writing the vanguard sets to storage, after a failure.
+const STORE_RETRY_MIN: Duration = Duration::from_secs(30);
+
+/// The highest delay before we retry writing the vanguard sets to storage, after a failure.
+const STORE_RETRY_MAX: Duration = Duration::from_secs(60 * 60);
+
 /// The vanguard manager.
 pub struct VanguardMgr<R: Runtime> {
     /// The mutable state.
@@ -49,6 +65,13 @@ pub struct VanguardMgr<R: Runtime> {
     /// The persistent storage handle, used for writing the vanguard sets to disk
     /// if full vanguards are enabled.
     storage: DynStorageHandle<VanguardSets>,
+    /// The number of vanguards we have rejected for each reason,
+    /// over all the times [`select_vanguard`](VanguardMgr::select_vanguard)
+    /// failed with [`NoSuitableRelay`](VanguardMgrError::NoSuitableRelay).
+    ///
+    /// This is not part of [`Inner`], because we only hold a read lock on that
+    /// while selecting vanguards.
+    rejections: Mutex<VanguardRejections>,
 }
 
 /// The mutable inner state of [`VanguardMgr`].
@@ -60,6 +83,10 @@ struct Inner {
     // TODO(#1382): we should derive the mode from the
     // vanguards-enabled and vanguards-hs-service consensus params.
     mode: VanguardMode,
+    /// The extra requirements for the relays we select as L2 vanguards.
+    l2_requirements: VanguardLayerRequirements,
+    /// The extra requirements for the relays we select as L3 vanguards.
+    l3_requirements: VanguardLayerRequirements,
     /// The L2 and L3 vanguards.
     ///
     /// The L3 vanguards are only used if we are running in
@@ -102,6 +129,50 @@ struct Inner {
     has_onion_svc: bool,
     /// A channel for sending VanguardConfig changes to the vanguard maintenance task.
     config_tx: watch::Sender<VanguardConfig>,
+    /// The sink for the vanguard audit log, if any.
+    ///
+    /// Set using [`VanguardMgr::set_audit_sink`].
+    audit_sink: Option<Arc<dyn VanguardAuditSink>>,
+    /// A deterministic RNG to use for selecting vanguards, instead of the thread RNG.
+    ///
+    /// This is only ever set by [`VanguardMgr::set_rng_seed`], for tests and simulations.
+    seeded_rng: Option<StdRng>,
+    /// The subscribers to [`UpcomingRotation`] notices.
+    ///
+    /// Added by [`VanguardMgr::upcoming_rotations`].
+    rotation_subscribers: Vec<RotationSubscriber>,
+    /// A channel for telling the vanguard maintenance task about new `rotation_subscribers`.
+    rotation_subscribed_tx: watch::Sender<()>,
+    /// The subscribers to [`UnlistedVanguard`] notices.
+    ///
+    /// Added by [`VanguardMgr::unlisted_vanguards`].
+    unlisted_subscribers: Vec<mpsc::UnboundedSender<UnlistedVanguard>>,
+    /// The number of vanguards we loaded from the vanguard state file,
+    /// if we have not yet checked them against a consensus.
+    ///
+    /// Until we have a `NetDir`, the vanguards we loaded are only expired by time,
+    /// so relays that left the network while we were not running stay in our sets.
+    /// We check them against the first `NetDir` we obtain,
+    /// in [`update_vanguard_sets`](Inner::update_vanguard_sets).
+    unchecked_persisted: Option<usize>,
+    /// The outcome of checking the vanguards we loaded from the vanguard state file,
+    /// once we have done so.
+    persisted_check: Option<PersistedVanguardsCheck>,
+    /// If our latest attempt to write the vanguard sets to storage failed,
+    /// when we are going to try again.
+    ///
+    /// Until then, our vanguard sets only exist in memory.
+    /// See [`flush_to_storage`](Inner::flush_to_storage).
+    store_failure: Option<StoreFailure>,
+}
+
+/// A failure to write the vanguard sets to storage.
+#[derive(Debug)]
+struct StoreFailure {
+    /// Used to decide how long to wait before the next attempt.
+    delay: RetryDelay,
+    /// The time at which to try again.
+    retry_at: SystemTime,
 }
 
 /// Whether the [`VanguardMgr::maintain_vanguard_sets`] task
@@ -134,12 +205,23 @@ impl<R: Runtime> VanguardMgr<R> {
         let params = VanguardParams::default();
         let storage: DynStorageHandle<VanguardSets> = state_mgr.create_handle(STORAGE_KEY);
 
+        let mut unchecked_persisted = None;
         let vanguard_sets = match storage.load()? {
             Some(mut sets) => {
                 info!("Loading vanguards from vanguard state file");
                 // Discard the now-expired the vanguards
                 let now = runtime.wallclock();
                 let _ = sets.remove_expired(now);
+                // A relay can't be a vanguard of both layers.
+                let overlapping = sets.remove_overlapping();
+                if !overlapping.is_empty() {
+                    warn!(
+                        "Discarded {} L3 vanguard(s) from the vanguard state file that were also L2 vanguards",
+                        overlapping.len()
+                    );
+                }
+                // The others are checked against the consensus once we have one.
+                unchecked_persisted = Some(sets.len());
                 sets
             }
             None => {
@@ -153,18 +235,30 @@ impl<R: Runtime> VanguardMgr<R> {
         };
 
         let (config_tx, _config_rx) = watch::channel();
+        let (rotation_subscribed_tx, _rotation_subscribed_rx) = watch::channel();
         let inner = Inner {
             params,
             mode: config.mode(),
+            l2_requirements: config.l2_requirements().clone(),
+            l3_requirements: config.l3_requirements().clone(),
             vanguard_sets,
             has_onion_svc,
             config_tx,
+            audit_sink: None,
+            seeded_rng: None,
+            rotation_subscribers: vec![],
+            rotation_subscribed_tx,
+            unlisted_subscribers: vec![],
+            unchecked_persisted,
+            persisted_check: None,
+            store_failure: None,
         };
 
         Ok(Self {
             inner: RwLock::new(inner),
             runtime,
             storage,
+            rejections: Mutex::new(VanguardRejections::default()),
         })
     }
 
@@ -181,23 +275,60 @@ impl<R: Runtime> VanguardMgr<R> {
         R: Runtime,
     {
         let netdir_provider = Arc::clone(netdir_provider);
-        let config_rx = self
-            .inner
-            .write()
-            .expect("poisoned lock")
-            .config_tx
-            .subscribe();
+        let (config_rx, rotation_subscribed_rx) = {
+            let inner = self.inner.write().expect("poisoned lock");
+            (
+                inner.config_tx.subscribe(),
+                inner.rotation_subscribed_tx.subscribe(),
+            )
+        };
+        let task = TaskRegistry::global().register("vanguard maintenance", self.runtime.clone());
         self.runtime
             .spawn(Self::maintain_vanguard_sets(
                 Arc::downgrade(self),
                 Arc::downgrade(&netdir_provider),
                 config_rx,
+                rotation_subscribed_rx,
+                task,
             ))
             .map_err(|e| VanguardMgrError::Spawn(Arc::new(e)))?;
 
         Ok(())
     }
 
+    /// Install a [`VanguardAuditSink`], replacing the previous one (if any).
+    ///
+    /// From now on, every change to the vanguard sets
+    /// (and every change of [`VanguardMode`]) is reported to `sink`.
+    ///
+    /// Note: the vanguards that were discarded when loading
+    /// the vanguard sets from the state file are not reported.
+    pub fn set_audit_sink(&self, sink: Arc<dyn VanguardAuditSink>) {
+        self.inner.write().expect("poisoned lock").audit_sink = Some(sink);
+    }
+
+    /// Make this `VanguardMgr` select its vanguards
+    /// using a deterministic RNG seeded with `seed`.
+    ///
+    /// Together with a fixed [`NetDir`] and a mock runtime,
+    /// this makes the contents of the vanguard sets reproducible across runs,
+    /// which is useful for tests, and for evaluating the choice of vanguard parameters.
+    /// (A [`VanguardAuditTrace`](audit::VanguardAuditTrace) can be used
+    /// to export the resulting decisions.)
+    ///
+    /// This should be called before the vanguard sets are first populated.
+    /// The selections are only reproducible with the same version of this crate
+    /// (and of `rand`).
+    ///
+    /// Never use this outside of tests and simulations:
+    /// vanguards that can be predicted offer no protection.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_rng_seed(&self, seed: [u8; 32]) {
+        use rand::SeedableRng as _;
+
+        self.inner.write().expect("poisoned lock").seeded_rng = Some(StdRng::from_seed(seed));
+    }
+
     /// Replace the configuration in this `VanguardMgr` with the specified `config`.
     pub fn reconfigure(&self, config: &VanguardConfig) -> Result<RetireCircuits, ReconfigureError> {
         // TODO(#1382): abolish VanguardConfig and derive the mode from the VanguardParams
@@ -209,15 +340,38 @@ impl<R: Runtime> VanguardMgr<R> {
         // but not decessarily downgrade to lite if we stop.
         // See <https://gitlab.torproject.org/tpo/core/arti/-/merge_requests/2083#note_3018173>
         let mut inner = self.inner.write().expect("poisoned lock");
+        let requirements_changed = config.l2_requirements() != &inner.l2_requirements
+            || config.l3_requirements() != &inner.l3_requirements;
+        if requirements_changed {
+            inner.l2_requirements = config.l2_requirements().clone();
+            inner.l3_requirements = config.l3_requirements().clone();
+        }
+
         let new_mode = config.mode();
         if new_mode != inner.mode {
+            let old_mode = inner.mode;
             inner.mode = new_mode;
+            inner.audit(
+                self.runtime.wallclock(),
+                [VanguardAuditEvent::ModeChanged {
+                    old: old_mode,
+                    new: new_mode,
+                }],
+            );
 
             // Wake up the maintenance task to replenish the vanguard pools.
             inner.config_tx.maybe_send(|_| config.clone());
 
             Ok(RetireCircuits::All)
         } else {
+            if requirements_changed {
+                // Wake up the maintenance task to replace the vanguards
+                // that don't meet the new requirements.
+                //
+                // Circuits using those vanguards can live out their lives:
+                // they were built with vanguards that met our requirements at the time.
+                inner.config_tx.maybe_send(|_| config.clone());
+            }
             Ok(RetireCircuits::None)
         }
     }
@@ -245,6 +399,8 @@ impl<R: Runtime> VanguardMgr<R> {
     ///
     /// Returns a [`NoSuitableRelay`](VanguardMgrError::NoSuitableRelay) error
     /// if none of our vanguards satisfy the `layer` and `neighbor_exclusion` requirements.
+    /// The error says how many of our vanguards were rejected for each reason;
+    /// these are also added to the totals returned by [`rejections`](VanguardMgr::rejections).
     ///
     /// Returns a [`BootstrapRequired`](VanguardMgrError::BootstrapRequired) error
     /// if called before the vanguard manager has finished bootstrapping,
@@ -300,7 +456,79 @@ impl<R: Runtime> VanguardMgr<R> {
                 }
             };
 
-        relay.ok_or(VanguardMgrError::NoSuitableRelay(layer))
+        relay.map_err(|rejected| {
+            debug!("no suitable {layer} vanguard: {rejected}");
+            *self.rejections.lock().expect("poisoned lock") += rejected;
+            VanguardMgrError::NoSuitableRelay { layer, rejected }
+        })
+    }
+
+    /// Return the number of vanguards we have rejected for each reason,
+    /// over all the times we failed to select a vanguard.
+    ///
+    /// Rejections from selections that eventually succeeded are not counted.
+    pub fn rejections(&self) -> VanguardRejections {
+        *self.rejections.lock().expect("poisoned lock")
+    }
+
+    /// Return when each of our vanguards is due to be rotated,
+    /// for each of the layers that are in use in the current [`VanguardMode`].
+    ///
+    /// The layers are returned in order, starting with [`Layer2`](Layer::Layer2).
+    /// If vanguards are disabled, this returns an empty list.
+    pub fn next_rotation(&self) -> Vec<LayerRotationSchedule> {
+        let now = self.runtime.wallclock();
+        let inner = self.inner.read().expect("poisoned lock");
+
+        layers_in_use(inner.mode, &inner.vanguard_sets)
+            .into_iter()
+            .map(|(layer, set)| LayerRotationSchedule::new(layer, set, now))
+            .collect()
+    }
+
+    /// Return a stream that yields an [`UpcomingRotation`]
+    /// `lead_time` before each of our vanguards is due to be rotated.
+    ///
+    /// Vanguards that are due to be rotated within `lead_time` of now
+    /// are reported straight away.
+    ///
+    /// The notices are sent by the vanguard maintenance task,
+    /// so the stream won't yield anything unless
+    /// [`launch_background_tasks`](VanguardMgr::launch_background_tasks) has been called.
+    /// The stream ends when the `VanguardMgr` is dropped.
+    pub fn upcoming_rotations(&self, lead_time: Duration) -> BoxStream<'static, UpcomingRotation> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut inner = self.inner.write().expect("poisoned lock");
+        inner
+            .rotation_subscribers
+            .push(RotationSubscriber::new(lead_time, tx));
+        // Wake up the maintenance task, so that it sends the notices that are already due.
+        *inner.rotation_subscribed_tx.borrow_mut() = ();
+
+        rx.boxed()
+    }
+
+    /// Return a stream that yields an [`UnlistedVanguard`]
+    /// whenever one of our vanguards is removed from its set
+    /// because it is no longer listed in the consensus.
+    ///
+    /// By the time a notice is sent, the set has already been replenished
+    /// (if there are enough suitable relays).
+    /// Subscribers may want to stop using any circuits that go through the removed relay.
+    ///
+    /// Like [`upcoming_rotations`](VanguardMgr::upcoming_rotations),
+    /// the stream won't yield anything unless
+    /// [`launch_background_tasks`](VanguardMgr::launch_background_tasks) has been called,
+    /// and it ends when the `VanguardMgr` is dropped.
+    pub fn unlisted_vanguards(&self) -> BoxStream<'static, UnlistedVanguard> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner
+            .write()
+            .expect("poisoned lock")
+            .unlisted_subscribers
+            .push(tx);
+
+        rx.boxed()
     }
 
     /// The vanguard set management task.
@@ -310,10 +538,14 @@ impl<R: Runtime> VanguardMgr<R> {
     /// * ensures the vanguard sets are repopulated with new vanguards
     ///   when the number of vanguards drops below a certain threshold
     /// * handles `NetDir` changes, updating the vanguard set sizes as needed
+    ///
+    /// `task` is our entry in the [`TaskRegistry`].
     async fn maintain_vanguard_sets(
         mgr: Weak<Self>,
         netdir_provider: Weak<dyn NetDirProvider>,
         mut config_rx: watch::Receiver<VanguardConfig>,
+        mut rotation_subscribed_rx: watch::Receiver<()>,
+        task: RegisteredTask,
     ) {
         let mut netdir_events = match netdir_provider.upgrade() {
             Some(provider) => provider.events(),
@@ -323,14 +555,16 @@ impl<R: Runtime> VanguardMgr<R> {
         };
 
         loop {
-            match Self::run_once(
+            let status = Self::run_once(
                 Weak::clone(&mgr),
                 Weak::clone(&netdir_provider),
                 &mut netdir_events,
                 &mut config_rx,
+                &mut rotation_subscribed_rx,
             )
-            .await
-            {
+            .await;
+            task.note_activity();
+            match status {
                 Ok(ShutdownStatus::Continue) => continue,
                 Ok(ShutdownStatus::Terminate) => {
                     debug!("Vanguard manager is shutting down");
@@ -344,10 +578,12 @@ impl<R: Runtime> VanguardMgr<R> {
         }
     }
 
-    /// Wait until a vanguard expires or until there is a new [`NetDir`].
+    /// Wait until a vanguard expires, until an [`UpcomingRotation`] notice is due,
+    /// or until there is a new [`NetDir`].
     ///
     /// This populates the L2 and L3 vanguard sets,
-    /// and rotates the vanguards when their lifetime expires.
+    /// rotates the vanguards when their lifetime expires,
+    /// and sends the `UpcomingRotation` notices.
     ///
     /// Note: the L3 set is only populated with vanguards if
     /// [`Full`](VanguardMode::Full) vanguards are enabled.
@@ -356,6 +592,7 @@ impl<R: Runtime> VanguardMgr<R> {
         netdir_provider: Weak<dyn NetDirProvider>,
         netdir_events: &mut BoxStream<'static, DirEvent>,
         config_rx: &mut watch::Receiver<VanguardConfig>,
+        rotation_subscribed_rx: &mut watch::Receiver<()>,
     ) -> Result<ShutdownStatus, VanguardMgrError> {
         let (mgr, netdir_provider) = match (mgr.upgrade(), netdir_provider.upgrade()) {
             (Some(mgr), Some(netdir_provider)) => (mgr, netdir_provider),
@@ -363,11 +600,12 @@ impl<R: Runtime> VanguardMgr<R> {
         };
 
         let now = mgr.runtime.wallclock();
-        let next_to_expire = mgr.rotate_expired(&netdir_provider, now)?;
-        // A future that sleeps until the next vanguard expires
+        let next_wakeup = mgr.rotate_expired(&netdir_provider, now)?;
+        // A future that sleeps until the next vanguard expires,
+        // or until the next UpcomingRotation notice is due
         let sleep_fut = async {
-            if let Some(dur) = next_to_expire {
-                let () = mgr.runtime.sleep(dur).await;
+            if let Some(when) = next_wakeup {
+                let () = mgr.runtime.sleep_until_wallclock(when).await;
             } else {
                 future::pending::<()>().await;
             }
@@ -395,8 +633,13 @@ impl<R: Runtime> VanguardMgr<R> {
 
                 Ok(ShutdownStatus::Continue)
             },
+            _subscribed = rotation_subscribed_rx.recv().fuse() => {
+                // Someone wants to hear about upcoming rotations:
+                // rotate_expired will send them the notices that are already due.
+                Ok(ShutdownStatus::Continue)
+            },
             () = sleep_fut.fuse() => {
-                // A vanguard expired, time to run the cleanup
+                // A vanguard expired, or a notice is due, time to run the cleanup
                 Ok(ShutdownStatus::Continue)
             },
         }
@@ -417,44 +660,74 @@ impl<R: Runtime> VanguardMgr<R> {
         }
     }
 
-    /// Rotate the vanguards that have expired,
-    /// returning how long until the next vanguard will expire,
-    /// or `None` if there are no vanguards in any of our sets.
+    /// Rotate the vanguards that have expired, and send any [`UpcomingRotation`] notices that are due,
+    /// returning when the next vanguard will expire
+    /// (or the next notice is due, or we next retry writing to storage, if that is sooner),
+    /// or `None` if there is nothing to wake up for.
     fn rotate_expired(
         &self,
         netdir_provider: &Arc<dyn NetDirProvider>,
         now: SystemTime,
-    ) -> Result<Option<Duration>, VanguardMgrError> {
+    ) -> Result<Option<SystemTime>, VanguardMgrError> {
         let mut inner = self.inner.write().expect("poisoned lock");
         let inner = &mut *inner;
 
-        let vanguard_sets = &mut inner.vanguard_sets;
-        let expired_count = vanguard_sets.remove_expired(now);
+        let expired = inner.vanguard_sets.remove_expired(now);
 
-        if expired_count > 0 {
+        if !expired.is_empty() {
             info!("Rotating vanguards");
         }
+        inner.audit(now, expired);
 
         if let Some(netdir) = Self::timely_netdir(netdir_provider)? {
             // If we have a NetDir, replenish the vanguard sets that don't have enough vanguards.
             inner.update_vanguard_sets(&self.runtime, &self.storage, &netdir)?;
+        } else if inner.store_failure.is_some() {
+            // Our sets haven't changed, but we still need to save them.
+            inner.flush_to_storage(&self.storage, now);
         }
 
-        let Some(expiry) = inner.vanguard_sets.next_expiry() else {
-            // Both vanguard sets are empty
-            return Ok(None);
-        };
+        let next_notice = inner.notify_upcoming_rotations(now);
+        let next_retry = inner.store_failure.as_ref().map(|failure| failure.retry_at);
 
-        expiry
-            .duration_since(now)
-            .map_err(|_| internal!("when > now, but now is later than when?!").into())
-            .map(Some)
+        // (If both vanguard sets are empty, there is no next expiry.)
+        let wakeup = [inner.vanguard_sets.next_expiry(), next_notice, next_retry]
+            .into_iter()
+            .flatten()
+            .min();
+
+        Ok(wakeup)
     }
 
     /// Get the current [`VanguardMode`].
     pub fn mode(&self) -> VanguardMode {
         self.inner.read().expect("poisoned lock").mode
     }
+
+    /// Return how many of the vanguards loaded from the vanguard state file
+    /// were still listed in the first consensus we obtained.
+    ///
+    /// Returns `None` if we did not load any vanguards from the state file,
+    /// or if we have not yet obtained a consensus to check them against.
+    pub fn persisted_vanguards_check(&self) -> Option<PersistedVanguardsCheck> {
+        self.inner.read().expect("poisoned lock").persisted_check
+    }
+
+    /// Return true if our vanguard sets are saved to the vanguard state file,
+    /// or if they don't need to be (because full vanguards are not in use).
+    ///
+    /// Returns false if our latest attempt to write them failed
+    /// (for example, because the disk is full, or the state directory is read-only).
+    /// In that case, we keep using the vanguard sets we have in memory,
+    /// and periodically try to write them again,
+    /// but we will select new vanguards if we are restarted in the meantime.
+    pub fn vanguards_persisted(&self) -> bool {
+        self.inner
+            .read()
+            .expect("poisoned lock")
+            .store_failure
+            .is_none()
+    }
 }
 
 impl Inner {
@@ -483,7 +756,34 @@ impl Inner {
         // Update our params with the new values.
         self.update_params(params.clone());
 
-        self.vanguard_sets.remove_unlisted(netdir);
+        let now = runtime.wallclock();
+        let unlisted = self.vanguard_sets.remove_unlisted(netdir);
+        let unlisted_notices = unlisted
+            .iter()
+            .filter_map(|event| match event {
+                VanguardAuditEvent::Removed { layer, relay, .. } => Some(UnlistedVanguard {
+                    layer: *layer,
+                    relay: relay.clone(),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        self.audit(now, unlisted);
+        // Defense in depth: we never select a vanguard that is already in the other layer,
+        // but make sure there is no overlap before replenishing the sets.
+        let overlapping = self.vanguard_sets.remove_overlapping();
+        self.audit(now, overlapping);
+        // This also checks the vanguards we loaded from the state file,
+        // which might have been selected under different requirements.
+        let unsuitable = self.vanguard_sets.remove_unsuitable(
+            netdir,
+            &self.l2_requirements,
+            &self.l3_requirements,
+        );
+        self.audit(now, unsuitable);
+        // Until we have checked the vanguards loaded from the state file,
+        // these are the ones that are still listed (and still suitable).
+        let n_listed = self.vanguard_sets.len();
 
         // If we loaded some vanguards from persistent storage but we still need more,
         // we select them here.
@@ -493,30 +793,143 @@ impl Inner {
         //
         // If we have already populated the vanguard sets in a previous iteration,
         // this will ensure they have enough vanguards.
-        self.vanguard_sets
-            .replenish_vanguards(runtime, netdir, &params, self.mode)?;
+        let added = match &mut self.seeded_rng {
+            Some(rng) => self.vanguard_sets.replenish_vanguards(
+                runtime,
+                rng,
+                netdir,
+                &params,
+                self.mode,
+                &self.l2_requirements,
+                &self.l3_requirements,
+            )?,
+            None => self.vanguard_sets.replenish_vanguards(
+                runtime,
+                &mut rand::rng(),
+                netdir,
+                &params,
+                self.mode,
+                &self.l2_requirements,
+                &self.l3_requirements,
+            )?,
+        };
+        self.audit(now, added);
+
+        // If this is the first NetDir since we loaded our vanguards from the state file,
+        // report how many of them we kept.
+        //
+        // We hold the lock on our state throughout, so nobody can see the sets
+        // after the unlisted vanguards were removed, but before they were replaced.
+        if let Some(loaded) = self.unchecked_persisted.take() {
+            let check = PersistedVanguardsCheck {
+                loaded,
+                // (Some of them might have expired since we loaded them.)
+                survived: n_listed.min(loaded),
+            };
+            info!(
+                "{} of {} vanguard(s) loaded from the vanguard state file are still listed in the consensus",
+                check.survived, check.loaded
+            );
+            self.persisted_check = Some(check);
+        }
+
+        // Now that we have replaced them, tell our subscribers about the unlisted vanguards.
+        if !unlisted_notices.is_empty() {
+            info!(
+                "Replaced {} vanguard(s) that are no longer listed in the consensus",
+                unlisted_notices.len()
+            );
+            self.notify_unlisted(&unlisted_notices);
+        }
 
         // Flush the vanguard sets to disk.
-        self.flush_to_storage(storage)?;
+        self.flush_to_storage(storage, now);
 
         Ok(())
     }
 
+    /// Report the specified `events` to the audit sink, if we have one.
+    fn audit(&self, when: SystemTime, events: impl IntoIterator<Item = VanguardAuditEvent>) {
+        let Some(sink) = &self.audit_sink else {
+            return;
+        };
+
+        for event in events {
+            sink.record(VanguardAuditRecord { when, event });
+        }
+    }
+
+    /// Send the [`UpcomingRotation`] notices that are due,
+    /// and forget about the subscribers that have gone away.
+    ///
+    /// Returns the time at which the next notice will be due, if any.
+    fn notify_upcoming_rotations(&mut self, now: SystemTime) -> Option<SystemTime> {
+        let layers = layers_in_use(self.mode, &self.vanguard_sets);
+        self.rotation_subscribers.retain(|s| !s.is_closed());
+        self.rotation_subscribers
+            .iter_mut()
+            .filter_map(|s| s.notify(&layers, now))
+            .min()
+    }
+
+    /// Send each of `notices` to our [`UnlistedVanguard`] subscribers,
+    /// and forget about the subscribers that have gone away.
+    fn notify_unlisted(&mut self, notices: &[UnlistedVanguard]) {
+        self.unlisted_subscribers.retain(|tx| {
+            notices
+                .iter()
+                .all(|notice| tx.unbounded_send(notice.clone()).is_ok())
+        });
+    }
+
     /// Update our vanguard params.
     fn update_params(&mut self, new_params: VanguardParams) {
         self.params = new_params;
     }
 
     /// Flush the vanguard sets to storage, if the mode is "vanguards-full".
-    fn flush_to_storage(
-        &self,
-        storage: &DynStorageHandle<VanguardSets>,
-    ) -> Result<(), VanguardMgrError> {
+    ///
+    /// If we can't, we keep going with the vanguard sets we have in memory,
+    /// and record a [`StoreFailure`]:
+    /// until its `retry_at` time, further calls do nothing.
+    fn flush_to_storage(&mut self, storage: &DynStorageHandle<VanguardSets>, now: SystemTime) {
         match self.mode {
-            VanguardMode::Lite | VanguardMode::Disabled => Ok(()),
+            VanguardMode::Lite | VanguardMode::Disabled => {
+                // There's nothing that needs to be saved.
+                self.store_failure = None;
+            }
             VanguardMode::Full => {
+                if self
+                    .store_failure
+                    .as_ref()
+                    .is_some_and(|failure| now < failure.retry_at)
+                {
+                    return;
+                }
                 debug!("The vanguards may have changed; flushing to vanguard state file");
-                Ok(storage.store(&self.vanguard_sets)?)
+                match storage.store(&self.vanguard_sets) {
+                    Ok(()) => {
+                        if self.store_failure.take().is_some() {
+                            info!("Saved vanguards to the vanguard state file again");
+                        }
+                    }
+                    Err(e) => {
+                        let failure = self.store_failure.get_or_insert_with(|| StoreFailure {
+                            delay: RetryDelay::from_duration(STORE_RETRY_MIN),
+                            retry_at: now,
+                        });
+                        let delay = failure
+                            .delay
+                            .next_delay(&mut rand::rng())
+                            .min(STORE_RETRY_MAX);
+                        failure.retry_at = now + delay;
+                        warn_report!(
+                            e,
+                            "Unable to write vanguard state file; keeping vanguards in memory only, and retrying in {}",
+                            humantime::format_duration(Duration::from_secs(delay.as_secs())),
+                        );
+                    }
+                }
             }
         }
     }
@@ -538,6 +951,7 @@ impl VanguardMgr<MockRuntime> {
     ) -> Result<Arc<VanguardMgr<MockRuntime>>, VanguardMgrError> {
         let config = VanguardConfig {
             mode: ExplicitOrAuto::Explicit(mode),
+            ..Default::default()
         };
         let statemgr = TestingStateMgr::new();
         let lock = statemgr.try_lock()?;
@@ -576,6 +990,15 @@ impl VanguardMgr<MockRuntime> {
     }
 }
 
+/// Return the vanguard sets that are used in `mode`, along with their layers.
+fn layers_in_use(mode: VanguardMode, sets: &VanguardSets) -> Vec<(Layer, &VanguardSet)> {
+    match mode {
+        VanguardMode::Disabled => vec![],
+        VanguardMode::Lite => vec![(Layer::Layer2, sets.l2())],
+        VanguardMode::Full => vec![(Layer::Layer2, sets.l2()), (Layer::Layer3, sets.l3())],
+    }
+}
+
 /// The vanguard layer.
 #[derive(Debug, Clone, Copy, PartialEq)] //
 #[derive(derive_more::Display)] //
@@ -589,6 +1012,35 @@ pub enum Layer {
     Layer3,
 }
 
+/// A notice that one of our vanguards has been removed from its set,
+/// because it is no longer listed in the consensus.
+///
+/// Yielded by the stream returned from [`VanguardMgr::unlisted_vanguards`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct UnlistedVanguard {
+    /// The layer of the set the vanguard was in.
+    pub layer: Layer,
+    /// The identities of the relay.
+    pub relay: RelayIds,
+}
+
+/// The outcome of checking the vanguards loaded from the vanguard state file
+/// against the first consensus we obtained after loading them.
+///
+/// Returned by [`VanguardMgr::persisted_vanguards_check`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PersistedVanguardsCheck {
+    /// The number of unexpired vanguards we loaded from the state file.
+    pub loaded: usize,
+    /// The number of those vanguards that were still listed in the consensus
+    /// (and had not expired in the meantime).
+    ///
+    /// The others were removed from their sets, and replaced.
+    pub survived: usize,
+}
+
 #[cfg(test)]
 mod test {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -615,13 +1067,15 @@ mod test {
 
     use Layer::*;
     use tor_basic_utils::test_rng::testing_rng;
-    use tor_linkspec::{HasRelayIds, RelayIds};
+    use tor_linkspec::{HasRelayIds, RelayIdSet};
+    use tor_llcrypto::pk::rsa::RsaIdentity;
     use tor_netdir::{
         testnet::{self, construct_custom_netdir_with_params},
         testprovider::TestNetDirProvider,
     };
     use tor_persist::FsStateMgr;
     use tor_rtmock::MockRuntime;
+    use tor_rtmock::counting::CountingRuntime;
 
     use itertools::Itertools;
 
@@ -811,6 +1265,59 @@ mod test {
         });
     }
 
+    #[test]
+    fn no_suitable_relay() {
+        MockRuntime::test_with_various(|rt| async move {
+            let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Lite).unwrap();
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+            let mut rng = testing_rng();
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            assert_eq!(vanguardmgr.rejections(), VanguardRejections::default());
+
+            // Exclude every one of our L2 vanguards.
+            let l2_ids = vanguardmgr
+                .inner
+                .read()
+                .unwrap()
+                .vanguard_sets
+                .l2()
+                .vanguards()
+                .flat_map(|vanguard| vanguard.id.identities().map(|id| id.to_owned()))
+                .collect();
+            let selector = RelaySelector::new(
+                tor_relay_selection::RelayUsage::vanguard(),
+                RelayExclusion::exclude_identities(l2_ids),
+            );
+
+            for n_failures in 1..=2 {
+                let err = vanguardmgr
+                    .select_vanguard(&mut rng, &netdir, Layer2, &selector)
+                    .unwrap_err();
+                let VanguardMgrError::NoSuitableRelay {
+                    layer: Layer2,
+                    rejected,
+                } = err
+                else {
+                    panic!("unexpected error {err}");
+                };
+                assert_eq!(rejected.excluded, params.l2_pool_size());
+                assert_eq!(rejected.not_in_netdir, 0);
+                assert_eq!(rejected.wrong_usage, 0);
+
+                // The rejections are added to our totals.
+                let total = vanguardmgr.rejections();
+                assert_eq!(total.excluded, n_failures * params.l2_pool_size());
+            }
+
+            // Successful selections don't count.
+            vanguardmgr
+                .select_vanguard(&mut rng, &netdir, Layer2, &permissive_selector())
+                .unwrap();
+            assert_eq!(vanguardmgr.rejections().excluded, 2 * params.l2_pool_size());
+        });
+    }
+
     #[test]
     fn background_task_not_spawned() {
         MockRuntime::test_with_various(|rt| async move {
@@ -884,6 +1391,30 @@ mod test {
         });
     }
 
+    #[test]
+    fn select_vanguards_bw_weight_exponent() {
+        MockRuntime::test_with_various(|rt| async move {
+            for exponent in [0, 100, 250, 400] {
+                let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Full).unwrap();
+                let netdir = construct_custom_netdir_with_params(
+                    |_, _, _| {},
+                    [("guard-hs-bw-weight-exponent", exponent)],
+                    None,
+                )
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap();
+                let params = VanguardParams::try_from(netdir.params()).unwrap();
+                assert_eq!(params.bw_weight_exponent(), f64::from(exponent) / 100.0);
+
+                let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+
+                // However we weight them, there are enough suitable relays to fill the sets.
+                assert_sets_filled(&vanguardmgr, &params);
+            }
+        });
+    }
+
     /// Override the vanguard params from the netdir, returning the new VanguardParams.
     ///
     /// This also waits until the vanguard manager has had a chance to process the changes.
@@ -934,6 +1465,7 @@ mod test {
         let _ = vanguardmgr
             .reconfigure(&VanguardConfig {
                 mode: ExplicitOrAuto::Explicit(mode),
+                ..Default::default()
             })
             .unwrap();
 
@@ -970,6 +1502,47 @@ mod test {
         new_netdir
     }
 
+    #[test]
+    fn bounded_wakeups() {
+        MockRuntime::test_with_various(|rt| async move {
+            /// The longest `sleep_until_wallclock` sleeps before checking the clock again.
+            const MAX_SLEEP: Duration = Duration::from_secs(600);
+
+            let counting = CountingRuntime::new(rt.clone());
+            let config = VanguardConfig {
+                mode: ExplicitOrAuto::Explicit(VanguardMode::Lite),
+                ..Default::default()
+            };
+            let statemgr = TestingStateMgr::new();
+            assert!(statemgr.try_lock().unwrap().held());
+            let vanguardmgr =
+                Arc::new(VanguardMgr::new(&config, counting.clone(), statemgr, false).unwrap());
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let netdir_provider = Arc::new(TestNetDirProvider::new());
+            vanguardmgr
+                .launch_background_tasks(&(netdir_provider.clone() as Arc<dyn NetDirProvider>))
+                .unwrap();
+            rt.progress_until_stalled().await;
+            netdir_provider
+                .set_netdir_and_notify(Arc::new(netdir.clone()))
+                .await;
+            rt.progress_until_stalled().await;
+            assert_sets_filled(
+                &vanguardmgr,
+                &VanguardParams::try_from(netdir.params()).unwrap(),
+            );
+
+            // None of our vanguards expire within the hour,
+            // so until then, we only wake up to check the wallclock.
+            counting.take_calls();
+            rt.advance_by(MAX_SLEEP * 6 - Duration::from_secs(1)).await;
+            rt.progress_until_stalled().await;
+            let calls = counting.take_calls();
+            assert_eq!(calls.n_sleeps(), 5);
+            assert_eq!(calls.n_sleeps_in(MAX_SLEEP..=MAX_SLEEP), 5);
+        });
+    }
+
     #[test]
     fn override_vanguard_set_size() {
         MockRuntime::test_with_various(|rt| async move {
@@ -1101,6 +1674,316 @@ mod test {
         });
     }
 
+    #[test]
+    fn storage_unavailable() {
+        MockRuntime::test_with_various(|rt| async move {
+            let config = VanguardConfig {
+                mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                ..Default::default()
+            };
+            // We don't hold the lock on this storage, so we can't write to it.
+            let statemgr = TestingStateMgr::new();
+            let vanguardmgr =
+                Arc::new(VanguardMgr::new(&config, rt.clone(), statemgr.clone(), false).unwrap());
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+
+            // We still select our vanguards, but they aren't saved.
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            assert_sets_filled(&vanguardmgr, &params);
+            assert!(!vanguardmgr.vanguards_persisted());
+            assert!(
+                statemgr
+                    .load::<VanguardSets>(STORAGE_KEY)
+                    .unwrap()
+                    .is_none()
+            );
+
+            // Once we can write to the storage again, we save them at the next retry.
+            assert!(statemgr.try_lock().unwrap().held());
+            rt.advance_by(STORE_RETRY_MAX).await.unwrap();
+            rt.progress_until_stalled().await;
+            assert!(vanguardmgr.vanguards_persisted());
+            assert!(
+                statemgr
+                    .load::<VanguardSets>(STORAGE_KEY)
+                    .unwrap()
+                    .is_some()
+            );
+        });
+    }
+
+    #[test]
+    fn rotation_schedule() {
+        MockRuntime::test_with_various(|rt| async move {
+            let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Lite).unwrap();
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+
+            // Only the L2 vanguards are in use in lite mode.
+            let schedule = vanguardmgr.next_rotation();
+            assert_eq!(schedule.len(), 1);
+            let l2 = &schedule[0];
+            assert_eq!(l2.layer, Layer2);
+            assert_eq!(l2.vanguards.len(), params.l2_pool_size());
+            assert!(l2.vanguards.is_sorted_by_key(|v| v.expires));
+            assert_eq!(l2.next_expiry, Some(l2.vanguards[0].expires));
+
+            // Ask to be told an hour before each rotation.
+            // No vanguard has a lifetime that short, so there are no notices yet.
+            const LEAD_TIME: Duration = Duration::from_secs(60 * 60);
+            let mut notices = vanguardmgr.upcoming_rotations(LEAD_TIME);
+            rt.progress_until_stalled().await;
+            assert!(notices.next().now_or_never().is_none());
+
+            // Once we get close enough, we hear about the first vanguard to expire.
+            let first = &l2.vanguards[0];
+            rt.advance_by(first.remaining - LEAD_TIME).await.unwrap();
+            rt.progress_until_stalled().await;
+            let notice = notices.next().now_or_never().flatten().unwrap();
+            assert_eq!(notice.layer, Layer2);
+            assert_eq!(notice.relay, first.relay);
+            assert_eq!(notice.expires, first.expires);
+
+            // The vanguard hasn't been rotated yet.
+            assert!(find_in_set(&first.relay, &vanguardmgr, Layer2).is_some());
+        });
+    }
+
+    /// A [`VanguardAuditSink`] that remembers all the records it receives.
+    #[derive(Default)]
+    struct TestAuditSink(std::sync::Mutex<Vec<VanguardAuditRecord>>);
+
+    impl VanguardAuditSink for TestAuditSink {
+        fn record(&self, record: VanguardAuditRecord) {
+            self.0.lock().unwrap().push(record);
+        }
+    }
+
+    impl TestAuditSink {
+        /// Remove and return all the events recorded so far.
+        fn take_events(&self) -> Vec<VanguardAuditEvent> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+                .into_iter()
+                .map(|r| r.event)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn audit_log() {
+        MockRuntime::test_with_various(|rt| async move {
+            use audit::RemovalReason;
+
+            let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Lite).unwrap();
+            let sink = Arc::new(TestAuditSink::default());
+            vanguardmgr.set_audit_sink(Arc::clone(&sink) as Arc<dyn VanguardAuditSink>);
+
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+            let netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+
+            // Each of the L2 vanguards we selected was recorded.
+            let events = sink.take_events();
+            assert_eq!(events.len(), params.l2_pool_size());
+            for event in &events {
+                let VanguardAuditEvent::Added { layer, relay, .. } = event else {
+                    panic!("unexpected event {event:?}");
+                };
+                assert_eq!(*layer, Layer2);
+                assert!(find_in_set(relay, &vanguardmgr, Layer2).is_some());
+            }
+
+            // Switching to full vanguards is recorded, and so are the new L3 vanguards.
+            switch_hs_mode_config(&vanguardmgr, VanguardMode::Full);
+            rt.progress_until_stalled().await;
+            let events = sink.take_events();
+            assert!(matches!(
+                events[0],
+                VanguardAuditEvent::ModeChanged {
+                    old: VanguardMode::Lite,
+                    new: VanguardMode::Full
+                }
+            ));
+            let l3_added = events
+                .iter()
+                .filter(|e| matches!(e, VanguardAuditEvent::Added { layer: Layer3, .. }))
+                .count();
+            assert_eq!(l3_added, params.l3_pool_size());
+
+            // Removing a vanguard from the consensus causes it to be removed from its set.
+            let mut rng = testing_rng();
+            let excluded_vanguard = vanguardmgr
+                .select_vanguard(&mut rng, &netdir, Layer2, &permissive_selector())
+                .unwrap();
+            let excluded_id = RelayIds::from_relay_ids(excluded_vanguard.relay());
+            let _ = install_netdir_excluding_vanguard(
+                &rt,
+                &excluded_vanguard,
+                ENABLE_FULL_VANGUARDS,
+                &netdir_provider,
+            )
+            .await;
+
+            let events = sink.take_events();
+            assert!(events.iter().any(|e| matches!(
+                e,
+                VanguardAuditEvent::Removed {
+                    layer: Layer2,
+                    relay,
+                    reason: RemovalReason::Unlisted,
+                } if *relay == excluded_id
+            )));
+        });
+    }
+
+    #[test]
+    fn layer_requirements() {
+        MockRuntime::test_with_various(|rt| async move {
+            use crate::VanguardLayerRequirements;
+            use audit::RemovalReason;
+            use tor_netdir::{RelayWeight, WeightRole};
+
+            let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Lite).unwrap();
+            let sink = Arc::new(TestAuditSink::default());
+            vanguardmgr.set_audit_sink(Arc::clone(&sink) as Arc<dyn VanguardAuditSink>);
+
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            let _ = sink.take_events();
+
+            // The relays in the test network have weights between 1000 and 10000.
+            const MIN_WEIGHT: u32 = 7000;
+            let weight = |id: &RelayIds| {
+                let relay = netdir.by_ids(id).unwrap();
+                netdir.relay_weight(&relay, WeightRole::Unweighted)
+            };
+            let min_weight = RelayWeight::from(u64::from(MIN_WEIGHT));
+            let n_light = |mgr: &VanguardMgr<MockRuntime>| {
+                let inner = mgr.inner.read().unwrap();
+                inner
+                    .l2_vanguards()
+                    .iter()
+                    .filter(|v| weight(&v.id) < min_weight)
+                    .count()
+            };
+            let light_before = n_light(&vanguardmgr);
+
+            // Only require a minimum weight for L2 vanguards.
+            let config = VanguardConfig {
+                mode: ExplicitOrAuto::Explicit(VanguardMode::Lite),
+                l2_requirements: VanguardLayerRequirements {
+                    min_consensus_weight: MIN_WEIGHT,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            assert_eq!(
+                vanguardmgr.reconfigure(&config).unwrap(),
+                RetireCircuits::None
+            );
+            rt.progress_until_stalled().await;
+
+            // The vanguards that are too light were replaced with ones that aren't.
+            assert_eq!(n_light(&vanguardmgr), 0);
+            assert_sets_filled(&vanguardmgr, &params);
+            let events = sink.take_events();
+            let unsuitable = events
+                .iter()
+                .filter(|e| {
+                    matches!(
+                        e,
+                        VanguardAuditEvent::Removed {
+                            layer: Layer2,
+                            reason: RemovalReason::Unsuitable,
+                            ..
+                        }
+                    )
+                })
+                .count();
+            assert_eq!(unsuitable, light_before);
+
+            // The L3 requirements are independent of the L2 ones.
+            let _ = vanguardmgr
+                .reconfigure(&VanguardConfig {
+                    mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                    ..config
+                })
+                .unwrap();
+            rt.progress_until_stalled().await;
+            assert_eq!(n_light(&vanguardmgr), 0);
+            assert_sets_filled(&vanguardmgr, &params);
+        });
+    }
+
+    #[test]
+    fn unlisted_vanguards() {
+        MockRuntime::test_with_various(|rt| async move {
+            let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Lite).unwrap();
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+            let netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+
+            let mut notices = vanguardmgr.unlisted_vanguards();
+            assert!(notices.next().now_or_never().is_none());
+
+            let mut rng = testing_rng();
+            let excluded_vanguard = vanguardmgr
+                .select_vanguard(&mut rng, &netdir, Layer2, &permissive_selector())
+                .unwrap();
+            let excluded_id = RelayIds::from_relay_ids(excluded_vanguard.relay());
+            let _ =
+                install_netdir_excluding_vanguard(&rt, &excluded_vanguard, [], &netdir_provider)
+                    .await;
+
+            // We hear about the unlisted vanguard...
+            let notice = notices.next().now_or_never().flatten().unwrap();
+            assert_eq!(notice.layer, Layer2);
+            assert_eq!(notice.relay, excluded_id);
+            assert!(notices.next().now_or_never().is_none());
+
+            // ...which has already been replaced.
+            assert!(find_in_set(&excluded_id, &vanguardmgr, Layer2).is_none());
+            assert_eq!(vanguard_count(&vanguardmgr), params.l2_pool_size());
+        });
+    }
+
+    #[test]
+    fn seeded_selection() {
+        MockRuntime::test_with_various(|rt| async move {
+            use audit::VanguardAuditTrace;
+
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let params = VanguardParams::try_from(netdir.params()).unwrap();
+
+            let mut selections = vec![];
+            for seed in [[1; 32], [1; 32], [2; 32]] {
+                let vanguardmgr = VanguardMgr::new_testing(&rt, VanguardMode::Lite).unwrap();
+                vanguardmgr.set_rng_seed(seed);
+                let trace = Arc::new(VanguardAuditTrace::new());
+                vanguardmgr.set_audit_sink(Arc::clone(&trace) as Arc<dyn VanguardAuditSink>);
+                let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+
+                let added = trace
+                    .take_records()
+                    .into_iter()
+                    .map(|record| match record.event {
+                        VanguardAuditEvent::Added { relay, .. } => relay,
+                        event => panic!("unexpected event {event:?}"),
+                    })
+                    .collect_vec();
+                assert_eq!(added.len(), params.l2_pool_size());
+                selections.push(added);
+            }
+
+            // The same seed gives the same vanguards, in the same order.
+            assert_eq!(selections[0], selections[1]);
+            assert_ne!(selections[0], selections[2]);
+        });
+    }
+
     #[test]
     fn full_vanguards_persistence() {
         MockRuntime::test_with_various(|rt| async move {
@@ -1188,6 +2071,7 @@ mod test {
 
             let config = VanguardConfig {
                 mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                ..Default::default()
             };
 
             // The state file contains no vanguards
@@ -1224,8 +2108,18 @@ mod test {
                 (l2_vanguards, l3_vanguards)
             };
 
+            // We haven't checked the vanguards against a consensus yet.
+            assert_eq!(vanguardmgr.persisted_vanguards_check(), None);
+
             let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
             let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            assert_eq!(
+                vanguardmgr.persisted_vanguards_check(),
+                Some(PersistedVanguardsCheck {
+                    loaded: 5,
+                    survived: 5
+                })
+            );
             {
                 let inner = vanguardmgr.inner.read().unwrap();
                 let l2_vanguards = inner.vanguard_sets.l2_vanguards();
@@ -1247,11 +2141,107 @@ mod test {
         });
     }
 
+    #[test]
+    fn prune_unlisted_from_state_file() {
+        MockRuntime::test_with_various(|rt| async move {
+            let now = time::UNIX_EPOCH + Duration::from_secs(1610000000);
+            rt.jump_wallclock(now);
+
+            let config = VanguardConfig {
+                mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                ..Default::default()
+            };
+            let (statemgr, _dir) = state_dir_with_vanguards(VANGUARDS_JSON);
+            let vanguardmgr =
+                Arc::new(VanguardMgr::new(&config, rt.clone(), statemgr, false).unwrap());
+            let unlisted = vanguardmgr.inner.read().unwrap().l2_vanguards()[0].clone();
+
+            // The first consensus we see doesn't list one of the vanguards we loaded.
+            let netdir = construct_custom_netdir_with_params(
+                |_idx, bld, _| {
+                    let md_so_far = bld.md.testing_md().unwrap();
+                    if Some(md_so_far.ed25519_id()) == unlisted.id.ed_identity() {
+                        bld.omit_rs = true;
+                    }
+                },
+                [],
+                None,
+            )
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+
+            assert_eq!(
+                vanguardmgr.persisted_vanguards_check(),
+                Some(PersistedVanguardsCheck {
+                    loaded: 5,
+                    survived: 4
+                })
+            );
+            // The unlisted vanguard was replaced.
+            assert!(find_in_set(&unlisted.id, &vanguardmgr, Layer2).is_none());
+            let inner = vanguardmgr.inner.read().unwrap();
+            assert_eq!(inner.vanguard_sets.l2_vanguards_deficit(), 0);
+            assert_eq!(inner.vanguard_sets.l3_vanguards_deficit(), 0);
+        });
+    }
+
+    #[test]
+    fn overlapping_state_file() {
+        MockRuntime::test_with_various(|rt| async move {
+            let now = time::UNIX_EPOCH + Duration::from_secs(1610000000);
+            rt.jump_wallclock(now);
+
+            let config = VanguardConfig {
+                mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                ..Default::default()
+            };
+            // Make the (otherwise expired) L3 entry for relay 08, which is also in L2, unexpired.
+            let vanguards_json = VANGUARDS_JSON.replace(
+                r#""secs_since_epoch": 0,
+        "nanos_since_epoch": 668800291"#,
+                r#""secs_since_epoch": 1710000000,
+        "nanos_since_epoch": 668800291"#,
+            );
+            assert_ne!(vanguards_json, VANGUARDS_JSON);
+            let (statemgr, _dir) = state_dir_with_vanguards(&vanguards_json);
+            let vanguardmgr =
+                Arc::new(VanguardMgr::new(&config, rt.clone(), statemgr, false).unwrap());
+
+            let overlapping = RsaIdentity::from([8; 20]);
+            {
+                let inner = vanguardmgr.inner.read().unwrap();
+                // The relay is kept in the L2 set, and removed from the L3 one.
+                let in_set = |set: &Vec<TimeBoundVanguard>| {
+                    set.iter()
+                        .any(|v| v.id.rsa_identity() == Some(&overlapping))
+                };
+                assert!(in_set(inner.l2_vanguards()));
+                assert!(!in_set(inner.l3_vanguards()));
+                assert_eq!(inner.l2_vanguards().len(), 3);
+                assert_eq!(inner.l3_vanguards().len(), 2);
+            }
+
+            // When the sets are replenished, the new vanguards don't overlap either.
+            let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+            let _netdir_provider = vanguardmgr.init_vanguard_sets(&netdir).await.unwrap();
+            let inner = vanguardmgr.inner.read().unwrap();
+            assert_eq!(inner.vanguard_sets.l2_vanguards_deficit(), 0);
+            assert_eq!(inner.vanguard_sets.l3_vanguards_deficit(), 0);
+            let l2_ids = RelayIdSet::from(inner.vanguard_sets.l2());
+            for v in inner.l3_vanguards() {
+                assert!(v.id.identities().all(|id| !l2_ids.contains(id)));
+            }
+        });
+    }
+
     #[test]
     fn invalid_state_file() {
         MockRuntime::test_with_various(|rt| async move {
             let config = VanguardConfig {
                 mode: ExplicitOrAuto::Explicit(VanguardMode::Full),
+                ..Default::default()
             };
             let (statemgr, _dir) = state_dir_with_vanguards(INVALID_VANGUARDS_JSON);
             let res = VanguardMgr::new(&config, rt.clone(), statemgr, false);
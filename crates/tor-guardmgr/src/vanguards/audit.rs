@@ -0,0 +1,124 @@
+//! Audit logging for changes to the vanguard sets.
+//!
+//! A [`VanguardAuditSink`] registered with
+//! [`VanguardMgr::set_audit_sink`](crate::vanguards::VanguardMgr::set_audit_sink)
+//! is told about every vanguard that is added to or removed from our vanguard sets,
+//! and about every change of [`VanguardMode`].
+//!
+//! The sink is expected to append the records to some durable,
+//! ideally tamper-evident, log: the `VanguardMgr` itself does not store them.
+
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tor_linkspec::RelayIds;
+
+use crate::VanguardMode;
+use crate::vanguards::Layer;
+
+/// A single entry in the vanguard audit log.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct VanguardAuditRecord {
+    /// The wallclock time at which the change was made.
+    pub when: SystemTime,
+    /// What changed.
+    pub event: VanguardAuditEvent,
+}
+
+/// A change to the vanguard sets, or to the way they are used.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum VanguardAuditEvent {
+    /// A relay was selected as a vanguard.
+    Added {
+        /// The layer of the set the relay was added to.
+        layer: Layer,
+        /// The identities of the relay.
+        relay: RelayIds,
+        /// When the relay is due to be rotated out of the set.
+        expires: SystemTime,
+    },
+    /// A relay was removed from a vanguard set.
+    Removed {
+        /// The layer of the set the relay was removed from.
+        layer: Layer,
+        /// The identities of the relay.
+        relay: RelayIds,
+        /// Why the relay was removed.
+        reason: RemovalReason,
+    },
+    /// The [`VanguardMode`] was changed by a reconfiguration.
+    ModeChanged {
+        /// The mode we were using before the change.
+        old: VanguardMode,
+        /// The mode we are using now.
+        new: VanguardMode,
+    },
+}
+
+/// The reason why a vanguard was removed from its set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)] //
+#[derive(derive_more::Display)] //
+#[non_exhaustive]
+pub enum RemovalReason {
+    /// The lifetime of the vanguard ran out, and it was rotated.
+    #[display("expired")]
+    Expired,
+    /// The relay is no longer listed in the consensus.
+    #[display("unlisted")]
+    Unlisted,
+    /// The relay was also a vanguard of another layer.
+    ///
+    /// This only happens if the vanguard state file had a relay in both layers.
+    #[display("overlapping")]
+    Overlapping,
+    /// The relay no longer meets the configured requirements of its layer.
+    ///
+    /// See [`VanguardLayerRequirements`](crate::VanguardLayerRequirements).
+    #[display("unsuitable")]
+    Unsuitable,
+}
+
+/// A destination for [`VanguardAuditRecord`]s.
+///
+/// Implementations must not block: `record` is called
+/// while the vanguard manager's state is locked.
+pub trait VanguardAuditSink: Send + Sync {
+    /// Append `record` to the audit log.
+    fn record(&self, record: VanguardAuditRecord);
+}
+
+/// A [`VanguardAuditSink`] that keeps every record it receives in memory.
+///
+/// This is useful for exporting a trace of the decisions made by a
+/// [`VanguardMgr`](crate::vanguards::VanguardMgr) in tests and simulations.
+/// It should not be used in long-running programs, since it never forgets anything.
+#[derive(Debug, Default)]
+pub struct VanguardAuditTrace {
+    /// The records received so far, in order.
+    records: Mutex<Vec<VanguardAuditRecord>>,
+}
+
+impl VanguardAuditTrace {
+    /// Create a new, empty `VanguardAuditTrace`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a copy of every record received so far, in order.
+    pub fn records(&self) -> Vec<VanguardAuditRecord> {
+        self.records.lock().expect("poisoned lock").clone()
+    }
+
+    /// Remove and return every record received so far, in order.
+    pub fn take_records(&self) -> Vec<VanguardAuditRecord> {
+        std::mem::take(&mut *self.records.lock().expect("poisoned lock"))
+    }
+}
+
+impl VanguardAuditSink for VanguardAuditTrace {
+    fn record(&self, record: VanguardAuditRecord) {
+        self.records.lock().expect("poisoned lock").push(record);
+    }
+}
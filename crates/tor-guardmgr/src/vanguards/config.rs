@@ -24,6 +24,9 @@ const DEFAULT_L3_GUARD_LIFETIME_MIN: Duration = Duration::from_secs(3600);
 /// The default maximum lifetime of L3 guards.
 const DEFAULT_L3_GUARD_LIFETIME_MAX: Duration = Duration::from_secs(3600 * 48);
 
+/// The default exponent applied to consensus weights when selecting vanguards.
+const DEFAULT_BW_WEIGHT_EXPONENT: f64 = 1.0;
+
 /// A set of parameters, derived from the consensus document,
 /// controlling the behavior of a [`VanguardMgr`](crate::vanguards::VanguardMgr).
 ///
@@ -61,6 +64,13 @@ pub struct VanguardParams {
     /// The maximum lifetime of L3 guards
     #[getter(as_copy)]
     l3_lifetime_max: Duration,
+    /// The exponent applied to the consensus weight of each suitable relay
+    /// when selecting L2 and L3 guards.
+    ///
+    /// `0.0` means relays are selected uniformly at random,
+    /// and `1.0` means they are selected in proportion to their consensus weight.
+    #[getter(as_copy)]
+    bw_weight_exponent: f64,
 }
 
 impl Default for VanguardParams {
@@ -74,6 +84,7 @@ impl Default for VanguardParams {
             l3_pool_size: DEFAULT_L3_POOL_SIZE,
             l3_lifetime_min: DEFAULT_L3_GUARD_LIFETIME_MIN,
             l3_lifetime_max: DEFAULT_L3_GUARD_LIFETIME_MAX,
+            bw_weight_exponent: DEFAULT_BW_WEIGHT_EXPONENT,
         }
     }
 }
@@ -122,6 +133,7 @@ impl TryFrom<&NetParameters> for VanguardParams {
             l3_pool_size: p.guard_hs_l3_number.try_into()?,
             l3_lifetime_min,
             l3_lifetime_max,
+            bw_weight_exponent: f64::from(p.guard_hs_bw_weight_exponent.get()) / 100.0,
         })
     }
 }
@@ -1,5 +1,7 @@
 //! Error types for the vanguards subsystem.
 
+use std::fmt;
+use std::ops::AddAssign;
 use std::sync::Arc;
 
 use futures::task::SpawnError;
@@ -29,8 +31,13 @@ pub enum VanguardMgrError {
     },
 
     /// Could not find a suitable relay to use for the specifier layer.
-    #[error("No suitable relays")]
-    NoSuitableRelay(Layer),
+    #[error("No suitable relays for {layer} vanguard ({rejected})")]
+    NoSuitableRelay {
+        /// The layer we tried to select a vanguard for.
+        layer: Layer,
+        /// Why each of the vanguards in that layer was rejected.
+        rejected: VanguardRejections,
+    },
 
     /// Could not get timely network directory.
     #[error("Unable to get timely network directory")]
@@ -54,7 +61,7 @@ impl HasKind for VanguardMgrError {
         match self {
             VanguardMgrError::BootstrapRequired { .. } => ErrorKind::BootstrapRequired,
             VanguardMgrError::LayerNotSupported { .. } => ErrorKind::BadApiUsage,
-            VanguardMgrError::NoSuitableRelay(_) => ErrorKind::NoPath,
+            VanguardMgrError::NoSuitableRelay { .. } => ErrorKind::NoPath,
             VanguardMgrError::NetDir(e) => e.kind(),
             VanguardMgrError::State(e) => e.kind(),
             VanguardMgrError::Spawn(e) => e.kind(),
@@ -62,3 +69,40 @@ impl HasKind for VanguardMgrError {
         }
     }
 }
+
+/// The number of vanguards we rejected for each reason,
+/// when we were unable to select a vanguard.
+///
+/// Returned as part of a [`NoSuitableRelay`](VanguardMgrError::NoSuitableRelay) error,
+/// and accumulated over all such failures by
+/// [`VanguardMgr::rejections`](crate::vanguards::VanguardMgr::rejections).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VanguardRejections {
+    /// The number of vanguards that are not listed in the network directory.
+    pub not_in_netdir: usize,
+    /// The number of vanguards that are unsuitable for the intended usage
+    /// (for example, because they lack the flags we need).
+    pub wrong_usage: usize,
+    /// The number of vanguards that were excluded by the relay selector's other restrictions
+    /// (for example, because they would neighbor themselves in the path).
+    pub excluded: usize,
+}
+
+impl fmt::Display for VanguardRejections {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} not in network directory, {} unsuitable for usage, {} excluded",
+            self.not_in_netdir, self.wrong_usage, self.excluded
+        )
+    }
+}
+
+impl AddAssign for VanguardRejections {
+    fn add_assign(&mut self, rhs: Self) {
+        self.not_in_netdir = self.not_in_netdir.saturating_add(rhs.not_in_netdir);
+        self.wrong_usage = self.wrong_usage.saturating_add(rhs.wrong_usage);
+        self.excluded = self.excluded.saturating_add(rhs.excluded);
+    }
+}
@@ -0,0 +1,153 @@
+//! Information about when our vanguards are due to be rotated.
+//!
+//! Circuits that use a vanguard stop being usable
+//! once the vanguard is rotated out of its set.
+//! A circuit pool that doesn't want to be caught short
+//! can use [`VanguardMgr::next_rotation`](crate::vanguards::VanguardMgr::next_rotation)
+//! to find out when that will happen,
+//! or [`VanguardMgr::upcoming_rotations`](crate::vanguards::VanguardMgr::upcoming_rotations)
+//! to be told shortly before it does.
+
+use std::time::{Duration, SystemTime};
+
+use futures::channel::mpsc;
+use tor_linkspec::RelayIds;
+
+use super::Layer;
+use super::set::VanguardSet;
+
+/// The vanguards of a single [`Layer`], and when they are due to be rotated.
+///
+/// Returned by [`VanguardMgr::next_rotation`](crate::vanguards::VanguardMgr::next_rotation).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct LayerRotationSchedule {
+    /// The layer.
+    pub layer: Layer,
+    /// When the first of the vanguards in this layer is due to be rotated.
+    ///
+    /// This is `None` if the layer has no vanguards.
+    pub next_expiry: Option<SystemTime>,
+    /// The vanguards in this layer, soonest to expire first.
+    pub vanguards: Vec<VanguardLifetime>,
+}
+
+/// The remaining lifetime of a single vanguard.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct VanguardLifetime {
+    /// The identities of the relay.
+    pub relay: RelayIds,
+    /// When the relay is due to be rotated out of its set.
+    pub expires: SystemTime,
+    /// How long until `expires`.
+    pub remaining: Duration,
+}
+
+/// A notice that a vanguard is about to be rotated out of its set.
+///
+/// Yielded by the stream returned from
+/// [`VanguardMgr::upcoming_rotations`](crate::vanguards::VanguardMgr::upcoming_rotations).
+///
+/// Note that the vanguard that will replace this one is not chosen
+/// until the rotation actually happens.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct UpcomingRotation {
+    /// The layer of the set the vanguard is in.
+    pub layer: Layer,
+    /// The identities of the relay.
+    pub relay: RelayIds,
+    /// When the relay is due to be rotated out of its set.
+    pub expires: SystemTime,
+}
+
+impl LayerRotationSchedule {
+    /// Describe the rotation schedule of `set`, which is used for `layer`.
+    pub(super) fn new(layer: Layer, set: &VanguardSet, now: SystemTime) -> Self {
+        let mut vanguards = set
+            .vanguards()
+            .map(|v| VanguardLifetime {
+                relay: v.id.clone(),
+                expires: v.when,
+                remaining: v.when.duration_since(now).unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        vanguards.sort_by_key(|v| v.expires);
+
+        Self {
+            layer,
+            next_expiry: vanguards.first().map(|v| v.expires),
+            vanguards,
+        }
+    }
+}
+
+/// A subscriber to [`UpcomingRotation`] notices.
+pub(super) struct RotationSubscriber {
+    /// How long before its expiry to tell the subscriber about a vanguard.
+    lead_time: Duration,
+    /// The expiry time of the latest-expiring vanguard we have told the subscriber about.
+    ///
+    /// We only send notices for vanguards that expire after this:
+    /// vanguards are always selected with a lifetime much longer than any sensible `lead_time`,
+    /// so a new vanguard won't expire before one we have already sent a notice for.
+    notified_until: Option<SystemTime>,
+    /// The sender for the notices.
+    tx: mpsc::UnboundedSender<UpcomingRotation>,
+}
+
+impl RotationSubscriber {
+    /// Create a new `RotationSubscriber`.
+    pub(super) fn new(lead_time: Duration, tx: mpsc::UnboundedSender<UpcomingRotation>) -> Self {
+        Self {
+            lead_time,
+            notified_until: None,
+            tx,
+        }
+    }
+
+    /// Return true if the subscriber has gone away.
+    pub(super) fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Send a notice for every vanguard in `layers` that expires
+    /// within `lead_time` of `now`, and that we haven't sent a notice for yet.
+    ///
+    /// Returns the time at which the next notice will be due,
+    /// or `None` if there are no vanguards left to send notices for.
+    pub(super) fn notify(
+        &mut self,
+        layers: &[(Layer, &VanguardSet)],
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        let mut pending = layers
+            .iter()
+            .flat_map(|(layer, set)| set.vanguards().map(move |v| (*layer, v)))
+            .filter(|(_, v)| self.notified_until.is_none_or(|until| v.when > until))
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|(_, v)| v.when);
+
+        let mut next_due = None;
+        for (layer, v) in pending {
+            let due = v
+                .when
+                .checked_sub(self.lead_time)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            if due > now {
+                next_due = Some(due);
+                break;
+            }
+
+            let _ = self.tx.unbounded_send(UpcomingRotation {
+                layer,
+                relay: v.id.clone(),
+                expires: v.when,
+            });
+            self.notified_until = Some(v.when);
+        }
+
+        next_due
+    }
+}
@@ -10,14 +10,17 @@ use serde::{Deserialize, Serialize};
 use tor_basic_utils::RngExt as _;
 use tor_error::internal;
 use tor_linkspec::{HasRelayIds as _, RelayIdSet, RelayIds};
-use tor_netdir::{NetDir, Relay};
+use tor_netdir::{NetDir, Relay, RelayWeight, WeightRole};
 use tor_relay_selection::{LowLevelRelayPredicate as _, RelayExclusion, RelaySelector, RelayUsage};
 use tor_rtcompat::Runtime;
 use tracing::{debug, trace};
 
-use crate::{VanguardMgrError, VanguardMode};
+use crate::{VanguardLayerRequirements, VanguardMgrError, VanguardMode};
 
-use super::VanguardParams;
+use super::err::VanguardRejections;
+
+use super::audit::{RemovalReason, VanguardAuditEvent};
+use super::{Layer, VanguardParams};
 
 /// A vanguard relay.
 #[derive(Clone, amplify::Getters)]
@@ -102,79 +105,180 @@ impl VanguardSets {
         &self.l3_vanguards
     }
 
+    /// Return the total number of vanguards in the L2 and L3 sets.
+    pub(super) fn len(&self) -> usize {
+        self.l2_vanguards.vanguards().count() + self.l3_vanguards.vanguards().count()
+    }
+
     /// Remove the vanguards that are expired at the specified timestamp.
     ///
-    /// Returns the number of vanguards that were removed.
-    pub(super) fn remove_expired(&mut self, now: SystemTime) -> usize {
+    /// Returns a [`VanguardAuditEvent`] for each vanguard that was removed.
+    pub(super) fn remove_expired(&mut self, now: SystemTime) -> Vec<VanguardAuditEvent> {
         let l2_expired = self.l2_vanguards.remove_expired(now);
         let l3_expired = self.l3_vanguards.remove_expired(now);
 
-        l2_expired + l3_expired
+        removal_events(Layer::Layer2, l2_expired, RemovalReason::Expired)
+            .chain(removal_events(
+                Layer::Layer3,
+                l3_expired,
+                RemovalReason::Expired,
+            ))
+            .collect()
     }
 
     /// Remove the vanguards that are no longer listed in `netdir`.
     ///
-    /// Returns whether either of the two sets have changed.
-    pub(super) fn remove_unlisted(&mut self, netdir: &NetDir) {
-        self.l2_vanguards.remove_unlisted(netdir);
-        self.l3_vanguards.remove_unlisted(netdir);
+    /// Returns a [`VanguardAuditEvent`] for each vanguard that was removed.
+    pub(super) fn remove_unlisted(&mut self, netdir: &NetDir) -> Vec<VanguardAuditEvent> {
+        let l2_unlisted = self.l2_vanguards.remove_unlisted(netdir);
+        let l3_unlisted = self.l3_vanguards.remove_unlisted(netdir);
+
+        removal_events(Layer::Layer2, l2_unlisted, RemovalReason::Unlisted)
+            .chain(removal_events(
+                Layer::Layer3,
+                l3_unlisted,
+                RemovalReason::Unlisted,
+            ))
+            .collect()
+    }
+
+    /// Remove the vanguards that no longer meet the requirements of their layer,
+    /// according to `netdir`.
+    ///
+    /// Vanguards that aren't listed in `netdir` are kept:
+    /// use [`remove_unlisted`](Self::remove_unlisted) to remove those.
+    ///
+    /// Returns a [`VanguardAuditEvent`] for each vanguard that was removed.
+    pub(super) fn remove_unsuitable(
+        &mut self,
+        netdir: &NetDir,
+        l2_requirements: &VanguardLayerRequirements,
+        l3_requirements: &VanguardLayerRequirements,
+    ) -> Vec<VanguardAuditEvent> {
+        let l2_unsuitable = self.l2_vanguards.remove_unsuitable(netdir, l2_requirements);
+        let l3_unsuitable = self.l3_vanguards.remove_unsuitable(netdir, l3_requirements);
+
+        removal_events(Layer::Layer2, l2_unsuitable, RemovalReason::Unsuitable)
+            .chain(removal_events(
+                Layer::Layer3,
+                l3_unsuitable,
+                RemovalReason::Unsuitable,
+            ))
+            .collect()
+    }
+
+    /// Remove the L3 vanguards that are also L2 vanguards.
+    ///
+    /// A relay must never be in both sets at once.
+    /// We never select such a relay when replenishing the sets,
+    /// but a vanguard state file might still contain one
+    /// (for example, if it was edited by hand).
+    /// In that case, we keep the relay in the L2 set:
+    /// L3 vanguards are rotated more often, so replacing one is less disruptive.
+    ///
+    /// Returns a [`VanguardAuditEvent`] for each vanguard that was removed.
+    pub(super) fn remove_overlapping(&mut self) -> Vec<VanguardAuditEvent> {
+        let l2_ids = RelayIdSet::from(&self.l2_vanguards);
+        let overlapping = self.l3_vanguards.retain(|v| {
+            let overlaps = v.id.identities().any(|id| l2_ids.contains(id));
+
+            if overlaps {
+                debug!(id=?v.id, "Removing L3 vanguard that is also an L2 vanguard");
+            }
+
+            !overlaps
+        });
+
+        removal_events(Layer::Layer3, overlapping, RemovalReason::Overlapping).collect()
     }
 
     /// Replenish the vanguard sets if necessary, using the directory information
     /// from the specified [`NetDir`].
     ///
     /// Note: the L3 set is only replenished if [`Full`](VanguardMode::Full) vanguards are enabled.
-    pub(super) fn replenish_vanguards<R: Runtime>(
+    ///
+    /// The new vanguards of each layer are never members of the other layer,
+    /// and always meet the requirements of their own layer
+    /// (`l2_requirements` or `l3_requirements`).
+    ///
+    /// Returns a [`VanguardAuditEvent`] for each vanguard that was added.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn replenish_vanguards<R: Runtime, Rng: RngCore>(
         &mut self,
         runtime: &R,
+        rng: &mut Rng,
         netdir: &NetDir,
         params: &VanguardParams,
         mode: VanguardMode,
-    ) -> Result<(), VanguardMgrError> {
+        l2_requirements: &VanguardLayerRequirements,
+        l3_requirements: &VanguardLayerRequirements,
+    ) -> Result<Vec<VanguardAuditEvent>, VanguardMgrError> {
         trace!("Replenishing vanguard sets");
 
         // Resize the vanguard sets if necessary.
         self.l2_vanguards.update_target(params.l2_pool_size());
 
-        let mut rng = rand::rng();
-        Self::replenish_set(
-            runtime,
-            &mut rng,
-            netdir,
-            &mut self.l2_vanguards,
-            params.l2_lifetime_min(),
-            params.l2_lifetime_max(),
-        )?;
+        let mut events = addition_events(
+            Layer::Layer2,
+            Self::replenish_set(
+                runtime,
+                rng,
+                netdir,
+                &mut self.l2_vanguards,
+                &self.l3_vanguards,
+                l2_requirements,
+                params.l2_lifetime_min(),
+                params.l2_lifetime_max(),
+                params.bw_weight_exponent(),
+            )?,
+        )
+        .collect::<Vec<_>>();
 
         if mode == VanguardMode::Full {
             self.l3_vanguards.update_target(params.l3_pool_size());
-            Self::replenish_set(
+            let added = Self::replenish_set(
                 runtime,
-                &mut rng,
+                rng,
                 netdir,
                 &mut self.l3_vanguards,
+                &self.l2_vanguards,
+                l3_requirements,
                 params.l3_lifetime_min(),
                 params.l3_lifetime_max(),
+                params.bw_weight_exponent(),
             )?;
+            events.extend(addition_events(Layer::Layer3, added));
         }
 
-        Ok(())
+        Ok(events)
     }
 
     /// Replenish a single `VanguardSet` with however many vanguards it is short of.
+    ///
+    /// None of the new vanguards will be members of `other_set`,
+    /// the vanguard set of the other layer,
+    /// and all of them will meet `requirements`.
+    ///
+    /// Returns the vanguards that were added to the set.
+    #[allow(clippy::too_many_arguments)]
     fn replenish_set<R: Runtime, Rng: RngCore>(
         runtime: &R,
         rng: &mut Rng,
         netdir: &NetDir,
         vanguard_set: &mut VanguardSet,
+        other_set: &VanguardSet,
+        requirements: &VanguardLayerRequirements,
         min_lifetime: Duration,
         max_lifetime: Duration,
-    ) -> Result<bool, VanguardMgrError> {
-        let mut set_changed = false;
+        bw_weight_exponent: f64,
+    ) -> Result<Vec<TimeBoundVanguard>, VanguardMgrError> {
+        let mut added = vec![];
         let deficit = vanguard_set.deficit();
         if deficit > 0 {
-            // Exclude the relays that are already in this vanguard set.
-            let exclude_ids = RelayIdSet::from(&*vanguard_set);
+            // Exclude the relays that are already in this vanguard set,
+            // or in the other one.
+            let mut exclude_ids = RelayIdSet::from(&*vanguard_set);
+            exclude_ids.extend(RelayIdSet::from(other_set).iter().map(|id| id.to_owned()));
             let exclude = RelayExclusion::exclude_identities(exclude_ids);
             // Pick some vanguards to add to the vanguard_set.
             let new_vanguards = Self::add_n_vanguards(
@@ -183,40 +287,57 @@ impl VanguardSets {
                 netdir,
                 deficit,
                 exclude,
+                requirements,
                 min_lifetime,
                 max_lifetime,
+                bw_weight_exponent,
             )?;
 
-            if !new_vanguards.is_empty() {
-                set_changed = true;
-            }
-
             for v in new_vanguards {
-                vanguard_set.add_vanguard(v);
+                vanguard_set.add_vanguard(v.clone());
+                added.push(v);
             }
         }
 
-        Ok(set_changed)
+        Ok(added)
     }
 
-    /// Select `n` relays to use as vanguards.
+    /// Select `n` relays that meet `requirements` to use as vanguards.
+    ///
+    /// Each relay is selected with probability proportional to its consensus weight
+    /// raised to the power of `bw_weight_exponent`.
     ///
     /// Each selected vanguard will have a random lifetime
     /// between `min_lifetime` and `max_lifetime`.
+    #[allow(clippy::too_many_arguments)]
     fn add_n_vanguards<R: Runtime, Rng: RngCore>(
         runtime: &R,
         rng: &mut Rng,
         netdir: &NetDir,
         n: usize,
         exclude: RelayExclusion,
+        requirements: &VanguardLayerRequirements,
         min_lifetime: Duration,
         max_lifetime: Duration,
+        bw_weight_exponent: f64,
     ) -> Result<Vec<TimeBoundVanguard>, VanguardMgrError> {
         trace!(relay_count = n, "selecting relays to use as vanguards");
 
         let vanguard_sel = RelaySelector::new(RelayUsage::vanguard(), exclude);
 
-        let (relays, _outcome) = vanguard_sel.select_n_relays(rng, n, netdir);
+        let relays = if bw_weight_exponent == 1.0 && requirements.is_unrestricted() {
+            // This is the usual weighting, and there are no extra requirements,
+            // so we can let the RelaySelector do the work.
+            let (relays, _outcome) = vanguard_sel.select_n_relays(rng, n, netdir);
+            relays
+        } else {
+            let candidates = netdir
+                .relays()
+                .filter(|relay| vanguard_sel.permits_relay(relay))
+                .filter(|relay| requirements.permits(netdir, relay))
+                .collect::<Vec<_>>();
+            select_n_weighted(rng, netdir, &candidates, n, bw_weight_exponent)
+        };
 
         relays
             .into_iter()
@@ -234,6 +355,82 @@ impl VanguardSets {
     }
 }
 
+/// Return the weight with which to select `relay` as a vanguard.
+///
+/// This is the consensus weight of `relay`, raised to the power of `bw_weight_exponent`.
+fn vanguard_weight(netdir: &NetDir, relay: &Relay<'_>, bw_weight_exponent: f64) -> f64 {
+    let weight = netdir
+        .relay_weight(relay, WeightRole::Middle)
+        .checked_div(RelayWeight::from(1))
+        .unwrap_or_default();
+
+    weight.powf(bw_weight_exponent)
+}
+
+/// Select up to `n` distinct relays from `candidates`,
+/// weighted by [`vanguard_weight`].
+///
+/// If too few of the candidates have a nonzero weight,
+/// the relays are selected uniformly at random instead.
+fn select_n_weighted<'a, Rng: RngCore>(
+    rng: &mut Rng,
+    netdir: &NetDir,
+    candidates: &[Relay<'a>],
+    n: usize,
+    bw_weight_exponent: f64,
+) -> Vec<Relay<'a>> {
+    let n_nonzero = candidates
+        .iter()
+        .filter(|relay| vanguard_weight(netdir, relay, bw_weight_exponent) > 0.0)
+        .count();
+
+    if n_nonzero < cmp::min(n, candidates.len()) {
+        debug!(
+            n_nonzero,
+            n_candidates = candidates.len(),
+            "Too few candidate vanguards with nonzero weight; selecting uniformly at random"
+        );
+        return candidates.choose_multiple(rng, n).cloned().collect();
+    }
+
+    match candidates.choose_multiple_weighted(rng, n, |relay| {
+        vanguard_weight(netdir, relay, bw_weight_exponent)
+    }) {
+        Ok(relays) => relays.cloned().collect(),
+        Err(e) => {
+            debug!("Unable to select weighted vanguards ({e}); selecting uniformly at random");
+            candidates.choose_multiple(rng, n).cloned().collect()
+        }
+    }
+}
+
+/// Build a [`VanguardAuditEvent::Removed`] for each of the `removed` vanguards.
+fn removal_events(
+    layer: Layer,
+    removed: Vec<TimeBoundVanguard>,
+    reason: RemovalReason,
+) -> impl Iterator<Item = VanguardAuditEvent> {
+    removed
+        .into_iter()
+        .map(move |v| VanguardAuditEvent::Removed {
+            layer,
+            relay: v.id,
+            reason,
+        })
+}
+
+/// Build a [`VanguardAuditEvent::Added`] for each of the `added` vanguards.
+fn addition_events(
+    layer: Layer,
+    added: Vec<TimeBoundVanguard>,
+) -> impl Iterator<Item = VanguardAuditEvent> {
+    added.into_iter().map(move |v| VanguardAuditEvent::Added {
+        layer,
+        relay: v.id,
+        expires: v.when,
+    })
+}
+
 /// Randomly select the lifetime of a vanguard from the `max(X,X)` distribution,
 /// where `X` is a uniform random value between `min_lifetime` and `max_lifetime`.
 ///
@@ -267,30 +464,55 @@ impl VanguardSet {
     ///
     /// See [`VanguardMgr::select_vanguard`](crate::vanguards::VanguardMgr::select_vanguard)
     /// for more information.
+    ///
+    /// If none of the vanguards in this set are usable,
+    /// returns the number of vanguards we rejected for each reason.
     pub(super) fn pick_relay<'a, R: RngCore>(
         &self,
         rng: &mut R,
         netdir: &'a NetDir,
         relay_selector: &RelaySelector<'a>,
-    ) -> Option<Vanguard<'a>> {
+    ) -> Result<Vanguard<'a>, VanguardRejections> {
+        let mut rejected = VanguardRejections::default();
         let good_relays = self
             .vanguards
             .iter()
             .filter_map(|vanguard| {
-                // Skip over any unusable relays
-                let relay = netdir.by_ids(&vanguard.id)?;
-                relay_selector
-                    .low_level_predicate_permits_relay(&relay)
-                    .then_some(relay)
+                // Skip over any unusable relays, remembering why they are unusable.
+                let Some(relay) = netdir.by_ids(&vanguard.id) else {
+                    rejected.not_in_netdir += 1;
+                    return None;
+                };
+                if relay_selector.low_level_predicate_permits_relay(&relay) {
+                    Some(relay)
+                } else {
+                    if relay_selector
+                        .usage()
+                        .low_level_predicate_permits_relay(&relay)
+                    {
+                        rejected.excluded += 1;
+                    } else {
+                        rejected.wrong_usage += 1;
+                    }
+                    None
+                }
             })
             .collect::<Vec<_>>();
 
         // Note: We make a uniform choice instead of a weighted one,
         // because we already made a bandwidth-weighted choice when we added
         // the vanguards to this set in the first place.
-        good_relays.choose(rng).map(|relay| Vanguard {
-            relay: relay.clone(),
-        })
+        good_relays
+            .choose(rng)
+            .map(|relay| Vanguard {
+                relay: relay.clone(),
+            })
+            .ok_or(rejected)
+    }
+
+    /// Return an iterator over the vanguards in this set.
+    pub(super) fn vanguards(&self) -> impl Iterator<Item = &TimeBoundVanguard> {
+        self.vanguards.iter()
     }
 
     /// Whether this vanguard set is empty.
@@ -310,8 +532,8 @@ impl VanguardSet {
 
     /// Remove the vanguards that are no longer listed in `netdir`
     ///
-    /// Returns the number of vanguards that were unlisted.
-    fn remove_unlisted(&mut self, netdir: &NetDir) -> usize {
+    /// Returns the vanguards that were unlisted.
+    fn remove_unlisted(&mut self, netdir: &NetDir) -> Vec<TimeBoundVanguard> {
         self.retain(|v| {
             let cond = netdir.ids_listed(&v.id) != Some(false);
 
@@ -323,10 +545,35 @@ impl VanguardSet {
         })
     }
 
+    /// Remove the listed vanguards that don't meet `requirements`, according to `netdir`.
+    ///
+    /// Returns the vanguards that were removed.
+    fn remove_unsuitable(
+        &mut self,
+        netdir: &NetDir,
+        requirements: &VanguardLayerRequirements,
+    ) -> Vec<TimeBoundVanguard> {
+        if requirements.is_unrestricted() {
+            return vec![];
+        }
+
+        self.retain(|v| {
+            let cond = netdir
+                .by_ids(&v.id)
+                .is_none_or(|relay| requirements.permits(netdir, &relay));
+
+            if !cond {
+                debug!(id=?v.id, "Removing vanguard that no longer meets our requirements");
+            }
+
+            cond
+        })
+    }
+
     /// Remove the vanguards that are expired at the specified timestamp.
     ///
-    /// Returns the number of vanguards that expired.
-    fn remove_expired(&mut self, now: SystemTime) -> usize {
+    /// Returns the vanguards that expired.
+    fn remove_expired(&mut self, now: SystemTime) -> Vec<TimeBoundVanguard> {
         self.retain(|v| {
             let cond = v.when > now;
 
@@ -338,14 +585,16 @@ impl VanguardSet {
         })
     }
 
-    /// A wrapper around [`Vec::retain`] that returns the number of discarded elements.
-    fn retain<F>(&mut self, f: F) -> usize
+    /// A wrapper around [`Vec::retain`] that returns the discarded elements.
+    fn retain<F>(&mut self, mut f: F) -> Vec<TimeBoundVanguard>
     where
         F: FnMut(&TimeBoundVanguard) -> bool,
     {
-        let old_len = self.vanguards.len();
-        self.vanguards.retain(f);
-        old_len - self.vanguards.len()
+        let (kept, discarded): (Vec<_>, Vec<_>) = std::mem::take(&mut self.vanguards)
+            .into_iter()
+            .partition(|v| f(v));
+        self.vanguards = kept;
+        discarded
     }
 
     /// Find the timestamp of the vanguard that is due to expire next.
@@ -401,3 +650,83 @@ derive_deftly_adhoc! {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use float_eq::assert_float_eq;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_netdir::testnet;
+
+    #[test]
+    fn select_weighted() {
+        /// How many times we select a relay for each exponent.
+        const N_TRIALS: usize = 4000;
+        /// The tolerance for the fraction of the trials that picked one of the heaviest relays.
+        const TOL: f64 = 0.05;
+
+        let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
+        let vanguard_sel =
+            RelaySelector::new(RelayUsage::vanguard(), RelayExclusion::no_relays_excluded());
+        let candidates = netdir
+            .relays()
+            .filter(|relay| vanguard_sel.permits_relay(relay))
+            .collect::<Vec<_>>();
+        let weight = |relay: &Relay<'_>| vanguard_weight(&netdir, relay, 1.0);
+        let weights = candidates.iter().map(weight).collect::<Vec<_>>();
+        let heaviest = weights.iter().copied().fold(0.0, f64::max);
+        assert!(weights.iter().any(|w| *w < heaviest));
+
+        let mut rng = testing_rng();
+        let mut prev_expected = 0.0;
+        for exponent in [0.0, 1.0, 4.0] {
+            // The heaviest relays are picked in proportion to their weight,
+            // raised to the power of the exponent.
+            let total: f64 = weights.iter().map(|w| w.powf(exponent)).sum();
+            let expected = weights
+                .iter()
+                .filter(|w| **w == heaviest)
+                .map(|w| w.powf(exponent))
+                .sum::<f64>()
+                / total;
+            let n_heaviest = (0..N_TRIALS)
+                .filter(|_| {
+                    let selected = select_n_weighted(&mut rng, &netdir, &candidates, 1, exponent);
+                    weight(&selected[0]) == heaviest
+                })
+                .count();
+            assert_float_eq!(
+                n_heaviest as f64 / N_TRIALS as f64,
+                expected,
+                abs <= TOL,
+                "exponent {exponent}"
+            );
+
+            // The higher the exponent, the more we favor the heaviest relays.
+            assert!(expected > prev_expected + TOL);
+            prev_expected = expected;
+        }
+
+        // We never select the same relay twice.
+        let selected = select_n_weighted(&mut rng, &netdir, &candidates, 10, 4.0);
+        assert_eq!(selected.len(), 10);
+        let ids = selected
+            .iter()
+            .map(RelayIds::from_relay_ids)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 10);
+    }
+}
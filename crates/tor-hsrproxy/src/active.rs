@@ -0,0 +1,318 @@
+//! Tracking the connections that a proxy is currently forwarding.
+//!
+//! Every stream that we forward to a local target is listed in a [`ConnectionTable`]
+//! from the moment we decide to forward it until we stop forwarding data on it,
+//! so that operators can see what their service is doing,
+//! and close connections that they don't want.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::FutureExt as _;
+use futures::future::Shared;
+use oneshot_fused_workaround as oneshot;
+use tor_hsservice::HsNickname;
+
+use crate::config::TargetAddr;
+
+/// An identifier for a connection forwarded by an
+/// [`OnionServiceReverseProxy`](crate::OnionServiceReverseProxy).
+///
+/// Identifiers are never reused by the same proxy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct ConnectionId(u64);
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "conn#{}", self.0)
+    }
+}
+
+/// Information about a connection that we are forwarding.
+///
+/// Returned by
+/// [`OnionServiceReverseProxy::active_connections`](crate::OnionServiceReverseProxy::active_connections).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ActiveConnection {
+    /// The identifier of this connection.
+    ///
+    /// Use this to [close](crate::OnionServiceReverseProxy::close_connection) it.
+    pub id: ConnectionId,
+    /// The onion service that the connection is for.
+    pub nickname: HsNickname,
+    /// The port on the onion service to which the client connected.
+    pub port: u16,
+    /// The local target to which we are forwarding the connection.
+    pub target: TargetAddr,
+    /// When we started handling the connection.
+    ///
+    /// This includes the time it took to connect to `target`.
+    pub started: SystemTime,
+    /// The number of bytes we have forwarded from the client to `target` so far.
+    pub bytes_to_target: u64,
+    /// The number of bytes we have forwarded from `target` to the client so far.
+    pub bytes_from_target: u64,
+    /// The number of relay cells in which we have sent `bytes_from_target` to the client,
+    /// approximately.
+    ///
+    /// We count a cell for every full cell's worth of data,
+    /// and one for each partial cell's worth that we flushed.
+    pub cells_from_target: u64,
+}
+
+impl ActiveConnection {
+    /// Return the average number of bytes that we sent to the client in each relay cell,
+    /// or `None` if we haven't sent any cells.
+    ///
+    /// The closer this is to the payload size of a cell, the less overhead we are adding.
+    /// See [`BufferConfig`](crate::config::BufferConfig) for ways to improve it.
+    pub fn bytes_per_cell_from_target(&self) -> Option<u64> {
+        self.bytes_from_target.checked_div(self.cells_from_target)
+    }
+}
+
+/// The connections that a proxy is forwarding.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTable {
+    /// The mutable state of this table.
+    inner: Mutex<TableInner>,
+}
+
+/// The mutable state of a [`ConnectionTable`].
+#[derive(Debug, Default)]
+struct TableInner {
+    /// The identifier to give to the next connection.
+    next_id: u64,
+    /// The connections we are forwarding, by identifier.
+    conns: BTreeMap<ConnectionId, Entry>,
+}
+
+/// An entry in a [`ConnectionTable`].
+#[derive(Debug)]
+struct Entry {
+    /// The onion service that the connection is for.
+    nickname: HsNickname,
+    /// The port on the onion service to which the client connected.
+    port: u16,
+    /// The local target.
+    target: TargetAddr,
+    /// When we started handling the connection.
+    started: SystemTime,
+    /// The counters for the data we have forwarded.
+    counters: Arc<ByteCounters>,
+    /// A sender that we drop to tell the tasks forwarding the connection to stop.
+    close_tx: Option<oneshot::Sender<void::Void>>,
+}
+
+/// The number of bytes forwarded in each direction on a connection.
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounters {
+    /// Bytes from the client to the target.
+    to_target: AtomicU64,
+    /// Bytes from the target to the client.
+    from_target: AtomicU64,
+    /// Relay cells from the target to the client (approximately).
+    cells_from_target: AtomicU64,
+}
+
+/// A direction in which we forward data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Direction {
+    /// From the client to the local target.
+    ToTarget,
+    /// From the local target to the client.
+    FromTarget,
+}
+
+impl ByteCounters {
+    /// Record that we have forwarded `n_bytes` in `direction`.
+    pub(crate) fn note_forwarded(&self, direction: Direction, n_bytes: usize) {
+        let counter = match direction {
+            Direction::ToTarget => &self.to_target,
+            Direction::FromTarget => &self.from_target,
+        };
+        counter.fetch_add(
+            u64::try_from(n_bytes).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Record that we have sent `n_cells` relay cells to the client.
+    pub(crate) fn note_cells_from_target(&self, n_cells: usize) {
+        self.cells_from_target.fetch_add(
+            u64::try_from(n_cells).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Return the number of relay cells we have sent to the client.
+    pub(crate) fn cells_from_target(&self) -> u64 {
+        self.cells_from_target.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of bytes we have forwarded in `direction`.
+    pub(crate) fn forwarded(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::ToTarget => self.to_target.load(Ordering::Relaxed),
+            Direction::FromTarget => self.from_target.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ConnectionTable {
+    /// Return information about every connection in this table, ordered by identifier.
+    pub(crate) fn list(&self) -> Vec<ActiveConnection> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        inner
+            .conns
+            .iter()
+            .map(|(id, entry)| ActiveConnection {
+                id: *id,
+                nickname: entry.nickname.clone(),
+                port: entry.port,
+                target: entry.target.clone(),
+                started: entry.started,
+                bytes_to_target: entry.counters.forwarded(Direction::ToTarget),
+                bytes_from_target: entry.counters.forwarded(Direction::FromTarget),
+                cells_from_target: entry.counters.cells_from_target(),
+            })
+            .collect()
+    }
+
+    /// Tell the tasks forwarding the connection `id` to stop.
+    ///
+    /// Return false if there is no such connection, or if we have already closed it.
+    pub(crate) fn close(&self, id: ConnectionId) -> bool {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner
+            .conns
+            .get_mut(&id)
+            .and_then(|entry| entry.close_tx.take())
+            .is_some()
+    }
+}
+
+/// A connection listed in a [`ConnectionTable`].
+///
+/// The connection is removed from the table when this is dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    /// The table that lists the connection.
+    table: Arc<ConnectionTable>,
+    /// The identifier of the connection.
+    id: ConnectionId,
+    /// The counters for the data we forward.
+    counters: Arc<ByteCounters>,
+    /// A future that resolves once someone has asked us to close the connection.
+    closed: Shared<oneshot::Receiver<void::Void>>,
+}
+
+impl ConnectionGuard {
+    /// List a new connection in `table`.
+    pub(crate) fn register(
+        table: &Arc<ConnectionTable>,
+        nickname: &HsNickname,
+        port: u16,
+        target: &TargetAddr,
+        now: SystemTime,
+    ) -> Self {
+        let (close_tx, close_rx) = oneshot::channel();
+        let counters = Arc::new(ByteCounters::default());
+        let mut inner = table.inner.lock().expect("poisoned lock");
+        let id = ConnectionId(inner.next_id);
+        inner.next_id += 1;
+        inner.conns.insert(
+            id,
+            Entry {
+                nickname: nickname.clone(),
+                port,
+                target: target.clone(),
+                started: now,
+                counters: counters.clone(),
+                close_tx: Some(close_tx),
+            },
+        );
+        ConnectionGuard {
+            table: table.clone(),
+            id,
+            counters,
+            closed: close_rx.shared(),
+        }
+    }
+
+    /// Return the counters in which to record the data we forward.
+    pub(crate) fn counters(&self) -> &Arc<ByteCounters> {
+        &self.counters
+    }
+
+    /// Wait until someone asks us to close this connection.
+    pub(crate) async fn closed(&self) {
+        // The sender is never used, so this only returns once it is dropped.
+        let _: Result<void::Void, _> = self.closed.clone().await;
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut inner = self.table.inner.lock().expect("poisoned lock");
+        inner.conns.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn list_and_close() {
+        let table = Arc::new(ConnectionTable::default());
+        let nickname = HsNickname::new("allium".into()).unwrap();
+        let target = TargetAddr::Inet("127.0.0.1:8080".parse().unwrap());
+        let now = SystemTime::UNIX_EPOCH;
+
+        let a = ConnectionGuard::register(&table, &nickname, 80, &target, now);
+        let b = ConnectionGuard::register(&table, &nickname, 443, &target, now);
+        a.counters().note_forwarded(Direction::ToTarget, 5);
+        a.counters().note_forwarded(Direction::FromTarget, 7);
+        a.counters().note_cells_from_target(2);
+
+        let list = table.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].id, a.id);
+        assert_eq!(list[0].port, 80);
+        assert_eq!(list[0].bytes_to_target, 5);
+        assert_eq!(list[0].bytes_from_target, 7);
+        assert_eq!(list[0].bytes_per_cell_from_target(), Some(3));
+        assert_eq!(list[1].bytes_per_cell_from_target(), None);
+        assert_eq!(list[1].port, 443);
+        assert_ne!(a.id, b.id);
+
+        // Closing a connection tells its forwarding tasks, but only once.
+        assert!(table.close(b.id));
+        assert!(!table.close(b.id));
+        block_on(b.closed());
+
+        // Connections leave the table when we stop forwarding them.
+        let b_id = b.id;
+        drop(b);
+        assert_eq!(table.list().len(), 1);
+        assert!(!table.close(b_id));
+    }
+}
@@ -3,7 +3,7 @@
 use derive_builder::Builder;
 use derive_deftly::Deftly;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, ops::RangeInclusive, str::FromStr};
+use std::{net::SocketAddr, num::NonZeroUsize, ops::RangeInclusive, str::FromStr, time::Duration};
 use tracing::warn;
 //use tor_config::derive_deftly_template_Flattenable;
 use tor_config::{ConfigBuildError, define_list_builder_accessors, define_list_builder_helper};
@@ -18,8 +18,35 @@ pub struct ProxyConfig {
     /// matches, we take the DestroyCircuit action.
     #[builder(sub_builder, setter(custom))]
     pub(crate) proxy_ports: ProxyRuleList,
+
+    /// The largest number of streams from a single rendezvous circuit
+    /// that we will forward at once.
+    ///
+    /// Once a circuit has this many forwarded streams open,
+    /// we reject any further requests on it with an END message,
+    /// until some of the existing streams are closed.
+    /// Requests on other circuits are unaffected.
+    ///
+    /// If this is not set, there is no limit.
+    #[builder(default)]
+    pub(crate) max_forwarded_streams_per_circuit: Option<NonZeroUsize>,
+
+    /// A limit on how much data we forward for this onion service
+    /// during each accounting period.
+    ///
+    /// If this is not set, there is no limit.
+    /// (We keep track of how much data we forward either way.)
+    #[builder(default)]
+    pub(crate) bandwidth_quota: Option<BandwidthQuota>,
+
+    /// What to do with RESOLVE requests.
+    ///
+    /// We only receive these if the onion service is configured to pass them on to us.
+    /// This cannot be a forward action. By default, we reject the request.
+    #[builder(default = "ProxyAction::RejectStream")]
+    pub(crate) resolve_requests: ProxyAction,
     //
-    // TODO: Someday we may want to allow udp, resolve, etc.  If we do, it will
+    // TODO: Someday we may want to allow udp, etc.  If we do, it will
     // be via another option, rather than adding another subtype to ProxySource.
 }
 
@@ -39,6 +66,106 @@ impl ProxyConfigBuilder {
             covered.insert(range.clone());
         }
 
+        for rule in self.proxy_ports.access_opt().iter().flatten() {
+            if rule.buffering.buffer_size == 0 {
+                return Err(ConfigBuildError::Invalid {
+                    field: "proxy_ports".into(),
+                    problem: format!("Zero buffer_size for port pattern {}", rule.source),
+                });
+            }
+            if rule.buffering.buffer_size > MAX_BUFFER_SIZE {
+                return Err(ConfigBuildError::Invalid {
+                    field: "proxy_ports".into(),
+                    problem: format!(
+                        "buffer_size for port pattern {} is larger than {MAX_BUFFER_SIZE}",
+                        rule.source
+                    ),
+                });
+            }
+            if let Some(response) = &rule.response {
+                if rule.target != ProxyAction::RejectStream {
+                    return Err(ConfigBuildError::Invalid {
+                        field: "proxy_ports".into(),
+                        problem: format!(
+                            "Response given for port pattern {}, which does not reject streams",
+                            rule.source
+                        ),
+                    });
+                }
+                if let Err(problem) = response.check() {
+                    return Err(ConfigBuildError::Invalid {
+                        field: "proxy_ports".into(),
+                        problem: format!(
+                            "{} in response for port pattern {}",
+                            problem, rule.source
+                        ),
+                    });
+                }
+            }
+            #[cfg(feature = "udp")]
+            if matches!(
+                rule.target,
+                ProxyAction::Forward(Encapsulation::Datagram, _)
+            ) && rule.preamble != Preamble::default()
+            {
+                return Err(ConfigBuildError::Invalid {
+                    field: "proxy_ports".into(),
+                    problem: format!(
+                        "Preamble given for port pattern {}, which forwards datagrams",
+                        rule.source
+                    ),
+                });
+            }
+            if let Some(timeout) = rule.connect_timeout {
+                if !matches!(rule.target, ProxyAction::Forward(..)) {
+                    return Err(ConfigBuildError::Invalid {
+                        field: "proxy_ports".into(),
+                        problem: format!(
+                            "connect_timeout given for port pattern {}, which does not forward streams",
+                            rule.source
+                        ),
+                    });
+                }
+                if timeout.is_zero() {
+                    return Err(ConfigBuildError::Invalid {
+                        field: "proxy_ports".into(),
+                        problem: format!("Zero connect_timeout for port pattern {}", rule.source),
+                    });
+                }
+            }
+            if let Some(keepalive) = &rule.keepalive {
+                if !matches!(rule.target, ProxyAction::Forward(Encapsulation::Simple, _)) {
+                    return Err(ConfigBuildError::Invalid {
+                        field: "proxy_ports".into(),
+                        problem: format!(
+                            "keepalive given for port pattern {}, which does not forward streams",
+                            rule.source
+                        ),
+                    });
+                }
+                if rule.preamble != Preamble::default() {
+                    // The preamble describes a single client's stream,
+                    // so a connection that carries it can't be given to another one.
+                    return Err(ConfigBuildError::Invalid {
+                        field: "proxy_ports".into(),
+                        problem: format!(
+                            "Both keepalive and a preamble given for port pattern {}",
+                            rule.source
+                        ),
+                    });
+                }
+                if keepalive.pool_size == 0 || keepalive.idle_timeout.is_zero() {
+                    return Err(ConfigBuildError::Invalid {
+                        field: "proxy_ports".into(),
+                        problem: format!(
+                            "Zero pool_size or idle_timeout in keepalive for port pattern {}",
+                            rule.source
+                        ),
+                    });
+                }
+            }
+        }
+
         // Warn about proxy setups that are likely to be surprising.
         let mut any_forward = false;
         for rule in self.proxy_ports.access_opt().iter().flatten() {
@@ -63,6 +190,22 @@ impl ProxyConfigBuilder {
             warn!("Onion service is not configured to accept any connections.");
         }
 
+        if let Some(ProxyAction::Forward(..)) = &self.resolve_requests {
+            return Err(ConfigBuildError::Invalid {
+                field: "resolve_requests".into(),
+                problem: "RESOLVE requests cannot be forwarded".into(),
+            });
+        }
+
+        if let Some(Some(quota)) = &self.bandwidth_quota {
+            if matches!(quota.action, ProxyAction::Forward(..)) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "bandwidth_quota".into(),
+                    problem: "The action for streams over quota cannot be a forward".into(),
+                });
+            }
+        }
+
         Ok(())
     }
 }
@@ -87,50 +230,552 @@ define_list_builder_helper! {
 }
 
 impl ProxyConfig {
-    /// Find the configured action to use when receiving a request for a
+    /// Find the configured rule to use when receiving a request for a
     /// connection on a given port.
-    pub(crate) fn resolve_port_for_begin(&self, port: u16) -> Option<&ProxyAction> {
+    pub(crate) fn resolve_port_for_begin(&self, port: u16) -> Option<&ProxyRule> {
         self.proxy_ports
             .iter()
             .find(|rule| rule.source.matches_port(port))
-            .map(|rule| &rule.target)
     }
 }
 
 /// A single rule in a `ProxyConfig`.
 ///
 /// Rules take the form of, "When this pattern matches, take this action."
+///
+/// A rule is usually written as a `[pattern, action]` pair.
+/// Rules that need any other per-rule options are written as a table,
+/// with the pattern and action in its `source` and `target` fields.
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
-// TODO: we might someday want to accept structs here as well, so that
-// we can add per-rule fields if we need to.  We can make that an option if/when
-// it comes up, however.
-#[serde(from = "ProxyRuleAsTuple", into = "ProxyRuleAsTuple")]
+#[serde(from = "ProxyRuleAsEnum", into = "ProxyRuleAsEnum")]
 pub struct ProxyRule {
     /// Any connections to a port matching this pattern match this rule.
     source: ProxyPattern,
     /// When this rule matches, we take this action.
     target: ProxyAction,
+    /// How we buffer the data we forward for connections matching this rule.
+    buffering: BufferConfig,
+    /// What we send to the target before forwarding any data, for connections matching this rule.
+    preamble: Preamble,
+    /// What we send to the client instead of rejecting its stream, if anything.
+    ///
+    /// Only allowed if `target` is [`ProxyAction::RejectStream`].
+    response: Option<StaticResponse>,
+    /// How long we may take to connect to the target and accept the client's stream,
+    /// for connections matching this rule.
+    ///
+    /// If it takes any longer, we reject the stream instead.
+    /// Only allowed if `target` is a [`ProxyAction::Forward`].
+    connect_timeout: Option<Duration>,
+    /// Whether, and how, we reuse connections to the target, for connections matching this rule.
+    ///
+    /// Only allowed if `target` forwards streams, and there is no `preamble`.
+    keepalive: Option<KeepaliveConfig>,
 }
 
 /// Helper type used to (de)serialize ProxyRule.
-type ProxyRuleAsTuple = (ProxyPattern, ProxyAction);
-impl From<ProxyRuleAsTuple> for ProxyRule {
-    fn from(value: ProxyRuleAsTuple) -> Self {
-        Self {
-            source: value.0,
-            target: value.1,
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ProxyRuleAsEnum {
+    /// A rule with no options other than its pattern and action.
+    Tuple(ProxyPattern, ProxyAction),
+    /// A rule with additional options.
+    Struct {
+        /// See [`ProxyRule::source`].
+        source: ProxyPattern,
+        /// See [`ProxyRule::target`].
+        target: ProxyAction,
+        /// See [`ProxyRule::buffering`].
+        #[serde(flatten)]
+        buffering: BufferConfig,
+        /// See [`ProxyRule::preamble`].
+        #[serde(default)]
+        preamble: Preamble,
+        /// See [`ProxyRule::response`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        response: Option<StaticResponse>,
+        /// See [`ProxyRule::connect_timeout`].
+        #[serde(
+            default,
+            with = "humantime_serde",
+            skip_serializing_if = "Option::is_none"
+        )]
+        connect_timeout: Option<Duration>,
+        /// See [`ProxyRule::keepalive`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keepalive: Option<KeepaliveConfig>,
+    },
+}
+
+impl From<ProxyRuleAsEnum> for ProxyRule {
+    fn from(value: ProxyRuleAsEnum) -> Self {
+        match value {
+            ProxyRuleAsEnum::Tuple(source, target) => Self::new(source, target),
+            ProxyRuleAsEnum::Struct {
+                source,
+                target,
+                buffering,
+                preamble,
+                response,
+                connect_timeout,
+                keepalive,
+            } => Self {
+                source,
+                target,
+                buffering,
+                preamble,
+                response,
+                connect_timeout,
+                keepalive,
+            },
         }
     }
 }
-impl From<ProxyRule> for ProxyRuleAsTuple {
+impl From<ProxyRule> for ProxyRuleAsEnum {
     fn from(value: ProxyRule) -> Self {
-        (value.source, value.target)
+        let ProxyRule {
+            source,
+            target,
+            buffering,
+            preamble,
+            response,
+            connect_timeout,
+            keepalive,
+        } = value;
+        if buffering == BufferConfig::default()
+            && preamble == Preamble::default()
+            && response.is_none()
+            && connect_timeout.is_none()
+            && keepalive.is_none()
+        {
+            ProxyRuleAsEnum::Tuple(source, target)
+        } else {
+            ProxyRuleAsEnum::Struct {
+                source,
+                target,
+                buffering,
+                preamble,
+                response,
+                connect_timeout,
+                keepalive,
+            }
+        }
     }
 }
 impl ProxyRule {
     /// Create a new ProxyRule mapping `source` to `target`.
     pub fn new(source: ProxyPattern, target: ProxyAction) -> Self {
-        Self { source, target }
+        Self {
+            source,
+            target,
+            buffering: BufferConfig::default(),
+            preamble: Preamble::default(),
+            response: None,
+            connect_timeout: None,
+            keepalive: None,
+        }
+    }
+
+    /// Use `buffering` for the connections that match this rule.
+    pub fn with_buffering(mut self, buffering: BufferConfig) -> Self {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Send `preamble` to the target of the connections that match this rule.
+    pub fn with_preamble(mut self, preamble: Preamble) -> Self {
+        self.preamble = preamble;
+        self
+    }
+
+    /// Send `response` to the clients whose streams match this rule,
+    /// instead of rejecting their streams.
+    ///
+    /// This is only allowed if this rule's action is [`ProxyAction::RejectStream`].
+    pub fn with_response(mut self, response: StaticResponse) -> Self {
+        self.response = Some(response);
+        self
+    }
+
+    /// Give up on the connections that match this rule, and reject their streams,
+    /// if we can't connect to the target and accept the stream within `timeout`.
+    ///
+    /// This is only allowed if this rule's action is a [`ProxyAction::Forward`].
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep the connections to the target of this rule open once their client is done with them,
+    /// and reuse them for later streams that match this rule, as described by `keepalive`.
+    ///
+    /// This is only allowed if this rule forwards streams, and has no preamble.
+    pub fn with_keepalive(mut self, keepalive: KeepaliveConfig) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Return the action to take when this rule matches.
+    pub(crate) fn target(&self) -> &ProxyAction {
+        &self.target
+    }
+
+    /// Return the buffering configuration for connections matching this rule.
+    pub(crate) fn buffering(&self) -> &BufferConfig {
+        &self.buffering
+    }
+
+    /// Return what to send to the target of connections matching this rule.
+    pub(crate) fn preamble(&self) -> Preamble {
+        self.preamble
+    }
+
+    /// Return what to send to clients instead of rejecting their streams, if anything.
+    pub(crate) fn response(&self) -> Option<&StaticResponse> {
+        self.response.as_ref()
+    }
+
+    /// Return how long we may take to set up connections matching this rule, if there is a limit.
+    pub(crate) fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Return how we reuse connections to the target of this rule, if we do.
+    pub(crate) fn keepalive(&self) -> Option<&KeepaliveConfig> {
+        self.keepalive.as_ref()
+    }
+}
+
+/// How we buffer the data that we copy between an onion service stream
+/// and its local target.
+///
+/// By default, we read up to 1024 bytes at a time, and flush the data we have
+/// written as soon as there is no more data to read.
+/// This gives the lowest latency, but it can result in many partially filled cells.
+/// High-throughput targets (such as file servers) may prefer to hold on to data
+/// a little longer, so that it can be sent in fewer, fuller cells.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct BufferConfig {
+    /// The size of the buffer we use for each read, in bytes.
+    ///
+    /// Must be nonzero, and no larger than 1 MiB.
+    pub buffer_size: usize,
+    /// The largest number of bytes that we will write without flushing.
+    ///
+    /// If unset, we only flush when there is no more data to read.
+    pub max_unflushed: Option<usize>,
+    /// Once there is no more data to read, how long we wait for more data
+    /// before flushing the data we have already written.
+    ///
+    /// If zero, we flush immediately.
+    #[serde(with = "humantime_serde")]
+    pub flush_interval: Duration,
+    /// If set, once there is no more data to read, we only wait for `flush_interval`
+    /// if fewer than this many bytes are waiting to be flushed.
+    ///
+    /// This coalesces the tiny writes of chatty targets into fuller cells,
+    /// without delaying larger amounts of data.
+    /// If unset, we always wait for `flush_interval`.
+    pub coalesce_below: Option<usize>,
+    /// Whether to write the data we send to the client in chunks that each fill a relay cell.
+    ///
+    /// If true, we only flush the data we send to the client after a whole number of cells,
+    /// when `max_unflushed` bytes are waiting, so that none of those cells is partially empty.
+    /// (We still flush whatever is waiting once there is no more data to read.)
+    pub cell_aligned: bool,
+}
+
+/// Default value for [`BufferConfig::buffer_size`].
+const DEFAULT_BUFFER_SIZE: usize = 1024;
+
+/// Largest permitted value for [`BufferConfig::buffer_size`].
+///
+/// We allocate a buffer of this size for each direction of every stream,
+/// so we don't let a typo exhaust our memory.
+const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_unflushed: None,
+            flush_interval: Duration::ZERO,
+            coalesce_below: None,
+            cell_aligned: false,
+        }
+    }
+}
+
+/// How we reuse the connections that we open to a local target.
+///
+/// Opening a new connection for every stream adds latency,
+/// which is noticeable for targets that see many short-lived streams.
+/// With this option, once a client closes its stream,
+/// we keep the connection to the target open (if the target did too),
+/// and use it for the next stream to the same target from the same client.
+///
+/// This is only useful for targets that treat every connection as a sequence of
+/// independent requests, such as an HTTP server with keep-alive enabled:
+/// data from several of a client's streams may be read by the target on the same connection.
+/// We never give a connection to a different client
+/// (that is, to a stream on a different circuit),
+/// since the target may still be answering the previous client's request;
+/// and we never reuse a connection on which the target has sent data
+/// that we didn't forward.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct KeepaliveConfig {
+    /// The largest number of idle connections that we keep open to each target,
+    /// for each client.
+    ///
+    /// Must not be zero.
+    pub pool_size: usize,
+    /// How long we keep an idle connection open before closing it.
+    ///
+    /// Must not be zero.
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: 4,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A limit on how much data we forward for an onion service during each accounting period.
+///
+/// Once the limit is reached, we take `action` on every new stream
+/// that we would otherwise have forwarded, until the next period begins.
+/// Streams that are already open are not affected,
+/// so the limit can be exceeded by the data on those streams.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct BandwidthQuota {
+    /// The largest number of bytes that we forward in each period,
+    /// counting the data sent in both directions.
+    pub max_bytes: u64,
+    /// How long each accounting period lasts.
+    #[serde(default)]
+    pub period: QuotaPeriod,
+    /// What to do with new streams once the quota is used up.
+    ///
+    /// This cannot be a forward action. By default, we reject the stream.
+    #[serde(default = "default_over_quota_action")]
+    pub action: ProxyAction,
+}
+
+/// Return the default value for [`BandwidthQuota::action`].
+fn default_over_quota_action() -> ProxyAction {
+    ProxyAction::RejectStream
+}
+
+impl BandwidthQuota {
+    /// Create a new quota, allowing `max_bytes` in each `period`,
+    /// which rejects new streams once it is used up.
+    pub fn new(max_bytes: u64, period: QuotaPeriod) -> Self {
+        Self {
+            max_bytes,
+            period,
+            action: default_over_quota_action(),
+        }
+    }
+}
+
+/// The length of the accounting period for a [`BandwidthQuota`].
+///
+/// Periods begin at midnight UTC.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum QuotaPeriod {
+    /// Each period lasts a day.
+    Daily,
+    /// Each period lasts a calendar month, starting on its first day.
+    #[default]
+    Monthly,
+}
+
+/// Information about a forwarded connection that we send to the local target
+/// before any of the client's data.
+///
+/// This lets the target (for example, an HTTP server) log
+/// which port of the onion service a connection was made to,
+/// and which rendezvous circuit it arrived on,
+/// so that it can tell apart the connections from different circuits.
+/// It never contains anything about the client itself:
+/// onion services do not learn their clients' addresses.
+///
+/// The circuit identifiers are only meaningful within a single run of Arti.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Preamble {
+    /// Send nothing: forward the client's data verbatim.
+    #[default]
+    None,
+    /// Send a block of header lines, terminated by an empty line:
+    ///
+    /// ```text
+    /// X-Onion-Port: 80
+    /// X-Onion-Circuit: Circ 4.2
+    ///
+    /// ```
+    ///
+    /// Every line ends with CRLF.
+    Headers,
+    /// Send a [PROXY protocol] version 2 header.
+    ///
+    /// The header has no addresses (its address family is `AF_UNSPEC`).
+    /// The port is sent as a two-byte, big-endian value
+    /// in a TLV of type [`PP2_TYPE_ONION_PORT`](Preamble::PP2_TYPE_ONION_PORT),
+    /// and the circuit as a UTF-8 string
+    /// in a TLV of type [`PP2_TYPE_ONION_CIRCUIT`](Preamble::PP2_TYPE_ONION_CIRCUIT).
+    ///
+    /// [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+    ProxyV2,
+}
+
+impl Preamble {
+    /// The PROXY v2 TLV type we use for the port of the onion service.
+    ///
+    /// (This is in the range reserved for custom applications.)
+    pub const PP2_TYPE_ONION_PORT: u8 = 0xE0;
+
+    /// The PROXY v2 TLV type we use for the circuit identifier.
+    ///
+    /// (This is in the range reserved for custom applications.)
+    pub const PP2_TYPE_ONION_CIRCUIT: u8 = 0xE1;
+
+    /// Encode this preamble for a connection to `port`, arriving on `circuit`.
+    ///
+    /// Returns an empty vector if there is nothing to send.
+    pub(crate) fn encode(&self, port: u16, circuit: impl std::fmt::Display) -> Vec<u8> {
+        match self {
+            Preamble::None => vec![],
+            Preamble::Headers => {
+                format!("X-Onion-Port: {port}\r\nX-Onion-Circuit: {circuit}\r\n\r\n").into_bytes()
+            }
+            Preamble::ProxyV2 => {
+                /// The signature that starts every PROXY v2 header.
+                const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+                /// Version 2, command PROXY.
+                const VERSION_COMMAND: u8 = 0x21;
+                /// Address family AF_UNSPEC, transport protocol UNSPEC.
+                const FAMILY_PROTOCOL: u8 = 0x00;
+
+                let circuit = circuit.to_string();
+                let mut tlvs = vec![];
+                for (kind, value) in [
+                    (Self::PP2_TYPE_ONION_PORT, &port.to_be_bytes()[..]),
+                    (Self::PP2_TYPE_ONION_CIRCUIT, circuit.as_bytes()),
+                ] {
+                    let len = u16::try_from(value.len()).expect("Enormous circuit identifier");
+                    tlvs.push(kind);
+                    tlvs.extend_from_slice(&len.to_be_bytes());
+                    tlvs.extend_from_slice(value);
+                }
+
+                let len = u16::try_from(tlvs.len()).expect("Enormous PROXY header");
+                let mut header = SIGNATURE.to_vec();
+                header.extend_from_slice(&[VERSION_COMMAND, FAMILY_PROTOCOL]);
+                header.extend_from_slice(&len.to_be_bytes());
+                header.extend_from_slice(&tlvs);
+                header
+            }
+        }
+    }
+}
+
+/// A static HTTP response that we send to clients instead of rejecting their streams.
+///
+/// This lets an onion service show something friendlier than a failed connection
+/// (such as a maintenance page) while its local target is unavailable.
+/// We accept the stream, send the response, and close the stream again,
+/// without looking at anything that the client sends.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct StaticResponse {
+    /// The HTTP status code of the response.
+    ///
+    /// Defaults to 503 (Service Unavailable); must be between 100 and 599.
+    #[serde(default = "default_response_status")]
+    pub status: u16,
+    /// The value of the `Content-Type` header of the response.
+    ///
+    /// Defaults to plain UTF-8 text.
+    #[serde(default = "default_response_content_type")]
+    pub content_type: String,
+    /// The body of the response.
+    #[serde(default)]
+    pub body: String,
+}
+
+/// Return the default value for [`StaticResponse::status`].
+fn default_response_status() -> u16 {
+    503
+}
+
+/// Return the default value for [`StaticResponse::content_type`].
+fn default_response_content_type() -> String {
+    "text/plain; charset=utf-8".into()
+}
+
+impl StaticResponse {
+    /// Create a new plain-text response with a given `status` and `body`.
+    pub fn new(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: default_response_content_type(),
+            body: body.into(),
+        }
+    }
+
+    /// Check whether this response can be sent.
+    ///
+    /// On failure, return a description of the problem.
+    fn check(&self) -> Result<(), String> {
+        if !(100..=599).contains(&self.status) {
+            return Err(format!("Invalid HTTP status {}", self.status));
+        }
+        if self.content_type.chars().any(|c| c.is_control()) {
+            return Err("Control character in content_type".into());
+        }
+        Ok(())
+    }
+
+    /// Encode this response as an HTTP/1.1 message.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            410 => "Gone",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            // The reason phrase is optional.
+            _ => "",
+        };
+        let mut msg = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        msg.extend_from_slice(self.body.as_bytes());
+        msg
     }
 }
 
@@ -339,8 +984,8 @@ impl std::fmt::Display for TargetAddr {
 
 /// The method by which we encapsulate a forwarded request.
 ///
-/// (Right now, only `Simple` is supported, but we may later support
-/// "HTTP CONNECT", "HAProxy", or others.)
+/// (Right now, only `Simple` is supported, along with `Datagram` if the `udp` feature
+/// is enabled, but we may later support "HTTP CONNECT", "HAProxy", or others.)
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 #[non_exhaustive]
 pub enum Encapsulation {
@@ -351,6 +996,18 @@ pub enum Encapsulation {
     /// only the local port will distinguish one request from another.
     #[default]
     Simple,
+    /// Handle a request by forwarding the datagrams that the client sends on its stream
+    /// to a UDP socket at the target address, and sending the replies back on the stream.
+    ///
+    /// Since Tor does not yet support UDP, the datagrams are carried on an ordinary stream,
+    /// each one preceded by its length, as a two-byte big-endian integer.
+    /// We only forward replies that come from the target address.
+    ///
+    /// Written as `udp:ADDR` in the configuration.
+    /// Once Tor can carry datagrams natively, rules like this will be able to use that,
+    /// without any change to their configuration.
+    #[cfg(feature = "udp")]
+    Datagram,
 }
 
 impl FromStr for ProxyAction {
@@ -365,17 +1022,35 @@ impl FromStr for ProxyAction {
             Ok(Self::IgnoreStream)
         } else if let Some(addr) = s.strip_prefix("simple:") {
             Ok(Self::Forward(Encapsulation::Simple, addr.parse()?))
+        } else if let Some(addr) = s.strip_prefix("udp:") {
+            Self::datagram_from_str(addr)
         } else {
             Ok(Self::Forward(Encapsulation::Simple, s.parse()?))
         }
     }
 }
 
+impl ProxyAction {
+    /// Parse `addr`, the target of a `udp:` action.
+    #[cfg(feature = "udp")]
+    fn datagram_from_str(addr: &str) -> Result<Self, ProxyConfigError> {
+        Ok(Self::Forward(Encapsulation::Datagram, addr.parse()?))
+    }
+
+    /// Reject a `udp:` action, since this build can't forward datagrams.
+    #[cfg(not(feature = "udp"))]
+    fn datagram_from_str(_addr: &str) -> Result<Self, ProxyConfigError> {
+        Err(ProxyConfigError::UnsupportedEncapsulation("udp".into()))
+    }
+}
+
 impl std::fmt::Display for ProxyAction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ProxyAction::DestroyCircuit => write!(f, "destroy"),
             ProxyAction::Forward(Encapsulation::Simple, addr) => write!(f, "simple:{}", addr),
+            #[cfg(feature = "udp")]
+            ProxyAction::Forward(Encapsulation::Datagram, addr) => write!(f, "udp:{}", addr),
             ProxyAction::RejectStream => write!(f, "reject"),
             ProxyAction::IgnoreStream => write!(f, "ignore"),
         }
@@ -390,6 +1065,10 @@ pub enum ProxyConfigError {
     #[error("Could not parse onion service target type {0:?}")]
     UnrecognizedTargetType(String),
 
+    /// We encountered a proxy target whose encapsulation is not supported by this build.
+    #[error("Onion service target type {0:?} is not supported in this build of Arti")]
+    UnsupportedEncapsulation(String),
+
     /// A socket address could not be parsed to be invalid.
     #[error("Could not parse onion service target address {0:?}")]
     InvalidTargetAddr(String, #[source] std::net::AddrParseError),
@@ -548,6 +1227,40 @@ mod test {
         ));
     }
 
+    #[test]
+    fn target_udp() {
+        use ProxyAction as T;
+
+        #[cfg(feature = "udp")]
+        {
+            use Encapsulation::Datagram;
+            use TargetAddr as A;
+
+            let sa: SocketAddr = "127.0.0.1:53".parse().unwrap();
+            let action = T::from_str("udp:127.0.0.1:53").unwrap();
+            assert!(matches!(&action, T::Forward(Datagram, A::Inet(a)) if *a == sa));
+            assert_eq!(action.to_string(), "udp:inet:127.0.0.1:53");
+            assert_eq!(T::from_str(&action.to_string()).unwrap(), action);
+
+            // Datagrams have no room for a preamble.
+            let mut b = ProxyConfigBuilder::default();
+            b.proxy_ports().push(
+                ProxyRule::new(
+                    ProxyPattern::one_port(53).unwrap(),
+                    T::from_str("udp:127.0.0.1:53").unwrap(),
+                )
+                .with_preamble(Preamble::Headers),
+            );
+            assert!(b.build().is_err());
+        }
+
+        #[cfg(not(feature = "udp"))]
+        assert!(matches!(
+            T::from_str("udp:127.0.0.1:53"),
+            Err(ProxyConfigError::UnsupportedEncapsulation(_))
+        ));
+    }
+
     #[test]
     fn deserialize() {
         use Encapsulation::Simple;
@@ -574,6 +1287,268 @@ mod test {
         assert_eq!(cfg.proxy_ports[2].target, ProxyAction::DestroyCircuit);
     }
 
+    #[test]
+    fn deserialize_buffering() {
+        let ex = r#"
+proxy_ports = [
+    [ 80, "127.0.0.1:10080" ],
+    { source = "443", target = "127.0.0.1:10443", buffer_size = 8192, max_unflushed = 65536, flush_interval = "50ms", coalesce_below = 256, cell_aligned = true },
+    { source = "8080", target = "127.0.0.1:18080" },
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.proxy_ports.len(), 3);
+        assert_eq!(cfg.proxy_ports[0].buffering, BufferConfig::default());
+        assert_eq!(
+            cfg.proxy_ports[1].buffering,
+            BufferConfig {
+                buffer_size: 8192,
+                max_unflushed: Some(65536),
+                flush_interval: Duration::from_millis(50),
+                coalesce_below: Some(256),
+                cell_aligned: true,
+            }
+        );
+        assert_eq!(cfg.proxy_ports[2].buffering, BufferConfig::default());
+        assert_eq!(cfg.proxy_ports[2].source.0, 8080..=8080);
+
+        // Rules with non-default options round-trip as tables; the rest as pairs.
+        let json = serde_json::to_value(&cfg.proxy_ports).unwrap();
+        assert!(json[0].is_array());
+        assert!(json[1].is_object());
+        assert!(json[2].is_array());
+        let rules: Vec<ProxyRule> = serde_json::from_value(json).unwrap();
+        assert_eq!(rules, cfg.proxy_ports);
+
+        let ex = r#"
+proxy_ports = [
+    { source = "443", target = "127.0.0.1:10443", buffer_size = 0 },
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        assert!(matches!(bld.build(), Err(ConfigBuildError::Invalid { .. })));
+
+        let ex = r#"
+proxy_ports = [
+    { source = "443", target = "127.0.0.1:10443", buffer_size = 1048577 },
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        assert!(matches!(bld.build(), Err(ConfigBuildError::Invalid { .. })));
+
+        let ex = r#"
+proxy_ports = [
+    { source = "443", target = "127.0.0.1:10443", buffer_size = 1048576 },
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.proxy_ports[0].buffering.buffer_size, MAX_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn deserialize_preamble() {
+        let ex = r#"
+proxy_ports = [
+    { source = "80", target = "127.0.0.1:10080", preamble = "headers" },
+    { source = "443", target = "127.0.0.1:10443", preamble = "proxy_v2", buffer_size = 8192 },
+    [ 8080, "127.0.0.1:18080" ],
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.proxy_ports[0].preamble, Preamble::Headers);
+        assert_eq!(cfg.proxy_ports[0].buffering, BufferConfig::default());
+        assert_eq!(cfg.proxy_ports[1].preamble, Preamble::ProxyV2);
+        assert_eq!(cfg.proxy_ports[1].buffering.buffer_size, 8192);
+        assert_eq!(cfg.proxy_ports[2].preamble, Preamble::None);
+
+        let json = serde_json::to_value(&cfg.proxy_ports).unwrap();
+        assert!(json[0].is_object());
+        assert!(json[2].is_array());
+        let rules: Vec<ProxyRule> = serde_json::from_value(json).unwrap();
+        assert_eq!(rules, cfg.proxy_ports);
+    }
+
+    #[test]
+    fn encode_preamble() {
+        assert!(Preamble::None.encode(80, "Circ 4.2").is_empty());
+        assert_eq!(
+            Preamble::Headers.encode(80, "Circ 4.2"),
+            b"X-Onion-Port: 80\r\nX-Onion-Circuit: Circ 4.2\r\n\r\n"
+        );
+
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x10".to_vec();
+        expected.extend_from_slice(b"\xE0\x00\x02\x01\xBB");
+        expected.extend_from_slice(b"\xE1\x00\x08Circ 4.2");
+        assert_eq!(Preamble::ProxyV2.encode(443, "Circ 4.2"), expected);
+    }
+
+    #[test]
+    fn deserialize_response() {
+        let ex = r#"
+proxy_ports = [
+    { source = "80", target = "reject", response = { body = "Back soon!" } },
+    { source = "8080", target = "reject", response = { status = 404, content_type = "text/html", body = "<p>No</p>" } },
+    [ 443, "reject" ],
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(
+            cfg.proxy_ports[0].response,
+            Some(StaticResponse::new(503, "Back soon!"))
+        );
+        assert_eq!(
+            cfg.proxy_ports[1].response(),
+            Some(&StaticResponse {
+                status: 404,
+                content_type: "text/html".into(),
+                body: "<p>No</p>".into(),
+            })
+        );
+        assert_eq!(cfg.proxy_ports[2].response, None);
+
+        let json = serde_json::to_value(&cfg.proxy_ports).unwrap();
+        assert!(json[0].is_object());
+        assert!(json[2].is_array());
+        let rules: Vec<ProxyRule> = serde_json::from_value(json).unwrap();
+        assert_eq!(rules, cfg.proxy_ports);
+
+        for bad in [
+            // Only rules that reject streams can have a response.
+            r#"{ source = "80", target = "127.0.0.1:10080", response = { body = "Hi" } }"#,
+            r#"{ source = "80", target = "reject", response = { status = 99 } }"#,
+            r#"{ source = "80", target = "reject", response = { content_type = "text/plain\r\nX: y" } }"#,
+        ] {
+            let ex = format!("proxy_ports = [ {bad} ]");
+            let bld: ProxyConfigBuilder = toml::de::from_str(&ex).unwrap();
+            assert!(matches!(bld.build(), Err(ConfigBuildError::Invalid { .. })));
+        }
+    }
+
+    #[test]
+    fn deserialize_connect_timeout() {
+        let ex = r#"
+proxy_ports = [
+    { source = "80", target = "127.0.0.1:10080", connect_timeout = "5s" },
+    [ 443, "127.0.0.1:10443" ],
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(
+            cfg.proxy_ports[0].connect_timeout(),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(cfg.proxy_ports[1].connect_timeout(), None);
+
+        let json = serde_json::to_value(&cfg.proxy_ports).unwrap();
+        assert!(json[0].is_object());
+        assert!(json[1].is_array());
+        let rules: Vec<ProxyRule> = serde_json::from_value(json).unwrap();
+        assert_eq!(rules, cfg.proxy_ports);
+
+        for bad in [
+            // Only rules that forward streams can have a connect timeout.
+            r#"{ source = "80", target = "reject", connect_timeout = "5s" }"#,
+            r#"{ source = "80", target = "127.0.0.1:10080", connect_timeout = "0s" }"#,
+        ] {
+            let ex = format!("proxy_ports = [ {bad} ]");
+            let bld: ProxyConfigBuilder = toml::de::from_str(&ex).unwrap();
+            assert!(matches!(bld.build(), Err(ConfigBuildError::Invalid { .. })));
+        }
+    }
+
+    #[test]
+    fn deserialize_keepalive() {
+        let ex = r#"
+proxy_ports = [
+    { source = "80", target = "127.0.0.1:10080", keepalive = { pool_size = 8, idle_timeout = "1 min" } },
+    { source = "81", target = "127.0.0.1:10081", keepalive = {} },
+    [ 443, "127.0.0.1:10443" ],
+]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(
+            cfg.proxy_ports[0].keepalive(),
+            Some(&KeepaliveConfig {
+                pool_size: 8,
+                idle_timeout: Duration::from_secs(60),
+            })
+        );
+        assert_eq!(
+            cfg.proxy_ports[1].keepalive(),
+            Some(&KeepaliveConfig::default())
+        );
+        assert_eq!(cfg.proxy_ports[2].keepalive(), None);
+
+        let json = serde_json::to_value(&cfg.proxy_ports).unwrap();
+        assert!(json[0].is_object());
+        assert!(json[2].is_array());
+        let rules: Vec<ProxyRule> = serde_json::from_value(json).unwrap();
+        assert_eq!(rules, cfg.proxy_ports);
+
+        for bad in [
+            // Only rules that forward streams can reuse connections.
+            r#"{ source = "80", target = "reject", keepalive = {} }"#,
+            // A preamble can't be shared between streams.
+            r#"{ source = "80", target = "127.0.0.1:10080", preamble = "headers", keepalive = {} }"#,
+            r#"{ source = "80", target = "127.0.0.1:10080", keepalive = { pool_size = 0 } }"#,
+            r#"{ source = "80", target = "127.0.0.1:10080", keepalive = { idle_timeout = "0s" } }"#,
+        ] {
+            let ex = format!("proxy_ports = [ {bad} ]");
+            let bld: ProxyConfigBuilder = toml::de::from_str(&ex).unwrap();
+            assert!(matches!(bld.build(), Err(ConfigBuildError::Invalid { .. })));
+        }
+    }
+
+    #[test]
+    fn encode_response() {
+        assert_eq!(
+            StaticResponse::new(503, "Back soon!").encode(),
+            b"HTTP/1.1 503 Service Unavailable\r\n\
+              Content-Type: text/plain; charset=utf-8\r\n\
+              Content-Length: 10\r\n\
+              Connection: close\r\n\r\n\
+              Back soon!"
+        );
+        assert_eq!(
+            StaticResponse::new(299, "").encode(),
+            b"HTTP/1.1 299 \r\nContent-Type: text/plain; charset=utf-8\r\n\
+              Content-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn deserialize_stream_limit() {
+        let ex = r#"
+proxy_ports = [ [ 80, "127.0.0.1:10080" ] ]
+max_forwarded_streams_per_circuit = 16
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(
+            cfg.max_forwarded_streams_per_circuit,
+            Some(NonZeroUsize::new(16).unwrap())
+        );
+
+        let ex = r#"
+proxy_ports = [ [ 80, "127.0.0.1:10080" ] ]
+"#;
+        let bld: ProxyConfigBuilder = toml::de::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(cfg.max_forwarded_streams_per_circuit, None);
+
+        let ex = r#"
+proxy_ports = [ [ 80, "127.0.0.1:10080" ] ]
+max_forwarded_streams_per_circuit = 0
+"#;
+        assert!(toml::de::from_str::<ProxyConfigBuilder>(ex).is_err());
+    }
+
     #[test]
     fn validation_fail() {
         // this should fail; the third pattern isn't reachable.
@@ -605,6 +1580,74 @@ mod test {
         assert!(bld.build().is_ok());
     }
 
+    #[test]
+    fn bandwidth_quota() {
+        let ex = r#"{
+            "proxy_ports": [ [ "80", "127.0.0.1:10080" ] ],
+            "bandwidth_quota": { "max_bytes": 1000000 }
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        let cfg = bld.build().unwrap();
+        assert_eq!(
+            cfg.bandwidth_quota,
+            Some(BandwidthQuota::new(1_000_000, QuotaPeriod::Monthly))
+        );
+
+        let ex = r#"{
+            "proxy_ports": [ [ "80", "127.0.0.1:10080" ] ],
+            "bandwidth_quota": { "max_bytes": 1000, "period": "daily", "action": "destroy" }
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        let quota = bld.build().unwrap().bandwidth_quota.unwrap();
+        assert_eq!(quota.period, QuotaPeriod::Daily);
+        assert_eq!(quota.action, ProxyAction::DestroyCircuit);
+
+        // Forwarding can't be the over-quota action.
+        let ex = r#"{
+            "proxy_ports": [ [ "80", "127.0.0.1:10080" ] ],
+            "bandwidth_quota": { "max_bytes": 1000, "action": "127.0.0.1:10081" }
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        match bld.build() {
+            Err(ConfigBuildError::Invalid { field, .. }) => {
+                assert_eq!(field, "bandwidth_quota");
+            }
+            other => panic!("Expected an Invalid error; got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn resolve_requests() {
+        let ex = r#"{ "proxy_ports": [ [ "80", "127.0.0.1:10080" ] ] }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        assert_eq!(
+            bld.build().unwrap().resolve_requests,
+            ProxyAction::RejectStream
+        );
+
+        let ex = r#"{
+            "proxy_ports": [ [ "80", "127.0.0.1:10080" ] ],
+            "resolve_requests": "ignore"
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        assert_eq!(
+            bld.build().unwrap().resolve_requests,
+            ProxyAction::IgnoreStream
+        );
+
+        let ex = r#"{
+            "proxy_ports": [ [ "80", "127.0.0.1:10080" ] ],
+            "resolve_requests": "127.0.0.1:53"
+        }"#;
+        let bld: ProxyConfigBuilder = serde_json::from_str(ex).unwrap();
+        match bld.build() {
+            Err(ConfigBuildError::Invalid { field, .. }) => {
+                assert_eq!(field, "resolve_requests");
+            }
+            other => panic!("Expected an Invalid error; got {other:?}"),
+        }
+    }
+
     #[test]
     fn demo() {
         let b: ProxyConfigBuilder = toml::de::from_str(
@@ -0,0 +1,71 @@
+//! Programmatic handling of incoming stream requests.
+//!
+//! Most reverse proxies are driven entirely by their [`ProxyConfig`](crate::ProxyConfig).
+//! Programs that embed Arti may instead want to decide what to do with each request
+//! at runtime: for example, to route requests from different circuits to different backends.
+//! They can do this by giving the proxy a [`StreamRequestHandler`],
+//! using [`OnionServiceReverseProxy::with_handler`](crate::OnionServiceReverseProxy::with_handler).
+
+use tor_hsservice::StreamRequest;
+use tor_proto::circuit::UniqId;
+
+use crate::config::ProxyAction;
+
+/// Information about an incoming BEGIN request, as given to a [`StreamRequestHandler`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct BeginInfo {
+    /// The port that the client asked to connect to.
+    pub port: u16,
+    /// The address that the client asked to connect to.
+    ///
+    /// Clients usually send the onion service's own address, or nothing at all,
+    /// so this is rarely useful for routing.
+    /// It is decoded lossily if it is not valid UTF-8.
+    pub addr: String,
+    /// The rendezvous circuit that the request arrived on.
+    ///
+    /// Circuit identifiers are only meaningful within a single run of Arti.
+    pub circuit: UniqId,
+}
+
+/// What to do with an incoming stream request, as decided by a [`StreamRequestHandler`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StreamDecision {
+    /// Handle the request according to the proxy's configuration,
+    /// as if there were no handler.
+    UseConfig(StreamRequest),
+    /// Take `action` on the request, as if a configured rule had matched it.
+    ///
+    /// Forwarded connections use the default [`BufferConfig`](crate::config::BufferConfig),
+    /// and are not sent a [`Preamble`](crate::config::Preamble).
+    /// They still count towards the limit on forwarded streams per circuit.
+    Act(StreamRequest, ProxyAction),
+    /// The handler has taken responsibility for the request:
+    /// the proxy will do nothing further with it.
+    ///
+    /// A handler that returns this should accept, reject, or drop the request itself.
+    Handled,
+}
+
+/// A way for an embedding program to decide what to do with each incoming stream request.
+///
+/// This is implemented for every suitable closure.
+pub trait StreamRequestHandler: Send + Sync {
+    /// Decide what to do with the BEGIN `request`, which is described by `info`.
+    ///
+    /// This is called once for each request, before the proxy looks at its configuration.
+    /// It should not block: a handler that needs to do slow work with a request
+    /// should spawn a task for it, and return [`StreamDecision::Handled`].
+    fn handle_request(&self, request: StreamRequest, info: &BeginInfo) -> StreamDecision;
+}
+
+impl<F> StreamRequestHandler for F
+where
+    F: Fn(StreamRequest, &BeginInfo) -> StreamDecision + Send + Sync,
+{
+    fn handle_request(&self, request: StreamRequest, info: &BeginInfo) -> StreamDecision {
+        self(request, info)
+    }
+}
@@ -47,8 +47,15 @@
 // TODO #1645 (either remove this, or decide to have it everywhere)
 #![cfg_attr(not(all(feature = "full", feature = "experimental")), allow(unused))]
 
+mod active;
 pub mod config;
+mod handler;
+mod pool;
 mod proxy;
+mod quota;
 
+pub use active::{ActiveConnection, ConnectionId};
 pub use config::ProxyConfig;
+pub use handler::{BeginInfo, StreamDecision, StreamRequestHandler};
 pub use proxy::OnionServiceReverseProxy;
+pub use quota::BandwidthUsage;
@@ -0,0 +1,287 @@
+//! Keeping connections to local targets open, so that later streams can reuse them.
+//!
+//! When a rule has a [`KeepaliveConfig`], we don't close our connection to its target
+//! once the client closes its stream.
+//! Instead, we put the connection in a [`ConnectionPool`],
+//! and take it out again for the next stream to the same target
+//! from the same client.
+//!
+//! We never give a connection to a different client than the one that used it last:
+//! we can't tell where one response from the target ends,
+//! so a response that the target sends late
+//! (after we have stopped forwarding data from the connection)
+//! would otherwise reach whoever reuses the connection next.
+//! Each circuit to the service counts as a separate client.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::io::Result as IoResult;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::{AsyncRead, AsyncReadExt as _, AsyncWrite, FutureExt as _};
+
+use crate::config::KeepaliveConfig;
+
+/// A set of idle connections to local targets, by the key `K` of the streams that may reuse them.
+///
+/// `K` must tell apart both the targets and the clients:
+/// we only ever hand a connection to a stream with the same key as the one that used it last.
+#[derive(Debug)]
+pub(crate) struct ConnectionPool<K, S> {
+    /// The idle connections for each key, oldest first.
+    idle: Mutex<HashMap<K, VecDeque<IdleConnection<S>>>>,
+}
+
+/// A connection in a [`ConnectionPool`].
+#[derive(Debug)]
+struct IdleConnection<S> {
+    /// The connection itself.
+    stream: S,
+    /// When we are going to close it, unless someone reuses it first.
+    expires: Instant,
+}
+
+impl<K, S> Default for ConnectionPool<K, S> {
+    fn default() -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash, S: AsyncRead + Unpin> ConnectionPool<K, S> {
+    /// Take the most recently used idle connection for `key`, if there is one.
+    ///
+    /// Connections that have expired by `now`,
+    /// or that the target has closed or sent data on while they were idle,
+    /// are closed instead of being returned.
+    pub(crate) fn take(&self, key: &K, now: Instant) -> Option<S> {
+        let mut idle = self.idle.lock().expect("poisoned lock");
+        let conns = idle.get_mut(key)?;
+        let mut found = None;
+        while let Some(mut conn) = conns.pop_back() {
+            if conn.expires > now && is_quiet(&mut conn.stream) {
+                found = Some(conn.stream);
+                break;
+            }
+        }
+        if conns.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    /// Put `stream`, an idle connection for `key`, in this pool, as configured by `keepalive`.
+    ///
+    /// If there are already `keepalive.pool_size` idle connections for `key`,
+    /// we close the oldest one.
+    pub(crate) fn put(&self, key: K, mut stream: S, keepalive: &KeepaliveConfig, now: Instant) {
+        if !is_quiet(&mut stream) {
+            // Either the target closed the connection,
+            // or it sent something that nobody is going to read.
+            return;
+        }
+        let mut idle = self.idle.lock().expect("poisoned lock");
+        let conns = idle.entry(key).or_default();
+        conns.push_back(IdleConnection {
+            stream,
+            expires: now + keepalive.idle_timeout,
+        });
+        while conns.len() > keepalive.pool_size {
+            conns.pop_front();
+        }
+    }
+
+    /// Close every idle connection that has expired by `now`.
+    pub(crate) fn expire(&self, now: Instant) {
+        let mut idle = self.idle.lock().expect("poisoned lock");
+        idle.retain(|_, conns| {
+            conns.retain(|conn| conn.expires > now);
+            !conns.is_empty()
+        });
+    }
+
+    /// Return the number of idle connections for `key`.
+    #[cfg(test)]
+    fn n_idle(&self, key: &K) -> usize {
+        let idle = self.idle.lock().expect("poisoned lock");
+        idle.get(key).map_or(0, VecDeque::len)
+    }
+}
+
+/// A writer that is only flushed, not closed, when we close it.
+///
+/// We write to a connection that we may reuse through this,
+/// so that the client closing its stream doesn't close the connection.
+pub(crate) struct KeepOpen<W>(pub(crate) W);
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for KeepOpen<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+}
+
+/// A reader that counts the bytes read from it.
+///
+/// We read from a connection that we may reuse through this,
+/// so that we can tell whether we forwarded everything we read from it.
+pub(crate) struct CountingReader<'c, R> {
+    /// The underlying reader.
+    inner: R,
+    /// The number of bytes read so far.
+    count: &'c AtomicU64,
+}
+
+impl<'c, R> CountingReader<'c, R> {
+    /// Wrap `inner`, adding the number of bytes we read from it to `count`.
+    pub(crate) fn new(inner: R, count: &'c AtomicU64) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            self.count
+                .fetch_add(u64::try_from(*n).unwrap_or(u64::MAX), Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Return true if there is nothing to read from `stream` right now.
+///
+/// A connection on which the target has sent data, or closed its side,
+/// can't be given to a new client.
+fn is_quiet<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut byte = [0_u8; 1];
+    stream.read(&mut byte).now_or_never().is_none()
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use futures::TryStreamExt as _;
+    use futures::channel::mpsc;
+    use futures::io::Cursor;
+    use std::time::Duration;
+
+    /// A connection that never has anything to read.
+    #[derive(Debug, Eq, PartialEq)]
+    struct Quiet(u8);
+
+    impl AsyncRead for Quiet {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn take_and_put() {
+        let pool = ConnectionPool::default();
+        let key = ("alice", "127.0.0.1:8080");
+        let other = ("alice", "127.0.0.1:8081");
+        let keepalive = KeepaliveConfig {
+            pool_size: 2,
+            idle_timeout: Duration::from_secs(30),
+        };
+        let now = Instant::now();
+
+        assert_eq!(pool.take(&key, now), None);
+        for n in 0..3 {
+            pool.put(key, Quiet(n), &keepalive, now);
+        }
+        // The oldest connection was closed to make room for the others.
+        assert_eq!(pool.n_idle(&key), 2);
+        assert_eq!(pool.take(&other, now), None);
+        assert_eq!(pool.take(&key, now), Some(Quiet(2)));
+
+        // Idle connections expire.
+        let later = now + Duration::from_secs(31);
+        pool.put(key, Quiet(3), &keepalive, later);
+        assert_eq!(pool.take(&key, later), Some(Quiet(3)));
+        assert_eq!(pool.take(&key, later), None);
+
+        pool.put(key, Quiet(4), &keepalive, now);
+        pool.expire(later);
+        assert_eq!(pool.n_idle(&key), 0);
+    }
+
+    #[test]
+    fn unquiet_connections() {
+        let pool = ConnectionPool::default();
+        let key = ("alice", "127.0.0.1:8080");
+        let keepalive = KeepaliveConfig::default();
+        let now = Instant::now();
+
+        // A connection with unread data, and one that the target has closed.
+        pool.put(key, Cursor::new(b"leftover".to_vec()), &keepalive, now);
+        pool.put(key, Cursor::new(vec![]), &keepalive, now);
+        assert_eq!(pool.n_idle(&key), 0);
+    }
+
+    #[test]
+    fn late_response() {
+        let pool = ConnectionPool::default();
+        let alice = ("alice", "127.0.0.1:8080");
+        let bob = ("bob", "127.0.0.1:8080");
+        let keepalive = KeepaliveConfig::default();
+        let now = Instant::now();
+
+        // Alice closes her stream before the target has answered her request,
+        // so the connection looks idle when we put it in the pool.
+        let (target, conn) = mpsc::unbounded::<IoResult<Vec<u8>>>();
+        pool.put(alice, conn.into_async_read(), &keepalive, now);
+        assert_eq!(pool.n_idle(&alice), 1);
+
+        // Bob never gets Alice's connection, even though it looks idle:
+        // the target could still answer her request on it.
+        assert!(pool.take(&bob, now).is_none());
+
+        // The answer arrives. Now nobody gets the connection.
+        target
+            .unbounded_send(Ok(b"HTTP/1.1 200 OK\r\n".to_vec()))
+            .unwrap();
+        assert!(pool.take(&bob, now).is_none());
+        assert!(pool.take(&alice, now).is_none());
+        assert_eq!(pool.n_idle(&alice), 0);
+    }
+}
@@ -1,41 +1,102 @@
 //! A simple reverse-proxy implementation for onion services.
 
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+use futures::future::{Either, select};
 use futures::{
     AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future, FutureExt as _, Stream,
-    StreamExt as _, select_biased, task::SpawnExt as _,
+    StreamExt as _,
+    io::{ReadHalf, WriteHalf},
+    select_biased,
+    task::SpawnExt as _,
 };
 use itertools::iproduct;
 use oneshot_fused_workaround as oneshot;
 use safelog::sensitive as sv;
 use std::collections::HashMap;
 use std::io::{Error as IoError, Result as IoResult};
+use std::num::NonZeroUsize;
 use strum::IntoEnumIterator;
 use tor_cell::relaycell::msg as relaymsg;
 use tor_error::{ErrorKind, HasKind, debug_report};
 use tor_hsservice::{HsNickname, RendRequest, StreamRequest};
 use tor_log_ratelim::log_ratelim;
+use tor_proto::circuit::UniqId;
 use tor_proto::stream::{DataStream, IncomingStreamRequest};
-use tor_rtcompat::Runtime;
+use tor_rtcompat::{NetStreamProvider, Runtime, SleepProviderExt as _, TimeoutError};
 
+use crate::active::{ActiveConnection, ConnectionGuard, ConnectionId, ConnectionTable, Direction};
 use crate::config::{
-    Encapsulation, ProxyAction, ProxyActionDiscriminants, ProxyConfig, TargetAddr,
+    BandwidthQuota, BufferConfig, Encapsulation, KeepaliveConfig, Preamble, ProxyAction,
+    ProxyActionDiscriminants, ProxyConfig, QuotaPeriod, StaticResponse, TargetAddr,
 };
+use crate::handler::{BeginInfo, StreamDecision, StreamRequestHandler};
+use crate::pool::{ConnectionPool, CountingReader, KeepOpen};
+use crate::quota::{BandwidthUsage, SAVE_INTERVAL, UsageTracker};
+
+#[cfg(feature = "udp")]
+mod datagram;
+
+/// The type of the TCP connections that a runtime `R` makes.
+type TcpStreamOf<R> = <R as NetStreamProvider<SocketAddr>>::Stream;
+
+/// The streams that may reuse a connection in our [`ConnectionPool`]:
+/// those from the same circuit (that is, the same client), to the same target.
+type PoolKey = (UniqId, SocketAddr);
+
+/// The number of bytes of stream data that fit in one relay cell.
+///
+/// (Cells in the newer relay cell format hold a little less;
+/// this is only used for sizing writes, and counting cells approximately.)
+const CELL_PAYLOAD: usize = relaymsg::Data::MAXLEN;
 
 /// A reverse proxy that handles connections from an `OnionService` by routing
 /// them to local addresses.
-#[derive(Debug)]
+///
+/// A single proxy can handle requests for several onion services,
+/// each with its own [`ProxyConfig`]:
+/// see [`for_services`](Self::for_services).
 pub struct OnionServiceReverseProxy {
     /// Mutable state held by this reverse proxy.
     state: Mutex<State>,
+    /// A handler that gets to decide what to do with each request
+    /// before we look at our configuration, if we were given one.
+    handler: Option<Arc<dyn StreamRequestHandler>>,
+    /// How much data we have forwarded for the onion services
+    /// that don't have their own configuration.
+    usage: Arc<UsageTracker>,
+    /// How much data we have forwarded for each onion service
+    /// that has its own configuration.
+    service_usage: Mutex<HashMap<HsNickname, Arc<UsageTracker>>>,
+    /// The connections that we are currently forwarding.
+    connections: Arc<ConnectionTable>,
+}
+
+impl std::fmt::Debug for OnionServiceReverseProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OnionServiceReverseProxy")
+            .field("state", &self.state)
+            .field("handler", &self.handler.as_ref().map(|_| "<handler>"))
+            .field("usage", &self.usage.usage())
+            .field("connections", &self.connections)
+            .finish()
+    }
 }
 
 /// Mutable part of an RProxy
 #[derive(Debug)]
 struct State {
-    /// The current configuration for this reverse proxy.
-    config: ProxyConfig,
+    /// The current configuration for onion services
+    /// that don't have their own configuration in `service_configs`.
+    ///
+    /// If this is `None`, we destroy the circuit of every request to such a service.
+    config: Option<ProxyConfig>,
+    /// The current configuration for each onion service that has its own, by nickname.
+    service_configs: HashMap<HsNickname, ProxyConfig>,
     /// A sender that we'll drop when it's time to shut down this proxy.
     shutdown_tx: Option<oneshot::Sender<void::Void>>,
     /// A receiver that we'll use to monitor for shutdown signals.
@@ -62,17 +123,114 @@ impl HasKind for HandleRequestsError {
 impl OnionServiceReverseProxy {
     /// Create a new proxy with a given configuration.
     pub fn new(config: ProxyConfig) -> Arc<Self> {
+        Self::new_inner(Some(config), HashMap::new(), None)
+    }
+
+    /// Create a new proxy that handles requests for several onion services,
+    /// each with its own configuration.
+    ///
+    /// Call [`handle_requests`](Self::handle_requests) once for each onion service,
+    /// with its nickname.
+    /// Requests for an onion service that has no configuration here
+    /// (and hasn't been given one with [`reconfigure_service`](Self::reconfigure_service))
+    /// are answered by destroying their circuit.
+    pub fn for_services(configs: impl IntoIterator<Item = (HsNickname, ProxyConfig)>) -> Arc<Self> {
+        Self::new_inner(None, configs.into_iter().collect(), None)
+    }
+
+    /// Create a new proxy with a given configuration,
+    /// which asks `handler` what to do with each request before consulting the configuration.
+    ///
+    /// See [`StreamRequestHandler`] for details.
+    pub fn with_handler(config: ProxyConfig, handler: Arc<dyn StreamRequestHandler>) -> Arc<Self> {
+        Self::new_inner(Some(config), HashMap::new(), Some(handler))
+    }
+
+    /// Helper: create a new proxy, with an optional handler.
+    fn new_inner(
+        config: Option<ProxyConfig>,
+        service_configs: HashMap<HsNickname, ProxyConfig>,
+        handler: Option<Arc<dyn StreamRequestHandler>>,
+    ) -> Arc<Self> {
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         Arc::new(Self {
             state: Mutex::new(State {
                 config,
+                service_configs,
                 shutdown_tx: Some(shutdown_tx),
                 shutdown_rx: shutdown_rx.shared(),
             }),
+            handler,
+            usage: Default::default(),
+            service_usage: Default::default(),
+            connections: Default::default(),
         })
     }
 
-    /// Try to change the configuration of this proxy.
+    /// Use `storage` to save how much data we have forwarded,
+    /// so that our [`BandwidthQuota`] isn't reset when we restart.
+    ///
+    /// Any usage previously saved in `storage` is loaded, and counts towards the quota.
+    /// This should be called before [`handle_requests`](Self::handle_requests).
+    ///
+    /// This only covers the onion services that don't have their own configuration;
+    /// see [`set_service_usage_storage`](Self::set_service_usage_storage).
+    pub fn set_usage_storage(
+        &self,
+        storage: tor_persist::DynStorageHandle<BandwidthUsage>,
+    ) -> Result<(), tor_persist::Error> {
+        self.usage.set_storage(storage)
+    }
+
+    /// Return how much data we have forwarded in the current accounting period.
+    ///
+    /// Returns `None` if we haven't forwarded anything yet.
+    ///
+    /// The accounting period is that of our [`BandwidthQuota`], if we have one;
+    /// otherwise it is a calendar month.
+    pub fn bandwidth_usage(&self) -> Option<BandwidthUsage> {
+        self.usage.usage()
+    }
+
+    /// Like [`set_usage_storage`](Self::set_usage_storage),
+    /// but for the onion service `nickname`, which has its own configuration.
+    pub fn set_service_usage_storage(
+        &self,
+        nickname: &HsNickname,
+        storage: tor_persist::DynStorageHandle<BandwidthUsage>,
+    ) -> Result<(), tor_persist::Error> {
+        self.service_usage(nickname).set_storage(storage)
+    }
+
+    /// Like [`bandwidth_usage`](Self::bandwidth_usage),
+    /// but for the onion service `nickname`, which has its own configuration.
+    pub fn service_bandwidth_usage(&self, nickname: &HsNickname) -> Option<BandwidthUsage> {
+        self.service_usage
+            .lock()
+            .expect("poisoned lock")
+            .get(nickname)?
+            .usage()
+    }
+
+    /// Return information about every connection that we are currently forwarding,
+    /// for all of our onion services, in the order in which we started handling them.
+    ///
+    /// This includes connections that we are still trying to open to their local target.
+    pub fn active_connections(&self) -> Vec<ActiveConnection> {
+        self.connections.list()
+    }
+
+    /// Close the forwarded connection `id`,
+    /// both to the client and to the local target.
+    ///
+    /// Return false if we aren't forwarding any such connection
+    /// (for example, because it has already closed).
+    pub fn close_connection(&self, id: ConnectionId) -> bool {
+        self.connections.close(id)
+    }
+
+    /// Try to change the configuration of this proxy,
+    /// for the onion services that don't have their own configuration.
     ///
     /// This change applies only to new connections through the proxy; existing
     /// connections are not affected.
@@ -86,7 +244,7 @@ impl OnionServiceReverseProxy {
             return Ok(());
         }
         let mut state = self.state.lock().expect("poisoned lock");
-        state.config = config;
+        state.config = Some(config);
         // Note: we don't need to use a postage::watch here, since we just want
         // to lock this configuration whenever we get a request.  We could use a
         // Mutex<Arc<>> instead, but the performance shouldn't matter.
@@ -94,6 +252,54 @@ impl OnionServiceReverseProxy {
         Ok(())
     }
 
+    /// Try to change the configuration of this proxy for the onion service `nickname`,
+    /// giving it its own configuration if it didn't have one.
+    ///
+    /// This change applies only to new connections through the proxy; existing
+    /// connections are not affected.
+    pub fn reconfigure_service(
+        &self,
+        nickname: HsNickname,
+        config: ProxyConfig,
+        how: tor_config::Reconfigure,
+    ) -> Result<(), tor_config::ReconfigureError> {
+        if how == tor_config::Reconfigure::CheckAllOrNothing {
+            // Every possible reconfiguration is allowed.
+            return Ok(());
+        }
+        let mut state = self.state.lock().expect("poisoned lock");
+        state.service_configs.insert(nickname, config);
+        Ok(())
+    }
+
+    /// Remove the configuration of the onion service `nickname`.
+    ///
+    /// New requests for it are then handled using the configuration
+    /// for onion services that don't have their own, if there is one;
+    /// existing connections are not affected.
+    ///
+    /// We save how much data we have forwarded for it, if we have somewhere to save it,
+    /// and then stop tracking it.
+    ///
+    /// Return the configuration that was removed, if there was one.
+    pub fn remove_service(&self, nickname: &HsNickname) -> Option<ProxyConfig> {
+        let removed = self
+            .state
+            .lock()
+            .expect("poisoned lock")
+            .service_configs
+            .remove(nickname);
+        let usage = self
+            .service_usage
+            .lock()
+            .expect("poisoned lock")
+            .remove(nickname);
+        if let Some(usage) = usage {
+            usage.save();
+        }
+        removed
+    }
+
     /// Shut down all request-handlers running using with this proxy.
     pub fn shutdown(&self) {
         let mut state = self.state.lock().expect("poisoned lock");
@@ -105,7 +311,8 @@ impl OnionServiceReverseProxy {
     /// The future returned by this function blocks indefinitely, so you may
     /// want to spawn a separate task for it.
     ///
-    /// The provided nickname is used for logging.
+    /// The provided nickname is used for logging,
+    /// and to choose which of our configurations applies to the requests.
     pub async fn handle_requests<R, S>(
         &self,
         runtime: R,
@@ -125,6 +332,9 @@ impl OnionServiceReverseProxy {
             .clone()
             .fuse();
         let nickname = Arc::new(nickname);
+        let stream_counts = StreamCounts::default();
+        // The idle connections that the rules with keepalive can reuse.
+        let pool = Arc::new(ConnectionPool::<PoolKey, TcpStreamOf<R>>::default());
 
         /// Which of the three counters for each action
         #[cfg(feature = "metrics")]
@@ -159,34 +369,103 @@ impl OnionServiceReverseProxy {
 
             Arc::new(counters)
         };
+        #[cfg(feature = "metrics")]
+        let timeout_counter = metrics::counter!(
+            "arti_hss_proxy_connections_timed_out_total",
+            "nickname" => nickname.to_string()
+        );
+
+        // We save our usage from time to time here, rather than on the streams that we forward,
+        // so that forwarding data never waits on the disk.
+        let mut save_usage = Box::pin(runtime.sleep(SAVE_INTERVAL).fuse());
 
-        loop {
+        let result = loop {
             let stream_request = select_biased! {
-                _ = shutdown_rx => return Ok(()),
+                _ = shutdown_rx => break Ok(()),
+                () = save_usage => {
+                    self.save_usage();
+                    save_usage = Box::pin(runtime.sleep(SAVE_INTERVAL).fuse());
+                    continue;
+                }
                 stream_request = stream_requests.next() => match stream_request {
-                    None => return Ok(()),
+                    None => break Ok(()),
                     Some(s) => s,
                 }
             };
 
-            runtime.spawn({
-                let action = self.choose_action(stream_request.request());
+            let Some((stream_request, handler_action)) = self.consult_handler(stream_request)
+            else {
+                // The handler has taken care of this request.
+                continue;
+            };
+
+            let spawned = runtime.spawn({
+                let (mut action, options) =
+                    self.choose_action_with(&nickname, handler_action, stream_request.request());
+                let slot = match action {
+                    ProxyAction::Forward(..) => {
+                        let circuit = stream_request.tunnel_unique_id();
+                        let slot = StreamSlot::acquire(
+                            &stream_counts,
+                            circuit,
+                            self.max_forwarded_streams_per_circuit(&nickname),
+                        );
+                        if slot.is_none() {
+                            tracing::debug!(
+                                "Too many open streams on circuit {} for onion service {}; rejecting request",
+                                circuit,
+                                nickname,
+                            );
+                            action = ProxyAction::RejectStream;
+                        } else if let Some(quota) =
+                            self.exhausted_quota(&nickname, runtime.wallclock())
+                        {
+                            tracing::debug!(
+                                "Onion service {} is over its bandwidth quota; taking action {} on request",
+                                nickname,
+                                quota.action,
+                            );
+                            action = quota.action;
+                        }
+                        slot
+                    }
+                    _ => None,
+                };
                 let runtime = runtime.clone();
                 let nickname = nickname.clone();
                 let req = stream_request.request().clone();
+                let accounting = self.accounting(&nickname);
+                let connections = self.connections.clone();
+                let pool = pool.clone();
 
                 #[cfg(feature = "metrics")]
                 let metrics_counters = metrics_counters.clone();
+                #[cfg(feature = "metrics")]
+                let timeout_counter = timeout_counter.clone();
 
                 async move {
-                    let outcome =
-                        run_action(runtime, nickname.as_ref(), action.clone(), stream_request).await;
+                    let outcome = run_action(
+                        runtime,
+                        nickname.as_ref(),
+                        action.clone(),
+                        options,
+                        stream_request,
+                        slot,
+                        accounting,
+                        connections,
+                        pool,
+                    )
+                    .await;
 
                     #[cfg(feature = "metrics")]
                     {
                         use CounterSelector as CS;
 
                         let action = ProxyActionDiscriminants::from(&action);
+                        // Timeouts also count as failures, below.
+                        if matches!(outcome, Err(RequestFailed::Timeout)) {
+                            timeout_counter.increment(1);
+                        }
                         let outcome = outcome.as_ref().map(|_|()).map_err(|_|());
                         for outcome in [CS::Total, CS::Ret(outcome)] {
                             if let Some(counter) = metrics_counters.get(&(action, outcome)) {
@@ -203,46 +482,374 @@ impl OnionServiceReverseProxy {
                         Err(_) => WARN, "Unable to take action {:?} for request {:?}", sv(action), sv(req)
                     );
                 }
-            })
-                .map_err(|e| HandleRequestsError::Spawn(Arc::new(e)))?;
+            });
+            if let Err(e) = spawned {
+                break Err(HandleRequestsError::Spawn(Arc::new(e)));
+            }
+        };
+
+        self.save_usage();
+        result
+    }
+
+    /// Save the usage of every onion service that we have somewhere to save it for.
+    fn save_usage(&self) {
+        let service_usage: Vec<_> = self
+            .service_usage
+            .lock()
+            .expect("poisoned lock")
+            .values()
+            .cloned()
+            .collect();
+        self.usage.save();
+        for usage in service_usage {
+            usage.save();
         }
     }
 
-    /// Choose the configured action that we should take in response to a
-    /// [`StreamRequest`], based on our current configuration.
-    fn choose_action(&self, stream_request: &IncomingStreamRequest) -> ProxyAction {
-        let port: u16 = match stream_request {
-            IncomingStreamRequest::Begin(begin) => {
-                // The C tor implementation deliberately ignores the address and
-                // flags on the BEGIN message, so we do too.
-                begin.port()
-            }
-            other => {
-                tracing::warn!(
-                    "Rejecting onion service request for invalid command {:?}. Internal error.",
-                    other
-                );
-                return ProxyAction::DestroyCircuit;
-            }
+    /// If we have a handler, ask it what to do with `request`.
+    ///
+    /// Return `None` if the handler has taken responsibility for the request.
+    /// Otherwise, return the request, along with the action that the handler chose for it
+    /// (or `None` if we should choose the action from our configuration).
+    fn consult_handler(
+        &self,
+        request: StreamRequest,
+    ) -> Option<(StreamRequest, Option<ProxyAction>)> {
+        let Some(handler) = &self.handler else {
+            return Some((request, None));
+        };
+        let IncomingStreamRequest::Begin(begin) = request.request() else {
+            // We only give BEGIN requests to the handler.
+            return Some((request, None));
         };
+        let info = BeginInfo {
+            port: begin.port(),
+            addr: String::from_utf8_lossy(begin.addr()).into_owned(),
+            circuit: request.tunnel_unique_id(),
+        };
+
+        match handler.handle_request(request, &info) {
+            StreamDecision::UseConfig(request) => Some((request, None)),
+            StreamDecision::Act(request, action) => Some((request, Some(action))),
+            StreamDecision::Handled => None,
+        }
+    }
+
+    /// Run `f` on the current configuration for the onion service `nickname`.
+    ///
+    /// Return `None` if we have no configuration for it.
+    fn with_config<T>(
+        &self,
+        nickname: &HsNickname,
+        f: impl FnOnce(&ProxyConfig) -> T,
+    ) -> Option<T> {
+        let state = self.state.lock().expect("poisoned lock");
+        state
+            .service_configs
+            .get(nickname)
+            .or(state.config.as_ref())
+            .map(f)
+    }
 
-        self.state
+    /// Return the tracker for the onion service `nickname`'s own configuration,
+    /// creating it if necessary.
+    fn service_usage(&self, nickname: &HsNickname) -> Arc<UsageTracker> {
+        self.service_usage
             .lock()
             .expect("poisoned lock")
-            .config
-            .resolve_port_for_begin(port)
-            .cloned()
-            // The default action is "destroy the circuit."
-            .unwrap_or(ProxyAction::DestroyCircuit)
+            .entry(nickname.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Return the tracker that counts the data we forward for the onion service `nickname`.
+    ///
+    /// Each onion service that has its own configuration has its own tracker;
+    /// the others share one.
+    fn usage_for(&self, nickname: &HsNickname) -> Arc<UsageTracker> {
+        let has_own_config = self
+            .state
+            .lock()
+            .expect("poisoned lock")
+            .service_configs
+            .contains_key(nickname);
+        if has_own_config {
+            self.service_usage(nickname)
+        } else {
+            self.usage.clone()
+        }
+    }
+
+    /// Choose the configured action that we should take in response to a
+    /// [`StreamRequest`] for the onion service `nickname`, based on our current configuration.
+    ///
+    /// Also return the other options from the rule that we chose.
+    fn choose_action(
+        &self,
+        nickname: &HsNickname,
+        stream_request: &IncomingStreamRequest,
+    ) -> (ProxyAction, RuleOptions) {
+        self.with_config(nickname, |config| {
+            choose_configured_action(config, stream_request)
+        })
+        .unwrap_or_else(|| {
+            tracing::debug!(
+                "No proxy configuration for onion service {}; destroying circuit",
+                nickname,
+            );
+            (ProxyAction::DestroyCircuit, RuleOptions::default())
+        })
+    }
+
+    /// Choose the action that we should take in response to a [`StreamRequest`]
+    /// for the onion service `nickname`, given the action that our handler chose for it, if any.
+    ///
+    /// If the handler chose an action, we take it with the default [`RuleOptions`];
+    /// otherwise, we use our configuration, as for [`choose_action`](Self::choose_action).
+    fn choose_action_with(
+        &self,
+        nickname: &HsNickname,
+        handler_action: Option<ProxyAction>,
+        stream_request: &IncomingStreamRequest,
+    ) -> (ProxyAction, RuleOptions) {
+        match handler_action {
+            Some(action) => (action, RuleOptions::default()),
+            None => self.choose_action(nickname, stream_request),
+        }
+    }
+
+    /// Return the bandwidth quota of the onion service `nickname`,
+    /// if it has one and it is used up at `now`.
+    fn exhausted_quota(&self, nickname: &HsNickname, now: SystemTime) -> Option<BandwidthQuota> {
+        let quota = self
+            .with_config(nickname, |config| config.bandwidth_quota.clone())
+            .flatten()?;
+        self.usage_for(nickname)
+            .is_exhausted(&quota, now)
+            .then_some(quota)
+    }
+
+    /// Return where to record the data that we forward for the onion service `nickname`.
+    fn accounting(&self, nickname: &HsNickname) -> Accounting {
+        let period = self
+            .with_config(nickname, |config| {
+                config.bandwidth_quota.as_ref().map(|quota| quota.period)
+            })
+            .flatten()
+            .unwrap_or_default();
+        Accounting {
+            usage: self.usage_for(nickname),
+            period,
+            unrecorded: 0,
+            connection: None,
+        }
+    }
+
+    /// Return the configured limit on forwarded streams for each rendezvous circuit
+    /// of the onion service `nickname`.
+    fn max_forwarded_streams_per_circuit(&self, nickname: &HsNickname) -> Option<NonZeroUsize> {
+        self.with_config(nickname, |config| config.max_forwarded_streams_per_circuit)
+            .flatten()
     }
 }
 
-/// Take the configured action from `action` on the incoming request `request`.
+/// Choose the action that `config` says we should take in response to a [`StreamRequest`].
+///
+/// Also return the other options from the rule that we chose.
+fn choose_configured_action(
+    config: &ProxyConfig,
+    stream_request: &IncomingStreamRequest,
+) -> (ProxyAction, RuleOptions) {
+    if let IncomingStreamRequest::Resolve(_) = stream_request {
+        return (config.resolve_requests.clone(), RuleOptions::default());
+    }
+
+    let Some(port) = begin_port(stream_request) else {
+        tracing::warn!(
+            "Rejecting onion service request for invalid command {:?}. Internal error.",
+            stream_request
+        );
+        return (ProxyAction::DestroyCircuit, RuleOptions::default());
+    };
+
+    config
+        .resolve_port_for_begin(port)
+        .map(|rule| {
+            let options = RuleOptions {
+                buffering: rule.buffering().clone(),
+                preamble: rule.preamble(),
+                response: rule.response().cloned(),
+                connect_timeout: rule.connect_timeout(),
+                keepalive: rule.keepalive().cloned(),
+            };
+            (rule.target().clone(), options)
+        })
+        // The default action is "destroy the circuit."
+        .unwrap_or((ProxyAction::DestroyCircuit, RuleOptions::default()))
+}
+
+/// The options from a rule, other than its action.
+#[derive(Clone, Debug, Default)]
+struct RuleOptions {
+    /// How to buffer the data on the stream, if we forward it.
+    buffering: BufferConfig,
+    /// What to send to the local target first, if we forward the stream.
+    preamble: Preamble,
+    /// What to send to the client instead of rejecting the stream, if anything.
+    response: Option<StaticResponse>,
+    /// How long we may take to set up the stream, if we forward it.
+    connect_timeout: Option<Duration>,
+    /// How we reuse connections to the local target, if we do.
+    keepalive: Option<KeepaliveConfig>,
+}
+
+/// Return the port that `stream_request` asks to connect to,
+/// or `None` if it is not a BEGIN request.
+fn begin_port(stream_request: &IncomingStreamRequest) -> Option<u16> {
+    match stream_request {
+        IncomingStreamRequest::Begin(begin) => {
+            // The C tor implementation deliberately ignores the address and
+            // flags on the BEGIN message, so we do too.
+            Some(begin.port())
+        }
+        _ => None,
+    }
+}
+
+/// The number of forwarded streams currently open on each rendezvous circuit.
+type StreamCounts = Arc<Mutex<HashMap<UniqId, usize>>>;
+
+/// A single forwarded stream, counted towards the limit for its circuit.
+///
+/// The stream stops being counted when this is dropped.
+#[derive(Debug)]
+struct StreamSlot {
+    /// The counts that we incremented when we took this slot.
+    counts: StreamCounts,
+    /// The circuit that the stream is on.
+    circuit: UniqId,
+}
+
+impl StreamSlot {
+    /// Try to take a slot for a new forwarded stream on `circuit`.
+    ///
+    /// Return `None` if `circuit` already has `max` forwarded streams open.
+    fn acquire(counts: &StreamCounts, circuit: UniqId, max: Option<NonZeroUsize>) -> Option<Self> {
+        let mut map = counts.lock().expect("poisoned lock");
+        let n = map.entry(circuit).or_default();
+        if max.is_some_and(|max| *n >= max.get()) {
+            return None;
+        }
+        *n += 1;
+        Some(StreamSlot {
+            counts: counts.clone(),
+            circuit,
+        })
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut map = self.counts.lock().expect("poisoned lock");
+        if let Some(n) = map.get_mut(&self.circuit) {
+            *n = n.saturating_sub(1);
+            if *n == 0 {
+                map.remove(&self.circuit);
+            }
+        }
+    }
+}
+
+/// How many bytes we forward on a stream before we record them in its [`UsageTracker`].
+///
+/// Recording them takes a lock that the other streams of the proxy share,
+/// so we don't do it for every read.
+const RECORD_BATCH: usize = 64 * 1024;
+
+/// Where to record the data that we forward on a stream.
+#[derive(Clone)]
+struct Accounting {
+    /// The tracker for the onion service.
+    usage: Arc<UsageTracker>,
+    /// The accounting period to record the data in.
+    period: QuotaPeriod,
+    /// The number of bytes that we have forwarded, but not yet recorded in `usage`.
+    unrecorded: usize,
+    /// The counters of the connection on which we are forwarding data,
+    /// and the direction in which we are forwarding it, if we know them.
+    connection: Option<(Arc<crate::active::ByteCounters>, Direction)>,
+}
+
+impl Accounting {
+    /// Record that we have forwarded `n_bytes` at `now`.
+    ///
+    /// The bytes only reach `usage` in batches of [`RECORD_BATCH`];
+    /// call [`record`](Self::record) to record the rest.
+    fn note_forwarded(&mut self, n_bytes: usize, now: SystemTime) {
+        if let Some((counters, direction)) = &self.connection {
+            counters.note_forwarded(*direction, n_bytes);
+        }
+        self.unrecorded = self.unrecorded.saturating_add(n_bytes);
+        if self.unrecorded >= RECORD_BATCH {
+            self.record(now);
+        }
+    }
+
+    /// Record all the bytes that we have forwarded in `usage`, as of `now`.
+    fn record(&mut self, now: SystemTime) {
+        if self.unrecorded > 0 {
+            self.usage.note_forwarded(self.unrecorded, self.period, now);
+            self.unrecorded = 0;
+        }
+    }
+
+    /// Return true if the data we record here is data that we send to the client.
+    fn to_client(&self) -> bool {
+        matches!(self.connection, Some((_, Direction::FromTarget)))
+    }
+
+    /// Record that we have flushed `n_bytes` of data, written since the previous flush.
+    ///
+    /// If we are sending that data to the client,
+    /// this is when any partial cell gets sent, so we count the cells it took.
+    fn note_flushed(&self, n_bytes: usize) {
+        if let Some((counters, Direction::FromTarget)) = &self.connection {
+            counters.note_cells_from_target(n_bytes.div_ceil(CELL_PAYLOAD));
+        }
+    }
+
+    /// Return a copy of this `Accounting` that also records the data we forward
+    /// in `direction` in the counters of `conn`.
+    fn for_connection(&self, conn: &ConnectionGuard, direction: Direction) -> Accounting {
+        Accounting {
+            connection: Some((conn.counters().clone(), direction)),
+            unrecorded: 0,
+            ..self.clone()
+        }
+    }
+}
+
+/// Take the configured action from `action` on the incoming request `request`,
+/// using the other settings from its rule in `options`.
+///
+/// If the request is forwarded, `slot` is held until the forwarded stream closes,
+/// the data on the stream is recorded in `accounting`,
+/// and the stream is listed in `connections` while we forward it.
+/// If the rule allows it, we reuse a connection to the target from `pool`
+/// that was last used by the same client,
+/// and return the connection to `pool` once the stream closes.
+#[allow(clippy::too_many_arguments)]
 async fn run_action<R: Runtime>(
     runtime: R,
     nickname: &HsNickname,
     action: ProxyAction,
+    options: RuleOptions,
     request: StreamRequest,
+    slot: Option<StreamSlot>,
+    accounting: Accounting,
+    connections: Arc<ConnectionTable>,
+    pool: Arc<ConnectionPool<PoolKey, TcpStreamOf<R>>>,
 ) -> Result<(), RequestFailed> {
     match action {
         ProxyAction::DestroyCircuit => {
@@ -253,22 +860,83 @@ async fn run_action<R: Runtime>(
         ProxyAction::Forward(encap, target) => match (encap, target) {
             (Encapsulation::Simple, ref addr @ TargetAddr::Inet(a)) => {
                 let rt_clone = runtime.clone();
-                forward_connection(rt_clone, request, runtime.connect(&a), nickname, addr).await?;
+                let port = begin_port(request.request()).unwrap_or_default();
+                let preamble = options.preamble.encode(port, request.tunnel_unique_id());
+                let conn = ConnectionGuard::register(
+                    &connections,
+                    nickname,
+                    port,
+                    addr,
+                    runtime.wallclock(),
+                );
+                let keepalive = options.keepalive.map(|config| Keepalive {
+                    pool,
+                    key: (request.tunnel_unique_id(), a),
+                    config,
+                });
+                let local_stream = match keepalive
+                    .as_ref()
+                    .and_then(|k| k.pool.take(&k.key, runtime.now()))
+                {
+                    Some(stream) => futures::future::ready(Ok(stream)).left_future(),
+                    None => runtime.connect(&a).right_future(),
+                };
+                forward_connection(
+                    rt_clone,
+                    request,
+                    local_stream,
+                    nickname,
+                    addr,
+                    &options.buffering,
+                    &preamble,
+                    options.connect_timeout,
+                    slot,
+                    accounting,
+                    conn,
+                    keepalive,
+                )
+                .await?;
+            }
+            #[cfg(feature = "udp")]
+            (Encapsulation::Datagram, ref addr @ TargetAddr::Inet(a)) => {
+                let port = begin_port(request.request()).unwrap_or_default();
+                let conn = ConnectionGuard::register(
+                    &connections,
+                    nickname,
+                    port,
+                    addr,
+                    runtime.wallclock(),
+                );
+                datagram::forward_datagrams(
+                    runtime,
+                    request,
+                    nickname,
+                    addr,
+                    a,
+                    options.connect_timeout,
+                    slot,
+                    accounting,
+                    conn,
+                )
+                .await?;
             } /* TODO (#1246)
                 (Encapsulation::Simple, TargetAddr::Unix(_)) => {
                     // TODO: We need to implement unix connections.
                 }
               */
         },
-        ProxyAction::RejectStream => {
-            // C tor sends DONE in this case, so we do too.
-            let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE);
+        ProxyAction::RejectStream => match &options.response {
+            Some(response) => send_response(request, nickname, &response.encode()).await?,
+            None => {
+                // C tor sends DONE in this case, so we do too.
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE);
 
-            request
-                .reject(end)
-                .await
-                .map_err(RequestFailed::CantReject)?;
-        }
+                request
+                    .reject(end)
+                    .await
+                    .map_err(RequestFailed::CantReject)?;
+            }
+        },
         ProxyAction::IgnoreStream => drop(request),
     };
     Ok(())
@@ -290,6 +958,11 @@ enum RequestFailed {
     #[error("Unable to accept onion service connection")]
     AcceptRemote(#[source] tor_hsservice::ClientError),
 
+    /// We took too long to connect to the local target and accept the stream,
+    /// so we gave up on it.
+    #[error("Timed out setting up forwarded onion service connection")]
+    Timeout,
+
     /// The runtime refused to spawn a task for us.
     #[error("Unable to spawn task")]
     Spawn(#[source] Arc<futures::task::SpawnError>),
@@ -301,29 +974,177 @@ impl HasKind for RequestFailed {
             RequestFailed::CantDestroy(e) => e.kind(),
             RequestFailed::CantReject(e) => e.kind(),
             RequestFailed::AcceptRemote(e) => e.kind(),
+            RequestFailed::Timeout => ErrorKind::LocalNetworkError,
             RequestFailed::Spawn(e) => e.kind(),
         }
     }
 }
 
+/// Where to put a connection to a local target once we are done with it,
+/// so that it can be reused.
+struct Keepalive<S> {
+    /// The pool to put the connection in.
+    pool: Arc<ConnectionPool<PoolKey, S>>,
+    /// The streams that may reuse the connection.
+    key: PoolKey,
+    /// How long to keep the connection, and how many others like it.
+    config: KeepaliveConfig,
+}
+
 /// Try to open a connection to an appropriate local target using
-/// `target_stream_future`.  If successful, try to report success on `request`
-/// and transmit data between the two stream indefinitely.  On failure, close
-/// `request`.
+/// `target_stream_future`, and send it `preamble`.  If successful, try to report
+/// success on `request` and transmit data between the two stream indefinitely.
+/// On failure, close `request`.
+///
+/// If `connect_timeout` is set, and we can't connect to the target and accept `request`
+/// within that time, we close `request` and return [`RequestFailed::Timeout`].
+///
+/// `slot` and `conn` are held until data has stopped flowing in both directions.
+/// The data that we forward is recorded in `accounting`.
+/// If `conn` is closed, we stop forwarding data, and drop both streams.
+///
+/// If `keepalive` is set, we stop forwarding data as soon as the client closes its stream,
+/// and try to put the connection to the target in its pool: see [`forward_reusable`].
 ///
 /// Only return an error if we were unable to behave as intended due to a
 /// problem we did not already report.
+#[allow(clippy::too_many_arguments)]
 async fn forward_connection<R, FUT, TS>(
     runtime: R,
     request: StreamRequest,
     target_stream_future: FUT,
     nickname: &HsNickname,
     addr: &TargetAddr,
+    buffering: &BufferConfig,
+    preamble: &[u8],
+    connect_timeout: Option<Duration>,
+    slot: Option<StreamSlot>,
+    accounting: Accounting,
+    conn: ConnectionGuard,
+    keepalive: Option<Keepalive<TS>>,
 ) -> Result<(), RequestFailed>
 where
     R: Runtime,
     FUT: Future<Output = Result<TS, IoError>>,
-    TS: AsyncRead + AsyncWrite + Send + 'static,
+    TS: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let deadline = connect_timeout.map(|t| runtime.now() + t);
+
+    let connect = connect_to_target(target_stream_future, nickname, addr, preamble);
+    let (local_r, local_w) = match run_until(&runtime, deadline, connect).await {
+        Ok(Some(local)) => local,
+        Ok(None) => return reject_request(request).await,
+        Err(_) => {
+            reject_request(request).await?;
+            return Err(RequestFailed::Timeout);
+        }
+    };
+
+    let onion_service_stream: DataStream = {
+        let connected = relaymsg::Connected::new_empty();
+        match run_until(&runtime, deadline, request.accept(connected)).await {
+            Ok(r) => r.map_err(RequestFailed::AcceptRemote)?,
+            // Dropping the request has closed the client's stream.
+            Err(_) => return Err(RequestFailed::Timeout),
+        }
+    };
+
+    let (svc_r, svc_w) = onion_service_stream.split();
+
+    if let Some(keepalive) = keepalive {
+        runtime
+            .spawn(forward_reusable(
+                runtime.clone(),
+                (local_r, local_w),
+                (svc_r, svc_w),
+                buffering.clone(),
+                accounting,
+                slot,
+                conn,
+                keepalive,
+            ))
+            .map_err(|e| RequestFailed::Spawn(Arc::new(e)))?;
+        return Ok(());
+    }
+
+    spawn_copy_tasks(
+        &runtime,
+        (local_r, local_w),
+        (svc_r, svc_w),
+        buffering,
+        slot,
+        accounting,
+        conn,
+    )
+}
+
+/// Helper for [`forward_connection`]: launch the tasks that copy data
+/// between the `local` target and the onion service stream `svc`.
+///
+/// `slot` and `conn` are held until data has stopped flowing in both directions.
+/// If `conn` is closed, both tasks stop, and drop their halves of the streams.
+fn spawn_copy_tasks<R, LR, LW, SR, SW>(
+    runtime: &R,
+    local: (LR, LW),
+    svc: (SR, SW),
+    buffering: &BufferConfig,
+    slot: Option<StreamSlot>,
+    accounting: Accounting,
+    conn: ConnectionGuard,
+) -> Result<(), RequestFailed>
+where
+    R: Runtime,
+    LR: AsyncRead + Unpin + Send + 'static,
+    LW: AsyncWrite + Unpin + Send + 'static,
+    SR: AsyncRead + Unpin + Send + 'static,
+    SW: AsyncWrite + Unpin + Send + 'static,
+{
+    let (local_r, local_w) = local;
+    let (svc_r, svc_w) = svc;
+
+    let held = Arc::new((slot, conn));
+    runtime
+        .spawn({
+            let copy = copy_interactive(
+                runtime.clone(),
+                local_r,
+                svc_w,
+                buffering.clone(),
+                accounting.for_connection(&held.1, Direction::FromTarget),
+            );
+            let held = held.clone();
+            async move { until_closed(copy, &held.1).await }
+        })
+        .map_err(|e| RequestFailed::Spawn(Arc::new(e)))?;
+    runtime
+        .spawn({
+            let copy = copy_interactive(
+                runtime.clone(),
+                svc_r,
+                local_w,
+                buffering.clone(),
+                accounting.for_connection(&held.1, Direction::ToTarget),
+            );
+            async move { until_closed(copy, &held.1).await }
+        })
+        .map_err(|e| RequestFailed::Spawn(Arc::new(e)))?;
+
+    Ok(())
+}
+
+/// Helper for [`forward_connection`]: open a connection to the local target `addr`
+/// using `target_stream_future`, and send it `preamble`.
+///
+/// Return `None` if we were unable to do so, after reporting the problem.
+async fn connect_to_target<FUT, TS>(
+    target_stream_future: FUT,
+    nickname: &HsNickname,
+    addr: &TargetAddr,
+    preamble: &[u8],
+) -> Option<(ReadHalf<TS>, WriteHalf<TS>)>
+where
+    FUT: Future<Output = Result<TS, IoError>>,
+    TS: AsyncRead + AsyncWrite,
 {
     let local_stream = target_stream_future.await.map_err(Arc::new);
 
@@ -334,41 +1155,167 @@ where
         local_stream
     );
 
-    let local_stream = match local_stream {
-        Ok(s) => s,
-        Err(_) => {
-            let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE);
-            if let Err(e_rejecting) = request.reject(end).await {
-                debug_report!(
-                    &e_rejecting,
-                    "Unable to reject onion service request from client"
-                );
-                return Err(RequestFailed::CantReject(e_rejecting));
+    // We reported the (rate-limited) error from local_stream in
+    // log_ratelim above.
+    let local_stream = local_stream.ok()?;
+    let (local_r, mut local_w) = local_stream.split();
+
+    // (If the preamble is empty, this does nothing.)
+    if let Err(e) = local_w.write_all(preamble).await {
+        debug_report!(
+            &e,
+            "Unable to send preamble to {} for onion service {}",
+            sv(addr),
+            nickname
+        );
+        return None;
+    }
+    Some((local_r, local_w))
+}
+
+/// Forward data between the client's stream `svc` and the local target `local`,
+/// a connection that we may reuse, until the client closes its stream.
+///
+/// Afterwards, if the client closed its stream cleanly,
+/// and the target hasn't closed the connection or sent anything that we didn't forward,
+/// we put the connection in the pool of `keepalive`,
+/// where only later streams from the same client can reuse it;
+/// we close it if nobody reuses it before it expires.
+///
+/// `slot` and `conn` are held, and `accounting` is used, as in [`forward_connection`].
+#[allow(clippy::too_many_arguments)]
+async fn forward_reusable<R, TS>(
+    runtime: R,
+    local: (ReadHalf<TS>, WriteHalf<TS>),
+    svc: (ReadHalf<DataStream>, WriteHalf<DataStream>),
+    buffering: BufferConfig,
+    accounting: Accounting,
+    slot: Option<StreamSlot>,
+    conn: ConnectionGuard,
+    keepalive: Keepalive<TS>,
+) where
+    R: Runtime,
+    TS: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut local_r, mut local_w) = local;
+    let (svc_r, svc_w) = svc;
+    // The number of bytes we have read from the target.
+    let read_from_target = AtomicU64::new(0);
+
+    let client_closed = {
+        let to_target = copy_interactive(
+            runtime.clone(),
+            svc_r,
+            KeepOpen(&mut local_w),
+            buffering.clone(),
+            accounting.for_connection(&conn, Direction::ToTarget),
+        );
+        let from_target = copy_interactive(
+            runtime.clone(),
+            CountingReader::new(&mut local_r, &read_from_target),
+            svc_w,
+            buffering,
+            accounting.for_connection(&conn, Direction::FromTarget),
+        );
+        let relay = async {
+            match select(pin!(to_target), pin!(from_target)).await {
+                // Tor streams can't be half-closed, so the client is gone.
+                Either::Left((result, _)) => result.is_ok(),
+                // The target is gone, but the client may still be sending.
+                Either::Right((_, to_target)) => {
+                    let _ = to_target.await;
+                    false
+                }
             }
-            // We reported the (rate-limited) error from local_stream in
-            // DEBUG_REPORT above.
-            return Ok(());
+        };
+        match select(pin!(relay), pin!(conn.closed())).await {
+            Either::Left((client_closed, _)) => client_closed,
+            Either::Right(_) => false,
         }
     };
+    let forwarded_everything = read_from_target.load(Ordering::Relaxed)
+        == conn.counters().forwarded(Direction::FromTarget);
+    drop(slot);
+    drop(conn);
 
-    let onion_service_stream: DataStream = {
-        let connected = relaymsg::Connected::new_empty();
-        request
-            .accept(connected)
-            .await
-            .map_err(RequestFailed::AcceptRemote)?
+    if !(client_closed && forwarded_everything) {
+        return;
+    }
+    let Ok(stream) = local_r.reunite(local_w) else {
+        return;
     };
+    let Keepalive { pool, key, config } = keepalive;
+    pool.put(key, stream, &config, runtime.now());
+    runtime.sleep(config.idle_timeout).await;
+    pool.expire(runtime.now());
+}
 
-    let (svc_r, svc_w) = onion_service_stream.split();
-    let (local_r, local_w) = local_stream.split();
+/// Run `fut` to completion, or until someone closes `conn`, whichever comes first.
+async fn until_closed<F: Future>(fut: F, conn: &ConnectionGuard) {
+    let closed = conn.closed();
+    futures::future::select(std::pin::pin!(fut), std::pin::pin!(closed)).await;
+}
 
-    runtime
-        .spawn(copy_interactive(local_r, svc_w).map(|_| ()))
-        .map_err(|e| RequestFailed::Spawn(Arc::new(e)))?;
-    runtime
-        .spawn(copy_interactive(svc_r, local_w).map(|_| ()))
-        .map_err(|e| RequestFailed::Spawn(Arc::new(e)))?;
+/// Run `fut` to completion, or until `deadline` if there is one.
+async fn run_until<R, F>(
+    runtime: &R,
+    deadline: Option<Instant>,
+    fut: F,
+) -> Result<F::Output, TimeoutError>
+where
+    R: Runtime,
+    F: Future,
+{
+    match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(runtime.now());
+            runtime.timeout(remaining, fut).await
+        }
+        None => Ok(fut.await),
+    }
+}
 
+/// Accept `request`, send `response` on it, and close it.
+///
+/// Only return an error if we were unable to accept the request.
+async fn send_response(
+    request: StreamRequest,
+    nickname: &HsNickname,
+    response: &[u8],
+) -> Result<(), RequestFailed> {
+    let mut stream: DataStream = request
+        .accept(relaymsg::Connected::new_empty())
+        .await
+        .map_err(RequestFailed::AcceptRemote)?;
+
+    // If the client goes away before reading the response, that's their business.
+    let result = async {
+        stream.write_all(response).await?;
+        stream.close().await
+    }
+    .await;
+    if let Err(e) = result {
+        debug_report!(
+            &e,
+            "Unable to send response to client of onion service {}",
+            nickname
+        );
+    }
+    Ok(())
+}
+
+/// Reject `request` after we failed to connect to its local target.
+///
+/// Only return an error if we were unable to reject the request.
+async fn reject_request(request: StreamRequest) -> Result<(), RequestFailed> {
+    let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE);
+    if let Err(e_rejecting) = request.reject(end).await {
+        debug_report!(
+            &e_rejecting,
+            "Unable to reject onion service request from client"
+        );
+        return Err(RequestFailed::CantReject(e_rejecting));
+    }
     Ok(())
 }
 
@@ -382,19 +1329,34 @@ where
 ///
 /// This function assumes that the writer might need to be flushed for
 /// any buffered data to be sent.  It tries to minimize the number of
-/// flushes, however, by only flushing the writer when the reader has no data.
+/// flushes, however, by only flushing the writer when the reader has no data
+/// (and, if `buffering` has a nonzero `flush_interval`, has had no data for that long,
+/// unless at least `buffering.coalesce_below` bytes are waiting),
+/// or when more than `buffering.max_unflushed` bytes are waiting to be flushed.
+///
+/// Every byte that we copy is recorded in `accounting`,
+/// which also tells us whether we are writing to the client.
 ///
 /// NOTE: This is duplicate code from `arti::socks`.  But instead of
 /// deduplicating it, we should change the behavior in `DataStream` that makes
 /// it necessary. See arti#786 for a fuller discussion.
-async fn copy_interactive<R, W>(mut reader: R, mut writer: W) -> IoResult<()>
+async fn copy_interactive<RT, R, W>(
+    runtime: RT,
+    mut reader: R,
+    mut writer: W,
+    buffering: BufferConfig,
+    mut accounting: Accounting,
+) -> IoResult<()>
 where
+    RT: Runtime,
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
     use futures::{poll, task::Poll};
 
-    let mut buf = [0_u8; 1024];
+    let mut buf = vec![0_u8; buffering.buffer_size];
+    // The number of bytes we have written since we last flushed.
+    let mut unflushed = 0;
 
     // At this point we could just loop, calling read().await,
     // write_all().await, and flush().await.  But we want to be more
@@ -408,19 +1370,60 @@ where
             Poll::Ready(Err(e)) => break Err(e),
             Poll::Ready(Ok(0)) => break Ok(()), // EOF
             Poll::Ready(Ok(n)) => {
-                writer.write_all(&buf[..n]).await?;
+                write_data(
+                    &mut writer,
+                    &buf[..n],
+                    &mut unflushed,
+                    &buffering,
+                    &accounting,
+                )
+                .await?;
+                accounting.note_forwarded(n, runtime.wallclock());
                 continue;
             }
-            Poll::Pending => writer.flush().await?,
+            Poll::Pending if !holds_back(&buffering, unflushed) => {
+                flush_data(&mut writer, &mut unflushed, &accounting).await?;
+            }
+            Poll::Pending => {}
         }
 
         // The read future is pending, so we should wait on it.
-        match read_future.await {
+        //
+        // If we are holding back some unflushed data, only wait for up to
+        // `flush_interval` before flushing it.
+        let read_result = if unflushed > 0 {
+            match runtime
+                .timeout(buffering.flush_interval, &mut read_future)
+                .await
+            {
+                Ok(res) => res,
+                Err(_timeout) => {
+                    flush_data(&mut writer, &mut unflushed, &accounting).await?;
+                    read_future.await
+                }
+            }
+        } else {
+            read_future.await
+        };
+
+        match read_result {
             Err(e) => break Err(e),
             Ok(0) => break Ok(()),
-            Ok(n) => writer.write_all(&buf[..n]).await?,
+            Ok(n) => {
+                write_data(
+                    &mut writer,
+                    &buf[..n],
+                    &mut unflushed,
+                    &buffering,
+                    &accounting,
+                )
+                .await?;
+                accounting.note_forwarded(n, runtime.wallclock());
+            }
         }
     };
+    accounting.record(runtime.wallclock());
+    accounting.note_flushed(unflushed);
 
     // Make sure that we flush any lingering data if we can.
     //
@@ -434,3 +1437,480 @@ where
 
     loop_result.or(flush_result)
 }
+
+/// Helper for [`copy_interactive`]: write `data` to `writer`,
+/// and flush it if more than `buffering.max_unflushed` bytes are now waiting to be flushed.
+///
+/// If `buffering.cell_aligned` is set, and we are writing to the client,
+/// we write `data` in chunks that end on cell boundaries,
+/// and only flush at the end of a chunk,
+/// once at least `max_unflushed` bytes (rounded down to whole cells) are waiting.
+///
+/// `unflushed` is the number of bytes written since the last flush.
+async fn write_data<W>(
+    writer: &mut W,
+    mut data: &[u8],
+    unflushed: &mut usize,
+    buffering: &BufferConfig,
+    accounting: &Accounting,
+) -> IoResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if !(buffering.cell_aligned && accounting.to_client()) {
+        writer.write_all(data).await?;
+        *unflushed += data.len();
+        if buffering.max_unflushed.is_some_and(|max| *unflushed >= max) {
+            flush_data(writer, unflushed, accounting).await?;
+        }
+        return Ok(());
+    }
+
+    // Never flush less than one full cell.
+    let max_unflushed = buffering
+        .max_unflushed
+        .map(|max| (max - max % CELL_PAYLOAD).max(CELL_PAYLOAD));
+    while !data.is_empty() {
+        // Write up to the end of the current cell.
+        let chunk_len = (CELL_PAYLOAD - *unflushed % CELL_PAYLOAD).min(data.len());
+        let (chunk, rest) = data.split_at(chunk_len);
+        writer.write_all(chunk).await?;
+        *unflushed += chunk_len;
+        data = rest;
+        if *unflushed % CELL_PAYLOAD == 0 && max_unflushed.is_some_and(|max| *unflushed >= max) {
+            flush_data(writer, unflushed, accounting).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Helper for [`copy_interactive`]: flush `writer`,
+/// recording the `unflushed` bytes we wrote since the last flush in `accounting`.
+async fn flush_data<W>(
+    writer: &mut W,
+    unflushed: &mut usize,
+    accounting: &Accounting,
+) -> IoResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.flush().await?;
+    accounting.note_flushed(*unflushed);
+    *unflushed = 0;
+    Ok(())
+}
+
+/// Helper for [`copy_interactive`]: return true if, once there is no more data to read,
+/// we should wait for up to `buffering.flush_interval` before flushing the `unflushed` bytes
+/// that are waiting, rather than flushing them immediately.
+fn holds_back(buffering: &BufferConfig, unflushed: usize) -> bool {
+    !buffering.flush_interval.is_zero()
+        && buffering
+            .coalesce_below
+            .is_none_or(|coalesce_below| unflushed < coalesce_below)
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::config::ProxyConfigBuilder;
+    use futures::TryStreamExt as _;
+    use tor_config::Reconfigure;
+    use tor_rtmock::MockRuntime;
+
+    /// Build a `ProxyConfig` from `json`.
+    fn config(json: &str) -> ProxyConfig {
+        let bld: ProxyConfigBuilder = serde_json::from_str(json).unwrap();
+        bld.build().unwrap()
+    }
+
+    #[test]
+    fn per_service_config() {
+        let ignore = || config(r#"{ "proxy_ports": [ [ "80", "ignore" ] ] }"#);
+        let reject = || config(r#"{ "proxy_ports": [ [ "80", "reject" ] ] }"#);
+        let nick_1 = HsNickname::new("nick_1".to_string()).unwrap();
+        let nick_2 = HsNickname::new("nick_2".to_string()).unwrap();
+        let nick_3 = HsNickname::new("nick_3".to_string()).unwrap();
+
+        let proxy = OnionServiceReverseProxy::for_services([
+            (nick_1.clone(), ignore()),
+            (nick_2.clone(), reject()),
+        ]);
+        let begin = IncomingStreamRequest::Begin(relaymsg::Begin::new("a", 80, 0).unwrap());
+        let action = |nickname: &HsNickname| proxy.choose_action(nickname, &begin).0;
+
+        assert_eq!(action(&nick_1), ProxyAction::IgnoreStream);
+        assert_eq!(action(&nick_2), ProxyAction::RejectStream);
+        // We have no configuration for this one.
+        assert_eq!(action(&nick_3), ProxyAction::DestroyCircuit);
+
+        proxy
+            .reconfigure_service(nick_3.clone(), ignore(), Reconfigure::AllOrNothing)
+            .unwrap();
+        assert_eq!(action(&nick_3), ProxyAction::IgnoreStream);
+
+        assert!(proxy.remove_service(&nick_1).is_some());
+        assert_eq!(action(&nick_1), ProxyAction::DestroyCircuit);
+
+        // The shared configuration covers the services that don't have their own.
+        proxy
+            .reconfigure(reject(), Reconfigure::AllOrNothing)
+            .unwrap();
+        assert_eq!(action(&nick_1), ProxyAction::RejectStream);
+        assert_eq!(action(&nick_3), ProxyAction::IgnoreStream);
+
+        // Each service with its own configuration has its own usage.
+        let now = SystemTime::now();
+        for (nick, n_bytes) in [(&nick_2, 100), (&nick_3, 10), (&nick_1, 1)] {
+            let mut accounting = proxy.accounting(nick);
+            accounting.note_forwarded(n_bytes, now);
+            accounting.record(now);
+        }
+        assert_eq!(proxy.service_bandwidth_usage(&nick_2).unwrap().bytes, 100);
+        assert_eq!(proxy.service_bandwidth_usage(&nick_3).unwrap().bytes, 10);
+        assert_eq!(proxy.bandwidth_usage().unwrap().bytes, 1);
+
+        // Once a service is removed, we stop tracking its usage.
+        assert!(proxy.remove_service(&nick_2).is_some());
+        assert!(proxy.service_bandwidth_usage(&nick_2).is_none());
+        assert!(!proxy.service_usage.lock().unwrap().contains_key(&nick_2));
+        assert!(proxy.remove_service(&nick_2).is_none());
+        // Asking about a service doesn't start tracking it.
+        assert!(proxy.service_bandwidth_usage(&nick_1).is_none());
+        assert!(!proxy.service_usage.lock().unwrap().contains_key(&nick_1));
+    }
+
+    #[test]
+    fn handler_action() {
+        let nickname = HsNickname::new("allium".to_string()).unwrap();
+        let proxy = OnionServiceReverseProxy::with_handler(
+            config(
+                r#"{ "proxy_ports": [
+                    { "source": "443", "target": "127.0.0.1:10443",
+                      "preamble": "proxy_v2", "buffer_size": 8192 },
+                    [ "80", "reject" ]
+                ] }"#,
+            ),
+            Arc::new(|request: StreamRequest, _: &BeginInfo| StreamDecision::UseConfig(request)),
+        );
+        let begin =
+            |port| IncomingStreamRequest::Begin(relaymsg::Begin::new("a", port, 0).unwrap());
+        let elsewhere: ProxyAction = "127.0.0.1:10080".parse().unwrap();
+
+        // Without an action from the handler, we follow the matching rule.
+        let (action, options) = proxy.choose_action_with(&nickname, None, &begin(443));
+        assert_eq!(action, "127.0.0.1:10443".parse().unwrap());
+        assert_eq!(options.preamble, Preamble::ProxyV2);
+        assert_eq!(options.buffering.buffer_size, 8192);
+
+        // The handler's action overrides the rule, along with the rule's options.
+        let (action, options) =
+            proxy.choose_action_with(&nickname, Some(elsewhere.clone()), &begin(443));
+        assert_eq!(action, elsewhere);
+        assert_eq!(options.preamble, Preamble::None);
+        assert_eq!(options.buffering, BufferConfig::default());
+
+        let (action, _) =
+            proxy.choose_action_with(&nickname, Some(ProxyAction::RejectStream), &begin(443));
+        assert_eq!(action, ProxyAction::RejectStream);
+        let (action, _) =
+            proxy.choose_action_with(&nickname, Some(ProxyAction::IgnoreStream), &begin(80));
+        assert_eq!(action, ProxyAction::IgnoreStream);
+        let (action, _) = proxy.choose_action_with(&nickname, None, &begin(80));
+        assert_eq!(action, ProxyAction::RejectStream);
+    }
+
+    #[test]
+    fn accounting_batches() {
+        let nickname = HsNickname::new("allium".to_string()).unwrap();
+        let proxy = OnionServiceReverseProxy::new(config(r#"{ "proxy_ports": [] }"#));
+        let now = SystemTime::now();
+        let mut accounting = proxy.accounting(&nickname);
+
+        // Small reads are held back until they add up to a batch.
+        accounting.note_forwarded(100, now);
+        assert!(proxy.bandwidth_usage().is_none());
+        accounting.note_forwarded(RECORD_BATCH, now);
+        assert_eq!(
+            proxy.bandwidth_usage().unwrap().bytes,
+            (RECORD_BATCH + 100) as u64
+        );
+
+        // The rest are recorded when we ask.
+        accounting.note_forwarded(7, now);
+        accounting.record(now);
+        assert_eq!(
+            proxy.bandwidth_usage().unwrap().bytes,
+            (RECORD_BATCH + 107) as u64
+        );
+    }
+
+    /// A writer that records how many bytes had been written at each flush.
+    #[derive(Default)]
+    struct FlushRecorder {
+        /// The number of bytes written so far.
+        written: usize,
+        /// The value of `written` at each flush.
+        ///
+        /// Shared, so that we can inspect it while the writer is in use.
+        flushes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl FlushRecorder {
+        /// Return the value of `written` at each flush so far.
+        fn flushes(&self) -> Vec<usize> {
+            self.flushes.lock().unwrap().clone()
+        }
+    }
+
+    impl AsyncWrite for FlushRecorder {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<IoResult<usize>> {
+            self.written += buf.len();
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<IoResult<()>> {
+            let written = self.written;
+            self.flushes.lock().unwrap().push(written);
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<IoResult<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn cell_aligned_writes() {
+        let nickname = HsNickname::new("allium".to_string()).unwrap();
+        let proxy = OnionServiceReverseProxy::for_services([(
+            nickname.clone(),
+            config(r#"{ "proxy_ports": [ [ "80", "ignore" ] ] }"#),
+        )]);
+        let table = Arc::new(ConnectionTable::default());
+        let target = TargetAddr::Inet("127.0.0.1:8080".parse().unwrap());
+        let conn = ConnectionGuard::register(&table, &nickname, 80, &target, SystemTime::now());
+        let buffering = BufferConfig {
+            max_unflushed: Some(1000),
+            cell_aligned: true,
+            ..Default::default()
+        };
+        let write_700s = |direction| {
+            let accounting = proxy.accounting(&nickname).for_connection(&conn, direction);
+            let mut writer = FlushRecorder::default();
+            let mut unflushed = 0;
+            futures::executor::block_on(async {
+                for _ in 0..3 {
+                    write_data(
+                        &mut writer,
+                        &[0; 700],
+                        &mut unflushed,
+                        &buffering,
+                        &accounting,
+                    )
+                    .await
+                    .unwrap();
+                }
+                flush_data(&mut writer, &mut unflushed, &accounting)
+                    .await
+                    .unwrap();
+            });
+            writer.flushes()
+        };
+
+        // To the client, we only flush whole cells, and at least max_unflushed rounded down.
+        assert_eq!(CELL_PAYLOAD, 498);
+        assert_eq!(write_700s(Direction::FromTarget), [996, 1992, 2100]);
+        // Two cells at each of the first two flushes, and one partial cell at the last.
+        assert_eq!(conn.counters().cells_from_target(), 5);
+
+        // To the target, cells don't matter.
+        assert_eq!(write_700s(Direction::ToTarget), [1400, 2100]);
+        assert_eq!(conn.counters().cells_from_target(), 5);
+    }
+
+    #[test]
+    fn flush_interval() {
+        MockRuntime::test_with_various(|rt| async move {
+            let nickname = HsNickname::new("allium".to_string()).unwrap();
+            let proxy = OnionServiceReverseProxy::new(config(r#"{ "proxy_ports": [] }"#));
+            let buffering = BufferConfig {
+                max_unflushed: Some(1000),
+                flush_interval: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let (tx, rx) = futures::channel::mpsc::unbounded::<IoResult<Vec<u8>>>();
+            let writer = FlushRecorder::default();
+            let flushes_handle = writer.flushes.clone();
+            let copy = rt
+                .spawn_with_handle(copy_interactive(
+                    rt.clone(),
+                    rx.into_async_read(),
+                    writer,
+                    buffering,
+                    proxy.accounting(&nickname),
+                ))
+                .unwrap();
+            let flushes = || flushes_handle.lock().unwrap().clone();
+            let send = |n| tx.unbounded_send(Ok(vec![0; n])).unwrap();
+
+            // Data is held back until there has been nothing to read for flush_interval.
+            send(100);
+            rt.progress_until_stalled().await;
+            assert_eq!(flushes(), []);
+            rt.advance_by(Duration::from_millis(30)).await;
+            send(100);
+            rt.advance_by(Duration::from_millis(49)).await;
+            assert_eq!(flushes(), []);
+            rt.advance_by(Duration::from_millis(1)).await;
+            assert_eq!(flushes(), [200]);
+
+            // ...unless more than max_unflushed bytes are waiting.
+            // (We read at most buffer_size bytes at a time.)
+            send(1500);
+            rt.progress_until_stalled().await;
+            assert_eq!(flushes(), [200, 1224]);
+            rt.advance_by(Duration::from_millis(50)).await;
+            assert_eq!(flushes(), [200, 1224, 1700]);
+
+            tx.close_channel();
+            copy.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn close_connection() {
+        MockRuntime::test_with_various(|rt| async move {
+            let nickname = HsNickname::new("allium".to_string()).unwrap();
+            let proxy = OnionServiceReverseProxy::new(config(r#"{ "proxy_ports": [] }"#));
+            let target = TargetAddr::Inet("127.0.0.1:8080".parse().unwrap());
+            let conn = ConnectionGuard::register(
+                &proxy.connections,
+                &nickname,
+                80,
+                &target,
+                SystemTime::now(),
+            );
+            let (mut client, svc) = tor_rtmock::io::stream_pair();
+            let (mut local, local_target) = tor_rtmock::io::stream_pair();
+            spawn_copy_tasks(
+                &rt,
+                local_target.split(),
+                svc.split(),
+                &BufferConfig::default(),
+                None,
+                proxy.accounting(&nickname),
+                conn,
+            )
+            .unwrap();
+
+            // Data flows in both directions...
+            client.write_all(b"hello").await.unwrap();
+            local.write_all(b"hi!").await.unwrap();
+            rt.advance_until_stalled().await;
+            let mut buf = [0_u8; 5];
+            local.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            client.read_exact(&mut buf[..3]).await.unwrap();
+            assert_eq!(&buf[..3], b"hi!");
+            let list = proxy.active_connections();
+            assert_eq!(list.len(), 1);
+            assert_eq!(list[0].bytes_to_target, 5);
+            assert_eq!(list[0].bytes_from_target, 3);
+
+            // ...until we close the connection,
+            // which closes both streams, and forgets the connection.
+            assert!(proxy.close_connection(list[0].id));
+            rt.advance_until_stalled().await;
+            assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(local.read(&mut buf).await.unwrap(), 0);
+            assert!(client.write_all(b"hello?").await.is_err());
+            assert!(proxy.active_connections().is_empty());
+            assert!(!proxy.close_connection(list[0].id));
+        });
+    }
+
+    #[test]
+    fn connect_timeout() {
+        MockRuntime::test_with_various(|rt| async move {
+            let secs = Duration::from_secs;
+            // Try to connect to a target that takes `connect_after` to answer;
+            // return whether we managed to.
+            let attempt = |connect_after, connect_timeout: Option<Duration>| {
+                let nickname = HsNickname::new("allium".to_string()).unwrap();
+                let addr = TargetAddr::Inet("127.0.0.1:8080".parse().unwrap());
+                let target = rt
+                    .sleep(connect_after)
+                    .map(|()| Ok(futures::io::Cursor::new(vec![])));
+                let deadline = connect_timeout.map(|t| rt.now() + t);
+                let rt = rt.clone();
+                rt.clone()
+                    .spawn_with_handle(async move {
+                        let connect = connect_to_target(target, &nickname, &addr, b"preamble");
+                        run_until(&rt, deadline, connect).await.is_ok()
+                    })
+                    .unwrap()
+            };
+
+            let fast = attempt(secs(3), Some(secs(5)));
+            let slow = attempt(secs(10), Some(secs(5)));
+            let patient = attempt(secs(10), None);
+            rt.advance_until_stalled().await;
+            assert!(fast.await);
+            assert!(!slow.await);
+            assert!(patient.await);
+
+            // The deadline covers every step of setting up the connection.
+            let deadline = Some(rt.now() + secs(5));
+            let first = rt.spawn_with_handle({
+                let rt = rt.clone();
+                async move { run_until(&rt, deadline, rt.sleep(secs(3))).await.is_ok() }
+            });
+            rt.advance_until_stalled().await;
+            assert!(first.unwrap().await);
+            let second = rt.spawn_with_handle({
+                let rt = rt.clone();
+                async move { run_until(&rt, deadline, rt.sleep(secs(3))).await.is_ok() }
+            });
+            rt.advance_until_stalled().await;
+            assert!(!second.unwrap().await);
+        });
+    }
+
+    #[test]
+    fn coalesce_small_writes() {
+        let mut buffering = BufferConfig::default();
+        assert!(!holds_back(&buffering, 10));
+
+        buffering.flush_interval = Duration::from_millis(50);
+        assert!(holds_back(&buffering, 10));
+        assert!(holds_back(&buffering, 10_000));
+
+        buffering.coalesce_below = Some(256);
+        assert!(holds_back(&buffering, 10));
+        assert!(!holds_back(&buffering, 256));
+    }
+}
@@ -0,0 +1,376 @@
+//! Forwarding datagrams between onion service streams and local UDP sockets.
+//!
+//! Tor can't carry UDP (yet), so a client sends its datagrams on an ordinary stream,
+//! each one preceded by its length as a two-byte, big-endian integer.
+//! We send each of them to the target in a UDP datagram of its own,
+//! and send the target's replies back to the client in the same form.
+
+use super::*;
+
+use futures::future::{Either, select};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::pin;
+use tor_rtcompat::{UdpProvider as _, UdpSocket};
+
+/// The largest datagram that fits in a frame.
+const MAX_DATAGRAM_LEN: usize = 0xFFFF;
+
+/// Bind a local UDP socket from which to reach `target`, and try to report success on `request`.
+/// If successful, forward datagrams between the two until the client closes its stream.
+/// On failure, close `request`.
+///
+/// If `connect_timeout` is set, and we can't bind the socket and accept `request`
+/// within that time, we close `request` and return [`RequestFailed::Timeout`].
+///
+/// `slot` and `conn` are held until we stop forwarding datagrams.
+/// The datagrams that we forward are recorded in `accounting`.
+/// If `conn` is closed, we stop forwarding datagrams, and drop the stream.
+///
+/// Only return an error if we were unable to behave as intended due to a
+/// problem we did not already report.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn forward_datagrams<R: Runtime>(
+    runtime: R,
+    request: StreamRequest,
+    nickname: &HsNickname,
+    addr: &TargetAddr,
+    target: SocketAddr,
+    connect_timeout: Option<Duration>,
+    slot: Option<StreamSlot>,
+    accounting: Accounting,
+    conn: ConnectionGuard,
+) -> Result<(), RequestFailed> {
+    let deadline = connect_timeout.map(|t| runtime.now() + t);
+
+    let bind_addr: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = match run_until(&runtime, deadline, runtime.bind(&bind_addr)).await {
+        Ok(socket) => socket.map_err(Arc::new),
+        Err(_) => {
+            reject_request(request).await?;
+            return Err(RequestFailed::Timeout);
+        }
+    };
+
+    log_ratelim!(
+        "Binding a UDP socket for {} for onion service {}", sv(addr), nickname;
+        socket
+    );
+
+    // We reported the (rate-limited) error from socket in log_ratelim above.
+    let Ok(socket) = socket else {
+        return reject_request(request).await;
+    };
+
+    let onion_service_stream: DataStream = {
+        let connected = relaymsg::Connected::new_empty();
+        match run_until(&runtime, deadline, request.accept(connected)).await {
+            Ok(r) => r.map_err(RequestFailed::AcceptRemote)?,
+            // Dropping the request has closed the client's stream.
+            Err(_) => return Err(RequestFailed::Timeout),
+        }
+    };
+
+    runtime
+        .spawn({
+            let runtime = runtime.clone();
+            async move {
+                let relay = relay_datagrams(
+                    &runtime,
+                    onion_service_stream,
+                    &socket,
+                    target,
+                    &accounting,
+                    &conn,
+                );
+                until_closed(relay, &conn).await;
+                drop(slot);
+            }
+        })
+        .map_err(|e| RequestFailed::Spawn(Arc::new(e)))?;
+
+    Ok(())
+}
+
+/// Forward datagrams between `stream` and `target`, using `socket`,
+/// until the client closes the stream or we encounter an error.
+///
+/// Every datagram that we forward is recorded in `accounting`,
+/// and in the counters of `conn`.
+async fn relay_datagrams<R, S, U>(
+    runtime: &R,
+    stream: S,
+    socket: &U,
+    target: SocketAddr,
+    accounting: &Accounting,
+    conn: &ConnectionGuard,
+) -> IoResult<()>
+where
+    R: Runtime,
+    S: AsyncRead + AsyncWrite,
+    U: UdpSocket,
+{
+    let (mut stream_r, mut stream_w) = stream.split();
+
+    let mut to_target_accounting = accounting.for_connection(conn, Direction::ToTarget);
+    let mut from_target_accounting = accounting.for_connection(conn, Direction::FromTarget);
+    let to_target = datagrams_to_target(
+        runtime,
+        &mut stream_r,
+        socket,
+        target,
+        &mut to_target_accounting,
+    );
+    let from_target = datagrams_from_target(
+        runtime,
+        &mut stream_w,
+        socket,
+        target,
+        &mut from_target_accounting,
+    );
+    // UDP has no notion of closing, so we only stop when the client goes away,
+    // or when something fails.
+    let result = match select(pin!(to_target), pin!(from_target)).await {
+        Either::Left((r, _)) | Either::Right((r, _)) => r,
+    };
+    let now = runtime.wallclock();
+    to_target_accounting.record(now);
+    from_target_accounting.record(now);
+
+    // As in copy_interactive, only do a "proper" close if the client closed cleanly.
+    let close_result = if result.is_ok() {
+        stream_w.close().await
+    } else {
+        stream_w.flush().await
+    };
+
+    result.or(close_result)
+}
+
+/// Send every datagram that the client sends on `reader` to `target`.
+///
+/// Returns when the client closes its stream.
+async fn datagrams_to_target<R, S, U>(
+    runtime: &R,
+    reader: &mut S,
+    socket: &U,
+    target: SocketAddr,
+    accounting: &mut Accounting,
+) -> IoResult<()>
+where
+    R: Runtime,
+    S: AsyncRead + Unpin,
+    U: UdpSocket,
+{
+    let mut buf = Vec::new();
+    while read_frame(reader, &mut buf).await? {
+        socket.send(&buf, &target).await?;
+        accounting.note_forwarded(buf.len(), runtime.wallclock());
+    }
+    Ok(())
+}
+
+/// Send every datagram that we receive from `target` to the client, on `writer`.
+///
+/// Datagrams from any other address are discarded.
+/// Only returns on error.
+async fn datagrams_from_target<R, S, U>(
+    runtime: &R,
+    writer: &mut S,
+    socket: &U,
+    target: SocketAddr,
+    accounting: &mut Accounting,
+) -> IoResult<()>
+where
+    R: Runtime,
+    S: AsyncWrite + Unpin,
+    U: UdpSocket,
+{
+    let mut buf = vec![0_u8; MAX_DATAGRAM_LEN];
+    loop {
+        let (n, from) = socket.recv(&mut buf).await?;
+        if from != target {
+            continue;
+        }
+        write_frame(writer, &buf[..n]).await?;
+        accounting.note_forwarded(n, runtime.wallclock());
+    }
+}
+
+/// Read one framed datagram from `reader` into `buf`.
+///
+/// Return `false` if the stream ended cleanly, before the start of a frame.
+async fn read_frame<R>(reader: &mut R, buf: &mut Vec<u8>) -> IoResult<bool>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0_u8; 2];
+    if reader.read(&mut len[..1]).await? == 0 {
+        return Ok(false);
+    }
+    reader.read_exact(&mut len[1..]).await?;
+    buf.resize(usize::from(u16::from_be_bytes(len)), 0);
+    reader.read_exact(buf).await?;
+    Ok(true)
+}
+
+/// Write `datagram` to `writer` as a single frame, and flush it.
+async fn write_frame<W>(writer: &mut W, datagram: &[u8]) -> IoResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = u16::try_from(datagram.len()).map_err(|_| {
+        IoError::new(
+            std::io::ErrorKind::InvalidInput,
+            "datagram too long to frame",
+        )
+    })?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(datagram).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::config::ProxyConfigBuilder;
+    use futures::channel::mpsc;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+    use tor_rtmock::MockRuntime;
+
+    /// A UDP socket whose datagrams are sent and received on channels.
+    struct FakeUdpSocket {
+        /// Where we put the datagrams that we send, with their destinations.
+        sent: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+        /// Where we get the datagrams that we receive, with their sources.
+        received: futures::lock::Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl UdpSocket for FakeUdpSocket {
+        async fn recv(&self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+            let Some((datagram, from)) = self.received.lock().await.next().await else {
+                return Err(std::io::ErrorKind::NotConnected.into());
+            };
+            buf[..datagram.len()].copy_from_slice(&datagram);
+            Ok((datagram.len(), from))
+        }
+        async fn send(&self, buf: &[u8], target: &SocketAddr) -> IoResult<usize> {
+            self.sent
+                .unbounded_send((buf.to_vec(), *target))
+                .map_err(|_| IoError::from(std::io::ErrorKind::NotConnected))?;
+            Ok(buf.len())
+        }
+        fn local_addr(&self) -> IoResult<SocketAddr> {
+            Ok("127.0.0.1:7777".parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn framing() {
+        block_on(async {
+            let mut framed = Vec::new();
+            write_frame(&mut framed, b"hello").await.unwrap();
+            write_frame(&mut framed, b"").await.unwrap();
+            assert_eq!(&framed[..7], b"\x00\x05hello");
+            assert!(
+                write_frame(&mut framed, &vec![0; MAX_DATAGRAM_LEN + 1])
+                    .await
+                    .is_err()
+            );
+
+            let mut reader = Cursor::new(framed);
+            let mut buf = Vec::new();
+            assert!(read_frame(&mut reader, &mut buf).await.unwrap());
+            assert_eq!(buf, b"hello");
+            assert!(read_frame(&mut reader, &mut buf).await.unwrap());
+            assert!(buf.is_empty());
+            // A clean EOF, between frames.
+            assert!(!read_frame(&mut reader, &mut buf).await.unwrap());
+
+            // A frame that was cut short is an error.
+            let mut reader = Cursor::new(b"\x00\x05hel".to_vec());
+            assert!(read_frame(&mut reader, &mut buf).await.is_err());
+        });
+    }
+    #[test]
+    fn relay() {
+        MockRuntime::test_with_various(|rt| async move {
+            let nickname = HsNickname::new("allium".to_string()).unwrap();
+            let proxy =
+                OnionServiceReverseProxy::new(ProxyConfigBuilder::default().build().unwrap());
+            let target: SocketAddr = "127.0.0.1:53".parse().unwrap();
+            let table = Arc::new(ConnectionTable::default());
+            let conn = ConnectionGuard::register(
+                &table,
+                &nickname,
+                53,
+                &TargetAddr::Inet(target),
+                SystemTime::now(),
+            );
+            let counters = conn.counters().clone();
+
+            let (sent_tx, mut sent_rx) = mpsc::unbounded();
+            let (received_tx, received_rx) = mpsc::unbounded();
+            let socket = FakeUdpSocket {
+                sent: sent_tx,
+                received: futures::lock::Mutex::new(received_rx),
+            };
+            let (mut client, stream) = tor_rtmock::io::stream_pair();
+            let relay = rt
+                .spawn_with_handle({
+                    let rt = rt.clone();
+                    let accounting = proxy.accounting(&nickname);
+                    async move {
+                        relay_datagrams(&rt, stream, &socket, target, &accounting, &conn).await
+                    }
+                })
+                .unwrap();
+
+            // Each frame that the client sends becomes a datagram of its own.
+            write_frame(&mut client, b"hello").await.unwrap();
+            write_frame(&mut client, b"").await.unwrap();
+            rt.progress_until_stalled().await;
+            assert_eq!(sent_rx.next().await.unwrap(), (b"hello".to_vec(), target));
+            assert_eq!(sent_rx.next().await.unwrap(), (vec![], target));
+
+            // Replies from the target come back as frames; anything else is discarded.
+            let other: SocketAddr = "127.0.0.1:54".parse().unwrap();
+            received_tx
+                .unbounded_send((b"spoofed".to_vec(), other))
+                .unwrap();
+            received_tx
+                .unbounded_send((b"world!".to_vec(), target))
+                .unwrap();
+            let mut buf = Vec::new();
+            assert!(read_frame(&mut client, &mut buf).await.unwrap());
+            assert_eq!(buf, b"world!");
+
+            assert_eq!(counters.forwarded(Direction::ToTarget), 5);
+            assert_eq!(counters.forwarded(Direction::FromTarget), 6);
+
+            // Once the client closes its stream, we stop, close ours,
+            // and record what we forwarded.
+            client.close().await.unwrap();
+            relay.await.unwrap();
+            assert!(!read_frame(&mut client, &mut buf).await.unwrap());
+            assert_eq!(proxy.bandwidth_usage().unwrap().bytes, 11);
+        });
+    }
+}
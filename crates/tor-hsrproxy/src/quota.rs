@@ -0,0 +1,236 @@
+//! Accounting for the data that we forward, and enforcement of bandwidth quotas.
+//!
+//! We count the bytes that we forward in both directions on every stream,
+//! grouped into accounting periods (see [`QuotaPeriod`]).
+//! If the proxy was given a place to store its usage,
+//! it saves the count from time to time (see [`SAVE_INTERVAL`]),
+//! so that restarting doesn't give the service a fresh quota.
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tor_error::warn_report;
+use tor_persist::DynStorageHandle;
+
+use crate::config::{BandwidthQuota, QuotaPeriod};
+
+/// How often the proxy saves our usage.
+pub(crate) const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The amount of data that we have forwarded for an onion service during an accounting period.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct BandwidthUsage {
+    /// When the accounting period began.
+    #[serde(with = "humantime_serde")]
+    pub period_start: SystemTime,
+    /// The number of bytes that we have forwarded during this period,
+    /// in either direction.
+    pub bytes: u64,
+}
+
+impl QuotaPeriod {
+    /// Return the start of the period that contains `now`.
+    pub(crate) fn start_of(self, now: SystemTime) -> SystemTime {
+        let now = time::OffsetDateTime::from(now);
+        let date = match self {
+            QuotaPeriod::Daily => now.date(),
+            QuotaPeriod::Monthly => now.date().replace_day(1).expect("No first day of month?"),
+        };
+        date.midnight().assume_utc().into()
+    }
+}
+
+/// Tracks how much data we have forwarded for a single onion service.
+#[derive(Default)]
+pub(crate) struct UsageTracker {
+    /// The mutable state of this tracker.
+    inner: Mutex<Inner>,
+}
+
+/// The mutable state of a [`UsageTracker`].
+#[derive(Default)]
+struct Inner {
+    /// Our usage in the current period, if we have forwarded anything yet.
+    usage: Option<BandwidthUsage>,
+    /// Where to save our usage, if anywhere.
+    storage: Option<DynStorageHandle<BandwidthUsage>>,
+    /// True if `usage` has changed since we last saved it.
+    dirty: bool,
+    /// True if we have already logged that the quota is used up for the current period.
+    logged_exhausted: bool,
+}
+
+impl Inner {
+    /// Return our usage for the `period` that contains `now`,
+    /// starting a new period if necessary.
+    fn current(&mut self, period: QuotaPeriod, now: SystemTime) -> &mut BandwidthUsage {
+        let period_start = period.start_of(now);
+        if self
+            .usage
+            .as_ref()
+            .is_none_or(|usage| usage.period_start != period_start)
+        {
+            self.usage = None;
+            self.logged_exhausted = false;
+            self.dirty = true;
+        }
+        self.usage.get_or_insert_with(|| BandwidthUsage {
+            period_start,
+            bytes: 0,
+        })
+    }
+
+    /// Save our usage, if it has changed and we have somewhere to save it.
+    fn save(&mut self) {
+        let (Some(storage), Some(usage)) = (&self.storage, &self.usage) else {
+            return;
+        };
+        if !self.dirty || !storage.can_store() {
+            return;
+        }
+        match storage.store(usage) {
+            Ok(()) => self.dirty = false,
+            Err(e) => warn_report!(e, "Unable to save onion service bandwidth usage"),
+        }
+    }
+}
+
+impl UsageTracker {
+    /// Use `storage` to save our usage, loading any usage that was saved there before.
+    ///
+    /// Usage from a different accounting period is discarded when it is next used.
+    pub(crate) fn set_storage(
+        &self,
+        storage: DynStorageHandle<BandwidthUsage>,
+    ) -> Result<(), tor_persist::Error> {
+        let loaded = storage.load()?;
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if let Some(loaded) = loaded {
+            match &mut inner.usage {
+                Some(usage) if usage.period_start == loaded.period_start => {
+                    usage.bytes = usage.bytes.saturating_add(loaded.bytes);
+                }
+                Some(usage) if usage.period_start > loaded.period_start => {}
+                usage => *usage = Some(loaded),
+            }
+            inner.dirty = true;
+        }
+        inner.storage = Some(storage);
+        Ok(())
+    }
+
+    /// Return our usage in the current accounting period, if we have forwarded anything in it.
+    pub(crate) fn usage(&self) -> Option<BandwidthUsage> {
+        self.inner.lock().expect("poisoned lock").usage.clone()
+    }
+
+    /// Record that we have forwarded `n_bytes` at `now`, as part of the given `period`.
+    pub(crate) fn note_forwarded(&self, n_bytes: usize, period: QuotaPeriod, now: SystemTime) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let usage = inner.current(period, now);
+        usage.bytes = usage
+            .bytes
+            .saturating_add(n_bytes.try_into().unwrap_or(u64::MAX));
+        inner.dirty = true;
+    }
+
+    /// Save our usage now, if it has changed and we have somewhere to save it.
+    pub(crate) fn save(&self) {
+        self.inner.lock().expect("poisoned lock").save();
+    }
+
+    /// Return true if we have used up `quota` for the period that contains `now`.
+    pub(crate) fn is_exhausted(&self, quota: &BandwidthQuota, now: SystemTime) -> bool {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let exhausted = inner.current(quota.period, now).bytes >= quota.max_bytes;
+        if exhausted && !inner.logged_exhausted {
+            inner.logged_exhausted = true;
+            tracing::info!(
+                "Onion service has used up its bandwidth quota of {} bytes for this period; \
+                 taking action {} on new streams",
+                quota.max_bytes,
+                quota.action,
+            );
+        }
+        exhausted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    use super::*;
+    use humantime_serde::re::humantime::parse_rfc3339;
+    use tor_persist::{StateMgr as _, TestingStateMgr};
+
+    #[test]
+    fn period_start() {
+        let t = parse_rfc3339("2025-03-17T13:45:10Z").unwrap();
+        assert_eq!(
+            QuotaPeriod::Daily.start_of(t),
+            parse_rfc3339("2025-03-17T00:00:00Z").unwrap()
+        );
+        assert_eq!(
+            QuotaPeriod::Monthly.start_of(t),
+            parse_rfc3339("2025-03-01T00:00:00Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn quota() {
+        let quota = BandwidthQuota::new(1000, QuotaPeriod::Daily);
+        let tracker = UsageTracker::default();
+        let day1 = parse_rfc3339("2025-03-17T13:00:00Z").unwrap();
+        let day2 = parse_rfc3339("2025-03-18T01:00:00Z").unwrap();
+
+        assert!(!tracker.is_exhausted(&quota, day1));
+        tracker.note_forwarded(600, quota.period, day1);
+        assert!(!tracker.is_exhausted(&quota, day1));
+        tracker.note_forwarded(400, quota.period, day1);
+        assert!(tracker.is_exhausted(&quota, day1));
+
+        // The next day, we start again.
+        assert!(!tracker.is_exhausted(&quota, day2));
+        assert_eq!(tracker.usage().unwrap().bytes, 0);
+    }
+
+    #[test]
+    fn persistence() {
+        let quota = BandwidthQuota::new(1000, QuotaPeriod::Monthly);
+        let mgr = TestingStateMgr::new();
+        mgr.try_lock().unwrap();
+        let now = parse_rfc3339("2025-03-17T13:00:00Z").unwrap();
+
+        let tracker = UsageTracker::default();
+        tracker
+            .set_storage(mgr.clone().create_handle("hsrproxy_usage"))
+            .unwrap();
+        tracker.note_forwarded(700, quota.period, now);
+        tracker.note_forwarded(100, quota.period, now);
+        tracker.save();
+
+        // A new tracker picks up where the old one left off.
+        let tracker = UsageTracker::default();
+        tracker
+            .set_storage(mgr.create_handle("hsrproxy_usage"))
+            .unwrap();
+        assert_eq!(tracker.usage().unwrap().bytes, 800);
+        tracker.note_forwarded(200, quota.period, now);
+        assert!(tracker.is_exhausted(&quota, now));
+    }
+}
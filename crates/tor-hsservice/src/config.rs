@@ -35,6 +35,17 @@ pub struct OnionServiceConfig {
     #[builder(default = "DEFAULT_NUM_INTRO_POINTS")]
     pub(crate) num_intro_points: u8,
 
+    /// The largest number of introduction points to list in our descriptors.
+    ///
+    /// If we have established more introduction points than this,
+    /// we list only the ones that we expect to keep using for longest.
+    /// The others are still maintained, but are not advertised.
+    ///
+    /// If this is not set, we list all of them.  Must be at least 1.
+    #[builder(default)]
+    #[deftly(publisher_view)]
+    pub(crate) max_advertised_intro_points: Option<u8>,
+
     /// A rate-limit on the acceptable rate of introduction requests.
     ///
     /// We send this to the send to the introduction point to configure how many
@@ -54,6 +65,118 @@ pub struct OnionServiceConfig {
     #[builder(default = "65535")]
     max_concurrent_streams_per_circuit: u32,
 
+    /// Should we pass RESOLVE requests from clients on to the application?
+    ///
+    /// If this is false (the default), we answer every RESOLVE request
+    /// by closing its stream with an error, without telling the application.
+    #[builder(default)]
+    accept_resolve_requests: bool,
+
+    /// How many stream requests on a single circuit will we queue
+    /// while they wait for the application to accept or reject them?
+    ///
+    /// Requests that arrive while the queue is full are rejected.
+    /// If this is not set, we use a default that is large enough for any well-behaved client.
+    /// A value of 0 is treated as 1.
+    #[builder(default)]
+    max_queued_stream_requests_per_circuit: Option<u32>,
+
+    /// A rate-limit on the stream requests we accept on each circuit.
+    ///
+    /// Requests beyond this limit are rejected before the application sees them.
+    /// If this is not set, the rate of stream requests is not limited.
+    #[builder(default)]
+    rate_limit_stream_requests_per_circuit: Option<TokenBucketConfig>,
+
+    /// The percentage of the HsDirs on each HsDir ring that must have accepted
+    /// our latest descriptor for the service to be reported as `Running`.
+    ///
+    /// If our descriptor was uploaded to at least one HsDir on each ring,
+    /// but fewer than this percentage, the service is reported as `DegradedReachable`.
+    ///
+    /// Defaults to 100 (all uploads must succeed); must be between 1 and 100.
+    #[builder(default = "DEFAULT_RUNNING_UPLOAD_PERCENT")]
+    pub(crate) running_upload_percent: u8,
+
+    /// The number of consecutive temporary keystore failures
+    /// that the descriptor publisher tolerates.
+    ///
+    /// If the publisher can't access the keystore because of a problem that might go away
+    /// (for example, an I/O error), it retries with an increasing delay,
+    /// and reports the service as `Recovering`.
+    /// If it fails more than this many times in a row, the service is reported as `Broken`.
+    ///
+    /// Defaults to 5.  If this is 0, the first such failure breaks the service.
+    #[builder(default = "DEFAULT_KEYSTORE_FAILURE_LIMIT")]
+    pub(crate) keystore_failure_limit: u32,
+
+    /// The longest time by which we delay the start of each descriptor upload.
+    ///
+    /// When we publish our descriptor, we start the upload to each HsDir
+    /// after a random delay between zero and this value,
+    /// so that we don't build all the circuits for the uploads at once.
+    ///
+    /// Defaults to 0 (start every upload immediately); must be at most 5 minutes.
+    #[builder(default)]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    #[deftly(publisher_view)]
+    pub(crate) upload_jitter: Duration,
+
+    /// Whether to build a single descriptor for each round of uploads.
+    ///
+    /// By default, we build and sign a fresh descriptor, with a fresh revision counter,
+    /// for each HsDir that we upload to.
+    /// If this is true, we build one descriptor for each time period in each round,
+    /// and upload it to every HsDir of that time period,
+    /// so that we sign (and access the keystore) once per round rather than once per HsDir.
+    #[builder(default)]
+    #[deftly(publisher_view)]
+    pub(crate) one_descriptor_per_round: bool,
+
+    /// The percentage of the uploads to the HsDirs of the current time period
+    /// that must succeed before we start uploading to the HsDirs of the other time periods.
+    ///
+    /// By default, we upload to the HsDirs of all our time periods at once.
+    /// If this is set, we upload each new descriptor to the HsDirs of the current time period
+    /// (the ones that most clients look it up on) first,
+    /// and only start the uploads to the others once this percentage of those uploads
+    /// have succeeded, or once they have all finished, whichever comes first.
+    /// This gets clients to the service sooner
+    /// when we can only build a few circuits at a time, as just after bootstrapping.
+    ///
+    /// Must be between 1 and 100.
+    #[builder(default)]
+    #[deftly(publisher_view)]
+    pub(crate) primary_ring_first_percent: Option<u8>,
+
+    /// Whether to date our descriptors using the consensus, if our clock seems to be wrong.
+    ///
+    /// If the wall clock is well outside the validity period of the latest consensus,
+    /// we report a [`Problem::ClockSkew`](crate::status::Problem::ClockSkew) either way.
+    /// If this is true, we also build and sign our descriptors
+    /// as if the time were the nearest one within that period,
+    /// so that the HsDirs don't reject them as expired or not yet valid.
+    ///
+    /// (Our time periods, and therefore our HsDirs, always come from the consensus.)
+    #[builder(default)]
+    #[deftly(publisher_view)]
+    pub(crate) use_consensus_time_if_skewed: bool,
+
+    /// The lifetime that we advertise in our descriptors.
+    ///
+    /// HsDirs and clients may keep using a descriptor for this long.
+    /// A shorter lifetime lets highly dynamic services get their changes out sooner,
+    /// at the cost of uploading their descriptors more often:
+    /// we reupload each descriptor at a random time
+    /// between a third and two thirds of its lifetime after uploading it.
+    ///
+    /// Defaults to 3 hours (so that we reupload every 60 to 120 minutes);
+    /// must be between 30 minutes and 3 hours.
+    #[builder(default = "MAX_DESCRIPTOR_LIFETIME")]
+    #[builder_field_attr(serde(default, with = "humantime_serde::option"))]
+    #[deftly(publisher_view)]
+    pub(crate) descriptor_lifetime: Duration,
+
     /// If true, we will require proof-of-work when we're under heavy load.
     // TODO POW: If this is set to true but the pow feature is disabled we should error.
     #[builder(default = "false")]
@@ -69,6 +192,36 @@ pub struct OnionServiceConfig {
     #[deftly(publisher_view)]
     #[getter(as_mut)]
     pub(crate) restricted_discovery: RestrictedDiscoveryConfig,
+
+    /// The keystore in which to store the blinded identity keys we derive
+    /// for each time period.
+    ///
+    /// If this is not set, we use the primary keystore.
+    ///
+    /// Only newly derived keys are affected: a key that already exists
+    /// in some other keystore is used from there.
+    #[builder(default)]
+    #[deftly(publisher_view)]
+    pub(crate) blind_id_keystore: Option<KeystoreId>,
+
+    /// The keystore in which to store the descriptor signing keys we generate
+    /// for each time period.
+    ///
+    /// If this is not set, we use the primary keystore.
+    ///
+    /// Only newly generated keys are affected: a key that already exists
+    /// in some other keystore is used from there.
+    #[builder(default)]
+    #[deftly(publisher_view)]
+    pub(crate) desc_signing_keystore: Option<KeystoreId>,
+
+    /// What to do when none of the introduction points we advertise
+    /// can be reached over IPv4.
+    ///
+    /// See [`IptAddrFamilyPolicy`].  Defaults to `warn`.
+    #[builder(default)]
+    #[deftly(publisher_view)]
+    pub(crate) ipt_addr_family_policy: IptAddrFamilyPolicy,
     // TODO(#727): add support for single onion services
     //
     // TODO: Perhaps this belongs at a higher level.  Perhaps we don't need it
@@ -143,6 +296,28 @@ derive_deftly_adhoc! {
 /// Default number of introduction points.
 const DEFAULT_NUM_INTRO_POINTS: u8 = 3;
 
+/// Default value for `running_upload_percent`.
+const DEFAULT_RUNNING_UPLOAD_PERCENT: u8 = 100;
+
+/// Default value for `keystore_failure_limit`.
+const DEFAULT_KEYSTORE_FAILURE_LIMIT: u32 = 5;
+
+/// Largest supported value for `upload_jitter`.
+///
+/// Delaying our uploads for longer than this would leave us unreachable for too long.
+const MAX_UPLOAD_JITTER: Duration = Duration::from_secs(5 * 60);
+
+/// Smallest supported value for `descriptor_lifetime`.
+///
+/// This is the smallest `descriptor-lifetime` permitted by the specification.
+const MIN_DESCRIPTOR_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// Largest supported value for `descriptor_lifetime`, and its default.
+///
+/// This is the lifetime that the IPT manager proposes for every descriptor;
+/// we must not advertise our introduction points for longer than it expects us to.
+const MAX_DESCRIPTOR_LIFETIME: Duration = Duration::from_secs(3 * 60 * 60);
+
 impl OnionServiceConfig {
     /// Check whether an onion service running with this configuration can
     /// switch over `other` according to the rules of `how`.
@@ -217,6 +392,9 @@ impl OnionServiceConfig {
             // as they are rotated out.)
             num_intro_points: simply_update,
 
+            // The descriptor publisher responds by generating and publishing a new descriptor.
+            max_advertised_intro_points: simply_update,
+
             // IPT manager's "new configuration" select arm handles this,
             // by replacing IPTs if necessary.
             rate_limit_at_intro: simply_update,
@@ -224,11 +402,48 @@ impl OnionServiceConfig {
             // We extract this on every introduction request.
             max_concurrent_streams_per_circuit: simply_update,
 
+            // We extract this on every introduction request.
+            accept_resolve_requests: simply_update,
+
+            // We extract this on every introduction request.
+            max_queued_stream_requests_per_circuit: simply_update,
+
+            // We extract this on every introduction request.
+            rate_limit_stream_requests_per_circuit: simply_update,
+
+            // The descriptor publisher uses this the next time it reports its status.
+            running_upload_percent: simply_update,
+
+            // The descriptor publisher uses this the next time it fails to access the keystore.
+            keystore_failure_limit: simply_update,
+
+            // The descriptor publisher uses this for its next upload.
+            upload_jitter: simply_update,
+
+            // The descriptor publisher uses this for its next upload.
+            one_descriptor_per_round: simply_update,
+
+            // The descriptor publisher uses this for its next upload.
+            primary_ring_first_percent: simply_update,
+
+            // The descriptor publisher uses this the next time it builds a descriptor.
+            use_consensus_time_if_skewed: simply_update,
+
+            // The descriptor publisher responds by generating and publishing a new descriptor.
+            descriptor_lifetime: simply_update,
+
             // The descriptor publisher responds by generating and publishing a new descriptor.
             restricted_discovery: simply_update,
 
             // TODO POW: Verify that simply_update has correct behaviour here.
             enable_pow: simply_update,
+
+            // The descriptor publisher uses these the next time it needs a new key.
+            blind_id_keystore: simply_update,
+            desc_signing_keystore: simply_update,
+
+            // The descriptor publisher checks this the next time the IPTs change.
+            ipt_addr_family_policy: simply_update,
         }
 
         Ok(other)
@@ -250,8 +465,21 @@ impl OnionServiceConfig {
     pub(crate) fn filter_settings(&self) -> crate::rend_handshake::RequestFilter {
         crate::rend_handshake::RequestFilter {
             max_concurrent_streams: self.max_concurrent_streams_per_circuit as usize,
+            accept_resolve: self.accept_resolve_requests,
         }
     }
+
+    /// Return the bounds on each circuit's queue of stream requests for this configuration.
+    pub(crate) fn stream_queue_params(&self) -> tor_proto::stream::IncomingStreamQueueParams {
+        let mut params = tor_proto::stream::IncomingStreamQueueParams::new();
+        if let Some(depth) = self.max_queued_stream_requests_per_circuit {
+            params.depth(depth as usize);
+        }
+        if let Some(limit) = &self.rate_limit_stream_requests_per_circuit {
+            params.rate_limit(limit.rate, limit.burst);
+        }
+        params
+    }
 }
 
 impl OnionServiceConfigBuilder {
@@ -279,6 +507,56 @@ impl OnionServiceConfigBuilder {
             }
         }
 
+        if let Some(Some(0)) = self.max_advertised_intro_points {
+            return Err(ConfigBuildError::Invalid {
+                field: "max_advertised_intro_points".into(),
+                problem: "must be at least 1".into(),
+            });
+        }
+
+        if let Some(percent) = self.running_upload_percent {
+            if !(1..=100).contains(&percent) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "running_upload_percent".into(),
+                    problem: "out of range 1-100".into(),
+                });
+            }
+        }
+
+        if let Some(Some(percent)) = self.primary_ring_first_percent {
+            if !(1..=100).contains(&percent) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "primary_ring_first_percent".into(),
+                    problem: "out of range 1-100".into(),
+                });
+            }
+        }
+
+        if let Some(jitter) = self.upload_jitter {
+            if jitter > MAX_UPLOAD_JITTER {
+                return Err(ConfigBuildError::Invalid {
+                    field: "upload_jitter".into(),
+                    problem: format!(
+                        "more than {}",
+                        humantime::format_duration(MAX_UPLOAD_JITTER)
+                    ),
+                });
+            }
+        }
+
+        if let Some(lifetime) = self.descriptor_lifetime {
+            if !(MIN_DESCRIPTOR_LIFETIME..=MAX_DESCRIPTOR_LIFETIME).contains(&lifetime) {
+                return Err(ConfigBuildError::Invalid {
+                    field: "descriptor_lifetime".into(),
+                    problem: format!(
+                        "not between {} and {}",
+                        humantime::format_duration(MIN_DESCRIPTOR_LIFETIME),
+                        humantime::format_duration(MAX_DESCRIPTOR_LIFETIME)
+                    ),
+                });
+            }
+        }
+
         // Make sure that our rate_limit_at_intro is valid.
         if let Some(Some(ref rate_limit)) = self.rate_limit_at_intro {
             let _ignore_extension: est_intro::DosParams =
@@ -294,6 +572,32 @@ impl OnionServiceConfigBuilder {
     }
 }
 
+/// What the descriptor publisher does when our advertised introduction points
+/// lack address family diversity.
+///
+/// The set of introduction points we advertise lacks diversity
+/// if none of them can be reached over IPv4
+/// (according to the consensus, or failing that, to their link specifiers),
+/// since many clients can't reach relays over IPv6.
+/// A set of introduction points that can only be reached over IPv4 is fine.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum IptAddrFamilyPolicy {
+    /// Don't check our introduction points' address families.
+    Ignore,
+    /// Log a warning, and publish our descriptor anyway.
+    #[default]
+    Warn,
+    /// Log a warning, and postpone publishing our descriptor
+    /// in the hope that the introduction point manager will pick
+    /// more diverse introduction points.
+    ///
+    /// We don't postpone publication for more than a few minutes:
+    /// after that, we publish whatever introduction points we have.
+    Delay,
+}
+
 /// Configure a token-bucket style limit on some process.
 //
 // TODO: Someday we may wish to lower this; it will be used in far more places.
@@ -94,6 +94,8 @@
 
 mod key_provider;
 
+use key_provider::ClientKeyEntry;
+
 pub use key_provider::{
     DirectoryKeyProvider, DirectoryKeyProviderBuilder, DirectoryKeyProviderList,
     DirectoryKeyProviderListBuilder, StaticKeyProvider, StaticKeyProviderBuilder,
@@ -109,6 +111,7 @@ use derive_more::{Display, Into};
 
 use tor_config_path::CfgPathResolver;
 use tor_error::warn_report;
+use tor_hscrypto::pk::HsClientDescEncKeyParseError;
 use tor_persist::slug::BadSlug;
 
 /// The recommended maximum number of restricted mode clients.
@@ -159,6 +162,52 @@ impl FromStr for HsClientNickname {
     }
 }
 
+/// An entry from one of the `key_dirs` that we did not accept as an authorized client key.
+///
+/// The client the entry was meant for can't discover the service,
+/// so the rejected entries are reported in the service's status
+/// (see [`Problem::RestrictedDiscoveryRejectedKeys`](crate::status::Problem::RestrictedDiscoveryRejectedKeys)).
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("Rejected client key entry {}", path.display_lossy())]
+#[non_exhaustive]
+pub struct RejectedClientKey {
+    /// The path of the rejected entry.
+    pub path: PathBuf,
+    /// Why we rejected it.
+    #[source]
+    pub reason: ClientKeyRejection,
+}
+
+/// The reason why we rejected a [`RejectedClientKey`].
+//
+// Note: there is no variant for expired keys, because the
+// `descriptor:x25519:` format doesn't have a notion of expiry.
+#[derive(Clone, Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ClientKeyRejection {
+    /// The entry isn't a key file (for example, it doesn't have the `.auth` extension).
+    #[error("Not a valid key entry: {0}")]
+    InvalidEntry(String),
+
+    /// The file name isn't a valid [`HsClientNickname`].
+    #[error("Invalid client nickname")]
+    BadNickname(#[source] BadSlug),
+
+    /// We couldn't read the file.
+    #[error("Inaccessible file or bad permissions")]
+    Unreadable(#[source] fs_mistrust::Error),
+
+    /// The file doesn't contain a validly encoded key.
+    #[error("Invalid key encoding")]
+    BadEncoding(#[source] HsClientDescEncKeyParseError),
+
+    /// An entry with a higher precedence has the same client nickname.
+    ///
+    /// See [`RestrictedDiscoveryConfig`] for the precedence rules.
+    #[error("Duplicate client nickname {0}")]
+    DuplicateNickname(HsClientNickname),
+}
+
 /// Configuration for enabling restricted discovery mode.
 ///
 /// # Client nickname uniqueness
@@ -168,7 +217,8 @@ impl FromStr for HsClientNickname {
 /// already have an entry in any of the configured `key_dirs`,
 /// and any one nickname must not occur in more than one of the `key_dirs`.
 ///
-/// Violating this rule will cause the additional keys to be ignored.
+/// Violating this rule will cause the additional keys to be ignored
+/// (and reported as [`RejectedClientKey`]s).
 /// If there are multiple entries for the same nickname,
 /// the entry with the highest precedence will be used, and all the others will be ignored.
 /// The precedence rules are as follows:
@@ -230,6 +280,8 @@ impl RestrictedDiscoveryConfig {
     /// Read the client keys from all the configured key providers.
     ///
     /// Returns `None` if restricted mode is disabled.
+    /// Otherwise, returns the authorized client keys,
+    /// and the entries from `key_dirs` we rejected, sorted by path.
     ///
     // TODO: this is not currently implemented (reconfigure() doesn't call read_keys)
     /// When reconfiguring a [`RunningOnionService`](crate::RunningOnionService),
@@ -238,32 +290,33 @@ impl RestrictedDiscoveryConfig {
     // TODO: this is a footgun. We might want to rethink this before we make
     // the restricted-discovery feature non-experimental:
     /// Note: if there are multiple entries for the same [`HsClientNickname`],
-    /// only one of them will be used (the others are ignored).
+    /// only one of them will be used (the others are rejected).
     /// The deduplication logic is as follows:
     ///   * the `static_keys` take precedence over the keys from `key_dirs`
     ///   * the ordering of the directories in `key_dirs` represents the order of precedence
     pub(crate) fn read_keys(
         &self,
         path_resolver: &CfgPathResolver,
-    ) -> Option<RestrictedDiscoveryKeys> {
+    ) -> Option<(RestrictedDiscoveryKeys, Vec<RejectedClientKey>)> {
         if !self.enabled {
             return None;
         }
 
-        let mut authorized_clients = BTreeMap::new();
-
         // The static_keys are inserted first, so they have precedence over
         // the keys from key_dirs.
-        extend_key_map(
-            &mut authorized_clients,
-            RestrictedDiscoveryKeys::from(self.static_keys.clone()),
-        );
+        //
+        // They can't contain duplicates (the builder rejects them).
+        let mut authorized_clients = RestrictedDiscoveryKeys::from(self.static_keys.clone());
+        let mut rejected = vec![];
 
         // The key_dirs are read in order of appearance,
         // which is also the order of precedence.
         for dir in &self.key_dirs {
             match dir.read_keys(path_resolver) {
-                Ok(keys) => extend_key_map(&mut authorized_clients, keys),
+                Ok((keys, dir_rejected)) => {
+                    rejected.extend(dir_rejected);
+                    extend_key_map(&mut authorized_clients, &mut rejected, keys);
+                }
                 Err(e) => {
                     warn_report!(e, "Failed to read keys at {}", dir.path());
                 }
@@ -277,18 +330,21 @@ impl RestrictedDiscoveryConfig {
             );
         }
 
-        Some(authorized_clients)
+        rejected.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Some((authorized_clients, rejected))
     }
 }
 
-/// Helper for extending a key map with additional keys.
+/// Helper for extending a key map with the keys read from a key directory.
 ///
-/// Logs a warning if any of the keys are already present in the map.
+/// Any keys whose nickname is already present in the map are added to `rejected`.
 fn extend_key_map(
     key_map: &mut RestrictedDiscoveryKeys,
-    keys: impl IntoIterator<Item = (HsClientNickname, HsClientDescEncKey)>,
+    rejected: &mut Vec<RejectedClientKey>,
+    keys: impl IntoIterator<Item = ClientKeyEntry>,
 ) {
-    for (nickname, key) in keys.into_iter() {
+    for (path, nickname, key) in keys.into_iter() {
         match key_map.entry(nickname.clone()) {
             Entry::Vacant(v) => {
                 let _: &mut HsClientDescEncKey = v.insert(key);
@@ -298,6 +354,10 @@ fn extend_key_map(
                     client_nickname=%nickname,
                     "Ignoring duplicate client key"
                 );
+                rejected.push(RejectedClientKey {
+                    path,
+                    reason: ClientKeyRejection::DuplicateNickname(nickname),
+                });
             }
         }
     }
@@ -503,6 +563,7 @@ mod test {
             restricted_config
                 .read_keys(&path_resolver)
                 .unwrap()
+                .0
                 .is_empty()
         );
     }
@@ -602,6 +663,7 @@ mod test {
             let mut authorized_clients = config
                 .read_keys(&path_resolver)
                 .unwrap()
+                .0
                 .into_iter()
                 .collect_vec();
             authorized_clients.sort_by(|k1, k2| k1.0.cmp(&k2.0));
@@ -655,7 +717,7 @@ mod test {
         builder.key_dirs().access().extend([key_dir1, key_dir2]);
         let config = builder.build().unwrap();
         let path_resolver = CfgPathResolver::default();
-        let keys = config.read_keys(&path_resolver).unwrap();
+        let (keys, rejected) = config.read_keys(&path_resolver).unwrap();
 
         // Check that foo is the entry we inserted into static_keys:
         let foo_key_found = keys.get(&foo_nick).unwrap();
@@ -665,6 +727,21 @@ mod test {
         // (dir1 takes precedence over dir2)
         let bar_key_found = keys.get(&bar_nick).unwrap();
         assert_eq!(bar_key_found, &bar_key1);
+
+        // The entries that lost are reported as duplicates
+        let rejected = rejected
+            .iter()
+            .map(|r| match &r.reason {
+                ClientKeyRejection::DuplicateNickname(nick) => (r.path.clone(), nick.clone()),
+                _ => panic!("unexpected rejection {r:?}"),
+            })
+            .collect_vec();
+        let mut expected = vec![
+            (dir1.path().join("foo.auth"), foo_nick),
+            (dir2.path().join("bar.auth"), bar_nick),
+        ];
+        expected.sort();
+        assert_eq!(rejected, expected);
     }
 
     #[test]
@@ -706,6 +783,20 @@ mod test {
         let config = builder.build().unwrap();
 
         let path_resolver = CfgPathResolver::default();
-        assert_eq!(config.read_keys(&path_resolver).unwrap().len(), VALID_COUNT);
+        let (keys, rejected) = config.read_keys(&path_resolver).unwrap();
+        assert_eq!(keys.len(), VALID_COUNT);
+
+        // The malformed entries are reported, sorted by path
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0].path, dir.path().join("bar.not_auth"));
+        assert!(matches!(
+            rejected[0].reason,
+            ClientKeyRejection::InvalidEntry(_)
+        ));
+        assert_eq!(rejected[1].path, dir.path().join("foo.auth"));
+        assert!(matches!(
+            rejected[1].reason,
+            ClientKeyRejection::BadEncoding(_)
+        ));
     }
 }
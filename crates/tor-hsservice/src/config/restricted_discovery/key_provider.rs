@@ -1,6 +1,8 @@
 //! Service discovery client key providers.
 
-use crate::config::restricted_discovery::HsClientNickname;
+use crate::config::restricted_discovery::{
+    ClientKeyRejection, HsClientNickname, RejectedClientKey,
+};
 use crate::internal_prelude::*;
 
 use std::collections::BTreeMap;
@@ -16,8 +18,6 @@ use tor_config::define_list_builder_helper;
 use tor_config::mistrust::BuilderExt as _;
 use tor_config_path::{CfgPath, CfgPathError, CfgPathResolver};
 use tor_error::warn_report;
-use tor_hscrypto::pk::HsClientDescEncKeyParseError;
-use tor_persist::slug::BadSlug;
 
 /// A static mapping from [`HsClientNickname`] to client discovery keys.
 #[serde_with::serde_as]
@@ -104,6 +104,9 @@ pub struct DirectoryKeyProvider {
     permissions: Mistrust,
 }
 
+/// A client key read from a [`DirectoryKeyProvider`], with the path of its file.
+pub(super) type ClientKeyEntry = (PathBuf, HsClientNickname, HsClientDescEncKey);
+
 /// The serialized format of a [`DirectoryKeyProviderListBuilder`]:
 pub type DirectoryKeyProviderList = Vec<DirectoryKeyProvider>;
 
@@ -117,10 +120,12 @@ define_list_builder_helper! {
 
 impl DirectoryKeyProvider {
     /// Read the client service discovery keys from the specified directory.
+    ///
+    /// Returns the valid keys, and the entries we rejected.
     pub(super) fn read_keys(
         &self,
         path_resolver: &CfgPathResolver,
-    ) -> Result<Vec<(HsClientNickname, HsClientDescEncKey)>, DirectoryKeyProviderError> {
+    ) -> Result<(Vec<ClientKeyEntry>, Vec<RejectedClientKey>), DirectoryKeyProviderError> {
         let dir_path = self.path.path(path_resolver).map_err(|err| {
             DirectoryKeyProviderError::PathExpansionFailed {
                 path: self.path.clone(),
@@ -137,45 +142,59 @@ impl DirectoryKeyProvider {
                 err,
             })?;
 
+        let mut keys = vec![];
+        let mut rejected = vec![];
+
         // TODO: should this be a method on CheckedDir?
-        Ok(fs::read_dir(checked_dir.as_path())
+        for entry in fs::read_dir(checked_dir.as_path())
             .map_err(|e| DirectoryKeyProviderError::IoError(Arc::new(e)))?
-            .flat_map(|entry| match read_key_file(&checked_dir, entry) {
-                Ok((client_nickname, key)) => Some((client_nickname, key)),
+        {
+            let entry = match entry {
+                Ok(entry) => entry,
                 Err(e) => {
-                    warn_report!(e, "Failed to read client discovery key",);
-                    None
+                    warn_report!(e, "Failed to read client discovery key directory entry");
+                    continue;
                 }
-            })
-            .collect_vec())
+            };
+
+            match read_key_file(&checked_dir, &entry) {
+                Ok((client_nickname, key)) => keys.push((entry.path(), client_nickname, key)),
+                Err(reason) => {
+                    let e = RejectedClientKey {
+                        path: entry.path(),
+                        reason,
+                    };
+                    warn_report!(e, "Rejecting client discovery key");
+                    rejected.push(e);
+                }
+            }
+        }
+
+        Ok((keys, rejected))
     }
 }
 
-/// Read the client key at  `path`.
+/// Read the client key from the directory `entry`.
 fn read_key_file(
     checked_dir: &CheckedDir,
-    entry: io::Result<DirEntry>,
-) -> Result<(HsClientNickname, HsClientDescEncKey), DirectoryKeyProviderError> {
+    entry: &DirEntry,
+) -> Result<(HsClientNickname, HsClientDescEncKey), ClientKeyRejection> {
     /// The extension the client key files are expected to have.
     const KEY_EXTENSION: &str = "auth";
 
-    let entry = entry.map_err(|e| DirectoryKeyProviderError::IoError(Arc::new(e)))?;
-
     if entry.path().is_dir() {
-        return Err(DirectoryKeyProviderError::InvalidKeyDirectoryEntry {
-            path: entry.path(),
-            problem: "entry is a directory".into(),
-        });
+        return Err(ClientKeyRejection::InvalidEntry(
+            "entry is a directory".into(),
+        ));
     }
 
     let file_name = entry.file_name();
     let file_name: &Path = file_name.as_ref();
     let extension = file_name.extension().and_then(|e| e.to_str());
     if extension != Some(KEY_EXTENSION) {
-        return Err(DirectoryKeyProviderError::InvalidKeyDirectoryEntry {
-            path: file_name.into(),
-            problem: "invalid extension (file must end in .auth)".into(),
-        });
+        return Err(ClientKeyRejection::InvalidEntry(
+            "invalid extension (file must end in .auth)".into(),
+        ));
     }
 
     // We unwrap_or_default() instead of returning an error if the file stem is None,
@@ -184,21 +203,15 @@ fn read_key_file(
         .file_stem()
         .and_then(|e| e.to_str())
         .unwrap_or_default();
-    let client_nickname = HsClientNickname::from_str(client_nickname)?;
+    let client_nickname =
+        HsClientNickname::from_str(client_nickname).map_err(ClientKeyRejection::BadNickname)?;
 
-    let key = checked_dir.read_to_string(file_name).map_err(|err| {
-        DirectoryKeyProviderError::FsMistrust {
-            path: entry.path(),
-            err,
-        }
-    })?;
+    let key = checked_dir
+        .read_to_string(file_name)
+        .map_err(ClientKeyRejection::Unreadable)?;
 
-    let parsed_key = HsClientDescEncKey::from_str(key.trim()).map_err(|err| {
-        DirectoryKeyProviderError::KeyParse {
-            path: entry.path(),
-            err,
-        }
-    })?;
+    let parsed_key =
+        HsClientDescEncKey::from_str(key.trim()).map_err(ClientKeyRejection::BadEncoding)?;
 
     Ok((client_nickname, parsed_key))
 }
@@ -229,27 +242,4 @@ pub(super) enum DirectoryKeyProviderError {
         #[source]
         err: CfgPathError,
     },
-
-    /// Found an invalid key entry.
-    #[error("{path} is not a valid key entry: {problem}")]
-    InvalidKeyDirectoryEntry {
-        /// The path of the key we were trying to read.
-        path: PathBuf,
-        /// The problem we encountered.
-        problem: String,
-    },
-
-    /// Failed to parse a client nickname.
-    #[error("Invalid client nickname")]
-    ClientNicknameParse(#[from] BadSlug),
-
-    /// Failed to parse a key.
-    #[error("Failed to parse key at {path}")]
-    KeyParse {
-        /// The path of the key we were trying to parse.
-        path: PathBuf,
-        /// The underlying error.
-        #[source]
-        err: HsClientDescEncKeyParseError,
-    },
 }
@@ -129,6 +129,10 @@ pub enum ClientError {
     /// Failed to send a END message and reject a stream.
     #[error("Could not reject stream from rendezvous circuit")]
     RejectStream(#[source] tor_proto::Error),
+
+    /// Failed to send a RESOLVED message in answer to a RESOLVE request.
+    #[error("Could not answer resolve request from rendezvous circuit")]
+    AnswerResolve(#[source] tor_proto::Error),
 }
 
 impl HasKind for ClientError {
@@ -138,6 +142,7 @@ impl HasKind for ClientError {
             ClientError::EstablishSession(e) => e.kind(),
             ClientError::AcceptStream(e) => e.kind(),
             ClientError::RejectStream(e) => e.kind(),
+            ClientError::AnswerResolve(e) => e.kind(),
         }
     }
 }
@@ -277,6 +282,18 @@ impl FatalError {
             cause: Arc::new(err),
         }
     }
+
+    /// Return true if this is a failure to access the keystore
+    /// that might go away if we try again later.
+    ///
+    /// (For example, an I/O error while reading a key,
+    /// as opposed to a corrupted keystore or a missing key.)
+    pub(crate) fn is_transient_keystore_error(&self) -> bool {
+        matches!(
+            self,
+            FatalError::Keystore(_) | FatalError::KeystoreRace { .. }
+        ) && self.kind() == ErrorKind::KeystoreAccessFailed
+    }
 }
 
 impl HasKind for FatalError {
@@ -78,7 +78,7 @@ pub(crate) use {
     tor_circmgr::hspool::HsCircPool,
     tor_config::{ConfigBuildError, Reconfigure, ReconfigureError},
     tor_dirclient::request::HsDescUploadRequest,
-    tor_dirclient::{Error as DirClientError, RequestFailedError, send_request},
+    tor_dirclient::{Error as DirClientError, RequestError, RequestFailedError, send_request},
     tor_error::define_asref_dyn_std_error,
     tor_error::{Bug, ErrorKind, ErrorReport as _, HasKind},
     tor_error::{bad_api_usage, internal, into_bad_api_usage, into_internal},
@@ -92,7 +92,7 @@ pub(crate) use {
     tor_hscrypto::time::TimePeriod,
     tor_keymgr::{
         KeyMgr, KeySpecifier, KeySpecifierComponentViaDisplayFromStr, KeySpecifierPattern as _,
-        KeystoreSelector, derive_deftly_template_KeySpecifier,
+        KeystoreId, KeystoreSelector, derive_deftly_template_KeySpecifier,
         {ArtiPathRange, KeySpecifierComponent},
     },
     tor_linkspec::{
@@ -109,7 +109,7 @@ pub(crate) use {
     },
     tor_proto::MetaCellDisposition,
     tor_proto::stream::DataStream,
-    tor_rtcompat::SleepProvider,
+    tor_rtcompat::{DynTimeProvider, SleepProvider},
     tor_rtcompat::{Runtime, SleepProviderExt as _},
 };
 
@@ -128,12 +128,13 @@ pub(crate) use {
     crate::ipt_set::IptsManagerView,
     crate::ipt_set::IptsPublisherUploadView,
     crate::ipt_set::IptsPublisherView,
-    crate::ipt_set::{self, PublishIptSet},
+    crate::ipt_set::{self, PublishIptSet, PublishOutcome},
     crate::keys::expire_publisher_keys,
     crate::keys::{IptKeyRole, IptKeySpecifier, IptKeySpecifierPattern},
     crate::publish::Publisher,
     crate::replay::IptReplayLog,
     crate::replay::ReplayError,
+    crate::self_test::{ReachabilityTestError, ReachabilityTester, SelfTester},
     crate::status::PublisherStatusSender,
     crate::status::State,
     crate::status::{IptMgrStatusSender, State as IptMgrState},
@@ -149,7 +150,7 @@ pub(crate) use {
         keys::BlindIdKeypairSpecifierPattern,
         rend_handshake::{self, RendCircConnector},
     },
-    crate::{DescUploadError, IptError},
+    crate::{DescUploadError, HsDirRejection, IptError},
     crate::{FatalError, RendRequest},
     crate::{HsNickname, LinkSpecs, NtorPublicKey, req::RendRequestContext},
     crate::{ShutdownStatus, ipt_establish},
@@ -318,6 +318,7 @@ impl IptEstablisher {
             kp_hss_ntor: Arc::clone(&k_ntor),
             kp_hs_ipt_sid: k_sid.as_ref().as_ref().verifying_key().into(),
             filter: config.filter_settings(),
+            stream_queue: config.stream_queue_params(),
             netdir_provider: netdir_provider.clone(),
             circ_pool: pool.clone(),
         });
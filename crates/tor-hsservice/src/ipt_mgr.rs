@@ -26,6 +26,17 @@ const IPT_PUBLISH_UNCERTAIN: Duration = Duration::from_secs(3 * 60 * 60); // 3 h
 /// Expiry time to put on a final descriptor (IPT publication set Certain
 const IPT_PUBLISH_CERTAIN: Duration = IPT_PUBLISH_UNCERTAIN;
 
+/// How many times in a row the publisher can completely fail to publish, before we replace IPTs
+///
+/// Each time, the publisher has failed to upload our descriptor
+/// to *any* of the HsDirs for the current time period.
+/// That might be due to a problem with our introduction points
+/// (for example, the descriptor might be rejected),
+/// so we try again with new ones.
+///
+/// TODO: This is something we might want to tune based on experience.
+const PUBLISH_FAILURES_BEFORE_IPT_REPLACEMENT: u32 = 3;
+
 //========== data structures ==========
 
 /// IPT Manager (for one hidden service)
@@ -121,6 +132,11 @@ pub(crate) struct State<R, M> {
     #[educe(Debug(ignore))]
     ipt_removal_cleanup_needed: bool,
 
+    /// How many times in a row has the publisher completely failed to publish?
+    ///
+    /// Reset when we replace our IPTs, or when the publisher succeeds.
+    consecutive_publish_failures: u32,
+
     /// Signal for us to shut down
     shutdown: broadcast::Receiver<Void>,
 
@@ -655,6 +671,7 @@ impl<R: Runtime, M: Mockable<R>> IptManager<R, M> {
             irelays,
             last_irelay_selection_outcome: Ok(()),
             ipt_removal_cleanup_needed: false,
+            consecutive_publish_failures: 0,
             runtime: PhantomData,
         };
         let mgr = IptManager { imm, state };
@@ -1197,7 +1214,7 @@ impl<R: Runtime, M: Mockable<R>> IptManager<R, M> {
 
         publish_set.ipts = if let Some(lifetime) = publish_lifetime {
             let selected = self.publish_set_select();
-            for ipt in &selected {
+            for (_, ipt) in &selected {
                 self.state.mockable.start_accepting(&*ipt.establisher);
             }
             Some(Self::make_publish_set(selected, lifetime)?)
@@ -1221,14 +1238,15 @@ impl<R: Runtime, M: Mockable<R>> IptManager<R, M> {
     /// The returned list is in the same order as our data structure:
     /// firstly, by the ordering in `State.irelays`, and then within each relay,
     /// by the ordering in `IptRelay.ipts`.  Both of these are stable.
+    /// Each IPT is returned along with its relay.
     ///
     /// ### Performance
     ///
     /// This function is at worst O(N) where N is the number of IPTs.
     /// See the performance note on [`run_once()`](Self::run_once).
-    fn publish_set_select(&self) -> VecDeque<&Ipt> {
+    fn publish_set_select(&self) -> VecDeque<(&IptRelay, &Ipt)> {
         /// Good candidate introduction point for publication
-        type Candidate<'i> = &'i Ipt;
+        type Candidate<'i> = (&'i IptRelay, &'i Ipt);
 
         let target_n = self.target_n_intro_points();
 
@@ -1241,7 +1259,7 @@ impl<R: Runtime, M: Mockable<R>> IptManager<R, M> {
                 if !current_ipt.is_good() {
                     return None;
                 }
-                Some(current_ipt)
+                Some((ir, current_ipt))
             })
             .collect();
 
@@ -1288,12 +1306,12 @@ impl<R: Runtime, M: Mockable<R>> IptManager<R, M> {
     /// This function is at worst O(N) where N is the number of IPTs.
     /// See the performance note on [`run_once()`](Self::run_once).
     fn make_publish_set<'i>(
-        selected: impl IntoIterator<Item = &'i Ipt>,
+        selected: impl IntoIterator<Item = (&'i IptRelay, &'i Ipt)>,
         lifetime: Duration,
     ) -> Result<ipt_set::IptSet, FatalError> {
         let ipts = selected
             .into_iter()
-            .map(|current_ipt| {
+            .map(|(ir, current_ipt)| {
                 let TS::Good { details, .. } = &current_ipt.status_last else {
                     return Err(internal!("was good but now isn't?!").into());
                 };
@@ -1305,6 +1323,7 @@ impl<R: Runtime, M: Mockable<R>> IptManager<R, M> {
                 let publish = ipt_set::IptInSet {
                     ipt: publish,
                     lid: current_ipt.lid,
+                    planned_retirement: ir.planned_retirement,
                 };
 
                 Ok::<_, FatalError>(publish)
@@ -1521,6 +1540,10 @@ impl<R: Runtime, M: Mockable<R>> IptManager<R, M> {
                 self.state.handle_ipt_status_update(&self.imm, lid, update);
             }
 
+            outcome = publisher.await_publish_outcome().fuse() => {
+                self.state.handle_publish_outcome(&self.imm, outcome);
+            }
+
             _dir_event = async {
                 match self.state.last_irelay_selection_outcome {
                     Ok(()) => future::pending().await,
@@ -1663,6 +1686,36 @@ impl<R: Runtime, M: Mockable<R>> State<R, M> {
         Ok(())
     }
 
+    /// Update `self`'s record of how the publisher is doing
+    ///
+    /// If the publisher keeps failing completely, we replace all our IPTs.
+    /// (The previously-published IPTs will automatically be retained so long as needed,
+    /// by the rest of our algorithm.)
+    fn handle_publish_outcome(&mut self, imm: &Immutable<R>, outcome: PublishOutcome) {
+        match outcome {
+            PublishOutcome::Succeeded => {
+                self.consecutive_publish_failures = 0;
+                return;
+            }
+            PublishOutcome::Failed => self.consecutive_publish_failures += 1,
+        }
+
+        if self.consecutive_publish_failures < PUBLISH_FAILURES_BEFORE_IPT_REPLACEMENT {
+            return;
+        }
+
+        info!(
+            "HS service {}: replacing IPTs: failed to publish descriptor {} times in a row",
+            &imm.nick, self.consecutive_publish_failures,
+        );
+        for ir in &mut self.irelays {
+            for ipt in &mut ir.ipts {
+                ipt.is_current = None;
+            }
+        }
+        self.consecutive_publish_failures = 0;
+    }
+
     /// Update `self`'s status tracking for one introduction point
     fn handle_ipt_status_update(&mut self, imm: &Immutable<R>, lid: IptLocalId, update: IptStatus) {
         let Some(ipt) = self.ipt_by_lid_mut(lid) else {
@@ -1804,7 +1857,7 @@ mod test {
     use crate::test::{create_keymgr, create_storage_handles_from_state_dir};
     use rand::SeedableRng as _;
     use slotmap_careful::DenseSlotMap;
-    use std::collections::BTreeMap;
+    use std::collections::{BTreeMap, BTreeSet};
     use std::sync::Mutex;
     use test_temp_dir::{TestTempDir, test_temp_dir};
     use tor_basic_utils::test_rng::TestingRng;
@@ -1940,7 +1993,11 @@ mod test {
 
             let keymgr = create_keymgr(temp_dir);
             let keymgr = keymgr.into_untracked(); // OK because our return value captures 'd
-            let status_tx = StatusSender::new(OnionServiceStatus::new_shutdown()).into();
+            let status_tx = StatusSender::new(
+                OnionServiceStatus::new_shutdown(),
+                DynTimeProvider::new(runtime.clone()),
+            )
+            .into();
             let mgr = IptManager::new(
                 runtime.clone(),
                 Arc::new(dir),
@@ -1973,6 +2030,11 @@ mod test {
             assert_eq!(runtime.mock_task().n_tasks(), 1); // just us
         }
 
+        fn estabs_inventory_lids(&self) -> BTreeSet<IptLocalId> {
+            let estabs = self.estabs.lock().unwrap();
+            estabs.values().map(|e| e.params.lid).collect()
+        }
+
         fn estabs_inventory(&self) -> impl Eq + Debug + 'static + use<> {
             let estabs = self.estabs.lock().unwrap();
             estabs
@@ -2129,4 +2191,40 @@ mod test {
             m.shutdown_check_no_tasks(&runtime).await;
         });
     }
+
+    #[test]
+    #[traced_test]
+    fn test_mgr_publish_failures() {
+        MockRuntime::test_with_various(|runtime| async move {
+            let temp_dir = test_temp_dir!();
+
+            let mut m = MockedIptManager::startup(runtime.clone(), &temp_dir, 0, usize::MAX);
+            runtime.progress_until_stalled().await;
+
+            let old_lids = m.estabs_inventory_lids();
+            assert_eq!(old_lids.len(), 3);
+
+            // Occasional failures, interspersed with successes, are tolerated
+            for outcome in [
+                PublishOutcome::Failed,
+                PublishOutcome::Failed,
+                PublishOutcome::Succeeded,
+                PublishOutcome::Failed,
+                PublishOutcome::Failed,
+            ] {
+                m.pub_view.note_publish_outcome(outcome);
+                runtime.progress_until_stalled().await;
+            }
+            assert_eq!(m.estabs_inventory_lids(), old_lids);
+
+            // But repeated failures cause all the IPTs to be replaced
+            m.pub_view.note_publish_outcome(PublishOutcome::Failed);
+            runtime.progress_until_stalled().await;
+            let new_lids = m.estabs_inventory_lids();
+            assert_eq!(new_lids.len(), 3);
+            assert!(new_lids.is_disjoint(&old_lids));
+
+            m.shutdown_check_no_tasks(&runtime).await;
+        });
+    }
 }
@@ -112,6 +112,13 @@ pub(crate) struct IptInSet {
     /// Set and used by the manager, to correlate this data structure with the manager's.
     /// May also be read by the publisher.
     pub(crate) lid: IptLocalId,
+
+    /// When the manager plans to stop using this introduction point's relay
+    ///
+    /// Set by the manager.
+    /// Read by the publisher, to decide which introduction points to advertise
+    /// if it is configured to advertise fewer than it has been given.
+    pub(crate) planned_retirement: Instant,
 }
 
 /// Actual introduction point details as specified to publisher by manager
@@ -136,6 +143,24 @@ pub(crate) type Ipt = tor_netdoc::doc::hsdesc::IntroPointDesc;
 const IPT_PUBLISH_EXPIRY_SLOP: Duration =
     Duration::from_secs(10 * 60).saturating_add(crate::publish::OVERALL_UPLOAD_TIMEOUT);
 
+/// How many publication outcomes can be queued for the manager
+///
+/// If the manager falls this far behind, further outcomes are discarded.
+/// This doesn't matter much: the manager only uses them as a heuristic.
+const PUBLISH_OUTCOME_BUFFER: usize = 8;
+
+/// Outcome of a batch of descriptor uploads for the current time period
+///
+/// Reported by the publisher to the manager,
+/// via [`IptsPublisherView::note_publish_outcome`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum PublishOutcome {
+    /// At least one of the uploads succeeded
+    Succeeded,
+    /// Every one of the uploads failed
+    Failed,
+}
+
 /// Shared view of introduction points - IPT manager's view
 ///
 /// This is the manager's end of a bidirectional "channel",
@@ -151,6 +176,9 @@ pub(crate) struct IptsManagerView {
     /// because the publisher needs to be able to mutably borrow the data
     /// without re-notifying itself when it drops the guard.
     notify: mpsc::Sender<()>,
+
+    /// Outcomes of publication attempts, from the publisher
+    publish_outcomes: mpsc::Receiver<PublishOutcome>,
 }
 
 /// Shared view of introduction points - IPT publisher's view
@@ -163,6 +191,9 @@ pub(crate) struct IptsPublisherView {
 
     /// Notification receiver
     notify: mpsc::Receiver<()>,
+
+    /// Outcomes of publication attempts, for the manager
+    publish_outcomes: mpsc::Sender<PublishOutcome>,
 }
 
 /// Shared view of introduction points - IPT publisher's publication-only view
@@ -222,12 +253,18 @@ pub(crate) fn ipts_channel(
     //
     // Internally-generated instructions, no need for mq.
     let (tx, rx) = mpsc_channel_no_memquota(0);
+    let (outcome_tx, outcome_rx) = mpsc_channel_no_memquota(PUBLISH_OUTCOME_BUFFER);
     let r = (
         IptsManagerView {
             shared: shared.clone(),
             notify: tx,
+            publish_outcomes: outcome_rx,
+        },
+        IptsPublisherView {
+            shared,
+            notify: rx,
+            publish_outcomes: outcome_tx,
         },
-        IptsPublisherView { shared, notify: rx },
     );
     Ok(r)
 }
@@ -267,6 +304,20 @@ impl IptsManagerView {
     pub(crate) fn borrow_for_read(&mut self) -> impl std::ops::Deref<Target = PublishIptSet> + '_ {
         lock_shared(&self.shared)
     }
+
+    /// Wait for the publisher to report the outcome of a publication attempt
+    ///
+    /// If the publisher has gone away, never completes.
+    ///
+    /// Cancellation safe.
+    pub(crate) async fn await_publish_outcome(&mut self) -> PublishOutcome {
+        match self.publish_outcomes.next().await {
+            Some(outcome) => outcome,
+            // The publisher has shut down (or crashed); the manager will find out
+            // about that by other means.
+            None => future::pending().await,
+        }
+    }
 }
 
 impl<R: SleepProvider> Drop for NotifyingBorrow<'_, R> {
@@ -351,6 +402,16 @@ impl IptsPublisherView {
         lock_shared(&self.shared)
     }
 
+    /// Tell the manager about the outcome of a batch of uploads for the current time period
+    ///
+    /// The manager uses this to decide whether our introduction points might be to blame
+    /// for our failure to publish.
+    pub(crate) fn note_publish_outcome(&mut self, outcome: PublishOutcome) {
+        // Channel full?  The manager is behind; it will catch up with the later outcomes.
+        // Channel disconnected?  The manager has shut down, and so will we, soon.
+        let _: Result<(), mpsc::TrySendError<_>> = self.publish_outcomes.try_send(outcome);
+    }
+
     /// Obtain an [`IptsPublisherUploadView`], for use just prior to a publication attempt
     pub(crate) fn upload_view(&self) -> IptsPublisherUploadView {
         let shared = self.shared.clone();
@@ -635,6 +696,7 @@ mod test {
                 .push(IptInSet {
                     ipt: test_intro_point(),
                     lid: [42; 32].into(),
+                    planned_retirement: runtime.now(),
                 });
 
             pv_expect_one_await_update(&mut pv).await;
@@ -651,6 +713,18 @@ mod test {
 
             pv_note_publication_attempt(&runtime, &pv, runtime.now() - Duration::from_secs(10));
             assert_eq!(mv_get_0_expiry(&mut mv), expected_expiry);
+
+            // publication outcomes are delivered to the manager, in order
+
+            pv.note_publish_outcome(PublishOutcome::Failed);
+            pv.note_publish_outcome(PublishOutcome::Succeeded);
+            assert_eq!(mv.await_publish_outcome().await, PublishOutcome::Failed);
+            assert_eq!(mv.await_publish_outcome().await, PublishOutcome::Succeeded);
+            {
+                let fut = mv.await_publish_outcome();
+                pin_mut!(fut);
+                assert!(poll!(fut).is_pending());
+            }
         });
 
         drop(temp_dir_owned); // prove it's still live
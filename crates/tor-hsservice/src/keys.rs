@@ -171,20 +171,24 @@ pub(crate) struct IptKeySpecifier {
 }
 
 /// Expire publisher keys for no-longer relevant TPs
+///
+/// Failing to remove an expired key doesn't stop us from removing the others.
+/// Returns the number of expired keys we failed to remove.
 pub(crate) fn expire_publisher_keys(
     keymgr: &KeyMgr,
     nickname: &HsNickname,
     relevant_periods: &[HsDirParams],
-) -> tor_keymgr::Result<()> {
+) -> tor_keymgr::Result<usize> {
     // Only remove the keys of the hidden service
     // that concerns us
     let arti_pat = tor_keymgr::KeyPathPattern::Arti(format!("hss/{}/*", &nickname));
     let possibly_relevant_keys = keymgr.list_matching(&arti_pat)?;
+    let mut n_failed = 0;
 
     for entry in possibly_relevant_keys {
         let key_path = entry.key_path();
         // Remove the key identified by `spec` if it's no longer relevant
-        let remove_if_expired = |spec: &dyn HsTimePeriodKeySpecifier| {
+        let mut remove_if_expired = |spec: &dyn HsTimePeriodKeySpecifier| {
             if spec.nickname() != nickname {
                 return Err(internal!(
                     "keymgr gave us key {spec:?} that doesn't match our pattern {arti_pat:?}"
@@ -196,7 +200,10 @@ pub(crate) fn expire_publisher_keys(
                 .all(|p| &p.time_period() != spec.period());
 
             if is_expired {
-                keymgr.remove_entry(&entry)?;
+                if let Err(e) = keymgr.remove_entry(&entry) {
+                    debug_report!(e, "failed to remove expired key {spec:?}");
+                    n_failed += 1;
+                }
             }
 
             tor_keymgr::Result::Ok(())
@@ -218,7 +225,7 @@ pub(crate) fn expire_publisher_keys(
         remove_if_expired!(DescSigningKeypairSpecifier);
     }
 
-    Ok(())
+    Ok(n_failed)
 }
 
 #[cfg(test)]
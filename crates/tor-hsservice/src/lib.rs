@@ -69,6 +69,7 @@ mod publish;
 mod rend_handshake;
 mod replay;
 mod req;
+mod self_test;
 pub mod status;
 mod timeout_track;
 
@@ -97,7 +98,7 @@ use internal_prelude::*;
 // ---------- public exports ----------
 
 pub use anon_level::Anonymity;
-pub use config::OnionServiceConfig;
+pub use config::{IptAddrFamilyPolicy, OnionServiceConfig};
 pub use err::{ClientError, EstablishSessionError, FatalError, IntroRequestError, StartupError};
 pub use ipt_mgr::IptError;
 pub use keys::{
@@ -106,7 +107,9 @@ pub use keys::{
 };
 use pow::{NewPowManager, PowManager};
 pub use publish::UploadError as DescUploadError;
+pub use publish::{BuiltDescriptor, DescriptorSink, DescriptorSummary, HsDirRejection};
 pub use req::{RendRequest, StreamRequest};
+pub use self_test::{ReachabilityTestError, ReachabilityTester};
 pub use tor_hscrypto::pk::HsId;
 pub use tor_persist::hsnickname::{HsNickname, InvalidNickname};
 
@@ -137,6 +140,8 @@ pub struct RunningOnionService {
     nickname: HsNickname,
     /// The key manager, used for accessing the underlying key stores.
     keymgr: Arc<KeyMgr>,
+    /// The summaries of the descriptors most recently built by the publisher.
+    descriptor_summaries: publish::DescriptorSummaries,
 }
 
 /// Implementation details for an onion service.
@@ -144,6 +149,9 @@ struct SvcInner {
     /// Configuration information about this service.
     config_tx: postage::watch::Sender<Arc<OnionServiceConfig>>,
 
+    /// Whether the operator has paused descriptor publication.
+    publication_paused_tx: postage::watch::Sender<bool>,
+
     /// A oneshot that will be dropped when this object is dropped.
     _shutdown_tx: postage::broadcast::Sender<void::Void>,
 
@@ -182,6 +190,9 @@ struct ForLaunch<R: Runtime> {
 
     /// Proof-of-work manager.
     pow_manager: Arc<PowManager<R>>,
+
+    /// The reachability self-test, if we have a [`ReachabilityTester`].
+    self_tester: Option<SelfTester<R>>,
 }
 
 /// Private trait used to type-erase `ForLaunch<R>`, so that we don't need to
@@ -196,6 +207,9 @@ impl<R: Runtime> Launchable for ForLaunch<R> {
         self.ipt_mgr.launch_background_tasks(self.ipt_mgr_view)?;
         self.publisher.launch()?;
         self.pow_manager.launch()?;
+        if let Some(self_tester) = self.self_tester {
+            self_tester.launch()?;
+        }
 
         Ok(())
     }
@@ -236,6 +250,19 @@ pub struct OnionService {
     keymgr: Arc<KeyMgr>,
     /// The location on disk where the persistent data is stored.
     state_dir: StateDirectory,
+    /// A hook to tell about every descriptor we build, if any.
+    ///
+    /// See [`DescriptorSink`].
+    #[builder(default, setter(strip_option))]
+    descriptor_sink: Option<Arc<dyn DescriptorSink>>,
+    /// A way to check that the service is reachable, if any.
+    ///
+    /// If this is set, the service won't report that it is
+    /// [`Running`](status::State::Running) until the tester has
+    /// connected to it.
+    /// See [`ReachabilityTester`].
+    #[builder(default, setter(strip_option))]
+    reachability_tester: Option<Arc<dyn ReachabilityTester>>,
 }
 
 impl OnionService {
@@ -267,6 +294,8 @@ impl OnionService {
             config,
             keymgr,
             state_dir,
+            descriptor_sink,
+            reachability_tester,
         } = self;
 
         let nickname = config.nickname.clone();
@@ -278,7 +307,7 @@ impl OnionService {
 
         // TODO (#1106): make this configurable
         let selector = KeystoreSelector::Primary;
-        maybe_generate_hsid(&keymgr, &config.nickname, offline_hsid, selector)?;
+        let hsid = maybe_generate_hsid(&keymgr, &config.nickname, offline_hsid, selector)?;
 
         if config.restricted_discovery.enabled {
             info!(
@@ -305,6 +334,9 @@ impl OnionService {
         let pow_manager_storage_handle = state_handle
             .storage_handle("pow_manager")
             .map_err(StartupError::StateDirectoryInaccessible)?;
+        let hsdir_cache_storage_handle = state_handle
+            .storage_handle("publisher_hsdirs")
+            .map_err(StartupError::StateDirectoryInaccessible)?;
         let pow_nonce_dir = state_handle
             .raw_subdir("pow_nonces")
             .map_err(StartupError::StateDirectoryInaccessible)?;
@@ -324,11 +356,16 @@ impl OnionService {
 
         let (shutdown_tx, shutdown_rx) = broadcast::channel(0);
         let (config_tx, config_rx) = postage::watch::channel_with(Arc::new(config));
+        let (publication_paused_tx, publication_paused_rx) = postage::watch::channel_with(false);
 
         let (ipt_mgr_view, publisher_view) =
             crate::ipt_set::ipts_channel(&runtime, iptpub_storage_handle)?;
 
-        let status_tx = StatusSender::new(OnionServiceStatus::new_shutdown());
+        let status_tx = StatusSender::new(
+            OnionServiceStatus::new_shutdown(),
+            DynTimeProvider::new(runtime.clone()),
+        );
+        let descriptor_summaries = publish::DescriptorSummaries::default();
 
         let ipt_mgr = IptManager::new(
             runtime.clone(),
@@ -345,6 +382,16 @@ impl OnionService {
             status_tx.clone().into(),
         )?;
 
+        let self_tester = reachability_tester.map(|tester| {
+            SelfTester::new(
+                runtime.clone(),
+                hsid,
+                tester,
+                status_tx.clone().into(),
+                shutdown_rx.clone(),
+            )
+        });
+
         let publisher: Publisher<R, publish::Real<R>> = Publisher::new(
             runtime,
             nickname.clone(),
@@ -357,13 +404,19 @@ impl OnionService {
             path_resolver,
             pow_manager.clone(),
             publisher_update_rx,
+            publication_paused_rx,
+            Arc::clone(&descriptor_summaries),
+            descriptor_sink,
+            hsdir_cache_storage_handle,
         );
 
         let svc = Arc::new(RunningOnionService {
             nickname,
             keymgr,
+            descriptor_summaries,
             inner: Mutex::new(SvcInner {
                 config_tx,
+                publication_paused_tx,
                 _shutdown_tx: shutdown_tx,
                 status_tx,
                 unlaunched: Some((
@@ -373,6 +426,7 @@ impl OnionService {
                         ipt_mgr,
                         ipt_mgr_view,
                         pow_manager,
+                        self_tester,
                     }),
                 )),
             }),
@@ -464,6 +518,33 @@ impl RunningOnionService {
         // connections, but existing ones.
     }
 
+    /// Stop uploading descriptors for this onion service, until
+    /// [`resume_publication`](Self::resume_publication) is called.
+    ///
+    /// This is intended for maintenance windows in which the operator deliberately wants
+    /// the published descriptors to age out, so that clients stop being able to reach the service.
+    /// The service keeps running, and keeps its introduction points and descriptors up to date,
+    /// but nothing is uploaded to the HsDirs, not even when the descriptors are due for republication.
+    /// Uploads that are already in progress are allowed to finish.
+    ///
+    /// Does nothing if publication is already paused.
+    pub fn pause_publication(&self) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.publication_paused_tx.maybe_send(|_| true);
+    }
+
+    /// Resume uploading descriptors for this onion service,
+    /// after a call to [`pause_publication`](Self::pause_publication).
+    ///
+    /// Any descriptors that changed or were due for republication while we were paused
+    /// are uploaded right away.
+    ///
+    /// Does nothing if publication isn't paused.
+    pub fn resume_publication(&self) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.publication_paused_tx.maybe_send(|_| false);
+    }
+
     /*
     /// Tell this onion service about some new short-term keys it can use.
     pub fn add_keys(&self, keys: ()) -> Result<(), Bug> {
@@ -486,6 +567,34 @@ impl RunningOnionService {
             .subscribe()
     }
 
+    /// Return the most recent changes in the state of the components of this onion service,
+    /// oldest first.
+    ///
+    /// Only a bounded number of recent changes are kept.
+    pub fn status_history(&self) -> Vec<status::StatusTransition> {
+        self.inner
+            .lock()
+            .expect("poisoned lock")
+            .status_tx
+            .history()
+    }
+
+    /// Return a summary of the most recently built descriptor
+    /// for each of the time periods we are publishing descriptors for,
+    /// ordered by time period.
+    ///
+    /// This is intended for debugging: the summaries don't include
+    /// the descriptors themselves, or any of their keys.
+    pub fn descriptor_summaries(&self) -> Vec<DescriptorSummary> {
+        self.descriptor_summaries
+            .lock()
+            .expect("poisoned lock")
+            .values()
+            .cloned()
+            .sorted_by_key(|summary| summary.time_period.interval_num())
+            .collect()
+    }
+
     /// Tell this onion service to begin running, and return a
     /// stream of rendezvous requests on the service.
     ///
@@ -4,6 +4,8 @@
 
 mod backoff;
 mod descriptor;
+mod hsdir_cache;
+mod metrics;
 mod reactor;
 mod reupload_timer;
 
@@ -12,16 +14,27 @@ use crate::internal_prelude::*;
 use crate::pow::PowManager;
 
 use backoff::{BackoffError, BackoffSchedule, RetriableError, Runner};
-use descriptor::{DescriptorStatus, VersionedDescriptor, build_sign};
+use descriptor::{
+    DescriptorStatus, IptAddrFamilies, VersionedDescriptor, build_sign, select_intro_points,
+};
+use hsdir_cache::HsDirCacheRecord;
+use metrics::{PublisherMetrics, Ring};
 use reactor::Reactor;
-use reactor::read_blind_id_keypair;
+use reactor::{keystore_selector, read_blind_id_keypair};
 use reupload_timer::ReuploadTimer;
 
 use tor_config_path::CfgPathResolver;
 
-pub use reactor::UploadError;
+pub use descriptor::{BuiltDescriptor, DescriptorSink, DescriptorSummary};
+pub(crate) use hsdir_cache::HsDirCacheStorageHandle;
+pub use reactor::{HsDirRejection, UploadError};
 pub(crate) use reactor::{Mockable, OVERALL_UPLOAD_TIMEOUT, Real};
 
+/// The summary of the most recently built descriptor for each of our relevant time periods.
+///
+/// This is updated by the publisher, and read by the [`RunningOnionService`](crate::RunningOnionService).
+pub(crate) type DescriptorSummaries = Arc<Mutex<HashMap<TimePeriod, DescriptorSummary>>>;
+
 /// A handle for the Hsdir Publisher for an onion service.
 ///
 /// This handle represents a set of tasks that identify the hsdirs for each
@@ -57,6 +70,14 @@ pub(crate) struct Publisher<R: Runtime, M: Mockable> {
     /// Queue on which we receive messages from the [`PowManager`] telling us that a seed has
     /// rotated and thus we need to republish the descriptor for a particular time period.
     update_from_pow_manager_rx: mpsc::Receiver<TimePeriod>,
+    /// A channel telling us whether the operator wants publication to be paused.
+    paused_rx: watch::Receiver<bool>,
+    /// Where to record the summaries of the descriptors we build.
+    descriptor_summaries: DescriptorSummaries,
+    /// A hook to tell about every descriptor we build, if any.
+    descriptor_sink: Option<Arc<dyn DescriptorSink>>,
+    /// Where to save the HsDirs we compute, for a faster start after a restart.
+    hsdir_cache: HsDirCacheStorageHandle,
 }
 
 impl<R: Runtime, M: Mockable> Publisher<R, M> {
@@ -79,6 +100,10 @@ impl<R: Runtime, M: Mockable> Publisher<R, M> {
         path_resolver: Arc<CfgPathResolver>,
         pow_manager: Arc<PowManager<R>>,
         update_from_pow_manager_rx: mpsc::Receiver<TimePeriod>,
+        paused_rx: watch::Receiver<bool>,
+        descriptor_summaries: DescriptorSummaries,
+        descriptor_sink: Option<Arc<dyn DescriptorSink>>,
+        hsdir_cache: HsDirCacheStorageHandle,
     ) -> Self {
         let config = config_rx.borrow().clone();
         Self {
@@ -94,6 +119,10 @@ impl<R: Runtime, M: Mockable> Publisher<R, M> {
             path_resolver,
             pow_manager,
             update_from_pow_manager_rx,
+            paused_rx,
+            descriptor_summaries,
+            descriptor_sink,
+            hsdir_cache,
         }
     }
 
@@ -112,6 +141,10 @@ impl<R: Runtime, M: Mockable> Publisher<R, M> {
             path_resolver,
             pow_manager,
             update_from_pow_manager_rx: publisher_update_rx,
+            paused_rx,
+            descriptor_summaries,
+            descriptor_sink,
+            hsdir_cache,
         } = self;
 
         let reactor = Reactor::new(
@@ -127,6 +160,10 @@ impl<R: Runtime, M: Mockable> Publisher<R, M> {
             path_resolver,
             pow_manager,
             publisher_update_rx,
+            paused_rx,
+            descriptor_summaries,
+            descriptor_sink,
+            hsdir_cache,
         );
 
         runtime
@@ -163,39 +200,50 @@ mod test {
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
     use super::*;
 
-    use std::collections::HashMap;
+    use std::collections::{HashMap, VecDeque};
     use std::io;
-    use std::path::Path;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
     use std::pin::Pin;
     use std::sync::Mutex;
-    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::task::{Context, Poll};
     use std::time::Duration;
 
     use async_trait::async_trait;
     use fs_mistrust::Mistrust;
     use futures::{AsyncRead, AsyncWrite};
+    use rand::SeedableRng as _;
     use tempfile::{TempDir, tempdir};
     use test_temp_dir::test_temp_dir;
 
-    use tor_basic_utils::test_rng::{TestingRng, testing_rng};
+    use tor_basic_utils::test_rng::{Config as RngConfig, TestingRng, testing_rng};
     use tor_circmgr::hspool::HsCircKind;
     use tor_hscrypto::pk::{HsBlindId, HsDescSigningKeypair, HsId, HsIdKey, HsIdKeypair};
-    use tor_key_forge::ToEncodableKey;
-    use tor_keymgr::{ArtiNativeKeystore, KeyMgrBuilder, KeySpecifier};
+    use tor_key_forge::{EncodableItem, ErasedKey, KeystoreItemType, ToEncodableKey};
+    use tor_keymgr::{
+        ArtiNativeKeystore, KeyMgrBuilder, KeySpecifier, Keystore, KeystoreEntry,
+        KeystoreEntryResult, KeystoreId, RawEntryId,
+    };
+    use tor_linkspec::LinkSpec;
     use tor_llcrypto::pk::{ed25519, rsa};
     use tor_netdir::testprovider::TestNetDirProvider;
     use tor_netdir::{NetDir, testnet};
-    use tor_netdoc::doc::hsdesc::test_data;
+    use tor_netdoc::doc::hsdesc::{IntroPointDesc, test_data};
+    use tor_proto::ClockSkew;
     use tor_rtcompat::ToplevelBlockOn;
     use tor_rtmock::MockRuntime;
+    use tor_rtmock::counting::CountingRuntime;
 
     use crate::HsNickname;
-    use crate::config::OnionServiceConfigBuilder;
-    use crate::ipt_set::{IptInSet, IptSet, ipts_channel};
+    use crate::config::{IptAddrFamilyPolicy, OnionServiceConfigBuilder};
+    use crate::ipt_set::{IptInSet, IptSet, IptsManagerView, ipts_channel};
     use crate::pow::NewPowManager;
-    use crate::publish::reactor::MockableDirTunnel;
-    use crate::status::{OnionServiceStatus, StatusSender};
+    use crate::publish::reactor::{
+        IPT_ADDR_FAMILY_MAX_DELAY, KEY_DIRS_RECHECK_INTERVAL, KEY_EXPIRY_RETRY_INTERVAL,
+        MockableDirTunnel,
+    };
+    use crate::status::{OnionServiceStatus, OnionServiceStatusStream, Problem, StatusSender};
     use crate::test::create_storage_handles;
     use crate::{
         BlindIdKeypairSpecifier, BlindIdPublicKeySpecifier, DescSigningKeypairSpecifier,
@@ -211,6 +259,9 @@ mod test {
     /// The HTTP response the HSDir returns if something went wrong
     const ERR_RESPONSE: &str = "HTTP/1.1 500 UH_OH\r\n\r\n";
 
+    /// The response an HSDir returns if it has been told to misbehave with [`Fault::Malformed`].
+    const MALFORMED_RESPONSE: &str = "THIS IS NOT HTTP\r\n\r\n";
+
     /// The error doesn't matter (we return a dummy io::Error from poll_read).
     ///
     /// NOTE: ideally, this would be an io::Result, but io::Error isn't Clone (the tests need to
@@ -228,10 +279,88 @@ mod test {
     {
     }
 
+    /// A failure to inject into the uploads to a particular HsDir.
+    #[derive(Clone, Debug)]
+    enum Fault {
+        /// We fail to build the circuit to the HsDir.
+        Circuit,
+        /// We build the circuit, but fail to open the directory stream.
+        Stream,
+        /// Building the circuit to the HsDir takes this long.
+        ///
+        /// This doesn't make the attempt fail, unless the delay causes it to time out.
+        Delay(Duration),
+        /// The HsDir answers our upload with [`MALFORMED_RESPONSE`].
+        Malformed,
+    }
+
+    impl Fault {
+        /// Return the number of extra uploads and circuits this fault costs us,
+        /// if the next attempt succeeds.
+        fn extra_uploads_and_circuits(&self) -> (usize, usize) {
+            match self {
+                // The attempt fails before we send anything.
+                Fault::Circuit | Fault::Delay(_) => (0, 0),
+                // The circuit isn't reused, since it might be the problem.
+                Fault::Stream => (0, 1),
+                // The request failed, but the circuit is fine, so it is reused.
+                Fault::Malformed => (1, 0),
+            }
+        }
+    }
+
+    /// The faults to inject into the uploads to each HsDir (identified by its RsaIdentity).
+    ///
+    /// The faults for an HsDir are used up in order,
+    /// each at the point of the upload where it applies.
+    /// Once an HsDir has no faults left, uploads to it proceed as usual.
     #[derive(Clone, Debug, Default)]
+    struct InjectedFaults(Arc<Mutex<HashMap<rsa::RsaIdentity, VecDeque<Fault>>>>);
+
+    impl InjectedFaults {
+        /// Arrange for the uploads to `hsdir` to suffer `faults`, after any already injected.
+        fn inject(&self, hsdir: rsa::RsaIdentity, faults: impl IntoIterator<Item = Fault>) {
+            self.0
+                .lock()
+                .unwrap()
+                .entry(hsdir)
+                .or_default()
+                .extend(faults);
+        }
+
+        /// Use up the next fault for `hsdir`, if it is one for which `applies` returns true.
+        fn take_if(
+            &self,
+            hsdir: &rsa::RsaIdentity,
+            applies: impl FnOnce(&Fault) -> bool,
+        ) -> Option<Fault> {
+            let mut faults = self.0.lock().unwrap();
+            let faults = faults.get_mut(hsdir)?;
+            if applies(faults.front()?) {
+                faults.pop_front()
+            } else {
+                None
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
     struct MockReactorState<I: PollReadIter> {
+        /// The runtime, for injecting [`Fault::Delay`]s.
+        runtime: DynTimeProvider,
+        /// The RNG from which we derive the RNGs we give the reactor.
+        ///
+        /// It is seeded deterministically (unless overridden with `ARTI_TEST_PRNG`),
+        /// so that the retry delays are the same on every run.
+        rng: Arc<Mutex<TestingRng>>,
         /// The number of `POST /tor/hs/3/publish` requests sent by the reactor.
         publish_count: Arc<AtomicUsize>,
+        /// The number of circuits launched by the reactor.
+        ///
+        /// Circuits that failed because of a [`Fault::Circuit`] aren't counted.
+        circuit_count: Arc<AtomicUsize>,
+        /// The faults to inject into the uploads to each HSDir.
+        faults: InjectedFaults,
         /// The values returned by `DataStream::poll_read` when uploading to an HSDir.
         ///
         /// The values represent the HTTP response (or lack thereof) each HSDir sends upon
@@ -253,7 +382,7 @@ mod test {
         type Tunnel = MockClientCirc<I>;
 
         fn thread_rng(&self) -> Self::Rng {
-            testing_rng()
+            TestingRng::from_rng(&mut *self.rng.lock().unwrap())
         }
 
         async fn get_or_launch_hs_dir<T>(
@@ -264,14 +393,30 @@ mod test {
         where
             T: tor_linkspec::CircTarget + Send + Sync,
         {
+            let id = *target.rsa_identity().unwrap();
+            if let Some(Fault::Delay(delay)) =
+                self.faults.take_if(&id, |f| matches!(f, Fault::Delay(_)))
+            {
+                self.runtime.sleep(delay).await;
+            }
+            if self
+                .faults
+                .take_if(&id, |f| matches!(f, Fault::Circuit))
+                .is_some()
+            {
+                return Err(tor_circmgr::Error::CircTimeout(None));
+            }
+
+            let _prev = self.circuit_count.fetch_add(1, Ordering::SeqCst);
             // Look up the next poll_read value to return for this relay.
-            let id = target.rsa_identity().unwrap();
             let mut map = self.responses_for_hsdir.lock().unwrap();
             let poll_read_responses = map
-                .entry(*id)
+                .entry(id)
                 .or_insert_with(|| self.poll_read_responses.clone());
 
             Ok(MockClientCirc {
+                hsdir: id,
+                faults: self.faults.clone(),
                 publish_count: Arc::clone(&self.publish_count),
                 poll_read_responses: poll_read_responses.clone(),
             }
@@ -286,6 +431,10 @@ mod test {
 
     #[derive(Debug, Clone)]
     struct MockClientCirc<I: PollReadIter> {
+        /// The HSDir at the end of this circuit.
+        hsdir: rsa::RsaIdentity,
+        /// The faults to inject into the uploads to each HSDir.
+        faults: InjectedFaults,
         /// The number of `POST /tor/hs/3/publish` requests sent by the reactor.
         publish_count: Arc<AtomicUsize>,
         /// The values to return from `poll_read`.
@@ -299,17 +448,31 @@ mod test {
         type DataStream = MockDataStream<I>;
 
         async fn begin_dir_stream(&self) -> Result<Self::DataStream, tor_circmgr::Error> {
+            let fault = self.faults.take_if(&self.hsdir, |f| {
+                matches!(f, Fault::Stream | Fault::Malformed)
+            });
+            if let Some(Fault::Stream) = fault {
+                return Err(tor_circmgr::Error::RequestTimeout);
+            }
+            let malformed_response = matches!(fault, Some(Fault::Malformed))
+                .then(|| io::Cursor::new(MALFORMED_RESPONSE.as_bytes()));
+
             Ok(MockDataStream {
                 publish_count: Arc::clone(&self.publish_count),
                 // TODO: this will need to change when we start reusing circuits (currently,
                 // we only ever create one data stream per circuit).
                 poll_read_responses: self.poll_read_responses.clone(),
+                malformed_response,
             })
         }
 
         fn source_info(&self) -> tor_proto::Result<Option<tor_dirclient::SourceInfo>> {
             Ok(None)
         }
+
+        fn is_closed(&self) -> bool {
+            false
+        }
     }
 
     #[derive(Debug)]
@@ -320,6 +483,9 @@ mod test {
         ///
         /// Used for testing whether the reactor correctly retries on failure.
         poll_read_responses: I,
+        /// The response to read instead of the `poll_read_responses`,
+        /// if we were told to inject a [`Fault::Malformed`].
+        malformed_response: Option<io::Cursor<&'static [u8]>>,
     }
 
     impl<I: PollReadIter> AsyncRead for MockDataStream<I> {
@@ -328,6 +494,9 @@ mod test {
             _cx: &mut Context<'_>,
             buf: &mut [u8],
         ) -> Poll<io::Result<usize>> {
+            if let Some(response) = &mut self.malformed_response {
+                return Poll::Ready(io::Read::read(response, buf));
+            }
             match self.as_mut().poll_read_responses.next() {
                 Some(res) => {
                     match res {
@@ -386,9 +555,10 @@ mod test {
             .unwrap();
     }
 
-    /// Create a new `KeyMgr`, provisioning its keystore with the necessary keys.
-    fn init_keymgr(
-        keystore_dir: &TempDir,
+    /// Create a new `KeyMgr` using `keystore`,
+    /// provisioning it with the necessary keys.
+    fn init_keymgr_with_store(
+        keystore: Box<dyn Keystore>,
         nickname: &HsNickname,
         netdir: &NetDir,
     ) -> (HsId, HsBlindId, Arc<KeyMgr>) {
@@ -402,15 +572,9 @@ mod test {
         let (hs_blind_id_key, hs_blind_id_kp, _subcredential) =
             id_keypair.compute_blinded_key(period).unwrap();
 
-        let keystore = ArtiNativeKeystore::from_path_and_mistrust(
-            keystore_dir,
-            &Mistrust::new_dangerously_trust_everyone(),
-        )
-        .unwrap();
-
         // Provision the keystore with the necessary keys:
         let keymgr = KeyMgrBuilder::default()
-            .primary_store(Box::new(keystore))
+            .primary_store(keystore)
             .build()
             .unwrap();
 
@@ -448,6 +612,16 @@ mod test {
         (hs_id, hs_blind_id_key.into(), keymgr.into())
     }
 
+    /// A [`DescriptorSink`] that remembers every descriptor it is given.
+    #[derive(Default)]
+    struct TestDescriptorSink(Mutex<Vec<BuiltDescriptor>>);
+
+    impl DescriptorSink for TestDescriptorSink {
+        fn descriptor_built(&self, descriptor: &BuiltDescriptor) {
+            self.0.lock().unwrap().push(descriptor.clone());
+        }
+    }
+
     fn build_test_config(nickname: HsNickname) -> OnionServiceConfig {
         OnionServiceConfigBuilder::default()
             .nickname(nickname)
@@ -456,38 +630,273 @@ mod test {
             .unwrap()
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn run_test<I: PollReadIter>(
+    /// A [`NetDirProvider`] whose netdir isn't timely while `timely` is unset.
+    struct MaybeTimelyNetDirProvider {
+        /// Provides the netdir, whether or not it is timely.
+        inner: TestNetDirProvider,
+        /// Whether the netdir is timely.
+        timely: AtomicBool,
+    }
+
+    impl NetDirProvider for MaybeTimelyNetDirProvider {
+        fn netdir(&self, timeliness: Timeliness) -> tor_netdir::Result<Arc<NetDir>> {
+            if matches!(timeliness, Timeliness::Timely) && !self.timely.load(Ordering::SeqCst) {
+                return Err(tor_netdir::Error::NoInfo);
+            }
+            self.inner.netdir(timeliness)
+        }
+
+        fn events(&self) -> BoxStream<'static, tor_netdir::DirEvent> {
+            self.inner.events()
+        }
+
+        fn params(&self) -> Arc<dyn AsRef<tor_netdir::params::NetParameters>> {
+            self.inner.params()
+        }
+
+        fn protocol_statuses(
+            &self,
+        ) -> Option<(SystemTime, Arc<tor_netdoc::doc::netstatus::ProtoStatuses>)> {
+            None
+        }
+    }
+
+    /// A keystore that fails to remove any keys while `fail_removals` is set.
+    struct FlakyKeystore {
+        /// The keystore we delegate to.
+        inner: ArtiNativeKeystore,
+        /// Whether to fail key removals.
+        fail_removals: Arc<AtomicBool>,
+    }
+
+    impl Keystore for FlakyKeystore {
+        fn id(&self) -> &KeystoreId {
+            self.inner.id()
+        }
+
+        fn contains(
+            &self,
+            key_spec: &dyn KeySpecifier,
+            item_type: &KeystoreItemType,
+        ) -> tor_keymgr::Result<bool> {
+            self.inner.contains(key_spec, item_type)
+        }
+
+        fn get(
+            &self,
+            key_spec: &dyn KeySpecifier,
+            item_type: &KeystoreItemType,
+        ) -> tor_keymgr::Result<Option<ErasedKey>> {
+            self.inner.get(key_spec, item_type)
+        }
+
+        fn raw_entry_id(&self, raw_id: &str) -> tor_keymgr::Result<RawEntryId> {
+            self.inner.raw_entry_id(raw_id)
+        }
+
+        fn insert(
+            &self,
+            key: &dyn EncodableItem,
+            key_spec: &dyn KeySpecifier,
+        ) -> tor_keymgr::Result<()> {
+            self.inner.insert(key, key_spec)
+        }
+
+        fn remove(
+            &self,
+            key_spec: &dyn KeySpecifier,
+            item_type: &KeystoreItemType,
+        ) -> tor_keymgr::Result<Option<()>> {
+            if self.fail_removals.load(Ordering::SeqCst) {
+                return Err(internal!("injected key removal failure").into());
+            }
+            self.inner.remove(key_spec, item_type)
+        }
+
+        fn remove_unchecked(&self, entry_id: &RawEntryId) -> tor_keymgr::Result<()> {
+            self.inner.remove_unchecked(entry_id)
+        }
+
+        fn list(&self) -> tor_keymgr::Result<Vec<KeystoreEntryResult<KeystoreEntry>>> {
+            self.inner.list()
+        }
+    }
+
+    /// A publisher test, before the publisher is launched.
+    ///
+    /// [`PublisherTest::new`] sets up a service with all the keys it needs,
+    /// on a test network whose relays can all act as HsDirs,
+    /// and whose HsDirs accept every upload.
+    /// Tests change whatever they need to, and then [`run`](PublisherTest::run) a scenario.
+    struct PublisherTest {
+        /// The runtime the test runs on.
         runtime: MockRuntime,
+        /// The nickname of the service.
         nickname: HsNickname,
+        /// The configuration the publisher starts with.
+        config: OnionServiceConfig,
+        /// The network the publisher sees.
+        ///
+        /// Its time period must be that of [`construct_test_netdir`],
+        /// since the keys of the service are generated for that time period.
+        netdir: Arc<NetDir>,
+        /// Whether `netdir` is timely.
+        timely: bool,
+        /// The directory holding the keystore of the service.
+        keystore_dir: TempDir,
+        /// The key manager of the service.
         keymgr: Arc<KeyMgr>,
-        pv: IptsPublisherView,
-        config_rx: watch::Receiver<Arc<OnionServiceConfig>>,
-        status_tx: PublisherStatusSender,
-        netdir: NetDir,
-        reactor_event: impl FnOnce(),
-        poll_read_responses: I,
-        expected_upload_count: usize,
-        republish_count: usize,
-        expect_errors: bool,
-    ) {
-        runtime.clone().block_on(async move {
-            let netdir_provider: Arc<dyn NetDirProvider> =
-                Arc::new(TestNetDirProvider::from(netdir));
+        /// The blinded identity of the service in the current time period.
+        blind_id: HsBlindId,
+        /// Makes the keystore fail to remove keys while set.
+        fail_removals: Arc<AtomicBool>,
+        /// The values each HsDir returns from `poll_read`, in order, for each upload.
+        poll_read_responses: Vec<PollReadResult<String>>,
+        /// The faults to inject into the uploads to each HsDir.
+        faults: InjectedFaults,
+        /// If not `None`, the only ORPort our introduction points advertise.
+        ipt_orport: Option<SocketAddr>,
+        /// The HsDirs the publisher saved before it was (re)started.
+        saved_hsdirs: Option<HsDirCacheRecord>,
+        /// The directory holding the state of the publisher and of the IPT manager.
+        state_dir: PathBuf,
+    }
+
+    impl PublisherTest {
+        /// Set up a publisher test whose state lives in `state_dir`.
+        fn new(state_dir: &Path) -> Self {
+            Self::with_runtime(state_dir, MockRuntime::new())
+        }
+
+        /// Set up a publisher test that runs on `runtime`.
+        fn with_runtime(state_dir: &Path, runtime: MockRuntime) -> Self {
+            let nickname = HsNickname::try_from(TEST_SVC_NICKNAME.to_string()).unwrap();
+            let config = build_test_config(nickname.clone());
+            let netdir = Arc::new(construct_test_netdir());
+            let keystore_dir = tempdir().unwrap();
+            let fail_removals = Arc::new(AtomicBool::new(false));
+            let keystore = FlakyKeystore {
+                inner: ArtiNativeKeystore::from_path_and_mistrust(
+                    &keystore_dir,
+                    &Mistrust::new_dangerously_trust_everyone(),
+                )
+                .unwrap(),
+                fail_removals: Arc::clone(&fail_removals),
+            };
+            let (_hsid, blind_id, keymgr) =
+                init_keymgr_with_store(Box::new(keystore), &nickname, &netdir);
+
+            Self {
+                runtime,
+                nickname,
+                config,
+                netdir,
+                timely: true,
+                keystore_dir,
+                keymgr,
+                blind_id,
+                fail_removals,
+                poll_read_responses: vec![Ok(OK_RESPONSE.into())],
+                faults: InjectedFaults::default(),
+                ipt_orport: None,
+                saved_hsdirs: None,
+                state_dir: state_dir.to_owned(),
+            }
+        }
+
+        /// Return the HsDirs we upload to in the current time period, in ring order.
+        fn hsdirs(&self) -> Vec<RelayIds> {
+            self.netdir
+                .hs_dirs_upload(self.blind_id, self.netdir.hs_time_period())
+                .unwrap()
+                .map(|relay| RelayIds::from_relay_ids(&relay))
+                .collect_vec()
+        }
+
+        /// Insert the signing key of a time period that is long gone,
+        /// returning its specifier.
+        fn insert_expired_key(&self) -> DescSigningKeypairSpecifier {
+            let period = self.netdir.hs_time_period();
+            let old_period = (0..3).fold(period, |p, _| p.prev().unwrap());
+            let old_key_spec = DescSigningKeypairSpecifier::new(self.nickname.clone(), old_period);
+            insert_svc_key(
+                HsDescSigningKeypair::from(ed25519::Keypair::generate(&mut testing_rng())),
+                &self.keymgr,
+                &old_key_spec,
+            );
+            old_key_spec
+        }
+
+        /// Launch the publisher, and run `scenario` against it.
+        ///
+        /// The publisher has started up by the time `scenario` is called.
+        fn run<F: Future<Output = ()>>(self, scenario: impl FnOnce(TestPublisher) -> F) {
+            let runtime = self.runtime.clone();
+            self.run_on(runtime, scenario);
+        }
+
+        /// Like [`run`](Self::run), but give the publisher `runtime`
+        /// rather than our [`MockRuntime`].
+        ///
+        /// `runtime` must be driven by our [`MockRuntime`].
+        fn run_on<R: Runtime, F: Future<Output = ()>>(
+            self,
+            runtime: R,
+            scenario: impl FnOnce(TestPublisher) -> F,
+        ) {
+            self.runtime.clone().block_on(async move {
+                let publisher = self.launch(&runtime);
+                publisher.settle().await;
+                scenario(publisher).await;
+            });
+        }
+
+        /// Launch a publisher that runs on `runtime`.
+        ///
+        /// The publisher doesn't start running until the runtime next makes progress.
+        fn launch<R: Runtime>(self, runtime: &R) -> TestPublisher {
             let publish_count = Default::default();
+            let circuit_count: Arc<AtomicUsize> = Default::default();
             let circpool = MockReactorState {
+                runtime: DynTimeProvider::new(runtime.clone()),
+                rng: Arc::new(Mutex::new(
+                    RngConfig::from_env()
+                        .unwrap_or(RngConfig::Deterministic)
+                        .into_rng(),
+                )),
                 publish_count: Arc::clone(&publish_count),
-                poll_read_responses,
+                circuit_count: Arc::clone(&circuit_count),
+                faults: self.faults,
+                poll_read_responses: self.poll_read_responses.into_iter(),
                 responses_for_hsdir: Arc::new(Mutex::new(Default::default())),
             };
 
-            let temp_dir = test_temp_dir!();
-            let state_dir = temp_dir.subdir_untracked("state_dir");
+            let netdir_provider = Arc::new(MaybeTimelyNetDirProvider {
+                inner: TestNetDirProvider::new(),
+                timely: AtomicBool::new(self.timely),
+            });
+            netdir_provider.inner.set_netdir(Arc::clone(&self.netdir));
+            let (config_tx, config_rx) = watch::channel_with(Arc::new(self.config.clone()));
+            let (ipts, pv) =
+                ipts_channel(&self.runtime, create_storage_handles(&self.state_dir).1).unwrap();
+            let status_sender = StatusSender::new(
+                OnionServiceStatus::new_shutdown(),
+                DynTimeProvider::new(self.runtime.clone()),
+            );
+            let status_tx: PublisherStatusSender = status_sender.clone().into();
+
             let mistrust = fs_mistrust::Mistrust::new_dangerously_trust_everyone();
-            let state_dir = StateDirectory::new(state_dir, &mistrust).unwrap();
-            let state_handle = state_dir.acquire_instance(&nickname).unwrap();
+            let state_dir =
+                StateDirectory::new(self.state_dir.join("state_dir"), &mistrust).unwrap();
+            let state_handle = state_dir.acquire_instance(&self.nickname).unwrap();
             let pow_nonce_dir = state_handle.raw_subdir("pow_nonces").unwrap();
             let pow_manager_storage_handle = state_handle.storage_handle("pow_manager").unwrap();
+            let hsdir_cache_storage_handle =
+                state_handle.storage_handle("publisher_hsdirs").unwrap();
+            let mut hsdir_cache = state_handle.storage_handle("publisher_hsdirs").unwrap();
+            if let Some(saved) = &self.saved_hsdirs {
+                hsdir_cache.store(saved).unwrap();
+            }
 
             let NewPowManager {
                 pow_manager,
@@ -496,51 +905,262 @@ mod test {
                 publisher_update_rx: update_from_pow_manager_rx,
             } = PowManager::new(
                 runtime.clone(),
-                nickname.clone(),
+                self.nickname.clone(),
                 pow_nonce_dir,
-                keymgr.clone(),
+                self.keymgr.clone(),
                 pow_manager_storage_handle,
                 netdir_provider.clone(),
             )
             .unwrap();
-            let mut status_rx = status_tx.subscribe();
-            let publisher: Publisher<MockRuntime, MockReactorState<_>> = Publisher::new(
+            let status_rx = status_sender.subscribe();
+            let descriptor_summaries = DescriptorSummaries::default();
+            let descriptor_sink = Arc::new(TestDescriptorSink::default());
+            let (paused_tx, paused_rx) = watch::channel_with(false);
+            let publisher: Publisher<R, MockReactorState<_>> = Publisher::new(
                 runtime.clone(),
-                nickname,
-                netdir_provider,
+                self.nickname,
+                netdir_provider.clone(),
                 circpool,
                 pv,
                 config_rx,
                 status_tx,
-                keymgr,
+                self.keymgr,
                 Arc::new(CfgPathResolver::default()),
                 pow_manager,
                 update_from_pow_manager_rx,
+                paused_rx,
+                Arc::clone(&descriptor_summaries),
+                Some(Arc::clone(&descriptor_sink) as Arc<dyn DescriptorSink>),
+                hsdir_cache_storage_handle,
             );
 
             publisher.launch().unwrap();
-            runtime.progress_until_stalled().await;
-            let status = status_rx.next().await.unwrap().publisher_status();
+
+            TestPublisher {
+                runtime: self.runtime,
+                config: self.config,
+                config_tx,
+                ipts,
+                ipt_orport: self.ipt_orport,
+                netdir: self.netdir,
+                netdir_provider,
+                publish_count,
+                circuit_count,
+                status_sender,
+                status_rx,
+                paused_tx,
+                descriptor_summaries,
+                descriptor_sink,
+                hsdir_cache,
+                _keystore_dir: self.keystore_dir,
+            }
+        }
+    }
+
+    /// A publisher launched by a [`PublisherTest`], and the means to observe and control it.
+    struct TestPublisher {
+        /// The runtime that drives the publisher.
+        runtime: MockRuntime,
+        /// The current configuration of the service.
+        config: OnionServiceConfig,
+        /// Tells the publisher about configuration changes.
+        config_tx: watch::Sender<Arc<OnionServiceConfig>>,
+        /// Tells the publisher about new introduction points.
+        ipts: IptsManagerView,
+        /// If not `None`, the only ORPort our introduction points advertise.
+        ipt_orport: Option<SocketAddr>,
+        /// The network the publisher sees.
+        netdir: Arc<NetDir>,
+        /// Provides `netdir` to the publisher.
+        netdir_provider: Arc<MaybeTimelyNetDirProvider>,
+        /// The number of `POST /tor/hs/3/publish` requests sent by the publisher.
+        publish_count: Arc<AtomicUsize>,
+        /// The number of circuits launched by the publisher.
+        circuit_count: Arc<AtomicUsize>,
+        /// The status of the service, as reported by the publisher.
+        status_sender: StatusSender,
+        /// The status updates of the service.
+        status_rx: OnionServiceStatusStream,
+        /// Tells the publisher whether to pause publication.
+        paused_tx: watch::Sender<bool>,
+        /// The summaries of the descriptors the publisher published.
+        descriptor_summaries: DescriptorSummaries,
+        /// Every descriptor the publisher built.
+        descriptor_sink: Arc<TestDescriptorSink>,
+        /// Where the publisher saves its HsDirs.
+        hsdir_cache: HsDirCacheStorageHandle,
+        /// The directory holding the keystore of the service.
+        _keystore_dir: TempDir,
+    }
+
+    impl TestPublisher {
+        /// Let the publisher run until it has nothing left to do, without advancing the time.
+        async fn settle(&self) {
+            self.runtime.progress_until_stalled().await;
+        }
+
+        /// Advance the time by `duration`, and let the publisher catch up.
+        async fn advance(&self, duration: Duration) {
+            self.runtime.advance_by(duration).await;
+            self.settle().await;
+        }
+
+        /// Give the publisher a new set of introduction points, and let it act on them.
+        async fn update_ipts(&mut self) {
+            self.ipts.borrow_for_update(self.runtime.clone()).ipts =
+                Some(test_ipt_set(&self.runtime, self.ipt_orport));
+            self.settle().await;
+        }
+
+        /// Give the publisher a new set of introduction points,
+        /// and wait until it has uploaded the resulting descriptors.
+        async fn publish(&mut self) {
+            self.update_ipts().await;
+            // We need to advance the time, because failed uploads are retried
+            // after a delay (see BackoffSchedule::next_delay).
+            self.advance(Duration::from_secs(1)).await;
+        }
+
+        /// Pause or resume publication.
+        async fn set_paused(&mut self, paused: bool) {
+            self.paused_tx.maybe_send(|_| paused);
+            self.settle().await;
+        }
+
+        /// Change the configuration of the service with `reconfigure`.
+        async fn reconfigure(&mut self, reconfigure: impl FnOnce(&mut OnionServiceConfig)) {
+            reconfigure(&mut self.config);
+            *self.config_tx.borrow_mut() = Arc::new(self.config.clone());
+            self.settle().await;
+        }
+
+        /// Tell the publisher about a new consensus (with the same contents as the old one).
+        async fn new_consensus(&self) {
+            self.netdir_provider
+                .inner
+                .set_netdir_and_notify(Arc::clone(&self.netdir))
+                .await;
+            self.settle().await;
+        }
+
+        /// Return the number of `POST /tor/hs/3/publish` requests sent so far.
+        fn publish_count(&self) -> usize {
+            self.publish_count.load(Ordering::SeqCst)
+        }
+
+        /// Return the number of circuits launched so far.
+        fn circuit_count(&self) -> usize {
+            self.circuit_count.load(Ordering::SeqCst)
+        }
+
+        /// Return the current state of the publisher.
+        fn state(&self) -> State {
+            self.status_sender.get().publisher_status().state()
+        }
+
+        /// Return the problem the publisher currently reports, if any.
+        fn problem(&self) -> Option<Problem> {
+            self.status_sender
+                .get()
+                .publisher_status()
+                .current_problem()
+                .cloned()
+        }
+    }
+
+    /// Run `test`, in which the IPTs change once the publisher has started.
+    ///
+    /// If `pause_before_event` is true, publication is paused before the IPTs change,
+    /// and resumed afterwards.
+    /// If `postponed_for` is not `None`, we expect publication to be postponed for that long.
+    fn run_test(
+        test: PublisherTest,
+        expected_upload_count: usize,
+        expected_circuit_count: usize,
+        republish_count: usize,
+        expect_errors: bool,
+        pause_before_event: bool,
+        postponed_for: Option<Duration>,
+    ) {
+        let one_descriptor_per_round = test.config.one_descriptor_per_round;
+        test.run(|mut publisher| async move {
+            let status = publisher.status_rx.next().await.unwrap().publisher_status();
             assert_eq!(State::Shutdown, status.state());
             assert!(status.current_problem().is_none());
 
             // Check that we haven't published anything yet
-            assert_eq!(publish_count.load(Ordering::SeqCst), 0);
+            assert_eq!(publisher.publish_count(), 0);
+
+            if pause_before_event {
+                publisher.set_paused(true).await;
+            }
+
+            publisher.update_ipts().await;
+
+            if pause_before_event {
+                // Nothing is uploaded while we're paused, not even after the reupload timers
+                // would have fired...
+                publisher.advance(Duration::from_secs(60 * 120)).await;
+                assert_eq!(publisher.publish_count(), 0);
+
+                // ...but the descriptor is uploaded as soon as we resume.
+                publisher.set_paused(false).await;
+            }
 
-            reactor_event();
+            if let Some(postponed_for) = postponed_for {
+                // Nothing is uploaded until the postponement is over...
+                publisher
+                    .advance(postponed_for - Duration::from_secs(1))
+                    .await;
+                assert_eq!(publisher.publish_count(), 0);
 
-            runtime.progress_until_stalled().await;
+                // ...and then we publish anyway.
+                publisher.advance(Duration::from_secs(1)).await;
+            }
 
             // We need to manually advance the time, because some of our tests check that the
             // failed uploads are retried, and there's a sleep() between the retries
             // (see BackoffSchedule::next_delay).
-            runtime.advance_by(Duration::from_secs(1)).await;
-            runtime.progress_until_stalled().await;
+            publisher.advance(Duration::from_secs(1)).await;
 
-            let initial_publish_count = publish_count.load(Ordering::SeqCst);
+            let initial_publish_count = publisher.publish_count();
             assert_eq!(initial_publish_count, expected_upload_count);
+            // Failed uploads are retried over the same circuit.
+            assert_eq!(publisher.circuit_count(), expected_circuit_count);
+
+            if expected_upload_count > 0 {
+                // We remember what we published.
+                let summaries = publisher.descriptor_summaries.lock().unwrap();
+                assert!(!summaries.is_empty());
+                for (period, summary) in summaries.iter() {
+                    assert_eq!(summary.time_period, *period);
+                    assert!(summary.n_intro_points > 0);
+                    assert!(summary.n_authorized_clients.is_none());
+                    assert_eq!(summary.lifetime, Duration::from_secs(20));
+                    assert!(summary.len > 0);
+                }
+
+                // We were told about each descriptor we built,
+                // including the last one for each time period.
+                let built = publisher.descriptor_sink.0.lock().unwrap();
+                assert!(!built.is_empty());
+                for desc in built.iter() {
+                    assert_eq!(desc.nickname.to_string(), TEST_SVC_NICKNAME);
+                    assert!(desc.descriptor.starts_with("hs-descriptor 3\n"));
+                }
+                for (period, summary) in summaries.iter() {
+                    assert!(built.iter().any(|desc| desc.time_period == *period
+                        && desc.revision_counter == summary.revision_counter
+                        && desc.descriptor.len() == summary.len));
+                    if one_descriptor_per_round {
+                        // All the HsDirs of the time period got the same descriptor.
+                        let n_built = built.iter().filter(|d| d.time_period == *period).count();
+                        assert_eq!(n_built, 1);
+                    }
+                }
+            }
 
-            let status = status_rx.next().await.unwrap().publisher_status();
+            let status = publisher.status_rx.next().await.unwrap().publisher_status();
             if expect_errors {
                 // The upload results aren't ready yet.
                 assert_eq!(State::Bootstrapping, status.state());
@@ -556,25 +1176,204 @@ mod test {
                 const MAX_TIMEOUT: Duration = Duration::from_secs(60 * 120);
 
                 // Wait until the reactor triggers the necessary number of reuploads.
-                runtime
-                    .advance_by(MAX_TIMEOUT * (republish_count as u32))
+                publisher
+                    .advance(MAX_TIMEOUT * (republish_count as u32))
                     .await;
-                runtime.progress_until_stalled().await;
 
                 let min_upload_count = expected_upload_count * republish_count;
                 // There will be twice as many reuploads if the publisher happens
                 // to reupload every hour (as opposed to every 2h).
                 let max_upload_count = 2 * min_upload_count;
-                let publish_count_now = publish_count.load(Ordering::SeqCst);
                 // This is the total number of reuploads (i.e. the number of times
                 // we published the descriptor to an HsDir).
-                let actual_reupload_count = publish_count_now - initial_publish_count;
+                let actual_reupload_count = publisher.publish_count() - initial_publish_count;
 
                 assert!((min_upload_count..=max_upload_count).contains(&actual_reupload_count));
             }
         });
     }
 
+    /// Return a test network whose relays can all act as HsDirs.
+    fn construct_test_netdir() -> NetDir {
+        testnet::construct_custom_netdir(|idx, node, _| advertise_hsdir_protocols(idx, node))
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap()
+    }
+
+    /// Return a test network whose relays can all act as HsDirs,
+    /// and which has shared random values,
+    /// so that we also upload to the HsDirs of the time periods around the current one.
+    fn construct_test_netdir_with_srvs() -> NetDir {
+        const SRV1: [u8; 32] = *b"The door refused to open.       ";
+        const SRV2: [u8; 32] = *b"It said, 'Five cents, please.'  ";
+
+        testnet::construct_custom_netdir(|idx, node, bld| {
+            advertise_hsdir_protocols(idx, node);
+            bld.shared_rand_cur(7, SRV1.into(), None)
+                .shared_rand_prev(7, SRV2.into(), None);
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap()
+    }
+
+    /// Make the relay numbered `idx` of a test network advertise the HSDir protocols.
+    ///
+    /// The default test network doesn't advertise any,
+    /// so we have to add them, or the publisher would refuse to upload to any HsDir.
+    fn advertise_hsdir_protocols(idx: usize, node: &mut testnet::NodeBuilders) {
+        let protocols = if idx % 2 == 0 {
+            "DirCache=2 HSDir=2"
+        } else {
+            "HSDir=2"
+        };
+        node.rs.protos(protocols.parse().unwrap());
+    }
+
+    /// Return a set containing the introduction points of our test descriptor.
+    ///
+    /// If `ipt_orport` is not `None`, it is the only ORPort these introduction points advertise.
+    fn test_ipt_set(runtime: &MockRuntime, ipt_orport: Option<SocketAddr>) -> IptSet {
+        let ipts = test_data::test_parsed_hsdesc()
+            .unwrap()
+            .intro_points()
+            .iter()
+            .enumerate()
+            .map(|(i, ipt)| {
+                let ipt = match ipt_orport {
+                    None => ipt.clone(),
+                    Some(addr) => IntroPointDesc::builder()
+                        .link_specifiers(vec![
+                            LinkSpec::OrPort(addr.ip(), addr.port()).encode().unwrap(),
+                        ])
+                        .ipt_kp_ntor(ipt.ipt_ntor_key().clone())
+                        .kp_hs_ipt_sid(ipt.ipt_sid_key().clone())
+                        .kp_hss_ntor(ipt.svc_ntor_key().clone())
+                        .build()
+                        .unwrap(),
+                };
+                IptInSet {
+                    ipt,
+                    lid: [i.try_into().unwrap(); 32].into(),
+                    planned_retirement: runtime.now(),
+                }
+            })
+            .collect();
+
+        IptSet {
+            ipts,
+            lifetime: Duration::from_secs(20),
+        }
+    }
+
+    /// A test that the publisher publishes the descriptor when the IPTs change,
+    /// with any settings that [`publish_after_ipt_change`] doesn't take.
+    ///
+    /// By default, publication isn't paused, the uploads don't suffer any faults,
+    /// and our introduction points are those of the test descriptor.
+    #[derive(Default)]
+    struct IptChangeTest<'a> {
+        /// Whether publication is paused before the IPTs change, and resumed afterwards.
+        pause_before_event: bool,
+        /// The faults to inject into the uploads.
+        ///
+        /// `faults[i]` is injected into the uploads to the `i`th HsDir we upload to.
+        /// Each of these HsDirs may suffer at most one fault,
+        /// so that its upload succeeds on the first retry
+        /// (a [`Fault::Delay`] must therefore be shorter than the first retry delay, of one second).
+        /// Any faults beyond the number of HsDirs are ignored.
+        faults: &'a [Option<Fault>],
+        /// If not `None`, the only ORPort our introduction points advertise.
+        ipt_orport: Option<SocketAddr>,
+        /// Whether we expect the publisher to postpone publication for as long as it can,
+        /// because our introduction points can't be reached over IPv4.
+        expect_postponed: bool,
+        /// Adjusts the configuration of the service before the test starts.
+        configure: Option<Box<dyn FnOnce(&mut OnionServiceConfig) + 'a>>,
+    }
+
+    impl<'a> IptChangeTest<'a> {
+        /// Pause publication before the IPTs change, and resume it afterwards.
+        fn paused(mut self) -> Self {
+            self.pause_before_event = true;
+            self
+        }
+
+        /// Inject `faults` into the uploads.
+        fn faults(mut self, faults: &'a [Option<Fault>]) -> Self {
+            self.faults = faults;
+            self
+        }
+
+        /// Make `orport` the only ORPort our introduction points advertise.
+        fn ipt_orport(mut self, orport: SocketAddr) -> Self {
+            self.ipt_orport = Some(orport);
+            self
+        }
+
+        /// Expect the publisher to postpone publication for as long as it can.
+        fn expect_postponed(mut self) -> Self {
+            self.expect_postponed = true;
+            self
+        }
+
+        /// Adjust the configuration of the service with `configure`.
+        fn configure(mut self, configure: impl FnOnce(&mut OnionServiceConfig) + 'a) -> Self {
+            self.configure = Some(Box::new(configure));
+            self
+        }
+
+        /// Run the test.
+        ///
+        /// The arguments are those of [`publish_after_ipt_change`].
+        fn run<I: PollReadIter>(
+            self,
+            temp_dir: &Path,
+            poll_read_responses: I,
+            multiplier: usize,
+            republish_count: usize,
+            expect_errors: bool,
+        ) {
+            let mut test = PublisherTest::new(temp_dir);
+            if let Some(configure) = self.configure {
+                configure(&mut test.config);
+            }
+            test.poll_read_responses = poll_read_responses.collect();
+            test.ipt_orport = self.ipt_orport;
+
+            let hsdirs = test.hsdirs();
+            let hsdir_count = hsdirs.len();
+
+            assert!(hsdir_count > 0);
+
+            // If any of the uploads fail, they will be retried. Note that the upload failure will
+            // affect _each_ hsdir, so the expected number of uploads is a multiple of hsdir_count.
+            let mut expected_upload_count = hsdir_count * multiplier;
+            let mut expected_circuit_count = hsdir_count;
+
+            // The injected faults only affect the HsDirs they were injected into.
+            for (hsdir, fault) in hsdirs.iter().zip(self.faults) {
+                let Some(fault) = fault else { continue };
+                let (extra_uploads, extra_circuits) = fault.extra_uploads_and_circuits();
+                expected_upload_count += extra_uploads;
+                expected_circuit_count += extra_circuits;
+                test.faults
+                    .inject(*hsdir.rsa_identity().unwrap(), [fault.clone()]);
+            }
+
+            run_test(
+                test,
+                expected_upload_count,
+                expected_circuit_count,
+                republish_count,
+                expect_errors,
+                self.pause_before_event,
+                self.expect_postponed.then_some(IPT_ADDR_FAMILY_MAX_DELAY),
+            );
+        }
+    }
+
     /// Test that the publisher publishes the descriptor when the IPTs change.
     ///
     /// The `poll_read_responses` are returned by each HSDir, in order, in response to each POST
@@ -591,59 +1390,10 @@ mod test {
         republish_count: usize,
         expect_errors: bool,
     ) {
-        let runtime = MockRuntime::new();
-        let nickname = HsNickname::try_from(TEST_SVC_NICKNAME.to_string()).unwrap();
-        let config = build_test_config(nickname.clone());
-        let (_config_tx, config_rx) = watch::channel_with(Arc::new(config));
-
-        let (mut mv, pv) = ipts_channel(&runtime, create_storage_handles(temp_dir).1).unwrap();
-        let update_ipts = || {
-            let ipts: Vec<IptInSet> = test_data::test_parsed_hsdesc()
-                .unwrap()
-                .intro_points()
-                .iter()
-                .enumerate()
-                .map(|(i, ipt)| IptInSet {
-                    ipt: ipt.clone(),
-                    lid: [i.try_into().unwrap(); 32].into(),
-                })
-                .collect();
-
-            mv.borrow_for_update(runtime.clone()).ipts = Some(IptSet {
-                ipts,
-                lifetime: Duration::from_secs(20),
-            });
-        };
-
-        let netdir = testnet::construct_netdir().unwrap_if_sufficient().unwrap();
-        let keystore_dir = tempdir().unwrap();
-
-        let (_hsid, blind_id, keymgr) = init_keymgr(&keystore_dir, &nickname, &netdir);
-
-        let hsdir_count = netdir
-            .hs_dirs_upload(blind_id, netdir.hs_time_period())
-            .unwrap()
-            .collect::<Vec<_>>()
-            .len();
-
-        assert!(hsdir_count > 0);
-
-        // If any of the uploads fail, they will be retried. Note that the upload failure will
-        // affect _each_ hsdir, so the expected number of uploads is a multiple of hsdir_count.
-        let expected_upload_count = hsdir_count * multiplier;
-        let status_tx = StatusSender::new(OnionServiceStatus::new_shutdown()).into();
-
-        run_test(
-            runtime.clone(),
-            nickname,
-            keymgr,
-            pv,
-            config_rx,
-            status_tx,
-            netdir,
-            update_ipts,
+        IptChangeTest::default().run(
+            temp_dir,
             poll_read_responses,
-            expected_upload_count,
+            multiplier,
             republish_count,
             expect_errors,
         );
@@ -692,6 +1442,658 @@ mod test {
             .used_by(|dir| publish_after_ipt_change(dir, poll_reads, 1, REUPLOAD_COUNT, false));
     }
 
+    #[test]
+    fn publish_after_resuming() {
+        // The IPTs change while publication is paused, so we only publish once we resume.
+        let poll_reads = [Ok(OK_RESPONSE.into())].into_iter();
+
+        test_temp_dir!().used_by(|dir| {
+            IptChangeTest::default()
+                .paused()
+                .run(dir, poll_reads, 1, 0, false)
+        });
+    }
+
+    #[test]
+    fn publish_one_descriptor_per_round() {
+        // Each HSDir first fails, so that we check that retries reuse the descriptor too.
+        let poll_reads = [Err(()), Ok(OK_RESPONSE.into())].into_iter();
+
+        test_temp_dir!().used_by(|dir| {
+            IptChangeTest::default()
+                .configure(|config| config.one_descriptor_per_round = true)
+                .run(dir, poll_reads, 2, 0, true);
+        });
+    }
+
+    #[test]
+    fn publish_primary_ring_first() {
+        // Uploads to the other time periods must still happen once the primary ring is done,
+        // even if some of the uploads to it have to be retried.
+        let poll_reads = [Err(()), Ok(OK_RESPONSE.into())].into_iter();
+
+        test_temp_dir!().used_by(|dir| {
+            IptChangeTest::default()
+                .configure(|config| config.primary_ring_first_percent = Some(50))
+                .run(dir, poll_reads, 2, 0, true);
+        });
+    }
+
+    #[test]
+    fn publish_other_rings_after_primary() {
+        for percent in [None, Some(50)] {
+            test_temp_dir!().used_by(|dir| {
+                let mut test = PublisherTest::new(dir);
+                test.config.primary_ring_first_percent = percent;
+                test.netdir = Arc::new(construct_test_netdir_with_srvs());
+                let n_time_periods = test.netdir.hs_all_time_periods().len();
+                assert!(n_time_periods > 1);
+
+                // Half of the uploads to the HsDirs of the current time period
+                // take 10 seconds, and the rest take 20.
+                let hsdirs = test.hsdirs();
+                let n_fast = hsdirs.len().div_ceil(2);
+                for (i, hsdir) in hsdirs.iter().enumerate() {
+                    let delay = if i < n_fast { 10 } else { 20 };
+                    test.faults.inject(
+                        *hsdir.rsa_identity().unwrap(),
+                        [Fault::Delay(Duration::from_secs(delay))],
+                    );
+                }
+
+                test.run(|mut publisher| async move {
+                    publisher.update_ipts().await;
+
+                    publisher.advance(Duration::from_secs(9)).await;
+                    if percent.is_none() {
+                        // The uploads for the other time periods don't wait.
+                        assert!(publisher.publish_count() > 0);
+                    } else {
+                        // The uploads for the other time periods wait for the primary ring...
+                        assert_eq!(publisher.publish_count(), 0);
+
+                        // ...but only until half of its uploads have succeeded.
+                        publisher.advance(Duration::from_secs(1)).await;
+                        assert!(publisher.publish_count() > n_fast);
+                    }
+
+                    // In the end, we upload to the HsDirs of all our time periods.
+                    publisher.advance(Duration::from_secs(20)).await;
+                    assert_eq!(
+                        publisher.descriptor_summaries.lock().unwrap().len(),
+                        n_time_periods
+                    );
+                });
+            });
+        }
+    }
+
+    #[test]
+    fn publish_despite_faults() {
+        // Some of the HsDirs misbehave once, each in a different way.
+        // Their uploads are retried, and all of them succeed in the end,
+        // so the faults don't show in our status.
+        let faults = [
+            Some(Fault::Circuit),
+            None,
+            Some(Fault::Stream),
+            Some(Fault::Malformed),
+            Some(Fault::Delay(Duration::from_millis(500))),
+        ];
+        let poll_reads = [Ok(OK_RESPONSE.into())].into_iter();
+
+        test_temp_dir!().used_by(|dir| {
+            IptChangeTest::default()
+                .faults(&faults)
+                .run(dir, poll_reads, 1, 0, false)
+        });
+    }
+
+    #[test]
+    fn publish_despite_fault_on_every_hsdir() {
+        for fault in [Fault::Circuit, Fault::Stream, Fault::Malformed] {
+            let faults = vec![Some(fault); 100];
+            let poll_reads = [Ok(OK_RESPONSE.into())].into_iter();
+
+            test_temp_dir!().used_by(|dir| {
+                IptChangeTest::default()
+                    .faults(&faults)
+                    .run(dir, poll_reads, 1, 0, false);
+            });
+        }
+    }
+
+    #[test]
+    fn publish_ipv4_only_ipts_without_delay() {
+        // Every client can reach IPv4 introduction points,
+        // so we don't wait for better ones, even if the policy says we may.
+        let poll_reads = [Ok(OK_RESPONSE.into())].into_iter();
+        let v4 = "192.0.2.1:9001".parse().unwrap();
+
+        test_temp_dir!().used_by(|dir| {
+            IptChangeTest::default()
+                .ipt_orport(v4)
+                .configure(|config| config.ipt_addr_family_policy = IptAddrFamilyPolicy::Delay)
+                .run(dir, poll_reads, 1, 0, false);
+        });
+    }
+
+    #[test]
+    fn publish_ipv6_only_ipts() {
+        let v6 = "[2001:db8::1]:9001".parse().unwrap();
+
+        // By default, we just warn about introduction points that can't be reached over IPv4.
+        for policy in [IptAddrFamilyPolicy::Warn, IptAddrFamilyPolicy::Ignore] {
+            let poll_reads = [Ok(OK_RESPONSE.into())].into_iter();
+            test_temp_dir!().used_by(|dir| {
+                IptChangeTest::default()
+                    .ipt_orport(v6)
+                    .configure(|config| config.ipt_addr_family_policy = policy)
+                    .run(dir, poll_reads, 1, 0, false);
+            });
+        }
+
+        // With the `Delay` policy, we wait for better introduction points,
+        // and publish what we have once we've waited long enough.
+        let poll_reads = [Ok(OK_RESPONSE.into())].into_iter();
+        test_temp_dir!().used_by(|dir| {
+            IptChangeTest::default()
+                .ipt_orport(v6)
+                .expect_postponed()
+                .configure(|config| config.ipt_addr_family_policy = IptAddrFamilyPolicy::Delay)
+                .run(dir, poll_reads, 1, 0, false);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "restricted-discovery")]
+    fn pause_while_config_broken() {
+        test_temp_dir!().used_by(|dir| {
+            let mut test = PublisherTest::new(dir);
+            // Restricted discovery mode without any authorized clients is a broken configuration.
+            test.config.restricted_discovery.enabled = true;
+
+            test.run(|mut publisher| async move {
+                publisher.update_ipts().await;
+                assert_eq!(publisher.state(), State::Broken);
+
+                // Pausing and resuming publication doesn't hide our broken configuration,
+                // or make us try to publish anyway.
+                publisher.set_paused(true).await;
+                assert_eq!(publisher.state(), State::Broken);
+                publisher.set_paused(false).await;
+                publisher.advance(Duration::from_secs(60)).await;
+                assert_eq!(publisher.state(), State::Broken);
+                assert_eq!(publisher.publish_count(), 0);
+
+                // If we are paused when the configuration is fixed, we stay paused...
+                publisher.set_paused(true).await;
+                publisher
+                    .reconfigure(|config| config.restricted_discovery.enabled = false)
+                    .await;
+                publisher.advance(Duration::from_secs(60)).await;
+                assert_ne!(publisher.state(), State::Broken);
+                assert_eq!(publisher.publish_count(), 0);
+
+                // ...until publication is resumed.
+                publisher.set_paused(false).await;
+                publisher.advance(Duration::from_secs(1)).await;
+                assert!(publisher.publish_count() > 0);
+            });
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "restricted-discovery")]
+    fn no_flapping_while_config_broken() {
+        test_temp_dir!().used_by(|dir| {
+            let mut test = PublisherTest::new(dir);
+            // Restricted discovery mode without any authorized clients is a broken configuration.
+            test.config.restricted_discovery.enabled = true;
+
+            test.run(|mut publisher| async move {
+                publisher.update_ipts().await;
+                assert_eq!(publisher.state(), State::Broken);
+                assert!(matches!(
+                    publisher.problem(),
+                    Some(Problem::RestrictedDiscoveryNoClients)
+                ));
+                let n_transitions = publisher.status_sender.history().len();
+
+                // Further IPT changes don't make us try to upload again,
+                // or make our status go back to Bootstrapping.
+                for _ in 0..5 {
+                    publisher.update_ipts().await;
+                    publisher.advance(Duration::from_secs(60)).await;
+                    assert_eq!(publisher.state(), State::Broken);
+                }
+                assert_eq!(publisher.status_sender.history().len(), n_transitions);
+                assert_eq!(publisher.publish_count(), 0);
+                assert_eq!(publisher.circuit_count(), 0);
+
+                // Once the configuration changes, we publish again.
+                publisher
+                    .reconfigure(|config| config.restricted_discovery.enabled = false)
+                    .await;
+                publisher.advance(Duration::from_secs(1)).await;
+                assert_ne!(publisher.state(), State::Broken);
+                assert!(publisher.publish_count() > 0);
+            });
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "restricted-discovery")]
+    fn publish_after_key_dir_created() {
+        use crate::config::restricted_discovery::DirectoryKeyProviderBuilder;
+        use tor_config_path::CfgPath;
+
+        test_temp_dir!().used_by(|dir| {
+            let mut test = PublisherTest::new(dir);
+            // Neither the key_dir nor its parent exist yet,
+            // so the file watcher can't tell us when they are created.
+            let key_dir_parent = tempdir().unwrap();
+            let key_dir = key_dir_parent.path().join("not_yet").join("keys");
+            let mut key_dir_provider = DirectoryKeyProviderBuilder::default();
+            key_dir_provider
+                .path(CfgPath::new_literal(&key_dir))
+                .permissions()
+                .dangerously_trust_everyone();
+            let mut config = OnionServiceConfigBuilder::default();
+            config
+                .nickname(test.nickname.clone())
+                .rate_limit_at_intro(None)
+                .restricted_discovery()
+                .enabled(true)
+                .watch_configuration(true)
+                .key_dirs()
+                .access()
+                .push(key_dir_provider);
+            test.config = config.build().unwrap();
+
+            test.run(|mut publisher| async move {
+                // Without any authorized clients, our configuration is broken.
+                publisher.update_ipts().await;
+                assert_eq!(publisher.state(), State::Broken);
+
+                // Once the key_dir has been created, we notice within KEY_DIRS_RECHECK_INTERVAL,
+                // and publish a descriptor for the client whose key it holds.
+                std::fs::create_dir_all(&key_dir).unwrap();
+                std::fs::write(
+                    key_dir.join("alice.auth"),
+                    "descriptor:x25519:zprrmiv6dv6sjfl7sfbsvlj5vunpgcdfevz7m23ltlvtccxjqbka",
+                )
+                .unwrap();
+                publisher
+                    .advance(KEY_DIRS_RECHECK_INTERVAL - Duration::from_secs(1))
+                    .await;
+                assert_eq!(publisher.state(), State::Broken);
+                assert_eq!(publisher.publish_count(), 0);
+
+                publisher.advance(Duration::from_secs(1)).await;
+                assert_ne!(publisher.state(), State::Broken);
+                publisher.advance(Duration::from_secs(1)).await;
+                assert!(publisher.publish_count() > 0);
+                let summaries = publisher.descriptor_summaries.lock().unwrap();
+                assert!(!summaries.is_empty());
+                for summary in summaries.values() {
+                    assert_eq!(summary.n_authorized_clients, Some(1));
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn retry_after_keystore_failure() {
+        test_temp_dir!().used_by(|dir| {
+            let test = PublisherTest::new(dir);
+            let period = test.netdir.hs_time_period();
+
+            // Make the descriptor signing key unreadable, in a way that the keystore
+            // reports as an access failure, rather than as a corrupted keystore.
+            let desc_sign_key = std::fs::read_dir(
+                test.keystore_dir
+                    .path()
+                    .join("hss")
+                    .join(test.nickname.to_string()),
+            )
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                let name = path.file_name().unwrap().to_string_lossy();
+                name.starts_with("ks_hs_desc_sign")
+            })
+            .unwrap();
+            let desc_sign_key_contents = std::fs::read(&desc_sign_key).unwrap();
+            std::fs::write(&desc_sign_key, [0xff]).unwrap();
+
+            test.run(|mut publisher| async move {
+                let built = |publisher: &TestPublisher| {
+                    publisher
+                        .descriptor_summaries
+                        .lock()
+                        .unwrap()
+                        .contains_key(&period)
+                };
+
+                publisher.publish().await;
+                assert!(!built(&publisher));
+                assert_ne!(publisher.state(), State::Broken);
+                let failed_publish_count = publisher.publish_count();
+
+                // Once the keystore is readable again, we build and upload the descriptor,
+                // without anything else having to happen first.
+                std::fs::write(&desc_sign_key, desc_sign_key_contents).unwrap();
+                // (Our last attempt still counts towards the upload rate limit.)
+                publisher.advance(Duration::from_secs(60)).await;
+                publisher.advance(Duration::from_secs(1)).await;
+                assert!(built(&publisher));
+                assert!(publisher.publish_count() > failed_publish_count);
+                assert!(!matches!(
+                    publisher.problem(),
+                    Some(Problem::KeystoreUnavailable(_))
+                ));
+            });
+        });
+    }
+
+    #[test]
+    fn publish_from_hsdir_cache() {
+        test_temp_dir!().used_by(|dir| {
+            let mut test = PublisherTest::new(dir);
+            let period = test.netdir.hs_time_period();
+            let hsdirs = test.hsdirs();
+            assert!(hsdirs.len() > 2);
+
+            // Before we restarted, we saved some of the HsDirs of the current time period,
+            // and we don't have a timely netdir yet.
+            let valid_until = test.runtime.wallclock() + Duration::from_secs(60 * 60 * 24);
+            test.saved_hsdirs = Some(HsDirCacheRecord::new(
+                valid_until,
+                vec![(period, hsdirs[..2].to_vec())],
+            ));
+            test.timely = false;
+
+            test.run(|mut publisher| async move {
+                // Without a timely netdir, we upload to the saved HsDirs.
+                publisher.publish().await;
+                assert_eq!(publisher.publish_count(), 2);
+
+                // Once we have a timely netdir, we replace the saved HsDirs,
+                // and upload to the ones we haven't uploaded to yet
+                // (once the rate limit allows).
+                publisher
+                    .netdir_provider
+                    .timely
+                    .store(true, Ordering::SeqCst);
+                publisher.new_consensus().await;
+                publisher.advance(Duration::from_secs(61)).await;
+                assert!(publisher.publish_count() >= hsdirs.len());
+
+                let saved = publisher.hsdir_cache.load().unwrap().unwrap();
+                assert_eq!(saved.hs_dirs(period).unwrap().len(), hsdirs.len());
+            });
+        });
+    }
+
+    #[test]
+    fn report_unsupported_hsdirs() {
+        test_temp_dir!().used_by(|dir| {
+            let mut test = PublisherTest::new(dir);
+            // Only the odd-numbered relays support v3 descriptors.
+            let netdir = testnet::construct_custom_netdir(|idx, node, _| {
+                let protocols = if idx % 2 == 0 {
+                    "DirCache=2"
+                } else {
+                    "HSDir=2"
+                };
+                node.rs.protos(protocols.parse().unwrap());
+            })
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+            let n_unsupported = netdir
+                .hs_dirs_upload(test.blind_id, netdir.hs_time_period())
+                .unwrap()
+                .filter(|relay| {
+                    !relay
+                        .protovers()
+                        .supports_named_subver(tor_protover::named::HSDIR_V3)
+                })
+                .count();
+            assert!(n_unsupported > 0);
+            test.netdir = Arc::new(netdir);
+
+            test.run(|mut publisher| async move {
+                publisher.publish().await;
+
+                // Every other upload succeeded,
+                // and the HsDirs we didn't upload to are only reported as a warning.
+                assert_eq!(publisher.state(), State::Running);
+                let Some(Problem::UnsupportedHsDirs(n)) = publisher.problem() else {
+                    panic!("unexpected problem {:?}", publisher.problem());
+                };
+                assert!(n >= n_unsupported);
+            });
+        });
+    }
+
+    #[test]
+    fn running_with_one_unsupported_hsdir() {
+        test_temp_dir!().used_by(|dir| {
+            let mut test = PublisherTest::new(dir);
+            // Find a member of our HsDir ring for the current time period,
+            // and build a network in which it is the only relay without HSDir=2.
+            // (The identities of the relays of the test network are their index, repeated.)
+            let unsupported_idx =
+                usize::from(test.hsdirs()[0].ed_identity().unwrap().as_bytes()[0]);
+            test.netdir = Arc::new(
+                testnet::construct_custom_netdir(|idx, node, _| {
+                    if idx == unsupported_idx {
+                        node.rs.protos("DirCache=2".parse().unwrap());
+                    } else {
+                        advertise_hsdir_protocols(idx, node);
+                    }
+                })
+                .unwrap()
+                .unwrap_if_sufficient()
+                .unwrap(),
+            );
+
+            test.run(|mut publisher| async move {
+                publisher.publish().await;
+
+                // Every upload we made succeeded,
+                // so the HsDir we skipped doesn't stop us from running.
+                assert_eq!(publisher.state(), State::Running);
+                assert!(matches!(
+                    publisher.problem(),
+                    Some(Problem::UnsupportedHsDirs(n)) if n >= 1
+                ));
+            });
+        });
+    }
+
+    #[test]
+    fn retry_expired_key_removal() {
+        test_temp_dir!().used_by(|dir| {
+            let test = PublisherTest::new(dir);
+            let old_key_spec = test.insert_expired_key();
+            let old_key_exists = {
+                let keymgr = Arc::clone(&test.keymgr);
+                move || {
+                    keymgr
+                        .get::<HsDescSigningKeypair>(&old_key_spec)
+                        .unwrap()
+                        .is_some()
+                }
+            };
+            let fail_removals = Arc::clone(&test.fail_removals);
+            fail_removals.store(true, Ordering::SeqCst);
+
+            test.run(|mut publisher| async move {
+                let pending_deletions = |publisher: &TestPublisher| match publisher.problem() {
+                    Some(Problem::ExpiredKeysNotRemoved(n)) => n,
+                    _ => 0,
+                };
+
+                publisher.publish().await;
+
+                // The next consensus tells us the old key has expired,
+                // but we can't remove it.
+                publisher.new_consensus().await;
+                publisher.advance(Duration::from_secs(61)).await;
+                assert!(old_key_exists());
+                assert_eq!(pending_deletions(&publisher), 1);
+
+                // We keep trying, and keep reporting the key we couldn't remove.
+                publisher.advance(KEY_EXPIRY_RETRY_INTERVAL).await;
+                assert!(old_key_exists());
+                assert_eq!(pending_deletions(&publisher), 1);
+
+                // Once the keystore lets us, our next attempt removes the key.
+                fail_removals.store(false, Ordering::SeqCst);
+                publisher.advance(KEY_EXPIRY_RETRY_INTERVAL).await;
+                assert!(!old_key_exists());
+                assert_eq!(pending_deletions(&publisher), 0);
+            });
+        });
+    }
+
+    /// Check that we report a wall clock that is slow compared to the consensus,
+    /// alongside any other warnings,
+    /// and that we date our descriptors by the consensus if `use_consensus_time_if_skewed`.
+    fn report_clock_skew(use_consensus_time_if_skewed: bool) {
+        test_temp_dir!().used_by(|dir| {
+            let valid_after = construct_test_netdir().lifetime().valid_after();
+            let runtime = MockRuntime::builder()
+                .starting_wallclock(valid_after - Duration::from_secs(2 * 60 * 60))
+                .build();
+            let mut test = PublisherTest::with_runtime(dir, runtime);
+            test.config.use_consensus_time_if_skewed = use_consensus_time_if_skewed;
+            test.fail_removals.store(true, Ordering::SeqCst);
+            test.insert_expired_key();
+
+            test.run(|mut publisher| async move {
+                publisher.publish().await;
+
+                // We published our descriptors, but warn about our clock.
+                assert!(matches!(
+                    publisher.problem(),
+                    Some(Problem::ClockSkew(ClockSkew::Slow(_)))
+                ));
+
+                {
+                    let summaries = publisher.descriptor_summaries.lock().unwrap();
+                    assert!(!summaries.is_empty());
+                    for summary in summaries.values() {
+                        if use_consensus_time_if_skewed {
+                            assert_eq!(summary.built_at, valid_after);
+                        } else {
+                            assert!(summary.built_at < valid_after);
+                            assert!(summary.built_at <= publisher.runtime.wallclock());
+                        }
+                    }
+                }
+
+                // The next consensus tells us the old key has expired,
+                // but we can't remove it: we warn about that too.
+                publisher.new_consensus().await;
+                publisher.advance(Duration::from_secs(61)).await;
+                let Some(Problem::Warnings(warnings)) = publisher.problem() else {
+                    panic!("expected several warnings, got {:?}", publisher.problem());
+                };
+                assert!(matches!(
+                    &warnings[..],
+                    [
+                        Problem::ClockSkew(ClockSkew::Slow(_)),
+                        Problem::ExpiredKeysNotRemoved(1)
+                    ]
+                ));
+            });
+        });
+    }
+
+    #[test]
+    fn report_clock_skew_signing_with_wallclock() {
+        report_clock_skew(false);
+    }
+
+    #[test]
+    fn report_clock_skew_signing_with_consensus_time() {
+        report_clock_skew(true);
+    }
+
+    #[test]
+    fn reupload_scheduled() {
+        /// The earliest time the descriptor can be republished.
+        const MIN_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+        /// The latest time the descriptor can be republished.
+        const MAX_TIMEOUT: Duration = Duration::from_secs(60 * 120);
+
+        test_temp_dir!().used_by(|dir| {
+            let test = PublisherTest::new(dir);
+            let counting = CountingRuntime::new(test.runtime.clone());
+
+            test.run_on(counting.clone(), |mut publisher| async move {
+                // We have nothing to reupload yet.
+                let calls = counting.take_calls();
+                assert_eq!(calls.n_sleeps_in(MIN_TIMEOUT..), 0);
+
+                publisher.publish().await;
+                let upload_count = publisher.publish_count();
+                assert!(upload_count > 0);
+
+                // Once the descriptor is published, we wait for a reupload,
+                // and for nothing later than that.
+                let calls = counting.take_calls();
+                assert!(calls.n_sleeps_in(MIN_TIMEOUT..=MAX_TIMEOUT) > 0);
+                assert_eq!(calls.n_sleeps_in(MAX_TIMEOUT + Duration::from_secs(1)..), 0);
+
+                // Nothing is reuploaded before the earliest reupload time...
+                publisher
+                    .advance(MIN_TIMEOUT - Duration::from_secs(2))
+                    .await;
+                assert_eq!(publisher.publish_count(), upload_count);
+
+                // ...and the descriptor is reuploaded by the latest one,
+                // after which the next reupload is scheduled.
+                counting.take_calls();
+                publisher
+                    .advance(MAX_TIMEOUT - MIN_TIMEOUT + Duration::from_secs(2))
+                    .await;
+                assert!(publisher.publish_count() > upload_count);
+                let calls = counting.take_calls();
+                assert!(calls.n_sleeps_in(MIN_TIMEOUT..=MAX_TIMEOUT) > 0);
+            });
+        });
+    }
+
+    #[test]
+    fn injected_faults() {
+        let faults = InjectedFaults::default();
+        let hsdir = rsa::RsaIdentity::from([1; 20]);
+        let other = rsa::RsaIdentity::from([2; 20]);
+        faults.inject(hsdir, [Fault::Circuit, Fault::Stream]);
+
+        // Faults are only used up where they apply, and in order.
+        assert!(faults.take_if(&other, |_| true).is_none());
+        assert!(
+            faults
+                .take_if(&hsdir, |f| matches!(f, Fault::Stream))
+                .is_none()
+        );
+        assert!(matches!(
+            faults.take_if(&hsdir, |_| true),
+            Some(Fault::Circuit)
+        ));
+        assert!(matches!(
+            faults.take_if(&hsdir, |_| true),
+            Some(Fault::Stream)
+        ));
+        assert!(faults.take_if(&hsdir, |_| true).is_none());
+    }
+
     // TODO (#1120): test that the descriptor is republished when the config changes
 
     // TODO (#1120): test that the descriptor is reuploaded only to the HSDirs that need it (i.e. the
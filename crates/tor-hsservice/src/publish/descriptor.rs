@@ -2,8 +2,13 @@
 
 use super::*;
 use crate::config::OnionServiceConfigPublisherView;
+use crate::ipt_set::IptInSet;
+use std::net::SocketAddr;
 use tor_cell::chancell::msg::HandshakeType;
+use tor_linkspec::{ChanTarget as _, LinkSpec};
 use tor_llcrypto::rng::EntropicRng;
+use tor_netdir::NetDir;
+use tor_netdoc::doc::hsdesc::pow::PowParams;
 
 /// Build the descriptor.
 ///
@@ -41,9 +46,11 @@ pub(super) fn build_sign<Rng: RngCore + CryptoRng, KeyRng: RngCore + EntropicRng
     /// to stop using it."
     const HS_DESC_CERT_LIFETIME_SEC: Duration = Duration::from_secs(54 * 60 * 60);
 
-    let intro_points = ipt_set
-        .ipts
-        .iter()
+    // Note that the IPT set will record all of its introduction points as published,
+    // even those that we leave out.  That's harmless: it just means that the IPT manager
+    // might keep an unadvertised introduction point around for longer than it needs to.
+    let intro_points = select_intro_points(&ipt_set.ipts, config.max_advertised_intro_points)
+        .into_iter()
         .map(|ipt_in_set| ipt_in_set.ipt.clone())
         .collect::<Vec<_>>();
 
@@ -54,10 +61,13 @@ pub(super) fn build_sign<Rng: RngCore + CryptoRng, KeyRng: RngCore + EntropicRng
         .get::<HsIdKey>(&svc_key_spec)?
         .ok_or_else(|| FatalError::MissingHsIdKeypair(nickname.clone()))?;
 
-    // TODO: make the keystore selector configurable
-    let keystore_selector = Default::default();
-    let blind_id_kp = read_blind_id_keypair(keymgr, nickname, period)?
-        .ok_or_else(|| internal!("hidden service offline mode not supported"))?;
+    let blind_id_kp = read_blind_id_keypair(
+        keymgr,
+        nickname,
+        period,
+        keystore_selector(config.blind_id_keystore.as_ref()),
+    )?
+    .ok_or_else(|| internal!("hidden service offline mode not supported"))?;
 
     let blind_id_key = HsBlindIdKey::from(&blind_id_kp);
     let subcredential = hsid.compute_subcredential(&blind_id_key, period);
@@ -65,7 +75,7 @@ pub(super) fn build_sign<Rng: RngCore + CryptoRng, KeyRng: RngCore + EntropicRng
     let hs_desc_sign_key_spec = DescSigningKeypairSpecifier::new(nickname.clone(), period);
     let hs_desc_sign = keymgr.get_or_generate::<HsDescSigningKeypair>(
         &hs_desc_sign_key_spec,
-        keystore_selector,
+        keystore_selector(config.desc_signing_keystore.as_ref()),
         key_rng,
     )?;
 
@@ -119,6 +129,9 @@ pub(super) fn build_sign<Rng: RngCore + CryptoRng, KeyRng: RngCore + EntropicRng
 
     let blind_id_kp = (&blind_id_kp).into();
 
+    // Never advertise our introduction points for longer than the IPT manager expects.
+    let lifetime = std::cmp::min(config.descriptor_lifetime, ipt_set.lifetime);
+
     let mut desc = HsDescBuilder::default()
         .blinded_id(&blind_id_kp)
         .hs_desc_sign(hs_desc_sign.as_ref())
@@ -129,7 +142,7 @@ pub(super) fn build_sign<Rng: RngCore + CryptoRng, KeyRng: RngCore + EntropicRng
         .intro_points(&intro_points[..])
         .intro_auth_key_cert_expiry(intro_auth_key_cert_expiry)
         .intro_enc_key_cert_expiry(intro_enc_key_cert_expiry)
-        .lifetime(((ipt_set.lifetime.as_secs() / 60) as u16).into())
+        .lifetime(((lifetime.as_secs() / 60) as u16).into())
         .revision_counter(revision_counter)
         .subcredential(subcredential)
         .auth_clients(auth_clients.as_deref())
@@ -137,15 +150,16 @@ pub(super) fn build_sign<Rng: RngCore + CryptoRng, KeyRng: RngCore + EntropicRng
 
     cfg_if::cfg_if! {
         if #[cfg(feature = "hs-pow-full")] {
-            let pow_params = pow_manager.get_pow_params(period);
-            match pow_params {
-                Ok(ref pow_params) => {
-                    desc = desc.pow_params(Some(pow_params));
-                },
+            let pow_params = match pow_manager.get_pow_params(period) {
+                Ok(pow_params) => Some(pow_params),
                 Err(err) => {
                     warn!(?err, "Couldn't get PoW params");
+                    None
                 }
-            }
+            };
+            desc = desc.pow_params(pow_params.as_ref());
+        } else {
+            let pow_params: Option<PowParams> = None;
         }
     }
 
@@ -154,12 +168,119 @@ pub(super) fn build_sign<Rng: RngCore + CryptoRng, KeyRng: RngCore + EntropicRng
         e => into_internal!("failed to build descriptor")(e).into(),
     })?;
 
+    let summary = DescriptorSummary {
+        time_period: period,
+        revision_counter,
+        built_at: now,
+        n_intro_points: intro_points.len(),
+        n_authorized_clients: auth_clients.as_ref().map(Vec::len),
+        pow_params,
+        len: desc.len(),
+        lifetime,
+    };
+
     Ok(VersionedDescriptor {
         desc,
         revision_counter,
+        summary,
     })
 }
 
+/// Choose which of the introduction points in `ipts` to list in the descriptor.
+///
+/// If there are more than `max` of them, we choose the ones that the IPT manager
+/// plans to keep for longest: those are the ones that clients will be able to keep using
+/// for the whole lifetime of the descriptor.
+///
+/// The chosen introduction points are returned in the same order as in `ipts`.
+pub(super) fn select_intro_points(ipts: &[IptInSet], max: Option<u8>) -> Vec<&IptInSet> {
+    let Some(max) = max.map(usize::from).filter(|max| *max < ipts.len()) else {
+        return ipts.iter().collect();
+    };
+
+    let mut chosen = ipts.iter().enumerate().collect_vec();
+    // This is a stable sort, so ties are broken by the order of `ipts`.
+    chosen.sort_by_key(|(_, ipt)| std::cmp::Reverse(ipt.planned_retirement));
+    chosen.truncate(max);
+    chosen.sort_by_key(|(idx, _)| *idx);
+
+    chosen.into_iter().map(|(_, ipt)| ipt).collect()
+}
+
+/// How many of a set of introduction points can be reached over each address family.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct IptAddrFamilies {
+    /// The number of introduction points.
+    pub(super) n_ipts: usize,
+    /// The number of introduction points with at least one IPv4 ORPort.
+    pub(super) ipv4: usize,
+    /// The number of introduction points with at least one IPv6 ORPort.
+    pub(super) ipv6: usize,
+}
+
+impl IptAddrFamilies {
+    /// Count the address families over which each of `ipts` can be reached.
+    ///
+    /// If an introduction point is listed in `netdir`, we use the ORPorts from the consensus;
+    /// otherwise, we use the ORPorts from its link specifiers.
+    pub(super) fn count<'i>(ipts: impl IntoIterator<Item = &'i IptInSet>, netdir: &NetDir) -> Self {
+        let mut families = Self::default();
+        for ipt in ipts {
+            // Link specifiers we can't parse can't tell us anything about the addresses.
+            let linkspecs = ipt
+                .ipt
+                .link_specifiers()
+                .iter()
+                .filter_map(|ls| ls.parse().ok())
+                .collect_vec();
+            let mut ids = RelayIds::builder();
+            let mut addrs = vec![];
+            for ls in &linkspecs {
+                match ls {
+                    LinkSpec::Ed25519Id(id) => {
+                        ids.ed_identity(*id);
+                    }
+                    LinkSpec::RsaId(id) => {
+                        ids.rsa_identity(*id);
+                    }
+                    LinkSpec::OrPort(addr, port) => addrs.push(SocketAddr::new(*addr, *port)),
+                    _ => {}
+                }
+            }
+            let relay = ids.build().ok().and_then(|ids| netdir.by_ids(&ids));
+            let addrs = relay
+                .as_ref()
+                .map(|r| r.addrs())
+                .unwrap_or(addrs.as_slice());
+            families.note(addrs);
+        }
+        families
+    }
+
+    /// Record an introduction point that has the ORPorts `addrs`.
+    fn note(&mut self, addrs: &[SocketAddr]) {
+        self.n_ipts += 1;
+        if addrs.iter().any(|a| a.is_ipv4()) {
+            self.ipv4 += 1;
+        }
+        if addrs.iter().any(|a| a.is_ipv6()) {
+            self.ipv6 += 1;
+        }
+    }
+
+    /// Return true if these introduction points lack the diversity clients need:
+    /// that is, if none of them has an IPv4 ORPort.
+    ///
+    /// Every client can reach relays over IPv4, but many can't reach them over IPv6,
+    /// so a set of introduction points with no IPv6 ORPorts is fine,
+    /// whereas one with no IPv4 ORPorts shuts out many of our clients.
+    ///
+    /// An empty set of introduction points is not considered to lack diversity.
+    pub(super) fn lacks_diversity(&self) -> bool {
+        self.n_ipts > 0 && self.ipv4 == 0
+    }
+}
+
 /// The freshness status of a descriptor at a particular HsDir.
 #[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub(super) enum DescriptorStatus {
@@ -177,4 +298,152 @@ pub(super) struct VersionedDescriptor {
     pub(super) desc: String,
     /// The revision counter.
     pub(super) revision_counter: RevisionCounter,
+    /// A redacted summary of the descriptor.
+    pub(super) summary: DescriptorSummary,
+}
+
+/// A summary of a descriptor built by the publisher, for debugging purposes.
+///
+/// This describes the contents of the descriptor without including the descriptor itself,
+/// or any of the keys used to sign or encrypt it.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct DescriptorSummary {
+    /// The time period the descriptor was built for.
+    pub time_period: TimePeriod,
+    /// The revision counter of the descriptor.
+    pub revision_counter: RevisionCounter,
+    /// The wallclock time at which the descriptor was built.
+    pub built_at: SystemTime,
+    /// The number of introduction points listed in the descriptor.
+    pub n_intro_points: usize,
+    /// The number of clients the descriptor was encrypted for.
+    ///
+    /// `None` if restricted discovery is disabled.
+    pub n_authorized_clients: Option<usize>,
+    /// The proof-of-work parameters advertised in the descriptor, if any.
+    pub pow_params: Option<PowParams>,
+    /// The length of the encoded descriptor, in bytes.
+    pub len: usize,
+    /// The lifetime of the descriptor.
+    pub lifetime: Duration,
+}
+
+/// A descriptor built by the publisher, as given to a [`DescriptorSink`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct BuiltDescriptor {
+    /// The nickname of the service the descriptor is for.
+    pub nickname: HsNickname,
+    /// The time period the descriptor was built for.
+    pub time_period: TimePeriod,
+    /// The revision counter of the descriptor.
+    pub revision_counter: RevisionCounter,
+    /// The encoded, signed descriptor.
+    pub descriptor: String,
+}
+
+/// A hook that is told about every descriptor built by the publisher.
+///
+/// Embedders can use this to archive descriptors,
+/// to feed them to external monitoring,
+/// or to publish them out of band.
+///
+/// Note that the publisher builds a fresh descriptor before each upload to each HsDir,
+/// so the sink will usually see several descriptors for each time period,
+/// with increasing revision counters.
+/// The sink is called whether or not the subsequent upload succeeds.
+///
+/// Implementations must not block, since they are called from the publisher's upload tasks.
+pub trait DescriptorSink: Send + Sync {
+    /// Handle the newly built `descriptor`.
+    fn descriptor_built(&self, descriptor: &BuiltDescriptor);
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_netdoc::doc::hsdesc::test_data;
+
+    #[test]
+    fn select_intro_points() {
+        let ipt = test_data::test_parsed_hsdesc().unwrap().intro_points()[0].clone();
+        let now = Instant::now();
+        let ipts = [30, 10, 20, 30]
+            .into_iter()
+            .enumerate()
+            .map(|(i, mins)| IptInSet {
+                ipt: ipt.clone(),
+                lid: [i.try_into().unwrap(); 32].into(),
+                planned_retirement: now + Duration::from_secs(mins * 60),
+            })
+            .collect_vec();
+
+        let chosen = |max| {
+            super::select_intro_points(&ipts, max)
+                .into_iter()
+                .map(|ipt| ipt.lid)
+                .collect_vec()
+        };
+        let lids = |idxs: &[u8]| {
+            idxs.iter()
+                .map(|i| IptLocalId::from([*i; 32]))
+                .collect_vec()
+        };
+
+        assert_eq!(chosen(None), lids(&[0, 1, 2, 3]));
+        assert_eq!(chosen(Some(4)), lids(&[0, 1, 2, 3]));
+        assert_eq!(chosen(Some(20)), lids(&[0, 1, 2, 3]));
+        assert_eq!(chosen(Some(3)), lids(&[0, 2, 3]));
+        assert_eq!(chosen(Some(2)), lids(&[0, 3]));
+        assert_eq!(chosen(Some(1)), lids(&[0]));
+    }
+
+    #[test]
+    fn ipt_addr_families() {
+        let v4: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:9001".parse().unwrap();
+        let families = |ipts: &[&[SocketAddr]]| {
+            let mut families = IptAddrFamilies::default();
+            for addrs in ipts {
+                families.note(addrs);
+            }
+            families
+        };
+
+        let none = families(&[]);
+        assert_eq!(none.n_ipts, 0);
+        assert!(!none.lacks_diversity());
+
+        let v4_only = families(&[&[v4], &[v4]]);
+        assert_eq!((v4_only.n_ipts, v4_only.ipv4, v4_only.ipv6), (2, 2, 0));
+        assert!(!v4_only.lacks_diversity());
+
+        let v6_only = families(&[&[v6], &[]]);
+        assert_eq!((v6_only.n_ipts, v6_only.ipv4, v6_only.ipv6), (2, 0, 1));
+        assert!(v6_only.lacks_diversity());
+
+        let dual_stack = families(&[&[v4, v6], &[v4]]);
+        assert_eq!(
+            (dual_stack.n_ipts, dual_stack.ipv4, dual_stack.ipv6),
+            (2, 2, 1)
+        );
+        assert!(!dual_stack.lacks_diversity());
+
+        let mixed = families(&[&[v4], &[v6]]);
+        assert!(!mixed.lacks_diversity());
+    }
 }
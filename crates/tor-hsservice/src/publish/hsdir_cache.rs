@@ -0,0 +1,89 @@
+//! The HsDirs we last computed, saved so that we can publish sooner after a restart.
+//!
+//! Normally, the publisher can't start uploading descriptors
+//! until it has a timely [`NetDir`] from which to compute our HsDirs.
+//! After a restart, that can take a while.
+//!
+//! So, every time we recompute our HsDirs, we save them, along with the time periods
+//! they are for, and the time when the consensus we computed them from stops being valid.
+//! If we restart before then, and don't have a timely netdir yet,
+//! we use the saved HsDirs (and whichever netdir we have) to start uploading straight away.
+
+use crate::internal_prelude::*;
+
+/// Handle for the persistent storage of our [`HsDirCacheRecord`].
+pub(crate) type HsDirCacheStorageHandle = tor_persist::state_dir::StorageHandle<HsDirCacheRecord>;
+
+/// On-disk record of the HsDirs we last computed.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct HsDirCacheRecord {
+    /// The `valid-until` time of the consensus from which we computed these HsDirs.
+    ///
+    /// The record is no use to us after this time.
+    valid_until: SystemTime,
+    /// The HsDirs we upload to, for each time period.
+    ///
+    /// (As in the `PowManagerStateRecord`, this is a list rather than a map,
+    /// because a `TimePeriod` can't be serialized as a string.)
+    rings: Vec<(TimePeriod, Vec<RelayIds>)>,
+}
+
+impl HsDirCacheRecord {
+    /// Make a new record of `rings`, which we computed from a consensus valid until `valid_until`.
+    pub(crate) fn new(valid_until: SystemTime, rings: Vec<(TimePeriod, Vec<RelayIds>)>) -> Self {
+        Self { valid_until, rings }
+    }
+
+    /// Return true if we can still use this record at the wall-clock time `now`.
+    pub(crate) fn is_valid_at(&self, now: SystemTime) -> bool {
+        now < self.valid_until
+    }
+
+    /// Return the HsDirs we recorded for `period`, if any.
+    pub(crate) fn hs_dirs(&self, period: TimePeriod) -> Option<&[RelayIds]> {
+        self.rings
+            .iter()
+            .find(|(tp, _)| *tp == period)
+            .map(|(_, hs_dirs)| hs_dirs.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+    #[test]
+    fn record() {
+        let valid_until = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let period = TimePeriod::from_parts(1440, 19_000, 720);
+        let other = TimePeriod::from_parts(1440, 19_001, 720);
+        let hs_dir = RelayIds::builder()
+            .ed_identity(Ed25519Identity::from([7; 32]))
+            .build()
+            .unwrap();
+        let record = HsDirCacheRecord::new(valid_until, vec![(period, vec![hs_dir.clone()])]);
+
+        // The record survives being saved and loaded.
+        let json = serde_json::to_string(&record).unwrap();
+        let record: HsDirCacheRecord = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(record.hs_dirs(period), Some(&[hs_dir][..]));
+        assert_eq!(record.hs_dirs(other), None);
+        assert!(record.is_valid_at(valid_until - Duration::from_secs(1)));
+        assert!(!record.is_valid_at(valid_until));
+    }
+}
@@ -0,0 +1,171 @@
+//! Metrics about the outcome of our descriptor uploads.
+//!
+//! Unless the `metrics` feature is enabled,
+//! [`PublisherMetrics`] is zero-sized, and recording an event does nothing.
+
+use crate::internal_prelude::*;
+
+use tor_netdir::NetDir;
+
+#[cfg(feature = "metrics")]
+use {
+    // Not to be confused with this module.
+    ::metrics::{Counter, Histogram, counter, histogram},
+    strum::IntoEnumIterator as _,
+};
+
+/// Which HsDir ring an upload was for.
+//
+// The variant names are part of the metrics schema.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "metrics", derive(strum::EnumIter, strum::IntoStaticStr))]
+#[cfg_attr(feature = "metrics", strum(serialize_all = "snake_case"))]
+pub(super) enum Ring {
+    /// The ring of the current time period.
+    Primary,
+    /// The ring of any other time period we publish for
+    /// (usually, the next or previous one).
+    Secondary,
+}
+
+impl Ring {
+    /// Return the ring of `period`, according to `netdir`.
+    pub(super) fn of(netdir: &NetDir, period: TimePeriod) -> Self {
+        if netdir.hs_time_period() == period {
+            Ring::Primary
+        } else {
+            Ring::Secondary
+        }
+    }
+}
+
+/// The metrics we record for one HsDir ring.
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+struct RingMetrics {
+    /// Descriptor uploads to individual HsDirs, including the failed ones.
+    uploads: Counter,
+    /// Descriptor uploads that succeeded.
+    uploads_ok: Counter,
+    /// Descriptor uploads that failed, after all our retries.
+    uploads_failed: Counter,
+    /// Time from the descriptor becoming dirty to its successful publication.
+    publish_latency: Histogram,
+    /// Uploads we postponed because we had uploaded too recently.
+    rate_limited: Counter,
+}
+
+/// Recorder for the publisher's metrics.
+///
+/// All metrics are labeled with the nickname of the service,
+/// and all except the descriptor size with the [`Ring`].
+#[derive(Clone)]
+pub(super) struct PublisherMetrics {
+    /// The metrics for each ring.
+    #[cfg(feature = "metrics")]
+    rings: HashMap<Ring, RingMetrics>,
+    /// The size of each descriptor we have built, in bytes.
+    #[cfg(feature = "metrics")]
+    descriptor_size: Histogram,
+}
+
+impl PublisherMetrics {
+    /// Register the metrics for the service `nickname`.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(super) fn new(nickname: &HsNickname) -> Self {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "metrics")] {
+                let rings = Ring::iter()
+                    .map(|ring| {
+                        let nickname = nickname.to_string();
+                        let ring_label: &'static str = ring.into();
+                        let counter = |name: &'static str| {
+                            counter!(name, "nickname" => nickname.clone(), "ring" => ring_label)
+                        };
+                        let metrics = RingMetrics {
+                            uploads: counter("arti_hss_publish_uploads_total"),
+                            uploads_ok: counter("arti_hss_publish_uploads_ok_total"),
+                            uploads_failed: counter("arti_hss_publish_uploads_failed_total"),
+                            publish_latency: histogram!(
+                                "arti_hss_publish_latency_seconds",
+                                "nickname" => nickname.clone(),
+                                "ring" => ring_label
+                            ),
+                            rate_limited: counter("arti_hss_publish_rate_limited_total"),
+                        };
+                        (ring, metrics)
+                    })
+                    .collect();
+                let descriptor_size = histogram!(
+                    "arti_hss_publish_descriptor_size_bytes",
+                    "nickname" => nickname.to_string()
+                );
+                PublisherMetrics {
+                    rings,
+                    descriptor_size,
+                }
+            } else {
+                PublisherMetrics {}
+            }
+        }
+    }
+
+    /// Record the outcome of a batch of uploads to the HsDirs of `ring`.
+    pub(super) fn note_uploads(&self, ring: Ring, succeeded: usize, failed: usize) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "metrics")] {
+                if let Some(m) = self.rings.get(&ring) {
+                    m.uploads.increment(to_u64(succeeded.saturating_add(failed)));
+                    m.uploads_ok.increment(to_u64(succeeded));
+                    m.uploads_failed.increment(to_u64(failed));
+                }
+            } else {
+                let _ = (ring, succeeded, failed);
+            }
+        }
+    }
+
+    /// Record that the descriptor for `ring` has been published to all its HsDirs,
+    /// `elapsed` after it was marked dirty.
+    pub(super) fn note_published(&self, ring: Ring, elapsed: Duration) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "metrics")] {
+                if let Some(m) = self.rings.get(&ring) {
+                    m.publish_latency.record(elapsed.as_secs_f64());
+                }
+            } else {
+                let _ = (ring, elapsed);
+            }
+        }
+    }
+
+    /// Record that we postponed an upload to the HsDirs of `ring` because of rate-limiting.
+    pub(super) fn note_rate_limited(&self, ring: Ring) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "metrics")] {
+                if let Some(m) = self.rings.get(&ring) {
+                    m.rate_limited.increment(1);
+                }
+            } else {
+                let _ = ring;
+            }
+        }
+    }
+
+    /// Record that we built a descriptor of `len` bytes.
+    pub(super) fn note_descriptor_size(&self, len: usize) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "metrics")] {
+                self.descriptor_size.record(len as f64);
+            } else {
+                let _ = len;
+            }
+        }
+    }
+}
+
+/// Convert a count to the type used by [`Counter`].
+#[cfg(feature = "metrics")]
+fn to_u64(n: usize) -> u64 {
+    n.try_into().unwrap_or(u64::MAX)
+}
@@ -27,8 +27,8 @@
 //!     has changed. See [`OnionServiceConfigPublisherView`]).
 //!   * there is a new consensus
 //!   * it is time to republish the descriptor (after we upload a descriptor,
-//!     we schedule it for republishing at a random time between 60 minutes and 120 minutes
-//!     in the future)
+//!     we schedule it for republishing at a random time between a third and two thirds
+//!     of its configured lifetime in the future: by default, between 60 and 120 minutes)
 //!
 //! ## Onion service status
 //!
@@ -38,7 +38,7 @@
 //!
 //! ```ignore
 //!
-//!                 update_publish_status(UploadScheduled|AwaitingIpts|RateLimited)
+//!                 update_publish_status(UploadScheduled|AwaitingIpts)
 //!                +---------------------------------------+
 //!                |                                       |
 //!                |                                       v
@@ -79,13 +79,18 @@ use tor_config::file_watcher::{
 };
 use tor_config_path::{CfgPath, CfgPathResolver};
 use tor_dirclient::SourceInfo;
-use tor_netdir::{DirEvent, NetDir};
+use tor_netdir::{DirEvent, NetDir, RelayWeight, WeightRole};
+use tor_netdoc::doc::netstatus::Lifetime;
+use tor_proto::ClockSkew;
+use tor_protover::Protocols;
+use tor_rtcompat::task::registry::TaskRegistry;
+use tor_rtcompat::wallclock::{WallclockJump, WallclockJumps, WallclockMonitor};
 
-use crate::config::OnionServiceConfigPublisherView;
 use crate::config::restricted_discovery::{
-    DirectoryKeyProviderList, RestrictedDiscoveryConfig, RestrictedDiscoveryKeys,
+    DirectoryKeyProviderList, RejectedClientKey, RestrictedDiscoveryConfig, RestrictedDiscoveryKeys,
 };
-use crate::status::{DescUploadRetryError, Problem};
+use crate::config::{IptAddrFamilyPolicy, OnionServiceConfigPublisherView};
+use crate::status::{DescUploadRetryError, Problem, StatusCause};
 
 use super::*;
 
@@ -102,10 +107,13 @@ use super::*;
 
 /// The upload rate-limiting threshold.
 ///
-/// Before initiating an upload, the reactor checks if the last upload was at least
-/// `UPLOAD_RATE_LIM_THRESHOLD` seconds ago. If so, it uploads the descriptor to all HsDirs that
-/// need it. If not, it schedules the upload to happen `UPLOAD_RATE_LIM_THRESHOLD` seconds from the
-/// current time.
+/// Before initiating an upload for a time period, the reactor checks if the last upload for that
+/// time period was at least `UPLOAD_RATE_LIM_THRESHOLD` seconds ago. If so, it uploads the
+/// descriptor to all the HsDirs of that time period that need it. If not, it postpones the upload
+/// until `UPLOAD_RATE_LIM_THRESHOLD` seconds after the last one.
+///
+/// Each time period is rate-limited independently,
+/// so that uploads for one time period never delay the uploads for another.
 //
 // TODO: We may someday need to tune this value; it was chosen more or less arbitrarily.
 const UPLOAD_RATE_LIM_THRESHOLD: Duration = Duration::from_secs(60);
@@ -127,6 +135,68 @@ const MAX_CONCURRENT_UPLOADS: usize = 16;
 /// across all attempts.
 pub(crate) const OVERALL_UPLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
+/// How long to wait before trying again to remove expired publisher keys
+/// that we failed to remove.
+//
+// TODO: this value was chosen more or less arbitrarily.
+pub(super) const KEY_EXPIRY_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long to wait before trying again to access the keystore,
+/// after our first temporary failure to access it.
+///
+/// The delay doubles after each consecutive failure,
+/// up to [`KEYSTORE_RETRY_MAX_DELAY`].
+//
+// TODO: this value was chosen more or less arbitrarily.
+const KEYSTORE_RETRY_INITIAL_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest time to wait before trying again to access the keystore.
+const KEYSTORE_RETRY_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// The longest time for which we postpone publishing our descriptor
+/// because our introduction points lack address family diversity.
+///
+/// Only used if our [`IptAddrFamilyPolicy`] is `Delay`.
+//
+// TODO: this value was chosen more or less arbitrarily.
+pub(super) const IPT_ADDR_FAMILY_MAX_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// How often we compare the wall clock with the monotonic clock,
+/// to find out whether the wall clock has jumped.
+///
+/// See [`Reactor::handle_wallclock_jump`].
+const WALLCLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often we check whether the restricted discovery `key_dirs`
+/// that didn't exist when we set up our file watcher have been created.
+///
+/// The file watcher can't watch a directory that doesn't exist
+/// (or whose parent doesn't exist), so we poll for those instead.
+pub(super) const KEY_DIRS_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How far the wall clock must drift from the monotonic clock
+/// between two checks for us to treat it as a jump.
+///
+/// Suspending and resuming the host usually causes a jump much larger than this.
+const WALLCLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(2 * 60);
+
+/// How far the wall clock must be outside the validity period of the consensus
+/// for us to report that it is skewed.
+///
+/// We may keep using a consensus for a little while after it expires,
+/// if we can't get a newer one, so we don't complain about a clock that is only slightly ahead.
+const CLOCK_SKEW_THRESHOLD: Duration = Duration::from_secs(60 * 60);
+
+/// The smallest factor by which we scale the single-attempt upload timeout of an HsDir.
+///
+/// See [`hsdir_timeout_factor`].
+const MIN_HSDIR_TIMEOUT_FACTOR: f64 = 0.5;
+
+/// The largest factor by which we scale the single-attempt upload timeout of an HsDir.
+///
+/// See [`hsdir_timeout_factor`].
+const MAX_HSDIR_TIMEOUT_FACTOR: f64 = 2.0;
+
 /// A reactor for the HsDir [`Publisher`]
 ///
 /// The entrypoint is [`Reactor::run`].
@@ -168,11 +238,11 @@ pub(super) struct Reactor<R: Runtime, M: Mockable> {
     /// A channel for sending upload completion notifications.
     ///
     /// This channel is polled in the main loop of the reactor.
-    upload_task_complete_rx: mpsc::Receiver<TimePeriodUploadResult>,
+    upload_task_complete_rx: mpsc::Receiver<UploadTaskMsg>,
     /// A channel for receiving upload completion notifications.
     ///
     /// A copy of this sender is handed to each upload task.
-    upload_task_complete_tx: mpsc::Sender<TimePeriodUploadResult>,
+    upload_task_complete_tx: mpsc::Sender<UploadTaskMsg>,
     /// A sender for notifying any pending upload tasks that the reactor is shutting down.
     ///
     /// Receivers can use this channel to find out when reactor is dropped.
@@ -187,6 +257,19 @@ pub(super) struct Reactor<R: Runtime, M: Mockable> {
     /// Queue on which we receive messages from the [`PowManager`] telling us that a seed has
     /// rotated and thus we need to republish the descriptor for a particular time period.
     update_from_pow_manager_rx: mpsc::Receiver<TimePeriod>,
+    /// A channel telling us whether the operator wants publication to be paused.
+    ///
+    /// See [`PublishStatus::Paused`].
+    paused_rx: watch::Receiver<bool>,
+    /// A stream telling us about sudden changes in the wall-clock time.
+    ///
+    /// `None` until [`run`](Reactor::run) launches the [`WallclockMonitor`].
+    wallclock_jumps: Option<WallclockJumps>,
+    /// Whether our HsDirs are the ones we saved before we restarted,
+    /// rather than ones computed from a timely netdir.
+    ///
+    /// See [`Reactor::start_from_hsdir_cache`].
+    using_cached_hsdirs: bool,
 }
 
 /// The immutable, shared state of the descriptor publisher reactor.
@@ -206,6 +289,19 @@ struct Immutable<R: Runtime, M: Mockable> {
     status_tx: PublisherStatusSender,
     /// Proof-of-work state.
     pow_manager: Arc<PowManager<R>>,
+    /// The summaries of the descriptors we have built.
+    descriptor_summaries: DescriptorSummaries,
+    /// A hook to tell about every descriptor we build, if any.
+    descriptor_sink: Option<Arc<dyn DescriptorSink>>,
+    /// Our metrics.
+    metrics: PublisherMetrics,
+    /// The last revision counter we generated for each time period.
+    ///
+    /// Used to keep our revision counters increasing
+    /// even if the wall clock goes backwards.
+    revision_counters: Mutex<HashMap<TimePeriod, RevisionCounter>>,
+    /// Where we save our HsDirs whenever we recompute them.
+    hsdir_cache: Mutex<HsDirCacheStorageHandle>,
 }
 
 impl<R: Runtime, M: Mockable> Immutable<R, M> {
@@ -221,8 +317,17 @@ impl<R: Runtime, M: Mockable> Immutable<R, M> {
     //
     // TODO (#1194): we don't support "offline" mode (yet), so this always returns an AesOpeKey
     // built from the blinded id key
-    fn create_ope_key(&self, period: TimePeriod) -> Result<AesOpeKey, FatalError> {
-        let ope_key = match read_blind_id_keypair(&self.keymgr, &self.nickname, period)? {
+    fn create_ope_key(
+        &self,
+        period: TimePeriod,
+        config: &OnionServiceConfigPublisherView,
+    ) -> Result<AesOpeKey, FatalError> {
+        let ope_key = match read_blind_id_keypair(
+            &self.keymgr,
+            &self.nickname,
+            period,
+            keystore_selector(config.blind_id_keystore.as_ref()),
+        )? {
             Some(key) => {
                 let key: ed25519::ExpandedKeypair = key.into();
                 key.to_secret_key_bytes()[0..32]
@@ -260,28 +365,57 @@ impl<R: Runtime, M: Mockable> Immutable<R, M> {
     ///
     /// Returns a revision counter generated according to the [encrypted time in period] scheme.
     ///
+    /// The wall clock isn't always trustworthy (for example, after the host is suspended
+    /// and resumed, or the clock is corrected by hand), so we don't rely on it blindly:
+    /// if `now` is before the start of the SRV period, we use the start of the period instead,
+    /// and we never return a revision counter lower than (or equal to)
+    /// the last one we returned for the same time period.
+    /// HsDirs would reject a descriptor with such a revision counter.
+    ///
     /// [encrypted time in period]: https://spec.torproject.org/rend-spec/revision-counter-mgt.html#encrypted-time
     fn generate_revision_counter(
         &self,
         params: &HsDirParams,
         now: SystemTime,
+        config: &OnionServiceConfigPublisherView,
     ) -> Result<RevisionCounter, FatalError> {
         // TODO: in the future, we might want to compute ope_key once per time period (as oppposed
         // to each time we generate a new descriptor), for performance reasons.
-        let ope_key = self.create_ope_key(params.time_period())?;
+        let ope_key = self.create_ope_key(params.time_period(), config)?;
 
         // TODO: perhaps this should be moved to a new HsDirParams::offset_within_sr() function
         let srv_start = params.start_of_shard_rand_period();
-        let offset = params.offset_within_srv_period(now).ok_or_else(|| {
-            internal!(
-                "current wallclock time not within SRV range?! (now={:?}, SRV_start={:?})",
-                now,
-                srv_start
-            )
-        })?;
+        let offset = match params.offset_within_srv_period(now) {
+            Some(offset) => offset,
+            None => {
+                warn!(
+                    nickname=%self.nickname,
+                    "wallclock time is before the start of the SRV period (now={}, SRV_start={}); is the clock wrong?",
+                    humantime::format_rfc3339_seconds(now),
+                    humantime::format_rfc3339_seconds(srv_start),
+                );
+                params.offset_within_srv_period(srv_start).ok_or_else(|| {
+                    internal!(
+                        "start of SRV period not within SRV range?! ({:?})",
+                        srv_start
+                    )
+                })?
+            }
+        };
         let rev = ope_key.encrypt(offset);
 
-        Ok(RevisionCounter::from(rev))
+        let mut counters = self.revision_counters.lock().expect("poisoned lock");
+        let last = counters.get(&params.time_period()).copied();
+        let counter = monotonic_revision_counter(last, rev)?;
+        if *counter != rev {
+            debug!(
+                nickname=%self.nickname, time_period=?params.time_period(),
+                "wallclock went backwards; bumping the previous revision counter instead"
+            );
+        }
+        counters.insert(params.time_period(), counter);
+
+        Ok(counter)
     }
 }
 
@@ -328,6 +462,9 @@ pub(crate) trait MockableDirTunnel: Send + Sync {
 
     /// Try to get a SourceInfo for this circuit, for using it in a directory request.
     fn source_info(&self) -> tor_proto::Result<Option<SourceInfo>>;
+
+    /// Return true if this circuit is closed and therefore unusable.
+    fn is_closed(&self) -> bool;
 }
 
 #[async_trait]
@@ -341,8 +478,17 @@ impl MockableDirTunnel for ServiceOnionServiceDirTunnel {
     fn source_info(&self) -> tor_proto::Result<Option<SourceInfo>> {
         SourceInfo::from_tunnel(self)
     }
+
+    fn is_closed(&self) -> bool {
+        Self::is_closed(self)
+    }
 }
 
+/// A tunnel to an HsDir, kept around for reuse by the next upload attempt to that HsDir.
+///
+/// See [`Reactor::upload_descriptor_with_retries`].
+type ReusableTunnel<M> = Mutex<Option<Arc<<M as Mockable>::Tunnel>>>;
+
 /// The real version of the mockable state of the reactor.
 #[derive(Clone, From, Into)]
 pub(crate) struct Real<R: Runtime>(Arc<HsCircPool<R>>);
@@ -391,6 +537,13 @@ struct Inner {
     ///
     /// The watcher is recreated whenever the `restricted_discovery.key_dirs` change.
     file_watcher: Option<FileWatcher>,
+    /// The restricted discovery `key_dirs` that didn't exist
+    /// when we last recreated `file_watcher`, and aren't watched by it.
+    missing_key_dirs: Vec<PathBuf>,
+    /// When to check again whether any of the `missing_key_dirs` exist.
+    ///
+    /// `None` if `missing_key_dirs` is empty.
+    key_dirs_recheck_at: Option<Instant>,
     /// The relevant time periods.
     ///
     /// This includes the current time period, as well as any other time periods we need to be
@@ -402,21 +555,6 @@ struct Inner {
     ///
     /// This is initialized in [`Reactor::run`].
     netdir: Option<Arc<NetDir>>,
-    /// The timestamp of our last upload.
-    ///
-    /// This is the time when the last update was _initiated_ (rather than completed), to prevent
-    /// the publisher from spawning multiple upload tasks at once in response to multiple external
-    /// events happening in quick succession, such as the IPT manager sending multiple IPT change
-    /// notifications in a short time frame (#1142), or an IPT change notification that's
-    /// immediately followed by a consensus change. Starting two upload tasks at once is not only
-    /// inefficient, but it also causes the publisher to generate two different descriptors with
-    /// the same revision counter (the revision counter is derived from the current timestamp),
-    /// which ultimately causes the slower upload task to fail (see #1142).
-    ///
-    /// Note: This is only used for deciding when to reschedule a rate-limited upload. It is _not_
-    /// used for retrying failed uploads (these are handled internally by
-    /// [`Reactor::upload_descriptor_with_retries`]).
-    last_uploaded: Option<Instant>,
     /// A max-heap containing the time periods for which we need to reupload the descriptor.
     // TODO: we are currently reuploading more than nececessary.
     // Ideally, this shouldn't contain contain duplicate TimePeriods,
@@ -434,8 +572,66 @@ struct Inner {
     ///
     /// `None`, unless the service is running in restricted discovery mode.
     authorized_clients: Option<Arc<RestrictedDiscoveryKeys>>,
+    /// The entries from the restricted discovery `key_dirs` that we rejected
+    /// the last time we read `authorized_clients`.
+    ///
+    /// Reported in our status, for as long as this is non-empty.
+    rejected_client_keys: Vec<RejectedClientKey>,
+    /// How far our wall clock was outside the validity period of our latest consensus,
+    /// the last time we checked.
+    ///
+    /// This is `ClockSkew::None` unless the skew exceeds [`CLOCK_SKEW_THRESHOLD`].
+    /// Reported in our status, for as long as it isn't `None`.
+    clock_skew: ClockSkew,
+    /// The percentage of the HsDirs on each ring that need to have our descriptor
+    /// for us to report the service as `Running`.
+    ///
+    /// This is not part of [`OnionServiceConfigPublisherView`],
+    /// because changing it doesn't require us to publish a new descriptor.
+    running_upload_percent: u8,
+    /// The number of expired publisher keys that we failed to remove from the keystore.
+    ///
+    /// This is updated each time we try to remove expired keys.
+    pending_key_deletions: usize,
+    /// When to try again to remove the expired publisher keys.
+    ///
+    /// `None` unless our last attempt to remove them failed.
+    key_expiry_retry: Option<Instant>,
+    /// If we are postponing our uploads because the introduction points
+    /// we would advertise lack address family diversity,
+    /// the time when we stop waiting for better ones and publish anyway.
+    ///
+    /// Only ever set if our [`IptAddrFamilyPolicy`] is `Delay`.
+    ipt_addr_family_delay_until: Option<Instant>,
+    /// The number of consecutive temporary keystore failures
+    /// we tolerate before giving up.
+    ///
+    /// This is not part of [`OnionServiceConfigPublisherView`],
+    /// because changing it doesn't require us to publish a new descriptor.
+    keystore_failure_limit: u32,
+    /// The number of times in a row we have failed to access the keystore
+    /// while computing our time periods or building our descriptors,
+    /// because of a failure that might be temporary.
+    keystore_failures: u32,
+    /// When to try again to access the keystore, and what to try again.
+    ///
+    /// `None` unless our last attempt failed because of a temporary keystore failure.
+    keystore_retry: Option<(Instant, KeystoreRetry)>,
+    /// A future that resolves once the uploads for the time periods other than the current one
+    /// may start.
+    ///
+    /// Replaced whenever we start uploading to the HsDirs of the current time period,
+    /// if our `primary_ring_first_percent` is set.
+    /// `None` if we have never done that.
+    primary_ring_released: Option<PrimaryRingReleased>,
 }
 
+/// A future that resolves once enough uploads to the HsDirs of the current time period
+/// have succeeded, or once they have all finished.
+///
+/// See [`OnionServiceConfig::primary_ring_first_percent`](crate::OnionServiceConfig).
+type PrimaryRingReleased = futures::future::Shared<oneshot::Receiver<()>>;
+
 /// The part of the reactor state that changes with every time period.
 struct TimePeriodContext {
     /// The HsDir params.
@@ -447,10 +643,40 @@ struct TimePeriodContext {
     // store `Relay<'_>`s in the reactor, we'd need a way of atomically swapping out both the
     // `NetDir` and the cached relays, and to convince Rust what we're doing is sound)
     hs_dirs: Vec<(RelayIds, DescriptorStatus)>,
+    /// The HsDirs of this time period that we won't upload to,
+    /// because they don't support the protocols we need.
+    ///
+    /// These are not part of `hs_dirs`, so we never try to upload to them,
+    /// but we report them as failures in our `upload_results`:
+    /// clients that look for our descriptor on them won't find it.
+    unsupported_hs_dirs: Vec<UnsupportedHsDir>,
     /// The revision counter of the last successful upload, if any.
     last_successful: Option<RevisionCounter>,
     /// The outcome of the last upload, if any.
     upload_results: Vec<HsDirUploadStatus>,
+    /// The timestamp of our last upload for this time period.
+    ///
+    /// This is the time when the last update was _initiated_ (rather than completed), to prevent
+    /// the publisher from spawning multiple upload tasks at once in response to multiple external
+    /// events happening in quick succession, such as the IPT manager sending multiple IPT change
+    /// notifications in a short time frame (#1142), or an IPT change notification that's
+    /// immediately followed by a consensus change. Starting two upload tasks at once is not only
+    /// inefficient, but it also causes the publisher to generate two different descriptors with
+    /// the same revision counter (the revision counter is derived from the current timestamp),
+    /// which ultimately causes the slower upload task to fail (see #1142).
+    ///
+    /// Note: This is only used for deciding when to reschedule a rate-limited upload. It is _not_
+    /// used for retrying failed uploads (these are handled internally by
+    /// [`Reactor::upload_descriptor_with_retries`]).
+    last_uploaded: Option<Instant>,
+    /// If we have postponed an upload for this time period because of rate-limiting,
+    /// the time when the rate-limit expires.
+    rate_limited_until: Option<Instant>,
+    /// When the descriptor for this time period last became dirty,
+    /// if it hasn't been published to all of our HsDirs since.
+    ///
+    /// Only used for metrics.
+    dirty_since: Option<Instant>,
 }
 
 impl TimePeriodContext {
@@ -466,7 +692,8 @@ impl TimePeriodContext {
         old_upload_results: Vec<HsDirUploadStatus>,
     ) -> Result<Self, FatalError> {
         let period = params.time_period();
-        let hs_dirs = Self::compute_hsdirs(period, blind_id, netdir, old_hsdirs)?;
+        let (hs_dirs, unsupported_hs_dirs) =
+            Self::compute_hsdirs(period, blind_id, netdir, old_hsdirs)?;
         let upload_results = old_upload_results
             .into_iter()
             .filter(|res|
@@ -479,50 +706,117 @@ impl TimePeriodContext {
         Ok(Self {
             params,
             hs_dirs,
+            unsupported_hs_dirs,
             last_successful: None,
             upload_results,
+            last_uploaded: None,
+            rate_limited_until: None,
+            dirty_since: None,
         })
     }
 
+    /// Create a `TimePeriodContext` for the HsDirs of `params` that we saved before a restart.
+    ///
+    /// The descriptor is dirty for all of them, since we don't know which of them have it.
+    /// See [`Reactor::start_from_hsdir_cache`].
+    fn from_saved(params: HsDirParams, hs_dirs: &[RelayIds], now: Instant) -> Self {
+        Self {
+            params,
+            hs_dirs: hs_dirs
+                .iter()
+                .map(|ids| (ids.clone(), DescriptorStatus::Dirty))
+                .collect(),
+            unsupported_hs_dirs: vec![],
+            last_successful: None,
+            upload_results: vec![],
+            last_uploaded: None,
+            rate_limited_until: None,
+            dirty_since: Some(now),
+        }
+    }
+
     /// Recompute the HsDirs for this time period.
+    ///
+    /// Returns the HsDirs we should upload to,
+    /// and the HsDirs we are skipping because they don't support
+    /// the [required protocols](required_hsdir_protocols).
+    ///
+    /// The HsDirs that are also in `old_hsdirs` keep their status,
+    /// wherever they are in the new list.
+    /// This matters when the `hsdir_spread_store` consensus parameter increases:
+    /// the new HsDirs are interleaved with the old ones in the ring,
+    /// but only the new ones need our descriptor.
     fn compute_hsdirs<'r>(
         period: TimePeriod,
         blind_id: HsBlindId,
         netdir: &Arc<NetDir>,
-        mut old_hsdirs: impl Iterator<Item = &'r (RelayIds, DescriptorStatus)>,
-    ) -> Result<Vec<(RelayIds, DescriptorStatus)>, FatalError> {
+        old_hsdirs: impl Iterator<Item = &'r (RelayIds, DescriptorStatus)>,
+    ) -> Result<(Vec<(RelayIds, DescriptorStatus)>, Vec<UnsupportedHsDir>), FatalError> {
         let hs_dirs = netdir.hs_dirs_upload(blind_id, period)?;
+        let required = required_hsdir_protocols();
+        let old_hsdirs = old_hsdirs.collect_vec();
+
+        let mut supported = vec![];
+        let mut unsupported = vec![];
+        for hs_dir in hs_dirs {
+            let mut builder = RelayIds::builder();
+            if let Some(ed_id) = hs_dir.ed_identity() {
+                builder.ed_identity(*ed_id);
+            }
 
-        Ok(hs_dirs
-            .map(|hs_dir| {
-                let mut builder = RelayIds::builder();
-                if let Some(ed_id) = hs_dir.ed_identity() {
-                    builder.ed_identity(*ed_id);
-                }
+            if let Some(rsa_id) = hs_dir.rsa_identity() {
+                builder.rsa_identity(*rsa_id);
+            }
 
-                if let Some(rsa_id) = hs_dir.rsa_identity() {
-                    builder.rsa_identity(*rsa_id);
-                }
+            let relay_id = builder.build().unwrap_or_else(|_| RelayIds::empty());
 
-                let relay_id = builder.build().unwrap_or_else(|_| RelayIds::empty());
+            let missing = required.difference(hs_dir.protovers());
+            if !missing.is_empty() {
+                debug!(
+                    time_period=?period,
+                    "not uploading descriptor to HsDir {}: it does not support {missing}",
+                    relay_id.display_relay_ids(),
+                );
+                unsupported.push(UnsupportedHsDir {
+                    relay_ids: relay_id,
+                    missing,
+                });
+                continue;
+            }
 
-                // Have we uploaded the descriptor to thiw relay before? If so, we don't need to
-                // reupload it unless it was already dirty and due for a reupload.
-                let status = match old_hsdirs.find(|(id, _)| *id == relay_id) {
-                    Some((_, status)) => *status,
-                    None => DescriptorStatus::Dirty,
-                };
+            // Have we uploaded the descriptor to thiw relay before? If so, we don't need to
+            // reupload it unless it was already dirty and due for a reupload.
+            let status = match old_hsdirs.iter().find(|(id, _)| *id == relay_id) {
+                Some((_, status)) => *status,
+                None => DescriptorStatus::Dirty,
+            };
 
-                (relay_id, status)
-            })
-            .collect::<Vec<_>>())
+            supported.push((relay_id, status));
+        }
+
+        if !old_hsdirs.is_empty() {
+            let n_new = supported
+                .iter()
+                .filter(|(id, _)| !old_hsdirs.iter().any(|(old_id, _)| old_id == id))
+                .count();
+            if n_new > 0 {
+                debug!(
+                    time_period=?period,
+                    "{n_new} new HsDirs for this time period (hsdir_spread_store is {})",
+                    netdir.params().hsdir_spread_store,
+                );
+            }
+        }
+
+        Ok((supported, unsupported))
     }
 
     /// Mark the descriptor dirty for all HSDirs of this time period.
-    fn mark_all_dirty(&mut self) {
+    fn mark_all_dirty(&mut self, now: Instant) {
         self.hs_dirs
             .iter_mut()
             .for_each(|(_relay_id, status)| *status = DescriptorStatus::Dirty);
+        self.dirty_since.get_or_insert(now);
     }
 
     /// Update the upload result for this time period.
@@ -531,13 +825,118 @@ impl TimePeriodContext {
     }
 }
 
+/// Return the revision counter to use instead of `rev`,
+/// if the last one we used for the same time period was `last`.
+///
+/// This is `rev`, unless that would not be greater than `last`.
+fn monotonic_revision_counter(
+    last: Option<RevisionCounter>,
+    rev: u64,
+) -> Result<RevisionCounter, Bug> {
+    match last {
+        Some(last) if rev <= *last => last
+            .checked_add(1)
+            .map(RevisionCounter::from)
+            .ok_or_else(|| internal!("revision counter overflow?!")),
+        _ => Ok(RevisionCounter::from(rev)),
+    }
+}
+
+/// Return how far `now` is outside the validity period `lifetime` of a consensus,
+/// or `ClockSkew::None` if that is no more than [`CLOCK_SKEW_THRESHOLD`].
+///
+/// A clock that is before the start of that period is slow;
+/// one that is after its end is fast.
+fn significant_clock_skew(lifetime: &Lifetime, now: SystemTime) -> ClockSkew {
+    if let Ok(skew) = lifetime.valid_after().duration_since(now) {
+        ClockSkew::Slow(skew)
+    } else if let Ok(skew) = now.duration_since(lifetime.valid_until()) {
+        ClockSkew::Fast(skew)
+    } else {
+        ClockSkew::None
+    }
+    .if_above(CLOCK_SKEW_THRESHOLD)
+}
+
+/// Return the time at which to build and sign a descriptor, if the wall clock says it is `now`.
+///
+/// This is `now`, unless it is skewed relative to the consensus with validity period `lifetime`
+/// and `use_consensus_time_if_skewed` is true.
+/// In that case, it is the time within the validity period that is closest to `now`.
+fn descriptor_time(
+    lifetime: &Lifetime,
+    now: SystemTime,
+    use_consensus_time_if_skewed: bool,
+) -> SystemTime {
+    if use_consensus_time_if_skewed && significant_clock_skew(lifetime, now).is_skewed() {
+        now.clamp(lifetime.valid_after(), lifetime.valid_until())
+    } else {
+        now
+    }
+}
+
+/// Wait for the next wall clock jump reported on `jumps`.
+///
+/// Never returns if `jumps` is `None`, or if the stream has ended.
+async fn next_wallclock_jump(jumps: &mut Option<WallclockJumps>) -> WallclockJump {
+    match jumps {
+        Some(jumps) => match jumps.next().await {
+            Some(jump) => jump,
+            None => future::pending().await,
+        },
+        None => future::pending().await,
+    }
+}
+
+/// Wait until the monotonic clock of `runtime` reaches `when`,
+/// the time of our next descriptor reupload.
+///
+/// Never returns if `when` is `None`.
+async fn sleep_until_reupload<R: Runtime>(runtime: &R, when: Option<Instant>) {
+    match when {
+        Some(when) => runtime.sleep_until(when).await,
+        None => future::pending().await,
+    }
+}
+
+/// Return the subprotocol versions an HsDir must support
+/// for us to upload our descriptor to it.
+///
+/// HsDirs only parse the outer layer of a descriptor.
+/// Features such as proof-of-work are advertised in the encrypted layers,
+/// which only clients read,
+/// so they don't add any requirements here.
+fn required_hsdir_protocols() -> Protocols {
+    [tor_protover::named::HSDIR_V3].into_iter().collect()
+}
+
+/// An HsDir we don't upload our descriptor to,
+/// because it doesn't support the [required protocols](required_hsdir_protocols).
+#[derive(Clone, Debug)]
+struct UnsupportedHsDir {
+    /// The identities of the HsDir.
+    relay_ids: RelayIds,
+    /// The required subprotocol versions the HsDir doesn't advertise.
+    missing: Protocols,
+}
+
 /// An error that occurs while trying to upload a descriptor.
 #[derive(Clone, Debug, thiserror::Error)]
 #[non_exhaustive]
 pub enum UploadError {
     /// An error that has occurred after we have contacted a directory cache and made a circuit to it.
     #[error("descriptor upload request failed: {}", _0.error)]
-    Request(#[from] RequestFailedError),
+    Request(RequestFailedError),
+
+    /// The HsDir answered our upload with an error response.
+    #[error("HsDir rejected descriptor: {rejection}")]
+    Rejected {
+        /// Why the HsDir rejected the descriptor, as best we can tell.
+        rejection: HsDirRejection,
+        /// The underlying error.
+        #[source]
+        error: RequestFailedError,
+    },
 
     /// Failed to establish circuit to hidden service directory
     #[error("could not build circuit to HsDir")]
@@ -559,6 +958,7 @@ impl UploadError {
     pub(crate) fn should_report_as_suspicious(&self) -> bool {
         match self {
             UploadError::Request(e) => e.error.should_report_as_suspicious_if_anon(),
+            UploadError::Rejected { .. } => false,
             UploadError::Circuit(_) => false, // TODO prop360
             UploadError::Stream(_) => false,  // TODO prop360
             UploadError::Bug(_) => false,
@@ -566,6 +966,83 @@ impl UploadError {
     }
 }
 
+impl From<RequestFailedError> for UploadError {
+    fn from(error: RequestFailedError) -> Self {
+        match &error.error {
+            RequestError::HttpStatus(status, message) => UploadError::Rejected {
+                rejection: HsDirRejection::from_response(*status, message),
+                error,
+            },
+            _ => UploadError::Request(error),
+        }
+    }
+}
+
+/// The reason an HsDir gave for rejecting one of our descriptors.
+///
+/// HsDirs only tell us an HTTP status code and a short message,
+/// so this is our best guess, based on those.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum HsDirRejection {
+    /// The descriptor was larger than the HsDir is willing to accept.
+    #[error("descriptor too big")]
+    TooBig,
+    /// The HsDir could not verify the signatures or certificates on the descriptor.
+    #[error("bad descriptor signature")]
+    BadSignature,
+    /// The HsDir is busy, or is limiting how many requests it accepts.
+    #[error("rate limited")]
+    RateLimited,
+    /// The HsDir does not think that it is responsible for our blinded ID.
+    ///
+    /// This usually means that we and the HsDir are using different consensuses.
+    #[error("HsDir is not responsible for this descriptor")]
+    WrongRing,
+    /// Some other error response.
+    #[error("HTTP status {status}: {message:?}")]
+    Other {
+        /// The HTTP status code.
+        status: u16,
+        /// The status message that came with it.
+        message: String,
+    },
+}
+
+impl HsDirRejection {
+    /// Classify an error response with HTTP status code `status` and status message `message`.
+    pub(crate) fn from_response(status: u16, message: &str) -> Self {
+        let lower = message.to_ascii_lowercase();
+        let mentions = |words: &[&str]| words.iter().any(|w| lower.contains(w));
+
+        if status == 413 || mentions(&["too big", "too large", "too long"]) {
+            HsDirRejection::TooBig
+        } else if mentions(&["signature", "certificate"]) {
+            HsDirRejection::BadSignature
+        } else if status == 429 || status == 503 || mentions(&["busy", "rate limit"]) {
+            HsDirRejection::RateLimited
+        } else if mentions(&["not responsible", "not an hsdir", "wrong ring", "hash ring"]) {
+            HsDirRejection::WrongRing
+        } else {
+            HsDirRejection::Other {
+                status,
+                message: message.to_owned(),
+            }
+        }
+    }
+
+    /// Return true if uploading the same descriptor to the same HsDir
+    /// is bound to be rejected in the same way.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            HsDirRejection::TooBig | HsDirRejection::BadSignature | HsDirRejection::WrongRing => {
+                true
+            }
+            HsDirRejection::RateLimited | HsDirRejection::Other { .. } => false,
+        }
+    }
+}
+
 impl<R: Runtime, M: Mockable> Reactor<R, M> {
     /// Create a new `Reactor`.
     #[allow(clippy::too_many_arguments)]
@@ -582,6 +1059,10 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         path_resolver: Arc<CfgPathResolver>,
         pow_manager: Arc<PowManager<R>>,
         update_from_pow_manager_rx: mpsc::Receiver<TimePeriod>,
+        paused_rx: watch::Receiver<bool>,
+        descriptor_summaries: DescriptorSummaries,
+        descriptor_sink: Option<Arc<dyn DescriptorSink>>,
+        hsdir_cache: HsDirCacheStorageHandle,
     ) -> Self {
         /// The maximum size of the upload completion notifier channel.
         ///
@@ -599,13 +1080,14 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         // since we never actually send anything on this channel.
         let (shutdown_tx, _shutdown_rx) = broadcast::channel(0);
 
-        let authorized_clients =
+        let (authorized_clients, rejected_client_keys) =
             Self::read_authorized_clients(&config.restricted_discovery, &path_resolver);
 
         // Create a channel for watching for changes in the configured
         // restricted_discovery.key_dirs.
         let (key_dirs_tx, key_dirs_rx) = file_watcher::channel();
 
+        let metrics = PublisherMetrics::new(&nickname);
         let imm = Immutable {
             runtime,
             mockable,
@@ -613,16 +1095,32 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             keymgr,
             status_tx,
             pow_manager,
+            descriptor_summaries,
+            descriptor_sink,
+            metrics,
+            revision_counters: Default::default(),
+            hsdir_cache: Mutex::new(hsdir_cache),
         };
 
         let inner = Inner {
             time_periods: vec![],
             config: Arc::new(config.into()),
             file_watcher: None,
+            missing_key_dirs: vec![],
+            key_dirs_recheck_at: None,
             netdir: None,
-            last_uploaded: None,
             reupload_timers: Default::default(),
             authorized_clients,
+            rejected_client_keys,
+            clock_skew: ClockSkew::None,
+            running_upload_percent: config.running_upload_percent,
+            pending_key_deletions: 0,
+            key_expiry_retry: None,
+            ipt_addr_family_delay_until: None,
+            keystore_failure_limit: config.keystore_failure_limit,
+            keystore_failures: 0,
+            keystore_retry: None,
+            primary_ring_released: None,
         };
 
         Self {
@@ -640,6 +1138,9 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             shutdown_tx,
             path_resolver,
             update_from_pow_manager_rx,
+            paused_rx,
+            wallclock_jumps: None,
+            using_cached_hsdirs: false,
         }
     }
 
@@ -651,25 +1152,47 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
     /// upload fails or is rate-limited.
     pub(super) async fn run(mut self) -> Result<(), FatalError> {
         debug!(nickname=%self.imm.nickname, "starting descriptor publisher reactor");
+        let task = TaskRegistry::global().register(
+            format!("descriptor publisher for {}", self.imm.nickname),
+            self.imm.runtime.clone(),
+        );
 
-        {
-            let netdir = self
-                .dir_provider
-                .wait_for_netdir(Timeliness::Timely)
-                .await?;
-            let time_periods = self.compute_time_periods(&netdir, &[])?;
-
-            let mut inner = self.inner.lock().expect("poisoned lock");
+        // If we don't have a timely netdir yet, we might be able to start with the HsDirs
+        // we saved before we restarted, instead of waiting for one.
+        let timely_netdir = self.dir_provider.netdir(Timeliness::Timely).ok();
+        if timely_netdir.is_some() || !self.start_from_hsdir_cache() {
+            let netdir = match timely_netdir {
+                Some(netdir) => netdir,
+                None => {
+                    self.dir_provider
+                        .wait_for_netdir(Timeliness::Timely)
+                        .await?
+                }
+            };
+            self.check_clock_skew(&netdir);
+            let _old: Option<Arc<NetDir>> = self.replace_netdir(netdir);
 
-            inner.netdir = Some(netdir);
-            inner.time_periods = time_periods;
+            // If the keystore is temporarily unavailable, we'll try again from run_once.
+            let _computed: bool = self.recompute_hs_dirs_or_retry()?;
         }
 
         // Create the initial key_dirs watcher.
         self.update_file_watcher();
 
+        // Find out about wall clock jumps, so we can republish our descriptors after them.
+        // (This must stay alive until we exit: the monitor stops when it is dropped.)
+        let wallclock_monitor = WallclockMonitor::launch(
+            &self.imm.runtime,
+            WALLCLOCK_CHECK_INTERVAL,
+            WALLCLOCK_JUMP_THRESHOLD,
+        )
+        .map_err(|e| FatalError::from_spawn("wallclock monitor", e))?;
+        self.wallclock_jumps = Some(wallclock_monitor.subscribe());
+
         loop {
-            match self.run_once().await {
+            let status = self.run_once().await;
+            task.note_activity();
+            match status {
                 Ok(ShutdownStatus::Continue) => continue,
                 Ok(ShutdownStatus::Terminate) => {
                     debug!(nickname=%self.imm.nickname, "descriptor publisher is shutting down!");
@@ -697,36 +1220,84 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
     async fn run_once(&mut self) -> Result<ShutdownStatus, FatalError> {
         let mut netdir_events = self.dir_provider.events();
 
+        if self.using_cached_hsdirs {
+            // We might have missed the event telling us about a timely netdir
+            // (we only listen for events while we are running this function).
+            if let Ok(netdir) = self.dir_provider.netdir(Timeliness::Timely) {
+                debug!(nickname=%self.imm.nickname, "replacing our saved HsDirs with current ones");
+                let relevant_periods = netdir.hs_all_time_periods();
+                self.handle_consensus_change(netdir).await?;
+                self.expire_keys(&relevant_periods)?;
+            }
+        }
+
         // Note: TrackingNow tracks the values it is compared with.
         // This is equivalent to sleeping for (until - now) units of time,
         let upload_rate_lim: TrackingNow = TrackingNow::now(&self.imm.runtime);
-        if let PublishStatus::RateLimited(until) = self.status() {
-            if upload_rate_lim > until {
-                // We are no longer rate-limited
-                self.expire_rate_limit().await?;
+        let rate_lim_expired = {
+            let mut inner = self.inner.lock().expect("poisoned lock");
+            inner
+                .time_periods
+                .iter_mut()
+                .filter_map(|ctx| {
+                    let until = ctx.rate_limited_until?;
+                    // If this is false, upload_rate_lim remembers to wake us up at `until`.
+                    (upload_rate_lim > until).then(|| {
+                        ctx.rate_limited_until = None;
+                        ctx.params.time_period()
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        if !rate_lim_expired.is_empty() {
+            // Some of our time periods are no longer rate-limited.
+            debug!(
+                time_periods=?rate_lim_expired,
+                "no longer rate-limited; resuming descriptor publication",
+            );
+            self.update_publish_status(PublishStatus::UploadScheduled)
+                .await?;
+        }
+
+        let ipt_addr_family_tracking = TrackingNow::now(&self.imm.runtime);
+        let ipt_addr_family_delay_expired = {
+            let mut inner = self.inner.lock().expect("poisoned lock");
+            match inner.ipt_addr_family_delay_until {
+                // If this is false, ipt_addr_family_tracking remembers to wake us up at `until`.
+                Some(until) if until <= ipt_addr_family_tracking => {
+                    inner.ipt_addr_family_delay_until = None;
+                    true
+                }
+                _ => false,
             }
+        };
+        if ipt_addr_family_delay_expired {
+            warn!(
+                nickname=%self.imm.nickname,
+                "introduction points still can't be reached over IPv4; publishing descriptor anyway",
+            );
+            self.update_publish_status_unless_waiting(PublishStatus::UploadScheduled)
+                .await?;
         }
 
-        let reupload_tracking = TrackingNow::now(&self.imm.runtime);
+        let now = self.imm.runtime.now();
         let mut reupload_periods = vec![];
-        {
+        let next_reupload = {
             let mut inner = self.inner.lock().expect("poisoned lock");
             let inner = &mut *inner;
             while let Some(reupload) = inner.reupload_timers.peek().copied() {
                 // First, extract all the timeouts that already elapsed.
-                if reupload.when <= reupload_tracking {
+                if reupload.when <= now {
                     inner.reupload_timers.pop();
                     reupload_periods.push(reupload.period);
                 } else {
                     // We are not ready to schedule any more reuploads.
-                    //
-                    // How much we need to sleep is implicitly
-                    // tracked in reupload_tracking (through
-                    // the TrackingNow implementation)
                     break;
                 }
             }
-        }
+            // We need to wake up when the earliest remaining timer expires.
+            inner.reupload_timers.peek().map(|reupload| reupload.when)
+        };
 
         // Check if it's time to schedule any reuploads.
         for period in reupload_periods {
@@ -735,30 +1306,151 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                     time_period=?period,
                     "descriptor reupload timer elapsed; scheduling reupload",
                 );
-                self.update_publish_status_unless_rate_lim(PublishStatus::UploadScheduled)
+                self.update_publish_status(PublishStatus::UploadScheduled)
+                    .await?;
+            }
+        }
+
+        let key_expiry_tracking = TrackingNow::now(&self.imm.runtime);
+        let key_expiry_retry = {
+            let inner = self.inner.lock().expect("poisoned lock");
+            match inner.key_expiry_retry {
+                // If this is false, key_expiry_tracking remembers to wake us up at `when`.
+                Some(when) if when <= key_expiry_tracking => Some(
+                    inner
+                        .time_periods
+                        .iter()
+                        .map(|ctx| ctx.params.clone())
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            }
+        };
+        if let Some(relevant_periods) = key_expiry_retry {
+            debug!(
+                nickname=%self.imm.nickname,
+                "retrying removal of expired keys",
+            );
+            self.expire_keys(&relevant_periods)?;
+            // Run another iteration, so that key_expiry_tracking
+            // learns about the next retry, if there is one.
+            return Ok(ShutdownStatus::Continue);
+        }
+
+        let keystore_retry_tracking = TrackingNow::now(&self.imm.runtime);
+        let keystore_retry = {
+            let inner = self.inner.lock().expect("poisoned lock");
+            match inner.keystore_retry {
+                // If this is false, keystore_retry_tracking remembers to wake us up at `when`.
+                Some((when, what)) if when <= keystore_retry_tracking => Some(what),
+                _ => None,
+            }
+        };
+        if let Some(what) = keystore_retry {
+            debug!(
+                nickname=%self.imm.nickname,
+                "retrying keystore access",
+            );
+            let retried = match what {
+                KeystoreRetry::RecomputeHsDirs => self.recompute_hs_dirs_or_retry()?,
+                KeystoreRetry::Upload => {
+                    // Our HsDirs are still dirty, so we only need to upload again.
+                    self.inner.lock().expect("poisoned lock").keystore_retry = None;
+                    true
+                }
+            };
+            if retried {
+                self.update_publish_status_unless_waiting(PublishStatus::UploadScheduled)
                     .await?;
+                self.upload_result_to_svc_status()?;
             }
+            // Run another iteration, so that keystore_retry_tracking
+            // learns about the next retry, if there is one.
+            return Ok(ShutdownStatus::Continue);
+        }
+
+        let key_dirs_recheck_tracking = TrackingNow::now(&self.imm.runtime);
+        let key_dirs_appeared = {
+            let mut inner = self.inner.lock().expect("poisoned lock");
+            match inner.key_dirs_recheck_at {
+                // If this is false, key_dirs_recheck_tracking remembers to wake us up at `when`.
+                Some(when) if when <= key_dirs_recheck_tracking => {
+                    let appeared = inner
+                        .missing_key_dirs
+                        .iter()
+                        .any(|dir| matches!(dir.try_exists(), Ok(true)));
+                    if !appeared {
+                        inner.key_dirs_recheck_at =
+                            Some(self.imm.runtime.now() + KEY_DIRS_RECHECK_INTERVAL);
+                    }
+                    appeared
+                }
+                _ => false,
+            }
+        };
+        if key_dirs_appeared {
+            debug!(
+                nickname=%self.imm.nickname,
+                "some of the missing key_dirs have been created",
+            );
+            self.imm.status_tx.note_cause(StatusCause::ConfigChange);
+            // This recreates the file watcher, so that it watches the new key_dirs.
+            self.handle_key_dirs_change(FileEvent::Rescan).await?;
+            return Ok(ShutdownStatus::Continue);
         }
 
         select_biased! {
             res = self.upload_task_complete_rx.next().fuse() => {
-                let Some(upload_res) = res else {
+                let Some(msg) = res else {
                     return Ok(ShutdownStatus::Terminate);
                 };
 
-                self.handle_upload_results(upload_res);
-                self.upload_result_to_svc_status()?;
+                match msg {
+                    UploadTaskMsg::Completed(upload_res) => {
+                        self.note_keystore_accessible();
+                        if let Some(outcome) = self.handle_upload_results(upload_res) {
+                            self.ipt_watcher.note_publish_outcome(outcome);
+                        }
+                        self.upload_result_to_svc_status()?;
+                    }
+                    UploadTaskMsg::KeystoreUnavailable(e) => {
+                        self.schedule_keystore_retry(e, KeystoreRetry::Upload)?;
+                    }
+                }
             },
             () = upload_rate_lim.wait_for_earliest(&self.imm.runtime).fuse() => {
-                self.expire_rate_limit().await?;
+                // Run another iteration, executing run_once again. This time, we will notice
+                // that the rate-limit has expired, and schedule the postponed upload.
+                return Ok(ShutdownStatus::Continue);
+            },
+            () = ipt_addr_family_tracking.wait_for_earliest(&self.imm.runtime).fuse() => {
+                // Run another iteration, executing run_once again. This time, we will notice
+                // that we have waited long enough for better introduction points,
+                // and schedule the postponed upload.
+                return Ok(ShutdownStatus::Continue);
             },
-            () = reupload_tracking.wait_for_earliest(&self.imm.runtime).fuse() => {
+            () = sleep_until_reupload(&self.imm.runtime, next_reupload).fuse() => {
                 // Run another iteration, executing run_once again. This time, we will remove the
                 // expired reupload from self.reupload_timers, mark the descriptor dirty for all
                 // relevant HsDirs, and schedule the upload by setting our status to
                 // UploadScheduled.
                 return Ok(ShutdownStatus::Continue);
             },
+            () = keystore_retry_tracking.wait_for_earliest(&self.imm.runtime).fuse() => {
+                // Run another iteration, executing run_once again. This time, we will
+                // try again to access the keystore.
+                return Ok(ShutdownStatus::Continue);
+            },
+            () = key_expiry_tracking.wait_for_earliest(&self.imm.runtime).fuse() => {
+                // Run another iteration, executing run_once again. This time, we will
+                // retry removing the expired keys we previously failed to remove.
+                return Ok(ShutdownStatus::Continue);
+            },
+            () = key_dirs_recheck_tracking.wait_for_earliest(&self.imm.runtime).fuse() => {
+                // Run another iteration, executing run_once again. This time, we will
+                // check whether the missing key_dirs have been created.
+                return Ok(ShutdownStatus::Continue);
+            },
             netdir_event = netdir_events.next().fuse() => {
                 let Some(netdir_event) = netdir_event else {
                     debug!("netdir event stream ended");
@@ -789,16 +1481,12 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                     }
                 };
                 let relevant_periods = netdir.hs_all_time_periods();
+                self.imm.status_tx.note_cause(StatusCause::ConsensusChange);
                 self.handle_consensus_change(netdir).await?;
-                expire_publisher_keys(
-                    &self.imm.keymgr,
-                    &self.imm.nickname,
-                    &relevant_periods,
-                ).unwrap_or_else(|e| {
-                    error_report!(e, "failed to remove expired keys");
-                });
+                self.expire_keys(&relevant_periods)?;
             }
             update = self.ipt_watcher.await_update().fuse() => {
+                self.imm.status_tx.note_cause(StatusCause::IptChange);
                 if self.handle_ipt_change(update).await? == ShutdownStatus::Terminate {
                     return Ok(ShutdownStatus::Terminate);
                 }
@@ -808,6 +1496,7 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                     return Ok(ShutdownStatus::Terminate);
                 };
 
+                self.imm.status_tx.note_cause(StatusCause::ConfigChange);
                 self.handle_svc_config_change(&config).await?;
             },
             res = self.key_dirs_rx.next().fuse() => {
@@ -819,6 +1508,7 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                     // Discard other events, so that we only reload once.
                 }
 
+                self.imm.status_tx.note_cause(StatusCause::ConfigChange);
                 self.handle_key_dirs_change(event).await?;
             }
             should_upload = self.publish_status_rx.next().fuse() => {
@@ -837,22 +1527,84 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                 let Some(time_period) = update_tp_pow_seed else {
                     return Ok(ShutdownStatus::Terminate);
                 };
+                self.imm.status_tx.note_cause(StatusCause::PowSeedRotation);
                 self.mark_dirty(&time_period);
                 self.upload_all().await?;
             }
+            paused = self.paused_rx.next().fuse() => {
+                let Some(paused) = paused else {
+                    return Ok(ShutdownStatus::Terminate);
+                };
+
+                self.handle_pause_change(paused).await?;
+            }
+            jump = next_wallclock_jump(&mut self.wallclock_jumps).fuse() => {
+                self.handle_wallclock_jump(&jump).await?;
+            }
         }
 
         Ok(ShutdownStatus::Continue)
     }
 
-    /// Returns the current status of the publisher
+    /// Remove the publisher keys of the time periods that are no longer relevant.
+    ///
+    /// If we fail to remove any of them, schedule another attempt
+    /// in [`KEY_EXPIRY_RETRY_INTERVAL`],
+    /// and report the number of keys we failed to remove
+    /// as a [`Problem::ExpiredKeysNotRemoved`] warning.
+    fn expire_keys(&self, relevant_periods: &[HsDirParams]) -> Result<(), FatalError> {
+        let res = expire_publisher_keys(&self.imm.keymgr, &self.imm.nickname, relevant_periods);
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let retry_at = self.imm.runtime.now() + KEY_EXPIRY_RETRY_INTERVAL;
+        let was_pending = inner.pending_key_deletions;
+        match res {
+            Ok(0) => {
+                if inner.pending_key_deletions > 0 {
+                    info!(
+                        nickname=%self.imm.nickname,
+                        "removed all previously pending expired keys",
+                    );
+                }
+                inner.pending_key_deletions = 0;
+                inner.key_expiry_retry = None;
+            }
+            Ok(n_failed) => {
+                warn!(
+                    nickname=%self.imm.nickname,
+                    "failed to remove {n_failed} expired key(s); retrying in {}",
+                    humantime::format_duration(KEY_EXPIRY_RETRY_INTERVAL),
+                );
+                inner.pending_key_deletions = n_failed;
+                inner.key_expiry_retry = Some(retry_at);
+            }
+            Err(e) => {
+                // We don't know how many keys are pending deletion,
+                // so we leave pending_key_deletions as it is.
+                error_report!(e, "failed to remove expired keys");
+                inner.key_expiry_retry = Some(retry_at);
+            }
+        }
+        let changed = inner.pending_key_deletions != was_pending;
+        drop(inner);
+
+        if changed {
+            self.report_warnings()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current status of the publisher
     fn status(&self) -> PublishStatus {
         *self.publish_status_rx.borrow()
     }
 
     /// Handle a batch of upload outcomes,
     /// possibly updating the status of the descriptor for the corresponding HSDirs.
-    fn handle_upload_results(&self, results: TimePeriodUploadResult) {
+    ///
+    /// Returns the [`PublishOutcome`] to report to the IPT manager,
+    /// if these uploads were for the current time period.
+    fn handle_upload_results(&self, results: TimePeriodUploadResult) -> Option<PublishOutcome> {
         let mut inner = self.inner.lock().expect("poisoned lock");
         let inner = &mut *inner;
 
@@ -865,16 +1617,20 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         let Some(period) = period else {
             // The uploads were for a time period that is no longer relevant, so we
             // can ignore the result.
-            return;
+            return None;
         };
 
         // We will need to reupload this descriptor at at some point, so we pick
-        // a random time between 60 minutes and 120 minutes in the future.
+        // a random time between a third and two thirds of its lifetime in the future.
+        // (With the default lifetime of 3 hours, that's between 60 and 120 minutes.)
         //
         // See https://spec.torproject.org/rend-spec/deriving-keys.html#WHEN-HSDESC
         let mut rng = self.imm.mockable.thread_rng();
         // TODO SPEC: Control republish period using a consensus parameter?
-        let minutes = rng.gen_range_checked(60..=120).expect("low > high?!");
+        let lifetime_minutes = inner.config.descriptor_lifetime.as_secs() / 60;
+        let minutes = rng
+            .gen_range_checked(lifetime_minutes / 3..=lifetime_minutes * 2 / 3)
+            .expect("low > high?!");
         let duration = Duration::from_secs(minutes * 60);
         let reupload_when = self.imm.runtime.now() + duration;
         let time_period = period.params.time_period();
@@ -903,6 +1659,14 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                 continue;
             };
 
+            if let Some(rejection) = &upload_res.rejection {
+                debug!(
+                    nickname=%self.imm.nickname, time_period=?time_period,
+                    "HsDir rejected our descriptor: {rejection}{}",
+                    if rejection.is_permanent() { " (not retrying)" } else { "" },
+                );
+            }
+
             if upload_res.upload_res.is_ok() {
                 let update_last_successful = match period.last_successful {
                     None => true,
@@ -929,16 +1693,71 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             upload_results.push(upload_res);
         }
 
+        if period
+            .hs_dirs
+            .iter()
+            .all(|(_, status)| *status == DescriptorStatus::Clean)
+        {
+            if let (Some(dirty_since), Some(netdir)) = (period.dirty_since.take(), &inner.netdir) {
+                let elapsed = self
+                    .imm
+                    .runtime
+                    .now()
+                    .saturating_duration_since(dirty_since);
+                self.imm
+                    .metrics
+                    .note_published(Ring::of(netdir, time_period), elapsed);
+            }
+        }
+
+        // If we can't publish the descriptor for the current time period anywhere,
+        // our introduction points might be to blame, so the IPT manager wants to know.
+        let is_current_period = inner
+            .netdir
+            .as_ref()
+            .is_some_and(|netdir| netdir.hs_time_period() == time_period);
+        let outcome = (is_current_period && !upload_results.is_empty()).then(|| {
+            if upload_results.iter().any(|res| res.upload_res.is_ok()) {
+                PublishOutcome::Succeeded
+            } else {
+                PublishOutcome::Failed
+            }
+        });
+
+        if !period.unsupported_hs_dirs.is_empty() {
+            debug!(
+                nickname=%self.imm.nickname, time_period=?time_period,
+                "skipped {} HsDir(s) that do not support the protocols we need: {}",
+                period.unsupported_hs_dirs.len(),
+                period
+                    .unsupported_hs_dirs
+                    .iter()
+                    .map(|hs_dir| format!(
+                        "{} (missing {})",
+                        hs_dir.relay_ids.display_relay_ids(),
+                        hs_dir.missing
+                    ))
+                    .join(", "),
+            );
+        }
+
         period.set_upload_results(upload_results);
+
+        outcome
     }
 
     /// Maybe update our list of HsDirs.
     async fn handle_consensus_change(&mut self, netdir: Arc<NetDir>) -> Result<(), FatalError> {
         trace!("the consensus has changed; recomputing HSDirs");
 
+        self.check_clock_skew(&netdir);
         let _old: Option<Arc<NetDir>> = self.replace_netdir(netdir);
+        self.using_cached_hsdirs = false;
 
-        self.recompute_hs_dirs()?;
+        if !self.recompute_hs_dirs_or_retry()? {
+            // We'll try again once the keystore is available.
+            return Ok(());
+        }
         self.update_publish_status_unless_waiting(PublishStatus::UploadScheduled)
             .await?;
 
@@ -951,10 +1770,90 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         Ok(())
     }
 
+    /// Recompute the HsDirs for all relevant time periods,
+    /// scheduling a retry if we fail because the keystore is temporarily unavailable.
+    ///
+    /// Returns `Ok(true)` if we recomputed the HsDirs,
+    /// and `Ok(false)` if we are going to try again later.
+    /// In that case, the service is reported as `Recovering` in the meantime.
+    ///
+    /// Returns an error if the failure wasn't temporary,
+    /// or if we have failed more than `keystore_failure_limit` times in a row.
+    fn recompute_hs_dirs_or_retry(&self) -> Result<bool, FatalError> {
+        match self.recompute_hs_dirs() {
+            Ok(()) => {
+                // Recomputing our HsDirs schedules an upload, which covers any retry we had planned.
+                self.inner.lock().expect("poisoned lock").keystore_retry = None;
+                self.note_keystore_accessible();
+                Ok(true)
+            }
+            Err(e) if e.is_transient_keystore_error() => {
+                self.schedule_keystore_retry(e, KeystoreRetry::RecomputeHsDirs)?;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Note that we have just accessed the keystore successfully.
+    ///
+    /// Unless we are still waiting to retry some other keystore access,
+    /// this resets our count of consecutive keystore failures.
+    fn note_keystore_accessible(&self) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if inner.keystore_retry.is_some() {
+            return;
+        }
+        if inner.keystore_failures > 0 {
+            info!(nickname=%self.imm.nickname, "the keystore is accessible again");
+        }
+        inner.keystore_failures = 0;
+    }
+
+    /// Note that `e`, a temporary keystore failure, stopped us doing `what`,
+    /// and schedule a retry.
+    ///
+    /// The service is reported as `Recovering` until we retry.
+    ///
+    /// Returns `e` if we have failed more than `keystore_failure_limit` times in a row.
+    fn schedule_keystore_retry(
+        &self,
+        e: FatalError,
+        what: KeystoreRetry,
+    ) -> Result<(), FatalError> {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.keystore_failures = inner.keystore_failures.saturating_add(1);
+        if inner.keystore_failures > inner.keystore_failure_limit {
+            return Err(e);
+        }
+
+        // If we were already going to retry something else, retry whichever covers both.
+        let what = inner
+            .keystore_retry
+            .map_or(what, |(_, pending)| pending.max(what));
+        let delay = keystore_retry_delay(inner.keystore_failures);
+        inner.keystore_retry = Some((self.imm.runtime.now() + delay, what));
+        warn_report!(
+            e,
+            "HS service {}: keystore unavailable ({} of at most {} failures); retrying in {}",
+            self.imm.nickname,
+            inner.keystore_failures,
+            inner.keystore_failure_limit,
+            humantime::format_duration(delay),
+        );
+        drop(inner);
+
+        self.imm
+            .status_tx
+            .send_recovering(Problem::KeystoreUnavailable(e));
+
+        Ok(())
+    }
+
     /// Recompute the HsDirs for all relevant time periods.
     fn recompute_hs_dirs(&self) -> Result<(), FatalError> {
-        let mut inner = self.inner.lock().expect("poisoned lock");
-        let inner = &mut *inner;
+        let mut guard = self.inner.lock().expect("poisoned lock");
+        let inner = &mut *guard;
 
         let netdir = Arc::clone(
             inner
@@ -964,12 +1863,119 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         );
 
         // Update our list of relevant time periods.
-        let new_time_periods = self.compute_time_periods(&netdir, &inner.time_periods)?;
+        let new_time_periods =
+            self.compute_time_periods(&netdir, &inner.time_periods, &inner.config)?;
         inner.time_periods = new_time_periods;
+        let record = hsdir_cache_record(&netdir, &inner.time_periods);
+
+        // Forget about the descriptors of the time periods that are no longer relevant.
+        self.imm
+            .descriptor_summaries
+            .lock()
+            .expect("poisoned lock")
+            .retain(|period, _| {
+                inner
+                    .time_periods
+                    .iter()
+                    .any(|ctx| ctx.params.time_period() == *period)
+            });
+        self.imm
+            .revision_counters
+            .lock()
+            .expect("poisoned lock")
+            .retain(|period, _| {
+                inner
+                    .time_periods
+                    .iter()
+                    .any(|ctx| ctx.params.time_period() == *period)
+            });
+        drop(guard);
+
+        // Don't hold our lock while we write to disk.
+        self.save_hsdirs(&record);
 
         Ok(())
     }
 
+    /// Save `record`, so that we can use its HsDirs if we restart before they expire.
+    ///
+    /// Failures are logged, but otherwise ignored:
+    /// without the saved HsDirs, we just take longer to start publishing after a restart.
+    fn save_hsdirs(&self, record: &HsDirCacheRecord) {
+        let mut hsdir_cache = self.imm.hsdir_cache.lock().expect("poisoned lock");
+        if let Err(e) = hsdir_cache.store(record) {
+            warn_report!(
+                e,
+                "HS service {}: failed to save our HsDirs",
+                self.imm.nickname
+            );
+        }
+    }
+
+    /// Try to start with the HsDirs we saved before we restarted,
+    /// using whichever netdir we have, however untimely.
+    ///
+    /// We only do this if the saved HsDirs came from a consensus that is still valid,
+    /// and if they are for the time periods of our netdir.
+    /// Once we get a timely netdir, we replace them with HsDirs computed from it
+    /// (keeping the status of any HsDirs that are in both).
+    ///
+    /// Returns true if we are now using the saved HsDirs.
+    fn start_from_hsdir_cache(&mut self) -> bool {
+        let loaded = self.imm.hsdir_cache.lock().expect("poisoned lock").load();
+        let record = match loaded {
+            Ok(Some(record)) => record,
+            Ok(None) => return false,
+            Err(e) => {
+                warn_report!(
+                    e,
+                    "HS service {}: failed to load our saved HsDirs",
+                    self.imm.nickname
+                );
+                return false;
+            }
+        };
+        if !record.is_valid_at(self.imm.runtime.wallclock()) {
+            debug!(nickname=%self.imm.nickname, "our saved HsDirs have expired");
+            return false;
+        }
+        let Ok(netdir) = self.dir_provider.netdir(Timeliness::Unchecked) else {
+            return false;
+        };
+
+        let now = self.imm.runtime.now();
+        let time_periods = netdir
+            .hs_all_time_periods()
+            .into_iter()
+            .filter_map(|params| {
+                let hs_dirs = record.hs_dirs(params.time_period())?;
+                Some(TimePeriodContext::from_saved(params, hs_dirs, now))
+            })
+            .collect_vec();
+        let current_period = netdir.hs_time_period();
+        if !time_periods
+            .iter()
+            .any(|ctx| ctx.params.time_period() == current_period)
+        {
+            debug!(
+                nickname=%self.imm.nickname,
+                "our saved HsDirs are not for the current time period"
+            );
+            return false;
+        }
+
+        info!(
+            nickname=%self.imm.nickname,
+            "starting with the HsDirs we saved before restarting, until we have a timely netdir"
+        );
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.netdir = Some(netdir);
+        inner.time_periods = time_periods;
+        drop(inner);
+        self.using_cached_hsdirs = true;
+        true
+    }
+
     /// Compute the [`TimePeriodContext`]s for the time periods from the specified [`NetDir`].
     ///
     /// The specified `time_periods` are used to preserve the `DescriptorStatus` of the
@@ -978,18 +1984,24 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         &self,
         netdir: &Arc<NetDir>,
         time_periods: &[TimePeriodContext],
+        config: &OnionServiceConfigPublisherView,
     ) -> Result<Vec<TimePeriodContext>, FatalError> {
+        let now = self.imm.runtime.now();
         netdir
             .hs_all_time_periods()
             .iter()
             .map(|params| {
                 let period = params.time_period();
-                let blind_id_kp =
-                    read_blind_id_keypair(&self.imm.keymgr, &self.imm.nickname, period)?
-                        // Note: for now, read_blind_id_keypair cannot return Ok(None).
-                        // It's supposed to return Ok(None) if we're in offline hsid mode,
-                        // but that might change when we do #1194
-                        .ok_or_else(|| internal!("offline hsid mode not supported"))?;
+                let blind_id_kp = read_blind_id_keypair(
+                    &self.imm.keymgr,
+                    &self.imm.nickname,
+                    period,
+                    keystore_selector(config.blind_id_keystore.as_ref()),
+                )?
+                // Note: for now, read_blind_id_keypair cannot return Ok(None).
+                // It's supposed to return Ok(None) if we're in offline hsid mode,
+                // but that might change when we do #1194
+                .ok_or_else(|| internal!("offline hsid mode not supported"))?;
 
                 let blind_id: HsBlindIdKey = (&blind_id_kp).into();
 
@@ -1006,6 +2018,7 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                     .iter()
                     .find(|ctx| ctx.params.time_period() == period)
                 {
+                    // We also preserve its rate-limiting state.
                     TimePeriodContext::new(
                         params.clone(),
                         blind_id.into(),
@@ -1013,6 +2026,12 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                         ctx.hs_dirs.iter(),
                         ctx.upload_results.clone(),
                     )
+                    .map(|new_ctx| TimePeriodContext {
+                        last_uploaded: ctx.last_uploaded,
+                        rate_limited_until: ctx.rate_limited_until,
+                        dirty_since: ctx.dirty_since,
+                        ..new_ctx
+                    })
                 } else {
                     // Passing an empty iterator here means all HsDirs in this TimePeriodContext
                     // will be marked as dirty, meaning we will need to upload our descriptor to them.
@@ -1023,6 +2042,10 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                         iter::empty(),
                         vec![],
                     )
+                    .map(|new_ctx| TimePeriodContext {
+                        dirty_since: Some(now),
+                        ..new_ctx
+                    })
                 }
             })
             .collect::<Result<Vec<TimePeriodContext>, FatalError>>()
@@ -1076,7 +2099,16 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
 
             let dirs = inner.config.restricted_discovery.key_dirs().clone();
 
-            watch_dirs(&mut watcher, &dirs, &self.path_resolver);
+            let missing = watch_dirs(&mut watcher, &dirs, &self.path_resolver);
+            if !missing.is_empty() {
+                debug!(
+                    "Some key_dirs don't exist; checking for them every {}",
+                    humantime::format_duration(KEY_DIRS_RECHECK_INTERVAL),
+                );
+            }
+            inner.key_dirs_recheck_at =
+                (!missing.is_empty()).then(|| self.imm.runtime.now() + KEY_DIRS_RECHECK_INTERVAL);
+            inner.missing_key_dirs = missing;
 
             let watcher = watcher
                 .start_watching(self.key_dirs_tx.clone())
@@ -1091,6 +2123,8 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                 debug!("removing key_dirs watcher");
             }
             inner.file_watcher = None;
+            inner.missing_key_dirs.clear();
+            inner.key_dirs_recheck_at = None;
         }
     }
 
@@ -1114,10 +2148,10 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             Some(Ok(())) => {
                 let should_upload = self.note_ipt_change();
                 debug!(nickname=%self.imm.nickname, "the introduction points have changed");
+                self.check_ipt_addr_families();
 
                 self.mark_all_dirty();
-                self.update_publish_status_unless_rate_lim(should_upload)
-                    .await?;
+                self.update_publish_status(should_upload).await?;
                 Ok(ShutdownStatus::Continue)
             }
             Some(Err(e)) => Err(e),
@@ -1128,6 +2162,62 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         }
     }
 
+    /// Check whether the introduction points we would advertise lack address family diversity,
+    /// and act according to our [`IptAddrFamilyPolicy`].
+    ///
+    /// If the policy is `Delay`, this postpones our uploads
+    /// (for at most [`IPT_ADDR_FAMILY_MAX_DELAY`] after we first noticed the problem).
+    /// If the introduction points are diverse enough, any such postponement is cancelled.
+    fn check_ipt_addr_families(&self) {
+        let (config, netdir) = {
+            let inner = self.inner.lock().expect("poisoned lock");
+            (Arc::clone(&inner.config), inner.netdir.clone())
+        };
+
+        let policy = config.ipt_addr_family_policy;
+        let families = match (policy, netdir) {
+            (IptAddrFamilyPolicy::Ignore, _) | (_, None) => None,
+            (_, Some(netdir)) => {
+                let mut ipts = self.ipt_watcher.borrow_for_publish();
+                ipts.ipts.as_mut().map(|ipt_set| {
+                    let advertised =
+                        select_intro_points(&ipt_set.ipts, config.max_advertised_intro_points);
+                    IptAddrFamilies::count(advertised, &netdir)
+                })
+            }
+        };
+
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let Some(families) = families.filter(IptAddrFamilies::lacks_diversity) else {
+            if inner.ipt_addr_family_delay_until.take().is_some() {
+                debug!(
+                    nickname=%self.imm.nickname,
+                    "some of our introduction points can now be reached over IPv4; no longer postponing publication",
+                );
+            }
+            return;
+        };
+
+        if policy == IptAddrFamilyPolicy::Delay {
+            let now = self.imm.runtime.now();
+            let until = *inner
+                .ipt_addr_family_delay_until
+                .get_or_insert(now + IPT_ADDR_FAMILY_MAX_DELAY);
+            warn!(
+                nickname=%self.imm.nickname,
+                n_ipts=families.n_ipts, ipv4=families.ipv4, ipv6=families.ipv6,
+                "none of our introduction points can be reached over IPv4; postponing descriptor publication for up to {}",
+                humantime::format_duration(until.saturating_duration_since(now)),
+            );
+        } else {
+            warn!(
+                nickname=%self.imm.nickname,
+                n_ipts=families.n_ipts, ipv4=families.ipv4, ipv6=families.ipv6,
+                "none of our introduction points can be reached over IPv4",
+            );
+        }
+    }
+
     /// Update the `PublishStatus` of the reactor with `new_state`,
     /// unless the current state is `AwaitingIpts`.
     async fn update_publish_status_unless_waiting(
@@ -1143,30 +2233,51 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
     }
 
     /// Update the `PublishStatus` of the reactor with `new_state`,
-    /// unless the current state is `RateLimited`.
-    async fn update_publish_status_unless_rate_lim(
-        &mut self,
-        new_state: PublishStatus,
-    ) -> Result<(), FatalError> {
-        // We can't exit this state until the rate-limit expires.
-        if !matches!(self.status(), PublishStatus::RateLimited(_)) {
-            self.update_publish_status(new_state).await?;
+    /// unless the current state is `ConfigBroken` or `Paused`.
+    ///
+    /// Once our configuration is broken, we stay in `ConfigBroken`
+    /// until [`clear_config_broken`](Self::clear_config_broken) is called.
+    /// Similarly, once the operator pauses publication, we stay in `Paused`
+    /// until they resume it (see [`handle_pause_change`](Self::handle_pause_change)).
+    async fn update_publish_status(&mut self, new_state: PublishStatus) -> Result<(), Bug> {
+        match self.status() {
+            PublishStatus::ConfigBroken => {
+                trace!(
+                    "publisher reactor configuration is broken; not changing status to {:?}",
+                    new_state
+                );
+                return Ok(());
+            }
+            PublishStatus::Paused => {
+                trace!(
+                    "descriptor publication is paused; not changing status to {:?}",
+                    new_state
+                );
+                return Ok(());
+            }
+            _ => {}
         }
 
-        Ok(())
+        self.set_publish_status(new_state).await
     }
 
     /// Unconditionally update the `PublishStatus` of the reactor with `new_state`.
-    async fn update_publish_status(&mut self, new_state: PublishStatus) -> Result<(), Bug> {
+    async fn set_publish_status(&mut self, new_state: PublishStatus) -> Result<(), Bug> {
         let onion_status = match new_state {
-            PublishStatus::Idle => None,
-            PublishStatus::UploadScheduled
-            | PublishStatus::AwaitingIpts
-            | PublishStatus::RateLimited(_) => Some(State::Bootstrapping),
+            // In ConfigBroken, we have already reported that we are broken.
+            //
+            // While Paused, we keep reporting the outcome of our last uploads:
+            // the operator asked for our descriptors to age out, so there is nothing to fix.
+            PublishStatus::Idle | PublishStatus::ConfigBroken | PublishStatus::Paused => None,
+            PublishStatus::UploadScheduled | PublishStatus::AwaitingIpts => {
+                Some(State::Bootstrapping)
+            }
         };
 
         if let Some(onion_status) = onion_status {
-            self.imm.status_tx.send(onion_status, None);
+            self.imm
+                .status_tx
+                .send(onion_status, self.problem_or_warning(None));
         }
 
         trace!(
@@ -1182,25 +2293,123 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         Ok(())
     }
 
+    /// If our configuration was broken, leave the `ConfigBroken` state,
+    /// so that we try to publish our descriptor again.
+    ///
+    /// This should be called whenever our configuration (or our list of authorized clients)
+    /// has changed.
+    async fn clear_config_broken(&mut self) -> Result<(), FatalError> {
+        if self.status() != PublishStatus::ConfigBroken {
+            return Ok(());
+        }
+
+        // If the operator paused publication while our configuration was broken,
+        // we stay paused until they resume it.
+        let paused = *self.paused_rx.borrow();
+        if paused {
+            debug!(nickname=%self.imm.nickname, "the configuration has changed; descriptor publication remains paused");
+            self.set_publish_status(PublishStatus::Paused).await?;
+
+            // We are no longer broken: while paused, we report the outcome of our last uploads.
+            let have_netdir = self.inner.lock().expect("poisoned lock").netdir.is_some();
+            if have_netdir {
+                self.upload_result_to_svc_status()?;
+            }
+            return Ok(());
+        }
+
+        debug!(nickname=%self.imm.nickname, "the configuration has changed; resuming descriptor publication");
+        let new_state = self.note_ipt_change();
+        Ok(self.set_publish_status(new_state).await?)
+    }
+
+    /// Pause or resume descriptor publication, as requested by the operator.
+    ///
+    /// Pausing puts us in [`PublishStatus::Paused`], regardless of our previous state,
+    /// unless our configuration is broken:
+    /// then we stay in [`PublishStatus::ConfigBroken`],
+    /// and [`clear_config_broken`](Self::clear_config_broken) takes care of
+    /// pausing us once the configuration is fixed, if we are still meant to be paused.
+    ///
+    /// Resuming picks our new state as if the introduction points had just changed,
+    /// so any descriptors that became dirty while we were paused are uploaded right away.
+    async fn handle_pause_change(&mut self, paused: bool) -> Result<(), Bug> {
+        if self.status() == PublishStatus::ConfigBroken {
+            debug!(
+                nickname=%self.imm.nickname,
+                paused,
+                "our configuration is broken; not changing publication status",
+            );
+            return Ok(());
+        }
+
+        let is_paused = self.status() == PublishStatus::Paused;
+        if paused == is_paused {
+            return Ok(());
+        }
+
+        if paused {
+            info!(nickname=%self.imm.nickname, "descriptor publication paused by the operator");
+            self.set_publish_status(PublishStatus::Paused).await
+        } else {
+            info!(nickname=%self.imm.nickname, "descriptor publication resumed by the operator");
+            let new_state = self.note_ipt_change();
+            self.set_publish_status(new_state).await
+        }
+    }
+
     /// Update the onion svc status based on the results of the last descriptor uploads.
+    ///
+    /// Does nothing if our configuration is broken:
+    /// in that case, we have already reported that we are broken.
     fn upload_result_to_svc_status(&self) -> Result<(), FatalError> {
-        let inner = self.inner.lock().expect("poisoned lock");
-        let netdir = inner
-            .netdir
-            .as_ref()
-            .ok_or_else(|| internal!("handling upload results without netdir?!"))?;
+        if self.status() == PublishStatus::ConfigBroken {
+            return Ok(());
+        }
+
+        let (state, err) = {
+            let inner = self.inner.lock().expect("poisoned lock");
+            let netdir = inner
+                .netdir
+                .as_ref()
+                .ok_or_else(|| internal!("handling upload results without netdir?!"))?;
 
-        let (state, err) = upload_result_state(netdir, &inner.time_periods);
-        self.imm.status_tx.send(state, err);
+            upload_result_state(netdir, &inner.time_periods, inner.running_upload_percent)
+        };
+        self.imm.status_tx.send(state, self.problem_or_warning(err));
 
         Ok(())
     }
 
+    /// Replace the `running_upload_percent` threshold with `percent`.
+    ///
+    /// Returns `true` if the threshold has changed,
+    /// and we already have a netdir (i.e., if our status needs to be recomputed).
+    fn replace_running_upload_percent_if_changed(&self, percent: u8) -> bool {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if inner.running_upload_percent == percent {
+            return false;
+        }
+
+        inner.running_upload_percent = percent;
+        inner.netdir.is_some()
+    }
+
     /// Update the descriptors based on the config change.
     async fn handle_svc_config_change(
         &mut self,
         config: &OnionServiceConfig,
     ) -> Result<(), FatalError> {
+        self.inner
+            .lock()
+            .expect("poisoned lock")
+            .keystore_failure_limit = config.keystore_failure_limit;
+
+        if self.replace_running_upload_percent_if_changed(config.running_upload_percent) {
+            // Our upload results haven't changed, but the way we interpret them has.
+            self.upload_result_to_svc_status()?;
+        }
+
         let new_config = Arc::new(config.into());
         if self.replace_config_if_changed(Arc::clone(&new_config)) {
             self.update_file_watcher();
@@ -1208,6 +2417,7 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
 
             info!(nickname=%self.imm.nickname, "Config has changed, generating a new descriptor");
             self.mark_all_dirty();
+            self.clear_config_broken().await?;
 
             // Schedule an upload, unless we're still waiting for IPTs.
             self.update_publish_status_unless_waiting(PublishStatus::UploadScheduled)
@@ -1237,10 +2447,15 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
 
         if self.update_authorized_clients_if_changed().await? {
             self.mark_all_dirty();
+            self.clear_config_broken().await?;
 
             // Schedule an upload, unless we're still waiting for IPTs.
             self.update_publish_status_unless_waiting(PublishStatus::UploadScheduled)
                 .await?;
+        } else {
+            // The set of rejected entries might have changed
+            // (for example, if a malformed key file was removed).
+            self.report_warnings()?;
         }
 
         Ok(())
@@ -1248,11 +2463,14 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
 
     /// Recreate the authorized_clients based on the current config.
     ///
+    /// Also replaces the list of rejected client key entries.
+    ///
     /// Returns `true` if the authorized clients have changed.
     async fn update_authorized_clients_if_changed(&mut self) -> Result<bool, FatalError> {
         let mut inner = self.inner.lock().expect("poisoned lock");
-        let authorized_clients =
+        let (authorized_clients, rejected_client_keys) =
             Self::read_authorized_clients(&inner.config.restricted_discovery, &self.path_resolver);
+        inner.rejected_client_keys = rejected_client_keys;
 
         let clients = &mut inner.authorized_clients;
         let changed = clients.as_ref() != authorized_clients.as_ref();
@@ -1265,32 +2483,165 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         Ok(changed)
     }
 
-    /// Read the authorized `RestrictedDiscoveryKeys` from `config`.
+    /// Read the authorized `RestrictedDiscoveryKeys` from `config`,
+    /// along with the client key entries we rejected.
     fn read_authorized_clients(
         config: &RestrictedDiscoveryConfig,
         path_resolver: &CfgPathResolver,
-    ) -> Option<Arc<RestrictedDiscoveryKeys>> {
-        let authorized_clients = config.read_keys(path_resolver);
+    ) -> (Option<Arc<RestrictedDiscoveryKeys>>, Vec<RejectedClientKey>) {
+        let Some((authorized_clients, rejected)) = config.read_keys(path_resolver) else {
+            return (None, vec![]);
+        };
 
-        if matches!(authorized_clients.as_ref(), Some(c) if c.is_empty()) {
+        if authorized_clients.is_empty() {
             warn!(
                 "Running in restricted discovery mode, but we have no authorized clients. Service will be unreachable"
             );
         }
 
-        authorized_clients.map(Arc::new)
+        if !rejected.is_empty() {
+            warn!(
+                "Rejected {} restricted discovery client key entries. Those clients will be unable to reach the service",
+                rejected.len()
+            );
+        }
+
+        (Some(Arc::new(authorized_clients)), rejected)
+    }
+
+    /// Return `err`, or, if it is `None`, a [`Problem`] warning about our clock skew,
+    /// about the expired keys we failed to remove,
+    /// about the HsDirs we skipped because they don't support the protocols we need,
+    /// or listing the rejected client key entries.
+    ///
+    /// If more than one of these applies, they are all reported, in a [`Problem::Warnings`].
+    ///
+    /// None of these prevents us from publishing,
+    /// so they are only reported when there isn't a more pressing problem.
+    fn problem_or_warning(&self, err: Option<Problem>) -> Option<Problem> {
+        if err.is_some() {
+            return err;
+        }
+
+        let inner = self.inner.lock().expect("poisoned lock");
+        let mut warnings = vec![];
+        // Restricted discovery can't be enabled without the feature,
+        // so without it, there are never any rejected keys.
+        #[cfg(feature = "restricted-discovery")]
+        if !inner.rejected_client_keys.is_empty() {
+            warnings.push(Problem::RestrictedDiscoveryRejectedKeys(
+                inner.rejected_client_keys.clone(),
+            ));
+        }
+        if inner.clock_skew.is_skewed() {
+            warnings.push(Problem::ClockSkew(inner.clock_skew));
+        }
+        if inner.pending_key_deletions > 0 {
+            warnings.push(Problem::ExpiredKeysNotRemoved(inner.pending_key_deletions));
+        }
+        let unsupported_hs_dirs = inner
+            .time_periods
+            .iter()
+            .map(|period| period.unsupported_hs_dirs.len())
+            .sum();
+        if unsupported_hs_dirs > 0 {
+            warnings.push(Problem::UnsupportedHsDirs(unsupported_hs_dirs));
+        }
+
+        match warnings.len() {
+            0 => None,
+            1 => warnings.pop(),
+            _ => Some(Problem::Warnings(warnings)),
+        }
+    }
+
+    /// Update our status to reflect our current warnings
+    /// (see [`problem_or_warning`](Self::problem_or_warning)).
+    ///
+    /// Does nothing unless we have finished uploading our descriptors:
+    /// otherwise, our next status update will take care of it.
+    fn report_warnings(&self) -> Result<(), FatalError> {
+        let have_netdir = self.inner.lock().expect("poisoned lock").netdir.is_some();
+        if self.status() != PublishStatus::Idle || !have_netdir {
+            return Ok(());
+        }
+
+        self.upload_result_to_svc_status()
+    }
+
+    /// Compare our wall clock against the validity period of `netdir`,
+    /// and remember how skewed it is, logging any change.
+    ///
+    /// The time periods themselves are always computed from the consensus,
+    /// but we sign our descriptors, and compute their revision counters, using the wall clock.
+    /// If the wall clock is badly wrong, the HsDirs (and clients) may reject them.
+    fn check_clock_skew(&self, netdir: &NetDir) {
+        let skew = significant_clock_skew(netdir.lifetime(), self.imm.runtime.wallclock());
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let was_skewed = inner.clock_skew.is_skewed();
+        inner.clock_skew = skew;
+        let adapt = inner.config.use_consensus_time_if_skewed;
+        drop(inner);
+
+        match skew {
+            ClockSkew::None if was_skewed => {
+                info!(
+                    nickname=%self.imm.nickname,
+                    "our clock now agrees with the consensus again",
+                );
+            }
+            ClockSkew::None => {}
+            ClockSkew::Slow(_) | ClockSkew::Fast(_) => warn!(
+                nickname=%self.imm.nickname,
+                "our clock seems to be {} by at least {}, compared to the consensus; {}",
+                if matches!(skew, ClockSkew::Slow(_)) { "slow" } else { "fast" },
+                humantime::format_duration(Duration::from_secs(skew.magnitude().as_secs())),
+                if adapt {
+                    "using the consensus to date our descriptors"
+                } else {
+                    "HsDirs may reject our descriptors"
+                },
+            ),
+        }
+    }
+
+    /// Re-sync our publication schedule after the wall clock jumped.
+    ///
+    /// Our reupload timers follow the monotonic clock, which doesn't advance
+    /// while the host is suspended, so after a jump the descriptors we published
+    /// may be much older (or, if the clock went backwards, newer) than we think.
+    /// We mark all of them dirty, so they are rebuilt,
+    /// with fresh timestamps and revision counters, and republished.
+    async fn handle_wallclock_jump(&mut self, jump: &WallclockJump) -> Result<(), FatalError> {
+        info!(
+            nickname=%self.imm.nickname,
+            "wallclock jumped {} by {}; rescheduling descriptor uploads",
+            if jump.is_forwards() { "forwards" } else { "backwards" },
+            humantime::format_duration(Duration::from_secs(jump.magnitude().as_secs())),
+        );
+
+        // The jump may have fixed (or broken) our clock.
+        let netdir = self.inner.lock().expect("poisoned lock").netdir.clone();
+        if let Some(netdir) = netdir {
+            self.check_clock_skew(&netdir);
+        }
+
+        self.mark_all_dirty();
+        self.update_publish_status_unless_waiting(PublishStatus::UploadScheduled)
+            .await
     }
 
     /// Mark the descriptor dirty for all time periods.
     fn mark_all_dirty(&self) {
         trace!("marking the descriptor dirty for all time periods");
 
+        let now = self.imm.runtime.now();
         self.inner
             .lock()
             .expect("poisoned lock")
             .time_periods
             .iter_mut()
-            .for_each(|tp| tp.mark_all_dirty());
+            .for_each(|tp| tp.mark_all_dirty(now));
     }
 
     /// Mark the descriptor dirty for the specified time period.
@@ -1306,7 +2657,7 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         match period_ctx {
             Some(ctx) => {
                 trace!(time_period=?period, "marking the descriptor dirty");
-                ctx.mark_all_dirty();
+                ctx.mark_all_dirty(self.imm.runtime.now());
                 true
             }
             None => false,
@@ -1315,20 +2666,19 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
 
     /// Try to upload our descriptor to the HsDirs that need it.
     ///
-    /// If we've recently uploaded some descriptors, we return immediately and schedule the upload
-    /// to happen after [`UPLOAD_RATE_LIM_THRESHOLD`].
+    /// If we've recently uploaded the descriptor for a time period, we postpone the upload for
+    /// that time period until [`UPLOAD_RATE_LIM_THRESHOLD`] after the last one.
+    /// The other time periods are not affected.
     ///
     /// Failed uploads are retried
     /// (see [`upload_descriptor_with_retries`](Reactor::upload_descriptor_with_retries)).
     ///
     /// If restricted discovery mode is enabled and there are no authorized clients,
-    /// we abort the upload and set our status to [`State::Broken`].
-    //
-    // Note: a broken restricted discovery config won't prevent future uploads from being scheduled
-    // (for example if the IPTs change),
-    // which can can cause the publisher's status to oscillate between `Bootstrapping` and `Broken`.
-    // TODO: we might wish to refactor the publisher to be more sophisticated about this.
-    //
+    /// we abort the upload, set our status to [`State::Broken`],
+    /// and enter [`PublishStatus::ConfigBroken`]:
+    /// we won't try to upload again (or change our status)
+    /// until the configuration or the list of authorized clients changes.
+    ///
     /// For each current time period, we spawn a task that uploads the descriptor to
     /// all the HsDirs on the HsDir ring of that time period.
     /// Each task shuts down on completion, or when the reactor is dropped.
@@ -1340,37 +2690,56 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
     /// Returns an error if it fails to spawn a task, or if an internal error occurs.
     #[allow(clippy::cognitive_complexity)] // TODO #2010: Refactor
     async fn upload_all(&mut self) -> Result<(), FatalError> {
+        if self.status() == PublishStatus::Paused {
+            trace!(nickname=%self.imm.nickname, "descriptor publication is paused; not uploading");
+            return Ok(());
+        }
+
         trace!("starting descriptor upload task...");
 
+        if self.status() == PublishStatus::ConfigBroken {
+            trace!("our configuration is broken; not uploading");
+            return Ok(());
+        }
+
         // Abort the upload entirely if we have an empty list of authorized clients
         let authorized_clients = match self.authorized_clients() {
             Ok(authorized_clients) => authorized_clients,
             Err(e) => {
-                error_report!(e, "aborting upload");
-                self.imm.status_tx.send_broken(e.clone());
+                error_report!(
+                    e,
+                    "aborting upload; not retrying until the configuration changes"
+                );
+                self.imm.status_tx.send_broken(config_problem(e));
+                self.set_publish_status(PublishStatus::ConfigBroken).await?;
 
                 // Returning an error would shut down the reactor, so we have to return Ok here.
                 return Ok(());
             }
         };
 
-        let last_uploaded = self.inner.lock().expect("poisoned lock").last_uploaded;
         let now = self.imm.runtime.now();
-        // Check if we should rate-limit this upload.
-        if let Some(ts) = last_uploaded {
-            let duration_since_upload = now.duration_since(ts);
-
-            if duration_since_upload < UPLOAD_RATE_LIM_THRESHOLD {
-                return Ok(self.start_rate_limit(UPLOAD_RATE_LIM_THRESHOLD).await?);
-            }
-        }
-
         let mut inner = self.inner.lock().expect("poisoned lock");
         let inner = &mut *inner;
 
-        let _ = inner.last_uploaded.insert(now);
+        if let Some(until) = inner.ipt_addr_family_delay_until {
+            debug!(
+                nickname=%self.imm.nickname,
+                "waiting for more diverse introduction points; postponing descriptor upload for {}",
+                humantime::format_duration(until.saturating_duration_since(now)),
+            );
+            return Ok(());
+        }
+
+        // If we upload to the HsDirs of the current time period first,
+        // we need to start that upload before any of the others.
+        let primary_period = inner.netdir.as_ref().map(|netdir| netdir.hs_time_period());
+        let order = (0..inner.time_periods.len())
+            .sorted_by_key(|&i| Some(inner.time_periods[i].params.time_period()) != primary_period)
+            .collect_vec();
 
-        for period_ctx in inner.time_periods.iter_mut() {
+        for i in order {
+            let period_ctx = &mut inner.time_periods[i];
             let upload_task_complete_tx = self.upload_task_complete_tx.clone();
 
             // Figure out which HsDirs we need to upload the descriptor to (some of them might already
@@ -1387,12 +2756,57 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                 })
                 .collect::<Vec<_>>();
 
+            let time_period = period_ctx.params.time_period();
+
             if hs_dirs.is_empty() {
-                trace!("the descriptor is clean for all HSDirs. Nothing to do");
-                return Ok(());
+                trace!(
+                    time_period=?time_period,
+                    "the descriptor is clean for all HSDirs. Nothing to do"
+                );
+                continue;
             }
 
-            let time_period = period_ctx.params.time_period();
+            // Check if we should rate-limit this upload.
+            if let Some(ts) = period_ctx.last_uploaded {
+                let duration_since_upload = now.duration_since(ts);
+
+                if duration_since_upload < UPLOAD_RATE_LIM_THRESHOLD {
+                    let until = ts + UPLOAD_RATE_LIM_THRESHOLD;
+                    if period_ctx.rate_limited_until.is_none() {
+                        debug!(
+                            time_period=?time_period,
+                            "We are rate-limited for {}; postponing descriptor upload",
+                            humantime::format_duration(until - now)
+                        );
+                        if let Some(netdir) = &inner.netdir {
+                            self.imm
+                                .metrics
+                                .note_rate_limited(Ring::of(netdir, time_period));
+                        }
+                    }
+                    period_ctx.rate_limited_until = Some(until);
+                    continue;
+                }
+            }
+
+            let _ = period_ctx.last_uploaded.insert(now);
+            period_ctx.rate_limited_until = None;
+
+            // Work out whether this upload has to wait for the current time period's,
+            // or the others have to wait for this one.
+            let mut release_others = None;
+            let wait_for_primary = match inner.config.primary_ring_first_percent {
+                Some(percent) if primary_period == Some(time_period) => {
+                    let (tx, rx) = oneshot::channel();
+                    let needed = (usize::from(percent) * hs_dirs.len()).div_ceil(100);
+                    release_others = Some((needed.max(1), tx));
+                    inner.primary_ring_released = Some(rx.shared());
+                    None
+                }
+                Some(_) => inner.primary_ring_released.clone(),
+                None => None,
+            };
+
             // This scope exists because rng is not Send, so it needs to fall out of scope before we
             // await anything.
             let netdir = Arc::clone(
@@ -1432,6 +2846,8 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                         authorized_clients.clone(),
                         upload_task_complete_tx,
                         shutdown_rx,
+                        wait_for_primary,
+                        release_others,
                     )
                     .await
                     {
@@ -1453,6 +2869,11 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
     ///
     /// Failed uploads are retried
     /// (see [`upload_descriptor_with_retries`](Reactor::upload_descriptor_with_retries)).
+    ///
+    /// If `wait_for_primary` is set, we don't start until it resolves.
+    /// If `release_others` is set, we send on its sender once its count of uploads have succeeded
+    /// (and drop it when we finish, in any case),
+    /// so that the uploads waiting for us can start.
     #[allow(clippy::too_many_arguments)] // TODO: refactor
     #[allow(clippy::cognitive_complexity)] // TODO: Refactor
     async fn upload_for_time_period(
@@ -1463,10 +2884,29 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         imm: Arc<Immutable<R, M>>,
         ipt_upload_view: IptsPublisherUploadView,
         authorized_clients: Option<Arc<RestrictedDiscoveryKeys>>,
-        mut upload_task_complete_tx: mpsc::Sender<TimePeriodUploadResult>,
+        mut upload_task_complete_tx: mpsc::Sender<UploadTaskMsg>,
         shutdown_rx: broadcast::Receiver<Void>,
+        wait_for_primary: Option<PrimaryRingReleased>,
+        mut release_others: Option<(usize, oneshot::Sender<()>)>,
     ) -> Result<(), FatalError> {
         let time_period = params.time_period();
+
+        if let Some(wait_for_primary) = wait_for_primary {
+            trace!(
+                time_period=?time_period,
+                "waiting for the uploads for the current time period before uploading descriptor"
+            );
+            let mut shutdown_rx = shutdown_rx.clone();
+            select_biased! {
+                shutdown = shutdown_rx.next().fuse() => {
+                    // This will always be None, since Void is uninhabited.
+                    let _: Option<Void> = shutdown;
+                    return Ok(());
+                },
+                _ = wait_for_primary.fuse() => {},
+            }
+        }
+
         trace!(time_period=?time_period, "uploading descriptor to all HSDirs for this time period");
 
         let hsdir_count = hs_dirs.len();
@@ -1498,20 +2938,126 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             Fatal(#[from] FatalError),
         }
 
+        // The bandwidth weight of a typical HsDir from this time period's ring,
+        // against which we scale the upload timeout of each individual HsDir.
+        let typical_weight = median_hsdir_weight(netdir, &hs_dirs);
+
         let max_hsdesc_len: usize = netdir
             .params()
             .hsdir_max_desc_size
             .try_into()
             .expect("Unable to convert positive int32 to usize!?");
 
+        // Build, sign, and record a new version of the descriptor,
+        // whose publication must complete or be abandoned before `worst_case_end`.
+        let build_descriptor =
+            |worst_case_end: Instant| -> Result<VersionedDescriptor, PublishError> {
+                let mut ipt_set = ipt_upload_view.borrow_for_publish();
+
+                // If there are no IPTs, we abort the upload. At this point, we might have
+                // uploaded the descriptor to some, but not all, HSDirs from the specified
+                // time period.
+                //
+                // Returning an error here means the upload completion task is never
+                // notified of the outcome of any of these uploads (which means the
+                // descriptor is not marked clean). This is OK, because if we suddenly find
+                // out we have no IPTs, it means our built `hsdesc` has an outdated set of
+                // IPTs, so we need to go back to the main loop to wait for IPT changes,
+                // and generate a fresh descriptor anyway.
+                //
+                // Ideally, this shouldn't happen very often (if at all).
+                let Some(ipts) = ipt_set.ipts.as_mut() else {
+                    return Err(PublishError::NoIpts);
+                };
+
+                let hsdesc = {
+                    trace!(
+                        nickname=%imm.nickname, time_period=?time_period,
+                        "building descriptor"
+                    );
+                    let mut rng = imm.mockable.thread_rng();
+                    let mut key_rng = tor_llcrypto::rng::CautiousRng;
+
+                    // We're about to generate a new version of the descriptor,
+                    // so let's generate a new revision counter.
+                    let now = descriptor_time(
+                        netdir.lifetime(),
+                        imm.runtime.wallclock(),
+                        config.use_consensus_time_if_skewed,
+                    );
+                    let revision_counter = imm.generate_revision_counter(&params, now, &config)?;
+
+                    build_sign(
+                        &imm.keymgr,
+                        &imm.pow_manager,
+                        &config,
+                        authorized_clients.as_deref(),
+                        ipts,
+                        time_period,
+                        revision_counter,
+                        &mut rng,
+                        &mut key_rng,
+                        now,
+                        max_hsdesc_len,
+                    )?
+                };
+
+                if let Err(e) = ipt_set.note_publication_attempt(&imm.runtime, worst_case_end) {
+                    let wait = e.log_retry_max(&imm.nickname)?;
+                    // TODO (#1226): retry instead of this
+                    return Err(FatalError::Bug(internal!(
+                        "ought to retry after {wait:?}, crashing instead"
+                    ))
+                    .into());
+                }
+
+                imm.descriptor_summaries
+                    .lock()
+                    .expect("poisoned lock")
+                    .insert(time_period, hsdesc.summary.clone());
+                imm.metrics.note_descriptor_size(hsdesc.desc.len());
+
+                if let Some(sink) = &imm.descriptor_sink {
+                    sink.descriptor_built(&BuiltDescriptor {
+                        nickname: imm.nickname.clone(),
+                        time_period,
+                        revision_counter: hsdesc.revision_counter,
+                        descriptor: hsdesc.desc.clone(),
+                    });
+                }
+
+                trace!(
+                    nickname=%imm.nickname, time_period=?time_period,
+                    revision_counter=?hsdesc.revision_counter,
+                    "generated new descriptor for time period",
+                );
+
+                Ok(hsdesc)
+            };
+
+        // Unless we are configured to build one descriptor per round,
+        // we generate a new descriptor before _each_ HsDir upload. This means each
+        // HsDir could, in theory, receive a different descriptor (not just in terms of
+        // revision-counters, but also with a different set of IPTs). It may seem like
+        // this could lead to some HsDirs being left with an outdated descriptor, but
+        // that's not the case: after the upload completes, the publisher will be
+        // notified by the ipt_watcher of the IPT change event (if there was one to
+        // begin with), which will trigger another upload job.
+        //
+        // If we are building one descriptor per round, we build it now,
+        // and upload that same descriptor (with the same revision counter) to every HsDir.
+        let shared_descriptor = config.one_descriptor_per_round.then(|| {
+            // Our last upload may start as late as `upload_jitter` from now.
+            let worst_case_end = imm.runtime.now() + config.upload_jitter + OVERALL_UPLOAD_TIMEOUT;
+            build_descriptor(worst_case_end)
+        });
+
         let upload_results = futures::stream::iter(hs_dirs)
             .map(|relay_ids| {
                 let netdir = netdir.clone();
-                let config = Arc::clone(&config);
                 let imm = Arc::clone(&imm);
-                let ipt_upload_view = ipt_upload_view.clone();
-                let authorized_clients = authorized_clients.clone();
-                let params = params.clone();
+                let build_descriptor = &build_descriptor;
+                let shared_descriptor = &shared_descriptor;
                 let mut shutdown_rx = shutdown_rx.clone();
 
                 let ed_id = relay_ids
@@ -1523,7 +3069,34 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                     .map(|id| id.to_string())
                     .unwrap_or_else(|| "unknown".into());
 
+                // Spread out the start of our uploads,
+                // so that we don't launch a burst of circuits to all the HsDirs at once.
+                let start_delay = imm
+                    .mockable
+                    .thread_rng()
+                    .gen_range_infallible(..=config.upload_jitter);
+
                 async move {
+                    // If we failed to build the shared descriptor, there is nothing to upload.
+                    let shared_descriptor = shared_descriptor.clone().transpose()?;
+
+                    if !start_delay.is_zero() {
+                        trace!(
+                            nickname=%imm.nickname, hsdir_id=%ed_id, hsdir_rsa_id=%rsa_id,
+                            "delaying descriptor upload by {}",
+                            humantime::format_duration(start_delay),
+                        );
+
+                        select_biased! {
+                            shutdown = shutdown_rx.next().fuse() => {
+                                // This will always be None, since Void is uninhabited.
+                                let _: Option<Void> = shutdown;
+                                return Err(PublishError::Shutdown);
+                            },
+                            () = imm.runtime.sleep(start_delay).fuse() => {},
+                        }
+                    }
+
                     let run_upload = |desc| async {
                         let Some(hsdir) = netdir.by_ids(&relay_ids) else {
                             // This should never happen (all of our relay_ids are from the stored
@@ -1537,99 +3110,35 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                             return Err(internal!("{err}").into());
                         };
 
+                        let timeout_factor = hsdir_timeout_factor(
+                            netdir.relay_weight(&hsdir, WeightRole::Unweighted),
+                            typical_weight,
+                        );
+
                         Self::upload_descriptor_with_retries(
                             desc,
                             &netdir,
                             &hsdir,
                             &ed_id,
                             &rsa_id,
+                            timeout_factor,
                             Arc::clone(&imm),
                         )
                         .await
                     };
 
-                    // How long until we're supposed to time out?
-                    let worst_case_end = imm.runtime.now() + OVERALL_UPLOAD_TIMEOUT;
-                    // We generate a new descriptor before _each_ HsDir upload. This means each
-                    // HsDir could, in theory, receive a different descriptor (not just in terms of
-                    // revision-counters, but also with a different set of IPTs). It may seem like
-                    // this could lead to some HsDirs being left with an outdated descriptor, but
-                    // that's not the case: after the upload completes, the publisher will be
-                    // notified by the ipt_watcher of the IPT change event (if there was one to
-                    // begin with), which will trigger another upload job.
-                    let hsdesc = {
-                        // This scope is needed because the ipt_set MutexGuard is not Send, so it
-                        // needs to fall out of scope before the await point below
-                        let mut ipt_set = ipt_upload_view.borrow_for_publish();
-
-                        // If there are no IPTs, we abort the upload. At this point, we might have
-                        // uploaded the descriptor to some, but not all, HSDirs from the specified
-                        // time period.
-                        //
-                        // Returning an error here means the upload completion task is never
-                        // notified of the outcome of any of these uploads (which means the
-                        // descriptor is not marked clean). This is OK, because if we suddenly find
-                        // out we have no IPTs, it means our built `hsdesc` has an outdated set of
-                        // IPTs, so we need to go back to the main loop to wait for IPT changes,
-                        // and generate a fresh descriptor anyway.
-                        //
-                        // Ideally, this shouldn't happen very often (if at all).
-                        let Some(ipts) = ipt_set.ipts.as_mut() else {
-                            return Err(PublishError::NoIpts);
-                        };
-
-                        let hsdesc = {
-                            trace!(
-                                nickname=%imm.nickname, time_period=?time_period,
-                                "building descriptor"
-                            );
-                            let mut rng = imm.mockable.thread_rng();
-                            let mut key_rng = tor_llcrypto::rng::CautiousRng;
-
-                            // We're about to generate a new version of the descriptor,
-                            // so let's generate a new revision counter.
-                            let now = imm.runtime.wallclock();
-                            let revision_counter = imm.generate_revision_counter(&params, now)?;
-
-                            build_sign(
-                                &imm.keymgr,
-                                &imm.pow_manager,
-                                &config,
-                                authorized_clients.as_deref(),
-                                ipts,
-                                time_period,
-                                revision_counter,
-                                &mut rng,
-                                &mut key_rng,
-                                imm.runtime.wallclock(),
-                                max_hsdesc_len,
-                            )?
-                        };
-
-                        if let Err(e) =
-                            ipt_set.note_publication_attempt(&imm.runtime, worst_case_end)
-                        {
-                            let wait = e.log_retry_max(&imm.nickname)?;
-                            // TODO (#1226): retry instead of this
-                            return Err(FatalError::Bug(internal!(
-                                "ought to retry after {wait:?}, crashing instead"
-                            ))
-                            .into());
-                        }
-
-                        hsdesc
-                    };
-
                     let VersionedDescriptor {
                         desc,
                         revision_counter,
-                    } = hsdesc;
-
-                    trace!(
-                        nickname=%imm.nickname, time_period=?time_period,
-                        revision_counter=?revision_counter,
-                        "generated new descriptor for time period",
-                    );
+                        summary: _,
+                    } = match shared_descriptor {
+                        Some(hsdesc) => hsdesc,
+                        None => {
+                            // How long until we're supposed to time out?
+                            let worst_case_end = imm.runtime.now() + OVERALL_UPLOAD_TIMEOUT;
+                            build_descriptor(worst_case_end)?
+                        }
+                    };
 
                     // (Actually launch the upload attempt. No timeout is needed
                     // here, since the backoff::Runner code will handle that for us.)
@@ -1655,21 +3164,58 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
                     // Note: UploadResult::Failure is only returned when
                     // upload_descriptor_with_retries fails, i.e. if all our retry
                     // attempts have failed
-                    Ok(HsDirUploadStatus {
+                    Ok(HsDirUploadStatus::new(
                         relay_ids,
                         upload_res,
                         revision_counter,
-                    })
+                    ))
                 }
             })
             // This fails to compile unless the stream is boxed. See https://github.com/rust-lang/rust/issues/104382
             .boxed()
             .buffer_unordered(MAX_CONCURRENT_UPLOADS)
+            .inspect_ok(|res| {
+                let Some((needed, _)) = &mut release_others else {
+                    return;
+                };
+                if res.upload_res.is_ok() {
+                    *needed = needed.saturating_sub(1);
+                }
+                if *needed == 0 {
+                    debug!(
+                        nickname=%imm.nickname, time_period=?time_period,
+                        "enough uploads for the current time period have succeeded; \
+                         starting the uploads for the other time periods",
+                    );
+                    if let Some((_, tx)) = release_others.take() {
+                        let _ = tx.send(());
+                    }
+                }
+            })
             .try_collect::<Vec<_>>()
             .await;
 
         let upload_results = match upload_results {
             Ok(v) => v,
+            Err(PublishError::Fatal(e)) if e.is_transient_keystore_error() => {
+                // We couldn't build the descriptor, but we might be able to later.
+                // The reactor will tell us to try again.
+                debug_report!(
+                    e,
+                    "HS service {}: keystore unavailable; abandoning upload for time period {:?}",
+                    imm.nickname,
+                    time_period
+                );
+                return upload_task_complete_tx
+                    .send(UploadTaskMsg::KeystoreUnavailable(e))
+                    .await
+                    .map_err(|_| {
+                        internal!(
+                            "failed to notify reactor of keystore failure (reactor shut down)"
+                        )
+                        .into()
+                    });
+            }
             Err(PublishError::Fatal(e)) => return Err(e),
             Err(PublishError::NoIpts) => {
                 debug!(
@@ -1689,9 +3235,11 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             }
         };
 
-        let (succeeded, _failed): (Vec<_>, Vec<_>) = upload_results
+        let (succeeded, failed): (Vec<_>, Vec<_>) = upload_results
             .iter()
             .partition(|res| res.upload_res.is_ok());
+        imm.metrics
+            .note_uploads(Ring::of(netdir, time_period), succeeded.len(), failed.len());
 
         debug!(
             nickname=%imm.nickname, time_period=?time_period,
@@ -1700,10 +3248,10 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         );
 
         if upload_task_complete_tx
-            .send(TimePeriodUploadResult {
+            .send(UploadTaskMsg::Completed(TimePeriodUploadResult {
                 time_period,
                 hsdir_result: upload_results,
-            })
+            }))
             .await
             .is_err()
         {
@@ -1718,6 +3266,14 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
 
     /// Upload a descriptor to the specified HSDir.
     ///
+    /// If `reusable_tunnel` contains an open tunnel (left there by a previous failed attempt),
+    /// the upload is attempted over that tunnel. Otherwise, a new tunnel is obtained from the
+    /// circuit pool.
+    ///
+    /// If the HsDir rejects the upload (or the request fails after we've opened the directory
+    /// stream), the tunnel is put back in `reusable_tunnel`, so that the next attempt doesn't
+    /// need to build a new circuit.
+    ///
     /// If an upload fails, this returns an `Err`. This function does not handle retries. It is up
     /// to the caller to retry on failure.
     ///
@@ -1726,6 +3282,7 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         hsdesc: String,
         netdir: &Arc<NetDir>,
         hsdir: &Relay<'_>,
+        reusable_tunnel: &ReusableTunnel<M>,
         imm: Arc<Immutable<R, M>>,
     ) -> Result<(), UploadError> {
         let request = HsDescUploadRequest::new(hsdesc);
@@ -1734,10 +3291,43 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             "starting descriptor upload",
         );
 
-        let tunnel = imm
-            .mockable
-            .get_or_launch_hs_dir(netdir, OwnedCircTarget::from_circ_target(hsdir))
-            .await?;
+        let reusable = reusable_tunnel
+            .lock()
+            .expect("poisoned lock")
+            .take()
+            .filter(|tunnel| !tunnel.is_closed());
+
+        let tunnel = match reusable {
+            Some(tunnel) => {
+                trace!(nickname=%imm.nickname, hsdir_id=%hsdir.id(), hsdir_rsa_id=%hsdir.rsa_id(),
+                    "reusing circuit from previous upload attempt",
+                );
+                tunnel
+            }
+            None => Arc::new(
+                imm.mockable
+                    .get_or_launch_hs_dir(netdir, OwnedCircTarget::from_circ_target(hsdir))
+                    .await?,
+            ),
+        };
+
+        let res = Self::upload_descriptor_on_tunnel(request, &tunnel, &imm).await;
+
+        // The circuit itself worked fine (it's the request that failed),
+        // so let's hang on to it in case we need to retry.
+        if let Err(UploadError::Request(_) | UploadError::Rejected { .. }) = &res {
+            *reusable_tunnel.lock().expect("poisoned lock") = Some(tunnel);
+        }
+
+        res
+    }
+
+    /// Upload a descriptor over the specified `tunnel`, which must end at the target HsDir.
+    async fn upload_descriptor_on_tunnel(
+        request: HsDescUploadRequest,
+        tunnel: &M::Tunnel,
+        imm: &Immutable<R, M>,
+    ) -> Result<(), UploadError> {
         let source: Option<SourceInfo> = tunnel
             .source_info()
             .map_err(into_internal!("Couldn't get SourceInfo for circuit"))?;
@@ -1780,12 +3370,14 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         hsdir: &Relay<'_>,
         ed_id: &str,
         rsa_id: &str,
+        timeout_factor: f64,
         imm: Arc<Immutable<R, M>>,
     ) -> UploadResult {
         /// The base delay to use for the backoff schedule.
         const BASE_DELAY_MSEC: u32 = 1000;
         let schedule = PublisherBackoffSchedule {
             retry_delay: RetryDelay::from_msec(BASE_DELAY_MSEC),
+            timeout_factor,
             mockable: imm.mockable.clone(),
         };
 
@@ -1795,8 +3387,21 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
             imm.runtime.clone(),
         );
 
+        // A tunnel from a previous failed attempt, if it's still usable.
+        //
+        // Reusing it between retries saves us from building a new circuit
+        // each time the HsDir rejects our upload.
+        let reusable_tunnel: ReusableTunnel<M> = Mutex::new(None);
+
         let fallible_op = || async {
-            let r = Self::upload_descriptor(hsdesc.clone(), netdir, hsdir, Arc::clone(&imm)).await;
+            let r = Self::upload_descriptor(
+                hsdesc.clone(),
+                netdir,
+                hsdir,
+                &reusable_tunnel,
+                Arc::clone(&imm),
+            )
+            .await;
 
             if let Err(e) = &r {
                 if e.should_report_as_suspicious() {
@@ -1839,29 +3444,6 @@ impl<R: Runtime, M: Mockable> Reactor<R, M> {
         }
     }
 
-    /// Stop publishing descriptors until the specified delay elapses.
-    async fn start_rate_limit(&mut self, delay: Duration) -> Result<(), Bug> {
-        if !matches!(self.status(), PublishStatus::RateLimited(_)) {
-            debug!(
-                "We are rate-limited for {}; pausing descriptor publication",
-                humantime::format_duration(delay)
-            );
-            let until = self.imm.runtime.now() + delay;
-            self.update_publish_status(PublishStatus::RateLimited(until))
-                .await?;
-        }
-
-        Ok(())
-    }
-
-    /// Handle the upload rate-limit being lifted.
-    async fn expire_rate_limit(&mut self) -> Result<(), Bug> {
-        debug!("We are no longer rate-limited; resuming descriptor publication");
-        self.update_publish_status(PublishStatus::UploadScheduled)
-            .await?;
-        Ok(())
-    }
-
     /// Return the authorized clients, if restricted mode is enabled.
     ///
     /// Returns `Ok(None)` if restricted discovery mode is disabled.
@@ -1917,12 +3499,17 @@ macro_rules! watch_path {
 }
 
 /// Add the specified directories to the watcher.
+///
+/// Returns the directories that don't exist, and therefore aren't watched.
+/// The caller should check for them periodically,
+/// and recreate the watcher once they appear.
 #[allow(clippy::cognitive_complexity)]
 fn watch_dirs<R: Runtime>(
     watcher: &mut FileWatcherBuilder<R>,
     dirs: &DirectoryKeyProviderList,
     path_resolver: &CfgPathResolver,
-) {
+) -> Vec<PathBuf> {
+    let mut missing = vec![];
     for path in dirs {
         let path = path.path();
         let Some(path) = maybe_expand_path(path, path_resolver) else {
@@ -1938,16 +3525,32 @@ fn watch_dirs<R: Runtime>(
         // but it will fail to set the watcher).
         if matches!(path.try_exists(), Ok(true)) {
             watch_path!(watcher, &path, watch_dir, "auth",);
+        } else {
+            missing.push(path.clone());
         }
         // FileWatcher::watch_path causes the parent dir of the path to be watched.
         if matches!(path.parent().map(|p| p.try_exists()), Some(Ok(true))) {
             watch_path!(watcher, &path, watch_path,);
         }
     }
+    missing
+}
+
+/// Return the [`KeystoreSelector`] for the configured keystore `id`, if any.
+///
+/// If no keystore was configured, we use the primary keystore.
+pub(super) fn keystore_selector(id: Option<&KeystoreId>) -> KeystoreSelector<'_> {
+    match id {
+        Some(id) => KeystoreSelector::Id(id),
+        None => KeystoreSelector::Primary,
+    }
 }
 
 /// Try to read the blinded identity key for a given `TimePeriod`.
 ///
+/// If the key doesn't exist yet, we derive it, and store it in the keystore
+/// chosen by `keystore_selector`.
+///
 /// Returns `None` if the service is running in "offline" mode.
 ///
 // TODO (#1194): we don't currently have support for "offline" mode so this can never return
@@ -1956,6 +3559,7 @@ pub(super) fn read_blind_id_keypair(
     keymgr: &Arc<KeyMgr>,
     nickname: &HsNickname,
     period: TimePeriod,
+    keystore_selector: KeystoreSelector,
 ) -> Result<Option<HsBlindIdKeypair>, FatalError> {
     let svc_key_spec = HsIdKeypairSpecifier::new(nickname.clone());
     let hsid_kp = keymgr
@@ -1964,8 +3568,6 @@ pub(super) fn read_blind_id_keypair(
 
     let blind_id_key_spec = BlindIdKeypairSpecifier::new(nickname.clone(), period);
 
-    // TODO: make the keystore selector configurable
-    let keystore_selector = Default::default();
     match keymgr.get::<HsBlindIdKeypair>(&blind_id_key_spec)? {
         Some(kp) => Ok(Some(kp)),
         None => {
@@ -1998,29 +3600,37 @@ pub(super) fn read_blind_id_keypair(
 
 /// Determine the [`State`] of the publisher based on the upload results
 /// from the current `time_periods`.
+///
+/// We are [`State::Running`] if, on each HsDir ring, at least `running_upload_percent`
+/// percent of the uploads succeeded.
 fn upload_result_state(
     netdir: &NetDir,
     time_periods: &[TimePeriodContext],
+    running_upload_percent: u8,
 ) -> (State, Option<Problem>) {
     let current_period = netdir.hs_time_period();
     let current_period_res = time_periods
         .iter()
         .find(|ctx| ctx.params.time_period() == current_period);
 
-    let succeeded_current_tp = current_period_res
+    let current_tp_res = current_period_res
         .iter()
         .flat_map(|res| &res.upload_results)
+        .collect_vec();
+
+    let succeeded_current_tp = current_tp_res
+        .iter()
         .filter(|res| res.upload_res.is_ok())
         .collect_vec();
 
     let secondary_tp_res = time_periods
         .iter()
         .filter(|ctx| ctx.params.time_period() != current_period)
+        .flat_map(|res| &res.upload_results)
         .collect_vec();
 
     let succeeded_secondary_tp = secondary_tp_res
         .iter()
-        .flat_map(|res| &res.upload_results)
         .filter(|res| res.upload_res.is_ok())
         .collect_vec();
 
@@ -2048,6 +3658,13 @@ fn upload_result_state(
         return (State::DegradedUnreachable, err);
     }
 
+    // Whether enough of the uploads to this ring succeeded for us to consider it healthy.
+    let meets_threshold = |succeeded: usize, total: usize| {
+        succeeded * 100 >= usize::from(running_upload_percent) * total
+    };
+    let meets_running_threshold = meets_threshold(succeeded_current_tp.len(), current_tp_res.len())
+        && meets_threshold(succeeded_secondary_tp.len(), secondary_tp_res.len());
+
     let state = match (
         succeeded_current_tp.as_slice(),
         succeeded_secondary_tp.as_slice(),
@@ -2057,15 +3674,16 @@ fn upload_result_state(
             // We are still bootstrapping.
             State::Bootstrapping
         }
-        (&[_, ..], &[_, ..]) if failed.is_empty() => {
+        (&[_, ..], &[_, ..]) if meets_running_threshold => {
             // We have uploaded the descriptor to one or more HsDirs from both
-            // HsDir rings (primary and secondary), and none of the uploads failed.
+            // HsDir rings (primary and secondary), and enough of the uploads
+            // to each ring succeeded (by default, this means none of them failed).
             // We are fully reachable.
             State::Running
         }
         (&[_, ..], &[_, ..]) => {
             // We have uploaded the descriptor to one or more HsDirs from both
-            // HsDir rings (primary and secondary), but some of the uploads failed.
+            // HsDir rings (primary and secondary), but too many of the uploads failed.
             // We are reachable, but we failed to upload the descriptor to all the HsDirs
             // that were supposed to have it.
             State::DegradedReachable
@@ -2089,12 +3707,6 @@ fn upload_result_state(
 enum PublishStatus {
     /// We need to call upload_all.
     UploadScheduled,
-    /// We are rate-limited until the specified [`Instant`].
-    ///
-    /// We have tried to schedule multiple uploads in a short time span,
-    /// and we are rate-limited. We are waiting for a signal from the schedule_upload_tx
-    /// channel to unblock us.
-    RateLimited(Instant),
     /// We are idle and waiting for external events.
     ///
     /// We have enough information to build the descriptor, but since we have already called
@@ -2106,6 +3718,96 @@ enum PublishStatus {
     /// `UploadScheduled`.
     #[default]
     AwaitingIpts,
+    /// Our configuration makes it impossible to publish a descriptor.
+    ///
+    /// (Currently, this means restricted discovery is enabled, but there are no authorized clients.)
+    ///
+    /// We have reported that we are [`Broken`](State::Broken).
+    /// We stay in this state, without uploading anything or changing our status,
+    /// until the configuration changes: see [`Reactor::clear_config_broken`].
+    /// Pausing and resuming publication doesn't take us out of this state.
+    ConfigBroken,
+    /// The operator has paused descriptor publication.
+    ///
+    /// We don't upload any descriptors, and ignore our reupload timers,
+    /// but we keep tracking the consensus, our introduction points, and our configuration,
+    /// marking descriptors dirty as usual.
+    /// Uploads that were already in progress when we paused are allowed to finish.
+    ///
+    /// We stay in this state until the operator resumes publication:
+    /// see [`Reactor::handle_pause_change`].
+    Paused,
+}
+
+/// Return the median bandwidth weight of the relays in `hs_dirs`.
+///
+/// Relays that are not in `netdir` are ignored.
+fn median_hsdir_weight(netdir: &NetDir, hs_dirs: &[RelayIds]) -> RelayWeight {
+    let mut weights = hs_dirs
+        .iter()
+        .filter_map(|ids| netdir.by_ids(ids))
+        .map(|relay| netdir.relay_weight(&relay, WeightRole::Unweighted))
+        .collect_vec();
+    weights.sort();
+
+    weights
+        .get(weights.len() / 2)
+        .copied()
+        .unwrap_or_else(|| 0.into())
+}
+
+/// Return the factor by which to scale the single-attempt upload timeout of an HsDir
+/// whose bandwidth weight is `weight`,
+/// when the weight of a typical HsDir is `typical`.
+///
+/// A relay with less bandwidth than usual is likely to be slower to answer,
+/// so we give it proportionally longer before giving up on an attempt;
+/// conversely, we give up sooner on an attempt to a relay with more bandwidth than usual,
+/// since it ought to have answered already.
+/// The factor is always between [`MIN_HSDIR_TIMEOUT_FACTOR`] and [`MAX_HSDIR_TIMEOUT_FACTOR`].
+///
+/// If we don't know the typical weight, the factor is 1.
+fn hsdir_timeout_factor(weight: RelayWeight, typical: RelayWeight) -> f64 {
+    if typical == 0.into() {
+        return 1.0;
+    }
+
+    typical
+        .checked_div(weight)
+        .unwrap_or(MAX_HSDIR_TIMEOUT_FACTOR)
+        .clamp(MIN_HSDIR_TIMEOUT_FACTOR, MAX_HSDIR_TIMEOUT_FACTOR)
+}
+
+/// Return how long to wait before trying again to access the keystore,
+/// after `n_failures` consecutive temporary failures.
+fn keystore_retry_delay(n_failures: u32) -> Duration {
+    let doublings = n_failures.saturating_sub(1).min(u32::BITS - 1);
+    KEYSTORE_RETRY_INITIAL_DELAY
+        .checked_mul(1 << doublings)
+        .unwrap_or(KEYSTORE_RETRY_MAX_DELAY)
+        .min(KEYSTORE_RETRY_MAX_DELAY)
+}
+
+/// Return a record of the HsDirs of `time_periods`, which we computed from `netdir`.
+fn hsdir_cache_record(netdir: &NetDir, time_periods: &[TimePeriodContext]) -> HsDirCacheRecord {
+    let rings = time_periods
+        .iter()
+        .map(|ctx| {
+            let hs_dirs = ctx.hs_dirs.iter().map(|(ids, _)| ids.clone()).collect();
+            (ctx.params.time_period(), hs_dirs)
+        })
+        .collect();
+    HsDirCacheRecord::new(netdir.lifetime().valid_until(), rings)
+}
+
+/// Return the [`Problem`] to report when [`Reactor::authorized_clients`] fails with `e`.
+fn config_problem(e: FatalError) -> Problem {
+    #[cfg(feature = "restricted-discovery")]
+    if matches!(e, FatalError::RestrictedDiscoveryNoClients) {
+        return Problem::RestrictedDiscoveryNoClients;
+    }
+
+    e.into()
 }
 
 /// The backoff schedule for the task that publishes descriptors.
@@ -2113,6 +3815,11 @@ enum PublishStatus {
 struct PublisherBackoffSchedule<M: Mockable> {
     /// The delays
     retry_delay: RetryDelay,
+    /// The factor by which to scale our estimated single-attempt timeout
+    /// for this particular HsDir.
+    ///
+    /// See [`hsdir_timeout_factor`].
+    timeout_factor: f64,
     /// The mockable reactor state, needed for obtaining an rng.
     mockable: M,
 }
@@ -2127,7 +3834,11 @@ impl<M: Mockable> BackoffSchedule for PublisherBackoffSchedule<M> {
     }
 
     fn single_attempt_timeout(&self) -> Option<Duration> {
-        Some(self.mockable.estimate_upload_timeout())
+        Some(
+            self.mockable
+                .estimate_upload_timeout()
+                .mul_f64(self.timeout_factor),
+        )
     }
 
     fn next_delay<E: RetriableError>(&mut self, _error: &E) -> Option<Duration> {
@@ -2139,11 +3850,33 @@ impl RetriableError for UploadError {
     fn should_retry(&self) -> bool {
         match self {
             UploadError::Request(_) | UploadError::Circuit(_) | UploadError::Stream(_) => true,
+            UploadError::Rejected { rejection, .. } => !rejection.is_permanent(),
             UploadError::Bug(_) => false,
         }
     }
 }
 
+/// A message from an upload task to the reactor.
+#[derive(Debug, Clone)]
+enum UploadTaskMsg {
+    /// The upload task finished uploading the descriptor.
+    Completed(TimePeriodUploadResult),
+    /// The upload task couldn't build the descriptor,
+    /// because of a keystore failure that might be temporary.
+    KeystoreUnavailable(FatalError),
+}
+
+/// What to try again once the keystore is (hopefully) accessible again.
+///
+/// Ordered so that each variant covers the ones before it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+enum KeystoreRetry {
+    /// Build and upload our descriptors.
+    Upload,
+    /// Recompute our HsDirs, and then build and upload our descriptors.
+    RecomputeHsDirs,
+}
+
 /// The outcome of uploading a descriptor to the HSDirs from a particular time period.
 #[derive(Debug, Clone)]
 struct TimePeriodUploadResult {
@@ -2160,10 +3893,34 @@ struct HsDirUploadStatus {
     relay_ids: RelayIds,
     /// The outcome of this attempt.
     upload_res: UploadResult,
+    /// Why the HsDir rejected our descriptor the last time it did so, if it did.
+    rejection: Option<HsDirRejection>,
     /// The revision counter of the descriptor we tried to upload.
     revision_counter: RevisionCounter,
 }
 
+impl HsDirUploadStatus {
+    /// Create a new `HsDirUploadStatus` for the upload of a descriptor with `revision_counter`
+    /// to `relay_ids`, which had the outcome `upload_res`.
+    fn new(
+        relay_ids: RelayIds,
+        upload_res: UploadResult,
+        revision_counter: RevisionCounter,
+    ) -> Self {
+        let rejection = upload_res
+            .as_ref()
+            .err()
+            .and_then(|e| e.hsdir_rejection())
+            .cloned();
+        HsDirUploadStatus {
+            relay_ids,
+            upload_res,
+            rejection,
+            revision_counter,
+        }
+    }
+}
+
 /// The outcome of uploading a descriptor.
 type UploadResult = Result<(), DescUploadRetryError>;
 
@@ -2210,18 +3967,18 @@ mod test {
         TimePeriodContext {
             params: params.clone(),
             hs_dirs: vec![],
+            unsupported_hs_dirs: vec![],
             last_successful: None,
             upload_results,
+            last_uploaded: None,
+            rate_limited_until: None,
+            dirty_since: None,
         }
     }
 
     /// Create a single `HsDirUploadStatus`
     fn create_upload_status(upload_res: UploadResult) -> HsDirUploadStatus {
-        HsDirUploadStatus {
-            relay_ids: RelayIds::empty(),
-            upload_res,
-            revision_counter: RevisionCounter::from(13),
-        }
+        HsDirUploadStatus::new(RelayIds::empty(), upload_res, RevisionCounter::from(13))
     }
 
     /// Create a bunch of results, all with the specified `upload_res`.
@@ -2244,6 +4001,157 @@ mod test {
         dir.unwrap_if_sufficient().unwrap()
     }
 
+    #[test]
+    fn revision_counters_increase() {
+        let rc = |n: u64| RevisionCounter::from(n);
+
+        assert_eq!(monotonic_revision_counter(None, 5).unwrap(), rc(5));
+        assert_eq!(monotonic_revision_counter(Some(rc(3)), 5).unwrap(), rc(5));
+        // The wall clock went backwards (or didn't advance).
+        assert_eq!(monotonic_revision_counter(Some(rc(5)), 5).unwrap(), rc(6));
+        assert_eq!(monotonic_revision_counter(Some(rc(7)), 2).unwrap(), rc(8));
+        assert!(monotonic_revision_counter(Some(rc(u64::MAX)), 2).is_err());
+    }
+
+    #[test]
+    fn hsdir_rejection() {
+        use HsDirRejection as HR;
+
+        let classify = |status, message| HsDirRejection::from_response(status, message);
+        assert_eq!(classify(400, "Descriptor too big"), HR::TooBig);
+        assert_eq!(classify(413, "Payload Too Large"), HR::TooBig);
+        assert_eq!(
+            classify(400, "Invalid descriptor signature"),
+            HR::BadSignature
+        );
+        assert_eq!(
+            classify(503, "Directory busy, try again later"),
+            HR::RateLimited
+        );
+        assert_eq!(classify(429, "Too Many Requests"), HR::RateLimited);
+        assert_eq!(
+            classify(400, "Not responsible for this descriptor"),
+            HR::WrongRing
+        );
+        assert_eq!(
+            classify(400, "Invalid v3 hidden service descriptor"),
+            HR::Other {
+                status: 400,
+                message: "Invalid v3 hidden service descriptor".into()
+            }
+        );
+
+        // Only permanent rejections stop us from retrying.
+        let upload_error = |status, message: &str| UploadError::Rejected {
+            rejection: classify(status, message),
+            error: RequestFailedError {
+                source: None,
+                error: RequestError::HttpStatus(status, message.into()),
+            },
+        };
+        assert!(!upload_error(400, "Descriptor too big").should_retry());
+        assert!(!upload_error(400, "Not responsible for this descriptor").should_retry());
+        assert!(upload_error(503, "Directory busy").should_retry());
+        assert!(upload_error(400, "Invalid v3 hidden service descriptor").should_retry());
+
+        // The rejection is recorded in the upload status.
+        let mut retry_error = RetryError::in_attempt_to("upload a descriptor");
+        retry_error.push(upload_error(400, "Bad signature"));
+        let status = create_upload_status(Err(DescUploadRetryError::FatalError(retry_error)));
+        assert_eq!(status.rejection, Some(HR::BadSignature));
+        assert_eq!(create_upload_status(Ok(())).rejection, None);
+    }
+
+    #[test]
+    fn skip_unsupported_hsdirs() {
+        // Only the even-numbered relays advertise support for v3 descriptors.
+        let supports_hsdir = |idx: usize| idx % 2 == 0;
+        let netdir = testnet::construct_custom_netdir(|idx, node, _| {
+            let protocols = if supports_hsdir(idx) {
+                "HSDir=2"
+            } else {
+                "DirCache=2"
+            };
+            node.rs.protos(protocols.parse().unwrap());
+        })
+        .unwrap()
+        .unwrap_if_sufficient()
+        .unwrap();
+        let netdir = Arc::new(netdir);
+
+        let blind_id = HsBlindId::from(ed25519::Ed25519Identity::new([42; 32]));
+        let params = netdir.hs_all_time_periods().remove(0);
+        let period = params.time_period();
+        let ctx = TimePeriodContext::new(params, blind_id, &netdir, iter::empty(), vec![]).unwrap();
+
+        let all_hsdirs = netdir.hs_dirs_upload(blind_id, period).unwrap().count();
+        assert_eq!(
+            ctx.hs_dirs.len() + ctx.unsupported_hs_dirs.len(),
+            all_hsdirs
+        );
+
+        let relay_supports_hsdir = |ids: &RelayIds| {
+            let relay = netdir.by_ids(ids).unwrap();
+            relay
+                .protovers()
+                .supports_named_subver(tor_protover::named::HSDIR_V3)
+        };
+        for (relay_ids, status) in &ctx.hs_dirs {
+            assert!(relay_supports_hsdir(relay_ids));
+            assert_eq!(*status, DescriptorStatus::Dirty);
+        }
+        for unsupported in &ctx.unsupported_hs_dirs {
+            assert!(!relay_supports_hsdir(&unsupported.relay_ids));
+            assert_eq!(unsupported.missing.to_string(), "HSDir=2");
+        }
+    }
+
+    #[test]
+    fn hsdir_spread_increase() {
+        let netdir_with_spread = |spread| {
+            let netdir = testnet::construct_custom_netdir(|_, _, bld| {
+                bld.param("hsdir_spread_store", spread);
+            })
+            .unwrap()
+            .unwrap_if_sufficient()
+            .unwrap();
+            Arc::new(netdir)
+        };
+        let small = netdir_with_spread(2);
+        let large = netdir_with_spread(4);
+
+        let blind_id = HsBlindId::from(ed25519::Ed25519Identity::new([42; 32]));
+        let params = small.hs_all_time_periods().remove(0);
+        let mut old_ctx =
+            TimePeriodContext::new(params.clone(), blind_id, &small, iter::empty(), vec![])
+                .unwrap();
+        // Pretend we have published the descriptor to all the HsDirs we knew about.
+        for (_, status) in &mut old_ctx.hs_dirs {
+            *status = DescriptorStatus::Clean;
+        }
+
+        let ctx = TimePeriodContext::new(params, blind_id, &large, old_ctx.hs_dirs.iter(), vec![])
+            .unwrap();
+        assert!(ctx.hs_dirs.len() > old_ctx.hs_dirs.len());
+        for (relay_ids, status) in &ctx.hs_dirs {
+            let was_known = old_ctx.hs_dirs.iter().any(|(id, _)| id == relay_ids);
+            // Only the newly added HsDirs need our descriptor.
+            let expected = if was_known {
+                DescriptorStatus::Clean
+            } else {
+                DescriptorStatus::Dirty
+            };
+            assert_eq!(*status, expected);
+        }
+        assert_eq!(
+            ctx.hs_dirs
+                .iter()
+                .filter(|(_, status)| *status == DescriptorStatus::Clean)
+                .count(),
+            old_ctx.hs_dirs.len()
+        );
+    }
+
     #[test]
     fn upload_result_status_bootstrapping() {
         let netdir = construct_netdir();
@@ -2268,7 +4176,7 @@ mod test {
                 .unwrap();
             let secondary_ctx = create_time_period_ctx(secondary_params, secondary_result.clone());
 
-            let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx]);
+            let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx], 100);
             assert_eq!(status, State::Bootstrapping);
             assert!(err.is_none());
         }
@@ -2293,7 +4201,7 @@ mod test {
 
         let primary_result = create_upload_results(Ok(()));
         let primary_ctx = create_time_period_ctx(primary_params, primary_result);
-        let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx]);
+        let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx], 100);
         assert_eq!(status, State::Running);
         assert!(err.is_none());
     }
@@ -2320,13 +4228,51 @@ mod test {
             .find(|param| param.time_period() != current_period)
             .unwrap();
         let secondary_ctx = create_time_period_ctx(secondary_params, secondary_result);
-        let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx]);
+        let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx], 100);
 
         // Degraded but reachable (because some of the secondary HsDir uploads failed).
         assert_eq!(status, State::DegradedReachable);
         assert!(matches!(err, Some(Problem::DescriptorUpload(_))));
     }
 
+    #[test]
+    fn upload_result_status_running_threshold() {
+        let netdir = construct_netdir();
+        let all_params = netdir.hs_all_time_periods();
+        let current_period = netdir.hs_time_period();
+        let primary_params = all_params
+            .iter()
+            .find(|param| param.time_period() == current_period)
+            .unwrap();
+        let secondary_params = all_params
+            .iter()
+            .find(|param| param.time_period() != current_period)
+            .unwrap();
+
+        let failed_res = create_upload_results(Err(DescUploadRetryError::Bug(internal!("test"))));
+        // Half of the secondary HsDir uploads failed.
+        let secondary_result: Vec<_> = create_upload_results(Ok(()))
+            .into_iter()
+            .chain(failed_res.iter().cloned())
+            .collect();
+
+        for (percent, expected_status) in [
+            (1, State::Running),
+            (50, State::Running),
+            (51, State::DegradedReachable),
+            (100, State::DegradedReachable),
+        ] {
+            let primary_ctx = create_time_period_ctx(primary_params, create_upload_results(Ok(())));
+            let secondary_ctx = create_time_period_ctx(secondary_params, secondary_result.clone());
+            let (status, err) =
+                upload_result_state(&netdir, &[primary_ctx, secondary_ctx], percent);
+
+            assert_eq!(status, expected_status, "percent={percent}");
+            // The failures are reported regardless of the threshold.
+            assert!(matches!(err, Some(Problem::DescriptorUpload(_))));
+        }
+    }
+
     #[test]
     fn upload_result_status_unreachable() {
         let netdir = construct_netdir();
@@ -2340,14 +4286,14 @@ mod test {
             create_upload_results(Err(DescUploadRetryError::Bug(internal!("test"))));
         let primary_ctx = create_time_period_ctx(primary_params, primary_result.clone());
         // No secondary TP (we are unreachable).
-        let (status, err) = upload_result_state(&netdir, &[primary_ctx]);
+        let (status, err) = upload_result_state(&netdir, &[primary_ctx], 100);
         assert_eq!(status, State::DegradedUnreachable);
         assert!(matches!(err, Some(Problem::DescriptorUpload(_))));
 
         // Add a successful result
         primary_result.push(create_upload_status(Ok(())));
         let primary_ctx = create_time_period_ctx(primary_params, primary_result.clone());
-        let (status, err) = upload_result_state(&netdir, &[primary_ctx]);
+        let (status, err) = upload_result_state(&netdir, &[primary_ctx], 100);
         // Still degraded, and unreachable (because we don't have a TimePeriodContext
         // for the secondary TP)
         assert_eq!(status, State::DegradedUnreachable);
@@ -2363,8 +4309,229 @@ mod test {
             .unwrap();
         let secondary_ctx = create_time_period_ctx(secondary_params, secondary_result.clone());
         let primary_ctx = create_time_period_ctx(primary_params, primary_result.clone());
-        let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx]);
+        let (status, err) = upload_result_state(&netdir, &[primary_ctx, secondary_ctx], 100);
         assert_eq!(status, State::DegradedUnreachable);
         assert!(matches!(err, Some(Problem::DescriptorUpload(_))));
     }
+
+    #[test]
+    fn hsdir_timeout_scaling() {
+        let w = |n: u64| RelayWeight::from(n);
+
+        // A typical relay keeps the estimated timeout.
+        assert_eq!(hsdir_timeout_factor(w(100), w(100)), 1.0);
+        // Slower relays get more time; faster ones get less.
+        assert_eq!(hsdir_timeout_factor(w(80), w(100)), 1.25);
+        assert_eq!(hsdir_timeout_factor(w(125), w(100)), 0.8);
+        // ...but only within limits.
+        assert_eq!(hsdir_timeout_factor(w(1), w(100)), MAX_HSDIR_TIMEOUT_FACTOR);
+        assert_eq!(hsdir_timeout_factor(w(0), w(100)), MAX_HSDIR_TIMEOUT_FACTOR);
+        assert_eq!(
+            hsdir_timeout_factor(w(10_000), w(100)),
+            MIN_HSDIR_TIMEOUT_FACTOR
+        );
+        // If we know nothing about the typical relay, we don't scale anything.
+        assert_eq!(hsdir_timeout_factor(w(100), w(0)), 1.0);
+        assert_eq!(hsdir_timeout_factor(w(0), w(0)), 1.0);
+
+        let netdir = construct_netdir();
+        let hs_dirs = netdir
+            .relays()
+            .take(5)
+            .map(|r| RelayIds::from_relay_ids(&r))
+            .collect_vec();
+        let mut weights = netdir
+            .relays()
+            .take(5)
+            .map(|r| netdir.relay_weight(&r, WeightRole::Unweighted))
+            .collect_vec();
+        weights.sort();
+        assert_eq!(median_hsdir_weight(&netdir, &hs_dirs), weights[2]);
+        assert_eq!(median_hsdir_weight(&netdir, &[]), w(0));
+    }
+
+    #[test]
+    fn dirty_since() {
+        let netdir = construct_netdir();
+        let params = netdir.hs_all_time_periods()[0].clone();
+        let mut ctx = create_time_period_ctx(&params, vec![]);
+        ctx.hs_dirs = netdir
+            .relays()
+            .take(2)
+            .map(|r| (RelayIds::from_relay_ids(&r), DescriptorStatus::Clean))
+            .collect();
+
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_secs(30);
+        ctx.mark_all_dirty(t0);
+        // The descriptor was already dirty, so marking it again doesn't reset the clock.
+        ctx.mark_all_dirty(t1);
+        assert_eq!(ctx.dirty_since, Some(t0));
+        assert!(
+            ctx.hs_dirs
+                .iter()
+                .all(|(_, status)| *status == DescriptorStatus::Dirty)
+        );
+    }
+
+    #[test]
+    fn keystore_retry_backoff() {
+        let secs = |n| keystore_retry_delay(n).as_secs();
+
+        assert_eq!(secs(1), 5);
+        assert_eq!(secs(2), 10);
+        assert_eq!(secs(3), 20);
+        assert_eq!(secs(6), 160);
+        assert_eq!(secs(7), KEYSTORE_RETRY_MAX_DELAY.as_secs());
+        assert_eq!(secs(u32::MAX), KEYSTORE_RETRY_MAX_DELAY.as_secs());
+    }
+
+    #[test]
+    fn transient_keystore_errors() {
+        let race = FatalError::KeystoreRace {
+            action: "read",
+            path: tor_keymgr::ArtiPath::new("hss/foo/ks_hs_blind_id".into()).unwrap(),
+        };
+        assert!(race.is_transient_keystore_error());
+
+        let corrupted: FatalError =
+            tor_keymgr::Error::Corruption(tor_keymgr::KeystoreCorruptionError::MissingCertificate)
+                .into();
+        assert!(!corrupted.is_transient_keystore_error());
+
+        let missing = FatalError::MissingHsIdKeypair("foo".parse().unwrap());
+        assert!(!missing.is_transient_keystore_error());
+    }
+
+    #[test]
+    fn watch_dirs_missing() {
+        use crate::config::restricted_discovery::DirectoryKeyProviderBuilder;
+        use tor_rtmock::MockRuntime;
+
+        MockRuntime::test_with_various(|rt| async move {
+            let tmp = tempfile::TempDir::new().unwrap();
+            let existing = tmp.path().join("existing");
+            std::fs::create_dir(&existing).unwrap();
+            let not_yet = tmp.path().join("not_yet");
+            let orphan = tmp.path().join("no_parent").join("keys");
+
+            let dirs = [&existing, &not_yet, &orphan]
+                .into_iter()
+                .map(|path| {
+                    let mut builder = DirectoryKeyProviderBuilder::default();
+                    builder
+                        .path(CfgPath::new_literal(path))
+                        .permissions()
+                        .dangerously_trust_everyone();
+                    builder.build().unwrap()
+                })
+                .collect::<DirectoryKeyProviderList>();
+
+            let path_resolver = CfgPathResolver::default();
+            let mut watcher = FileWatcher::builder(rt.clone());
+            let missing = watch_dirs(&mut watcher, &dirs, &path_resolver);
+            assert_eq!(missing, vec![not_yet.clone(), orphan]);
+
+            // Once a directory exists, it is no longer reported as missing.
+            std::fs::create_dir(&not_yet).unwrap();
+            let mut watcher = FileWatcher::builder(rt.clone());
+            let missing = watch_dirs(&mut watcher, &dirs, &path_resolver);
+            assert_eq!(missing.len(), 1);
+        });
+    }
+
+    #[test]
+    fn clock_skew() {
+        let valid_after = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let hour = Duration::from_secs(60 * 60);
+        let lifetime =
+            Lifetime::new(valid_after, valid_after + hour, valid_after + 3 * hour).unwrap();
+
+        // A clock within the validity period (or not far outside it) is fine.
+        for now in [
+            valid_after,
+            valid_after + 2 * hour,
+            valid_after - hour,
+            valid_after + 4 * hour,
+        ] {
+            assert_eq!(significant_clock_skew(&lifetime, now), ClockSkew::None);
+            assert_eq!(descriptor_time(&lifetime, now, true), now);
+        }
+
+        // A clock further outside it is skewed.
+        let slow = valid_after - 2 * hour;
+        assert_eq!(
+            significant_clock_skew(&lifetime, slow),
+            ClockSkew::Slow(2 * hour)
+        );
+        assert_eq!(descriptor_time(&lifetime, slow, false), slow);
+        assert_eq!(descriptor_time(&lifetime, slow, true), valid_after);
+
+        let fast = valid_after + 5 * hour;
+        assert_eq!(
+            significant_clock_skew(&lifetime, fast),
+            ClockSkew::Fast(2 * hour)
+        );
+        assert_eq!(descriptor_time(&lifetime, fast, false), fast);
+        assert_eq!(
+            descriptor_time(&lifetime, fast, true),
+            valid_after + 3 * hour
+        );
+    }
+
+    #[test]
+    fn blind_id_keystore_selection() {
+        use tor_basic_utils::test_rng::testing_rng;
+        use tor_keymgr::{ArtiEphemeralKeystore, KeyMgrBuilder};
+
+        let nickname = HsNickname::new("shallot".to_string()).unwrap();
+        let primary: KeystoreId = "primary".parse().unwrap();
+        let hsm: KeystoreId = "hsm".parse().unwrap();
+        let keymgr = KeyMgrBuilder::default()
+            .primary_store(Box::new(ArtiEphemeralKeystore::new(primary.to_string())))
+            .set_secondary_stores(vec![Box::new(ArtiEphemeralKeystore::new(hsm.to_string()))])
+            .build()
+            .unwrap();
+        let keymgr = Arc::new(keymgr);
+
+        let keypair = ed25519::Keypair::generate(&mut testing_rng());
+        let hsid_kp = HsIdKeypair::from(ed25519::ExpandedKeypair::from(&keypair));
+        keymgr
+            .insert(
+                hsid_kp,
+                &HsIdKeypairSpecifier::new(nickname.clone()),
+                KeystoreSelector::Primary,
+                false,
+            )
+            .unwrap();
+
+        let period = construct_netdir().hs_time_period();
+        let read = |period, id| {
+            let kp = read_blind_id_keypair(&keymgr, &nickname, period, keystore_selector(id))
+                .unwrap()
+                .unwrap();
+            HsBlindId::from(HsBlindIdKey::from(&kp))
+        };
+        let in_store = |period, id| {
+            let spec = BlindIdKeypairSpecifier::new(nickname.clone(), period);
+            keymgr
+                .get_from::<HsBlindIdKeypair>(&spec, id)
+                .unwrap()
+                .is_some()
+        };
+
+        // A new key is stored in the keystore we chose...
+        let key = read(period, Some(&hsm));
+        assert!(in_store(period, &hsm));
+        assert!(!in_store(period, &primary));
+        // ...and once it exists, it is used from there.
+        assert_eq!(read(period, None), key);
+        assert!(!in_store(period, &primary));
+
+        // If we didn't choose a keystore, we use the primary one.
+        let next = period.next().unwrap();
+        let _ = read(next, None);
+        assert!(in_store(next, &primary));
+        assert!(!in_store(next, &hsm));
+    }
 }
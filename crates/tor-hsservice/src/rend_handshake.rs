@@ -5,7 +5,7 @@ use super::*;
 // These imports just here, because they have names unsuitable for importing widely.
 use tor_cell::relaycell::{
     hs::intro_payload::{IntroduceHandshakePayload, OnionKey},
-    msg::{Introduce2, Rendezvous1},
+    msg::{End, EndReason, Introduce2, Rendezvous1},
 };
 use tor_circmgr::{ServiceOnionServiceDataTunnel, build::onion_circparams_from_netparams};
 use tor_linkspec::{decode::Strictness, verbatim::VerbatimLinkSpecCircTarget};
@@ -14,7 +14,10 @@ use tor_proto::{
         self,
         hs_ntor::{self, HsNtorHkdfKeyGenerator},
     },
-    stream::{IncomingStream, IncomingStreamRequestFilter},
+    stream::{
+        IncomingStream, IncomingStreamQueueParams, IncomingStreamRequest,
+        IncomingStreamRequestFilter,
+    },
 };
 
 /// An error produced while trying to process an introduction request we have
@@ -191,14 +194,25 @@ pub(crate) struct RequestFilter {
     // value of the setting every time.  Instead, we currently only copy this
     // setting when an intro request is accepted.
     pub(crate) max_concurrent_streams: usize,
+    /// Whether we give RESOLVE requests to the application,
+    /// rather than rejecting them ourselves.
+    pub(crate) accept_resolve: bool,
 }
 impl IncomingStreamRequestFilter for RequestFilter {
     fn disposition(
         &mut self,
-        _ctx: &tor_proto::stream::IncomingStreamRequestContext<'_>,
+        ctx: &tor_proto::stream::IncomingStreamRequestContext<'_>,
         circ: &tor_proto::circuit::ClientCircSyncView<'_>,
     ) -> tor_proto::Result<tor_proto::stream::IncomingStreamRequestDisposition> {
-        if circ.n_open_streams() >= self.max_concurrent_streams {
+        if !self.accept_resolve && matches!(ctx.request(), IncomingStreamRequest::Resolve(_)) {
+            // We use DONE, as we would when rejecting any other stream,
+            // so that the reason doesn't give anything away.
+            Ok(
+                tor_proto::stream::IncomingStreamRequestDisposition::RejectRequest(
+                    End::new_with_reason(EndReason::DONE),
+                ),
+            )
+        } else if circ.n_open_streams() >= self.max_concurrent_streams {
             // TODO: We may want to have a way to send back an END message as
             // well and not tear down the circuit.
             Ok(tor_proto::stream::IncomingStreamRequestDisposition::CloseCircuit)
@@ -269,6 +283,7 @@ impl IntroRequest {
     pub(crate) async fn establish_session(
         self,
         filter: RequestFilter,
+        stream_queue: &IncomingStreamQueueParams,
         hs_pool: Arc<dyn RendCircConnector>,
         provider: Arc<dyn NetDirProvider>,
     ) -> Result<OpenSession, EstablishSessionError> {
@@ -370,9 +385,18 @@ impl IntroRequest {
             .last_hop()
             .map_err(into_internal!("Circuit with no virtual hop"))?;
 
-        // Accept begins from that virtual hop
+        // Accept begins and resolves from that virtual hop.
+        // (Our filter decides whether resolves reach the application.)
         let stream_requests = tunnel
-            .allow_stream_requests(&[tor_cell::relaycell::RelayCmd::BEGIN], virtual_hop, filter)
+            .allow_stream_requests_with_queue(
+                &[
+                    tor_cell::relaycell::RelayCmd::BEGIN,
+                    tor_cell::relaycell::RelayCmd::RESOLVE,
+                ],
+                virtual_hop,
+                filter,
+                stream_queue,
+            )
             .await
             .map_err(E::AcceptBegins)?
             .boxed();
@@ -5,10 +5,11 @@
 
 use crate::internal_prelude::*;
 
-use tor_cell::relaycell::msg::{Connected, End, Introduce2};
+use tor_cell::relaycell::msg::{Connected, End, Introduce2, Resolved};
 use tor_circmgr::ServiceOnionServiceDataTunnel;
 use tor_hscrypto::Subcredential;
 use tor_keymgr::ArtiPath;
+use tor_proto::circuit::UniqId;
 use tor_proto::stream::{IncomingStream, IncomingStreamRequest};
 
 /// Request to complete an introduction/rendezvous handshake.
@@ -60,6 +61,9 @@ pub struct RendRequest {
 /// Protocol details: More specifically, we create one of these whenever we get a well-formed
 /// `BEGIN` message.  Based on this, the caller decides whether to send a
 /// `CONNECTED` message.
+/// If the service is configured to accept them, we also create one for each
+/// `RESOLVE` message; the caller can answer those with
+/// [`answer_resolve`](StreamRequest::answer_resolve).
 #[derive(Debug)]
 pub struct StreamRequest {
     /// The object that will be used to send data to and from the client.
@@ -87,6 +91,9 @@ pub(crate) struct RendRequestContext {
     /// Configuration for a filter for this service.
     pub(crate) filter: rend_handshake::RequestFilter,
 
+    /// Bounds on the queue of stream requests on each circuit for this service.
+    pub(crate) stream_queue: tor_proto::stream::IncomingStreamQueueParams,
+
     /// Provider we'll use to find a directory so that we can build a rendezvous
     /// circuit.
     pub(crate) netdir_provider: Arc<dyn tor_netdir::NetDirProvider>,
@@ -224,6 +231,7 @@ impl RendRequest {
         } = intro_request
             .establish_session(
                 self.context.filter.clone(),
+                &self.context.stream_queue,
                 self.context.circ_pool.clone(),
                 self.context.netdir_provider.clone(),
             )
@@ -271,6 +279,16 @@ impl StreamRequest {
             .map_err(ClientError::AcceptStream)
     }
 
+    /// Answer this `RESOLVE` request by sending the client a `RESOLVED` message.
+    ///
+    /// Returns an error if this is not a `RESOLVE` request.
+    pub async fn answer_resolve(self, resolved_message: Resolved) -> Result<(), ClientError> {
+        self.stream
+            .answer_resolve(resolved_message)
+            .await
+            .map_err(ClientError::AnswerResolve)
+    }
+
     /// Reject this request, and send the client an `END` message.
     ///
     /// NOTE: If you need to be consistent with other onion service
@@ -291,5 +309,12 @@ impl StreamRequest {
         Ok(())
     }
 
+    /// Return a process-unique identifier for the rendezvous tunnel that made this request.
+    ///
+    /// Every request made on the same tunnel has the same identifier.
+    pub fn tunnel_unique_id(&self) -> UniqId {
+        self.on_tunnel.unique_id()
+    }
+
     // TODO various accessors, including for circuit.
 }
@@ -0,0 +1,270 @@
+//! Optional self-test: check that we are reachable, by connecting to ourselves.
+//!
+//! Successfully uploading our descriptors doesn't mean that clients can reach us:
+//! our introduction points might be unusable, for example,
+//! or our descriptors might be unparseable.
+//!
+//! If the onion service was built with a [`ReachabilityTester`],
+//! then whenever the rest of the service believes it is fully reachable,
+//! we ask the tester to connect to the service, as a client would.
+//! Until that connection succeeds, we don't report that the service is
+//! [`Running`](State::Running).
+
+use crate::internal_prelude::*;
+use crate::status::SelfTestStatusSender;
+
+/// How long to wait after a failed self-test before trying again.
+const RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// A way to check whether an onion service is reachable by clients.
+///
+/// This crate cannot act as an onion service client itself,
+/// so an implementation will typically use a separate client
+/// (such as an `arti_client::TorClient`) to connect to the service.
+/// The connection must be made over the client's own circuits,
+/// so that it exercises the same path as a real client would:
+/// fetching the descriptor, contacting an introduction point,
+/// and completing a rendezvous.
+#[async_trait]
+pub trait ReachabilityTester: Send + Sync {
+    /// Try to connect to the onion service `hsid`.
+    ///
+    /// Return `Ok(())` as soon as a rendezvous circuit to the service
+    /// has been built: there's no need to send any data to the service.
+    ///
+    /// Implementations should enforce their own timeout.
+    async fn test_reachability(&self, hsid: HsId) -> Result<(), ReachabilityTestError>;
+}
+
+/// An error returned by a [`ReachabilityTester`].
+#[derive(Clone, Debug, Error)]
+#[error("Unable to connect to our own onion service")]
+pub struct ReachabilityTestError(#[source] Arc<dyn StdError + Send + Sync + 'static>);
+
+impl ReachabilityTestError {
+    /// Wrap the error that made a reachability test fail.
+    pub fn new(cause: impl StdError + Send + Sync + 'static) -> Self {
+        Self(Arc::new(cause))
+    }
+}
+
+/// An unlaunched task that runs our [`ReachabilityTester`].
+pub(crate) struct SelfTester<R: Runtime> {
+    /// The runtime.
+    runtime: R,
+    /// The identity of the service we are testing.
+    hsid: HsId,
+    /// The tester.
+    tester: Arc<dyn ReachabilityTester>,
+    /// A sender for updating the status of the self-test.
+    status_tx: SelfTestStatusSender,
+    /// A channel for receiving the signal to shut down.
+    shutdown_rx: broadcast::Receiver<Void>,
+}
+
+impl<R: Runtime> SelfTester<R> {
+    /// Create a new `SelfTester`.
+    pub(crate) fn new(
+        runtime: R,
+        hsid: HsId,
+        tester: Arc<dyn ReachabilityTester>,
+        status_tx: SelfTestStatusSender,
+        shutdown_rx: broadcast::Receiver<Void>,
+    ) -> Self {
+        Self {
+            runtime,
+            hsid,
+            tester,
+            status_tx,
+            shutdown_rx,
+        }
+    }
+
+    /// Launch the self-test task.
+    pub(crate) fn launch(self) -> Result<(), StartupError> {
+        let runtime = self.runtime.clone();
+        self.status_tx.send_pending();
+
+        runtime
+            .spawn(async move {
+                self.run().await;
+                debug!("the self-test task has shut down");
+            })
+            .map_err(|e| StartupError::Spawn {
+                spawning: "self-test task",
+                cause: e.into(),
+            })
+    }
+
+    /// Run the self-test task, until we are told to shut down.
+    async fn run(mut self) {
+        let mut status = self.status_tx.subscribe();
+
+        loop {
+            // Wait until the rest of the service thinks we are reachable.
+            loop {
+                select_biased! {
+                    _ = self.shutdown_rx.next().fuse() => return,
+                    st = status.next().fuse() => match st {
+                        None => return,
+                        Some(st) if st.state_without_self_test().is_fully_reachable() => break,
+                        Some(_) => {}
+                    },
+                }
+            }
+
+            debug!("testing whether our onion service is reachable");
+            let outcome = select_biased! {
+                _ = self.shutdown_rx.next().fuse() => return,
+                outcome = self.tester.test_reachability(self.hsid).fuse() => outcome,
+            };
+
+            match outcome {
+                Ok(()) => {
+                    info!("Onion service reachability self-test succeeded");
+                    self.status_tx.send_passed();
+
+                    // Keep reporting success until something else goes wrong;
+                    // then, test again once the service has recovered.
+                    loop {
+                        select_biased! {
+                            _ = self.shutdown_rx.next().fuse() => return,
+                            st = status.next().fuse() => match st {
+                                None => return,
+                                Some(st) if !st.state_without_self_test().is_fully_reachable() => break,
+                                Some(_) => {}
+                            },
+                        }
+                    }
+                    self.status_tx.send_pending();
+                }
+                Err(e) => {
+                    warn_report!(e, "Onion service reachability self-test failed");
+                    self.status_tx.send_failed(e);
+
+                    select_biased! {
+                        _ = self.shutdown_rx.next().fuse() => return,
+                        () = self.runtime.sleep(RETRY_DELAY).fuse() => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::status::Problem;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_rtmock::MockRuntime;
+
+    /// The outcome of a reachability test, as decided by the test.
+    type Outcome = oneshot::Sender<Result<(), ReachabilityTestError>>;
+
+    /// A [`ReachabilityTester`] whose outcomes are decided by the test.
+    struct FakeTester {
+        /// A sender on which we send each attempt, for the test to complete.
+        attempts: mpsc::UnboundedSender<Outcome>,
+    }
+
+    #[async_trait]
+    impl ReachabilityTester for FakeTester {
+        async fn test_reachability(&self, _hsid: HsId) -> Result<(), ReachabilityTestError> {
+            let (tx, rx) = oneshot::channel();
+            self.attempts.unbounded_send(tx).unwrap();
+            rx.await.unwrap()
+        }
+    }
+
+    #[test]
+    fn running_after_self_test() {
+        MockRuntime::test_with_various(|rt| async move {
+            let status_tx = StatusSender::new(
+                OnionServiceStatus::new_shutdown(),
+                DynTimeProvider::new(rt.clone()),
+            );
+            let ipt_mgr = IptMgrStatusSender::from(status_tx.clone());
+            let publisher = PublisherStatusSender::from(status_tx.clone());
+            let (attempts_tx, mut attempts) = mpsc::unbounded();
+            let (_shutdown_tx, shutdown_rx) = broadcast::channel(0);
+
+            let keypair = ed25519::Keypair::generate(&mut testing_rng());
+            let hsid = HsId::from(HsIdKey::from(keypair.verifying_key()));
+            let tester = Arc::new(FakeTester {
+                attempts: attempts_tx,
+            });
+            SelfTester::new(
+                rt.clone(),
+                hsid,
+                tester,
+                status_tx.clone().into(),
+                shutdown_rx,
+            )
+            .launch()
+            .unwrap();
+
+            // We don't test ourselves while our descriptors aren't published.
+            ipt_mgr.send(State::Running, None);
+            publisher.send(State::Bootstrapping, None);
+            rt.advance_until_stalled().await;
+            assert!(attempts.try_next().is_err());
+            assert_eq!(status_tx.get().state(), State::Bootstrapping);
+
+            // Once they are, we still aren't Running until the self-test passes.
+            publisher.send(State::Running, None);
+            rt.advance_until_stalled().await;
+            let attempt = attempts.try_next().unwrap().unwrap();
+            assert_eq!(status_tx.get().state(), State::Bootstrapping);
+
+            // If it fails, we're unreachable...
+            let err = ReachabilityTestError::new(io::Error::other("no route"));
+            attempt.send(Err(err)).unwrap();
+            rt.advance_until_stalled().await;
+            let status = status_tx.get();
+            assert_eq!(status.state(), State::DegradedUnreachable);
+            assert!(matches!(
+                status.current_problem(),
+                Some(Problem::SelfTest(_))
+            ));
+
+            // ...until we try again, and succeed.
+            rt.advance_by(RETRY_DELAY - Duration::from_secs(1)).await;
+            assert!(attempts.try_next().is_err());
+            rt.advance_by(Duration::from_secs(1)).await;
+            let attempt = attempts.try_next().unwrap().unwrap();
+            assert_eq!(status_tx.get().state(), State::DegradedUnreachable);
+            attempt.send(Ok(())).unwrap();
+            rt.advance_until_stalled().await;
+            let status = status_tx.get();
+            assert_eq!(status.state(), State::Running);
+            assert!(status.current_problem().is_none());
+
+            // If the rest of the service stops being reachable,
+            // we test ourselves again once it recovers.
+            publisher.send(State::Bootstrapping, None);
+            rt.advance_until_stalled().await;
+            assert!(attempts.try_next().is_err());
+            publisher.send(State::Running, None);
+            rt.advance_until_stalled().await;
+            let attempt = attempts.try_next().unwrap().unwrap();
+            assert_eq!(status_tx.get().state(), State::Bootstrapping);
+            attempt.send(Ok(())).unwrap();
+            rt.advance_until_stalled().await;
+            assert_eq!(status_tx.get().state(), State::Running);
+        });
+    }
+}
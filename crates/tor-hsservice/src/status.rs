@@ -2,6 +2,10 @@
 
 use crate::internal_prelude::*;
 
+#[cfg(feature = "restricted-discovery")]
+use crate::config::restricted_discovery::RejectedClientKey;
+use tor_proto::ClockSkew;
+
 /// The current reported status of an onion service.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct OnionServiceStatus {
@@ -10,6 +14,15 @@ pub struct OnionServiceStatus {
 
     /// The current high-level state for the descriptor publisher.
     publisher: ComponentStatus,
+
+    /// The current high-level state for the reachability self-test.
+    ///
+    /// This is `None` if the service has no
+    /// [`ReachabilityTester`](crate::ReachabilityTester).
+    /// Otherwise, its state is `Bootstrapping` until a test has been run,
+    /// `Running` if the latest test succeeded,
+    /// and `DegradedUnreachable` if it failed.
+    self_test: Option<ComponentStatus>,
     // TODO (#1194): Add key expiration
     //
     // NOTE: Do _not_ add general metrics (like failure/success rates , number
@@ -179,6 +192,21 @@ pub enum DescUploadRetryError {
     Bug(#[from] Bug),
 }
 
+impl DescUploadRetryError {
+    /// Return the reason the HsDir gave for rejecting our descriptor on our last attempt,
+    /// if that attempt failed because the HsDir rejected it.
+    pub fn hsdir_rejection(&self) -> Option<&HsDirRejection> {
+        let errors = match self {
+            Self::FatalError(e) | Self::MaxRetryCountExceeded(e) | Self::Timeout(e) => e,
+            Self::Bug(_) => return None,
+        };
+        match errors.sources().last()? {
+            DescUploadError::Rejected { rejection, .. } => Some(rejection),
+            _ => None,
+        }
+    }
+}
+
 /// A problem encountered by an onion service.
 #[derive(Clone, Debug, derive_more::From)]
 #[non_exhaustive]
@@ -191,6 +219,67 @@ pub enum Problem {
 
     /// We failed to establish one or more introduction points.
     Ipt(Vec<IptError>),
+
+    /// We were unable to connect to ourselves.
+    SelfTest(ReachabilityTestError),
+
+    /// We failed to access the keystore, but the failure might be temporary.
+    ///
+    /// We will keep trying to access it.
+    /// If we keep failing, the service will be reported as broken.
+    #[from(skip)]
+    KeystoreUnavailable(FatalError),
+
+    /// Restricted discovery is enabled, but no authorized clients are configured.
+    ///
+    /// We won't try to publish our descriptor again
+    /// until the configuration or the set of authorized clients changes.
+    #[cfg(feature = "restricted-discovery")]
+    #[from(skip)]
+    RestrictedDiscoveryNoClients,
+
+    /// Restricted discovery is enabled, and some of the configured client keys were rejected.
+    ///
+    /// The clients those entries were meant for can't discover the service.
+    /// This is reported alongside an otherwise healthy status,
+    /// until the offending entries are fixed or removed.
+    #[cfg(feature = "restricted-discovery")]
+    #[from(skip)]
+    RestrictedDiscoveryRejectedKeys(Vec<RejectedClientKey>),
+
+    /// Our clock seems to be wrong:
+    /// it is well outside the validity period of the latest consensus.
+    ///
+    /// HsDirs and clients may reject the descriptors we sign with it,
+    /// unless `use_consensus_time_if_skewed` is enabled.
+    /// This is reported alongside an otherwise healthy status,
+    /// for as long as the skew lasts.
+    #[from(skip)]
+    ClockSkew(ClockSkew),
+
+    /// We failed to remove this many expired keys from the keystore.
+    ///
+    /// We will keep trying to remove them.
+    /// This is reported alongside an otherwise healthy status,
+    /// until we succeed.
+    #[from(skip)]
+    ExpiredKeysNotRemoved(usize),
+
+    /// This many of the HsDirs responsible for our descriptor
+    /// don't support the protocols we need, so we didn't upload our descriptor to them.
+    ///
+    /// These HsDirs don't count towards the uploads we need to consider ourselves
+    /// [`Running`](State::Running).
+    /// This is reported alongside an otherwise healthy status,
+    /// for as long as those HsDirs are on our rings.
+    #[from(skip)]
+    UnsupportedHsDirs(usize),
+
+    /// More than one of the warnings above applies.
+    ///
+    /// Contains each of them, in the order they are listed above.
+    #[from(skip)]
+    Warnings(Vec<Problem>),
     // TODO: add variants for other transient errors?
 }
 
@@ -200,6 +289,7 @@ impl OnionServiceStatus {
         Self {
             ipt_mgr: ComponentStatus::new_shutdown(),
             publisher: ComponentStatus::new_shutdown(),
+            self_test: None,
         }
     }
 
@@ -207,9 +297,29 @@ impl OnionServiceStatus {
     ///
     /// The overall state is derived from the `State`s of its underlying components
     /// (i.e. the IPT manager and descriptor publisher).
+    ///
+    /// If the service has a [`ReachabilityTester`](crate::ReachabilityTester),
+    /// a service that would otherwise be fully reachable is reported as
+    /// `Bootstrapping` until the tester has managed to connect to it,
+    /// and as `DegradedUnreachable` if the tester's latest attempt failed.
     pub fn state(&self) -> State {
         use State::*;
 
+        let state = self.state_without_self_test();
+        match &self.self_test {
+            Some(self_test) if state.is_fully_reachable() => match self_test.state {
+                Running => state,
+                Shutdown | Bootstrapping => Bootstrapping,
+                _ => DegradedUnreachable,
+            },
+            _ => state,
+        }
+    }
+
+    /// Return the state of this onion service, ignoring the reachability self-test.
+    pub(crate) fn state_without_self_test(&self) -> State {
+        use State::*;
+
         match (self.ipt_mgr.state, self.publisher.state) {
             (Shutdown, _) | (_, Shutdown) => Shutdown,
             (Bootstrapping, _) | (_, Bootstrapping) => Bootstrapping,
@@ -226,7 +336,10 @@ impl OnionServiceStatus {
     /// Return the most severe current problem
     pub fn current_problem(&self) -> Option<&Problem> {
         match (&self.ipt_mgr.latest_error, &self.publisher.latest_error) {
-            (None, None) => None,
+            (None, None) => self
+                .self_test
+                .as_ref()
+                .and_then(|self_test| self_test.latest_error.as_ref()),
             (Some(e), Some(_)) => {
                 // For now, assume IPT manager errors are always more severe
                 // TODO: decide which error is the more severe (or return both)
@@ -236,6 +349,28 @@ impl OnionServiceStatus {
         }
     }
 
+    /// Return the state of `component`.
+    ///
+    /// A self-test that has not been set up is reported as `Shutdown`.
+    fn component_state(&self, component: StatusComponent) -> State {
+        self.component(component)
+            .map_or(State::Shutdown, |status| status.state)
+    }
+
+    /// Return the latest problem reported by `component`, if any.
+    fn component_problem(&self, component: StatusComponent) -> Option<&Problem> {
+        self.component(component)?.latest_error.as_ref()
+    }
+
+    /// Return the status of `component`, if it has one.
+    fn component(&self, component: StatusComponent) -> Option<&ComponentStatus> {
+        match component {
+            StatusComponent::IptManager => Some(&self.ipt_mgr),
+            StatusComponent::Publisher => Some(&self.publisher),
+            StatusComponent::SelfTest => self.self_test.as_ref(),
+        }
+    }
+
     /// Return a time before which the user must re-provision this onion service
     /// with new keys.
     ///
@@ -269,6 +404,65 @@ impl futures::Stream for OnionServiceStatusStream {
     }
 }
 
+/// The maximum number of entries kept in the status history of an onion service.
+const STATUS_HISTORY_LEN: usize = 64;
+
+/// A component of an onion service that reports its own [`State`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum StatusComponent {
+    /// The introduction point manager.
+    IptManager,
+    /// The descriptor publisher.
+    Publisher,
+    /// The reachability self-test.
+    SelfTest,
+}
+
+/// An event that prompted a component of an onion service to change its state.
+///
+/// This is the most recent such event the component had seen
+/// at the time of the change;
+/// it is not necessarily the only reason for the change.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum StatusCause {
+    /// We received a new consensus.
+    ConsensusChange,
+    /// The set of introduction points changed.
+    IptChange,
+    /// The configuration, or the set of authorized clients, changed.
+    ConfigChange,
+    /// The proof-of-work seed was rotated.
+    PowSeedRotation,
+}
+
+/// A recorded change in the [`State`] of one component of an onion service.
+///
+/// Returned by [`RunningOnionService::status_history`](crate::RunningOnionService::status_history).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct StatusTransition {
+    /// When the change happened.
+    pub when: SystemTime,
+    /// The component whose state changed.
+    pub component: StatusComponent,
+    /// The state of the component before the change.
+    ///
+    /// This is `Shutdown` if the component had not reported a state before.
+    pub old_state: State,
+    /// The state of the component after the change.
+    pub new_state: State,
+    /// The overall state of the service after the change.
+    ///
+    /// See [`OnionServiceStatus::state`].
+    pub service_state: State,
+    /// The event that prompted the change, if known.
+    pub cause: Option<StatusCause>,
+    /// The problem reported by the component along with the change, if any.
+    pub problem: Option<Problem>,
+}
+
 /// A shared handle to a postage::watch::Sender that we can use to update an OnionServiceStatus.
 //
 // TODO: Possibly, we don't need this to be Clone: as we implement the code
@@ -276,7 +470,55 @@ impl futures::Stream for OnionServiceStatusStream {
 // hold the Sender.  If that turns out to be the case, we should remove the
 // `Arc<Mutex<.>>` here.  If not, we should remove this comment.
 #[derive(Clone)]
-pub(crate) struct StatusSender(Arc<Mutex<postage::watch::Sender<OnionServiceStatus>>>);
+pub(crate) struct StatusSender(Arc<Mutex<StatusSenderInner>>);
+
+/// The mutable state of a [`StatusSender`].
+struct StatusSenderInner {
+    /// The sender for the current status.
+    tx: postage::watch::Sender<OnionServiceStatus>,
+    /// The most recent state transitions, oldest first.
+    ///
+    /// Holds at most [`STATUS_HISTORY_LEN`] entries.
+    history: VecDeque<StatusTransition>,
+    /// The event that most recently prompted the publisher to act,
+    /// if the publisher's state hasn't changed since.
+    publisher_cause: Option<StatusCause>,
+    /// The time provider used to timestamp `history`.
+    time_provider: DynTimeProvider,
+}
+
+impl StatusSenderInner {
+    /// Replace the current status with `new`, in which only `component` has changed.
+    ///
+    /// If the state of `component` is different, records the transition in our history.
+    /// If the status is different, notifies all listeners.
+    fn update(&mut self, component: StatusComponent, new: OnionServiceStatus) {
+        let old_state = self.tx.borrow().component_state(component);
+        let new_state = new.component_state(component);
+
+        if old_state != new_state {
+            let cause = match component {
+                // The cause only explains the transition it prompted.
+                StatusComponent::Publisher => self.publisher_cause.take(),
+                StatusComponent::IptManager | StatusComponent::SelfTest => None,
+            };
+            if self.history.len() >= STATUS_HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(StatusTransition {
+                when: self.time_provider.wallclock(),
+                component,
+                old_state,
+                new_state,
+                service_state: new.state(),
+                cause,
+                problem: new.component_problem(component).cloned(),
+            });
+        }
+
+        self.tx.maybe_send(|_| new);
+    }
+}
 
 /// A handle that can be used by the [`IptManager`]
 /// to update the [`OnionServiceStatus`].
@@ -293,7 +535,7 @@ pub(crate) struct PublisherStatusSender(StatusSender);
 /// TODO: this macro is a bit repetitive, it would be nice if we could reduce duplication even
 /// further (and auto-generate a `note_<state>` function for every `State` variant).
 macro_rules! impl_status_sender {
-    ($sender:ident, $field:ident) => {
+    ($sender:ident, $field:ident, $component:ident) => {
         impl $sender {
             /// Update `latest_error` and set the underlying state to `Broken`.
             ///
@@ -326,34 +568,99 @@ macro_rules! impl_status_sender {
             /// and notifies all listeners.
             pub(crate) fn send(&self, state: State, err: Option<Problem>) {
                 let sender = &self.0;
-                let mut tx = sender.0.lock().expect("Poisoned lock");
-                let mut svc_status = tx.borrow().clone();
+                let mut inner = sender.0.lock().expect("Poisoned lock");
+                let mut svc_status = inner.tx.borrow().clone();
                 svc_status.$field.state = state;
                 svc_status.$field.latest_error = err;
-                tx.maybe_send(|_| svc_status);
+                inner.update(StatusComponent::$component, svc_status);
             }
         }
     };
 }
 
-impl_status_sender!(IptMgrStatusSender, ipt_mgr);
-impl_status_sender!(PublisherStatusSender, publisher);
+impl_status_sender!(IptMgrStatusSender, ipt_mgr, IptManager);
+impl_status_sender!(PublisherStatusSender, publisher, Publisher);
+
+impl PublisherStatusSender {
+    /// Record that the publisher is acting on `cause`.
+    ///
+    /// The next change to the state of the publisher
+    /// is attributed to `cause` in the status history.
+    /// Later changes aren't, unless this is called again.
+    pub(crate) fn note_cause(&self, cause: StatusCause) {
+        self.0.0.lock().expect("Poisoned lock").publisher_cause = Some(cause);
+    }
+}
+
+/// A handle that can be used by the [`SelfTester`](crate::self_test::SelfTester)
+/// to update the [`OnionServiceStatus`].
+#[derive(Clone, derive_more::From)]
+pub(crate) struct SelfTestStatusSender(StatusSender);
+
+impl SelfTestStatusSender {
+    /// Record that the self-test has not yet succeeded, and has not failed either.
+    pub(crate) fn send_pending(&self) {
+        self.send(State::Bootstrapping, None);
+    }
+
+    /// Record that the latest self-test succeeded.
+    pub(crate) fn send_passed(&self) {
+        self.send(State::Running, None);
+    }
+
+    /// Record that the latest self-test failed with `err`.
+    pub(crate) fn send_failed(&self, err: ReachabilityTestError) {
+        self.send(State::DegradedUnreachable, Some(err.into()));
+    }
+
+    /// Update the state and latest_error of the self-test.
+    ///
+    /// If the new state is different, this updates the current status
+    /// and notifies all listeners.
+    fn send(&self, state: State, err: Option<Problem>) {
+        let mut inner = self.0.0.lock().expect("Poisoned lock");
+        let mut svc_status = inner.tx.borrow().clone();
+        svc_status.self_test = Some(ComponentStatus {
+            state,
+            latest_error: err,
+        });
+        inner.update(StatusComponent::SelfTest, svc_status);
+    }
+
+    /// Return a new OnionServiceStatusStream to return events from this StatusSender.
+    pub(crate) fn subscribe(&self) -> OnionServiceStatusStream {
+        self.0.subscribe()
+    }
+}
 
 impl StatusSender {
     /// Create a new StatusSender with a given initial status.
-    pub(crate) fn new(initial_status: OnionServiceStatus) -> Self {
+    ///
+    /// `time_provider` is used to timestamp the entries in the status history.
+    pub(crate) fn new(initial_status: OnionServiceStatus, time_provider: DynTimeProvider) -> Self {
         let (tx, _) = postage::watch::channel_with(initial_status);
-        StatusSender(Arc::new(Mutex::new(tx)))
+        StatusSender(Arc::new(Mutex::new(StatusSenderInner {
+            tx,
+            history: VecDeque::with_capacity(STATUS_HISTORY_LEN),
+            publisher_cause: None,
+            time_provider,
+        })))
     }
 
     /// Return a copy of the current status.
     pub(crate) fn get(&self) -> OnionServiceStatus {
-        self.0.lock().expect("Poisoned lock").borrow().clone()
+        self.0.lock().expect("Poisoned lock").tx.borrow().clone()
     }
 
     /// Return a new OnionServiceStatusStream to return events from this StatusSender.
     pub(crate) fn subscribe(&self) -> OnionServiceStatusStream {
-        OnionServiceStatusStream(self.0.lock().expect("Poisoned lock").subscribe())
+        OnionServiceStatusStream(self.0.lock().expect("Poisoned lock").tx.subscribe())
+    }
+
+    /// Return the most recent state transitions, oldest first.
+    pub(crate) fn history(&self) -> Vec<StatusTransition> {
+        let inner = self.0.lock().expect("Poisoned lock");
+        inner.history.iter().cloned().collect()
     }
 }
 
@@ -363,6 +670,11 @@ impl PublisherStatusSender {
     pub(crate) fn subscribe(&self) -> OnionServiceStatusStream {
         self.0.subscribe()
     }
+
+    /// Return a copy of the current status.
+    pub(crate) fn get(&self) -> OnionServiceStatus {
+        self.0.get()
+    }
 }
 
 #[cfg(all(test, not(feature = "hs-pow-full")))]
@@ -385,3 +697,122 @@ impl ComponentStatus {
         self.latest_error.as_ref()
     }
 }
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_rtmock::simple_time::SimpleMockTimeProvider;
+
+    #[test]
+    fn self_test_state() {
+        let status_tx = StatusSender::new(
+            OnionServiceStatus::new_shutdown(),
+            DynTimeProvider::new(SimpleMockTimeProvider::default()),
+        );
+        let ipt_mgr = IptMgrStatusSender::from(status_tx.clone());
+        let publisher = PublisherStatusSender::from(status_tx.clone());
+        let self_test = SelfTestStatusSender::from(status_tx.clone());
+
+        ipt_mgr.send(State::Running, None);
+        publisher.send(State::Running, None);
+        assert_eq!(status_tx.get().state(), State::Running);
+
+        // Once we have a self-test, we need it to pass before we're Running.
+        self_test.send_pending();
+        assert_eq!(status_tx.get().state(), State::Bootstrapping);
+        self_test.send_passed();
+        assert_eq!(status_tx.get().state(), State::Running);
+
+        let err = ReachabilityTestError::new(io::Error::other("no route"));
+        self_test.send_failed(err);
+        let status = status_tx.get();
+        assert_eq!(status.state(), State::DegradedUnreachable);
+        assert!(matches!(
+            status.current_problem(),
+            Some(Problem::SelfTest(_))
+        ));
+
+        // Problems elsewhere take precedence over the self-test.
+        publisher.send(State::Bootstrapping, None);
+        assert_eq!(status_tx.get().state(), State::Bootstrapping);
+        assert_eq!(
+            status_tx.get().state_without_self_test(),
+            State::Bootstrapping
+        );
+    }
+
+    #[test]
+    fn history() {
+        let time = SimpleMockTimeProvider::default();
+        let start = time.wallclock();
+        let status_tx = StatusSender::new(
+            OnionServiceStatus::new_shutdown(),
+            DynTimeProvider::new(time.clone()),
+        );
+        let ipt_mgr = IptMgrStatusSender::from(status_tx.clone());
+        let publisher = PublisherStatusSender::from(status_tx.clone());
+
+        ipt_mgr.send(State::Running, None);
+        // Sending the same state again isn't a transition.
+        ipt_mgr.send(State::Running, None);
+
+        time.advance(Duration::from_secs(60));
+        publisher.note_cause(StatusCause::ConsensusChange);
+        publisher.send(State::Bootstrapping, None);
+        publisher.send(State::Running, None);
+
+        let history = status_tx.history();
+        assert_eq!(history.len(), 3);
+
+        let ipt = &history[0];
+        assert_eq!(ipt.when, start);
+        assert_eq!(ipt.component, StatusComponent::IptManager);
+        assert_eq!(ipt.old_state, State::Shutdown);
+        assert_eq!(ipt.new_state, State::Running);
+        assert_eq!(ipt.service_state, State::Shutdown);
+        assert_eq!(ipt.cause, None);
+
+        let publ = &history[1];
+        assert_eq!(publ.when, start + Duration::from_secs(60));
+        assert_eq!(publ.component, StatusComponent::Publisher);
+        assert_eq!(publ.old_state, State::Shutdown);
+        assert_eq!(publ.new_state, State::Bootstrapping);
+        assert_eq!(publ.cause, Some(StatusCause::ConsensusChange));
+
+        // The cause only applies to the transition it prompted.
+        let publ = &history[2];
+        assert_eq!(publ.when, start + Duration::from_secs(60));
+        assert_eq!(publ.component, StatusComponent::Publisher);
+        assert_eq!(publ.old_state, State::Bootstrapping);
+        assert_eq!(publ.new_state, State::Running);
+        assert_eq!(publ.service_state, State::Running);
+        assert_eq!(publ.cause, None);
+
+        // The history is bounded; the oldest entries are dropped first.
+        for _ in 0..STATUS_HISTORY_LEN {
+            publisher.send_broken(Problem::DescriptorUpload(vec![]));
+            publisher.send(State::Running, None);
+        }
+        let history = status_tx.history();
+        assert_eq!(history.len(), STATUS_HISTORY_LEN);
+        assert_eq!(history[0].new_state, State::Broken);
+        assert!(matches!(
+            history[0].problem,
+            Some(Problem::DescriptorUpload(_))
+        ));
+        assert_eq!(history.last().unwrap().new_state, State::Running);
+    }
+}
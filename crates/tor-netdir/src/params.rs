@@ -474,6 +474,19 @@ pub struct NetParameters {
     pub sendme_emit_min_version: SendMeVersion = (0)
         from "sendme_emit_min_version",
 
+    /// The initial size, in cells, of our send window on each stream
+    /// that uses SENDME-based flow control.
+    ///
+    /// The default is the protocol default of 500 cells.
+    pub stream_send_window_init: BoundedInt32<1, 500> = (500)
+        from "stream_sendwindow_init",
+    /// The initial size, in cells, of our receive window on each stream
+    /// that uses SENDME-based flow control.
+    ///
+    /// The default is the protocol default of 500 cells.
+    pub stream_recv_window_init: BoundedInt32<1, 500> = (500)
+        from "stream_recvwindow_init",
+
     /// How long should never-used client circuits stay available,
     /// in the steady state?
     pub unused_client_circ_timeout: IntegerSeconds<BoundedInt32<60, 86_400>> = (30*60)
@@ -667,6 +680,21 @@ pub struct NetParameters {
     pub guard_hs_l3_lifetime_max: IntegerSeconds<BoundedInt32<1, {i32::MAX}>> = (172800)
         from  "guard-hs-l3-lifetime-max",
 
+    /// How strongly to prefer relays with a higher consensus weight
+    /// when selecting vanguards, expressed as a percentage.
+    ///
+    /// Each suitable relay is selected as a vanguard with probability proportional to
+    /// its consensus weight raised to the power of `guard-hs-bw-weight-exponent / 100`:
+    /// `0` selects uniformly at random among the suitable relays,
+    /// `100` selects in proportion to consensus weight,
+    /// and larger values favor high-bandwidth relays more strongly.
+    ///
+    /// (Regardless of this value, vanguards must have the Fast and Stable flags.)
+    //
+    // TODO(vanguards): add this to param spec
+    pub guard_hs_bw_weight_exponent: BoundedInt32<0, 400> = (100)
+        from "guard-hs-bw-weight-exponent",
+
     /// The KIST to use by default when building inter-relay channels:
     ///
     /// ```text
@@ -936,6 +964,8 @@ mod test {
             ("nf_ito_high_reduced", 40_000),
             ("sendme_accept_min_version", 31),
             ("sendme_emit_min_version", 32),
+            ("stream_sendwindow_init", 400),
+            ("stream_recvwindow_init", 450),
         ];
         let ignored = p.saturating_update(mp.iter().map(|(a, b)| (a, b)));
         assert!(ignored.is_empty());
@@ -982,6 +1012,8 @@ mod test {
         );
         assert_eq!(p.sendme_accept_min_version.get(), 31);
         assert_eq!(p.sendme_emit_min_version.get(), 32);
+        assert_eq!(p.stream_send_window_init.get(), 400);
+        assert_eq!(p.stream_recv_window_init.get(), 450);
 
         assert_eq!(
             Duration::try_from(p.guard_lifetime_unconfirmed).unwrap(),
@@ -62,6 +62,7 @@ pub mod kist;
 pub mod padding;
 pub mod params;
 mod reactor;
+pub mod traffic_class;
 mod unique_id;
 
 pub use crate::channel::params::*;
@@ -123,6 +124,7 @@ pub(crate) use codec::CodecError;
 pub use handshake::{OutboundClientHandshake, UnverifiedChannel, VerifiedChannel};
 
 use kist::KistParams;
+use traffic_class::TrafficClass;
 
 restricted_msg! {
     /// A channel message that we allow to be sent from a server to a client on
@@ -235,6 +237,11 @@ pub(crate) struct ChannelDetails {
     /// as otherwise the memquota system will tear the account down.
     #[allow(dead_code)]
     memquota: ChannelAccount,
+    /// The number of padding cells sent and received on this channel.
+    ///
+    /// Updated by the reactor.
+    /// Read from `Channel::padding_counts`.
+    padding_counters: padding::PaddingCounters,
 }
 
 /// Mutable details (state) used by the `Channel` (frontend)
@@ -475,6 +482,7 @@ impl Channel {
         let details = ChannelDetails {
             unused_since,
             memquota,
+            padding_counters: Default::default(),
         };
         let details = Arc::new(details);
 
@@ -636,6 +644,16 @@ impl Channel {
         Ok(self.send_control(CtrlMsg::KistConfigUpdate(kist_params))?)
     }
 
+    /// Tell this channel what class of traffic it is expected to carry.
+    ///
+    /// The channel marks the packets it sends accordingly,
+    /// if its underlying stream supports it.
+    ///
+    /// Returns `Err` if the channel is closed.
+    pub fn set_traffic_class(&self, class: TrafficClass) -> Result<()> {
+        Ok(self.send_control(CtrlMsg::SetTrafficClass(class))?)
+    }
+
     /// Return an error if this channel is somehow mismatched with the
     /// given target.
     pub fn check_match<T: HasRelayIds + ?Sized>(&self, target: &T) -> Result<()> {
@@ -658,6 +676,11 @@ impl Channel {
             .map(Into::into)
     }
 
+    /// Return the number of padding cells that this channel has sent and received so far.
+    pub fn padding_counts(&self) -> padding::PaddingCounts {
+        self.details.padding_counters.counts()
+    }
+
     /// Return a new [`ChannelSender`] to transmit cells on this channel.
     pub(crate) fn sender(&self) -> ChannelSender {
         ChannelSender {
@@ -829,14 +852,22 @@ impl HasRelayIds for Channel {
 }
 
 /// The status of a channel which was closed successfully.
-///
-/// **Note:** This doesn't have any associated data,
-/// but may be expanded in the future.
-// I can't think of any info we'd want to return to waiters,
-// but this type leaves the possibility open without requiring any backwards-incompatible changes.
 #[derive(Clone, Debug)]
 #[non_exhaustive]
-pub struct CloseInfo;
+pub struct CloseInfo {
+    /// True if the channel closed because the peer closed the connection.
+    pub(crate) closed_by_peer: bool,
+}
+
+impl CloseInfo {
+    /// Return true if the channel closed because the peer closed the connection.
+    ///
+    /// Otherwise, the channel was shut down on our side:
+    /// either with [`Channel::terminate`], or because nothing was using it any more.
+    pub fn closed_by_peer(&self) -> bool {
+        self.closed_by_peer
+    }
+}
 
 /// The status of a channel which closed unexpectedly.
 #[derive(Clone, Debug, thiserror::Error)]
@@ -858,6 +889,7 @@ fn fake_channel_details() -> Arc<ChannelDetails> {
     Arc::new(ChannelDetails {
         unused_since,
         memquota: crate::util::fake_mq(),
+        padding_counters: Default::default(),
     })
 }
 
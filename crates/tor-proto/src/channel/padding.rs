@@ -28,6 +28,7 @@
 //! so all our channels are client-to-guard or client-to-directory.
 
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 // TODO, coarsetime maybe?  But see arti#496 and also we want to use the mockable SleepProvider
 use std::time::{Duration, Instant};
 
@@ -407,6 +408,57 @@ impl<R: SleepProvider> Timer<R> {
     }
 }
 
+/// The number of padding cells that a channel has sent and received.
+///
+/// Returned by [`Channel::padding_counts`](super::Channel::padding_counts).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct PaddingCounts {
+    /// The number of PADDING cells we have sent.
+    pub sent: u64,
+    /// The number of PADDING and VPADDING cells we have received.
+    pub received: u64,
+}
+
+impl std::ops::AddAssign for PaddingCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.sent = self.sent.saturating_add(other.sent);
+        self.received = self.received.saturating_add(other.received);
+    }
+}
+
+/// Counters for the padding cells that a channel has sent and received.
+///
+/// Updated by the reactor, and read by the channel frontend.
+#[derive(Debug, Default)]
+pub(crate) struct PaddingCounters {
+    /// The number of PADDING cells we have sent.
+    sent: AtomicU64,
+    /// The number of PADDING and VPADDING cells we have received.
+    received: AtomicU64,
+}
+
+impl PaddingCounters {
+    /// Record that we have sent a padding cell.
+    pub(crate) fn note_sent(&self) {
+        // Relaxed ordering is fine: these are just statistics.
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that we have received a padding cell.
+    pub(crate) fn note_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return the number of padding cells sent and received so far.
+    pub(crate) fn counts(&self) -> PaddingCounts {
+        PaddingCounts {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
 impl Parameters {
     /// "Compile" the parameters into a form which can be quickly sampled
     fn prepare(self) -> Result<PreparedParameters, tor_error::Bug> {
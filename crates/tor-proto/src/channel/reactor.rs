@@ -23,7 +23,6 @@ use tor_rtcompat::SleepProvider;
 
 #[cfg_attr(not(target_os = "linux"), allow(unused))]
 use tor_error::error_report;
-#[cfg_attr(not(target_os = "linux"), allow(unused))]
 use tor_rtcompat::StreamOps;
 
 use futures::channel::mpsc;
@@ -32,7 +31,7 @@ use oneshot_fused_workaround as oneshot;
 use futures::Sink;
 use futures::StreamExt as _;
 use futures::sink::SinkExt;
-use futures::stream::Stream;
+use futures::stream::{FusedStream as _, Stream};
 use futures::{select, select_biased};
 use tor_error::internal;
 
@@ -41,7 +40,8 @@ use std::pin::Pin;
 use std::sync::Arc;
 
 use crate::channel::{
-    ChannelDetails, CloseInfo, codec::CodecError, kist::KistParams, padding, params::*, unique_id,
+    ChannelDetails, CloseInfo, codec::CodecError, kist::KistParams, padding, params::*,
+    traffic_class::TrafficClass, unique_id,
 };
 use crate::tunnel::circuit::{CircuitRxSender, celltypes::CreateResponse};
 use tracing::{debug, trace};
@@ -103,6 +103,8 @@ pub enum CtrlMsg {
     /// the sender of these messages is responsible for the optimisation of
     /// ensuring that "no-change" messages are elided.
     KistConfigUpdate(KistParams),
+    /// Mark the packets we send as belonging to a class of traffic.
+    SetTrafficClass(TrafficClass),
 }
 
 /// Object to handle incoming cells and background tasks on a channel.
@@ -129,7 +131,6 @@ pub struct Reactor<S: SleepProvider> {
     /// This should also be backed by a TLS connection if you want it to be secure.
     pub(super) output: BoxedChannelSink,
     /// A handler for setting stream options on the underlying stream.
-    #[cfg_attr(not(target_os = "linux"), allow(unused))]
     pub(super) streamops: BoxedChannelStreamOps,
     /// Timer tracking when to generate channel padding
     pub(super) padding_timer: Pin<Box<padding::Timer<S>>>,
@@ -206,7 +207,12 @@ impl<S: SleepProvider> Reactor<S> {
         }
 
         // Inform any waiters that the channel has closed.
-        let close_msg = result.as_ref().map_err(Clone::clone).map(|()| CloseInfo);
+        // If our input stream has ended, it was the peer that closed the channel.
+        let closed_by_peer = self.input.is_terminated();
+        let close_msg = result
+            .as_ref()
+            .map_err(Clone::clone)
+            .map(|()| CloseInfo { closed_by_peer });
         self.reactor_closed_tx.send(close_msg);
         result
     }
@@ -250,6 +256,7 @@ impl<S: SleepProvider> Reactor<S> {
                     },
                     p = self.padding_timer.as_mut().next() => {
                         // eprintln!("PADDING - SENDING PADDING: {:?}", &p);
+                        self.details.padding_counters.note_sent();
                         Some(p.into())
                     },
                 }
@@ -338,6 +345,7 @@ impl<S: SleepProvider> Reactor<S> {
                 }
             }
             CtrlMsg::KistConfigUpdate(kist) => self.apply_kist_params(&kist),
+            CtrlMsg::SetTrafficClass(class) => self.apply_traffic_class(class),
         }
         Ok(())
     }
@@ -366,8 +374,11 @@ impl<S: SleepProvider> Reactor<S> {
 
             CreatedFast(_) | Created2(_) => self.deliver_created(circid, msg.into()).await,
 
-            // These are always ignored.
-            Padding(_) | Vpadding(_) => Ok(()),
+            // These are always ignored, apart from being counted.
+            Padding(_) | Vpadding(_) => {
+                self.details.padding_counters.note_received();
+                Ok(())
+            }
         }
     }
 
@@ -514,6 +525,15 @@ impl<S: SleepProvider> Reactor<S> {
         }
     }
 
+    /// Mark the packets we send as belonging to `class`.
+    fn apply_traffic_class(&self, class: TrafficClass) {
+        if let Err(e) = self.streamops.set_ip_tos(class.ip_tos()) {
+            // This is expected for streams that aren't plain TCP
+            // (such as pluggable transports), and on some platforms.
+            debug_report!(e, "{}: Failed to mark channel as {:?} traffic", self, class);
+        }
+    }
+
     #[cfg(not(target_os = "linux"))]
     fn apply_kist_params(&self, params: &KistParams) {
         use super::kist::KistMode;
@@ -590,6 +610,37 @@ pub(crate) mod test {
         });
     }
 
+    #[test]
+    fn traffic_class() {
+        use crate::channel::traffic_class::TrafficClass;
+        use std::sync::Mutex;
+
+        /// A `StreamOps` that records the type-of-service values it is given.
+        #[derive(Clone, Default)]
+        struct RecordTos(Arc<Mutex<Vec<u8>>>);
+
+        impl StreamOps for RecordTos {
+            fn set_ip_tos(&self, tos: u8) -> std::io::Result<()> {
+                self.0.lock().unwrap().push(tos);
+                Ok(())
+            }
+        }
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut reactor, _output, _input) = new_reactor(rt);
+            let record = RecordTos::default();
+            reactor.streamops = Box::new(record.clone());
+
+            chan.set_traffic_class(TrafficClass::Interactive).unwrap();
+            reactor.run_once().await.unwrap();
+            chan.set_traffic_class(TrafficClass::Bulk).unwrap();
+            reactor.run_once().await.unwrap();
+
+            // AF21 and CS1, shifted past the ECN bits.
+            assert_eq!(*record.0.lock().unwrap(), vec![0x48, 0x20]);
+        });
+    }
+
     // Try shutdown while reactor is running.
     #[test]
     fn shutdown2() {
@@ -615,6 +666,23 @@ pub(crate) mod test {
 
             // Now let's see. The reactor should not _still_ be running.
             assert!(rr_s);
+
+            let info = chan.wait_for_close().await.unwrap();
+            assert!(!info.closed_by_peer());
+        });
+    }
+
+    // Make sure we notice when the peer closes the connection.
+    #[test]
+    fn closed_by_peer() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, reactor, _output, input) = new_reactor(rt);
+
+            drop(input);
+            reactor.run().await.unwrap();
+
+            let info = chan.wait_for_close().await.unwrap();
+            assert!(info.closed_by_peer());
         });
     }
 
@@ -742,6 +810,29 @@ pub(crate) mod test {
         });
     }
 
+    #[test]
+    fn count_padding() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut reactor, _output, mut input) = new_reactor(rt);
+            assert_eq!(chan.padding_counts(), padding::PaddingCounts::default());
+
+            input
+                .send(Ok(OpenChanCellS2C::new(None, msg::Padding::new().into())))
+                .await
+                .unwrap();
+            input
+                .send(Ok(OpenChanCellS2C::new(None, msg::Vpadding::new(7).into())))
+                .await
+                .unwrap();
+            reactor.run_once().await.unwrap();
+            reactor.run_once().await.unwrap();
+
+            let counts = chan.padding_counts();
+            assert_eq!(counts.received, 2);
+            assert_eq!(counts.sent, 0);
+        });
+    }
+
     #[test]
     fn deliver_relay() {
         tor_rtcompat::test_with_all_runtimes!(|rt| async move {
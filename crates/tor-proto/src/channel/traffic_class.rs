@@ -0,0 +1,42 @@
+//! Classes of traffic that a channel can carry.
+
+/// The kind of traffic that a channel is expected to carry.
+///
+/// A channel marks the packets it sends with a DSCP value
+/// that corresponds to its class.
+///
+/// Variants are ordered from least to most latency-sensitive.
+#[derive(Clone, Debug, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[non_exhaustive]
+pub enum TrafficClass {
+    /// Bulk traffic, for which throughput matters more than latency.
+    Bulk,
+
+    /// Directory traffic.
+    Directory,
+
+    /// Interactive traffic, for which latency matters.
+    Interactive,
+}
+
+impl TrafficClass {
+    /// Return the type-of-service byte with which to mark packets of this class.
+    ///
+    /// See RFC 4594 for the DSCP values.
+    pub(crate) fn ip_tos(self) -> u8 {
+        /// DSCP "CS1", for low-priority data.
+        const DSCP_CS1: u8 = 8;
+        /// DSCP "default forwarding".
+        const DSCP_DF: u8 = 0;
+        /// DSCP "AF21", for low-latency data.
+        const DSCP_AF21: u8 = 18;
+
+        let dscp = match self {
+            TrafficClass::Bulk => DSCP_CS1,
+            TrafficClass::Directory => DSCP_DF,
+            TrafficClass::Interactive => DSCP_AF21,
+        };
+        // The two low bits are for ECN, which we leave to the kernel.
+        dscp << 2
+    }
+}
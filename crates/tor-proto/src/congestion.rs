@@ -28,11 +28,12 @@ pub(crate) mod sendme;
 mod vegas;
 
 use crate::{Error, Result};
+use tor_error::bad_api_usage;
 
 use self::{
     params::{Algorithm, CongestionControlParams, CongestionWindowParams},
     rtt::RoundtripTimeEstimator,
-    sendme::SendmeValidator,
+    sendme::{SendmeTagCheck, SendmeValidator},
 };
 use tor_cell::relaycell::msg::SendmeTag;
 use tor_rtcompat::{DynTimeProvider, SleepProvider};
@@ -76,6 +77,14 @@ pub(crate) trait CongestionControlAlgorithm: Send + std::fmt::Debug {
     /// Inform the algorithm that we just sent a SENDME.
     fn sendme_sent(&mut self) -> Result<()>;
 
+    /// Return true iff we have sent cells for which we are still awaiting a SENDME.
+    fn has_inflight(&self) -> bool;
+    /// Set our congestion window to `value`, which another hop learned while in `state`.
+    ///
+    /// The value is clamped to the limits of our own parameters.
+    /// Does nothing if this algorithm doesn't use a congestion window.
+    fn restore_cwnd(&mut self, state: &State, value: u32);
+
     /// Return the number of in-flight cells (sent but awaiting SENDME ack).
     ///
     /// Optional, because not all algorithms track this.
@@ -111,7 +120,7 @@ impl CongestionSignals {
 }
 
 /// Congestion control state.
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default)]
 pub(crate) enum State {
     /// The initial state any circuit starts in. Used to gradually increase the amount of data
     /// being transmitted in order to converge towards to optimal capacity.
@@ -188,6 +197,11 @@ impl CongestionWindow {
         self.params.cwnd_min()
     }
 
+    /// Return maximum value of the congestion window.
+    pub(crate) fn max(&self) -> u32 {
+        self.params.cwnd_max()
+    }
+
     /// Set the congestion window value with a new value.
     pub(crate) fn set(&mut self, value: u32) {
         self.value = value;
@@ -273,6 +287,24 @@ pub(crate) struct CongestionControl {
     algorithm: Box<dyn CongestionControlAlgorithm>,
 }
 
+/// A snapshot of the congestion control state of a hop, taken so that it can be restored into
+/// another hop.
+///
+/// This keeps what we have learned about the network path (our congestion control state, our
+/// congestion window and our RTT estimates), but neither the parameters of the original hop nor
+/// its accounting of cells in flight.
+#[derive(Clone, Debug)]
+pub(crate) struct CongestionControlSnapshot {
+    /// The congestion control algorithm of the original hop.
+    algorithm: Algorithm,
+    /// The congestion control state we were in.
+    state: State,
+    /// The value of our congestion window, if our algorithm uses one.
+    cwnd: Option<u32>,
+    /// Our RTT estimates.
+    rtt: RttEstimates,
+}
+
 impl CongestionControl {
     /// Construct a new CongestionControl
     pub(crate) fn new(params: &CongestionControlParams) -> Self {
@@ -293,6 +325,11 @@ impl CongestionControl {
         }
     }
 
+    /// Change how strictly we check the tags of the circuit-level SENDMEs we receive.
+    pub(crate) fn set_sendme_tag_check(&mut self, check: SendmeTagCheck) {
+        self.sendme_validator.set_tag_check(check);
+    }
+
     /// Return true iff the underlying algorithm uses stream level SENDMEs.
     /// At the moment, only FixedWindow uses it. It has been eliminated with Vegas.
     pub(crate) fn uses_stream_sendme(&self) -> bool {
@@ -406,6 +443,67 @@ impl CongestionControl {
     pub(crate) fn algorithm(&self) -> Algorithm {
         self.algorithm.algorithm()
     }
+
+    /// Return true iff we have sent cells for which we are still awaiting a SENDME.
+    fn has_inflight(&self) -> bool {
+        self.algorithm.has_inflight()
+            || !self.sendme_validator.is_empty()
+            || self.rtt.expects_sendme()
+    }
+
+    /// Take a snapshot of our state, so that it can be restored into another hop with
+    /// [`restore`](Self::restore).
+    ///
+    /// Returns an error if we have cells in flight: the other hop would have no way to account
+    /// for their SENDMEs.
+    #[cfg_attr(not(test), allow(dead_code))] // TODO(conflux): use this for leg replacement
+    pub(crate) fn snapshot(&self) -> Result<CongestionControlSnapshot> {
+        if self.has_inflight() {
+            return Err(
+                bad_api_usage!("Snapshot of congestion control with cells in flight").into(),
+            );
+        }
+        Ok(CongestionControlSnapshot {
+            algorithm: self.algorithm.algorithm(),
+            state: self.state,
+            cwnd: self.algorithm.cwnd().map(CongestionWindow::get),
+            rtt: self.rtt.estimates(),
+        })
+    }
+
+    /// Carry over the state in `snapshot`, taken from another hop.
+    ///
+    /// This is meant to be called on a new hop, before any data has been sent on it.
+    /// We keep our own parameters:
+    /// the congestion window we carry over is clamped to our own limits.
+    ///
+    /// Returns an error if we have cells in flight, or if `snapshot` was taken from a hop using a
+    /// different congestion control algorithm.
+    #[cfg_attr(not(test), allow(dead_code))] // TODO(conflux): use this for leg replacement
+    pub(crate) fn restore(&mut self, snapshot: &CongestionControlSnapshot) -> Result<()> {
+        if self.has_inflight() {
+            return Err(bad_api_usage!(
+                "Restoring congestion control into a hop with cells in flight"
+            )
+            .into());
+        }
+        match (self.algorithm.algorithm(), &snapshot.algorithm) {
+            (Algorithm::FixedWindow(_), Algorithm::FixedWindow(_))
+            | (Algorithm::Vegas(_), Algorithm::Vegas(_)) => {}
+            (ours, theirs) => {
+                return Err(bad_api_usage!(
+                    "Restoring {theirs:?} congestion control into a hop using {ours:?}"
+                )
+                .into());
+            }
+        }
+        self.state = snapshot.state;
+        self.rtt.restore_estimates(&snapshot.rtt);
+        if let Some(cwnd) = snapshot.cwnd {
+            self.algorithm.restore_cwnd(&self.state, cwnd);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -424,10 +522,11 @@ mod test {
     #![allow(clippy::needless_pass_by_value)]
     //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
 
-    use crate::congestion::test_utils::new_cwnd;
+    use crate::congestion::test_utils::{new_cwnd, params};
 
-    use super::CongestionControl;
+    use super::{CongestionControl, CongestionSignals, State};
     use tor_cell::relaycell::msg::SendmeTag;
+    use tor_rtcompat::DynTimeProvider;
 
     impl CongestionControl {
         /// For testing: get a copy of the current send window, and the
@@ -461,4 +560,94 @@ mod test {
         cwnd.dec();
         assert_eq!(cwnd.get(), cwnd.params().cwnd_init());
     }
+
+    /// Send one congestion window worth of DATA cells on `cc`, and return the tag of the last
+    /// one, which is the one the SENDME will acknowledge.
+    fn send_until_sendme(cc: &mut CongestionControl, runtime: &DynTimeProvider) -> SendmeTag {
+        let mut n = 0_u8;
+        loop {
+            n += 1;
+            let tag = SendmeTag::from([n; 20]);
+            cc.note_data_sent(runtime, &tag).unwrap();
+            if !cc.send_window_and_expected_tags().1.is_empty() {
+                return tag;
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let runtime = DynTimeProvider::new(tor_rtmock::MockRuntime::default());
+        let signals = CongestionSignals::new(false, 0);
+
+        let mut old = CongestionControl::new(&params::build_cc_vegas_params());
+        old.state = State::Steady;
+        // Pretend we have learned that this path can take a bigger window.
+        old.algorithm.restore_cwnd(&old.state, 1000);
+
+        // We can't take a snapshot with cells in flight.
+        let tag = send_until_sendme(&mut old, &runtime);
+        assert!(old.snapshot().is_err());
+        old.note_sendme_received(&runtime, tag, signals).unwrap();
+        let snapshot = old.snapshot().unwrap();
+
+        // Nor restore it into a hop with cells in flight.
+        let mut busy = CongestionControl::new(&params::build_cc_vegas_params());
+        let _ = send_until_sendme(&mut busy, &runtime);
+        assert!(busy.restore(&snapshot).is_err());
+
+        // Nor into a hop with a different algorithm.
+        let mut fixed = CongestionControl::new(&params::build_cc_fixed_params());
+        assert!(fixed.restore(&snapshot).is_err());
+
+        let mut new = CongestionControl::new(&params::build_cc_vegas_params());
+        assert!(new.state.in_slow_start());
+        new.restore(&snapshot).unwrap();
+        assert!(!new.state.in_slow_start());
+        assert_eq!(new.rtt.estimates(), old.rtt.estimates());
+        assert_eq!(
+            new.send_window_and_expected_tags(),
+            old.send_window_and_expected_tags()
+        );
+        assert!(new.can_send());
+
+        // The restored state works as usual on the new hop.
+        let tag = send_until_sendme(&mut new, &runtime);
+        new.note_sendme_received(&runtime, tag, signals).unwrap();
+        assert!(new.snapshot().is_ok());
+    }
+
+    #[test]
+    fn restore_keeps_params() {
+        let old = CongestionControl::new(&params::build_cc_vegas_params());
+        let mut snapshot = old.snapshot().unwrap();
+        // Pretend we have learned a window below what the new hop allows.
+        snapshot.cwnd = Some(1);
+
+        // The new hop negotiated a different SENDME increment.
+        let mut new_params = params::build_cc_vegas_params();
+        new_params.cwnd_params_mut().set_sendme_inc(62);
+        let mut new = CongestionControl::new(&new_params);
+
+        new.restore(&snapshot).unwrap();
+        let cwnd = new.algorithm.cwnd().unwrap();
+        assert_eq!(cwnd.sendme_inc(), 62);
+        assert_eq!(cwnd.get(), cwnd.min());
+    }
+
+    #[test]
+    fn snapshot_restore_fixed() {
+        let runtime = DynTimeProvider::new(tor_rtmock::MockRuntime::default());
+        let mut old = CongestionControl::new(&params::build_cc_fixed_params());
+        let (window, _) = old.send_window_and_expected_tags();
+
+        old.note_data_sent(&runtime, &SendmeTag::from([1; 20]))
+            .unwrap();
+        assert!(old.snapshot().is_err());
+
+        let old = CongestionControl::new(&params::build_cc_fixed_params());
+        let mut new = CongestionControl::new(&params::build_cc_fixed_params());
+        new.restore(&old.snapshot().unwrap()).unwrap();
+        assert_eq!(new.send_window_and_expected_tags(), (window, vec![]));
+    }
 }
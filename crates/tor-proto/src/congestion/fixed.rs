@@ -74,6 +74,14 @@ impl CongestionControlAlgorithm for FixedWindow {
         Ok(())
     }
 
+    fn has_inflight(&self) -> bool {
+        self.sendwindow.window() != self.params.circ_window_start()
+    }
+
+    fn restore_cwnd(&mut self, _state: &State, _value: u32) {
+        // We don't use a congestion window.
+    }
+
     fn data_received(&mut self) -> Result<bool> {
         self.recvwindow.take()
     }
@@ -30,6 +30,22 @@ impl HasKind for Error {
     }
 }
 
+/// The estimates of a [`RoundtripTimeEstimator`],
+/// as carried over from one hop to another.
+///
+/// See [`RoundtripTimeEstimator::estimates`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RttEstimates {
+    /// The last measured round-trip time.
+    last_rtt: Option<Duration>,
+    /// The smoothed estimate of the round-trip time.
+    ewma_rtt: Option<Duration>,
+    /// The minimum observed round-trip time.
+    min_rtt: Option<Duration>,
+    /// The maximum observed round-trip time.
+    max_rtt: Option<Duration>,
+}
+
 /// Provides an estimate of the round-trip time (RTT) of a Tor circuit.
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -81,6 +97,31 @@ impl RoundtripTimeEstimator {
         }
     }
 
+    /// Return a copy of our estimates.
+    pub(crate) fn estimates(&self) -> RttEstimates {
+        RttEstimates {
+            last_rtt: self.last_rtt,
+            ewma_rtt: self.ewma_rtt,
+            min_rtt: self.min_rtt,
+            max_rtt: self.max_rtt,
+        }
+    }
+
+    /// Replace our estimates with `estimates`, taken from another estimator.
+    ///
+    /// We keep our own parameters, and our own pending SENDME expectations.
+    pub(crate) fn restore_estimates(&mut self, estimates: &RttEstimates) {
+        self.last_rtt = estimates.last_rtt;
+        self.ewma_rtt = estimates.ewma_rtt;
+        self.min_rtt = estimates.min_rtt;
+        self.max_rtt = estimates.max_rtt;
+    }
+
+    /// Return true iff we are expecting a SENDME for something we did.
+    pub(crate) fn expects_sendme(&self) -> bool {
+        !self.sendme_expected_from.is_empty()
+    }
+
     /// Return true iff the estimator is ready to be used or read.
     pub(crate) fn is_ready(&self) -> bool {
         !self.clock_stalled() && self.last_rtt.is_some()
@@ -11,10 +11,12 @@
 //! acknowledging.
 
 use std::collections::VecDeque;
+use std::time::Duration;
 
 use tor_cell::relaycell::RelayCmd;
 use tor_cell::relaycell::UnparsedRelayMsg;
 use tor_error::internal;
+use tracing::debug;
 
 use crate::{Error, Result};
 
@@ -87,6 +89,49 @@ impl WindowParams for StreamParams {
     }
 }
 
+/// The longest that [`SendmeEmission::Delayed`] may hold back a SENDME.
+pub(crate) const MAX_SENDME_DELAY: Duration = Duration::from_secs(1);
+
+/// When we send the circuit-level SENDMEs that our receive window calls for.
+///
+/// Either way, we send exactly one SENDME for every window increment we receive.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SendmeEmission {
+    /// Send each SENDME as soon as we have received the cell that calls for it.
+    ///
+    /// This refills the other side's window as early as possible,
+    /// which is what latency-sensitive circuits want.
+    #[default]
+    Immediate,
+    /// Hold each SENDME back for up to this long,
+    /// and send it along with any others that come due in the meantime.
+    ///
+    /// On a busy circuit, this sends our SENDMEs in batches,
+    /// at the cost of refilling the other side's window later:
+    /// if the other side runs out of window before we send them, it stalls.
+    /// The delay may be at most one second.
+    Delayed(Duration),
+}
+
+/// How strictly we check the authentication tags on incoming circuit-level SENDMEs.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SendmeTagCheck {
+    /// Close the circuit if a SENDME's tag doesn't match the one we expected.
+    ///
+    /// This is what the protocol requires.
+    #[default]
+    Strict,
+    /// Accept SENDMEs whose tag doesn't match the one we expected, logging them.
+    ///
+    /// We still close the circuit if we receive a SENDME we weren't expecting at all.
+    /// This violates the protocol:
+    /// it removes the guarantee that the other side has really read the data it acknowledges.
+    /// It is only meant for testing, and for diagnosing misbehaving relays.
+    Lenient,
+}
+
 /// Object used to validate SENDMEs as in managing the authenticated tag and verifying it.
 #[derive(Clone, Debug)]
 pub(crate) struct SendmeValidator<T>
@@ -96,6 +141,8 @@ where
     /// Tag values that incoming "SENDME" messages need to match in order
     /// for us to send more data.
     tags: VecDeque<T>,
+    /// What to do with a SENDME whose tag doesn't match.
+    check: SendmeTagCheck,
 }
 
 impl<T> SendmeValidator<T>
@@ -106,9 +153,15 @@ where
     pub(crate) fn new() -> Self {
         Self {
             tags: VecDeque::new(),
+            check: SendmeTagCheck::default(),
         }
     }
 
+    /// Change how strictly we check the tags of the SENDMEs we receive.
+    pub(crate) fn set_tag_check(&mut self, check: SendmeTagCheck) {
+        self.check = check;
+    }
+
     /// Record a SENDME tag for future validation once we receive it.
     pub(crate) fn record<U>(&mut self, tag: &U)
     where
@@ -126,6 +179,9 @@ where
         match (self.tags.front(), tag) {
             (Some(t), Some(tag)) if t == &tag => {} // this is the right tag.
             (Some(_), None) => {}                   // didn't need a tag.
+            (Some(_), Some(_)) if self.check == SendmeTagCheck::Lenient => {
+                debug!("Accepting circuit SENDME with mismatched tag");
+            }
             (Some(_), Some(_)) => {
                 return Err(Error::CircProto("Mismatched tag on circuit SENDME".into()));
             }
@@ -139,6 +195,11 @@ where
         Ok(())
     }
 
+    /// Return true iff we aren't expecting any SENDME.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
     #[cfg(test)]
     pub(crate) fn expected_tags(&self) -> Vec<T> {
         self.tags.iter().map(Clone::clone).collect()
@@ -340,6 +401,26 @@ mod test {
         assert!(w.take().is_err());
     }
 
+    #[test]
+    fn validator_tag_check() {
+        let mut v: SendmeValidator<u8> = SendmeValidator::new();
+        v.record(&1_u8);
+        v.record(&2_u8);
+        assert!(v.validate(Some(1_u8)).is_ok());
+        assert!(v.validate(Some(3_u8)).is_err());
+
+        let mut v: SendmeValidator<u8> = SendmeValidator::new();
+        v.set_tag_check(SendmeTagCheck::Lenient);
+        v.record(&1_u8);
+        v.record(&2_u8);
+        assert!(v.validate(Some(3_u8)).is_ok());
+        assert_eq!(v.expected_tags(), vec![2]);
+        assert!(v.validate(Some(2_u8)).is_ok());
+        assert!(v.is_empty());
+        // Even a lenient validator refuses SENDMEs it wasn't expecting.
+        assert!(v.validate(Some(3_u8)).is_err());
+    }
+
     fn new_sendwindow() -> SendWindow<CircParams> {
         SendWindow::new(1000)
     }
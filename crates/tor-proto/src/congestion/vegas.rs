@@ -289,6 +289,15 @@ impl CongestionControlAlgorithm for Vegas {
         Ok(())
     }
 
+    fn has_inflight(&self) -> bool {
+        self.num_inflight != 0
+    }
+
+    fn restore_cwnd(&mut self, state: &State, value: u32) {
+        self.cwnd.set(value.clamp(self.cwnd.min(), self.cwnd.max()));
+        self.num_sendme_until_cwnd_update = self.cwnd.update_rate(state);
+    }
+
     fn data_received(&mut self) -> Result<bool> {
         if self.num_cell_until_sendme == 0 {
             // This is not a protocol violation, it is a code flow error and so don't close the
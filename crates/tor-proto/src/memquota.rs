@@ -15,6 +15,7 @@
 //!   * Tor circuits ([`CircuitAccount`])
 //!     - inbound stream requests, on their way from the circuit to the handling code
 //!     - inbound data, on its way from the channel
+//!     - inbound conflux messages that arrived out of order, awaiting the missing ones
 //!   * Tor channels ([`ChannelAccount`])
 //!     - outbound data, on its way from a circuit to the channel
 //!       (this ought to be accounted to the circuit, TODO #1652)
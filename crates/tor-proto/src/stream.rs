@@ -30,8 +30,8 @@ pub(crate) use incoming::IncomingCmdChecker;
 #[cfg(feature = "hs-service")]
 #[cfg_attr(docsrs, doc(cfg(feature = "hs-service")))]
 pub use incoming::{
-    IncomingStream, IncomingStreamRequest, IncomingStreamRequestContext,
-    IncomingStreamRequestDisposition, IncomingStreamRequestFilter,
+    IncomingStream, IncomingStreamOverflow, IncomingStreamQueueParams, IncomingStreamRequest,
+    IncomingStreamRequestContext, IncomingStreamRequestDisposition, IncomingStreamRequestFilter,
 };
 pub use params::StreamParameters;
 pub use raw::StreamReceiver;
@@ -45,3 +45,7 @@ pub use tor_cell::relaycell::msg::IpVersionPreference;
 pub use {ctrl::ClientStreamCtrl, data::ClientDataStreamCtrl};
 
 pub(crate) use flow_control::{DrainRateRequest, StreamFlowControl, StreamRateLimit};
+
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub use flow_control::StreamFlowControlState;
@@ -29,6 +29,8 @@ use std::pin::Pin;
 use std::sync::Arc;
 #[cfg(feature = "stream-ctrl")]
 use std::sync::{Mutex, Weak};
+#[cfg(feature = "stream-ctrl")]
+use std::time::{Duration, Instant};
 
 use educe::Educe;
 
@@ -39,7 +41,7 @@ use crate::tunnel::{ClientTunnel, StreamTarget};
 use crate::util::token_bucket::dynamic_writer::DynamicRateLimitedWriter;
 use crate::util::token_bucket::writer::{RateLimitedWriter, RateLimitedWriterConfig};
 use tor_basic_utils::skip_fmt;
-use tor_cell::relaycell::msg::Data;
+use tor_cell::relaycell::msg::{Data, End};
 use tor_error::internal;
 use tor_rtcompat::{CoarseTimeProvider, DynTimeProvider, SleepProvider};
 
@@ -230,6 +232,8 @@ struct DataWriterInner {
 /// and so will (probably) not finish sending you any in-progress data.
 /// Do not use `close`/`shutdown` to communicate anything besides
 /// "I am done using this stream."
+/// (If you know that the other side supports it,
+/// you can use [`close_write`](DataWriter::close_write) instead.)
 ///
 // # Semver note
 //
@@ -290,6 +294,30 @@ impl DataWriter {
     pub fn client_stream_ctrl(&self) -> Option<&Arc<ClientDataStreamCtrl>> {
         Some(self.writer.inner().client_stream_ctrl())
     }
+
+    /// Close the write side of this stream, while continuing to read from it.
+    ///
+    /// This flushes any buffered data, and then sends an END message
+    /// to tell the other side that we have nothing more to send.
+    /// Unlike [`close`](futures::io::AsyncWriteExt::close),
+    /// this leaves the stream open for reading:
+    /// the corresponding [`DataReader`] keeps receiving data
+    /// until the other side ends the stream too.
+    ///
+    /// Once this has been called, any further attempt to write to this stream will fail.
+    ///
+    /// # Limitations
+    ///
+    /// The Tor protocol has no separate message for half-closing a stream.
+    /// A peer that doesn't expect half-closed streams will take our END
+    /// as closing the stream in both directions:
+    /// it may never send the rest of its data, nor an END of its own,
+    /// in which case reading from the stream will not finish until the circuit closes.
+    /// Only use this method with peers that are known to support half-closed streams.
+    pub async fn close_write(&mut self) -> IoResult<()> {
+        futures::future::poll_fn(|cx| Pin::new(&mut self.writer).inner_mut().poll_close_write(cx))
+            .await
+    }
 }
 
 impl AsyncWrite for DataWriter {
@@ -447,7 +475,7 @@ impl BufferIsEmpty for DataReaderInner {
 // case, we should really  eliminate as much duplicate state here as we can.
 // (See discussions at !1198 for some challenges with this.)
 #[cfg(feature = "stream-ctrl")]
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 struct DataStreamStatus {
     /// True if we've received a CONNECTED message.
     //
@@ -469,10 +497,33 @@ struct DataStreamStatus {
     /// (This is not a subset or superset of received_end; some errors are END
     /// messages but some aren't; some END messages are errors but some aren't.)
     received_err: bool,
+    /// The last time we sent or received a message on this stream.
+    ///
+    /// Initially, this is the time when the stream was created.
+    last_activity: Instant,
+    /// The time provider used for updating `last_activity`.
+    time_provider: DynTimeProvider,
 }
 
 #[cfg(feature = "stream-ctrl")]
 impl DataStreamStatus {
+    /// Create a new `DataStreamStatus` for a stream created just now.
+    fn new(time_provider: DynTimeProvider) -> Self {
+        Self {
+            received_connected: false,
+            sent_end: false,
+            received_end: false,
+            received_err: false,
+            last_activity: time_provider.now(),
+            time_provider,
+        }
+    }
+
+    /// Remember that we've just sent or received a message on this stream.
+    fn record_activity(&mut self) {
+        self.last_activity = self.time_provider.now();
+    }
+
     /// Remember that we've received a connected message.
     fn record_connected(&mut self) {
         self.received_connected = true;
@@ -520,6 +571,25 @@ impl ClientDataStreamCtrl {
         s.received_connected && !(s.sent_end || s.received_end || s.received_err)
     }
 
+    /// Return how long it has been since we last sent or received
+    /// a message on this stream.
+    ///
+    /// If the stream hasn't carried any messages yet,
+    /// this is the time since the stream was created.
+    ///
+    /// Note that a message only counts as "received" once it has been read by the
+    /// [`DataReader`]: if the application is not reading from the stream,
+    /// the stream will appear idle even if the other side is sending data.
+    ///
+    /// This can be used by applications to find (and close) streams
+    /// whose other side seems to have vanished.
+    pub fn idle_time(&self) -> Duration {
+        let s = self.status.lock().expect("poisoned lock");
+        s.time_provider
+            .now()
+            .saturating_duration_since(s.last_activity)
+    }
+
     // TODO RPC: Add more functions once we have the desired API more nailed
     // down.
 }
@@ -529,12 +599,16 @@ impl DataStream {
     ///
     /// For non-optimistic stream, function `wait_for_connection`
     /// must be called after to make sure CONNECTED is received.
+    ///
+    /// If `preserve_cell_boundaries` is true, each read returns the body of a single DATA message
+    /// (see [`StreamParameters::preserve_cell_boundaries`](super::StreamParameters::preserve_cell_boundaries)).
     pub(crate) fn new<P: SleepProvider + CoarseTimeProvider>(
         time_provider: P,
         receiver: StreamReceiver,
         xon_xoff_reader_ctrl: XonXoffReaderCtrl,
         target: StreamTarget,
         memquota: StreamAccount,
+        preserve_cell_boundaries: bool,
     ) -> Self {
         Self::new_inner(
             time_provider,
@@ -543,6 +617,7 @@ impl DataStream {
             target,
             false,
             memquota,
+            preserve_cell_boundaries,
         )
     }
 
@@ -567,6 +642,7 @@ impl DataStream {
             target,
             true,
             memquota,
+            false,
         )
     }
 
@@ -578,14 +654,16 @@ impl DataStream {
         target: StreamTarget,
         connected: bool,
         memquota: StreamAccount,
+        preserve_cell_boundaries: bool,
     ) -> Self {
         let relay_cell_format = target.relay_cell_format();
         let out_buf_len = Data::max_body_len(relay_cell_format);
         let rate_limit_stream = target.rate_limit_stream().clone();
+        let time_provider = DynTimeProvider::new(time_provider);
 
         #[cfg(feature = "stream-ctrl")]
         let status = {
-            let mut data_stream_status = DataStreamStatus::default();
+            let mut data_stream_status = DataStreamStatus::new(time_provider.clone());
             if connected {
                 data_stream_status.record_connected();
             }
@@ -604,6 +682,7 @@ impl DataStream {
                 pending: Vec::new(),
                 offset: 0,
                 connected,
+                preserve_cell_boundaries,
                 #[cfg(feature = "stream-ctrl")]
                 status: status.clone(),
             })),
@@ -625,8 +704,6 @@ impl DataStream {
             ctrl: ctrl.clone(),
         };
 
-        let time_provider = DynTimeProvider::new(time_provider);
-
         DataStream {
             w: DataWriter::new(w, rate_limit_stream, time_provider),
             r: DataReader::new(r, xon_xoff_reader_ctrl),
@@ -680,6 +757,13 @@ impl DataStream {
     pub fn client_stream_ctrl(&self) -> Option<&Arc<ClientDataStreamCtrl>> {
         Some(&self.ctrl)
     }
+
+    /// Close the write side of this stream, while continuing to read from it.
+    ///
+    /// See [`DataWriter::close_write`] for details, and for important limitations.
+    pub async fn close_write(&mut self) -> IoResult<()> {
+        self.w.close_write().await
+    }
 }
 
 impl AsyncRead for DataStream {
@@ -755,6 +839,14 @@ enum DataWriterState {
         #[educe(Debug(method = "skip_fmt"))] //
         BoxSyncFuture<'static, (DataWriterImpl, Result<()>)>,
     ),
+    /// The writer is flushing its last cell, and then sending an END
+    /// to close the write side of the stream.
+    ///
+    /// See [`DataWriter::close_write`].
+    Ending(
+        #[educe(Debug(method = "skip_fmt"))] //
+        BoxSyncFuture<'static, (DataWriterImpl, Result<()>)>,
+    ),
 }
 
 /// Internal: the write part of a DataStream
@@ -823,6 +915,11 @@ impl DataWriterInner {
                 }
             }
             DataWriterState::Flushing(fut) => fut,
+            DataWriterState::Ending(fut) => {
+                // We're already closing the write side: finish doing that.
+                self.state = Some(DataWriterState::Ending(fut));
+                return self.poll_close_write(cx);
+            }
             DataWriterState::Closed => {
                 self.state = Some(DataWriterState::Closed);
                 return Poll::Ready(Err(Error::NotConnected.into()));
@@ -864,6 +961,58 @@ impl DataWriterInner {
     }
 }
 
+impl DataWriterInner {
+    /// Flush any buffered data, and then close the write side of the stream
+    /// by sending an END message.
+    ///
+    /// See [`DataWriter::close_write`].
+    fn poll_close_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        loop {
+            let state = self.state.take().expect("Missing state in DataWriter");
+
+            let (mut future, ending): (BoxSyncFuture<_>, bool) = match state {
+                DataWriterState::Ready(imp) => (Box::pin(imp.flush_and_end()), true),
+                // Let the flush that is already in progress finish first.
+                DataWriterState::Flushing(fut) => (fut, false),
+                DataWriterState::Ending(fut) => (fut, true),
+                DataWriterState::Closed => {
+                    self.state = Some(DataWriterState::Closed);
+                    return Poll::Ready(Err(Error::NotConnected.into()));
+                }
+            };
+
+            match future.as_mut().poll(cx) {
+                Poll::Ready((_imp, Err(e))) => {
+                    self.state = Some(DataWriterState::Closed);
+                    return Poll::Ready(Err(e.into()));
+                }
+                Poll::Ready((_imp, Ok(()))) if ending => {
+                    // Note that we don't close `_imp.s`: that would close the stream
+                    // for the reader too.
+                    #[cfg(feature = "stream-ctrl")]
+                    {
+                        _imp.status.lock().expect("lock poisoned").sent_end = true;
+                    }
+                    self.state = Some(DataWriterState::Closed);
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready((imp, Ok(()))) => {
+                    // The earlier flush is done; go around again to send the END.
+                    self.state = Some(DataWriterState::Ready(imp));
+                }
+                Poll::Pending => {
+                    self.state = Some(if ending {
+                        DataWriterState::Ending(future)
+                    } else {
+                        DataWriterState::Flushing(future)
+                    });
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
 impl AsyncWrite for DataWriterInner {
     fn poll_write(
         mut self: Pin<&mut Self>,
@@ -887,8 +1036,8 @@ impl AsyncWrite for DataWriterInner {
                 Box::pin(imp.flush_buf())
             }
             DataWriterState::Flushing(fut) => fut,
-            DataWriterState::Closed => {
-                self.state = Some(DataWriterState::Closed);
+            state @ (DataWriterState::Ending(_) | DataWriterState::Closed) => {
+                self.state = Some(state);
                 return Poll::Ready(Err(Error::NotConnected.into()));
             }
         };
@@ -950,7 +1099,12 @@ impl DataWriterImpl {
             // this invariant will become false.
             assert!(remainder.is_empty());
             self.n_pending = 0;
-            self.s.send(cell.into()).await
+            let result = self.s.send(cell.into()).await;
+            #[cfg(feature = "stream-ctrl")]
+            if result.is_ok() {
+                self.status.lock().expect("poisoned lock").record_activity();
+            }
+            result
         } else {
             Ok(())
         };
@@ -958,6 +1112,18 @@ impl DataWriterImpl {
         (self, result)
     }
 
+    /// Flush the current buffer contents, and then send an END message
+    /// to close the write side of the stream.
+    async fn flush_and_end(self) -> (Self, Result<()>) {
+        let (mut this, result) = self.flush_buf().await;
+        if result.is_err() {
+            return (this, result);
+        }
+        let end = End::new_with_reason(EndReason::DONE);
+        let result = this.s.send(end.into()).await;
+        (this, result)
+    }
+
     /// Add as many bytes as possible from `b` to our internal buffer;
     /// return the number we were able to add.
     fn queue_bytes(&mut self, b: &[u8]) -> usize {
@@ -1022,6 +1188,14 @@ struct DataReaderImpl {
     /// If true, we have received a CONNECTED cell on this stream.
     connected: bool,
 
+    /// If true, each read returns the body of a single DATA message,
+    /// rather than treating the stream as a sequence of bytes.
+    ///
+    /// Since we only read a new message once `pending` is empty,
+    /// `pending` never holds more than one message;
+    /// so all we need to do is to refuse reads that would split it.
+    preserve_cell_boundaries: bool,
+
     /// Shared user-visible information about the state of this stream.
     #[cfg(feature = "stream-ctrl")]
     status: Arc<Mutex<DataStreamStatus>>,
@@ -1040,6 +1214,15 @@ impl AsyncRead for DataReaderInner {
         loop {
             let mut imp = match state {
                 DataReaderState::Open(mut imp) => {
+                    if imp.preserve_cell_boundaries && buf.len() < imp.pending.len() - imp.offset {
+                        // We can't return this message without splitting it.
+                        self.state = Some(DataReaderState::Open(imp));
+                        return Poll::Ready(Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "buffer too small to hold a DATA message",
+                        )));
+                    }
+
                     // There may be data to read already.
                     let n_copied = imp.extract_bytes(buf);
                     if n_copied != 0 || buf.is_empty() {
@@ -1140,6 +1323,11 @@ impl DataReaderImpl {
             Poll::Ready(None) => return Poll::Ready(Err(Error::NotConnected)),
         };
 
+        #[cfg(feature = "stream-ctrl")]
+        {
+            self.status.lock().expect("poisoned lock").record_activity();
+        }
+
         let result = match msg {
             Connected(_) if !self.connected => {
                 self.connected = true;
@@ -1,6 +1,8 @@
 //! Code for implementing flow control (stream-level).
 
 use postage::watch;
+#[cfg(any(test, feature = "testing"))]
+use tor_cell::relaycell::StreamId;
 use tor_cell::relaycell::flow_ctrl::{FlowCtrlVersion, Xoff, Xon, XonKbpsEwma};
 use tor_cell::relaycell::msg::Sendme;
 use tor_cell::relaycell::{RelayMsg, UnparsedRelayMsg};
@@ -55,10 +57,33 @@ impl StreamFlowControl {
                 rate_limit_updater,
                 drain_rate_requester,
                 last_sent_xon_xoff: None,
+                xoff_received: false,
             }),
         }
     }
 
+    /// Return a snapshot of the flow control state of the stream `stream_id`,
+    /// on which we have dropped `dropped_cells` cells.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn state(&self, stream_id: StreamId, dropped_cells: u16) -> StreamFlowControlState {
+        let (send_window, xoff_sent, xoff_received) = match &self.e {
+            StreamFlowControlEnum::WindowBased(w) => (Some(w.window()), false, false),
+            #[cfg(feature = "flowctl-cc")]
+            StreamFlowControlEnum::XonXoffBased(control) => (
+                None,
+                matches!(control.last_sent_xon_xoff, Some(LastSentXonXoff::Xoff)),
+                control.xoff_received,
+            ),
+        };
+        StreamFlowControlState {
+            stream_id,
+            send_window,
+            xoff_sent,
+            xoff_received,
+            dropped_cells,
+        }
+    }
+
     /// Whether this stream is ready to send `msg`.
     pub(crate) fn can_send<M: RelayMsg>(&self, msg: &M) -> bool {
         match &self.e {
@@ -161,6 +186,7 @@ impl StreamFlowControl {
                 };
 
                 *control.rate_limit_updater.borrow_mut() = rate;
+                control.xoff_received = false;
                 Ok(())
             }
         }
@@ -189,6 +215,7 @@ impl StreamFlowControl {
                 }
 
                 *control.rate_limit_updater.borrow_mut() = StreamRateLimit::ZERO;
+                control.xoff_received = true;
                 Ok(())
             }
         }
@@ -263,6 +290,35 @@ impl StreamFlowControl {
     }
 }
 
+/// A snapshot of the flow control state of a single stream.
+///
+/// Returned by [`ClientTunnel::stream_flow_control_states`](crate::ClientTunnel::stream_flow_control_states),
+/// for use in tests and when debugging flow control stalls.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct StreamFlowControlState {
+    /// The stream's ID.
+    pub stream_id: StreamId,
+    /// The number of DATA cells we may still send before we need a stream-level SENDME.
+    ///
+    /// `None` if the stream uses XON/XOFF flow control instead of SENDME windows.
+    pub send_window: Option<u16>,
+    /// Whether the last XON/XOFF message we sent on this stream was an XOFF,
+    /// so that the other side is waiting for an XON before it sends any more data.
+    ///
+    /// Always false if the stream uses SENDME windows.
+    pub xoff_sent: bool,
+    /// Whether the last XON/XOFF message we received on this stream was an XOFF,
+    /// so that we won't send any more data until the other side sends an XON.
+    ///
+    /// Always false if the stream uses SENDME windows.
+    pub xoff_received: bool,
+    /// The number of cells that we dropped on this stream
+    /// because the stream object had gone away before we could close it.
+    pub dropped_cells: u16,
+}
+
 /// Control state for XON/XOFF flow control.
 #[derive(Debug)]
 struct XonXoffControl {
@@ -274,6 +330,8 @@ struct XonXoffControl {
     drain_rate_requester: NotifySender<DrainRateRequest>,
     /// The last rate limit we sent.
     last_sent_xon_xoff: Option<LastSentXonXoff>,
+    /// Whether the last XON/XOFF message we received was an XOFF.
+    xoff_received: bool,
 }
 
 /// The last XON/XOFF message that we sent.
@@ -323,3 +381,57 @@ impl std::fmt::Display for StreamRateLimit {
 /// requests.
 #[derive(Debug)]
 pub(crate) struct DrainRateRequest;
+
+#[cfg(all(test, feature = "flowctl-cc"))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_cell::relaycell::msg::AnyRelayMsg;
+    use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCellFormat};
+
+    /// Encode `msg` as the only message in a relay cell on the stream `id`.
+    fn to_unparsed(id: StreamId, msg: AnyRelayMsg) -> UnparsedRelayMsg {
+        UnparsedRelayMsg::from_singleton_body(
+            RelayCellFormat::V0,
+            AnyRelayMsgOuter::new(Some(id), msg)
+                .encode(RelayCellFormat::V0, &mut testing_rng())
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn xoff_received() {
+        let (rate_limit_updater, _rate_limit_rx) = watch::channel_with(StreamRateLimit::MAX);
+        let mut flow_ctrl =
+            StreamFlowControl::new_xon_xoff_based(rate_limit_updater, NotifySender::new_typed());
+        let id = StreamId::new(77).unwrap();
+        assert!(!flow_ctrl.state(id, 0).xoff_received);
+
+        let xoff = Xoff::new(FlowCtrlVersion::V0).into();
+        flow_ctrl
+            .handle_incoming_xoff(to_unparsed(id, xoff))
+            .unwrap();
+        let state = flow_ctrl.state(id, 0);
+        assert!(state.xoff_received);
+        assert!(!state.xoff_sent);
+        assert_eq!(state.send_window, None);
+
+        let xon = Xon::new(FlowCtrlVersion::V0, XonKbpsEwma::Unlimited).into();
+        flow_ctrl.handle_incoming_xon(to_unparsed(id, xon)).unwrap();
+        assert!(!flow_ctrl.state(id, 0).xoff_received);
+    }
+}
@@ -5,7 +5,8 @@ use bitvec::prelude::*;
 use super::{AnyCmdChecker, DataStream, StreamStatus};
 use crate::circuit::ClientCircSyncView;
 use crate::tunnel::StreamComponents;
-use crate::tunnel::reactor::CloseStreamBehavior;
+use crate::tunnel::reactor::{CloseStreamBehavior, STREAM_READER_BUFFER};
+use crate::util::token_bucket::bucket::TokenBucketConfig;
 use crate::{Error, Result};
 use derive_deftly::Deftly;
 use oneshot_fused_workaround as oneshot;
@@ -85,6 +86,21 @@ impl IncomingStream {
         }
     }
 
+    /// Answer this RESOLVE request by sending the client `message`.
+    ///
+    /// This closes the stream: no END message is sent.
+    ///
+    /// Returns an error if this is not a RESOLVE request.
+    pub async fn answer_resolve(mut self, message: msg::Resolved) -> Result<()> {
+        if !matches!(self.request, IncomingStreamRequest::Resolve(_)) {
+            return Err(internal!("Cannot answer a non-RESOLVE request with RESOLVED").into());
+        }
+        self.components.target.send(message.into()).await?;
+        let rx = self.reject_inner(CloseStreamBehavior::SendNothing)?;
+
+        rx.await.map_err(|_| Error::CircuitClosed)?.map(|_| ())
+    }
+
     /// Reject this request and send an error message to the client.
     pub async fn reject(mut self, message: msg::End) -> Result<()> {
         let rx = self.reject_inner(CloseStreamBehavior::SendEnd(message))?;
@@ -146,7 +162,7 @@ type RelayCmdSet = bitvec::BitArr!(for 256);
 pub(crate) struct IncomingCmdChecker {
     /// The "begin" commands that can be received on this type of circuit:
     ///
-    ///   * onion service circuits only accept `BEGIN`
+    ///   * onion service circuits accept `BEGIN` and `RESOLVE`
     ///   * all relay circuits accept `BEGIN_DIR`
     ///   * exit relays additionally accept `BEGIN` or `RESOLVE` on relay circuits
     ///   * once CONNECT_UDP is implemented, relays and later onion services may accept CONNECT_UDP
@@ -218,6 +234,106 @@ pub enum IncomingStreamRequestDisposition {
     RejectRequest(msg::End),
 }
 
+/// What to do with an incoming stream request that arrives
+/// while the queue of requests awaiting the application is full.
+///
+/// See [`IncomingStreamQueueParams`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum IncomingStreamOverflow {
+    /// Reject the request with an END message (with reason `RESOURCELIMIT`),
+    /// but keep the circuit open.
+    #[default]
+    RejectRequest,
+    /// Close the circuit on which the request was received.
+    CloseCircuit,
+}
+
+/// A set of parameters that bound the queue of incoming stream requests
+/// that the application has not yet taken.
+///
+/// Each request in this queue has already been given a stream, with its own buffers;
+/// without a bound, a peer that floods a circuit with `BEGIN` messages
+/// could make us use an arbitrary amount of memory.
+#[derive(Clone, Debug)]
+pub struct IncomingStreamQueueParams {
+    /// The largest number of requests we queue.
+    depth: usize,
+    /// What to do with requests that arrive when the queue is full.
+    overflow: IncomingStreamOverflow,
+    /// The rate at which we accept requests, if it is limited.
+    rate_limit: Option<TokenBucketConfig>,
+}
+
+impl Default for IncomingStreamQueueParams {
+    fn default() -> Self {
+        Self {
+            // This is the capacity of the mpsc channel that we used for this queue
+            // before it was configurable: its buffer, plus one message for its one sender.
+            depth: STREAM_READER_BUFFER + 1,
+            overflow: IncomingStreamOverflow::default(),
+            rate_limit: None,
+        }
+    }
+}
+
+impl IncomingStreamQueueParams {
+    /// Create a new [`IncomingStreamQueueParams`] using default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Configure the largest number of incoming stream requests that we queue.
+    ///
+    /// A `depth` of zero is treated as one.
+    pub fn depth(&mut self, depth: usize) -> &mut Self {
+        self.depth = depth.max(1);
+        self
+    }
+
+    /// Configure what to do with incoming stream requests that arrive when the queue is full.
+    ///
+    /// The default is to reject them.
+    pub fn on_overflow(&mut self, overflow: IncomingStreamOverflow) -> &mut Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Limit the rate at which we accept incoming stream requests
+    /// to `rate` per second on average, allowing bursts of up to `burst` requests.
+    ///
+    /// Requests beyond this limit are rejected with an END message
+    /// (with reason `RESOURCELIMIT`) as soon as they arrive,
+    /// before the [`IncomingStreamRequestFilter`] sees them.
+    /// The limit applies to every kind of request that the circuit allows
+    /// (`BEGIN`, `BEGIN_DIR`, and `RESOLVE`).
+    ///
+    /// A `burst` of zero is treated as one.
+    /// By default, the rate is not limited.
+    pub fn rate_limit(&mut self, rate: u32, burst: u32) -> &mut Self {
+        self.rate_limit = Some(TokenBucketConfig {
+            rate: rate.into(),
+            bucket_max: burst.max(1).into(),
+        });
+        self
+    }
+
+    /// Return the largest number of incoming stream requests that we queue.
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Return what we do with incoming stream requests that arrive when the queue is full.
+    pub(crate) fn overflow(&self) -> IncomingStreamOverflow {
+        self.overflow
+    }
+
+    /// Return the rate at which we accept incoming stream requests, if it is limited.
+    pub(crate) fn rate_limit_config(&self) -> Option<&TokenBucketConfig> {
+        self.rate_limit.as_ref()
+    }
+}
+
 /// Information about a stream request, as passed to an [`IncomingStreamRequestFilter`].
 pub struct IncomingStreamRequestContext<'a> {
     /// The request message itself
@@ -13,6 +13,8 @@ pub struct StreamParameters {
     suppress_hostname: bool,
     /// True if we are suppressing flags.
     suppress_begin_flags: bool,
+    /// True if reads from the stream should preserve cell boundaries.
+    preserve_cell_boundaries: bool,
 }
 
 impl StreamParameters {
@@ -70,6 +72,28 @@ impl StreamParameters {
         self
     }
 
+    /// Configure whether reads from the stream should preserve cell boundaries.
+    ///
+    /// By default, a [`DataStream`](crate::stream::DataStream) is a byte stream,
+    /// like a TCP stream: a single read may return data from several `DATA` messages,
+    /// or only part of one.
+    ///
+    /// If this option is set, each successful read returns the body of exactly one
+    /// `DATA` message.  (Empty `DATA` messages are skipped.)
+    /// The buffer passed to each read must be large enough to hold the whole message:
+    /// a buffer of [`Data::MAXLEN`](tor_cell::relaycell::msg::Data::MAXLEN) bytes
+    /// is always sufficient.
+    /// If it is too small, the read fails with [`std::io::ErrorKind::InvalidInput`],
+    /// and the message is left in place to be read with a larger buffer.
+    ///
+    /// This is useful for protocols that rely on Tor's cell boundaries
+    /// for their own framing, and for testing.
+    /// It has no effect on how data is written to the stream.
+    pub fn preserve_cell_boundaries(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_cell_boundaries = preserve;
+        self
+    }
+
     /// Crate-internal: Return true if the stream is optimistic.
     pub(crate) fn is_optimistic(&self) -> bool {
         self.optimistic
@@ -84,6 +108,11 @@ impl StreamParameters {
         }
     }
 
+    /// Crate-internal: Return true if reads should preserve cell boundaries.
+    pub(crate) fn preserving_cell_boundaries(&self) -> bool {
+        self.preserve_cell_boundaries
+    }
+
     /// Crate-internal: Return true if we are suppressing hostnames.
     pub(crate) fn suppressing_hostname(&self) -> bool {
         self.suppress_hostname
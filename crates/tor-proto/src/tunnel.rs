@@ -29,9 +29,7 @@ use crate::stream::{
 use crate::util::notify::NotifySender;
 use crate::{Error, ResolveError, Result};
 use circuit::{CIRCUIT_BUFFER_SIZE, ClientCirc, Path, StreamMpscSender, UniqId};
-use reactor::{
-    CtrlCmd, CtrlMsg, FlowCtrlMsg, MetaCellHandler, RECV_WINDOW_INIT, STREAM_READER_BUFFER,
-};
+use reactor::{CtrlCmd, CtrlMsg, FlowCtrlMsg, MetaCellHandler, STREAM_READER_BUFFER};
 
 use postage::watch;
 use tor_async_utils::SinkCloseChannel as _;
@@ -45,7 +43,7 @@ use tor_memquota::mq_queue::{ChannelSpec as _, MpscSpec};
 
 #[cfg(feature = "hs-service")]
 use {
-    crate::stream::{IncomingCmdChecker, IncomingStream},
+    crate::stream::{IncomingCmdChecker, IncomingStream, IncomingStreamQueueParams},
     crate::tunnel::reactor::StreamReqInfo,
 };
 
@@ -262,6 +260,33 @@ impl ClientTunnel {
     ///
     /// Only onion services (and eventually) exit relays should call this
     /// method.
+    ///
+    /// Requests that the application has not yet taken from the returned `Stream`
+    /// are queued, up to the default [`IncomingStreamQueueParams`].
+    /// To bound this queue differently, use
+    /// [`allow_stream_requests_with_queue`](ClientTunnel::allow_stream_requests_with_queue).
+    #[cfg(feature = "hs-service")]
+    pub async fn allow_stream_requests<'a, FILT>(
+        self: &Arc<Self>,
+        allow_commands: &'a [tor_cell::relaycell::RelayCmd],
+        hop: TargetHop,
+        filter: FILT,
+    ) -> Result<impl futures::Stream<Item = IncomingStream> + use<'a, FILT>>
+    where
+        FILT: crate::stream::IncomingStreamRequestFilter + 'a,
+    {
+        self.allow_stream_requests_with_queue(
+            allow_commands,
+            hop,
+            filter,
+            &IncomingStreamQueueParams::default(),
+        )
+        .await
+    }
+
+    /// As [`allow_stream_requests`](ClientTunnel::allow_stream_requests),
+    /// but bound the queue of requests that the application has not yet taken
+    /// as `queue` says.
     //
     // TODO: Someday, we might want to allow a stream request handler to be
     // un-registered.  However, nothing in the Tor protocol requires it.
@@ -271,20 +296,18 @@ impl ClientTunnel {
     // will be discarded (along with the reactor of that circuit)
     #[cfg(feature = "hs-service")]
     #[allow(unreachable_code, unused_variables)] // TODO(conflux)
-    pub async fn allow_stream_requests<'a, FILT>(
+    pub async fn allow_stream_requests_with_queue<'a, FILT>(
         self: &Arc<Self>,
         allow_commands: &'a [tor_cell::relaycell::RelayCmd],
         hop: TargetHop,
         filter: FILT,
+        queue: &IncomingStreamQueueParams,
     ) -> Result<impl futures::Stream<Item = IncomingStream> + use<'a, FILT>>
     where
         FILT: crate::stream::IncomingStreamRequestFilter + 'a,
     {
         use futures::stream::StreamExt;
 
-        /// The size of the channel receiving IncomingStreamRequestContexts.
-        const INCOMING_BUFFER: usize = STREAM_READER_BUFFER;
-
         // TODO(#2002): support onion service conflux
         let circ = self.as_single_circ().map_err(tor_error::into_internal!(
             "Cannot allow stream requests on a multi-path tunnel"
@@ -292,14 +315,19 @@ impl ClientTunnel {
 
         let time_prov = circ.time_provider.clone();
         let cmd_checker = IncomingCmdChecker::new_any(allow_commands);
-        let (incoming_sender, incoming_receiver) = MpscSpec::new(INCOMING_BUFFER)
-            .new_mq(time_prov.clone(), circ.memquota.as_raw_account())?;
+        // An mpsc channel has room for one message per sender on top of its buffer;
+        // we have exactly one sender, so this makes its capacity exactly the queue depth.
+        let (incoming_sender, incoming_receiver) =
+            MpscSpec::new(queue.queue_depth().saturating_sub(1))
+                .new_mq(time_prov.clone(), circ.memquota.as_raw_account())?;
         let (tx, rx) = oneshot::channel();
 
         circ.command
             .unbounded_send(CtrlCmd::AwaitStreamRequest {
                 cmd_checker,
                 incoming_sender,
+                overflow: queue.overflow(),
+                rate_limit: queue.rate_limit_config().cloned(),
                 hop,
                 done: tx,
                 filter: Box::new(filter),
@@ -327,6 +355,7 @@ impl ClientTunnel {
                 drain_rate_request_stream,
                 memquota,
                 relay_cell_format,
+                recv_window_init,
             } = req_ctx;
 
             // We already enforce this in handle_incoming_stream_request; this
@@ -357,7 +386,7 @@ impl ClientTunnel {
             let reader = StreamReceiver {
                 target: target.clone(),
                 receiver,
-                recv_window: StreamRecvWindow::new(RECV_WINDOW_INIT),
+                recv_window: StreamRecvWindow::new(recv_window_init),
                 ended: false,
             };
 
@@ -424,7 +453,8 @@ impl ClientTunnel {
             })
             .map_err(|_| Error::CircuitClosed)?;
 
-        let (stream_id, hop, relay_cell_format) = rx.await.map_err(|_| Error::CircuitClosed)??;
+        let (stream_id, hop, relay_cell_format, recv_window_init) =
+            rx.await.map_err(|_| Error::CircuitClosed)??;
 
         let target = StreamTarget {
             tunnel: self.clone(),
@@ -441,7 +471,7 @@ impl ClientTunnel {
         let stream_receiver = StreamReceiver {
             target: target.clone(),
             receiver,
-            recv_window: StreamRecvWindow::new(RECV_WINDOW_INIT),
+            recv_window: StreamRecvWindow::new(recv_window_init),
             ended: false,
         };
 
@@ -457,10 +487,14 @@ impl ClientTunnel {
 
     /// Start a DataStream (anonymized connection) to the given
     /// address and port, using a BEGIN cell.
+    ///
+    /// If `preserve_cell_boundaries` is true, each read from the stream
+    /// returns the body of a single DATA message.
     async fn begin_data_stream(
         self: &Arc<Self>,
         msg: AnyRelayMsg,
         optimistic: bool,
+        preserve_cell_boundaries: bool,
     ) -> Result<DataStream> {
         let components = self
             .begin_stream_impl(msg, DataCmdChecker::new_any())
@@ -479,6 +513,7 @@ impl ClientTunnel {
             xon_xoff_reader_ctrl,
             target,
             memquota,
+            preserve_cell_boundaries,
         );
         if !optimistic {
             stream.wait_for_connection().await?;
@@ -486,6 +521,69 @@ impl ClientTunnel {
         Ok(stream)
     }
 
+    /// Single and multi path helper.
+    ///
+    /// Wait until congestion control lets us send data to the given `hop`.
+    ///
+    /// While a hop's congestion window is full, the reactor won't send any DATA on it,
+    /// but streams opened with [`begin_stream`](Self::begin_stream) are still set up,
+    /// and their data is queued behind that of the existing streams.
+    /// Applications that open many streams can await this first,
+    /// to defer opening more streams on a saturated tunnel.
+    ///
+    /// Returns immediately if the hop is not congested.
+    pub async fn wait_for_send_ready(&self, hop: TargetHop) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.circ
+            .command
+            .unbounded_send(CtrlCmd::WaitForSendReady { hop, done: sender })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Multi path helper.
+    ///
+    /// Return a stream of non-fatal events concerning the legs of this tunnel,
+    /// such as a leg being removed while the rest of the tunnel remains usable.
+    ///
+    /// Only events that happen after this call are reported.
+    /// The stream ends when the tunnel is closed.
+    #[cfg(feature = "conflux")]
+    pub fn conflux_leg_events(
+        &self,
+    ) -> Result<impl futures::Stream<Item = circuit::ConfluxLegEvent> + Send + 'static + use<>>
+    {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        self.circ
+            .command
+            .unbounded_send(CtrlCmd::SubscribeConfluxLegEvents { sender })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        Ok(receiver)
+    }
+
+    /// Single and multi path helper.
+    ///
+    /// Return a snapshot of the flow control state of each open stream to the given `hop`,
+    /// ordered by stream ID.
+    ///
+    /// This is meant for assertions in tests, and for debugging flow control stalls.
+    #[cfg(any(test, feature = "testing"))]
+    #[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+    pub async fn stream_flow_control_states(
+        &self,
+        hop: TargetHop,
+    ) -> Result<Vec<crate::stream::StreamFlowControlState>> {
+        let (sender, receiver) = oneshot::channel();
+        self.circ
+            .command
+            .unbounded_send(CtrlCmd::GetStreamFlowControlStates { hop, done: sender })
+            .map_err(|_| Error::CircuitClosed)?;
+
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
     /// Single and multi path helper.
     ///
     /// Start a stream to the given address and port, using a BEGIN
@@ -502,6 +600,7 @@ impl ClientTunnel {
         let parameters = parameters.unwrap_or_default();
         let begin_flags = parameters.begin_flags();
         let optimistic = parameters.is_optimistic();
+        let preserve_cell_boundaries = parameters.preserving_cell_boundaries();
         let target = if parameters.suppressing_hostname() {
             ""
         } else {
@@ -509,7 +608,8 @@ impl ClientTunnel {
         };
         let beginmsg = Begin::new(target, port, begin_flags)
             .map_err(|e| Error::from_cell_enc(e, "begin message"))?;
-        self.begin_data_stream(beginmsg.into(), optimistic).await
+        self.begin_data_stream(beginmsg.into(), optimistic, preserve_cell_boundaries)
+            .await
     }
 
     /// Start a new stream to the last relay in the tunnel, using
@@ -519,7 +619,7 @@ impl ClientTunnel {
         // Since they are local to a relay that we've already authenticated
         // with and built a tunnel to, there should be no additional checks
         // we need to perform to see whether the BEGINDIR will succeed.
-        self.begin_data_stream(AnyRelayMsg::BeginDir(Default::default()), true)
+        self.begin_data_stream(AnyRelayMsg::BeginDir(Default::default()), true, false)
             .await
     }
 
@@ -36,6 +36,7 @@
 //! This is client-only.
 
 pub(crate) mod celltypes;
+mod cmdfilter;
 pub(crate) mod halfcirc;
 
 #[cfg(feature = "hs-common")]
@@ -49,26 +50,31 @@ pub(crate) mod unique_id;
 use crate::channel::Channel;
 use crate::circuit::handshake::RelayCryptLayerProtocol;
 use crate::congestion::params::CongestionControlParams;
+use crate::congestion::sendme::MAX_SENDME_DELAY;
 use crate::crypto::cell::HopNum;
 use crate::crypto::handshake::ntor_v3::NtorV3PublicKey;
 use crate::memquota::CircuitAccount;
 use crate::tunnel::circuit::celltypes::*;
-use crate::tunnel::reactor::{CircuitHandshake, CtrlCmd, CtrlMsg, Reactor};
+use crate::tunnel::reactor::{
+    CircuitHandshake, CtrlCmd, CtrlMsg, RECV_WINDOW_INIT, Reactor, SEND_WINDOW_INIT,
+};
 use crate::util::skew::ClockSkew;
 use crate::{Error, Result};
 use cfg_if::cfg_if;
 use educe::Educe;
 use path::HopDetail;
 use tor_cell::chancell::CircId;
-use tor_cell::relaycell::RelayCellFormat;
+use tor_cell::relaycell::{RelayCellFormat, RelayCmd};
 use tor_error::{bad_api_usage, internal, into_internal};
 use tor_linkspec::{CircTarget, LinkSpecType, OwnedChanTarget, RelayIdType};
 use tor_protover::named;
 use tor_rtcompat::DynTimeProvider;
 
+pub use crate::congestion::sendme::{SendmeEmission, SendmeTagCheck};
 pub use crate::crypto::binding::CircuitBinding;
 pub use crate::memquota::StreamAccount;
 pub use crate::tunnel::circuit::unique_id::UniqId;
+pub use cmdfilter::{CmdFilterAction, RelayCmdFilter};
 
 use super::{ClientTunnel, TargetHop};
 
@@ -87,8 +93,17 @@ pub use path::{Path, PathEntry};
 /// The size of the buffer for communication between `ClientCirc` and its reactor.
 pub const CIRCUIT_BUFFER_SIZE: usize = 128;
 
+/// The default value of [`CircParameters::max_inbound_drop_cells`].
+pub const DEFAULT_MAX_INBOUND_DROP_CELLS: Option<u64> = Some(0);
+
 pub use crate::tunnel::reactor::syncview::ClientCircSyncView;
 
+#[cfg(feature = "reactor-profiling")]
+pub use crate::tunnel::reactor::profile::{EventTimes, LockWaits, ReactorProfile};
+
+#[cfg(feature = "conflux")]
+pub use crate::tunnel::reactor::{ConfluxLegEvent, RemoveLegReason};
+
 /// MPSC queue relating to a stream (either inbound or outbound), sender
 pub(crate) type StreamMpscSender<T> = mq_queue::Sender<T, MpscSpec>;
 /// MPSC queue relating to a stream (either inbound or outbound), receiver
@@ -362,6 +377,112 @@ pub(super) struct CircuitState {
     binding: Vec<Option<CircuitBinding>>,
 }
 
+/// Information about the relay cells that we send to one hop of a circuit.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RelayCellFormatStats {
+    /// The relay cell format that we negotiated with this hop.
+    pub format: RelayCellFormat,
+    /// The number of relay cells that we have sent to this hop.
+    ///
+    /// All of these cells were encoded using `format`.
+    pub cells_sent: u64,
+}
+
+/// Counts of the relay cells that we received from one hop of a circuit, but did not use.
+///
+/// Unexpected patterns in these cells can be a sign of a side-channel attack;
+/// they are also useful when studying padding.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct DroppedCellStats {
+    /// The number of DROP cells (long-range padding) that we have received from this hop.
+    pub drop_cells: u64,
+    /// The number of cells that we have received from this hop
+    /// for streams that nobody was reading any more, and which we therefore discarded.
+    ///
+    /// Cells for streams on which we have sent an END are not counted:
+    /// the other side can't know that we have closed the stream until it gets our END,
+    /// so we expect them.
+    pub ignored_cells: u64,
+    /// The number of cells from this hop that we discarded
+    /// because their command was not allowed by the hop's [`RelayCmdFilter`].
+    pub rejected_cells: u64,
+}
+
+/// Counts of the relay messages that we received from one hop of a circuit, by command.
+///
+/// Every message is counted, including those we then discard or reject.
+/// Unexpected counts (many DROP or SENDME messages, or any EXTENDED2 messages
+/// on a circuit we are not extending) can be a sign of an attack.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct RelayCmdStats {
+    /// The number of messages received with each command, indexed by command.
+    counts: [u64; 256],
+}
+
+impl Default for RelayCmdStats {
+    fn default() -> Self {
+        Self { counts: [0; 256] }
+    }
+}
+
+impl std::fmt::Debug for RelayCmdStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl RelayCmdStats {
+    /// Return the number of messages with the command `cmd` that we have received.
+    pub fn count(&self, cmd: RelayCmd) -> u64 {
+        self.counts[usize::from(u8::from(cmd))]
+    }
+
+    /// Return the number of DATA messages that we have received.
+    pub fn data_msgs(&self) -> u64 {
+        self.count(RelayCmd::DATA)
+    }
+
+    /// Return the number of SENDME messages that we have received.
+    pub fn sendme_msgs(&self) -> u64 {
+        self.count(RelayCmd::SENDME)
+    }
+
+    /// Return the number of DROP messages that we have received.
+    pub fn drop_msgs(&self) -> u64 {
+        self.count(RelayCmd::DROP)
+    }
+
+    /// Return the number of messages with commands that we don't recognize
+    /// that we have received.
+    pub fn unrecognized_msgs(&self) -> u64 {
+        self.iter()
+            .filter(|(c, _)| !c.is_recognized())
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// Return an iterator over every command that we have received,
+    /// in numerical order, along with the number of messages with that command.
+    pub fn iter(&self) -> impl Iterator<Item = (RelayCmd, u64)> + '_ {
+        (0..=u8::MAX)
+            .zip(self.counts.iter())
+            .filter(|(_, n)| **n > 0)
+            .map(|(cmd, n)| (RelayCmd::from(cmd), *n))
+    }
+
+    /// Note that we have received a message with the command `cmd`.
+    ///
+    /// Returns the number of such messages that we have now received.
+    pub(crate) fn note(&mut self, cmd: RelayCmd) -> u64 {
+        let n = &mut self.counts[usize::from(u8::from(cmd))];
+        *n = n.saturating_add(1);
+        *n
+    }
+}
+
 /// A ClientCirc that needs to send a create cell and receive a created* cell.
 ///
 /// To use one of these, call `create_firsthop_fast()` or `create_firsthop()`
@@ -420,6 +541,72 @@ pub struct CircParameters {
     /// Known limitation: If this value if `u32::MAX`,
     /// then a limit of `u32::MAX - 1` is enforced.
     pub n_outgoing_cells_permitted: Option<u32>,
+
+    /// Restrictions on the relay commands we accept from each hop.
+    ///
+    /// If this value is None, we accept any command that the reactor knows how to handle.
+    pub inbound_cmd_filter: Option<RelayCmdFilter>,
+
+    /// Maximum number of DROP cells that we accept from each hop.
+    ///
+    /// If we receive more DROP cells than this from a single hop,
+    /// we close the circuit with [`ExcessDropCells`](Error::ExcessDropCells).
+    ///
+    /// If this value is None, then there is no limit to the number of DROP cells.
+    ///
+    /// The default is [`DEFAULT_MAX_INBOUND_DROP_CELLS`]:
+    /// since we never negotiate circuit padding, a hop has no business sending us DROP cells,
+    /// so by default we close the circuit on the first one.
+    pub max_inbound_drop_cells: Option<u64>,
+
+    /// Maximum number of cells that we accept from each hop for streams that nobody is reading.
+    ///
+    /// We discard such cells, so an adversary could use them
+    /// to send us a signal that we would never otherwise notice.
+    /// If we receive more of them than this from a single hop,
+    /// we close the circuit with [`ExcessIgnoredCells`](Error::ExcessIgnoredCells).
+    ///
+    /// Some of these cells are normal:
+    /// when a stream is dropped, the other side keeps sending on it until it gets our END,
+    /// which can be up to a full stream window of cells.
+    /// (Cells that arrive after we have sent our END aren't counted.)
+    ///
+    /// If this value is None, then there is no limit to the number of ignored cells.
+    pub max_inbound_ignored_cells: Option<u64>,
+
+    /// When to send the circuit-level SENDMEs for the cells we receive from each hop.
+    ///
+    /// Latency-sensitive circuits should leave this at [`SendmeEmission::Immediate`].
+    pub sendme_emission: SendmeEmission,
+
+    /// How strictly to check the tags of the circuit-level SENDMEs we receive from each hop.
+    ///
+    /// Anything other than [`SendmeTagCheck::Strict`] violates the protocol,
+    /// and should only be used for testing or diagnostics.
+    pub sendme_tag_check: SendmeTagCheck,
+
+    /// The initial size, in cells, of our send window on each stream we open to each hop.
+    ///
+    /// This only matters for streams that use SENDME-based flow control
+    /// (that is, on hops where we don't negotiate congestion control).
+    ///
+    /// The hop expects us to send at most its own receive window before it sends a SENDME,
+    /// so this must be at least 1, and at most the protocol default of 500 cells.
+    ///
+    /// Defaults to that protocol default.
+    pub stream_send_window_init: u16,
+
+    /// The initial size, in cells, of our receive window on each stream to each hop.
+    ///
+    /// This only matters for streams that use SENDME-based flow control.
+    ///
+    /// The hop sends us its whole send window before it waits for a SENDME,
+    /// so this must match that window for the stream to work:
+    /// it must be at least 1, and at most the protocol default of 500 cells.
+    /// Real relays use the default, so anything smaller breaks streams to them.
+    ///
+    /// Defaults to that protocol default.
+    pub stream_recv_window_init: u16,
 }
 
 /// Type of negotiation that we'll be performing as we establish a hop.
@@ -468,6 +655,27 @@ pub(super) struct HopSettings {
     /// Maximum number of permitted outgoing relay cells for this hop.
     pub(super) n_outgoing_cells_permitted: Option<u32>,
 
+    /// Restrictions on the relay commands we accept from this hop.
+    pub(super) inbound_cmd_filter: Option<RelayCmdFilter>,
+
+    /// Maximum number of DROP cells that we accept from this hop.
+    pub(super) max_inbound_drop_cells: Option<u64>,
+
+    /// Maximum number of cells for closed streams that we accept from this hop.
+    pub(super) max_inbound_ignored_cells: Option<u64>,
+
+    /// When to send circuit-level SENDMEs to this hop.
+    pub(super) sendme_emission: SendmeEmission,
+
+    /// How strictly to check the tags of circuit-level SENDMEs from this hop.
+    pub(super) sendme_tag_check: SendmeTagCheck,
+
+    /// The initial send window for the streams we open to this hop.
+    pub(super) stream_send_window_init: u16,
+
+    /// The initial receive window for the streams to this hop.
+    pub(super) stream_recv_window_init: u16,
+
     /// The relay cell encryption algorithm and cell format for this hop.
     relay_crypt_protocol: RelayCryptLayerProtocol,
 }
@@ -482,12 +690,40 @@ impl HopSettings {
     ///
     /// This represents the `HopSettings` in a pre-negotiation state:
     /// the circuit negotiation process will modify it.
-    #[allow(clippy::unnecessary_wraps)] // likely to become fallible in the future.
+    ///
+    /// Returns an error if `params` asks for stream windows that we can't use,
+    /// or for a SENDME delay that is too long.
     pub(super) fn from_params_and_caps(
         hoptype: HopNegotiationType,
         params: &CircParameters,
         caps: &tor_protover::Protocols,
     ) -> Result<Self> {
+        let (stream_send_window_init, stream_recv_window_init) = {
+            if !(1..=SEND_WINDOW_INIT).contains(&params.stream_send_window_init) {
+                return Err(bad_api_usage!(
+                    "Stream send window {} out of range",
+                    params.stream_send_window_init
+                )
+                .into());
+            }
+            if !(1..=RECV_WINDOW_INIT).contains(&params.stream_recv_window_init) {
+                return Err(bad_api_usage!(
+                    "Stream receive window {} out of range",
+                    params.stream_recv_window_init
+                )
+                .into());
+            }
+            (
+                params.stream_send_window_init,
+                params.stream_recv_window_init,
+            )
+        };
+        if let SendmeEmission::Delayed(delay) = params.sendme_emission {
+            if delay > MAX_SENDME_DELAY {
+                return Err(bad_api_usage!("SENDME delay {:?} too long", delay).into());
+            }
+        }
+
         let mut ccontrol = params.ccontrol.clone();
         match ccontrol.alg() {
             crate::ccparams::Algorithm::FixedWindow(_) => {}
@@ -548,6 +784,13 @@ impl HopSettings {
             relay_crypt_protocol,
             n_incoming_cells_permitted: params.n_incoming_cells_permitted,
             n_outgoing_cells_permitted: params.n_outgoing_cells_permitted,
+            inbound_cmd_filter: params.inbound_cmd_filter.clone(),
+            max_inbound_drop_cells: params.max_inbound_drop_cells,
+            max_inbound_ignored_cells: params.max_inbound_ignored_cells,
+            sendme_emission: params.sendme_emission,
+            sendme_tag_check: params.sendme_tag_check,
+            stream_send_window_init,
+            stream_recv_window_init,
         })
     }
 
@@ -565,6 +808,13 @@ impl std::default::Default for CircParameters {
             ccontrol: crate::congestion::test_utils::params::build_cc_fixed_params(),
             n_incoming_cells_permitted: None,
             n_outgoing_cells_permitted: None,
+            inbound_cmd_filter: None,
+            max_inbound_drop_cells: DEFAULT_MAX_INBOUND_DROP_CELLS,
+            max_inbound_ignored_cells: None,
+            sendme_emission: SendmeEmission::default(),
+            sendme_tag_check: SendmeTagCheck::default(),
+            stream_send_window_init: SEND_WINDOW_INIT,
+            stream_recv_window_init: RECV_WINDOW_INIT,
         }
     }
 }
@@ -577,6 +827,13 @@ impl CircParameters {
             ccontrol,
             n_incoming_cells_permitted: None,
             n_outgoing_cells_permitted: None,
+            inbound_cmd_filter: None,
+            max_inbound_drop_cells: DEFAULT_MAX_INBOUND_DROP_CELLS,
+            max_inbound_ignored_cells: None,
+            sendme_emission: SendmeEmission::default(),
+            sendme_tag_check: SendmeTagCheck::default(),
+            stream_send_window_init: SEND_WINDOW_INIT,
+            stream_recv_window_init: RECV_WINDOW_INIT,
         }
     }
 }
@@ -703,6 +960,105 @@ impl ClientCirc {
         receiver.await.map_err(|_| Error::CircuitClosed)?
     }
 
+    /// Return the relay cell format used by each hop of this circuit, in order,
+    /// along with the number of relay cells we have sent to that hop.
+    ///
+    /// This lets callers check which relay cell formats are actually being negotiated.
+    pub async fn relay_cell_format_stats(&self) -> Result<Vec<RelayCellFormatStats>> {
+        let (sender, receiver) = oneshot::channel();
+        let msg = CtrlCmd::GetRelayCellFormatStats {
+            leg: self.unique_id,
+            done: sender,
+        };
+        self.command
+            .unbounded_send(msg)
+            .map_err(|_| Error::CircuitClosed)?;
+
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Return counts of the cells that each hop of this circuit has sent us,
+    /// but which we did not use, in order.
+    ///
+    /// See [`DroppedCellStats`].
+    pub async fn dropped_cell_stats(&self) -> Result<Vec<DroppedCellStats>> {
+        let (sender, receiver) = oneshot::channel();
+        let msg = CtrlCmd::GetDroppedCellStats {
+            leg: self.unique_id,
+            done: sender,
+        };
+        self.command
+            .unbounded_send(msg)
+            .map_err(|_| Error::CircuitClosed)?;
+
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Return counts of the relay messages that each hop of this circuit has sent us,
+    /// by command, in order.
+    ///
+    /// See [`RelayCmdStats`].
+    pub async fn relay_cmd_stats(&self) -> Result<Vec<RelayCmdStats>> {
+        let (sender, receiver) = oneshot::channel();
+        let msg = CtrlCmd::GetRelayCmdStats {
+            leg: self.unique_id,
+            done: sender,
+        };
+        self.command
+            .unbounded_send(msg)
+            .map_err(|_| Error::CircuitClosed)?;
+
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Wait until we have received at least `threshold` relay messages
+    /// with the command `cmd` from the given `hop`.
+    ///
+    /// Returns the hop's [`RelayCmdStats`] at the moment the threshold was reached,
+    /// which may be immediately.
+    /// Returns [`Error::CircuitClosed`] if the circuit closes first.
+    ///
+    /// Callers can use this to react to unusual patterns of relay messages
+    /// (for example, by closing the circuit) without polling
+    /// [`relay_cmd_stats`](Self::relay_cmd_stats).
+    pub async fn wait_for_relay_cmd_count(
+        &self,
+        hop: TargetHop,
+        cmd: RelayCmd,
+        threshold: u64,
+    ) -> Result<RelayCmdStats> {
+        let (sender, receiver) = oneshot::channel();
+        let msg = CtrlCmd::WaitForRelayCmdCount {
+            hop,
+            cmd,
+            threshold,
+            done: sender,
+        };
+        self.command
+            .unbounded_send(msg)
+            .map_err(|_| Error::CircuitClosed)?;
+
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
+    /// Return a snapshot of the time that this circuit's reactor has spent
+    /// on different kinds of work for this circuit.
+    ///
+    /// See [`ReactorProfile`].
+    #[cfg(feature = "reactor-profiling")]
+    pub async fn reactor_profile(&self) -> Result<ReactorProfile> {
+        let (sender, receiver) = oneshot::channel();
+        let msg = CtrlCmd::GetReactorProfile {
+            leg: self.unique_id,
+            done: sender,
+        };
+        self.command
+            .unbounded_send(msg)
+            .map_err(|_| Error::CircuitClosed)?;
+
+        receiver.await.map_err(|_| Error::CircuitClosed)?
+    }
+
     /// Extend the circuit, via the most appropriate circuit extension handshake,
     /// to the chosen `target` hop.
     pub async fn extend<Tg>(&self, target: &Tg, params: CircParameters) -> Result<()>
@@ -1116,9 +1472,9 @@ pub(crate) mod test {
     use crate::crypto::cell::RelayCellBody;
     use crate::crypto::handshake::ntor_v3::NtorV3Server;
     use crate::memquota::SpecificAccount as _;
-    use crate::stream::DataStream;
     #[cfg(feature = "hs-service")]
     use crate::stream::IncomingStreamRequestFilter;
+    use crate::stream::{DataStream, StreamParameters};
     use chanmsg::{AnyChanMsg, Created2, CreatedFast};
     use futures::channel::mpsc::{Receiver, Sender};
     use futures::io::{AsyncReadExt, AsyncWriteExt};
@@ -1145,7 +1501,7 @@ pub(crate) mod test {
 
     #[cfg(feature = "conflux")]
     use {
-        crate::tunnel::reactor::ConfluxHandshakeResult,
+        crate::tunnel::reactor::{ConfluxHandshakeResult, ConfluxLegEvent, RemoveLegReason},
         crate::util::err::ConfluxHandshakeError,
         futures::future::FusedFuture,
         futures::lock::Mutex as AsyncMutex,
@@ -1530,6 +1886,15 @@ pub(crate) mod test {
     async fn newtunnel<R: Runtime>(
         rt: &R,
         chan: Arc<Channel>,
+    ) -> (Arc<ClientTunnel>, CircuitRxSender) {
+        newtunnel_with_params(rt, chan, CircParameters::default()).await
+    }
+
+    // Helper: like newtunnel, but with the given circuit parameters.
+    async fn newtunnel_with_params<R: Runtime>(
+        rt: &R,
+        chan: Arc<Channel>,
+        params: CircParameters,
     ) -> (Arc<ClientTunnel>, CircuitRxSender) {
         let hops = std::iter::repeat_with(|| {
             let peer_id = tor_linkspec::OwnedChanTarget::builder()
@@ -1544,15 +1909,8 @@ pub(crate) mod test {
         .collect();
 
         let unique_id = UniqId::new(23, 17);
-        let (tunnel, circmsg_send) = newtunnel_ext(
-            rt,
-            unique_id,
-            chan,
-            hops,
-            2.into(),
-            CircParameters::default(),
-        )
-        .await;
+        let (tunnel, circmsg_send) =
+            newtunnel_ext(rt, unique_id, chan, hops, 2.into(), params).await;
 
         (Arc::new(tunnel), circmsg_send)
     }
@@ -1879,32 +2237,94 @@ pub(crate) mod test {
         });
     }
 
-    // Test: close a stream, either by dropping it or by calling AsyncWriteExt::close.
-    fn close_stream_helper(by_drop: bool) {
+    #[traced_test]
+    #[test]
+    fn close_write() {
         tor_rtcompat::test_with_all_runtimes!(|rt| async move {
             let (chan, mut rx, _sink) = working_fake_channel(&rt);
             let (tunnel, mut sink) = newtunnel(&rt, chan).await;
 
-            let stream_fut = async move {
-                let stream = tunnel
-                    .begin_stream("www.example.com", 80, None)
-                    .await
-                    .unwrap();
+            let begin_and_send_fut = async move {
+                let mut stream = tunnel.begin_dir_stream().await.unwrap();
+                stream.write_all(b"HTTP/1.0 GET /\r\n").await.unwrap();
+                // Closing the write side flushes our data, and sends an END...
+                stream.close_write().await.unwrap();
+                // ...after which we can't write any more...
+                assert!(stream.write_all(b"more").await.is_err());
+                // ...but we can still read the reply.
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                assert_eq!(&buf[..], b"HTTP/1.0 404 Not found\r\n");
+                stream
+            };
+            let reply_fut = async move {
+                // Read the BEGIN_DIR, and reply with a CONNECTED.
+                let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert!(matches!(rmsg, AnyRelayMsg::BeginDir(_)));
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
 
-                let (r, mut w) = stream.split();
-                if by_drop {
-                    // Drop the writer and the reader, which should close the stream.
-                    drop(r);
-                    drop(w);
-                    (None, tunnel) // make sure to keep the circuit alive
+                // We get the DATA, and then the END...
+                let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (_, rmsg) = rmsg.into_streamid_and_msg();
+                if let AnyRelayMsg::Data(d) = rmsg {
+                    assert_eq!(d.as_ref(), &b"HTTP/1.0 GET /\r\n"[..]);
                 } else {
-                    // Call close on the writer, while keeping the reader alive.
-                    w.close().await.unwrap();
-                    (Some(r), tunnel)
+                    panic!();
                 }
+                let (_, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match chmsg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (_, rmsg) = rmsg.into_streamid_and_msg();
+                assert!(matches!(rmsg, AnyRelayMsg::End(_)));
+
+                // ...but the stream is still open for reading, so we can reply.
+                let data = relaymsg::Data::new(b"HTTP/1.0 404 Not found\r\n")
+                    .unwrap()
+                    .into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
+                sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+
+                (rx, sink) // gotta keep these alive, or the reactor will exit.
             };
-            let handler_fut = async {
-                // Read the BEGIN message.
+
+            let (_stream, (_rx, _sink)) = futures::join!(begin_and_send_fut, reply_fut);
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn preserve_cell_boundaries() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut sink) = newtunnel(&rt, chan).await;
+
+            let mut params = StreamParameters::new();
+            params.preserve_cell_boundaries(true);
+            let begin_fut = tunnel.begin_stream("www.example.com", 80, Some(params));
+            let reply_fut = async {
+                // Read the BEGIN message, and reply with a CONNECTED.
                 let (_, msg) = rx.next().await.unwrap().into_circid_and_msg();
                 let rmsg = match msg {
                     AnyChanMsg::Relay(r) => {
@@ -1915,13 +2335,54 @@ pub(crate) mod test {
                 };
                 let (streamid, rmsg) = rmsg.into_streamid_and_msg();
                 assert_eq!(rmsg.cmd(), RelayCmd::BEGIN);
-
-                // Reply with a CONNECTED.
-                let connected =
-                    relaymsg::Connected::new_with_addr("10.0.0.1".parse().unwrap(), 1234).into();
+                let connected = relaymsg::Connected::new_empty().into();
                 sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
 
-                // Expect an END.
+                // Send a few DATA messages, including an empty one, and an END.
+                for body in [&b"hello"[..], b"", b"world"] {
+                    let data = relaymsg::Data::new(body).unwrap().into();
+                    sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+                }
+                let end = relaymsg::End::new_with_reason(relaymsg::EndReason::DONE).into();
+                sink.send(rmsg_to_ccmsg(streamid, end)).await.unwrap();
+            };
+            let (stream, ()) = futures::join!(begin_fut, reply_fut);
+            let mut stream = stream.unwrap();
+
+            // A buffer that is too small for the message is rejected,
+            // without losing any data.
+            let mut buf = [0_u8; 3];
+            let err = stream.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+            let mut buf = [0_u8; relaymsg::Data::MAXLEN];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"hello");
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"world");
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_eq!(n, 0);
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn relay_cell_format_stats() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut sink) = newtunnel(&rt, chan).await;
+            let circ = tunnel.as_single_circ().unwrap();
+
+            let stats = circ.relay_cell_format_stats().await.unwrap();
+            assert_eq!(stats.len(), 3);
+            for hop in &stats {
+                assert!(matches!(hop.format, RelayCellFormat::V0));
+                assert_eq!(hop.cells_sent, 0);
+            }
+
+            let begin_fut = tunnel.begin_stream("www.example.com", 80, None);
+            let reply_fut = async {
+                // Read the BEGIN message, and reply with a CONNECTED.
                 let (_, msg) = rx.next().await.unwrap().into_circid_and_msg();
                 let rmsg = match msg {
                     AnyChanMsg::Relay(r) => {
@@ -1930,22 +2391,473 @@ pub(crate) mod test {
                     }
                     other => panic!("{:?}", other),
                 };
-                let (_, rmsg) = rmsg.into_streamid_and_msg();
-                assert_eq!(rmsg.cmd(), RelayCmd::END);
-
-                (rx, sink) // keep these alive or the reactor will exit.
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert_eq!(rmsg.cmd(), RelayCmd::BEGIN);
+                let connected = relaymsg::Connected::new_empty().into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
             };
+            let (stream, ()) = futures::join!(begin_fut, reply_fut);
+            let _stream = stream.unwrap();
 
-            let ((_opt_reader, _circ), (_rx, _sink)) = futures::join!(stream_fut, handler_fut);
+            // Only the last hop has seen a cell.
+            let stats = circ.relay_cell_format_stats().await.unwrap();
+            let cells_sent: Vec<_> = stats.iter().map(|hop| hop.cells_sent).collect();
+            assert_eq!(cells_sent, [0, 0, 1]);
         });
     }
 
     #[traced_test]
     #[test]
-    fn drop_stream() {
-        close_stream_helper(true);
-    }
-
+    fn dropped_cell_stats() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let hops = std::iter::repeat_with(|| {
+                let peer_id = tor_linkspec::OwnedChanTarget::builder()
+                    .ed_identity([4; 32].into())
+                    .rsa_identity([5; 20].into())
+                    .build()
+                    .expect("Could not construct fake hop");
+
+                path::HopDetail::Relay(peer_id)
+            })
+            .take(3)
+            .collect();
+            let mut params = CircParameters::default();
+            params.max_inbound_drop_cells = Some(2);
+            let (tunnel, mut sink) =
+                newtunnel_ext(&rt, UniqId::new(23, 17), chan, hops, 2.into(), params).await;
+            let circ = tunnel.as_single_circ().unwrap();
+
+            // DROP cells are counted, but don't close the circuit.
+            for _ in 0..2 {
+                let drop_msg = relaymsg::Drop::default().into();
+                sink.send(rmsg_to_ccmsg(None, drop_msg)).await.unwrap();
+            }
+            rt.advance_until_stalled().await;
+            assert!(!tunnel.is_closed());
+
+            let stats = circ.dropped_cell_stats().await.unwrap();
+            let drop_cells: Vec<_> = stats.iter().map(|hop| hop.drop_cells).collect();
+            assert_eq!(drop_cells, [0, 0, 2]);
+            assert!(stats.iter().all(|hop| hop.ignored_cells == 0));
+
+            // ...until there are too many of them.
+            let drop_msg = relaymsg::Drop::default().into();
+            sink.send(rmsg_to_ccmsg(None, drop_msg)).await.unwrap();
+            rt.advance_until_stalled().await;
+            assert!(tunnel.is_closed());
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn default_drop_cell_limit() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut sink) = newtunnel(&rt, chan).await;
+
+            // We never negotiate padding, so by default a single DROP cell is too many.
+            let drop_msg = relaymsg::Drop::default().into();
+            sink.send(rmsg_to_ccmsg(None, drop_msg)).await.unwrap();
+            rt.advance_until_stalled().await;
+            assert!(tunnel.is_closed());
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn cells_after_end_not_ignored() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let mut params = CircParameters::default();
+            params.max_inbound_ignored_cells = Some(0);
+            let (tunnel, mut sink) = newtunnel_with_params(&rt, chan, params).await;
+
+            let stream_fut = async {
+                let stream = tunnel
+                    .begin_stream("www.example.com", 80, None)
+                    .await
+                    .unwrap();
+                // Dropping the stream makes us send an END.
+                drop(stream);
+            };
+            let handler_fut = async {
+                let (_, msg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match msg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert_eq!(rmsg.cmd(), RelayCmd::BEGIN);
+                let connected =
+                    relaymsg::Connected::new_with_addr("10.0.0.1".parse().unwrap(), 1234).into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+                let (_, msg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match msg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                assert_eq!(rmsg.cmd(), RelayCmd::END);
+                streamid
+            };
+            let ((), streamid) = futures::join!(stream_fut, handler_fut);
+
+            // The exit hasn't seen our END yet, so it keeps sending data.
+            for _ in 0..3 {
+                let data = relaymsg::Data::new(b"late").unwrap().into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            }
+            rt.advance_until_stalled().await;
+
+            // That's expected, so we don't count those cells, and the circuit stays open.
+            assert!(!tunnel.is_closed());
+            let circ = tunnel.as_single_circ().unwrap();
+            let stats = circ.dropped_cell_stats().await.unwrap();
+            assert!(stats.iter().all(|hop| hop.ignored_cells == 0));
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn relay_cmd_stats() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let mut params = CircParameters::default();
+            params.max_inbound_drop_cells = None;
+            let (tunnel, mut sink) = newtunnel_with_params(&rt, chan, params).await;
+            let circ = tunnel.as_single_circ().unwrap();
+
+            // Nothing received yet, so a threshold of zero is reached at once.
+            let stats = circ
+                .wait_for_relay_cmd_count(TargetHop::LastHop, RelayCmd::DROP, 0)
+                .await
+                .unwrap();
+            assert_eq!(stats.drop_msgs(), 0);
+
+            let mut waiter = std::pin::pin!(circ.wait_for_relay_cmd_count(
+                TargetHop::LastHop,
+                RelayCmd::DROP,
+                2
+            ));
+            assert!(futures::poll!(&mut waiter).is_pending());
+            rt.advance_until_stalled().await;
+
+            for _ in 0..3 {
+                let drop_msg = relaymsg::Drop::default().into();
+                sink.send(rmsg_to_ccmsg(None, drop_msg)).await.unwrap();
+            }
+            rt.advance_until_stalled().await;
+
+            // The waiter saw the stats as of the second DROP.
+            let stats = waiter.await.unwrap();
+            assert_eq!(stats.drop_msgs(), 2);
+
+            let stats = circ.relay_cmd_stats().await.unwrap();
+            let drops: Vec<_> = stats.iter().map(RelayCmdStats::drop_msgs).collect();
+            assert_eq!(drops, [0, 0, 3]);
+            assert_eq!(stats[2].data_msgs(), 0);
+            assert_eq!(stats[2].unrecognized_msgs(), 0);
+            assert_eq!(stats[2].iter().collect::<Vec<_>>(), [(RelayCmd::DROP, 3)]);
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn relay_cmd_waiters_pruned() {
+        use crate::tunnel::reactor::step::StepOutcome;
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (_created_send, created_recv) = oneshot::channel();
+            let (_circmsg_send, circmsg_recv) = fake_mpsc(64);
+            let (pending, mut reactor) = PendingClientTunnel::new(
+                CircId::new(128).unwrap(),
+                chan,
+                created_recv,
+                circmsg_recv,
+                UniqId::new(23, 17),
+                DynTimeProvider::new(rt.clone()),
+                CircuitAccount::new_noop(),
+            );
+            let circ = pending.circ;
+
+            let mut params = CircParameters::default();
+            params.max_inbound_drop_cells = None;
+            for (idx, peer_id) in hop_details(3, 0).into_iter().enumerate() {
+                let (tx, rx) = oneshot::channel();
+                circ.command
+                    .unbounded_send(CtrlCmd::AddFakeHop {
+                        relay_cell_format: RelayCellFormat::V0,
+                        fwd_lasthop: idx == 2,
+                        rev_lasthop: idx == 2,
+                        peer_id,
+                        params: params.clone(),
+                        done: tx,
+                    })
+                    .unwrap();
+                assert_eq!(reactor.step().await.unwrap(), StepOutcome::Continue);
+                rx.await.unwrap().unwrap();
+            }
+            let leg = reactor.primary_leg();
+            let last_hop = HopNum::from(2);
+
+            let wait_for_drops = |threshold| {
+                let (tx, rx) = oneshot::channel();
+                circ.command
+                    .unbounded_send(CtrlCmd::WaitForRelayCmdCount {
+                        hop: TargetHop::LastHop,
+                        cmd: RelayCmd::DROP,
+                        threshold,
+                        done: tx,
+                    })
+                    .unwrap();
+                rx
+            };
+
+            // One caller keeps waiting, and many give up.
+            let mut waiting = wait_for_drops(2);
+            assert_eq!(reactor.step().await.unwrap(), StepOutcome::Continue);
+            for _ in 0..10 {
+                drop(wait_for_drops(2));
+                assert_eq!(reactor.step().await.unwrap(), StepOutcome::Continue);
+            }
+            // We forget about the callers who gave up whenever somebody new starts waiting...
+            assert_eq!(reactor.n_relay_cmd_waiters(leg, last_hop), Some(2));
+
+            // ...and whenever we count a command.
+            let drop_msg = rmsg_to_ccmsg(None, relaymsg::Drop::default().into());
+            let outcome = reactor.inject_cell(leg, drop_msg).await.unwrap();
+            assert_eq!(outcome, StepOutcome::Continue);
+            assert_eq!(reactor.n_relay_cmd_waiters(leg, last_hop), Some(1));
+            assert!(futures::poll!(&mut waiting).is_pending());
+
+            let drop_msg = rmsg_to_ccmsg(None, relaymsg::Drop::default().into());
+            let outcome = reactor.inject_cell(leg, drop_msg).await.unwrap();
+            assert_eq!(outcome, StepOutcome::Continue);
+            assert_eq!(reactor.n_relay_cmd_waiters(leg, last_hop), Some(0));
+            assert_eq!(waiting.await.unwrap().unwrap().drop_msgs(), 2);
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn step_reactor() {
+        use crate::tunnel::reactor::step::StepOutcome;
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (_created_send, created_recv) = oneshot::channel();
+            let (_circmsg_send, circmsg_recv) = fake_mpsc(64);
+            let (pending, mut reactor) = PendingClientTunnel::new(
+                CircId::new(128).unwrap(),
+                chan,
+                created_recv,
+                circmsg_recv,
+                UniqId::new(23, 17),
+                DynTimeProvider::new(rt.clone()),
+                CircuitAccount::new_noop(),
+            );
+            let circ = pending.circ;
+
+            // Add our hops, handling one command at a time.
+            let mut params = CircParameters::default();
+            params.max_inbound_drop_cells = Some(2);
+            for (idx, peer_id) in hop_details(3, 0).into_iter().enumerate() {
+                let (tx, rx) = oneshot::channel();
+                circ.command
+                    .unbounded_send(CtrlCmd::AddFakeHop {
+                        relay_cell_format: RelayCellFormat::V0,
+                        fwd_lasthop: idx == 2,
+                        rev_lasthop: idx == 2,
+                        peer_id,
+                        params: params.clone(),
+                        done: tx,
+                    })
+                    .unwrap();
+                assert_eq!(reactor.step().await.unwrap(), StepOutcome::Continue);
+                rx.await.unwrap().unwrap();
+            }
+            assert_eq!(circ.n_hops().unwrap(), 3);
+
+            // Inject cells directly, bypassing the channel.
+            let leg = reactor.primary_leg();
+            for _ in 0..2 {
+                let drop_msg = rmsg_to_ccmsg(None, relaymsg::Drop::default().into());
+                let outcome = reactor.inject_cell(leg, drop_msg).await.unwrap();
+                assert_eq!(outcome, StepOutcome::Continue);
+            }
+
+            // There is no such leg.
+            let drop_msg = rmsg_to_ccmsg(None, relaymsg::Drop::default().into());
+            assert!(
+                reactor
+                    .inject_cell(UniqId::new(23, 18), drop_msg)
+                    .await
+                    .is_err()
+            );
+
+            // One DROP cell too many is a protocol violation.
+            let drop_msg = rmsg_to_ccmsg(None, relaymsg::Drop::default().into());
+            assert!(reactor.inject_cell(leg, drop_msg).await.is_err());
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn wait_for_send_ready() {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, _sink) = newtunnel(&rt, chan).await;
+
+            // A fresh circuit isn't congested.
+            tunnel
+                .wait_for_send_ready(TargetHop::LastHop)
+                .await
+                .unwrap();
+
+            // There is no fourth hop.
+            let hop: TargetHop = (tunnel.unique_id(), 3_u8.into()).into();
+            assert!(tunnel.wait_for_send_ready(hop).await.is_err());
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn wait_for_send_ready_after_sendme() {
+        use crate::ccparams::{
+            Algorithm, CongestionControlParamsBuilder, FixedWindowParamsBuilder,
+        };
+        use crate::congestion::test_utils::params::{build_cwnd_params, build_rtt_params};
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            // A circuit window with room for exactly 100 cells.
+            let fixed = FixedWindowParamsBuilder::default()
+                .circ_window_start(100)
+                .circ_window_min(100)
+                .circ_window_max(1000)
+                .build()
+                .unwrap();
+            let mut params = CircParameters::default();
+            params.ccontrol = CongestionControlParamsBuilder::default()
+                .rtt_params(build_rtt_params())
+                .cwnd_params(build_cwnd_params())
+                .alg(Algorithm::FixedWindow(fixed))
+                .fixed_window_params(fixed)
+                .build()
+                .unwrap();
+
+            let (tunnel, _stream, mut sink, _streamid, cells_received, _rx, _sink2) =
+                setup_incoming_sendme_case(&rt, 100 * 498, params).await;
+            let circ = tunnel.as_single_circ().unwrap();
+            assert_eq!(cells_received, 100);
+
+            // The window of the last hop is now exhausted.
+            let (tx, rx) = oneshot::channel();
+            circ.command
+                .unbounded_send(CtrlCmd::QuerySendWindow {
+                    hop: 2.into(),
+                    leg: tunnel.unique_id(),
+                    done: tx,
+                })
+                .unwrap();
+            let (window, tags) = rx.await.unwrap().unwrap();
+            assert_eq!(window, 0);
+            assert_eq!(
+                tags,
+                vec![SendmeTag::from(hex!(
+                    "6400000000000000000000000000000000000000"
+                ))]
+            );
+
+            // So waiting for it to be ready blocks...
+            let mut ready = Box::pin(tunnel.wait_for_send_ready(TargetHop::LastHop));
+            assert!(futures::poll!(&mut ready).is_pending());
+            rt.advance_until_stalled().await;
+            assert!(futures::poll!(&mut ready).is_pending());
+
+            // ...until a circuit-level SENDME reopens the window.
+            let c_sendme =
+                relaymsg::Sendme::new_tag(hex!("6400000000000000000000000000000000000000")).into();
+            sink.send(rmsg_to_ccmsg(None, c_sendme)).await.unwrap();
+            rt.advance_until_stalled().await;
+            ready.await.unwrap();
+        });
+    }
+
+    // Test: close a stream, either by dropping it or by calling AsyncWriteExt::close.
+    fn close_stream_helper(by_drop: bool) {
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut sink) = newtunnel(&rt, chan).await;
+
+            let stream_fut = async move {
+                let stream = tunnel
+                    .begin_stream("www.example.com", 80, None)
+                    .await
+                    .unwrap();
+
+                let (r, mut w) = stream.split();
+                if by_drop {
+                    // Drop the writer and the reader, which should close the stream.
+                    drop(r);
+                    drop(w);
+                    (None, tunnel) // make sure to keep the circuit alive
+                } else {
+                    // Call close on the writer, while keeping the reader alive.
+                    w.close().await.unwrap();
+                    (Some(r), tunnel)
+                }
+            };
+            let handler_fut = async {
+                // Read the BEGIN message.
+                let (_, msg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match msg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+                assert_eq!(rmsg.cmd(), RelayCmd::BEGIN);
+
+                // Reply with a CONNECTED.
+                let connected =
+                    relaymsg::Connected::new_with_addr("10.0.0.1".parse().unwrap(), 1234).into();
+                sink.send(rmsg_to_ccmsg(streamid, connected)).await.unwrap();
+
+                // Expect an END.
+                let (_, msg) = rx.next().await.unwrap().into_circid_and_msg();
+                let rmsg = match msg {
+                    AnyChanMsg::Relay(r) => {
+                        AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                            .unwrap()
+                    }
+                    other => panic!("{:?}", other),
+                };
+                let (_, rmsg) = rmsg.into_streamid_and_msg();
+                assert_eq!(rmsg.cmd(), RelayCmd::END);
+
+                (rx, sink) // keep these alive or the reactor will exit.
+            };
+
+            let ((_opt_reader, _circ), (_rx, _sink)) = futures::join!(stream_fut, handler_fut);
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn drop_stream() {
+        close_stream_helper(true);
+    }
+
     #[traced_test]
     #[test]
     fn close_stream() {
@@ -1956,6 +2868,7 @@ pub(crate) mod test {
     async fn setup_incoming_sendme_case<R: Runtime>(
         rt: &R,
         n_to_send: usize,
+        params: CircParameters,
     ) -> (
         Arc<ClientTunnel>,
         DataStream,
@@ -1966,7 +2879,7 @@ pub(crate) mod test {
         Sender<std::result::Result<OpenChanCellS2C, CodecError>>,
     ) {
         let (chan, mut rx, sink2) = working_fake_channel(rt);
-        let (tunnel, mut sink) = newtunnel(rt, chan).await;
+        let (tunnel, mut sink) = newtunnel_with_params(rt, chan, params).await;
         let circid = tunnel.as_single_circ().unwrap().peek_circid();
 
         let begin_and_send_fut = {
@@ -2043,7 +2956,7 @@ pub(crate) mod test {
     fn accept_valid_sendme() {
         tor_rtmock::MockRuntime::test_with_various(|rt| async move {
             let (tunnel, _stream, mut sink, streamid, cells_received, _rx, _sink2) =
-                setup_incoming_sendme_case(&rt, 300 * 498 + 3).await;
+                setup_incoming_sendme_case(&rt, 300 * 498 + 3, CircParameters::default()).await;
             let circ = tunnel.as_single_circ().unwrap();
 
             assert_eq!(cells_received, 301);
@@ -2096,20 +3009,244 @@ pub(crate) mod test {
 
             rt.advance_until_stalled().await;
 
-            // Now make sure that the circuit is still happy, and its
-            // window is updated.
-            {
-                let (tx, rx) = oneshot::channel();
-                circ.command
-                    .unbounded_send(CtrlCmd::QuerySendWindow {
-                        hop: 2.into(),
-                        leg: tunnel.unique_id(),
-                        done: tx,
+            // Now make sure that the circuit is still happy, and its
+            // window is updated.
+            {
+                let (tx, rx) = oneshot::channel();
+                circ.command
+                    .unbounded_send(CtrlCmd::QuerySendWindow {
+                        hop: 2.into(),
+                        leg: tunnel.unique_id(),
+                        done: tx,
+                    })
+                    .unwrap();
+                let (window, _tags) = rx.await.unwrap().unwrap();
+                assert_eq!(window, 1000 - 201);
+            }
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn stream_flow_control_states() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (tunnel, _stream, mut sink, streamid, cells_received, _rx, _sink2) =
+                setup_incoming_sendme_case(&rt, 300 * 498 + 3, CircParameters::default()).await;
+            assert_eq!(cells_received, 301);
+            let hop = tunnel.last_hop().unwrap();
+
+            let states = tunnel.stream_flow_control_states(hop).await.unwrap();
+            assert_eq!(states.len(), 1);
+            assert_eq!(Some(states[0].stream_id), streamid);
+            assert_eq!(states[0].send_window, Some(500 - 301));
+            assert!(!states[0].xoff_sent);
+            assert_eq!(states[0].dropped_cells, 0);
+
+            // A stream-level SENDME opens the window again.
+            let s_sendme = relaymsg::Sendme::new_empty().into();
+            sink.send(rmsg_to_ccmsg(streamid, s_sendme)).await.unwrap();
+            rt.advance_until_stalled().await;
+
+            let states = tunnel.stream_flow_control_states(hop).await.unwrap();
+            assert_eq!(states[0].send_window, Some(500 - 301 + 50));
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn stream_windows_from_params() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let mut params = CircParameters::default();
+            params.stream_send_window_init = 400;
+            let (tunnel, _stream, _sink, _streamid, cells_received, _rx, _sink2) =
+                setup_incoming_sendme_case(&rt, 300 * 498 + 3, params).await;
+            assert_eq!(cells_received, 301);
+            let hop = tunnel.last_hop().unwrap();
+
+            // The stream's window started at the size we asked for.
+            let states = tunnel.stream_flow_control_states(hop).await.unwrap();
+            assert_eq!(states[0].send_window, Some(400 - 301));
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn stream_recv_window_from_params() {
+        /// Send `n_cells` DATA cells on a stream whose receive window starts at `recv_window`,
+        /// read them all, and return whether we sent a stream-level SENDME in response.
+        async fn sendme_after(
+            rt: &tor_rtmock::MockRuntime,
+            recv_window: u16,
+            n_cells: usize,
+        ) -> bool {
+            let mut params = CircParameters::default();
+            params.stream_recv_window_init = recv_window;
+            let (_tunnel, mut stream, mut sink, streamid, _cells_received, mut rx, _sink2) =
+                setup_incoming_sendme_case(rt, 1, params).await;
+
+            for _ in 0..n_cells {
+                let data = relaymsg::Data::new(b"x").unwrap().into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            }
+            let mut buf = vec![0; n_cells];
+            stream.read_exact(&mut buf).await.unwrap();
+            rt.advance_until_stalled().await;
+
+            let Ok(Some(cell)) = rx.try_next() else {
+                return false;
+            };
+            let (_id, chmsg) = cell.into_circid_and_msg();
+            let AnyChanMsg::Relay(r) = chmsg else {
+                panic!("{chmsg:?}");
+            };
+            let rmsg = AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                .unwrap();
+            let (streamid2, rmsg) = rmsg.into_streamid_and_msg();
+            assert_eq!(streamid2, streamid);
+            assert!(matches!(rmsg, AnyRelayMsg::Sendme(_)));
+            true
+        }
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            // With the default window of 500 cells, we send our first SENDME after 50 cells.
+            assert!(!sendme_after(&rt, RECV_WINDOW_INIT, 20).await);
+            // A window of 120 cells needs to go back up to 150 after only 20.
+            assert!(sendme_after(&rt, 120, 20).await);
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    fn discarded_cells_count_towards_stream_window() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let mut params = CircParameters::default();
+            params.inbound_cmd_filter =
+                Some(RelayCmdFilter::deny([RelayCmd::DATA]).with_action(CmdFilterAction::Discard));
+            let (_tunnel, _stream, mut sink, streamid, _cells_received, mut rx, _sink2) =
+                setup_incoming_sendme_case(&rt, 1, params).await;
+
+            // The stream never sees these cells, but we still acknowledge them:
+            // with the default window of 500 cells, after 50 of them.
+            for _ in 0..49 {
+                let data = relaymsg::Data::new(b"x").unwrap().into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            }
+            rt.advance_until_stalled().await;
+            assert!(rx.try_next().is_err());
+
+            let data = relaymsg::Data::new(b"x").unwrap().into();
+            sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            rt.advance_until_stalled().await;
+            let (_id, chmsg) = rx.try_next().unwrap().unwrap().into_circid_and_msg();
+            let AnyChanMsg::Relay(r) = chmsg else {
+                panic!("{chmsg:?}");
+            };
+            let rmsg = AnyRelayMsgOuter::decode_singleton(RelayCellFormat::V0, r.into_relay_body())
+                .unwrap();
+            let (streamid2, rmsg) = rmsg.into_streamid_and_msg();
+            assert_eq!(streamid2, streamid);
+            assert!(matches!(rmsg, AnyRelayMsg::Sendme(_)));
+        });
+    }
+
+    #[test]
+    fn stream_windows_out_of_range() {
+        let caps = tor_protover::Protocols::default();
+        let settings = |send, recv| {
+            let mut params = CircParameters::default();
+            params.stream_send_window_init = send;
+            params.stream_recv_window_init = recv;
+            HopSettings::from_params_and_caps(HopNegotiationType::None, &params, &caps)
+        };
+
+        let ok = settings(100, 500).unwrap();
+        assert_eq!(ok.stream_send_window_init, 100);
+        assert_eq!(ok.stream_recv_window_init, 500);
+        assert!(settings(0, 500).is_err());
+        assert!(settings(501, 500).is_err());
+        assert!(settings(500, 0).is_err());
+        assert!(settings(500, 501).is_err());
+    }
+
+    #[test]
+    fn sendme_delay_out_of_range() {
+        let caps = tor_protover::Protocols::default();
+        let settings = |emission| {
+            let mut params = CircParameters::default();
+            params.sendme_emission = emission;
+            HopSettings::from_params_and_caps(HopNegotiationType::None, &params, &caps)
+        };
+
+        assert!(settings(SendmeEmission::Immediate).is_ok());
+        assert!(settings(SendmeEmission::Delayed(Duration::from_millis(100))).is_ok());
+        assert!(settings(SendmeEmission::Delayed(MAX_SENDME_DELAY)).is_ok());
+        assert!(settings(SendmeEmission::Delayed(MAX_SENDME_DELAY * 2)).is_err());
+    }
+
+    #[traced_test]
+    #[test]
+    fn circ_sendme_emission() {
+        /// Send 200 DATA cells on a circuit that sends its SENDMEs according to `emission`,
+        /// and return the number of circuit-level SENDMEs it sends right away,
+        /// and then after each of the `steps`.
+        async fn circ_sendmes(
+            rt: &tor_rtmock::MockRuntime,
+            emission: SendmeEmission,
+            steps: &[Duration],
+        ) -> Vec<usize> {
+            let mut params = CircParameters::default();
+            params.sendme_emission = emission;
+            let (_tunnel, _stream, mut sink, streamid, _cells_received, mut rx, _sink2) =
+                setup_incoming_sendme_case(rt, 1, params).await;
+
+            // Count the circuit-level SENDMEs that we've sent so far.
+            let mut count_sendmes = || {
+                std::iter::from_fn(|| rx.try_next().ok().flatten())
+                    .filter(|cell| {
+                        let AnyChanMsg::Relay(r) = cell.msg() else {
+                            panic!("{cell:?}");
+                        };
+                        let (streamid, rmsg) = AnyRelayMsgOuter::decode_singleton(
+                            RelayCellFormat::V0,
+                            r.clone().into_relay_body(),
+                        )
+                        .unwrap()
+                        .into_streamid_and_msg();
+                        streamid.is_none() && matches!(rmsg, AnyRelayMsg::Sendme(_))
                     })
-                    .unwrap();
-                let (window, _tags) = rx.await.unwrap().unwrap();
-                assert_eq!(window, 1000 - 201);
+                    .count()
+            };
+
+            // The circuit window calls for a SENDME every 100 cells.
+            for _ in 0..200 {
+                let data = relaymsg::Data::new(b"x").unwrap().into();
+                sink.send(rmsg_to_ccmsg(streamid, data)).await.unwrap();
+            }
+            rt.progress_until_stalled().await;
+            let mut counts = vec![count_sendmes()];
+            for step in steps {
+                rt.advance_by(*step).await;
+                counts.push(count_sendmes());
             }
+            counts
+        }
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let delay = Duration::from_millis(100);
+
+            // We send each SENDME as soon as it is due.
+            let counts = circ_sendmes(&rt, SendmeEmission::Immediate, &[delay]).await;
+            assert_eq!(counts, vec![2, 0]);
+
+            // We hold both SENDMEs back until the first one has waited for the delay,
+            // and then send them together.
+            let counts = circ_sendmes(
+                &rt,
+                SendmeEmission::Delayed(delay),
+                &[delay - Duration::from_millis(1), Duration::from_millis(1)],
+            )
+            .await;
+            assert_eq!(counts, vec![0, 0, 2]);
         });
     }
 
@@ -2121,7 +3258,7 @@ pub(crate) mod test {
             // a sendme with the wrong tag.
 
             let (tunnel, _stream, mut sink, _streamid, _cells_received, _rx, _sink2) =
-                setup_incoming_sendme_case(&rt, 300 * 498 + 3).await;
+                setup_incoming_sendme_case(&rt, 300 * 498 + 3, CircParameters::default()).await;
 
             let reply_with_sendme_fut = async move {
                 // make and send a circuit-level sendme with a bad tag.
@@ -2140,6 +3277,38 @@ pub(crate) mod test {
         });
     }
 
+    #[traced_test]
+    #[test]
+    fn lenient_circ_sendme() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            // Same as invalid_circ_sendme() above, but with a lenient tag check.
+            let mut params = CircParameters::default();
+            params.sendme_tag_check = SendmeTagCheck::Lenient;
+            let (tunnel, _stream, mut sink, _streamid, _cells_received, _rx, _sink2) =
+                setup_incoming_sendme_case(&rt, 300 * 498 + 3, params).await;
+            let circ = tunnel.as_single_circ().unwrap();
+
+            let c_sendme =
+                relaymsg::Sendme::new_tag(hex!("FFFF0000000000000000000000000000000000FF")).into();
+            sink.send(rmsg_to_ccmsg(None, c_sendme)).await.unwrap();
+            rt.advance_until_stalled().await;
+
+            // The circuit survives, and the SENDME still refills its window.
+            assert!(!tunnel.is_closed());
+            let (tx, rx) = oneshot::channel();
+            circ.command
+                .unbounded_send(CtrlCmd::QuerySendWindow {
+                    hop: 2.into(),
+                    leg: tunnel.unique_id(),
+                    done: tx,
+                })
+                .unwrap();
+            let (window, tags) = rx.await.unwrap().unwrap();
+            assert_eq!(window, 1000 - 201);
+            assert_eq!(tags.len(), 2);
+        });
+    }
+
     #[traced_test]
     #[test]
     fn test_busy_stream_fairness() {
@@ -2399,6 +3568,77 @@ pub(crate) mod test {
         });
     }
 
+    #[traced_test]
+    #[test]
+    #[cfg(feature = "hs-service")]
+    fn allow_resolve_requests() {
+        use crate::stream::IncomingStreamRequest;
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut send) = newtunnel(&rt, chan).await;
+
+            let rfmt = RelayCellFormat::V0;
+
+            let mut incoming = tunnel
+                .allow_stream_requests(
+                    &[
+                        tor_cell::relaycell::RelayCmd::BEGIN,
+                        tor_cell::relaycell::RelayCmd::RESOLVE,
+                    ],
+                    tunnel.resolve_last_hop().await,
+                    AllowAllStreamsFilter,
+                )
+                .await
+                .unwrap();
+
+            let simulate_service = async move {
+                let stream = incoming.next().await.unwrap();
+                assert!(matches!(
+                    stream.request(),
+                    IncomingStreamRequest::Resolve(_)
+                ));
+                stream
+                    .answer_resolve(relaymsg::Resolved::new_err(false, 0))
+                    .await
+                    .unwrap();
+                tunnel
+            };
+
+            let simulate_client = async move {
+                let resolve = relaymsg::Resolve::new("www.example.com");
+                let body: BoxedCellBody =
+                    AnyRelayMsgOuter::new(StreamId::new(12), AnyRelayMsg::Resolve(resolve))
+                        .encode(rfmt, &mut testing_rng())
+                        .unwrap();
+                let resolve_msg = chanmsg::Relay::from(body);
+
+                send.send(ClientCircChanMsg::Relay(resolve_msg))
+                    .await
+                    .unwrap();
+                send
+            };
+
+            let (_circ, _send) = futures::join!(simulate_service, simulate_client);
+
+            // The client gets the service's answer, on the stream it used for its request.
+            let (_id, chmsg) = rx.next().await.unwrap().into_circid_and_msg();
+            let AnyChanMsg::Relay(r) = chmsg else {
+                panic!("{chmsg:?}");
+            };
+            let rmsg = AnyRelayMsgOuter::decode_singleton(rfmt, r.into_relay_body()).unwrap();
+            let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+            assert_eq!(streamid, StreamId::new(12));
+            let AnyRelayMsg::Resolved(resolved) = rmsg else {
+                panic!("{rmsg:?}");
+            };
+            assert_eq!(
+                resolved.into_answers(),
+                relaymsg::Resolved::new_err(false, 0).into_answers()
+            );
+        });
+    }
+
     #[traced_test]
     #[test]
     #[cfg(feature = "hs-service")]
@@ -2550,6 +3790,160 @@ pub(crate) mod test {
         });
     }
 
+    #[traced_test]
+    #[test]
+    #[cfg(feature = "hs-service")]
+    fn incoming_stream_queue_full_rejects_once() {
+        use crate::tunnel::reactor::STREAM_READER_BUFFER;
+        use tor_cell::relaycell::msg::{BeginFlags, EndReason};
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, mut rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut send) = newtunnel(&rt, chan).await;
+
+            let _incoming = tunnel
+                .allow_stream_requests(
+                    &[tor_cell::relaycell::RelayCmd::BEGIN],
+                    tunnel.resolve_last_hop().await,
+                    AllowAllStreamsFilter,
+                )
+                .await
+                .unwrap();
+
+            // Send one BEGIN more than the queue of incoming requests can hold,
+            // without letting the service take any of them.
+            let n_begins = u16::try_from(STREAM_READER_BUFFER + 2).unwrap();
+            for id in 1..=n_begins {
+                let begin = relaymsg::Begin::new("localhost", 80, BeginFlags::IPV6_OKAY).unwrap();
+                send.send(rmsg_to_ccmsg(StreamId::new(id), begin.into()))
+                    .await
+                    .unwrap();
+            }
+            rt.advance_until_stalled().await;
+
+            // The last request didn't fit in the queue: we closed its stream,
+            // and sent exactly one END for it.
+            let ends = std::iter::from_fn(|| rx.try_next().ok().flatten())
+                .filter_map(|cell| {
+                    let AnyChanMsg::Relay(r) = cell.msg() else {
+                        panic!("{cell:?}");
+                    };
+                    let (streamid, rmsg) = AnyRelayMsgOuter::decode_singleton(
+                        RelayCellFormat::V0,
+                        r.clone().into_relay_body(),
+                    )
+                    .unwrap()
+                    .into_streamid_and_msg();
+                    match rmsg {
+                        AnyRelayMsg::End(end) => Some((streamid, end.reason())),
+                        _ => None,
+                    }
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                ends,
+                vec![(StreamId::new(n_begins), EndReason::RESOURCELIMIT)]
+            );
+            assert!(!tunnel.is_closed());
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    #[cfg(feature = "hs-service")]
+    fn incoming_stream_queue_overflow() {
+        use crate::stream::{IncomingStreamOverflow, IncomingStreamQueueParams};
+        use tor_cell::relaycell::msg::BeginFlags;
+
+        tor_rtcompat::test_with_all_runtimes!(|rt| async move {
+            let rfmt = RelayCellFormat::V0;
+
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut send) = newtunnel(&rt, chan).await;
+
+            let mut queue = IncomingStreamQueueParams::new();
+            queue
+                .depth(1)
+                .on_overflow(IncomingStreamOverflow::CloseCircuit);
+            let incoming = tunnel
+                .allow_stream_requests_with_queue(
+                    &[tor_cell::relaycell::RelayCmd::BEGIN],
+                    tunnel.resolve_last_hop().await,
+                    AllowAllStreamsFilter,
+                    &queue,
+                )
+                .await
+                .unwrap();
+
+            // Send two BEGINs, without letting the service take either of them.
+            for id in [12, 13] {
+                let begin = relaymsg::Begin::new("localhost", 80, BeginFlags::IPV6_OKAY).unwrap();
+                let body: BoxedCellBody =
+                    AnyRelayMsgOuter::new(StreamId::new(id), AnyRelayMsg::Begin(begin))
+                        .encode(rfmt, &mut testing_rng())
+                        .unwrap();
+                send.send(ClientCircChanMsg::Relay(chanmsg::Relay::from(body)))
+                    .await
+                    .unwrap();
+            }
+
+            // The second request didn't fit in the queue, so the circuit was closed.
+            tunnel.wait_for_close().await;
+            assert!(incoming.count().await <= 1);
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    #[cfg(feature = "hs-service")]
+    fn incoming_stream_rate_limit() {
+        use crate::stream::IncomingStreamQueueParams;
+        use futures::FutureExt as _;
+        use tor_cell::relaycell::msg::BeginFlags;
+
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let (chan, _rx, _sink) = working_fake_channel(&rt);
+            let (tunnel, mut send) = newtunnel(&rt, chan).await;
+
+            let mut queue = IncomingStreamQueueParams::new();
+            queue.rate_limit(1, 1);
+            let mut incoming = tunnel
+                .allow_stream_requests_with_queue(
+                    &[tor_cell::relaycell::RelayCmd::BEGIN],
+                    tunnel.resolve_last_hop().await,
+                    AllowAllStreamsFilter,
+                    &queue,
+                )
+                .await
+                .unwrap();
+
+            let begin = || {
+                let begin = relaymsg::Begin::new("localhost", 80, BeginFlags::IPV6_OKAY).unwrap();
+                AnyRelayMsg::Begin(begin)
+            };
+
+            // Two BEGINs at once: only the first one fits in the bucket.
+            for id in [12, 13] {
+                send.send(rmsg_to_ccmsg(StreamId::new(id), begin()))
+                    .await
+                    .unwrap();
+            }
+            rt.advance_until_stalled().await;
+            assert!(incoming.next().await.is_some());
+            assert!(incoming.next().now_or_never().is_none());
+            assert!(!tunnel.is_closed());
+            assert!(logs_contain("exceeds our rate limit"));
+
+            // Once the bucket has refilled, we accept requests again.
+            rt.advance_by(Duration::from_secs(1)).await;
+            send.send(rmsg_to_ccmsg(StreamId::new(14), begin()))
+                .await
+                .unwrap();
+            rt.advance_until_stalled().await;
+            assert!(incoming.next().await.is_some());
+        });
+    }
+
     #[traced_test]
     #[test]
     #[cfg(feature = "conflux")]
@@ -2993,6 +4387,115 @@ pub(crate) mod test {
         });
     }
 
+    #[traced_test]
+    #[test]
+    #[cfg(feature = "conflux")]
+    fn conflux_switch_abuse() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let abusive_switches = [
+                // Too many SWITCH cells in a row, without any data
+                vec![relaymsg::ConfluxSwitch::new(1); 5],
+                // A SWITCH cell that skips implausibly far ahead
+                vec![relaymsg::ConfluxSwitch::new(u32::MAX)],
+            ];
+
+            for switches in abusive_switches {
+                let TestTunnelCtx {
+                    tunnel,
+                    circs,
+                    conflux_link_rx,
+                } = setup_good_conflux_tunnel(&rt).await;
+
+                let [mut circ1, mut circ2]: [TestCircuitCtx; 2] = circs.try_into().unwrap();
+
+                let link = await_link_payload(&mut circ1.chan_rx).await;
+
+                // Send a LINKED cell on both legs
+                for circ in [&mut circ1, &mut circ2] {
+                    let linked = relaymsg::ConfluxLinked::new(link.payload().clone()).into();
+                    circ.circ_tx
+                        .send(rmsg_to_ccmsg(None, linked))
+                        .await
+                        .unwrap();
+                }
+
+                let conflux_hs_res = conflux_link_rx.await.unwrap().unwrap();
+                assert!(conflux_hs_res.iter().all(|res| res.is_ok()));
+
+                // Send the SWITCH cells on just one of the legs...
+                for switch in switches {
+                    let msg = rmsg_to_ccmsg(None, switch.into());
+                    circ2.circ_tx.send(msg).await.unwrap();
+                }
+
+                // ...which is enough to tear down the whole tunnel
+                rt.advance_until_stalled().await;
+                assert!(tunnel.is_closed());
+            }
+        });
+    }
+
+    #[traced_test]
+    #[test]
+    #[cfg(feature = "conflux")]
+    fn conflux_primary_leg_closed() {
+        tor_rtmock::MockRuntime::test_with_various(|rt| async move {
+            let TestTunnelCtx {
+                tunnel,
+                circs,
+                conflux_link_rx,
+            } = setup_good_conflux_tunnel(&rt).await;
+
+            let [mut circ1, mut circ2]: [TestCircuitCtx; 2] = circs.try_into().unwrap();
+
+            let link = await_link_payload(&mut circ1.chan_rx).await;
+
+            // Send a LINKED cell on both legs
+            for circ in [&mut circ1, &mut circ2] {
+                let linked = relaymsg::ConfluxLinked::new(link.payload().clone()).into();
+                circ.circ_tx
+                    .send(rmsg_to_ccmsg(None, linked))
+                    .await
+                    .unwrap();
+            }
+
+            let conflux_hs_res = conflux_link_rx.await.unwrap().unwrap();
+            assert!(conflux_hs_res.iter().all(|res| res.is_ok()));
+
+            let mut event_rx = tunnel.conflux_leg_events().unwrap();
+            rt.advance_until_stalled().await;
+
+            // Close the first leg, which is our primary leg
+            let TestCircuitCtx {
+                circ_tx, unique_id, ..
+            } = circ1;
+            drop(circ_tx);
+            rt.advance_until_stalled().await;
+
+            // We haven't sent anything on the closed leg,
+            // so we can carry on using the other one
+            assert!(!tunnel.is_closed());
+
+            let ConfluxLegEvent::LegRemoved {
+                leg,
+                reason,
+                n_legs_remaining,
+                new_primary,
+            } = event_rx.next().await.unwrap();
+            assert_eq!(leg, unique_id);
+            assert!(matches!(reason, RemoveLegReason::ChannelClosed));
+            assert_eq!(n_legs_remaining, 1);
+            assert_eq!(new_primary, Some(circ2.unique_id));
+
+            // Closing the last leg tears down the tunnel,
+            // and ends the stream of events.
+            drop(circ2.circ_tx);
+            rt.advance_until_stalled().await;
+            assert!(tunnel.is_closed());
+            assert!(event_rx.next().await.is_none());
+        });
+    }
+
     // This test ensures CtrlMsg::ShutdownAndReturnCircuit returns an
     // error when called on a multi-path tunnel
     #[traced_test]
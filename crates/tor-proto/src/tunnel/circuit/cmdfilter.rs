@@ -0,0 +1,159 @@
+//! Per-hop restrictions on the relay commands we accept.
+//!
+//! Ordinarily, we accept any relay command from any hop of a circuit,
+//! and rely on the reactor to reject the ones that make no sense.
+//! A [`RelayCmdFilter`] lets the creator of a circuit be stricter:
+//! for example, a client has no reason to accept `DROP` or `RESOLVED` cells
+//! from the middle hop of a circuit,
+//! and seeing one is a sign that the hop is misbehaving.
+
+use tor_cell::relaycell::RelayCmd;
+
+/// A set of relay commands, stored as a bitmap.
+#[derive(Clone, Copy, Default, Eq, PartialEq)]
+struct RelayCmdSet([u64; 4]);
+
+impl RelayCmdSet {
+    /// Add `cmd` to this set.
+    fn insert(&mut self, cmd: RelayCmd) {
+        let cmd = u8::from(cmd);
+        self.0[usize::from(cmd / 64)] |= 1 << (cmd % 64);
+    }
+
+    /// Return true if `cmd` is in this set.
+    fn contains(&self, cmd: RelayCmd) -> bool {
+        let cmd = u8::from(cmd);
+        self.0[usize::from(cmd / 64)] & (1 << (cmd % 64)) != 0
+    }
+}
+
+impl FromIterator<RelayCmd> for RelayCmdSet {
+    fn from_iter<I: IntoIterator<Item = RelayCmd>>(iter: I) -> Self {
+        let mut set = RelayCmdSet::default();
+        for cmd in iter {
+            set.insert(cmd);
+        }
+        set
+    }
+}
+
+impl std::fmt::Debug for RelayCmdSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(
+                (0..=u8::MAX)
+                    .map(RelayCmd::from)
+                    .filter(|c| self.contains(*c)),
+            )
+            .finish()
+    }
+}
+
+/// A policy restricting which relay commands we accept from a single circuit hop.
+///
+/// Cells with a disallowed command are counted,
+/// reported in the logs,
+/// and then handled as specified by the filter's [`CmdFilterAction`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RelayCmdFilter {
+    /// The commands that are listed in this filter.
+    cmds: RelayCmdSet,
+    /// If true, `cmds` lists the allowed commands;
+    /// otherwise, it lists the disallowed ones.
+    is_allow_list: bool,
+    /// What to do with a cell whose command is not allowed.
+    action: CmdFilterAction,
+}
+
+/// What to do with a cell that is rejected by a [`RelayCmdFilter`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CmdFilterAction {
+    /// Close the circuit with
+    /// [`Error::DisallowedRelayCmd`](crate::Error::DisallowedRelayCmd).
+    #[default]
+    CloseCircuit,
+    /// Discard the cell, and keep the circuit open.
+    ///
+    /// The cell still counts towards the circuit's flow-control windows,
+    /// and towards those of its stream, if that stream is open.
+    Discard,
+}
+
+impl RelayCmdFilter {
+    /// Return a filter that accepts only the commands in `cmds`.
+    pub fn allow_only(cmds: impl IntoIterator<Item = RelayCmd>) -> Self {
+        Self {
+            cmds: cmds.into_iter().collect(),
+            is_allow_list: true,
+            action: CmdFilterAction::default(),
+        }
+    }
+
+    /// Return a filter that accepts every command except those in `cmds`.
+    pub fn deny(cmds: impl IntoIterator<Item = RelayCmd>) -> Self {
+        Self {
+            cmds: cmds.into_iter().collect(),
+            is_allow_list: false,
+            action: CmdFilterAction::default(),
+        }
+    }
+
+    /// Set the action to take when a cell is rejected by this filter.
+    pub fn with_action(mut self, action: CmdFilterAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Return the action to take when a cell is rejected by this filter.
+    pub fn action(&self) -> CmdFilterAction {
+        self.action
+    }
+
+    /// Return true if this filter accepts cells with the command `cmd`.
+    pub fn allows(&self, cmd: RelayCmd) -> bool {
+        self.cmds.contains(cmd) == self.is_allow_list
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn allow_and_deny() {
+        let f = RelayCmdFilter::deny([RelayCmd::DROP, RelayCmd::RESOLVED]);
+        assert!(!f.allows(RelayCmd::DROP));
+        assert!(!f.allows(RelayCmd::RESOLVED));
+        assert!(f.allows(RelayCmd::DATA));
+        assert!(f.allows(RelayCmd::from(255)));
+        assert_eq!(f.action(), CmdFilterAction::CloseCircuit);
+
+        let f = RelayCmdFilter::allow_only([RelayCmd::SENDME, RelayCmd::from(200)])
+            .with_action(CmdFilterAction::Discard);
+        assert!(f.allows(RelayCmd::SENDME));
+        assert!(f.allows(RelayCmd::from(200)));
+        assert!(!f.allows(RelayCmd::DATA));
+        assert!(!f.allows(RelayCmd::from(201)));
+        assert_eq!(f.action(), CmdFilterAction::Discard);
+    }
+
+    #[test]
+    fn debug_fmt() {
+        let f = RelayCmdFilter::deny([RelayCmd::DROP]);
+        assert!(format!("{f:?}").contains("DROP"));
+    }
+}
@@ -19,6 +19,11 @@
 pub(super) mod circuit;
 mod conflux;
 mod control;
+#[cfg(feature = "conflux")]
+mod ooo_queue;
+pub(super) mod profile;
+#[cfg(test)]
+pub(crate) mod step;
 pub(super) mod syncview;
 
 use crate::crypto::cell::HopNum;
@@ -26,8 +31,6 @@ use crate::crypto::handshake::ntor_v3::NtorV3PublicKey;
 use crate::memquota::{CircuitAccount, StreamAccount};
 use crate::stream::queue::StreamQueueReceiver;
 use crate::stream::{AnyCmdChecker, StreamRateLimit};
-#[cfg(feature = "hs-service")]
-use crate::stream::{DrainRateRequest, IncomingStreamRequest, IncomingStreamRequestFilter};
 use crate::tunnel::circuit::CircuitRxReceiver;
 use crate::tunnel::circuit::celltypes::ClientCircChanMsg;
 use crate::tunnel::circuit::unique_id::UniqId;
@@ -41,13 +44,21 @@ use conflux::ConfluxSet;
 use control::ControlHandler;
 use postage::watch;
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
 use std::mem::size_of;
 use tor_cell::relaycell::flow_ctrl::XonKbpsEwma;
 use tor_cell::relaycell::msg::{AnyRelayMsg, End, Sendme};
 use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCellFormat, StreamId, UnparsedRelayMsg};
 use tor_error::{Bug, bad_api_usage, internal, into_bad_api_usage, trace_report, warn_report};
 use tor_rtcompat::DynTimeProvider;
+#[cfg(feature = "hs-service")]
+use {
+    crate::stream::{
+        DrainRateRequest, IncomingStreamOverflow, IncomingStreamRequest,
+        IncomingStreamRequestFilter,
+    },
+    crate::util::token_bucket::bucket::TokenBucket,
+    std::time::Instant,
+};
 
 use futures::StreamExt;
 use futures::channel::mpsc;
@@ -71,7 +82,7 @@ use tracing::{debug, info, trace, warn};
 use super::circuit::{MutableState, TunnelMutableState};
 
 #[cfg(feature = "conflux")]
-use {crate::util::err::ConfluxHandshakeError, conflux::OooRelayMsg};
+use {crate::util::err::ConfluxHandshakeError, conflux::OooRelayMsg, ooo_queue::OooQueue};
 
 pub(super) use control::{CtrlCmd, CtrlMsg, FlowCtrlMsg};
 
@@ -90,7 +101,7 @@ pub(super) type ConfluxHandshakeResult = Vec<StdResult<(), ConfluxHandshakeError
 #[cfg(feature = "conflux")]
 pub(super) type ConfluxLinkResultChannel = ReactorResultChannel<ConfluxHandshakeResult>;
 
-pub(crate) use circuit::{RECV_WINDOW_INIT, STREAM_READER_BUFFER};
+pub(crate) use circuit::{RECV_WINDOW_INIT, SEND_WINDOW_INIT, STREAM_READER_BUFFER};
 
 /// MPSC queue containing stream requests
 #[cfg(feature = "hs-service")]
@@ -148,7 +159,7 @@ impl Default for CloseStreamBehavior {
 enum RunOnceCmd {
     /// Run a single `RunOnceCmdInner` command.
     Single(RunOnceCmdInner),
-    /// Run multiple `RunOnceCmdInner` commands.
+    /// Run multiple `RunOnceCmdInner` commands, in order.
     //
     // Note: this whole enum *could* be replaced with Vec<RunOnceCmdInner>,
     // but most of the time we're only going to have *one* RunOnceCmdInner
@@ -212,7 +223,9 @@ enum RunOnceCmdInner {
         /// The circuit leg to begin the stream on.
         leg: UniqId,
         /// Oneshot channel to notify on completion, with the allocated stream ID.
-        done: ReactorResultChannel<(StreamId, HopLocation, RelayCellFormat)>,
+        ///
+        /// See [`CtrlMsg::BeginStream`].
+        done: ReactorResultChannel<(StreamId, HopLocation, RelayCellFormat, u16)>,
     },
     /// Consider sending an XON message with the given `rate`.
     MaybeSendXon {
@@ -294,22 +307,27 @@ enum RunOnceCmdInner {
     CleanShutdown,
 }
 
-impl RunOnceCmdInner {
-    /// Create a [`RunOnceCmdInner`] out of a [`CircuitCmd`] and [`UniqId`].
+impl RunOnceCmd {
+    /// Create a [`RunOnceCmd`] out of a [`CircuitCmd`] and [`UniqId`].
+    ///
+    /// A [`CircuitCmd::Batch`] becomes a [`RunOnceCmd::Multiple`],
+    /// with any nested batches flattened.
     fn from_circuit_cmd(leg: UniqId, cmd: CircuitCmd) -> Self {
-        match cmd {
-            CircuitCmd::Send(cell) => Self::Send {
+        use RunOnceCmdInner as I;
+
+        let cmd = match cmd {
+            CircuitCmd::Send(cell) => I::Send {
                 leg,
                 cell,
                 done: None,
             },
-            CircuitCmd::HandleSendMe { hop, sendme } => Self::HandleSendMe { leg, hop, sendme },
+            CircuitCmd::HandleSendMe { hop, sendme } => I::HandleSendMe { leg, hop, sendme },
             CircuitCmd::CloseStream {
                 hop,
                 sid,
                 behav,
                 reason,
-            } => Self::CloseStream {
+            } => I::CloseStream {
                 hop: HopLocation::Hop((leg, hop)),
                 sid,
                 behav,
@@ -317,15 +335,25 @@ impl RunOnceCmdInner {
                 done: None,
             },
             #[cfg(feature = "conflux")]
-            CircuitCmd::ConfluxRemove(reason) => Self::RemoveLeg { leg, reason },
+            CircuitCmd::ConfluxRemove(reason) => I::RemoveLeg { leg, reason },
             #[cfg(feature = "conflux")]
-            CircuitCmd::ConfluxHandshakeComplete(cell) => {
-                Self::ConfluxHandshakeComplete { leg, cell }
-            }
+            CircuitCmd::ConfluxHandshakeComplete(cell) => I::ConfluxHandshakeComplete { leg, cell },
             #[cfg(feature = "conflux")]
-            CircuitCmd::Enqueue(msg) => Self::Enqueue { leg, msg },
-            CircuitCmd::CleanShutdown => Self::CleanShutdown,
-        }
+            CircuitCmd::Enqueue(msg) => I::Enqueue { leg, msg },
+            CircuitCmd::CleanShutdown => I::CleanShutdown,
+            CircuitCmd::Batch(cmds) => {
+                let mut all = Vec::with_capacity(cmds.len());
+                for cmd in cmds {
+                    match Self::from_circuit_cmd(leg, cmd) {
+                        Self::Single(cmd) => all.push(cmd),
+                        Self::Multiple(cmds) => all.extend(cmds),
+                    }
+                }
+                return Self::Multiple(all);
+            }
+        };
+
+        Self::Single(cmd)
     }
 }
 
@@ -363,6 +391,14 @@ enum CircuitAction {
         /// The message to handle.
         cell: ClientCircChanMsg,
     },
+    /// Send the circuit-level SENDMEs that the specified circuit leg
+    /// has been holding back, and has to send by now.
+    ///
+    /// See [`SendmeEmission::Delayed`](crate::circuit::SendmeEmission::Delayed).
+    SendDelayedSendmes {
+        /// The unique identifier of the circuit leg.
+        leg: UniqId,
+    },
     /// Remove the specified circuit leg from the conflux set.
     ///
     /// Returned whenever a single circuit leg needs to be be removed
@@ -389,8 +425,10 @@ enum CircuitAction {
 }
 
 /// The reason for removing a circuit leg from the conflux set.
-#[derive(Debug, derive_more::Display)]
-enum RemoveLegReason {
+#[derive(Clone, Debug, derive_more::Display)]
+#[cfg_attr(feature = "conflux", visibility::make(pub))]
+#[non_exhaustive]
+pub(crate) enum RemoveLegReason {
     /// The conflux handshake timed out.
     ///
     /// On the client-side, this means we didn't receive
@@ -405,6 +443,28 @@ enum RemoveLegReason {
     ChannelClosed,
 }
 
+/// A non-fatal event concerning the legs of a multi-path tunnel.
+///
+/// Returned by [`ClientTunnel::conflux_leg_events`](crate::ClientTunnel::conflux_leg_events).
+#[cfg(feature = "conflux")]
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ConfluxLegEvent {
+    /// A leg was removed from the tunnel, but the rest of the tunnel is still usable.
+    ///
+    /// The recipient may want to build a replacement leg, and link it into the tunnel.
+    LegRemoved {
+        /// The leg that was removed.
+        leg: UniqId,
+        /// Why the leg was removed.
+        reason: RemoveLegReason,
+        /// The number of legs left in the tunnel.
+        n_legs_remaining: usize,
+        /// The leg we are now sending on, if the removed leg was our sending leg.
+        new_primary: Option<UniqId>,
+    },
+}
+
 /// An object that's waiting for a meta cell (one not associated with a stream) in order to make
 /// progress.
 ///
@@ -526,11 +586,13 @@ pub struct Reactor {
     conflux_hs_ctx: Option<ConfluxHandshakeCtx>,
     /// A min-heap buffering all the out-of-order messages received so far.
     ///
-    /// TODO(conflux): this becomes a DoS vector unless we impose a limit
-    /// on its size. We should make this participate in the memquota memory
-    /// tracking system, somehow.
+    /// Its memory use is accounted to the account of the tunnel's first circuit leg,
+    /// so that if it grows too large, the memory quota system will tear down the tunnel.
     #[cfg(feature = "conflux")]
-    ooo_msgs: BinaryHeap<ConfluxHeapEntry>,
+    ooo_msgs: OooQueue,
+    /// The subscribers to our [`ConfluxLegEvent`]s.
+    #[cfg(feature = "conflux")]
+    conflux_leg_event_txs: Vec<mpsc::UnboundedSender<ConfluxLegEvent>>,
 }
 
 /// The context for an on-going conflux handshake.
@@ -607,6 +669,9 @@ pub(crate) struct StreamReqInfo {
     /// The format which must be used with this stream to encode messages.
     #[deftly(has_memory_cost(indirect_size = "0"))]
     pub(crate) relay_cell_format: RelayCellFormat,
+    /// The initial receive window for this stream.
+    #[deftly(has_memory_cost(indirect_size = "0"))]
+    pub(crate) recv_window_init: u16,
     /// A channel for receiving messages from this stream.
     #[deftly(has_memory_cost(indirect_size = "0"))] // estimate
     pub(crate) receiver: StreamQueueReceiver,
@@ -634,6 +699,12 @@ pub(crate) struct StreamReqInfo {
 struct IncomingStreamRequestHandler {
     /// A sender for sharing information about an incoming stream request.
     incoming_sender: StreamReqSender,
+    /// What to do with requests that arrive when `incoming_sender` is full.
+    overflow: IncomingStreamOverflow,
+    /// The token bucket that limits the rate at which we accept requests, if any.
+    ///
+    /// Each request takes one token.
+    rate_limit: Option<TokenBucket<Instant>>,
     /// A [`AnyCmdChecker`] for validating incoming stream requests.
     cmd_checker: AnyCmdChecker,
     /// The hop to expect incoming stream requests from.
@@ -680,6 +751,9 @@ impl Reactor {
             incoming_stream_req_handler: None,
         };
 
+        #[cfg(feature = "conflux")]
+        let ooo_msgs = OooQueue::new(memquota.clone(), runtime.clone());
+
         let unique_id = TunnelScopedCircId::new(tunnel_id, unique_id);
         let circuit_leg = Circuit::new(
             runtime.clone(),
@@ -704,7 +778,9 @@ impl Reactor {
             #[cfg(feature = "conflux")]
             conflux_hs_ctx: None,
             #[cfg(feature = "conflux")]
-            ooo_msgs: Default::default(),
+            ooo_msgs,
+            #[cfg(feature = "conflux")]
+            conflux_leg_event_txs: Vec::new(),
         };
 
         (reactor, control_tx, command_tx, reactor_closed_rx, mutable)
@@ -768,7 +844,17 @@ impl Reactor {
         #[cfg(feature = "conflux")]
         self.try_dequeue_ooo_msgs().await?;
 
+        // If the memory quota system reclaims our out-of-order message buffer,
+        // we need to find out, even if nothing else happens on this tunnel.
+        #[cfg(feature = "conflux")]
+        let ooo_collapsed = futures::future::poll_fn(|cx| self.ooo_msgs.poll_collapsed(cx));
+        #[cfg(not(feature = "conflux"))]
+        let ooo_collapsed = futures::future::pending::<()>();
+
         let action = select_biased! {
+            () = ooo_collapsed.fuse() => {
+                return Err(Error::from(tor_memquota::MemoryReclaimedError::new()).into());
+            },
             res = self.command.next() => {
                 let cmd = unwrap_or_shutdown!(self, res, "command channel drop")?;
                 return ControlHandler::new(self).handle_cmd(cmd);
@@ -789,34 +875,18 @@ impl Reactor {
         };
 
         let cmd = match action {
-            CircuitAction::RunCmd { leg, cmd } => Some(RunOnceCmd::Single(
-                RunOnceCmdInner::from_circuit_cmd(leg, cmd),
-            )),
+            CircuitAction::RunCmd { leg, cmd } => Some(RunOnceCmd::from_circuit_cmd(leg, cmd)),
             CircuitAction::HandleControl(ctrl) => ControlHandler::new(self)
                 .handle_msg(ctrl)?
                 .map(RunOnceCmd::Single),
-            CircuitAction::HandleCell { leg, cell } => {
+            CircuitAction::HandleCell { leg, cell } => self.handle_cell(leg, cell)?,
+            CircuitAction::SendDelayedSendmes { leg } => {
                 let circ = self
                     .circuits
                     .leg_mut(leg)
                     .ok_or_else(|| internal!("the circuit leg we just had disappeared?!"))?;
-
-                let circ_cmds = circ.handle_cell(&mut self.cell_handlers, leg, cell)?;
-                if circ_cmds.is_empty() {
-                    None
-                } else {
-                    // TODO: we return RunOnceCmd::Multiple even if there's a single command.
-                    //
-                    // See the TODO on `Circuit::handle_cell`.
-                    let cmd = RunOnceCmd::Multiple(
-                        circ_cmds
-                            .into_iter()
-                            .map(|cmd| RunOnceCmdInner::from_circuit_cmd(leg, cmd))
-                            .collect(),
-                    );
-
-                    Some(cmd)
-                }
+                circ.take_due_sendmes()
+                    .map(|cmd| RunOnceCmd::from_circuit_cmd(leg, cmd))
             }
             CircuitAction::RemoveLeg { leg, reason } => {
                 Some(RunOnceCmdInner::RemoveLeg { leg, reason }.into())
@@ -830,9 +900,30 @@ impl Reactor {
         Ok(())
     }
 
+    /// Handle `cell`, which we received on the circuit leg `leg`.
+    ///
+    /// Returns the command we need to run in response, if any.
+    fn handle_cell(
+        &mut self,
+        leg: UniqId,
+        cell: ClientCircChanMsg,
+    ) -> StdResult<Option<RunOnceCmd>, ReactorError> {
+        let circ = self
+            .circuits
+            .leg_mut(leg)
+            .ok_or_else(|| internal!("the circuit leg we just had disappeared?!"))?;
+
+        let circ_cmds = circ.handle_cell(&mut self.cell_handlers, leg, cell)?;
+
+        Ok(CircuitCmd::batch(circ_cmds).map(|cmd| RunOnceCmd::from_circuit_cmd(leg, cmd)))
+    }
+
     /// Try to process the previously-out-of-order messages we might have buffered.
     #[cfg(feature = "conflux")]
     async fn try_dequeue_ooo_msgs(&mut self) -> StdResult<(), ReactorError> {
+        // If the memory quota system has reclaimed our buffer, give up on the tunnel.
+        self.ooo_msgs.check_collapsed()?;
+
         // Check if we're ready to dequeue any of the previously out-of-order cells.
         while let Some(entry) = self.ooo_msgs.peek() {
             let should_pop = self.circuits.is_seqno_in_order(entry.msg.seqno);
@@ -857,9 +948,7 @@ impl Reactor {
                     entry.msg.streamid,
                     entry.msg.msg,
                 )?
-                .map(|cmd| {
-                    RunOnceCmd::Single(RunOnceCmdInner::from_circuit_cmd(entry.leg_id, cmd))
-                });
+                .map(|cmd| RunOnceCmd::from_circuit_cmd(entry.leg_id, cmd));
 
             if let Some(cmd) = cmd {
                 self.handle_run_once_cmd(cmd).await?;
@@ -878,7 +967,12 @@ impl Reactor {
                 // we can't be certain it will be able to accept *all* of the cells
                 // that need to be sent here. This means we *may* end up buffering
                 // in its underlying SometimesUnboundedSink! That is OK, because
-                // RunOnceCmd::Multiple is only used for handling packed cells.
+                // RunOnceCmd::Multiple is only used for handling packed cells,
+                // and for small batches of commands (see CircuitCmd::Batch).
+                //
+                // We run all the commands before handling any other input,
+                // so the effects of a batch can't be interleaved with anything else.
+                // If one of them fails, we don't run the rest.
                 for cmd in cmds {
                     self.handle_single_run_once_cmd(cmd).await?;
                 }
@@ -926,6 +1020,19 @@ impl Reactor {
                         return Err(e.into());
                     }
                 }
+
+                // The tunnel is still usable, so this isn't fatal:
+                // let anyone who's interested know what happened.
+                #[cfg(feature = "conflux")]
+                {
+                    let new_primary = self.circuits.primary_leg_id();
+                    self.send_conflux_leg_event(ConfluxLegEvent::LegRemoved {
+                        leg,
+                        reason,
+                        n_legs_remaining: self.circuits.len(),
+                        new_primary: (new_primary != old_primary).then_some(new_primary),
+                    });
+                }
             }
             RunOnceCmdInner::BeginStream {
                 leg,
@@ -940,15 +1047,20 @@ impl Reactor {
                             .leg_mut(leg)
                             .ok_or_else(|| internal!("leg disappeared?!"))?;
                         let cell_hop = cell.hop;
-                        let relay_format = circ
+                        let circ_hop = circ
                             .hop_mut(cell_hop)
                             // TODO: Is this the right error type here? Or should there be a "HopDisappeared"?
-                            .ok_or(Error::NoSuchHop)?
-                            .relay_cell_format();
+                            .ok_or(Error::NoSuchHop)?;
+                        let relay_format = circ_hop.relay_cell_format();
+                        let recv_window_init = circ_hop.stream_recv_window_init();
 
                         let outcome = self.circuits.send_relay_cell_on_leg(cell, Some(leg)).await;
                         // don't care if receiver goes away.
-                        let _ = done.send(outcome.clone().map(|_| (stream_id, hop, relay_format)));
+                        let _ = done.send(
+                            outcome
+                                .clone()
+                                .map(|_| (stream_id, hop, relay_format, recv_window_init)),
+                        );
                         outcome?;
                     }
                     Err(e) => {
@@ -1039,7 +1151,11 @@ impl Reactor {
                     return Ok(());
                 };
 
-                let Some(msg) = hop.maybe_send_xon(rate, stream_id)? else {
+                let timer = profile::Timer::start();
+                let xon = hop.maybe_send_xon(rate, stream_id);
+                leg.profiler()
+                    .record(profile::EventClass::FlowControl, timer);
+                let Some(msg) = xon? else {
                     // Nothing to do.
                     return Ok(());
                 };
@@ -1076,6 +1192,8 @@ impl Reactor {
             RunOnceCmdInner::RemoveLeg { leg, reason } => {
                 warn!(tunnel_id = %self.tunnel_id, reason = %reason, "removing circuit leg");
 
+                #[cfg(feature = "conflux")]
+                let old_primary = self.circuits.primary_leg_id();
                 let circ = self.circuits.remove(leg)?;
                 let is_conflux_pending = circ.is_conflux_pending();
 
@@ -1142,7 +1260,7 @@ impl Reactor {
             #[cfg(feature = "conflux")]
             RunOnceCmdInner::Enqueue { leg, msg } => {
                 let entry = ConfluxHeapEntry { leg_id: leg, msg };
-                self.ooo_msgs.push(entry);
+                self.ooo_msgs.push(entry)?;
             }
         }
 
@@ -1247,6 +1365,14 @@ impl Reactor {
         Ok(())
     }
 
+    /// Send `event` to all the subscribers to our [`ConfluxLegEvent`]s,
+    /// forgetting about the ones that have gone away.
+    #[cfg(feature = "conflux")]
+    fn send_conflux_leg_event(&mut self, event: ConfluxLegEvent) {
+        self.conflux_leg_event_txs
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
     /// Prepare a `SendRelayCell` request, and install the given meta-cell handler.
     fn prepare_msg_and_install_handler(
         &mut self,
@@ -1509,5 +1635,53 @@ impl CellHandlers {
 
 #[cfg(test)]
 mod test {
-    // Tested in [`crate::tunnel::circuit::test`].
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+
+    // Most of the reactor is tested in [`crate::tunnel::circuit::test`].
+
+    use super::*;
+
+    #[test]
+    fn circuit_cmd_batches() {
+        let leg = UniqId::new(1, 1);
+        let sendme = |hop: u8| CircuitCmd::HandleSendMe {
+            hop: hop.into(),
+            sendme: Sendme::new_empty(),
+        };
+        let hop_of = |cmd: &RunOnceCmdInner| match cmd {
+            RunOnceCmdInner::HandleSendMe { hop, .. } => *hop,
+            _ => panic!("unexpected command {cmd:?}"),
+        };
+
+        assert!(CircuitCmd::batch([]).is_none());
+
+        // A lone command isn't wrapped in a batch.
+        let cmd = CircuitCmd::batch([sendme(0)]).unwrap();
+        assert!(matches!(cmd, CircuitCmd::HandleSendMe { .. }));
+        let RunOnceCmd::Single(cmd) = RunOnceCmd::from_circuit_cmd(leg, cmd) else {
+            panic!("single command became a batch");
+        };
+        assert_eq!(hop_of(&cmd), 0.into());
+
+        // Nested batches are flattened, preserving the order of the commands.
+        let inner = CircuitCmd::batch([sendme(1), sendme(2)]).unwrap();
+        let cmd = CircuitCmd::batch([sendme(0), inner, sendme(3)]).unwrap();
+        let RunOnceCmd::Multiple(cmds) = RunOnceCmd::from_circuit_cmd(leg, cmd) else {
+            panic!("batch became a single command");
+        };
+        let hops = cmds.iter().map(hop_of).collect::<Vec<_>>();
+        assert_eq!(hops, [0, 1, 2, 3].map(HopNum::from));
+    }
 }
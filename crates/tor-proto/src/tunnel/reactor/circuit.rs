@@ -5,9 +5,9 @@ pub(super) mod create;
 pub(super) mod extender;
 
 use crate::channel::{Channel, ChannelSender};
-use crate::circuit::HopSettings;
 #[cfg(feature = "counter-galois-onion")]
 use crate::circuit::handshake::RelayCryptLayerProtocol;
+use crate::circuit::{DroppedCellStats, HopSettings, RelayCellFormatStats, RelayCmdStats};
 use crate::congestion::CongestionSignals;
 use crate::congestion::sendme;
 use crate::crypto::binding::CircuitBinding;
@@ -57,6 +57,7 @@ use safelog::sensitive as sv;
 use tor_rtcompat::DynTimeProvider;
 use tracing::{debug, trace, warn};
 
+use super::profile::{EventClass, Profiler, Timer};
 use super::{
     CellHandlers, CircuitHandshake, CloseStreamBehavior, ReactorResultChannel, SendRelayCell,
 };
@@ -66,16 +67,13 @@ use std::pin::Pin;
 use std::result::Result as StdResult;
 use std::sync::Arc;
 use std::task::Poll;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use create::{Create2Wrap, CreateFastWrap, CreateHandshakeWrap};
 use extender::HandshakeAuxDataHandler;
 
 #[cfg(feature = "hs-service")]
-use {
-    crate::stream::{DataCmdChecker, IncomingStreamRequest},
-    tor_cell::relaycell::msg::Begin,
-};
+use crate::stream::{DataCmdChecker, IncomingStreamRequest};
 
 #[cfg(feature = "conflux")]
 use {
@@ -87,15 +85,22 @@ use {
 
 pub(super) use circhop::{CircHop, CircHopList};
 
-/// Initial value for outbound flow-control window on streams.
-pub(super) const SEND_WINDOW_INIT: u16 = 500;
-/// Initial value for inbound flow-control window on streams.
+/// Default initial value for outbound flow-control window on streams.
+///
+/// The window actually used for each hop comes from its [`HopSettings`].
+pub(crate) const SEND_WINDOW_INIT: u16 = 500;
+/// Default initial value for inbound flow-control window on streams.
+///
+/// The window actually used for each hop comes from its [`HopSettings`].
 pub(crate) const RECV_WINDOW_INIT: u16 = 500;
 /// Size of the buffer used between the reactor and a `StreamReader`.
 ///
 /// FIXME(eta): We pick 2× the receive window, which is very conservative (we arguably shouldn't
 ///             get sent more than the receive window anyway!). We might do due to things that
 ///             don't count towards the window though.
+///
+/// (Since no hop's receive window can be larger than the default one,
+/// this is also large enough for every hop.)
 pub(crate) const STREAM_READER_BUFFER: usize = (2 * RECV_WINDOW_INIT) as usize;
 
 /// A circuit "leg" from a tunnel.
@@ -141,6 +146,8 @@ pub(crate) struct Circuit {
     /// Memory quota account
     #[allow(dead_code)] // Partly here to keep it alive as long as the circuit
     memquota: CircuitAccount,
+    /// A record of the time we have spent on different kinds of work for this circuit.
+    profiler: Profiler,
 }
 
 /// A command to run in response to a circuit event.
@@ -191,6 +198,28 @@ pub(super) enum CircuitCmd {
     /// Enqueue an out-of-order cell in the reactor.
     #[cfg(feature = "conflux")]
     Enqueue(OooRelayMsg),
+    /// Run several commands, in order, in the same reactor iteration.
+    ///
+    /// The reactor handles no other input between the commands of a batch,
+    /// so this is for commands whose effects must not be separated
+    /// (for example, closing a stream, and sending a cell that refers to it).
+    ///
+    /// Use [`CircuitCmd::batch`] to construct this.
+    Batch(Vec<CircuitCmd>),
+}
+
+impl CircuitCmd {
+    /// Combine `cmds` into a single command that runs them in order.
+    ///
+    /// Returns `None` if there are no commands,
+    /// and a lone command as-is, rather than as a [`Batch`](CircuitCmd::Batch).
+    pub(super) fn batch(cmds: impl IntoIterator<Item = CircuitCmd>) -> Option<CircuitCmd> {
+        let mut cmds: Vec<_> = cmds.into_iter().collect();
+        match cmds.len() {
+            0 | 1 => cmds.pop(),
+            _ => Some(CircuitCmd::Batch(cmds)),
+        }
+    }
 }
 
 /// Return a `CircProto` error for the specified unsupported cell.
@@ -249,9 +278,15 @@ impl Circuit {
             #[cfg(feature = "conflux")]
             conflux_handler: None,
             memquota,
+            profiler: Profiler::default(),
         }
     }
 
+    /// Return the record of the time we have spent on different kinds of work for this circuit.
+    pub(super) fn profiler(&self) -> &Profiler {
+        &self.profiler
+    }
+
     /// Return the process-unique identifier of this circuit.
     pub(super) fn unique_id(&self) -> UniqId {
         self.unique_id.unique_id()
@@ -262,6 +297,22 @@ impl Circuit {
         &self.mutable
     }
 
+    /// Return the relay cell format and sent-cell count of each hop of this circuit.
+    pub(super) fn relay_cell_format_stats(&self) -> Vec<RelayCellFormatStats> {
+        self.hops.relay_cell_format_stats()
+    }
+
+    /// Return the counts of unused cells that we have received from each hop of this circuit.
+    pub(super) fn dropped_cell_stats(&self) -> Vec<DroppedCellStats> {
+        self.hops.dropped_cell_stats()
+    }
+
+    /// Return the counts of relay messages that we have received from each hop of this circuit,
+    /// by command.
+    pub(super) fn relay_cmd_stats(&self) -> Vec<RelayCmdStats> {
+        self.hops.relay_cmd_stats()
+    }
+
     /// Add this circuit to a multipath tunnel, by associating it with a new [`TunnelId`],
     /// and installing a [`ConfluxMsgHandler`] on this circuit.
     ///
@@ -323,6 +374,30 @@ impl Circuit {
         }
     }
 
+    /// Return the earliest time at which we have to send
+    /// the circuit-level SENDMEs that we are holding back, if any.
+    ///
+    /// See [`SendmeEmission::Delayed`](crate::circuit::SendmeEmission::Delayed).
+    pub(super) fn delayed_sendmes_due(&self) -> Option<Instant> {
+        self.hops.delayed_sendmes_due()
+    }
+
+    /// Return a command to send the circuit-level SENDMEs
+    /// that we have been holding back, and have to send by now.
+    ///
+    /// The SENDMEs are sent together, as a [`CircuitCmd::Batch`] if there are several.
+    pub(super) fn take_due_sendmes(&mut self) -> Option<CircuitCmd> {
+        use tor_rtcompat::SleepProvider as _;
+
+        let now = self.runtime.now();
+        CircuitCmd::batch(
+            self.hops
+                .take_due_sendmes(now)
+                .into_iter()
+                .map(CircuitCmd::Send),
+        )
+    }
+
     /// Handle a [`CtrlMsg::AddFakeHop`](super::CtrlMsg::AddFakeHop) message.
     #[cfg(test)]
     pub(super) fn handle_add_fake_hop(
@@ -449,6 +524,7 @@ impl Circuit {
             early,
             msg,
         )?;
+        circhop.note_cell_sent();
         // The cell counted for congestion control, inform our algorithm of such and pass down the
         // tag for authenticated SENDMEs.
         if c_t_w {
@@ -475,9 +551,8 @@ impl Circuit {
     // allocating a `Vec` here. Generally, the number of commands is going to be small
     // (usually 1, but > 1 when we start supporting packed cells).
     //
-    // We should consider using smallvec instead. It might also be a good idea to have a
-    // separate higher-level type splitting this out into Single(CircuitCmd),
-    // and Multiple(SmallVec<[CircuitCmd; <capacity>]>).
+    // We should consider using smallvec instead.
+    // (The reactor turns the commands into a single `CircuitCmd` using `CircuitCmd::batch`.)
     pub(super) fn handle_cell(
         &mut self,
         handlers: &mut CellHandlers,
@@ -537,7 +612,10 @@ impl Circuit {
         leg: UniqId,
         cell: Relay,
     ) -> Result<Vec<CircuitCmd>> {
-        let (hopnum, tag, decode_res) = self.decode_relay_cell(cell)?;
+        let timer = Timer::start();
+        let decoded = self.decode_relay_cell(cell);
+        self.profiler.record(EventClass::CellDecode, timer);
+        let (hopnum, tag, decode_res) = decoded?;
 
         // Check whether we are allowed to receive more data for this circuit hop.
         self.hop_mut(hopnum)
@@ -558,34 +636,54 @@ impl Circuit {
         };
 
         let mut circ_cmds = vec![];
-        // If we do need to send a circuit-level SENDME cell, do so.
+        // If we do need to send a circuit-level SENDME cell, do so,
+        // either right away or once the hop's SENDME delay has passed.
         if send_circ_sendme {
+            use tor_rtcompat::SleepProvider as _;
+
             // This always sends a V1 (tagged) sendme cell, and thereby assumes
             // that SendmeEmitMinVersion is no more than 1.  If the authorities
             // every increase that parameter to a higher number, this will
             // become incorrect.  (Higher numbers are not currently defined.)
             let sendme = Sendme::from(tag);
             let cell = AnyRelayMsgOuter::new(None, sendme.into());
-            circ_cmds.push(CircuitCmd::Send(SendRelayCell {
-                hop: hopnum,
-                early: false,
-                cell,
-            }));
 
+            let now = self.runtime.now();
+            let hop = self.hop_mut(hopnum).ok_or_else(|| {
+                Error::from(internal!(
+                    "Trying to send SENDME to nonexistent hop {:?}",
+                    hopnum
+                ))
+            })?;
             // Inform congestion control of the SENDME we are sending. This is a circuit level one.
-            self.hop_mut(hopnum)
-                .ok_or_else(|| {
-                    Error::from(internal!(
-                        "Trying to send SENDME to nonexistent hop {:?}",
-                        hopnum
-                    ))
-                })?
-                .ccontrol_mut()
-                .note_sendme_sent()?;
+            //
+            // (If we hold the SENDME back, this reopens our receive window a little early,
+            // but the hop can't use the window until it gets the SENDME anyway.)
+            hop.ccontrol_mut().note_sendme_sent()?;
+            if let Some(sendme) = hop.schedule_sendme(cell, now) {
+                circ_cmds.push(CircuitCmd::Send(sendme));
+            }
         }
 
         let (mut msgs, incomplete) = decode_res.into_parts();
         while let Some(msg) = msgs.next() {
+            let hop = self
+                .hop_mut(hopnum)
+                .ok_or_else(|| internal!("nonexistent hop {:?}", hopnum))?;
+            hop.note_received_cmd(msg.cmd());
+            let accepted = hop.check_inbound_cmd(msg.cmd())?;
+            if !accepted {
+                if let Some(sendme) = hop.note_discarded_msg(&msg)? {
+                    let cell = AnyRelayMsgOuter::new(msg.stream_id(), sendme.into());
+                    circ_cmds.push(CircuitCmd::Send(SendRelayCell {
+                        hop: hopnum,
+                        early: false,
+                        cell,
+                    }));
+                }
+                continue;
+            }
+
             let msg_status = self.handle_relay_msg(handlers, hopnum, leg, c_t_w, msg)?;
 
             match msg_status {
@@ -685,10 +783,17 @@ impl Circuit {
 
         // Returns the original message if it's an an incoming stream request
         // that we need to handle.
+        //
+        // (We borrow `self.hops` directly, rather than using `hop_mut`,
+        // so that we can still use `self.profiler`.)
         let hop = self
-            .hop_mut(hopnum)
+            .hops
+            .get_mut(hopnum)
             .ok_or_else(|| Error::CircProto("Cell from nonexistent hop!".into()))?;
-        let res = hop.handle_msg(cell_counts_toward_windows, streamid, msg)?;
+        let timer = Timer::start();
+        let res = hop.handle_msg(cell_counts_toward_windows, streamid, msg);
+        self.profiler.record(EventClass::StreamDelivery, timer);
+        let res = res?;
 
         // If it was an incoming stream request, we don't need to worry about
         // sending an XOFF as there's no stream data within this message.
@@ -703,7 +808,10 @@ impl Circuit {
         }
 
         // We may want to send an XOFF if the incoming buffer is too large.
-        if let Some(cell) = hop.maybe_send_xoff(streamid)? {
+        let timer = Timer::start();
+        let xoff = hop.maybe_send_xoff(streamid);
+        self.profiler.record(EventClass::FlowControl, timer);
+        if let Some(cell) = xoff? {
             let cell = AnyRelayMsgOuter::new(Some(streamid), cell.into());
             let cell = SendRelayCell {
                 hop: hopnum,
@@ -723,6 +831,7 @@ impl Circuit {
     ///   * this is not a conflux circuit (i.e. it doesn't have a [`ConfluxMsgHandler`])
     ///   * this is a client circuit and the conflux message originated an unexpected hop
     ///   * the cell was sent in violation of the handshake protocol
+    ///   * the peer is abusing SWITCH cells
     #[cfg(feature = "conflux")]
     fn handle_conflux_msg(
         &mut self,
@@ -739,7 +848,7 @@ impl Circuit {
             )));
         };
 
-        Ok(conflux_handler.handle_conflux_msg(msg, hop))
+        conflux_handler.handle_conflux_msg(msg, hop)
     }
 
     /// For conflux: return the sequence number of the last cell sent on this leg.
@@ -799,6 +908,7 @@ impl Circuit {
         use tor_error::into_internal;
         use tor_log_ratelim::log_ratelim;
 
+        use crate::stream::IncomingStreamOverflow;
         use crate::{circuit::CIRCUIT_BUFFER_SIZE, tunnel::reactor::StreamReqInfo};
 
         // We need to construct this early so that we don't double-borrow &mut self
@@ -835,12 +945,36 @@ impl Circuit {
             return Ok(None);
         }
 
-        let begin = msg
-            .decode::<Begin>()
-            .map_err(|e| Error::from_bytes_err(e, "Invalid Begin message"))?
-            .into_msg();
+        // Reject requests that exceed our rate limit before we do any more work on them.
+        if let Some(bucket) = handler.rate_limit.as_mut() {
+            use tor_rtcompat::SleepProvider as _;
+
+            bucket.refill(self.runtime.now());
+            if bucket.drain(1).is_err() {
+                debug!(
+                    circ_id = %self.unique_id,
+                    "Rejecting {} request that exceeds our rate limit",
+                    msg.cmd(),
+                );
+                let end_msg = AnyRelayMsgOuter::new(
+                    Some(stream_id),
+                    End::new_with_reason(EndReason::RESOURCELIMIT).into(),
+                );
+                let cell = SendRelayCell {
+                    hop: hop_num,
+                    early: false,
+                    cell: end_msg,
+                };
+                return Ok(Some(CircuitCmd::Send(cell)));
+            }
+        }
 
-        let req = IncomingStreamRequest::Begin(begin);
+        // The command checker has already made sure that this is one of the
+        // request types that we allow on this circuit.
+        let req = msg
+            .decode::<IncomingStreamRequest>()
+            .map_err(|e| Error::from_bytes_err(e, "invalid incoming stream request"))?
+            .into_msg();
 
         {
             use crate::stream::IncomingStreamRequestDisposition::*;
@@ -872,6 +1006,7 @@ impl Circuit {
         // since we needed to pass `&self.hops` by reference to our filter above. :(
         let hop = self.hops.get_mut(hop_num).ok_or(Error::CircuitClosed)?;
         let relay_cell_format = hop.relay_format();
+        let recv_window_init = hop.stream_recv_window_init();
 
         let memquota = StreamAccount::new(&self.memquota)?;
 
@@ -915,6 +1050,7 @@ impl Circuit {
             drain_rate_request_stream: drain_rate_request_rx,
             memquota,
             relay_cell_format,
+            recv_window_init,
         });
 
         log_ratelim!("Delivering message to incoming stream handler"; outcome);
@@ -922,19 +1058,28 @@ impl Circuit {
         if let Err(e) = outcome {
             if e.is_full() {
                 // The IncomingStreamRequestHandler's stream is full; it isn't
-                // handling requests fast enough. So instead, we reply with an
-                // END cell.
-                let end_msg = AnyRelayMsgOuter::new(
-                    Some(stream_id),
-                    End::new_with_reason(EndReason::RESOURCELIMIT).into(),
-                );
+                // handling requests fast enough.
+                if handler.overflow == IncomingStreamOverflow::CloseCircuit {
+                    debug!(
+                        circ_id = %self.unique_id,
+                        "Incoming stream request queue is full; closing circuit",
+                    );
+                    return Ok(Some(CircuitCmd::CleanShutdown));
+                }
 
-                let cell = SendRelayCell {
+                // So instead, we reply with an END cell.
+                //
+                // We have already added the stream to our map, so we must close it
+                // in the same step: otherwise we would notice later that its
+                // (discarded) sender is gone, and send a second END.
+                return Ok(Some(CircuitCmd::CloseStream {
                     hop: hop_num,
-                    early: false,
-                    cell: end_msg,
-                };
-                return Ok(Some(CircuitCmd::Send(cell)));
+                    sid: stream_id,
+                    behav: CloseStreamBehavior::SendEnd(End::new_with_reason(
+                        EndReason::RESOURCELIMIT,
+                    )),
+                    reason: streammap::TerminateReason::ExplicitEnd,
+                }));
             } else if e.is_disconnected() {
                 // The IncomingStreamRequestHandler's stream has been dropped.
                 // In the Tor protocol as it stands, this always means that the
@@ -1169,7 +1314,7 @@ impl Circuit {
 
         let hop_num = (hop_num as u8).into();
 
-        let hop = CircHop::new(self.unique_id, hop_num, settings);
+        let hop = CircHop::new(self.unique_id, hop_num, settings, self.profiler.clone());
         self.hops.push(hop);
         self.crypto_in.add_layer(rev);
         self.crypto_out.add_layer(fwd);
@@ -1223,6 +1368,14 @@ impl Circuit {
             return Ok(Some(CircuitCmd::CleanShutdown));
         }
 
+        if msg.cmd() == RelayCmd::DROP {
+            // DROP cells are padding: all we do with them is count them.
+            self.hop_mut(hopnum)
+                .ok_or_else(|| internal!("nonexistent hop {:?}", hopnum))?
+                .note_drop_cell()?;
+            return Ok(None);
+        }
+
         trace!(circ_id = %self.unique_id, cell = ?msg, "Received meta-cell");
 
         #[cfg(feature = "conflux")]
@@ -1295,6 +1448,7 @@ impl Circuit {
         msg: Sendme,
         signals: CongestionSignals,
     ) -> Result<Option<CircuitCmd>> {
+        let timer = Timer::start();
         // Cloned, because we borrow mutably from self when we get the circhop.
         let runtime = self.runtime.clone();
 
@@ -1311,6 +1465,9 @@ impl Circuit {
         // Update the CC object that we received a SENDME along with possible congestion signals.
         hop.ccontrol_mut()
             .note_sendme_received(&runtime, tag, signals)?;
+        // The SENDME may have opened up our congestion window.
+        hop.notify_send_ready();
+        self.profiler.record(EventClass::FlowControl, timer);
         Ok(None)
     }
 
@@ -1348,7 +1505,7 @@ impl Circuit {
         &self,
         exclude: Option<HopNum>,
     ) -> impl Stream<Item = Result<CircuitCmd>> + use<> {
-        self.hops.ready_streams_iterator(exclude)
+        self.hops.ready_streams_iterator(exclude, &self.profiler)
     }
 
     /// Return the congestion signals for this reactor. This is used by congestion control module.
@@ -1,10 +1,13 @@
 //! Module exposing structures relating to the reactor's view of a circuit's hops.
 
 use super::CircuitCmd;
-use super::{CloseStreamBehavior, SEND_WINDOW_INIT, SendRelayCell};
-use crate::circuit::HopSettings;
+use super::{CloseStreamBehavior, SendRelayCell};
+use crate::circuit::{
+    CmdFilterAction, DroppedCellStats, HopSettings, RelayCellFormatStats, RelayCmdFilter,
+    RelayCmdStats,
+};
 use crate::congestion::CongestionControl;
-use crate::congestion::sendme;
+use crate::congestion::sendme::{self, SendmeEmission};
 use crate::crypto::cell::HopNum;
 use crate::stream::queue::StreamQueueSender;
 use crate::stream::{
@@ -12,6 +15,8 @@ use crate::stream::{
 };
 use crate::tunnel::TunnelScopedCircId;
 use crate::tunnel::circuit::StreamMpscReceiver;
+use crate::tunnel::reactor::ReactorResultChannel;
+use crate::tunnel::reactor::profile::{EventClass, Profiler, Timer};
 use crate::tunnel::streammap::{
     self, EndSentStreamEnt, OpenStreamEnt, ShouldSendEnd, StreamEntMut,
 };
@@ -24,7 +29,7 @@ use postage::watch;
 use safelog::sensitive as sv;
 use tor_cell::chancell::BoxedCellBody;
 use tor_cell::relaycell::flow_ctrl::{Xoff, Xon, XonKbpsEwma};
-use tor_cell::relaycell::msg::AnyRelayMsg;
+use tor_cell::relaycell::msg::{AnyRelayMsg, Sendme};
 use tor_cell::relaycell::{
     AnyRelayMsgOuter, RelayCellDecoder, RelayCellDecoderResult, RelayCellFormat, RelayCmd,
     RelayMsg, StreamId, UnparsedRelayMsg,
@@ -36,8 +41,9 @@ use tracing::{trace, warn};
 use std::num::NonZeroU32;
 use std::pin::Pin;
 use std::result::Result as StdResult;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::task::Poll;
+use std::time::Instant;
 
 #[cfg(test)]
 use tor_cell::relaycell::msg::SendmeTag;
@@ -75,6 +81,56 @@ impl CircHopList {
         self.hops.len()
     }
 
+    /// Return the relay cell format and sent-cell count of every hop in the list, in order.
+    pub(super) fn relay_cell_format_stats(&self) -> Vec<RelayCellFormatStats> {
+        self.hops
+            .iter()
+            .map(|hop| RelayCellFormatStats {
+                format: hop.relay_format,
+                cells_sent: hop.n_cells_sent,
+            })
+            .collect()
+    }
+
+    /// Return the counts of unused cells received from every hop in the list, in order.
+    pub(super) fn dropped_cell_stats(&self) -> Vec<DroppedCellStats> {
+        self.hops
+            .iter()
+            .map(|hop| DroppedCellStats {
+                drop_cells: hop.n_drop_cells,
+                ignored_cells: hop.n_ignored_cells,
+                rejected_cells: hop.n_rejected_cells,
+            })
+            .collect()
+    }
+
+    /// Return the earliest time at which we have to send
+    /// the circuit-level SENDMEs that any hop in the list is holding back, if any.
+    pub(super) fn delayed_sendmes_due(&self) -> Option<Instant> {
+        self.hops
+            .iter()
+            .filter_map(|hop| hop.delayed_sendmes_due)
+            .min()
+    }
+
+    /// Take the circuit-level SENDMEs that any hop in the list is holding back,
+    /// and has to send by `now`.
+    pub(super) fn take_due_sendmes(&mut self, now: Instant) -> Vec<SendRelayCell> {
+        self.hops
+            .iter_mut()
+            .filter(|hop| hop.delayed_sendmes_due.is_some_and(|due| due <= now))
+            .flat_map(|hop| hop.take_delayed_sendmes())
+            .collect()
+    }
+
+    /// Return the counts of relay messages received from every hop in the list, in order.
+    pub(super) fn relay_cmd_stats(&self) -> Vec<RelayCmdStats> {
+        self.hops
+            .iter()
+            .map(|hop| hop.relay_cmd_stats.clone())
+            .collect()
+    }
+
     /// Returns a [`Stream`] of [`CircuitCmd`] to poll from the main loop.
     ///
     /// The iterator contains at most one [`CircuitCmd`] for each hop,
@@ -90,6 +146,7 @@ impl CircHopList {
     pub(super) fn ready_streams_iterator(
         &self,
         exclude: Option<HopNum>,
+        profiler: &Profiler,
     ) -> impl Stream<Item = Result<CircuitCmd>> + use<> {
         self.hops
             .iter()
@@ -122,20 +179,25 @@ impl CircHopList {
                 }
 
                 let hop_map = Arc::clone(&self.hops[i].map);
+                let profiler = profiler.clone();
                 Some(futures::future::poll_fn(move |cx| {
+                    let timer = Timer::start();
                     // Process an outbound message from the first ready stream on
                     // this hop. The stream map implements round robin scheduling to
                     // ensure fairness across streams.
                     // TODO: Consider looping here to process multiple ready
                     // streams. Need to be careful though to balance that with
                     // continuing to service incoming and control messages.
-                    let mut hop_map = hop_map.lock().expect("lock poisoned");
+                    let mut hop_map = profiler.lock_stream_map(&hop_map);
                     let Some((sid, msg)) = hop_map.poll_ready_streams_iter(cx).next() else {
                         // No ready streams for this hop.
                         return Poll::Pending;
                     };
 
+                    // We only count the polls that find a ready stream:
+                    // the others are cheap, and there are a lot of them.
                     if msg.is_none() {
+                        profiler.record(EventClass::StreamDelivery, timer);
                         return Poll::Ready(Ok(CircuitCmd::CloseStream {
                             hop: hop_num,
                             sid,
@@ -145,7 +207,6 @@ impl CircHopList {
                     };
                     let msg = hop_map.take_ready_msg(sid).expect("msg disappeared");
 
-                    #[allow(unused)] // unused in non-debug builds
                     let Some(StreamEntMut::Open(s)) = hop_map.get_mut(sid) else {
                         panic!("Stream {sid} disappeared");
                     };
@@ -155,11 +216,18 @@ impl CircHopList {
                         "Stream {sid} produced a message it can't send: {msg:?}"
                     );
 
+                    if msg.cmd() == RelayCmd::END {
+                        // The stream is closing its write side, but still wants to read:
+                        // keep it open until the other side ends it too.
+                        s.close_write();
+                    }
+
                     let cell = SendRelayCell {
                         hop: hop_num,
                         early: false,
                         cell: AnyRelayMsgOuter::new(Some(sid), msg),
                     };
+                    profiler.record(EventClass::StreamDelivery, timer);
                     Poll::Ready(Ok(CircuitCmd::Send(cell)))
                 }))
             })
@@ -174,7 +242,7 @@ impl CircHopList {
     pub(super) fn has_streams(&self) -> bool {
         self.hops
             .iter()
-            .any(|hop| hop.map.lock().expect("lock poisoned").n_open_streams() > 0)
+            .any(|hop| hop.lock_map().n_open_streams() > 0)
     }
 
     /// Return the number of streams currently open on this circuit.
@@ -235,6 +303,47 @@ pub(crate) struct CircHop {
     ///
     /// If this ever decrements from Some(1), then the circuit must be torn down with an error.
     n_outgoing_cells_permitted: Option<NonZeroU32>,
+
+    /// Restrictions on the relay commands we accept from this hop.
+    inbound_cmd_filter: Option<RelayCmdFilter>,
+    /// The number of cells from this hop that were rejected by `inbound_cmd_filter`.
+    n_rejected_cells: u64,
+    /// The number of DROP cells that we have received from this hop.
+    n_drop_cells: u64,
+    /// The maximum number of DROP cells that we accept from this hop.
+    max_drop_cells: Option<u64>,
+    /// The number of cells from this hop that we discarded,
+    /// because nothing was reading the streams they were for.
+    n_ignored_cells: u64,
+    /// The maximum number of ignored cells that we accept from this hop.
+    max_ignored_cells: Option<u64>,
+    /// The number of relay messages that we have received from this hop, by command.
+    relay_cmd_stats: RelayCmdStats,
+    /// Senders to notify once we have received enough messages with a given command
+    /// from this hop, along with that command and the number of messages.
+    ///
+    /// See [`CircHop::wait_for_relay_cmd_count`].
+    relay_cmd_waiters: Vec<(RelayCmd, u64, ReactorResultChannel<RelayCmdStats>)>,
+    /// The number of relay cells that we have encoded (in `relay_format`) and sent to this hop.
+    n_cells_sent: u64,
+    /// When to send the circuit-level SENDMEs for the cells we receive from this hop.
+    sendme_emission: SendmeEmission,
+    /// The circuit-level SENDMEs that we are holding back, under [`SendmeEmission::Delayed`].
+    delayed_sendmes: Vec<AnyRelayMsgOuter>,
+    /// When we have to send `delayed_sendmes`.
+    ///
+    /// This is `None` if there are none.
+    delayed_sendmes_due: Option<Instant>,
+    /// The initial send window for the streams we open to this hop.
+    stream_send_window_init: u16,
+    /// The initial receive window for the streams to this hop.
+    stream_recv_window_init: u16,
+    /// Where we record how long we wait for the lock on `map`.
+    profiler: Profiler,
+    /// Senders to notify once congestion control lets us send on this hop again.
+    ///
+    /// See [`CircHop::wait_until_can_send`].
+    send_ready_waiters: Vec<ReactorResultChannel<()>>,
 }
 
 impl CircHop {
@@ -243,6 +352,7 @@ impl CircHop {
         unique_id: TunnelScopedCircId,
         hop_num: HopNum,
         settings: &HopSettings,
+        profiler: Profiler,
     ) -> Self {
         /// Convert a limit from the form used in a HopSettings to that used here.
         /// (The format we use here is more compact.)
@@ -254,15 +364,35 @@ impl CircHop {
                 .expect("Adding one left it as zero?")
         }
         let relay_format = settings.relay_crypt_protocol().relay_cell_format();
+        let mut ccontrol = CongestionControl::new(&settings.ccontrol);
+        ccontrol.set_sendme_tag_check(settings.sendme_tag_check);
         CircHop {
             unique_id,
             hop_num,
-            map: Arc::new(Mutex::new(streammap::StreamMap::new())),
-            ccontrol: CongestionControl::new(&settings.ccontrol),
+            map: Arc::new(Mutex::new(streammap::StreamMap::new(
+                settings.stream_recv_window_init,
+            ))),
+            ccontrol,
             inbound: RelayCellDecoder::new(relay_format),
             relay_format,
             n_incoming_cells_permitted: settings.n_incoming_cells_permitted.map(cvt),
             n_outgoing_cells_permitted: settings.n_outgoing_cells_permitted.map(cvt),
+            inbound_cmd_filter: settings.inbound_cmd_filter.clone(),
+            n_rejected_cells: 0,
+            n_drop_cells: 0,
+            max_drop_cells: settings.max_inbound_drop_cells,
+            n_ignored_cells: 0,
+            max_ignored_cells: settings.max_inbound_ignored_cells,
+            relay_cmd_stats: RelayCmdStats::default(),
+            relay_cmd_waiters: Vec::new(),
+            n_cells_sent: 0,
+            sendme_emission: settings.sendme_emission,
+            delayed_sendmes: Vec::new(),
+            delayed_sendmes_due: None,
+            stream_send_window_init: settings.stream_send_window_init,
+            stream_recv_window_init: settings.stream_recv_window_init,
+            profiler,
+            send_ready_waiters: Vec::new(),
         }
     }
 
@@ -278,11 +408,9 @@ impl CircHop {
         cmd_checker: AnyCmdChecker,
     ) -> Result<(SendRelayCell, StreamId)> {
         let flow_ctrl = self.build_flow_ctrl(rate_limit_updater, drain_rate_requester)?;
-        let r =
-            self.map
-                .lock()
-                .expect("lock poisoned")
-                .add_ent(sender, rx, flow_ctrl, cmd_checker)?;
+        let r = self
+            .lock_map()
+            .add_ent(sender, rx, flow_ctrl, cmd_checker)?;
         let cell = AnyRelayMsgOuter::new(Some(r), message);
         Ok((
             SendRelayCell {
@@ -306,7 +434,7 @@ impl CircHop {
         message: CloseStreamBehavior,
         why: streammap::TerminateReason,
     ) -> Result<Option<SendRelayCell>> {
-        let should_send_end = self.map.lock().expect("lock poisoned").terminate(id, why)?;
+        let should_send_end = self.lock_map().terminate(id, why)?;
         trace!(
             circ_id = %self.unique_id,
             stream_id = %id,
@@ -344,7 +472,7 @@ impl CircHop {
             return Ok(None);
         }
 
-        let mut map = self.map.lock().expect("lock poisoned");
+        let mut map = self.lock_map();
         let Some(StreamEntMut::Open(ent)) = map.get_mut(id) else {
             // stream went away
             return Ok(None);
@@ -363,7 +491,7 @@ impl CircHop {
             return Ok(None);
         }
 
-        let mut map = self.map.lock().expect("lock poisoned");
+        let mut map = self.lock_map();
         let Some(StreamEntMut::Open(ent)) = map.get_mut(id) else {
             // stream went away
             return Ok(None);
@@ -386,12 +514,28 @@ impl CircHop {
         self.ccontrol.send_window_and_expected_tags()
     }
 
+    /// Return a snapshot of the flow control state of each open stream on this hop.
+    ///
+    /// WARNING: because this locks the stream map mutex,
+    /// it should never be called from a context where that mutex is already locked.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn stream_flow_control_states(&self) -> Vec<crate::stream::StreamFlowControlState> {
+        self.lock_map().flow_control_states()
+    }
+
     /// Return the number of open streams on this hop.
     ///
     /// WARNING: because this locks the stream map mutex,
     /// it should never be called from a context where that mutex is already locked.
     pub(crate) fn n_open_streams(&self) -> usize {
-        self.map.lock().expect("lock poisoned").n_open_streams()
+        self.lock_map().n_open_streams()
+    }
+
+    /// Lock our stream map.
+    ///
+    /// (With the `reactor-profiling` feature, this records whether we had to wait for it.)
+    fn lock_map(&self) -> MutexGuard<'_, streammap::StreamMap> {
+        self.profiler.lock_stream_map(&self.map)
     }
 
     /// Return a reference to our CongestionControl object.
@@ -409,6 +553,80 @@ impl CircHop {
         self.relay_format
     }
 
+    /// Arrange to send `sendme`, a circuit-level SENDME for a cell we received at `now`.
+    ///
+    /// Returns `sendme` if we should send it right away;
+    /// otherwise, we hold it back until [`CircHopList::take_due_sendmes`] takes it.
+    pub(super) fn schedule_sendme(
+        &mut self,
+        sendme: AnyRelayMsgOuter,
+        now: Instant,
+    ) -> Option<SendRelayCell> {
+        match self.sendme_emission {
+            SendmeEmission::Immediate => Some(SendRelayCell {
+                hop: self.hop_num,
+                early: false,
+                cell: sendme,
+            }),
+            SendmeEmission::Delayed(delay) => {
+                // Any SENDMEs that come due before then go out with this one.
+                self.delayed_sendmes_due.get_or_insert(now + delay);
+                self.delayed_sendmes.push(sendme);
+                None
+            }
+        }
+    }
+
+    /// Take all the circuit-level SENDMEs that we are holding back.
+    fn take_delayed_sendmes(&mut self) -> impl Iterator<Item = SendRelayCell> + use<> {
+        let hop = self.hop_num;
+        self.delayed_sendmes_due = None;
+        std::mem::take(&mut self.delayed_sendmes)
+            .into_iter()
+            .map(move |cell| SendRelayCell {
+                hop,
+                early: false,
+                cell,
+            })
+    }
+
+    /// Return the initial receive window for the streams to this hop.
+    pub(crate) fn stream_recv_window_init(&self) -> u16 {
+        self.stream_recv_window_init
+    }
+
+    /// Note that we have encoded a relay cell for this hop, and are about to send it.
+    pub(crate) fn note_cell_sent(&mut self) {
+        self.n_cells_sent = self.n_cells_sent.saturating_add(1);
+    }
+
+    /// Notify `done` once congestion control lets us send on this hop.
+    ///
+    /// If we can send right now, `done` is notified immediately;
+    /// otherwise, it is notified from [`notify_send_ready`](Self::notify_send_ready).
+    pub(crate) fn wait_until_can_send(&mut self, done: ReactorResultChannel<()>) {
+        if self.ccontrol.can_send() {
+            // don't care if receiver goes away
+            let _ = done.send(Ok(()));
+        } else {
+            self.send_ready_waiters.push(done);
+        }
+    }
+
+    /// If congestion control lets us send on this hop,
+    /// notify everybody who is waiting in [`wait_until_can_send`](Self::wait_until_can_send).
+    ///
+    /// This should be called whenever our congestion window might have opened up.
+    pub(crate) fn notify_send_ready(&mut self) {
+        if self.send_ready_waiters.is_empty() || !self.ccontrol.can_send() {
+            return;
+        }
+        for done in self.send_ready_waiters.drain(..) {
+            // don't care if receiver goes away
+            let _ = done.send(Ok(()));
+        }
+    }
+
     /// Take capacity to send `msg`.
     ///
     /// See [`OpenStreamEnt::take_capacity_to_send`].
@@ -419,7 +637,7 @@ impl CircHop {
         stream_id: StreamId,
         msg: &M,
     ) -> Result<()> {
-        let mut hop_map = self.map.lock().expect("lock poisoned");
+        let mut hop_map = self.lock_map();
         let Some(StreamEntMut::Open(ent)) = hop_map.get_mut(stream_id) else {
             warn!(
                 circ_id = %self.unique_id,
@@ -446,7 +664,7 @@ impl CircHop {
         stream_id: StreamId,
         cmd_checker: AnyCmdChecker,
     ) -> Result<()> {
-        let mut hop_map = self.map.lock().expect("lock poisoned");
+        let mut hop_map = self.lock_map();
         hop_map.add_ent_with_id(
             sink,
             rx,
@@ -464,7 +682,7 @@ impl CircHop {
     /// See [`StreamMap::ending_msg_received`](super::streammap::StreamMap::ending_msg_received).
     #[cfg(feature = "hs-service")]
     pub(super) fn ending_msg_received(&self, stream_id: StreamId) -> Result<()> {
-        let mut hop_map = self.map.lock().expect("lock poisoned");
+        let mut hop_map = self.lock_map();
 
         hop_map.ending_msg_received(stream_id)?;
 
@@ -481,6 +699,157 @@ impl CircHop {
             .map_err(|e| Error::from_bytes_err(e, "relay cell"))
     }
 
+    /// Check whether we accept messages with the command `cmd` from this hop.
+    ///
+    /// Returns `Ok(true)` if the message should be handled, and `Ok(false)` if it should be
+    /// discarded. Returns an error if the circuit should be closed.
+    pub(super) fn check_inbound_cmd(&mut self, cmd: RelayCmd) -> Result<bool> {
+        let Some(filter) = &self.inbound_cmd_filter else {
+            return Ok(true);
+        };
+
+        if filter.allows(cmd) {
+            return Ok(true);
+        }
+
+        self.n_rejected_cells += 1;
+        let action = filter.action();
+        warn!(
+            circ_id = %self.unique_id,
+            hop = %self.hop_num.display(),
+            cmd = %cmd,
+            n_rejected_cells = self.n_rejected_cells,
+            action = ?action,
+            "Received relay cell with a disallowed command",
+        );
+
+        match action {
+            CmdFilterAction::CloseCircuit => Err(Error::DisallowedRelayCmd {
+                hop: self.hop_num,
+                cmd,
+            }),
+            CmdFilterAction::Discard => Ok(false),
+        }
+    }
+
+    /// Note that we have discarded `msg`, which we received from this hop,
+    /// because `inbound_cmd_filter` doesn't allow its command.
+    ///
+    /// If `msg` counted towards the window of an open stream,
+    /// we still need to acknowledge it, or the other side would eventually
+    /// run out of window waiting for us.
+    /// Returns the stream-level SENDME that we should send on `msg`'s stream, if any.
+    pub(super) fn note_discarded_msg(&mut self, msg: &UnparsedRelayMsg) -> Result<Option<Sendme>> {
+        if !self.ccontrol.uses_stream_sendme() || !sendme::cell_counts_towards_windows(msg) {
+            return Ok(None);
+        }
+        let Some(id) = msg.stream_id() else {
+            return Ok(None);
+        };
+
+        let mut map = self.lock_map();
+        let Some(StreamEntMut::Open(ent)) = map.get_mut(id) else {
+            // The stream has closed (or never existed),
+            // so nobody is waiting for us to acknowledge this cell.
+            return Ok(None);
+        };
+
+        Ok(ent.note_discarded_cell()?.then(Sendme::new_empty))
+    }
+
+    /// Note that we have received a message with the command `cmd` from this hop.
+    ///
+    /// Notifies everybody who is waiting in
+    /// [`wait_for_relay_cmd_count`](Self::wait_for_relay_cmd_count)
+    /// for the count of `cmd` that we have now reached.
+    pub(super) fn note_received_cmd(&mut self, cmd: RelayCmd) {
+        let count = self.relay_cmd_stats.note(cmd);
+        if self.relay_cmd_waiters.is_empty() {
+            return;
+        }
+        let (reached, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.relay_cmd_waiters)
+            .into_iter()
+            .filter(|(_, _, done)| !done.is_canceled())
+            .partition(|(waiting_cmd, threshold, _)| *waiting_cmd == cmd && count >= *threshold);
+        self.relay_cmd_waiters = waiting;
+        for (_, _, done) in reached {
+            // don't care if receiver goes away
+            let _ = done.send(Ok(self.relay_cmd_stats.clone()));
+        }
+    }
+
+    /// Notify `done` once we have received at least `threshold` messages
+    /// with the command `cmd` from this hop.
+    ///
+    /// If we already have, `done` is notified immediately;
+    /// otherwise, it is notified from [`note_received_cmd`](Self::note_received_cmd).
+    pub(crate) fn wait_for_relay_cmd_count(
+        &mut self,
+        cmd: RelayCmd,
+        threshold: u64,
+        done: ReactorResultChannel<RelayCmdStats>,
+    ) {
+        if self.relay_cmd_stats.count(cmd) >= threshold {
+            // don't care if receiver goes away
+            let _ = done.send(Ok(self.relay_cmd_stats.clone()));
+        } else {
+            // Forget about anybody who has stopped waiting,
+            // so that this list doesn't grow without bound.
+            self.relay_cmd_waiters
+                .retain(|(_, _, waiting)| !waiting.is_canceled());
+            self.relay_cmd_waiters.push((cmd, threshold, done));
+        }
+    }
+
+    /// Return the number of callers in [`wait_for_relay_cmd_count`](Self::wait_for_relay_cmd_count)
+    /// that we have not yet notified, or forgotten about.
+    #[cfg(test)]
+    pub(crate) fn n_relay_cmd_waiters(&self) -> usize {
+        self.relay_cmd_waiters.len()
+    }
+
+    /// Note that we have received a DROP cell from this hop.
+    ///
+    /// Returns an error if we have now received more DROP cells than we accept.
+    pub(super) fn note_drop_cell(&mut self) -> Result<()> {
+        self.n_drop_cells += 1;
+        if self
+            .max_drop_cells
+            .is_some_and(|max| self.n_drop_cells > max)
+        {
+            warn!(
+                circ_id = %self.unique_id,
+                hop = %self.hop_num.display(),
+                n_drop_cells = self.n_drop_cells,
+                "Received too many DROP cells",
+            );
+            return Err(Error::ExcessDropCells { hop: self.hop_num });
+        }
+
+        Ok(())
+    }
+
+    /// Note that we have discarded a cell from this hop, because nothing was reading its stream.
+    ///
+    /// Returns an error if we have now ignored more cells than we accept.
+    fn note_ignored_cell(&mut self) -> Result<()> {
+        self.n_ignored_cells += 1;
+        if self
+            .max_ignored_cells
+            .is_some_and(|max| self.n_ignored_cells > max)
+        {
+            warn!(
+                circ_id = %self.unique_id,
+                hop = %self.hop_num.display(),
+                n_ignored_cells = self.n_ignored_cells,
+                "Received too many cells for abandoned streams",
+            );
+            return Err(Error::ExcessIgnoredCells { hop: self.hop_num });
+        }
+
+        Ok(())
+    }
+
     /// Handle `msg`, delivering it to the stream with the specified `streamid` if appropriate.
     ///
     /// Returns back the provided `msg`, if the message is an incoming stream request
@@ -489,17 +858,21 @@ impl CircHop {
     // TODO: the above is a bit of a code smell -- we should try to avoid passing the msg
     // back and forth like this.
     pub(super) fn handle_msg(
-        &self,
+        &mut self,
         cell_counts_toward_windows: bool,
         streamid: StreamId,
         msg: UnparsedRelayMsg,
     ) -> Result<Option<UnparsedRelayMsg>> {
-        let mut hop_map = self.map.lock().expect("lock poisoned");
+        let mut hop_map = self.lock_map();
+        // Whether we discarded this message, because nothing was reading its stream.
+        let mut ignored = false;
         match hop_map.get_mut(streamid) {
             Some(StreamEntMut::Open(ent)) => {
+                let dropped_before = ent.dropped;
                 // Can't have a stream level SENDME when congestion control is enabled.
                 let message_closes_stream =
                     Self::deliver_msg_to_stream(streamid, ent, cell_counts_toward_windows, msg)?;
+                ignored = ent.dropped > dropped_before;
 
                 if message_closes_stream {
                     hop_map.ending_msg_received(streamid)?;
@@ -520,6 +893,8 @@ impl CircHop {
             }
             Some(StreamEntMut::EndSent(EndSentStreamEnt { half_stream, .. })) => {
                 // We sent an end but maybe the other side hasn't heard.
+                // This is normal, so we don't count these cells as ignored:
+                // the half-stream makes sure that there aren't more of them than we expect.
 
                 match half_stream.handle_msg(msg)? {
                     StreamStatus::Open => {}
@@ -543,6 +918,11 @@ impl CircHop {
                 ));
             }
         }
+        drop(hop_map);
+
+        if ignored {
+            self.note_ignored_cell()?;
+        }
 
         Ok(None)
     }
@@ -556,7 +936,7 @@ impl CircHop {
         drain_rate_requester: NotifySender<DrainRateRequest>,
     ) -> Result<StreamFlowControl> {
         if self.ccontrol.uses_stream_sendme() {
-            let window = sendme::StreamSendWindow::new(SEND_WINDOW_INIT);
+            let window = sendme::StreamSendWindow::new(self.stream_send_window_init);
             Ok(StreamFlowControl::new_window_based(window))
         } else {
             cfg_if::cfg_if! {
@@ -124,6 +124,14 @@ pub(super) struct ConfluxSet {
     /// Whether we have selected our initial primary leg,
     /// if this is a multipath conflux set.
     selected_init_primary: bool,
+    /// The relative sequence number of a SWITCH we owe on the primary leg.
+    ///
+    /// Set when our previous primary leg is removed from the set,
+    /// and we carry its sequence numbers over to a new primary leg
+    /// (see [`fail_over_primary`](Self::fail_over_primary)).
+    /// The SWITCH is sent just before the next multiplexed cell.
+    #[cfg(feature = "conflux")]
+    pending_switch: Option<u32>,
 }
 
 /// The conflux join point.
@@ -171,6 +179,8 @@ impl ConfluxSet {
             desired_ux,
             last_seq_delivered: Arc::new(AtomicU64::new(0)),
             selected_init_primary: false,
+            #[cfg(feature = "conflux")]
+            pending_switch: None,
         };
 
         (set, mutable)
@@ -247,6 +257,11 @@ impl ConfluxSet {
         self.legs.iter_mut().find(|circ| circ.unique_id() == leg_id)
     }
 
+    /// Return the unique identifier of the primary leg of this conflux set.
+    pub(super) fn primary_leg_id(&self) -> UniqId {
+        self.primary_id
+    }
+
     /// Return the number of legs in this conflux set.
     pub(super) fn len(&self) -> usize {
         self.legs.len()
@@ -265,10 +280,17 @@ impl ConfluxSet {
     /// ([`ReactorError::Shutdown`]), tearing down the entire [`ConfluxSet`], if
     ///
     ///   * the set is depleted (empty) after removing the specified leg
-    ///   * `leg` is currently the sending (primary) leg of this set
-    ///   * the closed leg had the highest non-zero last_seq_recv/sent
+    ///   * `leg` is currently the sending (primary) leg of this set,
+    ///     and none of the remaining legs are linked
+    ///   * the closed leg had the highest non-zero last_seq_recv
+    ///   * the closed leg had the highest non-zero last_seq_sent,
+    ///     and some of the cells we sent on it have not been acknowledged
     ///   * the closed leg had some in-progress data (inflight > cc_sendme_inc)
     ///
+    /// If `leg` is the primary leg, and we don't need to tear down the set,
+    /// another linked leg becomes the primary leg,
+    /// and takes over the sequence numbers of the removed leg.
+    ///
     /// We do not yet support resumption. See [2.4.3. Closing circuits] in prop329.
     ///
     /// [2.4.3. Closing circuits]: https://spec.torproject.org/proposals/329-traffic-splitting.html#243-closing-circuits
@@ -290,16 +312,16 @@ impl ConfluxSet {
             return Err(ReactorError::Shutdown);
         }
 
-        if leg == self.primary_id {
-            // We have just removed our sending leg,
-            // so it's time to close the entire conflux set.
-            return Err(ReactorError::Shutdown);
-        }
-
         cfg_if::cfg_if! {
             if #[cfg(feature = "conflux")] {
                 self.remove_conflux(circ)
             } else {
+                if leg == self.primary_id {
+                    // We have just removed our sending leg,
+                    // so it's time to close the entire conflux set.
+                    return Err(ReactorError::Shutdown);
+                }
+
                 // Conflux is disabled, so we can't possibly continue running if the only
                 // leg in the tunnel is gone.
                 //
@@ -313,10 +335,11 @@ impl ConfluxSet {
     /// Handle the removal of a circuit,
     /// returning an error if the reactor needs to shut down.
     #[cfg(feature = "conflux")]
-    fn remove_conflux(&self, circ: Circuit) -> Result<Circuit, ReactorError> {
+    fn remove_conflux(&mut self, circ: Circuit) -> Result<Circuit, ReactorError> {
         let Some(status) = circ.conflux_status() else {
             return Err(internal!("Found non-conflux circuit in conflux set?!").into());
         };
+        let was_primary = circ.unique_id() == self.primary_id;
 
         // TODO(conflux): should the circmgr be notified about the leg removal?
         //
@@ -329,6 +352,12 @@ impl ConfluxSet {
         // Time to check if we need to tear down the entire set.
         match status {
             ConfluxStatus::Unlinked => {
+                if was_primary {
+                    // We were sending on a leg that isn't part of the multi-path tunnel
+                    // as far as the exit is concerned, so we can't carry on elsewhere.
+                    return Err(ReactorError::Shutdown);
+                }
+
                 // This circuit hasn't yet begun the conflux handshake,
                 // so we can safely remove it from the set
                 Ok(circ)
@@ -337,19 +366,14 @@ impl ConfluxSet {
                 let (circ_last_seq_recv, circ_last_seq_sent) =
                     (|| Ok::<_, ReactorError>((circ.last_seq_recv()?, circ.last_seq_sent()?)))()?;
 
-                // If the closed leg had the highest non-zero last_seq_recv/sent, close the set
+                // If the closed leg had the highest non-zero last_seq_recv, close the set:
+                // the exit might have sent us more cells on it, which we will never receive.
                 if let Some(max_last_seq_recv) = self.max_last_seq_recv() {
                     if circ_last_seq_recv > max_last_seq_recv {
                         return Err(ReactorError::Shutdown);
                     }
                 }
 
-                if let Some(max_last_seq_sent) = self.max_last_seq_sent() {
-                    if circ_last_seq_sent > max_last_seq_sent {
-                        return Err(ReactorError::Shutdown);
-                    }
-                }
-
                 let hop = self.join_point_hop(&circ)?;
 
                 let (inflight, cwnd) = (|| {
@@ -369,11 +393,106 @@ impl ConfluxSet {
                     return Err(ReactorError::Shutdown);
                 }
 
+                // If the closed leg had the highest non-zero last_seq_sent,
+                // the exit might not have received the last cells we sent on it.
+                // That's only a problem if some of them haven't been acknowledged:
+                // otherwise, we know that they all arrived.
+                if let Some(max_last_seq_sent) = self.max_last_seq_sent() {
+                    if circ_last_seq_sent > max_last_seq_sent && inflight > 0 {
+                        return Err(ReactorError::Shutdown);
+                    }
+                }
+
+                if was_primary {
+                    self.fail_over_primary(&circ)?;
+                }
+
                 Ok(circ)
             }
         }
     }
 
+    /// Replace our primary leg, `old_primary`, which has just been removed from the set,
+    /// with the linked leg that has the best RTT.
+    ///
+    /// The new primary leg takes over the sequence numbers of the old one;
+    /// we will tell the exit about this using a SWITCH cell,
+    /// just before we next send a multiplexed cell.
+    ///
+    /// Returns [`ReactorError::Shutdown`] if there are no linked legs left.
+    #[cfg(feature = "conflux")]
+    fn fail_over_primary(&mut self, old_primary: &Circuit) -> Result<(), ReactorError> {
+        let mut best = None;
+        for leg in self
+            .legs
+            .iter()
+            .filter(|leg| leg.conflux_status() == Some(ConfluxStatus::Linked))
+        {
+            let rtt = self.join_point_hop(leg)?.ccontrol().rtt();
+            let init_rtt_usec = || {
+                leg.init_rtt()
+                    .map(|rtt| u32::try_from(rtt.as_micros()).unwrap_or(u32::MAX))
+            };
+            // Prefer legs for which we have an RTT measurement.
+            let rtt = rtt
+                .ewma_rtt_usec()
+                .or_else(init_rtt_usec)
+                .unwrap_or(u32::MAX);
+
+            if best.is_none_or(|(_, best_rtt)| rtt < best_rtt) {
+                best = Some((leg.unique_id(), rtt));
+            }
+        }
+
+        let Some((new_primary_id, _)) = best else {
+            // There's nowhere for us to send our data.
+            return Err(ReactorError::Shutdown);
+        };
+
+        let prev_last_seq_sent = old_primary.last_seq_sent()?;
+        self.primary_id = new_primary_id;
+        let new_primary = self.primary_leg_mut()?;
+        let new_last_seq_sent = new_primary.last_seq_sent()?;
+
+        // Our primary leg always has the highest last_seq_sent:
+        // see maybe_update_primary_leg().
+        let seqno_delta = prev_last_seq_sent
+            .checked_sub(new_last_seq_sent)
+            .and_then(|delta| u32::try_from(delta).ok())
+            .ok_or_else(|| internal!("Invalid seqno delta for conflux fail-over?!"))?;
+
+        new_primary.set_last_seq_sent(prev_last_seq_sent)?;
+        // A SWITCH with a relative seqno of 0 would be pointless
+        // (and the exit might consider it a side-channel).
+        self.pending_switch = (seqno_delta > 0).then_some(seqno_delta);
+
+        info!(
+            tunnel_id = %self.tunnel_id,
+            old = %old_primary.unique_id(),
+            new = %new_primary_id,
+            "Primary conflux leg closed; now sending on another leg",
+        );
+
+        Ok(())
+    }
+
+    /// Take the SWITCH cell we owe on the primary leg, if there is one.
+    ///
+    /// See [`ConfluxSet::pending_switch`].
+    #[cfg(feature = "conflux")]
+    fn take_pending_switch(&mut self) -> Option<SendRelayCell> {
+        let seqno_delta = self.pending_switch.take()?;
+        let join_point = self.join_point.as_ref()?.hop;
+
+        let switch = ConfluxSwitch::new(seqno_delta);
+        let cell = AnyRelayMsgOuter::new(None, switch.into());
+        Some(SendRelayCell {
+            hop: join_point,
+            early: false,
+            cell,
+        })
+    }
+
     /// Return the maximum relative last_seq_recv across all circuits.
     #[cfg(feature = "conflux")]
     fn max_last_seq_recv(&self) -> Option<u64> {
@@ -888,6 +1007,14 @@ impl ConfluxSet {
                     Box::pin(std::future::pending())
                 };
 
+                // If we are holding back any circuit-level SENDMEs,
+                // we need to wake up to send them.
+                let sendme_timer = if let Some(due) = leg.delayed_sendmes_due() {
+                    Box::pin(runtime.sleep_until(due)) as Pin<Box<dyn Future<Output = ()> + Send>>
+                } else {
+                    Box::pin(std::future::pending())
+                };
+
                 let mut ready_streams = leg.ready_streams_iterator(exclude_hop);
                 let input = &mut leg.input;
                 // TODO: we don't really need prepare_send_from here
@@ -956,6 +1083,9 @@ impl ConfluxSet {
                                 reason: RemoveLegReason::ConfluxHandshakeTimeout,
                             }))
                         }
+                        () = sendme_timer.fuse() => {
+                            Ok(Ok(CircuitAction::SendDelayedSendmes { leg: unique_id }))
+                        }
                         ret = send_fut => {
                             // Note: We don't actually use the returned SinkSendable,
                             // and continue writing to the SometimesUboundedSink in the reactor :(
@@ -1004,6 +1134,13 @@ impl ConfluxSet {
                     // For leaky pipe, we must continue using the original leg
                     leg
                 } else {
+                    // If we have just failed over to a new primary leg,
+                    // tell the exit where we've got to on it.
+                    #[cfg(feature = "conflux")]
+                    if let Some(switch_cell) = self.take_pending_switch() {
+                        self.primary_leg_mut()?.send_relay_cell(switch_cell).await?;
+                    }
+
                     let old_primary_leg = self.primary_id;
                     // Check if it's time to switch our primary leg.
                     #[cfg(feature = "conflux")]
@@ -9,14 +9,41 @@ use std::time::{Duration, SystemTime};
 
 use tor_cell::relaycell::conflux::V1Nonce;
 use tor_cell::relaycell::{RelayCmd, StreamId, UnparsedRelayMsg};
-use tor_error::{Bug, internal};
+use tor_error::{Bug, internal, warn_report};
 
+use crate::Error;
 use crate::crypto::cell::HopNum;
 use crate::tunnel::reactor::circuit::ConfluxStatus;
 use crate::tunnel::reactor::{CircuitCmd, RemoveLegReason};
 
 use client::ClientConfluxMsgHandler;
 
+/// The maximum number of consecutive SWITCH cells we accept on a leg,
+/// without any multiplexed cells in between.
+///
+/// A well-behaved peer only switches to a leg when it has data to send on it,
+/// so a stream of SWITCH cells is at best pointless,
+/// and at worst a side channel
+/// (see [SIDE_CHANNELS] in prop329).
+///
+/// [SIDE_CHANNELS]: https://spec.torproject.org/proposals/329-traffic-splitting.html#side-channels
+//
+// TODO(conflux-tuning): we should pick a less arbitrary limit
+const MAX_SWITCHES_WITHOUT_DATA: u32 = 4;
+
+/// The maximum distance a SWITCH cell can move the sequence number of a leg
+/// ahead of the last message delivered to a stream.
+///
+/// The messages in this gap are ones the peer claims to have sent on the other legs,
+/// and which we will have to buffer until they arrive.
+/// A legitimate gap is bounded by the amount of data in flight on the other legs,
+/// so we bound it generously, to stop a hostile peer
+/// from forcing us to buffer an unlimited number of out-of-order messages.
+//
+// TODO(conflux-tuning): we should pick a less arbitrary limit,
+// perhaps based on the congestion windows of the other legs
+const MAX_SWITCH_SEQNO_GAP: u64 = 1 << 17;
+
 /// Cell handler for conflux cells.
 ///
 /// One per Circuit.
@@ -35,6 +62,9 @@ pub(crate) struct ConfluxMsgHandler {
     ///
     /// This is shared by all the circuits in a conflux set.
     last_seq_delivered: Arc<AtomicU64>,
+    /// The number of SWITCH cells received on this leg
+    /// since the last multiplexed cell.
+    switches_without_data: u32,
 }
 
 impl ConfluxMsgHandler {
@@ -48,6 +78,7 @@ impl ConfluxMsgHandler {
         Self {
             handler: Box::new(ClientConfluxMsgHandler::new(hop, nonce, runtime)),
             last_seq_delivered,
+            switches_without_data: 0,
         }
     }
 
@@ -62,11 +93,15 @@ impl ConfluxMsgHandler {
     }
 
     /// Handle the specified conflux `msg`.
+    ///
+    /// Returns an error if the peer is abusing SWITCH cells,
+    /// in which case the entire conflux set should be torn down.
     pub(crate) fn handle_conflux_msg(
         &mut self,
         msg: UnparsedRelayMsg,
         hop: HopNum,
-    ) -> Option<CircuitCmd> {
+    ) -> crate::Result<Option<CircuitCmd>> {
+        let is_switch = msg.cmd() == RelayCmd::CONFLUX_SWITCH;
         let res = (|| {
             // Ensure the conflux cell came from the expected hop
             // (see 4.2.1. Cell Injection Side Channel Mitigations in prop329).
@@ -80,17 +115,56 @@ impl ConfluxMsgHandler {
         // After removing the leg, the reactor will decide whether it needs
         // to shut down or not.
         match res {
-            Ok(cmd) => cmd,
+            Ok(cmd) => {
+                if is_switch {
+                    // Removing just this leg isn't enough here:
+                    // the damage (if any) is done to the whole set.
+                    self.check_switch_abuse().inspect_err(|e| {
+                        warn_report!(
+                            e,
+                            "Suspicious conflux SWITCH cells from hop {}, tearing down tunnel",
+                            hop.display(),
+                        );
+                    })?;
+                }
+
+                Ok(cmd)
+            }
             Err(e) => {
                 // Tell the reactor to remove this leg from the conflux set,
                 // and to notify the handshake initiator of the error
-                Some(CircuitCmd::ConfluxRemove(
+                Ok(Some(CircuitCmd::ConfluxRemove(
                     RemoveLegReason::ConfluxHandshakeErr(e),
-                ))
+                )))
             }
         }
     }
 
+    /// Check whether the SWITCH cell we have just handled
+    /// puts the peer over our SWITCH abuse limits.
+    fn check_switch_abuse(&mut self) -> crate::Result<()> {
+        self.switches_without_data += 1;
+        if self.switches_without_data > MAX_SWITCHES_WITHOUT_DATA {
+            return Err(Error::ConfluxSwitchAbuse(format!(
+                "received {} SWITCH cells without any data",
+                self.switches_without_data
+            )));
+        }
+
+        let last_seq_delivered = self.last_seq_delivered.load(atomic::Ordering::Acquire);
+        let gap = self
+            .handler
+            .last_seq_recv()
+            .saturating_sub(last_seq_delivered);
+        if gap > MAX_SWITCH_SEQNO_GAP {
+            return Err(Error::ConfluxSwitchAbuse(format!(
+                "SWITCH cell skipped {gap} messages ahead of the last delivered one"
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Client-only: note that the LINK cell was sent.
     ///
     /// Used for the initial RTT measurement.
@@ -158,6 +232,7 @@ impl ConfluxMsgHandler {
 
         // Increment the relative seqno on this leg.
         self.handler.inc_last_seq_recv();
+        self.switches_without_data = 0;
 
         let action = if self.is_msg_in_order()? {
             ConfluxAction::Deliver(msg)
@@ -1,15 +1,19 @@
 //! Module providing [`CtrlMsg`].
 
 use super::circuit::extender::CircuitExtender;
+use super::circuit::{CircHop, Circuit};
 use super::{
     CircuitHandshake, CloseStreamBehavior, MetaCellHandler, Reactor, ReactorResultChannel,
     RunOnceCmdInner, SendRelayCell,
 };
 use crate::Result;
-use crate::circuit::HopSettings;
+use crate::circuit::UniqId;
+use crate::circuit::{DroppedCellStats, HopSettings, RelayCellFormatStats, RelayCmdStats};
 use crate::crypto::binding::CircuitBinding;
 use crate::crypto::cell::{InboundClientLayer, OutboundClientLayer};
 use crate::crypto::handshake::ntor_v3::{NtorV3Client, NtorV3PublicKey};
+#[cfg(any(test, feature = "testing"))]
+use crate::stream::StreamFlowControlState;
 use crate::stream::queue::StreamQueueSender;
 use crate::stream::{AnyCmdChecker, DrainRateRequest, StreamRateLimit};
 use crate::tunnel::circuit::celltypes::CreateResponse;
@@ -20,25 +24,33 @@ use crate::tunnel::{HopLocation, TargetHop, streammap};
 use crate::util::notify::NotifySender;
 use crate::util::skew::ClockSkew;
 #[cfg(test)]
-use crate::{circuit::CircParameters, circuit::UniqId, crypto::cell::HopNum};
+use crate::{circuit::CircParameters, crypto::cell::HopNum};
 use postage::watch;
 use tor_cell::chancell::msg::HandshakeType;
 use tor_cell::relaycell::flow_ctrl::XonKbpsEwma;
 use tor_cell::relaycell::msg::{AnyRelayMsg, Sendme};
-use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCellFormat, StreamId};
+use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCellFormat, RelayCmd, StreamId};
 use tor_error::{Bug, bad_api_usage, internal, into_bad_api_usage};
 use tracing::{debug, trace};
 #[cfg(feature = "hs-service")]
 use {
-    super::StreamReqSender, crate::stream::IncomingStreamRequestFilter,
+    super::StreamReqSender,
+    crate::stream::{IncomingStreamOverflow, IncomingStreamRequestFilter},
     crate::tunnel::reactor::IncomingStreamRequestHandler,
+    crate::util::token_bucket::bucket::{TokenBucket, TokenBucketConfig},
 };
 
 #[cfg(test)]
 use tor_cell::relaycell::msg::SendmeTag;
 
+#[cfg(feature = "reactor-profiling")]
+use super::profile::ReactorProfile;
+
 #[cfg(feature = "conflux")]
-use super::{Circuit, ConfluxLinkResultChannel};
+use {
+    super::{ConfluxLegEvent, ConfluxLinkResultChannel},
+    futures::channel::mpsc,
+};
 
 use oneshot_fused_workaround as oneshot;
 
@@ -120,8 +132,10 @@ pub(crate) enum CtrlMsg {
         rate_limit_notifier: watch::Sender<StreamRateLimit>,
         /// Notifies the stream reader when it should send a new drain rate.
         drain_rate_requester: NotifySender<DrainRateRequest>,
-        /// Oneshot channel to notify on completion, with the allocated stream ID.
-        done: ReactorResultChannel<(StreamId, HopLocation, RelayCellFormat)>,
+        /// Oneshot channel to notify on completion, with the allocated stream ID,
+        /// the location of the hop, the cell format to use with it,
+        /// and the initial receive window for the stream.
+        done: ReactorResultChannel<(StreamId, HopLocation, RelayCellFormat, u16)>,
         /// A `CmdChecker` to keep track of which message types are acceptable.
         cmd_checker: AnyCmdChecker,
     },
@@ -247,6 +261,10 @@ pub(crate) enum CtrlCmd {
     AwaitStreamRequest {
         /// A channel for sending information about an incoming stream request.
         incoming_sender: StreamReqSender,
+        /// What to do with requests that arrive when `incoming_sender` is full.
+        overflow: IncomingStreamOverflow,
+        /// The rate at which we accept requests, if it is limited.
+        rate_limit: Option<TokenBucketConfig>,
         /// A `CmdChecker` to keep track of which message types are acceptable.
         cmd_checker: AnyCmdChecker,
         /// Oneshot channel to notify on completion.
@@ -266,6 +284,69 @@ pub(crate) enum CtrlCmd {
         /// Oneshot channel to notify on completion.
         done: ReactorResultChannel<Option<CircuitBinding>>,
     },
+    /// Request the relay cell format and sent-cell count of every hop of a circuit.
+    GetRelayCellFormatStats {
+        /// The circuit whose hops we are asking about.
+        leg: UniqId,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<Vec<RelayCellFormatStats>>,
+    },
+    /// Request the counts of unused cells received from every hop of a circuit.
+    GetDroppedCellStats {
+        /// The circuit whose hops we are asking about.
+        leg: UniqId,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<Vec<DroppedCellStats>>,
+    },
+    /// Request the counts of relay messages received from every hop of a circuit, by command.
+    GetRelayCmdStats {
+        /// The circuit whose hops we are asking about.
+        leg: UniqId,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<Vec<RelayCmdStats>>,
+    },
+    /// Wait until we have received a number of relay messages with a given command
+    /// from a target hop.
+    ///
+    /// The reactor notifies `done` as soon as the count reaches `threshold`,
+    /// which may be immediately.
+    WaitForRelayCmdCount {
+        /// The hop we are watching.
+        hop: TargetHop,
+        /// The command we are counting.
+        cmd: RelayCmd,
+        /// The count at which to notify `done`.
+        threshold: u64,
+        /// Oneshot channel to notify once the threshold is reached.
+        done: ReactorResultChannel<RelayCmdStats>,
+    },
+    /// Request a snapshot of the time the reactor has spent on different kinds of work
+    /// for a circuit.
+    #[cfg(feature = "reactor-profiling")]
+    GetReactorProfile {
+        /// The circuit we are asking about.
+        leg: UniqId,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<ReactorProfile>,
+    },
+    /// Wait until congestion control lets us send on a target hop.
+    ///
+    /// The reactor notifies `done` as soon as the hop is ready,
+    /// which may be immediately.
+    WaitForSendReady {
+        /// The hop we want to send on.
+        hop: TargetHop,
+        /// Oneshot channel to notify once the hop is ready.
+        done: ReactorResultChannel<()>,
+    },
+    /// Request a snapshot of the flow control state of each open stream on a hop.
+    #[cfg(any(test, feature = "testing"))]
+    GetStreamFlowControlStates {
+        /// The hop whose streams we are asking about.
+        hop: TargetHop,
+        /// Oneshot channel to notify on completion.
+        done: ReactorResultChannel<Vec<StreamFlowControlState>>,
+    },
     /// (tests only) Add a hop to the list of hops on this circuit, with dummy cryptography.
     #[cfg(test)]
     AddFakeHop {
@@ -294,6 +375,13 @@ pub(crate) enum CtrlCmd {
         /// or an error if the reactor's tunnel is multi-path.
         answer: oneshot::Sender<StdResult<Circuit, Bug>>,
     },
+    /// Start telling `sender` about non-fatal events concerning the legs of this tunnel,
+    /// such as a leg being removed.
+    #[cfg(feature = "conflux")]
+    SubscribeConfluxLegEvents {
+        /// The channel on which to send the events.
+        sender: mpsc::UnboundedSender<ConfluxLegEvent>,
+    },
 }
 
 /// A flow control update message.
@@ -320,6 +408,57 @@ impl<'a> ControlHandler<'a> {
         Self { reactor }
     }
 
+    /// Return the circuit leg `leg`, or an error saying that we don't know it.
+    ///
+    /// `purpose` describes what we wanted the leg for, for the error message.
+    fn leg(&self, leg: UniqId, purpose: &str) -> Result<&Circuit> {
+        self.reactor
+            .circuits
+            .leg(leg)
+            .ok_or_else(|| bad_api_usage!("Unknown circuit id {leg} when {purpose}").into())
+    }
+
+    /// As [`hop_mut`](Self::hop_mut), but return the hop immutably.
+    #[cfg(any(test, feature = "testing"))]
+    fn hop(&self, hop: TargetHop, purpose: &str) -> Result<&CircHop> {
+        let (leg_id, hop_num) = self
+            .reactor
+            .target_hop_to_hopnum_id(hop)
+            .ok_or_else(|| bad_api_usage!("Unknown TargetHop when {purpose}"))?;
+        self.reactor
+            .circuits
+            .leg(leg_id)
+            .and_then(|circ| circ.hop(hop_num))
+            .ok_or_else(|| {
+                bad_api_usage!(
+                    "Unknown hop {} on circuit {leg_id} when {purpose}",
+                    hop_num.display(),
+                )
+                .into()
+            })
+    }
+
+    /// Return the hop at `hop`, or an error saying that we don't know it.
+    ///
+    /// `purpose` describes what we wanted the hop for, for the error message.
+    fn hop_mut(&mut self, hop: TargetHop, purpose: &str) -> Result<&mut CircHop> {
+        let (leg_id, hop_num) = self
+            .reactor
+            .target_hop_to_hopnum_id(hop)
+            .ok_or_else(|| bad_api_usage!("Unknown TargetHop when {purpose}"))?;
+        self.reactor
+            .circuits
+            .leg_mut(leg_id)
+            .and_then(|circ| circ.hop_mut(hop_num))
+            .ok_or_else(|| {
+                bad_api_usage!(
+                    "Unknown hop {} on circuit {leg_id} when {purpose}",
+                    hop_num.display(),
+                )
+                .into()
+            })
+    }
+
     /// Handle a control message.
     pub(super) fn handle_msg(&mut self, msg: CtrlMsg) -> Result<Option<RunOnceCmdInner>> {
         trace!(
@@ -662,6 +801,8 @@ impl<'a> ControlHandler<'a> {
             CtrlCmd::AwaitStreamRequest {
                 cmd_checker,
                 incoming_sender,
+                overflow,
+                rate_limit,
                 hop,
                 done,
                 filter,
@@ -672,8 +813,13 @@ impl<'a> ControlHandler<'a> {
                 };
                 // TODO: At some point we might want to add a CtrlCmd for
                 // de-registering the handler.  See comments on `allow_stream_requests`.
+                use tor_rtcompat::SleepProvider as _;
+
+                let now = self.reactor.runtime.now();
                 let handler = IncomingStreamRequestHandler {
                     incoming_sender,
+                    overflow,
+                    rate_limit: rate_limit.map(|config| TokenBucket::new(&config, now)),
                     cmd_checker,
                     hop_num,
                     filter,
@@ -709,6 +855,73 @@ impl<'a> ControlHandler<'a> {
 
                 Ok(())
             }
+            CtrlCmd::GetRelayCellFormatStats { leg, done } => {
+                let stats = self
+                    .leg(leg, "getting relay cell format stats")
+                    .map(Circuit::relay_cell_format_stats);
+                let _ = done.send(stats);
+
+                Ok(())
+            }
+            CtrlCmd::GetDroppedCellStats { leg, done } => {
+                let stats = self
+                    .leg(leg, "getting dropped cell stats")
+                    .map(Circuit::dropped_cell_stats);
+                let _ = done.send(stats);
+
+                Ok(())
+            }
+            CtrlCmd::GetRelayCmdStats { leg, done } => {
+                let stats = self
+                    .leg(leg, "getting relay command stats")
+                    .map(Circuit::relay_cmd_stats);
+                let _ = done.send(stats);
+
+                Ok(())
+            }
+            CtrlCmd::WaitForRelayCmdCount {
+                hop,
+                cmd,
+                threshold,
+                done,
+            } => {
+                match self.hop_mut(hop, "waiting for relay command count") {
+                    Ok(hop) => hop.wait_for_relay_cmd_count(cmd, threshold, done),
+                    Err(e) => {
+                        let _ = done.send(Err(e));
+                    }
+                }
+
+                Ok(())
+            }
+            #[cfg(feature = "reactor-profiling")]
+            CtrlCmd::GetReactorProfile { leg, done } => {
+                let profile = self
+                    .leg(leg, "getting reactor profile")
+                    .map(|circuit| circuit.profiler().snapshot());
+                let _ = done.send(profile);
+
+                Ok(())
+            }
+            CtrlCmd::WaitForSendReady { hop, done } => {
+                match self.hop_mut(hop, "waiting for send readiness") {
+                    Ok(hop) => hop.wait_until_can_send(done),
+                    Err(e) => {
+                        let _ = done.send(Err(e));
+                    }
+                }
+
+                Ok(())
+            }
+            #[cfg(any(test, feature = "testing"))]
+            CtrlCmd::GetStreamFlowControlStates { hop, done } => {
+                let states = self
+                    .hop(hop, "getting stream flow control states")
+                    .map(CircHop::stream_flow_control_states);
+                let _ = done.send(states);
+
+                Ok(())
+            }
             #[cfg(test)]
             CtrlCmd::AddFakeHop {
                 relay_cell_format,
@@ -761,6 +974,11 @@ impl<'a> ControlHandler<'a> {
             CtrlCmd::ShutdownAndReturnCircuit { answer } => {
                 self.reactor.handle_shutdown_and_return_circuit(answer)
             }
+            #[cfg(feature = "conflux")]
+            CtrlCmd::SubscribeConfluxLegEvents { sender } => {
+                self.reactor.conflux_leg_event_txs.push(sender);
+                Ok(())
+            }
         }
     }
 }
@@ -0,0 +1,319 @@
+//! A memory-tracked buffer for conflux messages that arrived out of order.
+//!
+//! A conflux tunnel has to hold on to every message that arrives ahead of
+//! the next expected sequence number, until the missing messages arrive.
+//! A peer (or a misbehaving relay) can make this buffer arbitrarily large,
+//! so we account its contents to the circuit's [`CircuitAccount`].
+//! If the memory quota system selects the buffer for reclamation,
+//! the buffer reports itself as collapsed, and wakes the reactor,
+//! which shuts down the tunnel.
+//!
+//! Only this buffer is accounted here.
+//! The cells buffered by each hop and in each stream map are not (yet) tracked.
+
+use super::ConfluxHeapEntry;
+use crate::memquota::{CircuitAccount, SpecificAccount as _};
+use crate::util::err::ReactorError;
+use futures::task::AtomicWaker;
+use std::collections::BinaryHeap;
+use std::mem::size_of;
+use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tor_error::internal;
+use tor_memquota::mtracker::{IsParticipant, Participation, ReclaimFuture, Reclaimed};
+use tor_memquota::{EnabledToken, HasMemoryCost as _, MemoryReclaimedError};
+use tor_rtcompat::{CoarseInstant, CoarseTimeProvider as _, DynTimeProvider};
+
+/// A min-heap of out-of-order messages, whose memory use is tracked.
+#[derive(Debug)]
+pub(super) struct OooQueue {
+    /// The buffered messages, each with its memory cost.
+    heap: BinaryHeap<QueuedEntry>,
+    /// The account we claim memory from.
+    account: CircuitAccount,
+    /// Our participation in `account`, and the participant's shared state.
+    ///
+    /// Registered the first time we buffer a message,
+    /// so that tunnels which never see out-of-order messages
+    /// never appear to the memory quota system.
+    participant: Option<(Participation, Arc<OooParticipant>)>,
+    /// The time provider, used for reporting the age of our data.
+    runtime: DynTimeProvider,
+}
+
+/// An entry in an [`OooQueue`].
+#[derive(Debug)]
+struct QueuedEntry {
+    /// The entry itself.
+    entry: ConfluxHeapEntry,
+    /// The amount of memory we claimed for it.
+    cost: usize,
+}
+
+impl Ord for QueuedEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.entry.cmp(&other.entry)
+    }
+}
+
+impl PartialOrd for QueuedEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.entry == other.entry
+    }
+}
+
+impl Eq for QueuedEntry {}
+
+/// The part of an [`OooQueue`] that the memory quota tracker can see.
+#[derive(Debug, Default)]
+struct OooParticipant {
+    /// When the queue last went from empty to non-empty.
+    ///
+    /// `None` if the queue is empty.
+    ///
+    /// This overestimates the age of the oldest message:
+    /// a queue that never fully drains looks as old as its first message.
+    /// That makes a persistently backlogged tunnel a more likely reclamation victim,
+    /// which is what we want.
+    oldest: Mutex<Option<CoarseInstant>>,
+    /// Whether the memory quota system has asked us to collapse.
+    collapsed: AtomicBool,
+    /// The reactor task, to wake when we collapse.
+    waker: AtomicWaker,
+}
+
+impl OooParticipant {
+    /// Return the (approximate) age of our oldest message.
+    fn oldest(&self) -> Option<CoarseInstant> {
+        if self.is_collapsed() {
+            return None;
+        }
+        *self.oldest.lock().expect("poisoned lock")
+    }
+
+    /// Record whether the queue has data, and if so, since when.
+    fn set_oldest(&self, oldest: Option<CoarseInstant>) {
+        *self.oldest.lock().expect("poisoned lock") = oldest;
+    }
+
+    /// Mark this queue as collapsed, and wake the reactor so that it notices.
+    fn collapse(&self) {
+        self.collapsed.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Whether we have been told to collapse.
+    fn is_collapsed(&self) -> bool {
+        self.collapsed.load(Ordering::Acquire)
+    }
+}
+
+impl IsParticipant for OooParticipant {
+    fn get_oldest(&self, _: EnabledToken) -> Option<CoarseInstant> {
+        self.oldest()
+    }
+
+    fn reclaim(self: Arc<Self>, _: EnabledToken) -> ReclaimFuture {
+        // This wakes the reactor, which shuts down the tunnel,
+        // freeing the buffered messages.
+        self.collapse();
+        Box::pin(async { Reclaimed::Collapsing })
+    }
+}
+
+impl OooQueue {
+    /// Create a new, empty, queue accounted to `account`.
+    pub(super) fn new(account: CircuitAccount, runtime: DynTimeProvider) -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            account,
+            participant: None,
+            runtime,
+        }
+    }
+
+    /// Buffer `entry`, claiming memory for it.
+    ///
+    /// Returns an error if the queue has been reclaimed,
+    /// or if the memory quota system refuses the claim.
+    pub(super) fn push(&mut self, entry: ConfluxHeapEntry) -> StdResult<(), ReactorError> {
+        self.check_collapsed()?;
+
+        let cost = match EnabledToken::new_if_compiled_in() {
+            Some(enabled) => entry
+                .msg
+                .msg
+                .memory_cost(enabled)
+                .saturating_add(size_of::<QueuedEntry>()),
+            None => 0,
+        };
+
+        let now = self.runtime.now_coarse();
+        if self.participant.is_none() {
+            let particip = Arc::new(OooParticipant::default());
+            let partn = self
+                .account
+                .as_raw_account()
+                .register_participant(Arc::downgrade(&particip) as _)
+                .map_err(crate::Error::from)?;
+            self.participant = Some((partn, particip));
+        }
+        let Some((partn, particip)) = &mut self.participant else {
+            return Err(internal!("participant we just registered disappeared?!").into());
+        };
+        partn.claim(cost).map_err(crate::Error::from)?;
+
+        if self.heap.is_empty() {
+            particip.set_oldest(Some(now));
+        }
+        self.heap.push(QueuedEntry { entry, cost });
+
+        Ok(())
+    }
+
+    /// Return the next message in sequence order, without removing it.
+    pub(super) fn peek(&self) -> Option<&ConfluxHeapEntry> {
+        self.heap.peek().map(|e| &e.entry)
+    }
+
+    /// Remove and return the next message in sequence order, releasing its memory.
+    pub(super) fn pop(&mut self) -> Option<ConfluxHeapEntry> {
+        let QueuedEntry { entry, cost } = self.heap.pop()?;
+        if let Some((partn, particip)) = &mut self.participant {
+            partn.release(cost);
+            if self.heap.is_empty() {
+                particip.set_oldest(None);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Return the number of buffered messages.
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Return an error if the memory quota system has reclaimed this queue.
+    pub(super) fn check_collapsed(&self) -> StdResult<(), ReactorError> {
+        match &self.participant {
+            Some((_, particip)) if particip.is_collapsed() => {
+                Err(crate::Error::from(MemoryReclaimedError::new()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Poll for the memory quota system reclaiming this queue.
+    ///
+    /// Returns `Ready` once the queue has been reclaimed.
+    /// The reactor polls this alongside its other events,
+    /// so that a reclaimed queue is freed promptly
+    /// even if nothing else happens on the tunnel.
+    pub(super) fn poll_collapsed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        // Until we have registered with the memory quota system, we can't be reclaimed.
+        let Some((_, particip)) = &self.participant else {
+            return Poll::Pending;
+        };
+        // Register before checking, so that we can't miss a wakeup.
+        particip.waker.register(cx.waker());
+        if particip.is_collapsed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::crypto::cell::HopNum;
+    use crate::tunnel::circuit::unique_id::UniqId;
+    use crate::tunnel::reactor::conflux::OooRelayMsg;
+    use tor_basic_utils::test_rng::testing_rng;
+    use tor_cell::relaycell::msg::{AnyRelayMsg, Data};
+    use tor_cell::relaycell::{AnyRelayMsgOuter, RelayCellFormat, StreamId, UnparsedRelayMsg};
+    use tor_rtcompat::SleepProvider as _;
+    use tor_rtmock::MockRuntime;
+
+    fn entry(seqno: u64) -> ConfluxHeapEntry {
+        let streamid = StreamId::new(1).unwrap();
+        let msg =
+            AnyRelayMsgOuter::new(Some(streamid), AnyRelayMsg::Data(Data::new(b"hi").unwrap()));
+        let body = msg.encode(RelayCellFormat::V0, &mut testing_rng()).unwrap();
+        let msg = UnparsedRelayMsg::from_singleton_body(RelayCellFormat::V0, body).unwrap();
+        ConfluxHeapEntry {
+            leg_id: UniqId::new(1, 1),
+            msg: OooRelayMsg {
+                seqno,
+                hopnum: HopNum::from(0),
+                cell_counts_towards_windows: true,
+                streamid,
+                msg,
+            },
+        }
+    }
+
+    #[test]
+    fn ordering_and_age() {
+        MockRuntime::test_with_various(|rt| async move {
+            let runtime = DynTimeProvider::new(rt.clone());
+            let mut queue = OooQueue::new(CircuitAccount::new_noop(), runtime);
+            assert!(queue.peek().is_none());
+
+            let start = rt.now_coarse();
+            queue.push(entry(3)).unwrap();
+            rt.advance_by(std::time::Duration::from_secs(10)).await;
+            queue.push(entry(1)).unwrap();
+            queue.push(entry(2)).unwrap();
+            assert_eq!(queue.len(), 3);
+
+            let particip = Arc::clone(&queue.participant.as_ref().unwrap().1);
+            assert_eq!(particip.oldest(), Some(start));
+
+            for seqno in 1..=3 {
+                assert_eq!(queue.peek().unwrap().msg.seqno, seqno);
+                assert_eq!(queue.pop().unwrap().msg.seqno, seqno);
+            }
+            assert!(queue.pop().is_none());
+            assert_eq!(particip.oldest(), None);
+
+            // Once reclaimed, the queue wakes whoever is waiting for it,
+            // and refuses to buffer anything else.
+            queue.push(entry(4)).unwrap();
+            let mut collapsed = futures::future::poll_fn(|cx| queue.poll_collapsed(cx));
+            assert!(futures::poll!(&mut collapsed).is_pending());
+            particip.collapse();
+            assert!(futures::poll!(&mut collapsed).is_ready());
+            drop(collapsed);
+            assert_eq!(particip.oldest(), None);
+            assert!(matches!(
+                queue.check_collapsed(),
+                Err(ReactorError::Err(crate::Error::MemoryReclaimed(_)))
+            ));
+            assert!(queue.push(entry(5)).is_err());
+        });
+    }
+}
@@ -0,0 +1,287 @@
+//! Optional measurement of the time the circuit reactor spends on different kinds of work.
+//!
+//! Each [`Circuit`](super::circuit::Circuit) has a [`Profiler`],
+//! which the reactor uses to record how long it takes to decode cells,
+//! to move messages to and from streams, and to do flow control.
+//!
+//! It also records how often the reactor has to wait for the lock on a hop's stream map.
+//! The stream map of the join point of a conflux tunnel is shared by all of its legs,
+//! but every leg is handled by the same reactor task,
+//! and nothing outside that task locks the map
+//! (even [`ClientCircSyncView`](super::syncview::ClientCircSyncView)
+//! is only used from within the reactor).
+//! So we expect `contended` to stay at zero,
+//! and sharding the map would not save us anything.
+//! Nor would a lock-free ready-queue:
+//! the map already keeps its ready streams in a waker-driven
+//! [`StreamPollSet`](crate::util::stream_poll_set::StreamPollSet),
+//! so we never scan the streams that have nothing to send.
+//!
+//! Unless the `reactor-profiling` feature is enabled,
+//! a [`Profiler`] and a [`Timer`] are zero-sized,
+//! and measuring an event costs nothing.
+
+use std::sync::{Mutex, MutexGuard};
+#[cfg(feature = "reactor-profiling")]
+use std::{
+    sync::{Arc, TryLockError},
+    time::{Duration, Instant},
+};
+
+/// A kind of work done by the circuit reactor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum EventClass {
+    /// Decrypting and parsing an incoming relay cell.
+    CellDecode,
+    /// Delivering an incoming message to a stream,
+    /// or taking an outgoing message from a stream.
+    StreamDelivery,
+    /// Handling a circuit-level SENDME,
+    /// or deciding whether to send an XON or XOFF.
+    FlowControl,
+}
+
+/// The time a circuit reactor has spent on one kind of work.
+#[cfg(feature = "reactor-profiling")]
+#[derive(Clone, Copy, Debug, Default)]
+#[non_exhaustive]
+pub struct EventTimes {
+    /// The number of times we did this kind of work.
+    pub count: u64,
+    /// The total time we spent doing it.
+    pub total: Duration,
+    /// The longest time we spent doing it at once.
+    pub max: Duration,
+}
+
+#[cfg(feature = "reactor-profiling")]
+impl EventTimes {
+    /// Record that we spent `elapsed` doing this kind of work.
+    fn note(&mut self, elapsed: Duration) {
+        self.count = self.count.saturating_add(1);
+        self.total = self.total.saturating_add(elapsed);
+        self.max = self.max.max(elapsed);
+    }
+}
+
+/// How often a circuit reactor has had to wait for a lock, and for how long.
+#[cfg(feature = "reactor-profiling")]
+#[derive(Clone, Copy, Debug, Default)]
+#[non_exhaustive]
+pub struct LockWaits {
+    /// The number of times we took the lock.
+    pub acquisitions: u64,
+    /// The number of times the lock was held by someone else when we tried to take it.
+    pub contended: u64,
+    /// The total time we spent waiting for the lock.
+    pub total_wait: Duration,
+    /// The longest time we spent waiting for the lock at once.
+    pub max_wait: Duration,
+}
+
+#[cfg(feature = "reactor-profiling")]
+impl LockWaits {
+    /// Record that we took the lock, after waiting for `waited` if it was contended.
+    fn note(&mut self, waited: Option<Duration>) {
+        self.acquisitions = self.acquisitions.saturating_add(1);
+        if let Some(waited) = waited {
+            self.contended = self.contended.saturating_add(1);
+            self.total_wait = self.total_wait.saturating_add(waited);
+            self.max_wait = self.max_wait.max(waited);
+        }
+    }
+}
+
+/// A snapshot of the time a circuit reactor has spent on different kinds of work
+/// for one circuit.
+///
+/// The times are measured with the real monotonic clock, not the runtime's clock.
+/// They include only the work itself, not time spent waiting.
+///
+/// Returned by [`ClientCirc::reactor_profile`](crate::circuit::ClientCirc::reactor_profile).
+#[cfg(feature = "reactor-profiling")]
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ReactorProfile {
+    /// Decrypting and parsing incoming relay cells.
+    pub cell_decode: EventTimes,
+    /// Delivering incoming messages to streams, and taking outgoing messages from them.
+    pub stream_delivery: EventTimes,
+    /// Handling circuit-level SENDMEs, and deciding whether to send XONs and XOFFs.
+    pub flow_control: EventTimes,
+    /// Taking the locks on the stream maps of the circuit's hops.
+    pub stream_map_lock: LockWaits,
+}
+
+#[cfg(feature = "reactor-profiling")]
+impl ReactorProfile {
+    /// Return the times for `class`.
+    fn times_mut(&mut self, class: EventClass) -> &mut EventTimes {
+        match class {
+            EventClass::CellDecode => &mut self.cell_decode,
+            EventClass::StreamDelivery => &mut self.stream_delivery,
+            EventClass::FlowControl => &mut self.flow_control,
+        }
+    }
+}
+
+/// The start time of an event we are measuring.
+///
+/// Returned by [`Timer::start`], and passed to [`Profiler::record`].
+#[derive(Copy, Clone, Debug)]
+#[must_use]
+pub(crate) struct Timer {
+    /// When the event started.
+    #[cfg(feature = "reactor-profiling")]
+    started: Instant,
+}
+
+impl Timer {
+    /// Start measuring an event.
+    #[inline]
+    pub(crate) fn start() -> Self {
+        Timer {
+            #[cfg(feature = "reactor-profiling")]
+            started: Instant::now(),
+        }
+    }
+}
+
+/// A record of the time a reactor has spent on different kinds of work.
+///
+/// Clones share the same record.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Profiler {
+    /// The times recorded so far.
+    ///
+    /// This is only ever locked by the reactor task (and briefly, to take a snapshot),
+    /// so the lock is uncontended.
+    #[cfg(feature = "reactor-profiling")]
+    profile: Arc<Mutex<ReactorProfile>>,
+}
+
+impl Profiler {
+    /// Record that an event of class `class`, which began at `timer`, has just ended.
+    #[inline]
+    pub(crate) fn record(&self, class: EventClass, timer: Timer) {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "reactor-profiling")] {
+                let elapsed = timer.started.elapsed();
+                self.profile
+                    .lock()
+                    .expect("lock poisoned")
+                    .times_mut(class)
+                    .note(elapsed);
+            } else {
+                let _ = (class, timer);
+            }
+        }
+    }
+
+    /// Lock `map`, a hop's stream map, recording whether we had to wait for it.
+    #[inline]
+    pub(crate) fn lock_stream_map<'m, T>(&self, map: &'m Mutex<T>) -> MutexGuard<'m, T> {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "reactor-profiling")] {
+                let (guard, waited) = match map.try_lock() {
+                    Ok(guard) => (guard, None),
+                    Err(TryLockError::WouldBlock) => {
+                        let started = Instant::now();
+                        let guard = map.lock().expect("lock poisoned");
+                        (guard, Some(started.elapsed()))
+                    }
+                    Err(TryLockError::Poisoned(_)) => panic!("lock poisoned"),
+                };
+                self.profile
+                    .lock()
+                    .expect("lock poisoned")
+                    .stream_map_lock
+                    .note(waited);
+                guard
+            } else {
+                map.lock().expect("lock poisoned")
+            }
+        }
+    }
+
+    /// Return a snapshot of the times recorded so far.
+    #[cfg(feature = "reactor-profiling")]
+    pub(crate) fn snapshot(&self) -> ReactorProfile {
+        self.profile.lock().expect("lock poisoned").clone()
+    }
+}
+
+#[cfg(all(test, feature = "reactor-profiling"))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn record() {
+        let profiler = Profiler::default();
+        let clone = profiler.clone();
+
+        let timer = Timer::start();
+        std::thread::sleep(Duration::from_millis(2));
+        profiler.record(EventClass::CellDecode, timer);
+        clone.record(EventClass::CellDecode, Timer::start());
+        clone.record(EventClass::FlowControl, Timer::start());
+
+        // Clones share their record.
+        let profile = profiler.snapshot();
+        assert_eq!(profile.cell_decode.count, 2);
+        assert!(profile.cell_decode.max >= Duration::from_millis(2));
+        assert!(profile.cell_decode.total >= profile.cell_decode.max);
+        assert_eq!(profile.stream_delivery.count, 0);
+        assert_eq!(profile.stream_delivery.total, Duration::ZERO);
+        assert_eq!(profile.flow_control.count, 1);
+    }
+
+    #[test]
+    fn stream_map_lock() {
+        let profiler = Profiler::default();
+        let map = Arc::new(Mutex::new(0_u32));
+
+        *profiler.lock_stream_map(&map) += 1;
+        let profile = profiler.snapshot();
+        assert_eq!(profile.stream_map_lock.acquisitions, 1);
+        assert_eq!(profile.stream_map_lock.contended, 0);
+        assert_eq!(profile.stream_map_lock.total_wait, Duration::ZERO);
+
+        // Hold the lock in another thread for a while, so that we have to wait for it.
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn({
+            let map = Arc::clone(&map);
+            move || {
+                let mut guard = map.lock().unwrap();
+                locked_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+                *guard += 1;
+            }
+        });
+        locked_rx.recv().unwrap();
+        assert_eq!(*profiler.lock_stream_map(&map), 2);
+        holder.join().unwrap();
+
+        let profile = profiler.snapshot();
+        assert_eq!(profile.stream_map_lock.acquisitions, 2);
+        assert_eq!(profile.stream_map_lock.contended, 1);
+        assert!(profile.stream_map_lock.max_wait > Duration::ZERO);
+        assert_eq!(
+            profile.stream_map_lock.total_wait,
+            profile.stream_map_lock.max_wait
+        );
+    }
+}
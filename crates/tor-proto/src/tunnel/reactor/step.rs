@@ -0,0 +1,104 @@
+//! Tests only: drive a circuit [`Reactor`] one event at a time.
+//!
+//! Normally, a reactor runs in its own task,
+//! and decides for itself which of its inputs to handle next.
+//! That makes it hard to reproduce a particular sequence of events,
+//! as fuzzers and tests of the reactor's state machines need to.
+//!
+//! Instead of spawning [`Reactor::run`], such tests can call [`Reactor::step`]
+//! to handle exactly one event from the reactor's own inputs,
+//! and [`Reactor::inject_cell`] to handle a cell as if it had arrived on one of
+//! the tunnel's circuit legs, without going through a channel.
+
+use super::Reactor;
+use crate::Result;
+use crate::crypto::cell::HopNum;
+use crate::tunnel::circuit::celltypes::ClientCircChanMsg;
+use crate::tunnel::circuit::unique_id::UniqId;
+use crate::util::err::ReactorError;
+use std::result::Result as StdResult;
+use tor_error::bad_api_usage;
+
+/// The outcome of driving a [`Reactor`] by one event.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum StepOutcome {
+    /// The reactor handled the event, and is still running.
+    Continue,
+    /// The reactor has shut down cleanly.
+    ///
+    /// It must not be driven any further.
+    Shutdown,
+}
+
+impl Reactor {
+    /// Run a single iteration of the reactor loop.
+    ///
+    /// This handles exactly one command, control message, or cell,
+    /// exactly as [`run`](Reactor::run) would.
+    /// If none is ready, this waits until one is.
+    ///
+    /// Returns an error if the reactor has shut down with an error.
+    pub(crate) async fn step(&mut self) -> Result<StepOutcome> {
+        step_outcome(self.run_once().await)
+    }
+
+    /// Handle `cell` as if we had received it on the circuit leg `leg`.
+    ///
+    /// The cell does not pass through a channel,
+    /// and none of the reactor's other inputs are polled,
+    /// so the same sequence of injected cells always has the same effect.
+    ///
+    /// Returns an error if handling the cell caused the reactor to shut down with an error.
+    pub(crate) async fn inject_cell(
+        &mut self,
+        leg: UniqId,
+        cell: ClientCircChanMsg,
+    ) -> Result<StepOutcome> {
+        step_outcome(self.inject_cell_inner(leg, cell).await)
+    }
+
+    /// Helper for [`inject_cell`](Reactor::inject_cell).
+    async fn inject_cell_inner(
+        &mut self,
+        leg: UniqId,
+        cell: ClientCircChanMsg,
+    ) -> StdResult<(), ReactorError> {
+        if self.circuits.is_empty() {
+            return Err(ReactorError::Shutdown);
+        }
+        if self.circuits.leg_mut(leg).is_none() {
+            return Err(bad_api_usage!("injected a cell on nonexistent leg {leg}").into());
+        }
+
+        // As in run_once, handle any buffered messages that are now in order first.
+        #[cfg(feature = "conflux")]
+        self.try_dequeue_ooo_msgs().await?;
+
+        if let Some(cmd) = self.handle_cell(leg, cell)? {
+            self.handle_run_once_cmd(cmd).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the unique ID of the primary circuit leg of this tunnel.
+    pub(crate) fn primary_leg(&self) -> UniqId {
+        self.circuits.primary_leg_id()
+    }
+
+    /// Return the number of callers waiting for a count of relay commands
+    /// from the hop `hop` of the circuit leg `leg`,
+    /// or `None` if there is no such hop.
+    pub(crate) fn n_relay_cmd_waiters(&self, leg: UniqId, hop: HopNum) -> Option<usize> {
+        Some(self.circuits.leg(leg)?.hop(hop)?.n_relay_cmd_waiters())
+    }
+}
+
+/// Convert the result of a reactor iteration into a [`StepOutcome`].
+fn step_outcome(res: StdResult<(), ReactorError>) -> Result<StepOutcome> {
+    match res {
+        Ok(()) => Ok(StepOutcome::Continue),
+        Err(ReactorError::Shutdown) => Ok(StepOutcome::Shutdown),
+        Err(ReactorError::Err(e)) => Err(e),
+    }
+}
@@ -5,7 +5,6 @@ use crate::stream::queue::StreamQueueSender;
 use crate::stream::{AnyCmdChecker, StreamFlowControl};
 use crate::tunnel::circuit::StreamMpscReceiver;
 use crate::tunnel::halfstream::HalfStream;
-use crate::tunnel::reactor::circuit::RECV_WINDOW_INIT;
 use crate::util::stream_poll_set::{KeyAlreadyInsertedError, StreamPollSet};
 use crate::{Error, Result};
 use pin_project::pin_project;
@@ -38,6 +37,12 @@ pub(super) struct OpenStreamEnt {
     /// Number of cells dropped due to the stream disappearing before we can
     /// transform this into an `EndSent`.
     pub(super) dropped: u16,
+    /// Receive window for the cells on this stream that we discarded,
+    /// because the hop's inbound command filter doesn't allow their command.
+    ///
+    /// Those cells never reach the stream's reader, which only counts the cells it reads
+    /// in its own receive window, so we acknowledge them separately.
+    discarded_recv_window: sendme::StreamRecvWindow,
     /// A `CmdChecker` used to tell whether cells on this stream are valid.
     pub(super) cmd_checker: AnyCmdChecker,
     /// Flow control for this stream.
@@ -53,6 +58,11 @@ pub(super) struct OpenStreamEnt {
     /// Waker to be woken when more sending capacity becomes available (e.g.
     /// receiving a SENDME).
     flow_ctrl_waker: Option<Waker>,
+    /// True if we have sent an END on this stream to close its write side,
+    /// but are still delivering incoming messages to it.
+    ///
+    /// See [`OpenStreamEnt::close_write`].
+    write_closed: bool,
 }
 
 impl OpenStreamEnt {
@@ -74,6 +84,18 @@ impl OpenStreamEnt {
         Ok(())
     }
 
+    /// Note that we have discarded a cell on this stream that counts towards its windows.
+    ///
+    /// Return true if we should send a stream-level SENDME to acknowledge the cells
+    /// we have discarded.
+    pub(super) fn note_discarded_cell(&mut self) -> Result<bool> {
+        let send_sendme = self.discarded_recv_window.take()?;
+        if send_sendme {
+            self.discarded_recv_window.put();
+        }
+        Ok(send_sendme)
+    }
+
     /// The approximate number of stream inbound data bytes buffered.
     fn approx_stream_bytes_buffered(&self) -> usize {
         // NOTE: Here we want to know the total number of buffered incoming stream data bytes. We
@@ -124,6 +146,12 @@ impl OpenStreamEnt {
         self.flow_ctrl.handle_incoming_xoff(msg)
     }
 
+    /// Return a snapshot of the flow control state of this stream, whose ID is `id`.
+    #[cfg(any(test, feature = "testing"))]
+    fn flow_control_state(&self, id: StreamId) -> crate::stream::StreamFlowControlState {
+        self.flow_ctrl.state(id, self.dropped)
+    }
+
     /// Take capacity to send `msg`. If there's insufficient capacity, returns
     /// an error. Should be called at the point we've fully committed to
     /// sending the message.
@@ -133,6 +161,17 @@ impl OpenStreamEnt {
     pub(crate) fn take_capacity_to_send<M: RelayMsg>(&mut self, msg: &M) -> Result<()> {
         self.flow_ctrl.take_capacity_to_send(msg)
     }
+
+    /// Note that we have sent an END on this stream to close its write side,
+    /// but that we still want to deliver incoming messages to it.
+    ///
+    /// After this, the stream is never ready to send anything:
+    /// we discard anything it tries to send,
+    /// and only watch for all of its senders being dropped.
+    /// The stream stays open until the other side ends it too.
+    pub(super) fn close_write(&mut self) {
+        self.write_closed = true;
+    }
 }
 
 /// Private wrapper over `OpenStreamEnt`. We implement `futures::Stream` for
@@ -174,7 +213,20 @@ impl PeekableStream for OpenStreamEntStream {
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<&mut <Self as futures::Stream>::Item>> {
         let s = self.project();
-        let inner = s.inner.project();
+        let mut inner = s.inner.project();
+        if *inner.write_closed {
+            // We have already sent an END, so we mustn't send anything else;
+            // all we care about is whether the senders have all gone away.
+            loop {
+                match inner.rx.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(m)) => {
+                        debug!("Discarding {} message on write-closed stream", m.cmd());
+                    }
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
         let m = match inner.rx.poll_peek_mut(cx) {
             Poll::Ready(Some(m)) => m,
             Poll::Ready(None) => return Poll::Ready(None),
@@ -194,6 +246,9 @@ impl UnobtrusivePeekableStream for OpenStreamEntStream {
     ) -> Option<&mut <Self as futures::Stream>::Item> {
         let s = self.project();
         let inner = s.inner.project();
+        if *inner.write_closed {
+            return None;
+        }
         let m = inner.rx.unobtrusive_peek_mut()?;
         if inner.flow_ctrl.can_send(m) {
             Some(m)
@@ -289,11 +344,13 @@ pub(super) struct StreamMap {
     /// priority whenever an outgoing message is processed from that stream,
     /// putting it last in line.
     next_priority: Priority,
+    /// The initial receive window of the streams in this map.
+    recv_window_init: u16,
 }
 
 impl StreamMap {
-    /// Make a new empty StreamMap.
-    pub(super) fn new() -> Self {
+    /// Make a new empty StreamMap, for streams whose receive windows start at `recv_window_init`.
+    pub(super) fn new(recv_window_init: u16) -> Self {
         let mut rng = rand::rng();
         let next_stream_id: NonZeroU16 = rng.random();
         StreamMap {
@@ -301,6 +358,7 @@ impl StreamMap {
             closed_streams: HashMap::new(),
             next_stream_id: next_stream_id.into(),
             next_priority: Priority(0),
+            recv_window_init,
         }
     }
 
@@ -309,6 +367,22 @@ impl StreamMap {
         self.open_streams.len()
     }
 
+    /// Return a snapshot of the flow control state of every open stream in this map,
+    /// ordered by stream ID.
+    #[cfg(any(test, feature = "testing"))]
+    pub(super) fn flow_control_states(&self) -> Vec<crate::stream::StreamFlowControlState> {
+        let mut states: Vec<_> = self
+            .open_streams
+            .keys()
+            .filter_map(|id| {
+                let ent = self.open_streams.stream(id)?;
+                Some(ent.inner.flow_control_state(*id))
+            })
+            .collect();
+        states.sort_by_key(|state| state.stream_id);
+        states
+    }
+
     /// Return the next available priority.
     fn take_next_priority(&mut self) -> Priority {
         let rv = self.next_priority;
@@ -329,9 +403,11 @@ impl StreamMap {
                 sink,
                 flow_ctrl,
                 dropped: 0,
+                discarded_recv_window: sendme::StreamRecvWindow::new(self.recv_window_init),
                 cmd_checker,
                 rx: StreamUnobtrusivePeeker::new(rx),
                 flow_ctrl_waker: None,
+                write_closed: false,
             },
         };
         let priority = self.take_next_priority();
@@ -370,9 +446,11 @@ impl StreamMap {
                 sink,
                 flow_ctrl,
                 dropped: 0,
+                discarded_recv_window: sendme::StreamRecvWindow::new(self.recv_window_init),
                 cmd_checker,
                 rx: StreamUnobtrusivePeeker::new(rx),
                 flow_ctrl_waker: None,
+                write_closed: false,
             },
         };
         let priority = self.take_next_priority();
@@ -397,7 +475,12 @@ impl StreamMap {
     ///
     /// Returns true if there was really a stream there.
     pub(super) fn ending_msg_received(&mut self, id: StreamId) -> Result<()> {
-        if self.open_streams.remove(&id).is_some() {
+        if let Some((_id, _priority, ent)) = self.open_streams.remove(&id) {
+            if ent.inner.write_closed {
+                // We had already sent an END: both sides are now done,
+                // so we can forget about this stream.
+                return Ok(());
+            }
             let prev = self.closed_streams.insert(id, ClosedStreamEnt::EndReceived);
             debug_assert!(prev.is_none(), "Unexpected duplicate entry for {id}");
             return Ok(());
@@ -439,6 +522,7 @@ impl StreamMap {
                         flow_ctrl,
                         dropped,
                         cmd_checker,
+                        write_closed,
                         // notably absent: the channels for sink and stream, which will get dropped and
                         // closed (meaning reads/writes from/to this stream will now fail)
                         ..
@@ -447,7 +531,7 @@ impl StreamMap {
             // FIXME(eta): we don't copy the receive window, instead just creating a new one,
             //             so a malicious peer can send us slightly more data than they should
             //             be able to; see arti#230.
-            let mut recv_window = sendme::StreamRecvWindow::new(RECV_WINDOW_INIT);
+            let mut recv_window = sendme::StreamRecvWindow::new(self.recv_window_init);
             recv_window.decrement_n(dropped)?;
             // TODO: would be nice to avoid new_ref.
             let half_stream = HalfStream::new(flow_ctrl, recv_window, cmd_checker);
@@ -460,6 +544,10 @@ impl StreamMap {
                 }),
             );
             debug_assert!(prev.is_none(), "Unexpected duplicate entry for {id}");
+            if write_closed {
+                // We sent an END when we closed the write side.
+                return Ok(ShouldSendEnd::DontSend);
+            }
             return Ok(ShouldSendEnd::Send);
         }
 
@@ -580,7 +668,7 @@ mod test {
     #[test]
     #[allow(clippy::cognitive_complexity)]
     fn streammap_basics() -> Result<()> {
-        let mut map = StreamMap::new();
+        let mut map = StreamMap::new(crate::tunnel::reactor::RECV_WINDOW_INIT);
         let mut next_id = map.next_stream_id;
         let mut ids = Vec::new();
 
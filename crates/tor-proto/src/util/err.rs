@@ -1,10 +1,12 @@
 //! Define an error type for the tor-proto crate.
 use std::{sync::Arc, time::Duration};
 use thiserror::Error;
-use tor_cell::relaycell::{StreamId, msg::EndReason};
+use tor_cell::relaycell::{RelayCmd, StreamId, msg::EndReason};
 use tor_error::{Bug, ErrorKind, HasKind};
 use tor_linkspec::RelayIdType;
 
+use crate::crypto::cell::HopNum;
+
 /// An error type for the tor-proto crate.
 ///
 /// This type should probably be split into several.  There's more
@@ -138,6 +140,38 @@ pub enum Error {
     /// Tried to send too many cells to a circuit hop.
     #[error("Tried to send too many outbound cells")]
     ExcessOutboundCells,
+    /// Received more DROP cells from a circuit hop than we allow.
+    ///
+    /// See [`CircParameters::max_inbound_drop_cells`](crate::circuit::CircParameters::max_inbound_drop_cells).
+    #[error("Received too many DROP cells from hop {}", .hop.display())]
+    ExcessDropCells {
+        /// The hop that sent the cells.
+        hop: HopNum,
+    },
+    /// Received more cells for streams that nobody was reading from a circuit hop than we allow.
+    ///
+    /// See [`CircParameters::max_inbound_ignored_cells`](crate::circuit::CircParameters::max_inbound_ignored_cells).
+    #[error("Received too many cells for abandoned streams from hop {}", .hop.display())]
+    ExcessIgnoredCells {
+        /// The hop that sent the cells.
+        hop: HopNum,
+    },
+    /// Received a relay cell whose command is not allowed from the hop that sent it.
+    ///
+    /// See [`RelayCmdFilter`](crate::circuit::RelayCmdFilter).
+    #[error("Received disallowed {cmd} cell from hop {}", .hop.display())]
+    DisallowedRelayCmd {
+        /// The hop that sent the cell.
+        hop: HopNum,
+        /// The command of the cell.
+        cmd: RelayCmd,
+    },
+    /// The peer sent conflux SWITCH cells in a way that looks like an attack.
+    ///
+    /// For example, it sent too many of them without any data in between,
+    /// or used them to skip implausibly far ahead in the sequence.
+    #[error("Suspicious conflux SWITCH cells: {0}")]
+    ConfluxSwitchAbuse(String),
 
     /// Channel does not match target
     #[error("Peer identity mismatch: {0}")]
@@ -155,6 +189,9 @@ pub enum Error {
     /// Memory quota error
     #[error("memory quota error")]
     Memquota(#[from] tor_memquota::Error),
+    /// The memory quota system tore down this circuit, to reclaim the memory it was using.
+    #[error("circuit closed to reclaim memory")]
+    MemoryReclaimed(#[from] tor_memquota::MemoryReclaimedError),
 }
 
 /// Error which indicates that the channel was closed.
@@ -230,7 +267,7 @@ impl From<Error> for std::io::Error {
 
             CircuitClosed => ErrorKind::ConnectionReset,
 
-            Memquota { .. } => ErrorKind::OutOfMemory,
+            Memquota { .. } | MemoryReclaimed { .. } => ErrorKind::OutOfMemory,
 
             BytesErr { .. }
             | BadCellAuth
@@ -250,7 +287,11 @@ impl From<Error> for std::io::Error {
             | IdUnavailable(_)
             | StreamIdZero
             | ExcessInboundCells
-            | ExcessOutboundCells => ErrorKind::InvalidData,
+            | ExcessOutboundCells
+            | ExcessDropCells { .. }
+            | ExcessIgnoredCells { .. }
+            | DisallowedRelayCmd { .. }
+            | ConfluxSwitchAbuse(_) => ErrorKind::InvalidData,
 
             Bug(ref e) if e.kind() == tor_error::ErrorKind::BadApiUsage => ErrorKind::InvalidData,
 
@@ -302,7 +343,12 @@ impl HasKind for Error {
             E::StreamIdZero => EK::BadApiUsage,
             E::ExcessInboundCells => EK::TorProtocolViolation,
             E::ExcessOutboundCells => EK::Internal,
+            E::ExcessDropCells { .. } => EK::TorProtocolViolation,
+            E::ExcessIgnoredCells { .. } => EK::TorProtocolViolation,
+            E::DisallowedRelayCmd { .. } => EK::TorProtocolViolation,
+            E::ConfluxSwitchAbuse(_) => EK::TorProtocolViolation,
             E::Memquota(err) => err.kind(),
+            E::MemoryReclaimed(err) => err.kind(),
             E::Bug(e) => e.kind(),
         }
     }
@@ -397,6 +397,12 @@ where
         self.ready_streams.get_mut(&(priority.clone(), key.clone()))
     }
 
+    /// Iterate over the keys of all the streams managed by this object, in no particular order.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.priorities.keys()
+    }
+
     /// Number of streams managed by this object.
     pub fn len(&self) -> usize {
         self.priorities.len()
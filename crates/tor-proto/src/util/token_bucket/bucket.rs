@@ -66,8 +66,7 @@ impl<I: TokenBucketInstant> TokenBucket<I> {
     }
 
     /// Remove `count` tokens from the bucket.
-    // remove this if we use it in the future
-    #[cfg_attr(not(test), expect(dead_code))]
+    #[cfg_attr(not(any(test, feature = "hs-service")), expect(dead_code))]
     pub(crate) fn drain(&mut self, count: u64) -> Result<BecameEmpty, InsufficientTokensError> {
         Ok(self.claim(count)?.commit())
     }
@@ -43,6 +43,13 @@ where
     pub(crate) fn inner(&self) -> &W {
         self.writer.inner()
     }
+
+    /// Access the inner [`AsyncWrite`] writer of the [`RateLimitedWriter`] mutably.
+    ///
+    /// Anything written this way bypasses the rate limit.
+    pub(crate) fn inner_mut(self: Pin<&mut Self>) -> Pin<&mut W> {
+        self.project().writer.inner_mut()
+    }
 }
 
 impl<W, S, P> AsyncWrite for DynamicRateLimitedWriter<W, S, P>
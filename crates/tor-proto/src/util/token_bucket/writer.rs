@@ -86,6 +86,13 @@ where
         &self.inner
     }
 
+    /// Access the inner [`AsyncWrite`] writer mutably.
+    ///
+    /// Anything written this way bypasses the rate limit.
+    pub(crate) fn inner_mut(self: Pin<&mut Self>) -> Pin<&mut W> {
+        self.project().inner
+    }
+
     /// Adjust the refill rate and burst.
     ///
     /// A rate and/or burst of 0 is allowed.
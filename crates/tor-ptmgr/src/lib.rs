@@ -78,7 +78,7 @@ use {
 use {
     async_trait::async_trait,
     tor_chanmgr::{
-        builder::ChanBuilder,
+        builder::{ChanBuilder, StageTimeouts},
         factory::{AbstractPtError, ChannelFactory},
         transport::ExternalProxyPlugin,
     },
@@ -108,6 +108,11 @@ pub struct PtMgr<R> {
     /// PtReactor channel when the `managed-pts` feature is enabled.
     #[cfg(feature = "managed-pts")]
     tx: UnboundedSender<PtReactorMessage>,
+    /// The timeouts for each stage of building a channel through one of our transports.
+    ///
+    /// See [`PtMgr::with_stage_timeouts`].
+    #[cfg(feature = "tor-channel-factory")]
+    stage_timeouts: Arc<std::sync::Mutex<StageTimeouts>>,
 }
 
 impl<R: Runtime> PtMgr<R> {
@@ -180,9 +185,22 @@ impl<R: Runtime> PtMgr<R> {
             state,
             #[cfg(feature = "managed-pts")]
             tx,
+            #[cfg(feature = "tor-channel-factory")]
+            stage_timeouts: Default::default(),
         })
     }
 
+    /// Use `timeouts` for each stage of building a channel through one of our transports.
+    ///
+    /// Pass [`ChanMgr::stage_timeouts`](tor_chanmgr::ChanMgr::stage_timeouts) here,
+    /// so that channels through pluggable transports follow the `ChanMgr`'s configuration.
+    /// Otherwise, we use the default timeouts.
+    #[cfg(feature = "tor-channel-factory")]
+    pub fn with_stage_timeouts(mut self, timeouts: Arc<std::sync::Mutex<StageTimeouts>>) -> Self {
+        self.stage_timeouts = timeouts;
+        self
+    }
+
     /// Reload the configuration
     pub fn reconfigure(
         &self,
@@ -353,7 +371,11 @@ impl<R: Runtime> tor_chanmgr::factory::AbstractPtMgr for PtMgr<R> {
         };
 
         let proxy = ExternalProxyPlugin::new(self.runtime.clone(), cmethod.endpoint, cmethod.kind);
-        let factory = ChanBuilder::new(self.runtime.clone(), proxy);
+        let factory = ChanBuilder::new_with_timeouts(
+            self.runtime.clone(),
+            proxy,
+            Arc::clone(&self.stage_timeouts),
+        );
         // FIXME(eta): Should we cache constructed factories? If no: should this still be an Arc?
         // FIXME(eta): Should we track what transports are live somehow, so we can shut them down?
         Ok(Some(Arc::new(factory)))
@@ -66,6 +66,10 @@ dyn_clone::clone_trait_object!(DynProvider);
 /// Type-erased `SleepProvider` and `CoarseTimeProvider`
 ///
 /// Useful where time is needed, but we don't want a runtime type parameter.
+///
+/// The deadline helpers in [`SleepProviderExt`](crate::SleepProviderExt),
+/// such as `sleep_until` and `sleep_until_wallclock`, work here too:
+/// they are built on the type-erased `now`, `wallclock`, and `sleep`.
 #[derive(Clone, Debug)]
 pub struct DynTimeProvider(Impl);
 
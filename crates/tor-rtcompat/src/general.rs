@@ -53,6 +53,10 @@ impl StreamOps for Stream {
         self.0.set_tcp_notsent_lowat(notsent_lowat)
     }
 
+    fn set_ip_tos(&self, tos: u8) -> IoResult<()> {
+        self.0.set_ip_tos(tos)
+    }
+
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
         self.0.new_handle()
     }
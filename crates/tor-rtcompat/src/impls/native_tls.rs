@@ -52,6 +52,10 @@ impl<S: AsyncRead + AsyncWrite + StreamOps + Unpin> StreamOps for async_native_t
         self.get_ref().set_tcp_notsent_lowat(notsent_lowat)
     }
 
+    fn set_ip_tos(&self, tos: u8) -> IoResult<()> {
+        self.get_ref().set_ip_tos(tos)
+    }
+
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
         self.get_ref().new_handle()
     }
@@ -61,6 +61,10 @@ impl<S: StreamOps> StreamOps for futures_rustls::client::TlsStream<S> {
         self.get_ref().0.set_tcp_notsent_lowat(notsent_lowat)
     }
 
+    fn set_ip_tos(&self, tos: u8) -> IoResult<()> {
+        self.get_ref().0.set_ip_tos(tos)
+    }
+
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
         self.get_ref().0.new_handle()
     }
@@ -41,6 +41,10 @@ impl StreamOps for TcpSockFd {
         set_tcp_notsent_lowat(self, notsent_lowat)
     }
 
+    fn set_ip_tos(&self, tos: u8) -> io::Result<()> {
+        set_ip_tos(self, tos)
+    }
+
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
         Box::new(*self)
     }
@@ -81,6 +85,60 @@ pub(crate) fn set_tcp_notsent_lowat<S>(_sock: &S, _notsent_lowat: u32) -> io::Re
     .into())
 }
 
+/// Helper for implementing [`set_ip_tos`](crate::StreamOps::set_ip_tos).
+///
+/// Only implemented on Linux. Returns an error on all other platforms.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_ip_tos<S: AsRawFd>(sock: &S, tos: u8) -> io::Result<()> {
+    let fd = sock.as_raw_fd();
+
+    // The option we need depends on the address family of the socket.
+    let mut domain: libc::c_int = 0;
+    let mut socklen = mem::size_of_val(&domain) as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            &mut domain as *mut _ as *mut libc::c_void,
+            &mut socklen as *mut _,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (level, name) = if domain == libc::AF_INET6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    };
+
+    let tos = libc::c_int::from(tos);
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &tos as *const _ as *const libc::c_void,
+            mem::size_of_val(&tos) as libc::socklen_t,
+        )
+    };
+
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Helper for implementing [`set_ip_tos`](crate::StreamOps::set_ip_tos).
+///
+/// Only implemented on Linux. Returns an error on all other platforms.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_ip_tos<S>(_sock: &S, _tos: u8) -> io::Result<()> {
+    Err(UnsupportedStreamOp::new("set_ip_tos", "unsupported on non-linux platforms").into())
+}
+
 #[cfg(test)]
 mod tests {
     // @@ begin test lint list maintained by maint/add_warning @@
@@ -131,6 +189,36 @@ mod tests {
         assert_eq!(1337, notsent_lowat);
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(miri, ignore)] // sockets are unsupported https://github.com/rust-lang/miri/issues/3449
+    fn ip_tos() {
+        for (addr, level, name) in [
+            ("127.0.0.1:0", libc::IPPROTO_IP, libc::IP_TOS),
+            ("[::1]:0", libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+        ] {
+            let Ok(sock) = TcpListener::bind(addr) else {
+                // IPv6 might not be available.
+                continue;
+            };
+            set_ip_tos(&sock, 0x88).unwrap();
+
+            let mut tos: libc::c_int = 0;
+            let mut socklen = mem::size_of_val(&tos) as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(
+                    sock.as_raw_fd(),
+                    level,
+                    name,
+                    &mut tos as *mut _ as *mut libc::c_void,
+                    &mut socklen as *mut _,
+                )
+            };
+            assert_eq!(res, 0);
+            assert_eq!(tos, 0x88);
+        }
+    }
+
     #[test]
     #[cfg(not(target_os = "linux"))]
     #[cfg_attr(miri, ignore)] // sockets are unsupported https://github.com/rust-lang/miri/issues/3449
@@ -179,6 +179,10 @@ pub(crate) mod net {
             impls::streamops::set_tcp_notsent_lowat(&self.s, notsent_lowat)
         }
 
+        fn set_ip_tos(&self, tos: u8) -> IoResult<()> {
+            impls::streamops::set_ip_tos(&self.s, tos)
+        }
+
         #[cfg(target_os = "linux")]
         fn new_handle(&self) -> Box<dyn traits::StreamOps + Send + Unpin> {
             Box::new(impls::streamops::TcpSockFd::from_fd(&self.s))
@@ -59,11 +59,14 @@ mod compound;
 mod dyn_time;
 pub mod general;
 mod opaque;
+mod pause;
 pub mod scheduler;
+mod skew;
 mod timer;
 mod traits;
 pub mod unimpl;
 pub mod unix;
+pub mod wallclock;
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use std::io;
@@ -75,6 +78,8 @@ pub use traits::{
 
 pub use coarse_time::{CoarseDuration, CoarseInstant, RealCoarseTimeProvider};
 pub use dyn_time::DynTimeProvider;
+pub use pause::{PausableRuntime, PausableSleep, PausableTimeProvider};
+pub use skew::SkewedTimeProvider;
 pub use timer::{SleepProviderExt, Timeout, TimeoutError};
 
 /// Traits used to describe TLS connections and objects that can
@@ -450,6 +455,24 @@ mod test {
         Ok(())
     }
 
+    // Try a little monotonic delay, with a deadline.
+    fn tiny_sleep_until<R: ToplevelRuntime>(runtime: &R) -> IoResult<()> {
+        let rt = runtime.clone();
+        runtime.block_on(async {
+            let one_millis = Duration::from_millis(1);
+            let deadline = rt.now() + one_millis;
+
+            rt.sleep_until(deadline).await;
+            assert!(rt.now() >= deadline);
+
+            // A deadline in the past doesn't make us wait.
+            let i1 = Instant::now();
+            rt.sleep_until(deadline - one_millis).await;
+            assert!(Instant::now() - i1 < Duration::from_secs(60));
+        });
+        Ok(())
+    }
+
     // Try connecting to ourself and sending a little data.
     //
     // NOTE: requires Ipv4 localhost.
@@ -688,6 +711,7 @@ mod test {
         small_timeout_ok,
         small_timeout_expire,
         tiny_wallclock,
+        tiny_sleep_until,
         self_connect_tcp,
         self_connect_udp,
         listener_stream,
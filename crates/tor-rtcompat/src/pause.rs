@@ -0,0 +1,519 @@
+//! A clock that a test can stop, restart, and step forwards.
+//!
+//! [`MockRuntime`](https://docs.rs/tor-rtmock) gives unit tests complete control over time,
+//! but it also replaces the executor and the network.
+//! Integration tests that run several components on a real runtime
+//! (the onion service publisher, the vanguard manager, channel expiry, and so on)
+//! still sometimes need to hold time still while they inspect some state,
+//! or to skip ahead to the next interesting deadline.
+//!
+//! A [`PausableTimeProvider`] wraps another time provider,
+//! and lets a test pause and resume its clocks,
+//! and move them forwards, on behalf of every component that shares it.
+//! [`PausableTimeProvider::runtime`] builds a full runtime that uses it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
+
+use educe::Educe;
+
+use crate::skew::{add_signed_nanos, duration_nanos};
+use crate::{
+    CoarseDuration, CoarseInstant, CoarseTimeProvider, CompoundRuntime, DynTimeProvider, Runtime,
+    SleepProvider,
+};
+
+/// A time provider whose clocks can be paused, resumed, and advanced.
+///
+/// While the provider is running, its clocks advance at the same rate as
+/// those of the underlying provider.
+/// While it is paused, they stand still,
+/// and nothing sleeping on it wakes up,
+/// until the test calls [`resume`](PausableTimeProvider::resume)
+/// or [`advance`](PausableTimeProvider::advance).
+///
+/// All three clocks (monotonic, coarse, and wall-clock) are adjusted by the same amount.
+///
+/// Clones share their clocks:
+/// pausing one clone pauses all of them.
+/// This lets a test control a clock that it has already
+/// handed to the code under test.
+///
+/// This is meant for testing.
+/// To use it where a concrete runtime type isn't wanted,
+/// wrap a [`DynTimeProvider`].
+#[derive(Clone, Debug)]
+pub struct PausableTimeProvider<R = DynTimeProvider> {
+    /// The provider whose clocks we adjust.
+    inner: R,
+    /// Whether we are paused, and how far our clocks are from those of `inner`.
+    state: Arc<Mutex<PauseState>>,
+}
+
+/// The shared state of a [`PausableTimeProvider`].
+#[derive(Debug, Default)]
+struct PauseState {
+    /// How far ahead of the underlying clocks we were at `paused_at`
+    /// (or, if we are running, are now), in nanoseconds.
+    ///
+    /// Negative if we were behind.
+    offset_nanos: i128,
+    /// If we are paused, the underlying monotonic time at which we were paused.
+    paused_at: Option<Instant>,
+    /// A counter that we increment whenever our clocks are paused, resumed, or advanced.
+    ///
+    /// A [`PausableSleep`] uses this to notice that the underlying sleep
+    /// it is waiting for no longer ends at the right time.
+    generation: u64,
+    /// The identifier to give the next [`PausableSleep`] that we create.
+    next_sleep_id: u64,
+    /// The tasks to wake when our clocks are paused, resumed, or advanced,
+    /// indexed by the identifier of the [`PausableSleep`] that is waiting.
+    ///
+    /// Each sleep has at most one entry here,
+    /// which it removes when it completes or is dropped.
+    wakers: HashMap<u64, Waker>,
+}
+
+impl PauseState {
+    /// Return how far ahead of the underlying clocks we are,
+    /// given that the underlying monotonic clock says `inner_now`.
+    fn offset_nanos(&self, inner_now: Instant) -> i128 {
+        match self.paused_at {
+            Some(paused_at) => {
+                let paused_for = inner_now.saturating_duration_since(paused_at);
+                self.offset_nanos - duration_nanos(paused_for)
+            }
+            None => self.offset_nanos,
+        }
+    }
+
+    /// Record that our clocks have changed,
+    /// and return the wakers of every task that needs to find out.
+    #[must_use]
+    fn changed(&mut self) -> Vec<Waker> {
+        self.generation = self.generation.wrapping_add(1);
+        self.wakers.drain().map(|(_id, waker)| waker).collect()
+    }
+}
+
+/// A runtime that behaves like `R`, except that its time comes from a [`PausableTimeProvider`].
+///
+/// Returned by [`PausableTimeProvider::runtime`].
+pub type PausableRuntime<R> =
+    CompoundRuntime<R, PausableTimeProvider<R>, PausableTimeProvider<R>, R, R, R, R>;
+
+impl<R: SleepProvider> PausableTimeProvider<R> {
+    /// Wrap `inner` in a new `PausableTimeProvider`.
+    ///
+    /// The new provider is running,
+    /// and its clocks are the same as those of `inner`.
+    pub fn new(inner: R) -> Self {
+        PausableTimeProvider {
+            inner,
+            state: Arc::new(Mutex::new(PauseState::default())),
+        }
+    }
+
+    /// Stop our clocks.
+    ///
+    /// Does nothing if they are already stopped.
+    pub fn pause(&self) {
+        let now = self.inner.now();
+        let wakers = {
+            let mut state = self.state.lock().expect("poisoned lock");
+            if state.paused_at.is_some() {
+                return;
+            }
+            state.paused_at = Some(now);
+            state.changed()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Restart our clocks, from the time at which they were stopped.
+    ///
+    /// Does nothing if they are already running.
+    pub fn resume(&self) {
+        let now = self.inner.now();
+        let wakers = {
+            let mut state = self.state.lock().expect("poisoned lock");
+            if state.paused_at.is_none() {
+                return;
+            }
+            state.offset_nanos = state.offset_nanos(now);
+            state.paused_at = None;
+            state.changed()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Return true if our clocks are stopped.
+    pub fn is_paused(&self) -> bool {
+        self.state
+            .lock()
+            .expect("poisoned lock")
+            .paused_at
+            .is_some()
+    }
+
+    /// Move our clocks forwards by `dur`,
+    /// waking up everything whose sleep ends within that time.
+    ///
+    /// This works whether or not our clocks are stopped;
+    /// if they are stopped, they stay stopped.
+    pub fn advance(&self, dur: Duration) {
+        let wakers = {
+            let mut state = self.state.lock().expect("poisoned lock");
+            state.offset_nanos = state.offset_nanos.saturating_add(duration_nanos(dur));
+            state.changed()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Return a reference to the underlying provider.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Forget the waker registered by the [`PausableSleep`] with the identifier `id`, if any.
+    fn forget_waker(&self, id: u64) {
+        // This is called from `Drop`, so we mustn't panic if the lock is poisoned.
+        if let Ok(mut state) = self.state.lock() {
+            let _: Option<Waker> = state.wakers.remove(&id);
+        }
+    }
+
+    /// Return how far ahead of the underlying clocks we are, in nanoseconds.
+    fn current_offset_nanos(&self) -> i128 {
+        let now = self.inner.now();
+        self.state.lock().expect("poisoned lock").offset_nanos(now)
+    }
+}
+
+impl<R: Runtime> PausableTimeProvider<R> {
+    /// Return a runtime that behaves like the underlying runtime,
+    /// except that its time comes from this provider.
+    ///
+    /// Every component given a clone of the returned runtime
+    /// is paused, resumed, and advanced together.
+    pub fn runtime(&self) -> PausableRuntime<R> {
+        CompoundRuntime::new(
+            self.inner.clone(),
+            self.clone(),
+            self.clone(),
+            self.inner.clone(),
+            self.inner.clone(),
+            self.inner.clone(),
+            self.inner.clone(),
+        )
+    }
+}
+
+impl<R: SleepProvider> SleepProvider for PausableTimeProvider<R> {
+    type SleepFuture = PausableSleep<R>;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        let deadline = self.now().checked_add(duration);
+        let id = {
+            let mut state = self.state.lock().expect("poisoned lock");
+            let id = state.next_sleep_id;
+            state.next_sleep_id = id.wrapping_add(1);
+            id
+        };
+        PausableSleep {
+            provider: self.clone(),
+            id,
+            deadline,
+            inner: None,
+        }
+    }
+
+    fn now(&self) -> Instant {
+        shift_instant(self.inner.now(), self.current_offset_nanos())
+    }
+
+    fn wallclock(&self) -> SystemTime {
+        add_signed_nanos(self.inner.wallclock(), self.current_offset_nanos())
+    }
+
+    fn block_advance<T: Into<String>>(&self, reason: T) {
+        self.inner.block_advance(reason);
+    }
+
+    fn release_advance<T: Into<String>>(&self, reason: T) {
+        self.inner.release_advance(reason);
+    }
+
+    fn allow_one_advance(&self, dur: Duration) {
+        self.inner.allow_one_advance(dur);
+    }
+}
+
+impl<R: SleepProvider + CoarseTimeProvider> CoarseTimeProvider for PausableTimeProvider<R> {
+    fn now_coarse(&self) -> CoarseInstant {
+        let now = self.inner.now_coarse();
+        let offset_nanos = self.current_offset_nanos();
+        let magnitude =
+            Duration::from_nanos(u64::try_from(offset_nanos.unsigned_abs()).unwrap_or(u64::MAX));
+        if offset_nanos >= 0 {
+            now + CoarseDuration::from(magnitude)
+        } else {
+            now - CoarseDuration::from(magnitude)
+        }
+    }
+}
+
+/// A future returned by [`PausableTimeProvider::sleep`].
+///
+/// It becomes ready once the provider's monotonic clock reaches the end of the sleep,
+/// whether because time passed while the provider was running,
+/// or because the provider was [advanced](PausableTimeProvider::advance).
+#[derive(Educe)]
+#[educe(Debug)]
+#[must_use = "sleep() returns a future, which does nothing unless used"]
+pub struct PausableSleep<R: SleepProvider> {
+    /// The provider whose clock we are waiting for.
+    provider: PausableTimeProvider<R>,
+    /// Our identifier, under which we register our waker with the provider.
+    id: u64,
+    /// The time, on the provider's monotonic clock, at which we become ready.
+    ///
+    /// `None` if that time is too far in the future to represent,
+    /// in which case we never become ready.
+    deadline: Option<Instant>,
+    /// The underlying sleep that we are currently waiting for, if any,
+    /// and the generation of the provider's state for which we computed it.
+    #[educe(Debug(ignore))]
+    inner: Option<(u64, Pin<Box<R::SleepFuture>>)>,
+}
+
+impl<R: SleepProvider> Future for PausableSleep<R> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        loop {
+            let inner_now = this.provider.inner.now();
+            let (now, paused, generation) = {
+                let mut state = this.provider.state.lock().expect("poisoned lock");
+                match state.wakers.get_mut(&this.id) {
+                    Some(waker) => waker.clone_from(cx.waker()),
+                    None => {
+                        state.wakers.insert(this.id, cx.waker().clone());
+                    }
+                }
+                let now = shift_instant(inner_now, state.offset_nanos(inner_now));
+                (now, state.paused_at.is_some(), state.generation)
+            };
+
+            let Some(deadline) = this.deadline else {
+                return Poll::Pending;
+            };
+            if now >= deadline {
+                this.inner = None;
+                this.provider.forget_waker(this.id);
+                return Poll::Ready(());
+            }
+            if paused {
+                // We'll be woken when the provider is resumed or advanced.
+                this.inner = None;
+                return Poll::Pending;
+            }
+
+            match &mut this.inner {
+                Some((sleep_generation, sleep)) if *sleep_generation == generation => {
+                    match sleep.as_mut().poll(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        // Check the time again: the underlying sleep may have ended early,
+                        // or the provider may have changed while we were polling it.
+                        Poll::Ready(()) => this.inner = None,
+                    }
+                }
+                _ => {
+                    let remaining = deadline.saturating_duration_since(now);
+                    let sleep = Box::pin(this.provider.inner.sleep(remaining));
+                    this.inner = Some((generation, sleep));
+                }
+            }
+        }
+    }
+}
+
+impl<R: SleepProvider> Drop for PausableSleep<R> {
+    fn drop(&mut self) {
+        self.provider.forget_waker(self.id);
+    }
+}
+
+/// Return `t`, moved forwards (or, if negative, backwards) by `nanos` nanoseconds.
+///
+/// Returns `t` unchanged if the result can't be represented as an `Instant`.
+fn shift_instant(t: Instant, nanos: i128) -> Instant {
+    let magnitude = Duration::from_nanos(u64::try_from(nanos.unsigned_abs()).unwrap_or(u64::MAX));
+    let shifted = if nanos >= 0 {
+        t.checked_add(magnitude)
+    } else {
+        t.checked_sub(magnitude)
+    };
+    shifted.unwrap_or(t)
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::RealCoarseTimeProvider;
+    use futures::task::noop_waker_ref;
+
+    /// A time provider whose clocks only move when we tell them to,
+    /// and on which sleeping never ends.
+    #[derive(Clone, Debug)]
+    struct FakeClock(Arc<Mutex<(Instant, SystemTime)>>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            FakeClock(Arc::new(Mutex::new((Instant::now(), wall))))
+        }
+
+        fn advance(&self, d: Duration) {
+            let mut clocks = self.0.lock().unwrap();
+            clocks.0 += d;
+            clocks.1 += d;
+        }
+    }
+
+    impl SleepProvider for FakeClock {
+        type SleepFuture = futures::future::Pending<()>;
+
+        fn sleep(&self, _duration: Duration) -> Self::SleepFuture {
+            futures::future::pending()
+        }
+
+        fn now(&self) -> Instant {
+            self.0.lock().unwrap().0
+        }
+
+        fn wallclock(&self) -> SystemTime {
+            self.0.lock().unwrap().1
+        }
+    }
+
+    impl CoarseTimeProvider for FakeClock {
+        fn now_coarse(&self) -> CoarseInstant {
+            RealCoarseTimeProvider::new().now_coarse()
+        }
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let clock = FakeClock::new();
+        let pausable = PausableTimeProvider::new(clock.clone());
+        let start = pausable.now();
+        let start_wall = pausable.wallclock();
+        assert_eq!(start, clock.now());
+        assert!(!pausable.is_paused());
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(pausable.now(), start + Duration::from_secs(5));
+
+        // Clones share their clocks.
+        let pausable2 = pausable.clone();
+        pausable.pause();
+        assert!(pausable2.is_paused());
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(pausable2.now(), start + Duration::from_secs(5));
+        assert_eq!(pausable2.wallclock(), start_wall + Duration::from_secs(5));
+
+        // Advancing works while we're paused.
+        pausable.advance(Duration::from_secs(10));
+        assert_eq!(pausable.now(), start + Duration::from_secs(15));
+
+        // After resuming, we pick up where we left off.
+        pausable2.resume();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(pausable.now(), start + Duration::from_secs(16));
+        assert_eq!(pausable.wallclock(), start_wall + Duration::from_secs(16));
+    }
+
+    #[test]
+    fn sleep() {
+        let clock = FakeClock::new();
+        let pausable = PausableTimeProvider::new(clock.clone());
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        pausable.pause();
+        let mut sleep = pausable.sleep(Duration::from_secs(60));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+
+        // Time passing underneath doesn't wake a paused sleeper.
+        clock.advance(Duration::from_secs(3600));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+
+        pausable.advance(Duration::from_secs(59));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+        pausable.advance(Duration::from_secs(1));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_ready());
+
+        // Once resumed, a sleeper wakes when the underlying time has passed.
+        pausable.resume();
+        let mut sleep = pausable.sleep(Duration::from_secs(30));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(30));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn one_waker_per_sleep() {
+        let clock = FakeClock::new();
+        let pausable = PausableTimeProvider::new(clock.clone());
+        let n_wakers = || pausable.state.lock().unwrap().wakers.len();
+
+        let mut sleep1 = pausable.sleep(Duration::from_secs(60));
+        let mut sleep2 = pausable.sleep(Duration::from_secs(120));
+        // Each poll uses a different waker, as if the sleep had moved between tasks.
+        for _ in 0..10 {
+            let waker = futures::task::waker(Arc::new(NoopWake));
+            let mut cx = Context::from_waker(&waker);
+            assert!(Pin::new(&mut sleep1).poll(&mut cx).is_pending());
+            assert!(Pin::new(&mut sleep2).poll(&mut cx).is_pending());
+        }
+        assert_eq!(n_wakers(), 2);
+
+        // A sleep that ends forgets its waker...
+        let mut cx = Context::from_waker(noop_waker_ref());
+        pausable.advance(Duration::from_secs(60));
+        assert_eq!(n_wakers(), 0);
+        assert!(Pin::new(&mut sleep2).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut sleep1).poll(&mut cx).is_ready());
+        assert_eq!(n_wakers(), 1);
+
+        // ...and so does one that is dropped.
+        drop(sleep2);
+        assert_eq!(n_wakers(), 0);
+    }
+
+    /// A waker that does nothing, but is distinct from every other waker.
+    struct NoopWake;
+
+    impl futures::task::ArcWake for NoopWake {
+        fn wake_by_ref(_arc_self: &Arc<Self>) {}
+    }
+}
@@ -0,0 +1,339 @@
+//! Simulate a wall clock that is wrong, or that runs at the wrong rate.
+//!
+//! Different hosts on the Tor network rarely agree exactly about the time.
+//! Code that compares its own idea of the time with timestamps from elsewhere
+//! (descriptor lifetimes, revision counters, consensus validity intervals, and so on)
+//! needs to tolerate that.
+//! A [`SkewedTimeProvider`] wraps another time provider
+//! and adjusts its wall-clock time,
+//! so that tests can give each simulated party its own, slightly wrong, clock.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{CoarseInstant, CoarseTimeProvider, DynTimeProvider, SleepProvider};
+
+/// A time provider whose wall clock is offset from, and drifts relative to,
+/// that of another provider.
+///
+/// Only the wall-clock time ([`SleepProvider::wallclock`]) is affected.
+/// The monotonic clock, the coarse clock, and sleeping
+/// are all passed through to the underlying provider unchanged.
+///
+/// Clones share their skew:
+/// changing the skew on one clone changes it on all of them.
+/// This lets a test change the skew of a clock that it has already
+/// handed to the code under test.
+///
+/// This is meant for testing.
+/// To use it where a concrete runtime type isn't wanted,
+/// wrap a [`DynTimeProvider`], and (if necessary) wrap the result in another one.
+#[derive(Clone, Debug)]
+pub struct SkewedTimeProvider<R = DynTimeProvider> {
+    /// The provider whose wall clock we adjust.
+    inner: R,
+    /// The adjustment that we apply.
+    skew: Arc<Mutex<Skew>>,
+}
+
+/// The adjustment applied by a [`SkewedTimeProvider`].
+#[derive(Clone, Debug)]
+struct Skew {
+    /// How far ahead of the underlying wall clock we were at `anchor`, in nanoseconds.
+    ///
+    /// Negative if we were behind.
+    offset_nanos: i128,
+    /// How many seconds we gain for each second of the underlying wall clock.
+    ///
+    /// Negative if we lose time.
+    drift: f64,
+    /// The underlying wall-clock time from which we measure drift.
+    anchor: SystemTime,
+}
+
+impl Skew {
+    /// Return the total adjustment, in nanoseconds, at the underlying wall-clock time `now`.
+    fn adjustment_nanos(&self, now: SystemTime) -> i128 {
+        let elapsed = signed_nanos_between(self.anchor, now);
+        // Casting from f64 to i128 saturates, which is what we want.
+        #[allow(clippy::cast_possible_truncation)]
+        let drifted = (elapsed as f64 * self.drift).round() as i128;
+        self.offset_nanos.saturating_add(drifted)
+    }
+}
+
+impl<R: SleepProvider> SkewedTimeProvider<R> {
+    /// Wrap `inner` in a new `SkewedTimeProvider`.
+    ///
+    /// Initially, there is no skew: the wall clock is the same as that of `inner`.
+    pub fn new(inner: R) -> Self {
+        let anchor = inner.wallclock();
+        SkewedTimeProvider {
+            inner,
+            skew: Arc::new(Mutex::new(Skew {
+                offset_nanos: 0,
+                drift: 0.0,
+                anchor,
+            })),
+        }
+    }
+
+    /// Set our wall clock to be `offset` ahead of that of the underlying provider.
+    ///
+    /// Any drift accumulated so far is discarded; the drift rate is unchanged.
+    pub fn set_offset_ahead(&self, offset: Duration) {
+        self.set_offset_nanos(duration_nanos(offset));
+    }
+
+    /// Set our wall clock to be `offset` behind that of the underlying provider.
+    ///
+    /// Any drift accumulated so far is discarded; the drift rate is unchanged.
+    pub fn set_offset_behind(&self, offset: Duration) {
+        self.set_offset_nanos(-duration_nanos(offset));
+    }
+
+    /// Make our wall clock gain `drift` seconds for every second
+    /// that passes on the underlying provider's wall clock.
+    ///
+    /// A negative `drift` makes our clock lose time.
+    /// For example, a drift of `0.001` makes us gain 3.6 seconds an hour.
+    ///
+    /// Drift accumulated before this call is kept, as part of the offset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `drift` is not finite, or is -1 or less
+    /// (which would make our clock stop, or run backwards).
+    pub fn set_drift(&self, drift: f64) {
+        assert!(
+            drift.is_finite() && drift > -1.0,
+            "Unsupported clock drift {drift}"
+        );
+        let now = self.inner.wallclock();
+        let mut skew = self.skew.lock().expect("poisoned lock");
+        skew.offset_nanos = skew.adjustment_nanos(now);
+        skew.anchor = now;
+        skew.drift = drift;
+    }
+
+    /// Return how far ahead of the underlying provider our wall clock is.
+    ///
+    /// Returns a negative number of seconds if we are behind.
+    pub fn current_skew_secs(&self) -> f64 {
+        let now = self.inner.wallclock();
+        let nanos = self
+            .skew
+            .lock()
+            .expect("poisoned lock")
+            .adjustment_nanos(now);
+        nanos as f64 / 1e9
+    }
+
+    /// Return a reference to the underlying provider.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Set the offset to `offset_nanos`, and restart drift from now.
+    fn set_offset_nanos(&self, offset_nanos: i128) {
+        let now = self.inner.wallclock();
+        let mut skew = self.skew.lock().expect("poisoned lock");
+        skew.offset_nanos = offset_nanos;
+        skew.anchor = now;
+    }
+}
+
+impl<R: SleepProvider> SleepProvider for SkewedTimeProvider<R> {
+    type SleepFuture = R::SleepFuture;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        self.inner.sleep(duration)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn wallclock(&self) -> SystemTime {
+        let now = self.inner.wallclock();
+        let adjustment = self
+            .skew
+            .lock()
+            .expect("poisoned lock")
+            .adjustment_nanos(now);
+        add_signed_nanos(now, adjustment)
+    }
+
+    fn block_advance<T: Into<String>>(&self, reason: T) {
+        self.inner.block_advance(reason);
+    }
+
+    fn release_advance<T: Into<String>>(&self, reason: T) {
+        self.inner.release_advance(reason);
+    }
+
+    fn allow_one_advance(&self, dur: Duration) {
+        self.inner.allow_one_advance(dur);
+    }
+}
+
+impl<R: SleepProvider + CoarseTimeProvider> CoarseTimeProvider for SkewedTimeProvider<R> {
+    fn now_coarse(&self) -> CoarseInstant {
+        self.inner.now_coarse()
+    }
+}
+
+/// Return the number of nanoseconds in `d`.
+pub(crate) fn duration_nanos(d: Duration) -> i128 {
+    // A Duration holds at most u64::MAX seconds, so this can't overflow.
+    d.as_nanos()
+        .try_into()
+        .expect("Duration too large for i128?")
+}
+
+/// Return the number of nanoseconds from `earlier` to `later`.
+///
+/// Negative if `later` is before `earlier`.
+fn signed_nanos_between(earlier: SystemTime, later: SystemTime) -> i128 {
+    match later.duration_since(earlier) {
+        Ok(d) => duration_nanos(d),
+        Err(e) => -duration_nanos(e.duration()),
+    }
+}
+
+/// Return `t`, moved forwards (or, if negative, backwards) by `nanos` nanoseconds.
+///
+/// Returns `t` unchanged if the result can't be represented as a `SystemTime`.
+pub(crate) fn add_signed_nanos(t: SystemTime, nanos: i128) -> SystemTime {
+    /// Nanoseconds in a second.
+    const NANOS_PER_SEC: u128 = 1_000_000_000;
+    let magnitude = nanos.unsigned_abs();
+    let secs = u64::try_from(magnitude / NANOS_PER_SEC).unwrap_or(u64::MAX);
+    let subsec = u32::try_from(magnitude % NANOS_PER_SEC).expect("Remainder too large?");
+    let d = Duration::new(secs, subsec);
+    let adjusted = if nanos >= 0 {
+        t.checked_add(d)
+    } else {
+        t.checked_sub(d)
+    };
+    // If we can't represent the adjusted time, the skew is absurd;
+    // leaving the time unadjusted is as good as anything.
+    adjusted.unwrap_or(t)
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::RealCoarseTimeProvider;
+
+    /// A time provider whose wall clock only moves when we tell it to.
+    #[derive(Clone, Debug)]
+    struct FakeClock(Arc<Mutex<SystemTime>>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            FakeClock(Arc::new(Mutex::new(start)))
+        }
+
+        fn advance(&self, d: Duration) {
+            *self.0.lock().unwrap() += d;
+        }
+    }
+
+    impl SleepProvider for FakeClock {
+        type SleepFuture = futures::future::Ready<()>;
+
+        fn sleep(&self, _duration: Duration) -> Self::SleepFuture {
+            futures::future::ready(())
+        }
+
+        fn wallclock(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    impl CoarseTimeProvider for FakeClock {
+        fn now_coarse(&self) -> CoarseInstant {
+            RealCoarseTimeProvider::new().now_coarse()
+        }
+    }
+
+    #[test]
+    fn offset() {
+        let clock = FakeClock::new();
+        let skewed = SkewedTimeProvider::new(clock.clone());
+        assert_eq!(skewed.wallclock(), clock.wallclock());
+
+        skewed.set_offset_ahead(Duration::from_secs(90));
+        assert_eq!(
+            skewed.wallclock(),
+            clock.wallclock() + Duration::from_secs(90)
+        );
+
+        // Clones share their skew.
+        let skewed2 = skewed.clone();
+        skewed.set_offset_behind(Duration::from_millis(1500));
+        assert_eq!(
+            skewed2.wallclock(),
+            clock.wallclock() - Duration::from_millis(1500)
+        );
+        assert_eq!(skewed2.current_skew_secs(), -1.5);
+    }
+
+    #[test]
+    fn drift() {
+        let clock = FakeClock::new();
+        let skewed = SkewedTimeProvider::new(clock.clone());
+        skewed.set_offset_ahead(Duration::from_secs(10));
+        skewed.set_drift(0.01);
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(
+            skewed.wallclock(),
+            clock.wallclock() + Duration::from_secs(11)
+        );
+
+        // Changing the drift keeps what we have gained so far.
+        skewed.set_drift(-0.5);
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(
+            skewed.wallclock(),
+            clock.wallclock() + Duration::from_secs(9)
+        );
+
+        // Losing time can put us behind.
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(
+            skewed.wallclock(),
+            clock.wallclock() - Duration::from_secs(11)
+        );
+    }
+
+    #[test]
+    fn dyn_time_provider() {
+        let clock = FakeClock::new();
+        let skewed = SkewedTimeProvider::new(DynTimeProvider::new(clock.clone()));
+        skewed.set_offset_ahead(Duration::from_secs(3600));
+
+        // A skewed provider can itself be type-erased.
+        let erased = DynTimeProvider::new(skewed);
+        assert_eq!(
+            erased.wallclock(),
+            clock.wallclock() + Duration::from_secs(3600)
+        );
+    }
+}
@@ -1,6 +1,8 @@
 //! Functions for task management that don't belong inside the Runtime
 //! trait.
 
+pub mod registry;
+
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
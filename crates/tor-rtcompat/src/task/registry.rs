@@ -0,0 +1,199 @@
+//! A registry of named long-running tasks, for debugging.
+//!
+//! Some of our background tasks (such as an onion service's descriptor publisher,
+//! or the task that maintains our vanguards) are meant to run
+//! for as long as the process does.
+//! If one of them exits early or gets stuck, nothing else may notice:
+//! the only symptom might be that (say) a service silently stops publishing.
+//!
+//! A [`TaskRegistry`] lets such tasks record their names,
+//! and note whenever they do something useful,
+//! so that [`TaskRegistry::dump`] can show which of them are still alive,
+//! and which of them have gone quiet.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::SystemTime;
+
+use crate::{CoarseTimeProvider, DynTimeProvider, SleepProvider};
+
+/// A set of named long-running tasks.
+///
+/// Most code should use the process-wide registry, [`TaskRegistry::global`].
+#[derive(Clone, Debug, Default)]
+pub struct TaskRegistry {
+    /// The tasks that are currently registered.
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// The mutable state of a [`TaskRegistry`].
+#[derive(Debug, Default)]
+struct Inner {
+    /// The identifier to give to the next task that registers.
+    next_id: u64,
+    /// Every registered task, in the order in which they registered.
+    tasks: BTreeMap<u64, TaskInfo>,
+}
+
+/// Information about a task in a [`TaskRegistry`], as returned by [`TaskRegistry::dump`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TaskInfo {
+    /// The name of the task.
+    pub name: String,
+    /// When the task registered itself.
+    pub spawned: SystemTime,
+    /// When the task last reported that it had done something.
+    ///
+    /// This is the same as `spawned` if the task has not reported any activity.
+    pub last_activity: SystemTime,
+}
+
+/// The process-wide registry returned by [`TaskRegistry::global`].
+static GLOBAL: LazyLock<TaskRegistry> = LazyLock::new(TaskRegistry::new);
+
+impl TaskRegistry {
+    /// Create a new, empty, registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the process-wide registry.
+    pub fn global() -> &'static TaskRegistry {
+        &GLOBAL
+    }
+
+    /// Record that a task called `name` has started.
+    ///
+    /// The task stays in this registry until the returned [`RegisteredTask`] is dropped;
+    /// the task should hold on to it for as long as it runs,
+    /// and call [`RegisteredTask::note_activity`] whenever it makes progress.
+    ///
+    /// Timestamps are taken from the wall clock of `runtime`.
+    pub fn register<R>(&self, name: impl Into<String>, runtime: R) -> RegisteredTask
+    where
+        R: SleepProvider + CoarseTimeProvider,
+    {
+        let now = runtime.wallclock();
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.tasks.insert(
+            id,
+            TaskInfo {
+                name: name.into(),
+                spawned: now,
+                last_activity: now,
+            },
+        );
+
+        RegisteredTask {
+            registry: Arc::downgrade(&self.inner),
+            id,
+            runtime: DynTimeProvider::new(runtime),
+        }
+    }
+
+    /// Return information about every task that is currently registered,
+    /// in the order in which they registered.
+    pub fn dump(&self) -> Vec<TaskInfo> {
+        self.inner
+            .lock()
+            .expect("poisoned lock")
+            .tasks
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+/// A task's entry in a [`TaskRegistry`], returned by [`TaskRegistry::register`].
+///
+/// The task is removed from the registry when this is dropped.
+#[derive(Debug)]
+pub struct RegisteredTask {
+    /// The registry that this task is in.
+    registry: Weak<Mutex<Inner>>,
+    /// The identifier of this task within `registry`.
+    id: u64,
+    /// The runtime whose clock we use for timestamps.
+    runtime: DynTimeProvider,
+}
+
+impl RegisteredTask {
+    /// Record that this task has just done something.
+    pub fn note_activity(&self) {
+        let Some(registry) = self.registry.upgrade() else {
+            return;
+        };
+        let now = self.runtime.wallclock();
+        if let Some(info) = registry
+            .lock()
+            .expect("poisoned lock")
+            .tasks
+            .get_mut(&self.id)
+        {
+            info.last_activity = now;
+        }
+    }
+}
+
+impl Drop for RegisteredTask {
+    fn drop(&mut self) {
+        let Some(registry) = self.registry.upgrade() else {
+            return;
+        };
+        // We mustn't panic if the lock is poisoned, since we may be dropped
+        // while unwinding from a panic in the task.
+        if let Ok(mut inner) = registry.lock() {
+            let _: Option<TaskInfo> = inner.tasks.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(all(
+    test,
+    any(feature = "native-tls", feature = "rustls"),
+    any(feature = "async-std", feature = "tokio"),
+))]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    #[test]
+    fn register_and_dump() {
+        crate::test_with_one_runtime!(|runtime| async move {
+            let registry = TaskRegistry::new();
+            assert!(registry.dump().is_empty());
+
+            let first = registry.register("first", runtime.clone());
+            let second = registry.register("second", runtime.clone());
+            let names: Vec<_> = registry.dump().into_iter().map(|t| t.name).collect();
+            assert_eq!(names, ["first", "second"]);
+
+            second.note_activity();
+            let dump = registry.dump();
+            assert!(dump[1].last_activity >= dump[1].spawned);
+
+            drop(first);
+            let names: Vec<_> = registry.dump().into_iter().map(|t| t.name).collect();
+            assert_eq!(names, ["second"]);
+
+            // Dropping the registry first is harmless.
+            drop(registry);
+            second.note_activity();
+        });
+    }
+}
@@ -6,7 +6,7 @@ use pin_project::pin_project;
 use std::{
     pin::Pin,
     task::{Context, Poll},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 /// An error value given when a function times out.
@@ -52,6 +52,23 @@ pub trait SleepProviderExt: SleepProvider {
         }
     }
 
+    /// Pause until the monotonic clock ([`SleepProvider::now`]) is at `when` or later.
+    ///
+    /// Since the monotonic clock never jumps,
+    /// this is unaffected by any adjustments to the wall-clock time.
+    /// Use this for deadlines that were computed from an earlier `now()`.
+    ///
+    /// If `when` is in the past, the returned future completes immediately.
+    ///
+    /// # Limitations
+    ///
+    /// This uses [`SleepProvider::sleep`] for its timer, and is
+    /// subject to the same limitations.
+    #[must_use = "sleep_until() returns a future, which does nothing unless used"]
+    fn sleep_until(&self, when: Instant) -> Self::SleepFuture {
+        self.sleep(when.saturating_duration_since(self.now()))
+    }
+
     /// Pause until the wall-clock is at `when` or later, trying to
     /// recover from clock jumps.
     ///
@@ -59,14 +76,19 @@ pub trait SleepProviderExt: SleepProvider {
     /// wake up periodically to check the current time, and see if
     /// it is at or past the target.
     ///
+    /// Use this for deadlines that are expressed as a [`SystemTime`],
+    /// such as expiry times that were loaded from disk,
+    /// instead of converting them to a [`Duration`] by hand:
+    /// that conversion goes stale if the wall-clock is adjusted while we're sleeping.
+    ///
     /// # Limitations
     ///
     /// The ability of this function to detect clock jumps is limited
     /// to its granularity; it may finish a while after the declared
     /// wallclock time if the system clock jumps forward.
     ///
-    /// This function does not detect backward clock jumps; arguably,
-    /// we should have another function to do that.
+    /// If the system clock jumps backward, we only notice at the end of our current delay,
+    /// at which point we keep waiting until the wall-clock reaches `when`.
     ///
     /// This uses [`SleepProvider::sleep`] for its timer, and is
     /// subject to the same limitations.
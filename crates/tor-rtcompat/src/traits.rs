@@ -409,6 +409,22 @@ pub trait StreamOps {
         .into())
     }
 
+    /// Set the type-of-service byte (`IP_TOS`, or `IPV6_TCLASS` for IPv6)
+    /// on the packets sent on this `Stream`, if it is a TCP stream.
+    ///
+    /// The upper six bits of `tos` are the DSCP value.
+    ///
+    /// Implementations should return an [`UnsupportedStreamOp`] IO error
+    /// if the stream is not a TCP stream,
+    /// and on platforms where the operation is not supported.
+    fn set_ip_tos(&self, _tos: u8) -> IoResult<()> {
+        Err(UnsupportedStreamOp {
+            op: "set_ip_tos",
+            reason: "unsupported object type",
+        }
+        .into())
+    }
+
     /// Return a new handle that implements [`StreamOps`],
     /// and that can be used independently of `self`.
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
@@ -436,6 +452,11 @@ impl<T: StreamOps, C> StreamOps for Framed<T, C> {
         inner.set_tcp_notsent_lowat(notsent_lowat)
     }
 
+    fn set_ip_tos(&self, tos: u8) -> IoResult<()> {
+        let inner: &T = self;
+        inner.set_ip_tos(tos)
+    }
+
     fn new_handle(&self) -> Box<dyn StreamOps + Send + Unpin> {
         let inner: &T = self;
         inner.new_handle()
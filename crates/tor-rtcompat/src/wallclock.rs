@@ -0,0 +1,231 @@
+//! Detect sudden changes in the wall-clock time.
+//!
+//! The wall clock can jump forwards or backwards at any time:
+//! for example, when the host is suspended and resumed,
+//! when NTP corrects a badly set clock,
+//! or when the user changes the time by hand.
+//!
+//! Code that derives values from the wall clock
+//! (expiry times, timestamps that must increase, and so on)
+//! may need to react to such jumps.
+//! A [`WallclockMonitor`] periodically compares the progress of the
+//! monotonic clock with that of the wall clock,
+//! and tells its subscribers whenever the two disagree
+//! by more than a configured threshold.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::Stream;
+use futures::channel::mpsc;
+use futures::task::{Spawn, SpawnError, SpawnExt as _};
+use tracing::info;
+
+use crate::SleepProvider;
+
+/// A sudden change in the wall-clock time, detected by a [`WallclockMonitor`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WallclockJump {
+    /// The wall-clock time we expected to see,
+    /// based on how far the monotonic clock had advanced.
+    pub expected: SystemTime,
+    /// The wall-clock time we actually saw.
+    pub actual: SystemTime,
+}
+
+impl WallclockJump {
+    /// Return true if the wall clock jumped forwards.
+    ///
+    /// (This is what a suspend and resume usually looks like,
+    /// since the monotonic clock does not advance while the host is suspended.)
+    pub fn is_forwards(&self) -> bool {
+        self.actual > self.expected
+    }
+
+    /// Return the size of the jump, in either direction.
+    pub fn magnitude(&self) -> Duration {
+        match self.actual.duration_since(self.expected) {
+            Ok(d) => d,
+            Err(e) => e.duration(),
+        }
+    }
+}
+
+/// A reading of the monotonic and wall clocks, taken at (roughly) the same time.
+#[derive(Clone, Copy, Debug)]
+struct ClockReading {
+    /// The monotonic time.
+    mono: Instant,
+    /// The wall-clock time.
+    wall: SystemTime,
+}
+
+impl ClockReading {
+    /// Read both of the clocks of `runtime`.
+    fn take<R: SleepProvider>(runtime: &R) -> Self {
+        Self {
+            mono: runtime.now(),
+            wall: runtime.wallclock(),
+        }
+    }
+
+    /// Compare this reading with an `earlier` one.
+    ///
+    /// Return a [`WallclockJump`] if the wall clock has moved
+    /// more than `threshold` further (or less far) than the monotonic clock.
+    fn jump_since(&self, earlier: &ClockReading, threshold: Duration) -> Option<WallclockJump> {
+        let elapsed = self.mono.saturating_duration_since(earlier.mono);
+        let jump = WallclockJump {
+            expected: earlier.wall.checked_add(elapsed)?,
+            actual: self.wall,
+        };
+        (jump.magnitude() > threshold).then_some(jump)
+    }
+}
+
+/// The senders for every subscriber of a [`WallclockMonitor`].
+type Subscribers = Mutex<Vec<mpsc::UnboundedSender<WallclockJump>>>;
+
+/// A handle to a background task that detects sudden changes in the wall-clock time.
+///
+/// The background task exits once every clone of this handle has been dropped;
+/// at that point, the streams returned by [`subscribe`](WallclockMonitor::subscribe) end.
+#[derive(Clone, Debug)]
+pub struct WallclockMonitor {
+    /// The subscribers to notify about each jump.
+    subscribers: Arc<Subscribers>,
+}
+
+impl WallclockMonitor {
+    /// Launch a task that reads the clocks of `runtime` every `interval`,
+    /// and reports a [`WallclockJump`] whenever the wall clock has drifted
+    /// from the monotonic clock by more than `threshold` since the previous reading.
+    ///
+    /// `threshold` should be comfortably larger than the amount
+    /// by which the task can oversleep on a busy host.
+    pub fn launch<R: SleepProvider + Spawn>(
+        runtime: &R,
+        interval: Duration,
+        threshold: Duration,
+    ) -> Result<Self, SpawnError> {
+        let subscribers = Arc::new(Mutex::new(vec![]));
+        runtime.spawn(monitor_wallclock(
+            runtime.clone(),
+            Arc::downgrade(&subscribers),
+            interval,
+            threshold,
+        ))?;
+
+        Ok(Self { subscribers })
+    }
+
+    /// Return a stream of every [`WallclockJump`] detected from now on.
+    pub fn subscribe(&self) -> WallclockJumps {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().expect("poisoned lock").push(tx);
+        WallclockJumps { inner: rx }
+    }
+}
+
+/// A stream of [`WallclockJump`]s, returned by [`WallclockMonitor::subscribe`].
+#[derive(Debug)]
+pub struct WallclockJumps {
+    /// The receiver that implements this stream.
+    inner: mpsc::UnboundedReceiver<WallclockJump>,
+}
+
+impl Stream for WallclockJumps {
+    type Item = WallclockJump;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        std::pin::Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+/// The body of the task launched by [`WallclockMonitor::launch`].
+async fn monitor_wallclock<R: SleepProvider>(
+    runtime: R,
+    subscribers: Weak<Subscribers>,
+    interval: Duration,
+    threshold: Duration,
+) {
+    let mut last = ClockReading::take(&runtime);
+
+    loop {
+        runtime.sleep(interval).await;
+
+        let Some(subscribers) = subscribers.upgrade() else {
+            // Every WallclockMonitor handle is gone.
+            return;
+        };
+
+        let now = ClockReading::take(&runtime);
+        if let Some(jump) = now.jump_since(&last, threshold) {
+            info!(
+                "Wall clock jumped {} by {:?}",
+                if jump.is_forwards() {
+                    "forwards"
+                } else {
+                    "backwards"
+                },
+                jump.magnitude(),
+            );
+            subscribers
+                .lock()
+                .expect("poisoned lock")
+                .retain(|tx| tx.unbounded_send(jump.clone()).is_ok());
+        }
+        last = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn detect_jumps() {
+        let mono = Instant::now();
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let first = ClockReading { mono, wall };
+        let reading = |mono_secs, wall_secs| ClockReading {
+            mono: mono + Duration::from_secs(mono_secs),
+            wall: wall + Duration::from_secs(wall_secs),
+        };
+
+        // Both clocks advanced by the same amount, or nearly so.
+        assert_eq!(reading(60, 60).jump_since(&first, THRESHOLD), None);
+        assert_eq!(reading(60, 85).jump_since(&first, THRESHOLD), None);
+
+        // We were suspended for an hour.
+        let jump = reading(60, 3660).jump_since(&first, THRESHOLD).unwrap();
+        assert!(jump.is_forwards());
+        assert_eq!(jump.magnitude(), Duration::from_secs(3600));
+        assert_eq!(jump.expected, wall + Duration::from_secs(60));
+
+        // The clock was set back by two minutes.
+        let first = reading(0, 300);
+        let jump = reading(60, 240).jump_since(&first, THRESHOLD).unwrap();
+        assert!(!jump.is_forwards());
+        assert_eq!(jump.magnitude(), Duration::from_secs(120));
+    }
+}
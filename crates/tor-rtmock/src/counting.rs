@@ -0,0 +1,258 @@
+//! Keep track of how code under test uses its time provider.
+//!
+//! Code that schedules things (republishing a descriptor, rotating vanguards, and so on)
+//! usually does so by calling [`SleepProvider::sleep`].
+//! Checking only the eventual effects of that scheduling can be slow and imprecise.
+//! A [`CountingTimeProvider`] wraps another time provider,
+//! and records each call to it,
+//! so that tests can check how many sleeps were requested, and for how long.
+//!
+//! Code that wants a whole [`Runtime`] can be given a [`CountingRuntime`] instead.
+
+use std::sync::{Arc, Mutex};
+
+use tor_rtcompat::DynTimeProvider;
+
+use crate::util::impl_runtime_prelude::*;
+
+/// A time provider that counts the calls made to it,
+/// and otherwise behaves exactly like another provider.
+///
+/// We count calls to [`now`](SleepProvider::now),
+/// [`wallclock`](SleepProvider::wallclock),
+/// and [`sleep`](SleepProvider::sleep),
+/// and we record the duration of every requested sleep.
+///
+/// Clones share their counts:
+/// a test can keep one clone, and hand another to the code under test
+/// (wrapped in a [`DynTimeProvider`], if need be).
+#[derive(Clone, Debug)]
+pub struct CountingTimeProvider<R = DynTimeProvider> {
+    /// The provider to which we pass all calls.
+    inner: R,
+    /// What we have recorded so far.
+    calls: Arc<Mutex<TimeCalls>>,
+}
+
+/// The calls recorded by a [`CountingTimeProvider`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct TimeCalls {
+    /// The number of calls to [`SleepProvider::now`].
+    pub now: usize,
+    /// The number of calls to [`SleepProvider::wallclock`].
+    pub wallclock: usize,
+    /// The duration of every call to [`SleepProvider::sleep`], in the order they were made.
+    pub sleeps: Vec<Duration>,
+}
+
+impl TimeCalls {
+    /// Return the number of calls to [`SleepProvider::sleep`].
+    pub fn n_sleeps(&self) -> usize {
+        self.sleeps.len()
+    }
+
+    /// Return the number of sleeps requested with a duration in `range`.
+    pub fn n_sleeps_in<B: std::ops::RangeBounds<Duration>>(&self, range: B) -> usize {
+        self.sleeps.iter().filter(|d| range.contains(*d)).count()
+    }
+}
+
+impl<R: SleepProvider> CountingTimeProvider<R> {
+    /// Wrap `inner` in a new `CountingTimeProvider`, with no calls recorded.
+    pub fn new(inner: R) -> Self {
+        CountingTimeProvider {
+            inner,
+            calls: Default::default(),
+        }
+    }
+
+    /// Return a copy of the calls recorded so far.
+    pub fn calls(&self) -> TimeCalls {
+        self.lock().clone()
+    }
+
+    /// Return the calls recorded so far, and start counting again from zero.
+    pub fn take_calls(&self) -> TimeCalls {
+        std::mem::take(&mut *self.lock())
+    }
+
+    /// Return a reference to the underlying provider.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Lock our record of calls.
+    fn lock(&self) -> std::sync::MutexGuard<'_, TimeCalls> {
+        self.calls.lock().expect("poisoned lock")
+    }
+}
+
+impl<R: SleepProvider> SleepProvider for CountingTimeProvider<R> {
+    type SleepFuture = R::SleepFuture;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        self.lock().sleeps.push(duration);
+        self.inner.sleep(duration)
+    }
+
+    fn now(&self) -> Instant {
+        self.lock().now += 1;
+        self.inner.now()
+    }
+
+    fn wallclock(&self) -> SystemTime {
+        self.lock().wallclock += 1;
+        self.inner.wallclock()
+    }
+
+    fn block_advance<T: Into<String>>(&self, reason: T) {
+        self.inner.block_advance(reason);
+    }
+
+    fn release_advance<T: Into<String>>(&self, reason: T) {
+        self.inner.release_advance(reason);
+    }
+
+    fn allow_one_advance(&self, dur: Duration) {
+        self.inner.allow_one_advance(dur);
+    }
+}
+
+impl<R: SleepProvider + CoarseTimeProvider> CoarseTimeProvider for CountingTimeProvider<R> {
+    fn now_coarse(&self) -> CoarseInstant {
+        self.inner.now_coarse()
+    }
+}
+
+/// A wrapper [`Runtime`] that counts the calls made to the time provider
+/// of an underlying runtime.
+///
+/// All other calls are delegated to the underlying runtime unchanged.
+/// See [`CountingTimeProvider`] for what we record.
+#[derive(Clone, Debug, Deftly)]
+#[derive_deftly(SomeMockRuntime)]
+pub struct CountingRuntime<R: Runtime> {
+    /// The underlying runtime. Most calls get delegated here.
+    #[deftly(mock(task, net))]
+    #[deftly(mock(toplevel_where = "R: ToplevelBlockOn"))]
+    runtime: R,
+    /// The counting wrapper around `runtime`.  Time-related calls get delegated here.
+    #[deftly(mock(sleep))]
+    time: CountingTimeProvider<R>,
+}
+
+impl<R: Runtime> CountingRuntime<R> {
+    /// Create a new runtime that wraps `runtime`, and counts the calls to its time provider.
+    pub fn new(runtime: R) -> Self {
+        let time = CountingTimeProvider::new(runtime.clone());
+        CountingRuntime { runtime, time }
+    }
+
+    /// Return a reference to the underlying runtime.
+    pub fn inner(&self) -> &R {
+        &self.runtime
+    }
+
+    /// Return a reference to the [`CountingTimeProvider`] that records our calls.
+    pub fn counting(&self) -> &CountingTimeProvider<R> {
+        &self.time
+    }
+
+    /// Return a copy of the calls recorded so far.
+    ///
+    /// See [`CountingTimeProvider::calls`].
+    pub fn calls(&self) -> TimeCalls {
+        self.time.calls()
+    }
+
+    /// Return the calls recorded so far, and start counting again from zero.
+    ///
+    /// See [`CountingTimeProvider::take_calls`].
+    pub fn take_calls(&self) -> TimeCalls {
+        self.time.take_calls()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // @@ begin test lint list maintained by maint/add_warning @@
+    #![allow(clippy::bool_assert_comparison)]
+    #![allow(clippy::clone_on_copy)]
+    #![allow(clippy::dbg_macro)]
+    #![allow(clippy::mixed_attributes_style)]
+    #![allow(clippy::print_stderr)]
+    #![allow(clippy::print_stdout)]
+    #![allow(clippy::single_char_pattern)]
+    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unchecked_duration_subtraction)]
+    #![allow(clippy::useless_vec)]
+    #![allow(clippy::needless_pass_by_value)]
+    //! <!-- @@ end test lint list maintained by maint/add_warning @@ -->
+    use super::*;
+    use crate::MockRuntime;
+    use futures::task::SpawnExt as _;
+
+    #[test]
+    fn counts() {
+        let counting = CountingTimeProvider::new(MockRuntime::new());
+        let _ = counting.now();
+        let _ = counting.now();
+        let _ = counting.wallclock();
+        drop(counting.sleep(Duration::from_secs(3600)));
+        drop(counting.sleep(Duration::from_secs(5)));
+
+        let calls = counting.calls();
+        assert_eq!(calls.now, 2);
+        assert_eq!(calls.wallclock, 1);
+        assert_eq!(calls.n_sleeps(), 2);
+        assert_eq!(
+            calls.n_sleeps_in(Duration::from_secs(60 * 60)..=Duration::from_secs(120 * 60)),
+            1
+        );
+
+        // Taking the calls resets the counts.
+        assert_eq!(counting.take_calls(), calls);
+        assert_eq!(counting.calls(), TimeCalls::default());
+    }
+
+    #[test]
+    fn dyn_time_provider() {
+        let counting = CountingTimeProvider::new(DynTimeProvider::new(MockRuntime::new()));
+
+        // Clones share their counts, even once type-erased.
+        let erased = DynTimeProvider::new(counting.clone());
+        let _ = erased.wallclock();
+        drop(erased.sleep(Duration::from_millis(10)));
+        let calls = counting.calls();
+        assert_eq!(calls.wallclock, 1);
+        assert_eq!(calls.sleeps, vec![Duration::from_millis(10)]);
+    }
+
+    #[test]
+    fn runtime() {
+        MockRuntime::test_with_various(|rt| async move {
+            let counting = CountingRuntime::new(rt.clone());
+
+            let (tx, rx) = futures::channel::oneshot::channel();
+            let sleeper = counting.clone();
+            counting
+                .spawn(async move {
+                    sleeper.sleep(Duration::from_secs(30)).await;
+                    tx.send(()).unwrap();
+                })
+                .unwrap();
+            rt.progress_until_stalled().await;
+            assert_eq!(counting.calls().sleeps, vec![Duration::from_secs(30)]);
+
+            // The sleep is a real sleep of the underlying runtime.
+            let mut rx = rx;
+            assert!(rx.try_recv().unwrap().is_none());
+            rt.advance_by(Duration::from_secs(30)).await;
+            rx.await.unwrap();
+
+            assert_eq!(counting.take_calls().n_sleeps(), 1);
+            assert_eq!(counting.calls(), TimeCalls::default());
+        });
+    }
+}
@@ -52,6 +52,7 @@ extern crate core;
 #[macro_use]
 mod util;
 
+pub mod counting;
 pub mod io;
 pub mod net;
 pub mod simple_time;